@@ -0,0 +1,115 @@
+//! Indexing an existing archive as the out directory, so switching to this tool doesn't mean
+//! copying everything again.
+
+use std::path::Path;
+
+use eyre::Result;
+use rayon::iter::{ParallelBridge, ParallelIterator};
+use walkdir::WalkDir;
+
+use crate::{
+    AdoptArgs, chunks,
+    compress::{Transform, digest_archived},
+    destination,
+    digest::HashAlgorithm,
+    platform::FileInfo,
+    sau64::SimpleAtomicU64,
+    store::{PhotoSyncStore, RunId, RunStatus},
+    trash,
+};
+
+pub fn adopt(args: AdoptArgs) -> Result<()> {
+    let store = PhotoSyncStore::new(args.database_file)?;
+    let run = store.begin_run(&args.machine_id)?;
+    let result = adopt_dir(&store, run, &args.dir, args.hash_algo);
+    store.finish_run(
+        run,
+        if result.is_ok() {
+            RunStatus::Succeeded
+        } else {
+            RunStatus::Aborted
+        },
+    )?;
+    let (adopted, unchanged) = result?;
+    println!(
+        "adopted {adopted} files from {:?}, {unchanged} were already catalogued",
+        args.dir
+    );
+    Ok(())
+}
+
+/// Hashes and records every file in `dir` which isn't already catalogued with its current metadata,
+/// returning how many were and weren't.
+fn adopt_dir(
+    store: &PhotoSyncStore,
+    run: RunId,
+    dir: &Path,
+    algorithm: HashAlgorithm,
+) -> Result<(u64, u64)> {
+    let adopted = SimpleAtomicU64::default();
+    let unchanged = SimpleAtomicU64::default();
+    WalkDir::new(dir)
+        .into_iter()
+        .filter_entry(|entry| {
+            !trash::is_trash(entry)
+                && !destination::is_marker(entry)
+                && !chunks::is_repository(entry)
+        })
+        .par_bridge()
+        .try_for_each(|entry| {
+            let entry = entry?;
+            if !entry.file_type().is_file() {
+                return Ok(());
+            }
+            let path = entry.path().strip_prefix(dir)?;
+            let FileInfo { size, modified } = FileInfo::of(entry.path())?;
+            if store.target_file_is_current(path, modified, size)? {
+                unchanged.fetch_add(1);
+                return Ok(());
+            }
+            // nothing but its name says how it was stored.
+            let transform = Transform::by_name(path);
+            let digest = digest_archived(entry.path(), transform, algorithm)?;
+            store.mark_exists_in_target(run, path, modified, size, &digest)?;
+            store.record_transform(run, path, transform)?;
+            let adopted = adopted.fetch_add(1) + 1;
+            if adopted.is_multiple_of(100) {
+                println!("adopted {adopted} files");
+            }
+            Ok::<_, eyre::Error>(())
+        })?;
+    Ok((adopted.as_u64(), unchanged.as_u64()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::digest::digest;
+
+    #[test]
+    fn adopting_twice_only_hashes_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("2019")).unwrap();
+        fs::write(dir.path().join("2019/a.jpg"), "a").unwrap();
+        fs::write(dir.path().join("b.jpg"), "b").unwrap();
+        let store = PhotoSyncStore::new_for_tests().unwrap();
+        let run = store.begin_run("").unwrap();
+
+        let adopt = |dir| adopt_dir(&store, run, dir, HashAlgorithm::Sha256).unwrap();
+        assert_eq!(adopt(dir.path()), (2, 0));
+        let digest_a = digest(&dir.path().join("2019/a.jpg")).unwrap();
+        assert!(store.exists_in_target(&digest_a).unwrap());
+
+        fs::write(dir.path().join("b.jpg"), "changed").unwrap();
+        assert_eq!(adopt(dir.path()), (1, 1));
+        let paths: Vec<_> = store
+            .target_files()
+            .unwrap()
+            .into_iter()
+            .map(|f| f.path)
+            .collect();
+        assert_eq!(paths, [Path::new("2019/a.jpg"), Path::new("b.jpg")]);
+    }
+}
@@ -0,0 +1,246 @@
+//! AppleDouble (`._name`) files, which hold the resource fork and extended attributes of `name`
+//! when it is copied by macOS to a filesystem (e.g. over SMB) which can't store them itself.
+
+use std::{
+    ffi::OsString,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use clap::ValueEnum;
+use eyre::{Result, WrapErr, ensure, eyre};
+use tempfile::NamedTempFile;
+
+use crate::platform::set_archive_permissions;
+
+/// What to do with an AppleDouble file whose data file is also in the in directory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum AppleDoublePolicy {
+    /// Sync it like any other file.
+    Independent,
+    /// Copy it next to its data file, whenever that is copied.
+    Pair,
+    /// Write its contents as extended attributes of its data file, whenever that is copied.
+    Xattrs,
+    /// Don't sync it.
+    Skip,
+}
+
+const PREFIX: &str = "._";
+const MAGIC: u32 = 0x0005_1607;
+const RESOURCE_FORK: u32 = 2;
+const FINDER_INFO: u32 = 9;
+const FINDER_INFO_LEN: usize = 32;
+const ATTR_MAGIC: u32 = 0x4154_5452;
+/// Where the extended attributes header starts within the Finder info entry, after the Finder
+/// info and two bytes of padding.
+const ATTR_HEADER_OFFSET: usize = FINDER_INFO_LEN + 2;
+
+/// The data file `path` holds the metadata of, if it is an AppleDouble file.
+pub fn data_file_of(path: &Path) -> Option<PathBuf> {
+    let name = path.file_name()?.to_str()?.strip_prefix(PREFIX)?;
+    (!name.is_empty()).then(|| path.with_file_name(name))
+}
+
+/// The AppleDouble file which would hold the metadata of `path`.
+pub fn companion_of(path: &Path) -> Option<PathBuf> {
+    let mut name = OsString::from(PREFIX);
+    name.push(path.file_name()?);
+    Some(path.with_file_name(name))
+}
+
+/// Carries the AppleDouble file of `in_path`, if it has one, over to `out_path` as `policy` says,
+/// returning where it was copied to if it's paired. One already paired with `out_path` is left as
+/// it is, if it's the same.
+pub fn carry_over(
+    policy: AppleDoublePolicy,
+    in_path: &Path,
+    out_path: &Path,
+    temp_dir: &Path,
+) -> Result<Option<PathBuf>> {
+    let (Some(companion), Some(out_companion)) = (companion_of(in_path), companion_of(out_path))
+    else {
+        return Ok(None);
+    };
+    if !companion.is_file() {
+        return Ok(None);
+    }
+    match policy {
+        AppleDoublePolicy::Independent | AppleDoublePolicy::Skip => Ok(None),
+        AppleDoublePolicy::Pair => {
+            let contents = fs::read(&companion)?;
+            if fs::read(&out_companion).is_ok_and(|paired| paired == contents) {
+                return Ok(Some(out_companion));
+            }
+            let mut staged = NamedTempFile::new_in(temp_dir)?;
+            staged.write_all(&contents)?;
+            staged.persist_noclobber(&out_companion)?;
+            set_archive_permissions(&out_companion)?;
+            Ok(Some(out_companion))
+        }
+        AppleDoublePolicy::Xattrs => {
+            apply_as_xattrs(&companion, out_path)?;
+            Ok(None)
+        }
+    }
+}
+
+/// Sets the resource fork, Finder info and extended attributes held by the AppleDouble file at
+/// `apple_double` as extended attributes of `target`.
+pub fn apply_as_xattrs(apple_double: &Path, target: &Path) -> Result<()> {
+    let bytes = fs::read(apple_double)?;
+    for (name, value) in parse(&bytes).wrap_err_with(|| format!("reading {apple_double:?}"))? {
+        set_xattr(target, &name, &value)
+            .wrap_err_with(|| format!("setting {name} on {target:?}"))?;
+    }
+    Ok(())
+}
+
+/// The extended attributes an AppleDouble file stands for, by their macOS names.
+fn parse(bytes: &[u8]) -> Result<Vec<(String, Vec<u8>)>> {
+    ensure!(
+        be_u32(bytes, 0)? == MAGIC,
+        "not an AppleDouble file (bad magic number)"
+    );
+    let entries = usize::from(be_u16(bytes, 24)?);
+    let mut attrs = Vec::new();
+    for idx in 0..entries {
+        let at = 26 + idx * 12;
+        let (id, offset, len) = (
+            be_u32(bytes, at)?,
+            be_u32(bytes, at + 4)? as usize,
+            be_u32(bytes, at + 8)? as usize,
+        );
+        let entry = slice(bytes, offset, len)?;
+        match id {
+            RESOURCE_FORK if !entry.is_empty() => {
+                attrs.push(("com.apple.ResourceFork".to_string(), entry.to_vec()));
+            }
+            FINDER_INFO if entry.len() >= FINDER_INFO_LEN => {
+                let finder_info = &entry[..FINDER_INFO_LEN];
+                if finder_info.iter().any(|b| *b != 0) {
+                    attrs.push(("com.apple.FinderInfo".to_string(), finder_info.to_vec()));
+                }
+                if entry.len() > ATTR_HEADER_OFFSET {
+                    attrs.extend(parse_attrs(bytes, offset + ATTR_HEADER_OFFSET)?);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(attrs)
+}
+
+/// Parses the extended attributes macOS appends to the Finder info, at `header` in `bytes`.
+fn parse_attrs(bytes: &[u8], header: usize) -> Result<Vec<(String, Vec<u8>)>> {
+    if be_u32(bytes, header)? != ATTR_MAGIC {
+        return Ok(Vec::new());
+    }
+    let count = usize::from(be_u16(bytes, header + 34)?);
+    let mut at = header + 36;
+    let mut attrs = Vec::with_capacity(count);
+    for _ in 0..count {
+        let offset = be_u32(bytes, at)? as usize;
+        let len = be_u32(bytes, at + 4)? as usize;
+        let name_len = usize::from(*slice(bytes, at + 10, 1)?.first().unwrap());
+        let name = slice(bytes, at + 11, name_len)?;
+        let name = String::from_utf8(name.strip_suffix(&[0]).unwrap_or(name).to_vec())?;
+        attrs.push((name, slice(bytes, offset, len)?.to_vec()));
+        // entries are padded to four bytes.
+        at = (at + 11 + name_len + 3) & !3;
+    }
+    Ok(attrs)
+}
+
+fn slice(bytes: &[u8], at: usize, len: usize) -> Result<&[u8]> {
+    bytes
+        .get(at..at.saturating_add(len))
+        .ok_or_else(|| eyre!("truncated AppleDouble file"))
+}
+
+fn be_u16(bytes: &[u8], at: usize) -> Result<u16> {
+    Ok(u16::from_be_bytes(slice(bytes, at, 2)?.try_into()?))
+}
+
+fn be_u32(bytes: &[u8], at: usize) -> Result<u32> {
+    Ok(u32::from_be_bytes(slice(bytes, at, 4)?.try_into()?))
+}
+
+/// Linux only allows unprivileged extended attributes in the `user.` namespace.
+#[cfg(unix)]
+fn set_xattr(target: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+    let name = if cfg!(target_os = "macos") {
+        name.to_string()
+    } else {
+        format!("user.{name}")
+    };
+    xattr::set(target, name, value)
+}
+
+#[cfg(not(unix))]
+fn set_xattr(_target: &Path, _name: &str, _value: &[u8]) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "extended attributes are only supported on Unix",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An AppleDouble file with a resource fork and one extended attribute, laid out as macOS
+    /// writes them.
+    fn apple_double() -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend(MAGIC.to_be_bytes());
+        bytes.extend(0x0002_0000u32.to_be_bytes());
+        bytes.extend([0; 16]);
+        bytes.extend(2u16.to_be_bytes());
+        // entries: Finder info at 50, resource fork after it.
+        let finder_info_len = ATTR_HEADER_OFFSET as u32 + 36 + 16 + 4;
+        bytes.extend(FINDER_INFO.to_be_bytes());
+        bytes.extend(50u32.to_be_bytes());
+        bytes.extend(finder_info_len.to_be_bytes());
+        bytes.extend(RESOURCE_FORK.to_be_bytes());
+        bytes.extend((50 + finder_info_len).to_be_bytes());
+        bytes.extend(4u32.to_be_bytes());
+        assert_eq!(bytes.len(), 50);
+
+        bytes.extend([0; FINDER_INFO_LEN + 2]);
+        let value_at = 50 + ATTR_HEADER_OFFSET as u32 + 36 + 16;
+        bytes.extend(ATTR_MAGIC.to_be_bytes());
+        bytes.extend([0; 30]);
+        bytes.extend(1u16.to_be_bytes());
+        bytes.extend(value_at.to_be_bytes());
+        bytes.extend(4u32.to_be_bytes());
+        bytes.extend([0, 0, 5]);
+        bytes.extend(b"tags\0");
+        bytes.extend(b"blue");
+        bytes.extend(b"fork");
+        bytes
+    }
+
+    #[test]
+    fn companions_are_found_and_parsed() {
+        assert_eq!(
+            data_file_of(Path::new("2024/._IMG_0001.HEIC")),
+            Some(PathBuf::from("2024/IMG_0001.HEIC"))
+        );
+        assert_eq!(data_file_of(Path::new("IMG_0001.HEIC")), None);
+        assert_eq!(
+            companion_of(Path::new("2024/IMG_0001.HEIC")),
+            Some(PathBuf::from("2024/._IMG_0001.HEIC"))
+        );
+
+        assert_eq!(
+            parse(&apple_double()).unwrap(),
+            [
+                ("tags".to_string(), b"blue".to_vec()),
+                ("com.apple.ResourceFork".to_string(), b"fork".to_vec()),
+            ]
+        );
+        assert!(parse(b"not apple double").is_err());
+    }
+}
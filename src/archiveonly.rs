@@ -0,0 +1,111 @@
+//! Reporting archived content with no copy left in the in directory, i.e. the photos which exist
+//! only in the archive and most need a backup of their own.
+
+use std::{collections::HashSet, path::Path};
+
+use eyre::Result;
+
+use crate::{
+    ArchiveOnlyArgs,
+    store::{Archive, CataloguedFile, PhotoSyncStore, SourceFile},
+};
+
+pub fn archive_only(args: ArchiveOnlyArgs) -> Result<()> {
+    let store = PhotoSyncStore::new(args.database_file)?;
+    let archived = store
+        .old_target_files()?
+        .into_iter()
+        .map(|file| (Archive::OldOut, file))
+        .chain(store.target_files()?.into_iter().map(|f| (Archive::Out, f)));
+    let only = find_archive_only(
+        store.source_files()?,
+        &args.in_dir,
+        &args.machine_id,
+        archived,
+    );
+
+    let mut contents = HashSet::new();
+    let mut bytes = 0;
+    for (archive, file) in &only {
+        let dir = match archive {
+            Archive::OldOut => "old",
+            Archive::Out => "out",
+        };
+        println!("ARCHIVE ONLY {dir}:{:?} ({})", file.path, file.digest);
+        if contents.insert(file.digest) {
+            bytes += file.size;
+        }
+    }
+    println!(
+        "{} archived files ({} distinct, {}MB) have no copy left in {:?}",
+        only.len(),
+        contents.len(),
+        bytes / 1_000_000,
+        args.in_dir
+    );
+    Ok(())
+}
+
+/// The archived files whose content isn't in any file transferred from `in_dir` which is still
+/// there. Files are assumed unchanged since they were transferred, as a sync would have noticed.
+fn find_archive_only(
+    sources: Vec<SourceFile>,
+    in_dir: &Path,
+    namespace: &str,
+    archived: impl Iterator<Item = (Archive, CataloguedFile)>,
+) -> Vec<(Archive, CataloguedFile)> {
+    let in_source: HashSet<_> = sources
+        .into_iter()
+        .filter(|file| file.namespace == namespace && in_dir.join(&file.path).exists())
+        .map(|file| file.digest)
+        .collect();
+    archived
+        .filter(|(_, file)| !in_source.contains(&file.digest))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf, time::SystemTime};
+
+    use super::*;
+    use crate::digest::ContentHash;
+
+    #[test]
+    fn content_without_a_source_is_archive_only() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("kept.jpg"), "a").unwrap();
+        let source = |path: &str, id| SourceFile {
+            namespace: "laptop".to_string(),
+            path: PathBuf::from(path),
+            size: 1,
+            digest: ContentHash::new_for_tests(id),
+            in_old_target: false,
+        };
+        let archived = |path: &str, id| CataloguedFile {
+            path: PathBuf::from(path),
+            mtime: SystemTime::UNIX_EPOCH,
+            size: 1,
+            digest: ContentHash::new_for_tests(id),
+        };
+        let only = find_archive_only(
+            vec![source("kept.jpg", 1), source("deleted.jpg", 2)],
+            dir.path(),
+            "laptop",
+            [
+                (Archive::Out, archived("kept.jpg", 1)),
+                (Archive::Out, archived("deleted.jpg", 2)),
+                (Archive::OldOut, archived("scanned in 1998.jpg", 3)),
+            ]
+            .into_iter(),
+        );
+        let paths: Vec<_> = only.iter().map(|(_, file)| file.path.clone()).collect();
+        assert_eq!(
+            paths,
+            [
+                PathBuf::from("deleted.jpg"),
+                PathBuf::from("scanned in 1998.jpg")
+            ]
+        );
+    }
+}
@@ -0,0 +1,418 @@
+//! Where a sync archives the files it transfers: the out directory, or with `--out-url`, an
+//! S3-compatible bucket such as Backblaze B2 or a NAS reached over SFTP. Either way, what's stored
+//! is catalogued in the same store, by its path relative to the archive's root.
+
+use std::{
+    env, fmt, fs,
+    io::{self, Write},
+    path::{Component, Path, PathBuf},
+    process::{Command, Stdio},
+    str::FromStr,
+    time::SystemTime,
+};
+
+use chrono::DateTime;
+use eyre::{Result, bail, eyre};
+use tempfile::NamedTempFile;
+
+use crate::{
+    SyncArgs,
+    compress::{self, Transform},
+    digest::{ContentHash, HashAlgorithm},
+    paranoid,
+    platform::{FileInfo, create_archive_dirs, set_archive_permissions},
+    sftp::{SftpTarget, SftpUrl},
+};
+
+/// The target side of a transfer.
+pub trait TargetBackend: Send + Sync {
+    /// Where the file stored as `path` is, for the log and hooks.
+    fn location(&self, path: &Path) -> PathBuf;
+
+    /// Whether anything is stored as `path`.
+    fn exists(&self, path: &Path) -> Result<bool>;
+
+    /// When the file stored as `path` was last modified, or `None` if nothing is stored as it.
+    fn modified(&self, path: &Path) -> Result<Option<SystemTime>>;
+
+    /// Stores `staged` as `path`, failing with [`io::ErrorKind::AlreadyExists`] if something
+    /// already is, and returns when the stored copy was last modified.
+    fn put(&self, staged: NamedTempFile, path: &Path) -> io::Result<SystemTime>;
+
+    /// The digest of the content stored as `path`, undoing `transform`, how it was stored.
+    fn digest(
+        &self,
+        path: &Path,
+        transform: Transform,
+        algorithm: HashAlgorithm,
+    ) -> Result<ContentHash>;
+
+    /// Removes what's stored as `path`, e.g. a copy which didn't read back as it was written.
+    fn remove(&self, path: &Path) -> Result<()>;
+}
+
+/// An `--out-url`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OutUrl {
+    S3(S3Url),
+    Sftp(SftpUrl),
+}
+
+impl FromStr for OutUrl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once("://") {
+            Some(("s3", _)) => s.parse().map(Self::S3),
+            Some(("sftp", _)) => s.parse().map(Self::Sftp),
+            _ => Err(format!("{s:?} isn't an s3:// or sftp:// URL")),
+        }
+    }
+}
+
+/// The backend `args` archive to.
+pub fn open(args: &SyncArgs) -> Result<Box<dyn TargetBackend>> {
+    Ok(match (&args.out_dir, &args.out_url) {
+        (_, Some(OutUrl::S3(url))) => {
+            Box::new(S3Bucket::new(url.clone(), args.s3_endpoint.clone())?)
+        }
+        (_, Some(OutUrl::Sftp(url))) => Box::new(SftpTarget::new(url.clone())),
+        (Some(dir), None) => Box::new(LocalDir {
+            dir: dir.clone(),
+            fsync: args.fsync || args.paranoid,
+            paranoid: args.paranoid,
+        }),
+        (None, None) => bail!("an out directory or out URL is needed"),
+    })
+}
+
+/// The out directory, which copies are renamed into from the temp directory.
+pub struct LocalDir {
+    pub dir: PathBuf,
+    /// Whether the directory entries of copies are flushed to disk once in place, for `--fsync`.
+    pub fsync: bool,
+    /// Whether copies are dropped from the page cache once in place, for `--paranoid`.
+    pub paranoid: bool,
+}
+
+impl TargetBackend for LocalDir {
+    fn location(&self, path: &Path) -> PathBuf {
+        self.dir.join(path)
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool> {
+        Ok(self.dir.join(path).exists())
+    }
+
+    fn modified(&self, path: &Path) -> Result<Option<SystemTime>> {
+        match FileInfo::of(&self.dir.join(path)) {
+            Ok(info) => Ok(Some(info.modified)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn put(&self, staged: NamedTempFile, path: &Path) -> io::Result<SystemTime> {
+        let out_path = self.dir.join(path);
+        if let Some(parent) = out_path.parent() {
+            create_archive_dirs(&self.dir, parent)?;
+        }
+        staged.persist_noclobber(&out_path).map_err(|e| e.error)?;
+        set_archive_permissions(&out_path)?;
+        if self.fsync {
+            paranoid::sync_parent(&out_path)?;
+        }
+        if self.paranoid {
+            paranoid::evict_from_cache(&out_path)?;
+        }
+        Ok(FileInfo::of(&out_path)?.modified)
+    }
+
+    fn digest(
+        &self,
+        path: &Path,
+        transform: Transform,
+        algorithm: HashAlgorithm,
+    ) -> Result<ContentHash> {
+        compress::digest_archived(&self.dir.join(path), transform, algorithm)
+    }
+
+    fn remove(&self, path: &Path) -> Result<()> {
+        Ok(fs::remove_file(self.dir.join(path))?)
+    }
+}
+
+/// An `--out-url`, given as `s3://<bucket>/<prefix>`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct S3Url {
+    pub bucket: String,
+    /// Prepended to the path of each file to make its key, without a trailing `/`.
+    pub prefix: String,
+}
+
+impl FromStr for S3Url {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some(rest) = s.strip_prefix("s3://") else {
+            return Err(format!("{s:?} isn't an s3://<bucket>/<prefix> URL"));
+        };
+        let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+        if bucket.is_empty() {
+            return Err(format!("{s:?} names no bucket"));
+        }
+        Ok(Self {
+            bucket: bucket.to_string(),
+            prefix: prefix.trim_matches('/').to_string(),
+        })
+    }
+}
+
+impl fmt::Display for S3Url {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "s3://{}/{}", self.bucket, self.prefix)
+    }
+}
+
+/// A bucket reached through `curl`, which signs requests with the credentials in
+/// AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY.
+pub struct S3Bucket {
+    url: S3Url,
+    endpoint: String,
+    region: String,
+    /// `<access key>:<secret key>`.
+    credentials: String,
+}
+
+impl S3Bucket {
+    /// Archives to `url`, at `endpoint` if given, or else AWS's for AWS_REGION.
+    pub fn new(url: S3Url, endpoint: Option<String>) -> Result<Self> {
+        let var =
+            |name| env::var(name).map_err(|_| eyre!("{name} must be set to archive to {url}"));
+        let credentials = format!(
+            "{}:{}",
+            var("AWS_ACCESS_KEY_ID")?,
+            var("AWS_SECRET_ACCESS_KEY")?
+        );
+        let region = env::var("AWS_REGION").unwrap_or_else(|_| "us-east-1".into());
+        let endpoint = endpoint.unwrap_or_else(|| format!("https://s3.{region}.amazonaws.com"));
+        Ok(Self {
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            url,
+            region,
+            credentials,
+        })
+    }
+
+    fn key(&self, path: &Path) -> String {
+        let parts = path.components().filter_map(|component| match component {
+            Component::Normal(part) => Some(part.to_string_lossy()),
+            _ => None,
+        });
+        let mut key = self.url.prefix.clone();
+        for part in parts {
+            if !key.is_empty() {
+                key.push('/');
+            }
+            key.push_str(&part);
+        }
+        key
+    }
+
+    /// The path-style URL of the object stored as `path`, which works with every S3-compatible
+    /// service, unlike bucket subdomains.
+    fn object_url(&self, path: &Path) -> String {
+        format!(
+            "{}/{}/{}",
+            self.endpoint,
+            self.url.bucket,
+            encode_key(&self.key(path))
+        )
+    }
+
+    /// Makes a request for `path`, returning its status and the body or headers curl printed.
+    fn request(&self, path: &Path, args: &[&str]) -> io::Result<(u16, String)> {
+        let url = self.object_url(path);
+        let mut child = Command::new("curl")
+            .args(["--silent", "--show-error", "--config", "-"])
+            .arg("--aws-sigv4")
+            .arg(format!("aws:amz:{}:s3", self.region))
+            .args(["--write-out", "\n%{http_code}"])
+            .args(args)
+            .arg(&url)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| io::Error::other(format!("could not run curl: {e}")))?;
+        // passed on stdin rather than the command line, where other users could see them.
+        let credentials = self.credentials.replace('\\', "\\\\").replace('"', "\\\"");
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        writeln!(stdin, "user = \"{credentials}\"")?;
+        drop(stdin);
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!(
+                "curl could not reach {url}: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        let output = String::from_utf8_lossy(&output.stdout);
+        let (printed, status) = output.rsplit_once('\n').unwrap_or(("", &output));
+        let status = status
+            .trim()
+            .parse()
+            .map_err(|_| io::Error::other(format!("curl gave no status for {url}")))?;
+        Ok((status, printed.to_string()))
+    }
+}
+
+impl TargetBackend for S3Bucket {
+    fn location(&self, path: &Path) -> PathBuf {
+        PathBuf::from(format!("s3://{}/{}", self.url.bucket, self.key(path)))
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool> {
+        Ok(self.modified(path)?.is_some())
+    }
+
+    fn modified(&self, path: &Path) -> Result<Option<SystemTime>> {
+        match self.request(path, &["--head"])? {
+            (200, headers) => {
+                let last_modified = headers
+                    .lines()
+                    .find_map(|line| {
+                        let (name, value) = line.split_once(':')?;
+                        name.eq_ignore_ascii_case("last-modified")
+                            .then(|| DateTime::parse_from_rfc2822(value.trim()).ok())?
+                    })
+                    .ok_or_else(|| eyre!("{:?} has no Last-Modified", self.location(path)))?;
+                Ok(Some(last_modified.into()))
+            }
+            (404, _) => Ok(None),
+            (status, _) => bail!("HEAD {:?} failed with {status}", self.location(path)),
+        }
+    }
+
+    fn put(&self, staged: NamedTempFile, path: &Path) -> io::Result<SystemTime> {
+        let staged = staged.path().to_str().ok_or_else(|| {
+            io::Error::other(format!("the temp directory {staged:?} isn't UTF-8"))
+        })?;
+        // conditional writes keep what another machine may have just stored there.
+        match self.request(
+            path,
+            &["--upload-file", staged, "--header", "If-None-Match: *"],
+        )? {
+            (200, _) => self
+                .modified(path)
+                .map_err(|e| io::Error::other(format!("{e:#}")))?
+                .ok_or_else(|| {
+                    io::Error::other(format!("{:?} wasn't stored", self.location(path)))
+                }),
+            (412, _) => Err(io::ErrorKind::AlreadyExists.into()),
+            (status, body) => Err(io::Error::other(format!(
+                "PUT {:?} failed with {status}: {}",
+                self.location(path),
+                body.trim()
+            ))),
+        }
+    }
+
+    fn digest(
+        &self,
+        path: &Path,
+        transform: Transform,
+        algorithm: HashAlgorithm,
+    ) -> Result<ContentHash> {
+        let dir = tempfile::tempdir()?;
+        let download = dir
+            .path()
+            .join(path.file_name().unwrap_or("object".as_ref()));
+        let output = download
+            .to_str()
+            .ok_or_else(|| eyre!("{download:?} isn't UTF-8"))?;
+        match self.request(path, &["--output", output])? {
+            (200, _) => compress::digest_archived(&download, transform, algorithm),
+            (status, _) => bail!("GET {:?} failed with {status}", self.location(path)),
+        }
+    }
+
+    fn remove(&self, path: &Path) -> Result<()> {
+        match self.request(path, &["--request", "DELETE"])? {
+            (200 | 204, _) => Ok(()),
+            (status, _) => bail!("DELETE {:?} failed with {status}", self.location(path)),
+        }
+    }
+}
+
+/// Percent-encodes an object key for a URL, leaving its `/`s.
+fn encode_key(key: &str) -> String {
+    let mut encoded = String::new();
+    for byte in key.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files_are_stored_once_under_their_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let local = LocalDir {
+            dir: dir.path().join("out"),
+            fsync: false,
+            paranoid: false,
+        };
+        let staged = |content: &str| {
+            let mut staged = NamedTempFile::new_in(dir.path()).unwrap();
+            staged.write_all(content.as_bytes()).unwrap();
+            staged
+        };
+        let path = Path::new("2019/IMG_0001.JPG");
+        assert_eq!(local.modified(path).unwrap(), None);
+        local.put(staged("photo"), path).unwrap();
+        assert!(local.modified(path).unwrap().is_some());
+        assert_eq!(
+            local.put(staged("other"), path).unwrap_err().kind(),
+            io::ErrorKind::AlreadyExists
+        );
+        assert_eq!(
+            local
+                .digest(path, Transform::None, HashAlgorithm::Sha256)
+                .unwrap(),
+            HashAlgorithm::Sha256
+                .digest(&dir.path().join("out").join(path))
+                .unwrap()
+        );
+
+        let url = match "s3://photos/archive/".parse().unwrap() {
+            OutUrl::S3(url) => url,
+            other => panic!("{other:?}"),
+        };
+        assert_eq!(url.to_string(), "s3://photos/archive");
+        assert!("photos/archive".parse::<OutUrl>().is_err());
+        assert!("s3:///archive".parse::<OutUrl>().is_err());
+        let bucket = S3Bucket {
+            url,
+            endpoint: "https://s3.us-west-004.backblazeb2.com".into(),
+            region: "us-west-004".into(),
+            credentials: "key:secret".into(),
+        };
+        assert_eq!(
+            bucket.object_url(Path::new("2019/IMG 0001 (2).JPG")),
+            "https://s3.us-west-004.backblazeb2.com/photos/archive/2019/IMG%200001%20%282%29.JPG"
+        );
+        assert_eq!(
+            bucket.location(Path::new("2019/IMG_0001.JPG")),
+            Path::new("s3://photos/archive/2019/IMG_0001.JPG")
+        );
+    }
+}
@@ -0,0 +1,51 @@
+//! Catalogue writes gathered up to be made together in one transaction, as committing each file's
+//! on its own makes syncing the database to disk most of the work for libraries of small files.
+
+use std::sync::Mutex;
+
+/// How many writes are made in each transaction.
+pub const BATCH_SIZE: usize = 256;
+
+/// Writes waiting to be made, shared by the threads making them.
+pub struct WriteBatch<T> {
+    pending: Mutex<Vec<T>>,
+}
+
+impl<T> Default for WriteBatch<T> {
+    fn default() -> Self {
+        Self {
+            pending: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl<T> WriteBatch<T> {
+    /// Adds `write`, returning the batch to make if that fills it.
+    pub fn push(&self, write: T) -> Option<Vec<T>> {
+        let mut pending = self.pending.lock().unwrap();
+        pending.push(write);
+        (pending.len() >= BATCH_SIZE).then(|| std::mem::take(&mut *pending))
+    }
+
+    /// The writes left over, which didn't fill a batch.
+    pub fn take(&self) -> Vec<T> {
+        std::mem::take(&mut *self.pending.lock().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_are_made_a_batch_at_a_time() {
+        let batch = WriteBatch::default();
+        let batches: Vec<_> = (0..BATCH_SIZE * 2 + 1)
+            .filter_map(|write| batch.push(write))
+            .collect();
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[1][0], BATCH_SIZE);
+        assert_eq!(batch.take(), [BATCH_SIZE * 2]);
+        assert!(batch.take().is_empty());
+    }
+}
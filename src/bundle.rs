@@ -0,0 +1,237 @@
+//! Packing out directory files into numbered tar bundles of a fixed size, for cold storage such as
+//! BD-R discs or Glacier, with the catalogue recording which bundle holds each file.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use eyre::{Result, WrapErr};
+use tempfile::NamedTempFile;
+
+use crate::{
+    BundleArgs,
+    compress::{Transform, digest_archived},
+    digest::{ContentHash, DigestWriter},
+    encrypt::is_encrypted,
+    store::{Bundle, BundleMember, PhotoSyncStore},
+};
+
+/// The size of a tar header, and the block size its contents are padded to.
+const TAR_BLOCK: u64 = 512;
+
+pub fn bundle(args: BundleArgs) -> Result<()> {
+    let store = PhotoSyncStore::new(args.database_file)?;
+    if let Some(path) = &args.find {
+        for (name, digest) in store.bundles_of(path)? {
+            println!("{name} ({digest})");
+        }
+        return Ok(());
+    }
+    let max_bytes = args.bundle_size_mb * 1_000_000;
+
+    let mut groups = Vec::<Vec<BundleMember>>::new();
+    let mut group_bytes = 0;
+    for file in store.files_without_bundle()? {
+        let bytes = tar_size(file.size);
+        match groups.last_mut() {
+            Some(group) if group_bytes + bytes <= max_bytes => {
+                group_bytes += bytes;
+                group.push(file);
+            }
+            _ => {
+                group_bytes = bytes;
+                groups.push(vec![file]);
+            }
+        }
+    }
+    // the last bundle waits to be filled by later runs, unless it's wanted now.
+    if !args.flush {
+        groups.pop();
+    }
+
+    let transcoded = store.transcoded_files()?;
+    let encrypted = store.encrypted_files()?;
+    let transformed = store.transformed_files()?;
+    let mut next = store.bundle_count()? + 1;
+    for group in groups {
+        let name = format!("bundle-{next:06}.tar");
+        if let Some(bundle) = create_bundle(
+            &args.out_dir,
+            &args.bundle_dir,
+            name,
+            group,
+            &transcoded,
+            &encrypted,
+            &transformed,
+        )? {
+            store.add_bundle(&bundle, SystemTime::now())?;
+            println!(
+                "wrote {} ({} files, {}MB)",
+                bundle.name,
+                bundle.members.len(),
+                bundle.size / 1_000_000
+            );
+            next += 1;
+        }
+    }
+    Ok(())
+}
+
+/// How many bytes a file of `size` takes up in a tar.
+fn tar_size(size: u64) -> u64 {
+    TAR_BLOCK + size.div_ceil(TAR_BLOCK) * TAR_BLOCK
+}
+
+/// Writes those of `members` which still match their catalogued digest, or for those which were
+/// transcoded their digest in `transcoded`, or for those which were encrypted the digest of what
+/// was stored in `encrypted`, to a tar named `name`. Those stored otherwise than as they are are
+/// read as `transformed` says.
+fn create_bundle(
+    out_dir: &Path,
+    bundle_dir: &Path,
+    name: String,
+    members: Vec<BundleMember>,
+    transcoded: &HashMap<PathBuf, ContentHash>,
+    encrypted: &HashMap<PathBuf, ContentHash>,
+    transformed: &HashMap<PathBuf, Transform>,
+) -> Result<Option<Bundle>> {
+    let mut intact = Vec::with_capacity(members.len());
+    for member in members {
+        // a damaged copy in cold storage is worse than none, as it would be trusted.
+        let path = out_dir.join(&member.path);
+        let matches = match encrypted.get(&member.path) {
+            // encrypted copies can't be read without an identity, so are checked as stored.
+            Some(stored) => stored.algorithm().digest(&path).ok() == Some(*stored),
+            // those encrypted before what was stored was catalogued are taken on trust.
+            None if is_encrypted(&path) => path.is_file(),
+            None => {
+                let expected = (transcoded.get(&member.path).copied()).unwrap_or(member.digest);
+                let transform = transformed.get(&member.path).copied().unwrap_or_default();
+                digest_archived(&path, transform, expected.algorithm()).ok() == Some(expected)
+            }
+        };
+        if matches {
+            intact.push(member);
+        } else {
+            println!(
+                "{:?} doesn't match the catalogue, leaving it out of bundles",
+                member.path
+            );
+        }
+    }
+    if intact.is_empty() {
+        return Ok(None);
+    }
+
+    let mut staged = NamedTempFile::new_in(bundle_dir)?;
+    let mut tar = tar::Builder::new(DigestWriter::new(staged.as_file_mut()));
+    for member in &intact {
+        let path = out_dir.join(&member.path);
+        tar.append_file(&member.path, &mut File::open(&path)?)
+            .wrap_err_with(|| format!("could not bundle {path:?}"))?;
+    }
+    let digest = tar.into_inner()?.finalise()?;
+    staged.as_file().sync_all()?;
+    let size = staged.as_file().metadata()?.len();
+    staged.persist_noclobber(bundle_dir.join(&name))?;
+    Ok(Some(Bundle {
+        name,
+        digest,
+        size,
+        members: intact,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use super::*;
+    use crate::digest::digest;
+
+    #[test]
+    fn bundles_hold_intact_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (out_dir, bundle_dir) = (dir.path().join("out"), dir.path().join("bundles"));
+        fs::create_dir_all(out_dir.join("2020")).unwrap();
+        fs::create_dir_all(&bundle_dir).unwrap();
+        let store = PhotoSyncStore::new_for_tests().unwrap();
+        let run = store.begin_run("").unwrap();
+        for (path, content) in [("2020/a.jpg", "photo"), ("b.jpg", "another photo")] {
+            let full_path = out_dir.join(path);
+            fs::write(&full_path, content).unwrap();
+            store
+                .mark_exists_in_target(
+                    run,
+                    Path::new(path),
+                    SystemTime::now(),
+                    content.len() as u64,
+                    &digest(&full_path).unwrap(),
+                )
+                .unwrap();
+        }
+        fs::write(out_dir.join("b.jpg"), "rot").unwrap();
+        // catalogued by the digest of the HEIC it was transcoded from.
+        fs::write(out_dir.join("c.jpg"), "jpeg").unwrap();
+        let heic = ContentHash::new_for_tests(1);
+        store
+            .mark_exists_in_target(run, Path::new("c.jpg"), SystemTime::now(), 4, &heic)
+            .unwrap();
+        let output = digest(&out_dir.join("c.jpg")).unwrap();
+        store
+            .record_transcoded(run, Path::new("c.jpg"), &output)
+            .unwrap();
+        // catalogued by the digest of the plaintext, and checked by what was stored.
+        fs::write(out_dir.join("d.jpg.age"), "ciphertext").unwrap();
+        let stored = digest(&out_dir.join("d.jpg.age")).unwrap();
+        fs::write(out_dir.join("e.jpg.age"), "tampered").unwrap();
+        for (path, n) in [("d.jpg.age", 2), ("e.jpg.age", 3)] {
+            let plaintext = ContentHash::new_for_tests(n);
+            store
+                .mark_exists_in_target(run, Path::new(path), SystemTime::now(), 5, &plaintext)
+                .unwrap();
+            store
+                .record_encrypted(run, Path::new(path), &stored)
+                .unwrap();
+        }
+
+        let bundle = create_bundle(
+            &out_dir,
+            &bundle_dir,
+            "bundle-000001.tar".to_string(),
+            store.files_without_bundle().unwrap(),
+            &store.transcoded_files().unwrap(),
+            &store.encrypted_files().unwrap(),
+            &store.transformed_files().unwrap(),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(bundle.members.len(), 3);
+        let bundle_path = bundle_dir.join(&bundle.name);
+        assert_eq!(digest(&bundle_path).unwrap(), bundle.digest);
+        store.add_bundle(&bundle, SystemTime::now()).unwrap();
+        assert_eq!(
+            store.bundles_of(Path::new("2020/a.jpg")).unwrap(),
+            [(bundle.name, bundle.digest)]
+        );
+        assert_eq!(store.files_without_bundle().unwrap().len(), 2);
+
+        let mut archive = tar::Archive::new(File::open(&bundle_path).unwrap());
+        let entries: Vec<_> = archive
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap().path().unwrap().into_owned())
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                PathBuf::from("2020/a.jpg"),
+                PathBuf::from("c.jpg"),
+                PathBuf::from("d.jpg.age")
+            ]
+        );
+    }
+}
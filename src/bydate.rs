@@ -0,0 +1,77 @@
+//! Laying the out directory out by when each photo was taken, as `YYYY/MM/<name>`, for
+//! `--organize-by-date`.
+
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use chrono::{DateTime, Datelike, Local};
+use exif::{In, Reader, Tag, Value};
+
+use crate::collision::free_path;
+
+/// The year and month the file at `path` was taken in, from its EXIF `DateTimeOriginal`, or when
+/// it was last modified if it has none, e.g. videos and screenshots.
+pub fn taken_in(path: &Path, modified: SystemTime) -> (i32, u32) {
+    exif_taken_in(path).unwrap_or_else(|| {
+        let modified = DateTime::<Local>::from(modified);
+        (modified.year(), modified.month())
+    })
+}
+
+fn exif_taken_in(path: &Path) -> Option<(i32, u32)> {
+    let mut reader = BufReader::new(File::open(path).ok()?);
+    let exif = Reader::new().read_from_container(&mut reader).ok()?;
+    let field = exif.get_field(Tag::DateTimeOriginal, In::PRIMARY)?;
+    let Value::Ascii(values) = &field.value else {
+        return None;
+    };
+    let taken = exif::DateTime::from_ascii(values.first()?).ok()?;
+    // cameras whose clock was never set record zeroes.
+    (taken.year > 0 && (1..=12).contains(&taken.month))
+        .then_some((taken.year.into(), taken.month.into()))
+}
+
+/// Where the file which would otherwise go to `destination` goes for the month `taken`. If
+/// `is_taken` says another file already has that name, e.g. two cameras' `IMG_0001.JPG`, it's
+/// numbered as `IMG_0001 (2).JPG`.
+pub fn dated_path(
+    destination: &Path,
+    (year, month): (i32, u32),
+    is_taken: impl Fn(&Path) -> bool,
+) -> PathBuf {
+    let dir = PathBuf::from(format!("{year:04}")).join(format!("{month:02}"));
+    let name = destination.file_name().unwrap_or(destination.as_os_str());
+    free_path(&dir.join(name), is_taken)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn files_are_filed_by_month() {
+        let dir = tempfile::tempdir().unwrap();
+        let photo = dir.path().join("IMG_0001.JPG");
+        std::fs::write(&photo, "no exif here").unwrap();
+        // mid-month, so that it's July in every timezone.
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_689_400_000);
+        let taken = taken_in(&photo, modified);
+        assert_eq!(taken, (2023, 7));
+
+        let destination = Path::new("Camera/IMG_0001.JPG");
+        assert_eq!(
+            dated_path(destination, taken, |_| false),
+            Path::new("2023/07/IMG_0001.JPG")
+        );
+        assert_eq!(
+            dated_path(destination, taken, |p| p.ends_with("IMG_0001.JPG")),
+            Path::new("2023/07/IMG_0001 (2).JPG")
+        );
+    }
+}
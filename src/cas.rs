@@ -0,0 +1,174 @@
+//! `--layout cas`: archiving content at a path derived from its digest, `ab/cd/<digest>.<ext>`,
+//! rather than mirroring the in directory, so that no two contents can collide. Where each source
+//! file's content went is catalogued as it is for the mirrored layout, and `export-view` builds a
+//! tree of links from that which mirrors the in directories, for browsing.
+
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use clap::ValueEnum;
+use eyre::{Result, WrapErr};
+
+use crate::{ExportViewArgs, digest::ContentHash, store::PhotoSyncStore, symlinks};
+
+/// How the out directory is laid out.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Layout {
+    /// Mirror the in directory's paths, as the plugin or `--organize-by-date` change them.
+    #[default]
+    Mirror,
+    /// Name each content after its digest, `ab/cd/<digest>.<ext>`.
+    Cas,
+}
+
+/// How `export-view` links to the archive.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum ViewLink {
+    #[default]
+    Symlink,
+    /// Hardlinks, which need the view on the out directory's filesystem.
+    Hardlink,
+}
+
+/// Where the content `digest`, of the file `original`, is archived in the content-addressed
+/// layout, keeping the file's extension so it still opens as what it is.
+pub fn addressed_path(digest: &ContentHash, original: &Path) -> PathBuf {
+    let hex = digest.as_bytes().iter().fold(String::new(), |mut hex, b| {
+        let _ = write!(hex, "{b:02x}");
+        hex
+    });
+    let mut path = PathBuf::from(&hex[..2]).join(&hex[2..4]).join(&hex);
+    if let Some(extension) = original.extension() {
+        path.set_extension(extension.to_ascii_lowercase());
+    }
+    path
+}
+
+pub fn export_view(args: ExportViewArgs) -> Result<()> {
+    let store = PhotoSyncStore::new(args.database_file)?;
+    let canonical =
+        |dir: &Path| fs::canonicalize(dir).wrap_err_with(|| format!("could not find {dir:?}"));
+    let out_dir = canonical(&args.out_dir)?;
+    let old_out_dir = args.old_out_dir.as_deref().map(canonical).transpose()?;
+    let (mut linked, mut existing, mut unarchived) = (0, 0, 0);
+    for (path, target) in view(&store, &out_dir, old_out_dir.as_deref())? {
+        let Some(target) = target else {
+            unarchived += 1;
+            continue;
+        };
+        let link = args.view_dir.join(&path);
+        if link.symlink_metadata().is_ok() {
+            existing += 1;
+            continue;
+        }
+        // catalogued, but gone from the archive.
+        if !target.is_file() {
+            unarchived += 1;
+            continue;
+        }
+        if let Some(parent) = link.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        match args.link {
+            ViewLink::Symlink => symlinks::symlink(&target, &link),
+            ViewLink::Hardlink => fs::hard_link(&target, &link),
+        }
+        .wrap_err_with(|| format!("could not link {link:?} to {target:?}"))?;
+        linked += 1;
+    }
+    println!(
+        "linked {linked} files into {:?}, {existing} were already there",
+        args.view_dir
+    );
+    if unarchived > 0 {
+        println!("{unarchived} files have no copy in the out directory to link to");
+    }
+    Ok(())
+}
+
+/// Each file transferred from an in directory, under its namespace's directory unless that's the
+/// default, and the file in `out_dir`, or failing that `old_out_dir`, holding its content, if one
+/// does.
+fn view(
+    store: &PhotoSyncStore,
+    out_dir: &Path,
+    old_out_dir: Option<&Path>,
+) -> Result<Vec<(PathBuf, Option<PathBuf>)>> {
+    let mut copies = HashMap::new();
+    for file in store.target_files()? {
+        copies
+            .entry(file.digest)
+            .or_insert_with(|| out_dir.join(file.path));
+    }
+    if let Some(old_out_dir) = old_out_dir {
+        for file in store.old_target_files()? {
+            copies
+                .entry(file.digest)
+                .or_insert_with(|| old_out_dir.join(file.path));
+        }
+    }
+    let view = (store.source_files()?.into_iter())
+        .map(|file| {
+            let path = Path::new(&file.namespace).join(&file.path);
+            (path, copies.get(&file.digest).cloned())
+        })
+        .collect();
+    Ok(view)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+
+    #[test]
+    fn views_mirror_the_in_directories() {
+        let (digest, old) = (
+            ContentHash::new_for_tests(0xab),
+            ContentHash::new_for_tests(2),
+        );
+        let addressed = addressed_path(&digest, Path::new("2019/IMG_0001.HEIC"));
+        assert_eq!(
+            addressed,
+            Path::new("ab/ab").join(format!("{}.heic", "ab".repeat(32)))
+        );
+
+        let store = PhotoSyncStore::new_for_tests().unwrap();
+        let run = store.begin_run("").unwrap();
+        let now = SystemTime::now();
+        store
+            .mark_exists_in_target(run, &addressed, now, 1, &digest)
+            .unwrap();
+        for (namespace, path, digest) in [
+            ("", "2019/IMG_0001.HEIC", digest),
+            ("laptop", "IMG_0001.HEIC", digest),
+            ("", "gone.jpg", ContentHash::new_for_tests(1)),
+            ("", "old.jpg", old),
+        ] {
+            store
+                .mark_transferred_from_source(run, namespace, Path::new(path), &digest, now, 1)
+                .unwrap();
+        }
+        store
+            .mark_exists_in_old_target(run, Path::new("old.jpg"), now, 1, &old)
+            .unwrap();
+        let (out_dir, old_out_dir) = (Path::new("/out"), Path::new("/old"));
+        let mut view = view(&store, out_dir, Some(old_out_dir)).unwrap();
+        view.sort();
+        let addressed = out_dir.join(addressed);
+        assert_eq!(
+            view,
+            [
+                (PathBuf::from("2019/IMG_0001.HEIC"), Some(addressed.clone())),
+                (PathBuf::from("gone.jpg"), None),
+                (PathBuf::from("laptop/IMG_0001.HEIC"), Some(addressed)),
+                (PathBuf::from("old.jpg"), Some(old_out_dir.join("old.jpg"))),
+            ]
+        );
+    }
+}
@@ -0,0 +1,686 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    time::Duration,
+    time::SystemTime,
+};
+
+use eyre::Result;
+
+use crate::{
+    compress::Transform,
+    digest::{ContentHash, HashAlgorithm},
+    failures::FailureKind,
+    metadata::MediaMetadata,
+    partial::PartialDigest,
+    phash::ImageFingerprint,
+    store::{
+        CataloguedFile, ClassifiedOut, DirSignature, IgnoredPath, PendingTransfer, PhotoSyncStore,
+        ReviewDecision, RunCounts, RunId, RunStatus, SourceVersion, TransferredSource,
+        WasTransferredFromSourceResult,
+    },
+};
+
+/// The catalogue queries and updates a sync run depends on. Implemented by the local sqlite store
+/// and by [`crate::remote::RemoteCatalogue`], which forwards them to a catalogue server.
+pub trait Catalogue: Sync {
+    fn exists_in_old_target(
+        &self,
+        path: &Path,
+        last_modified: SystemTime,
+        size: u64,
+    ) -> Result<WasTransferredFromSourceResult>;
+
+    fn mark_exists_in_old_target(
+        &self,
+        run: RunId,
+        path: &Path,
+        last_modified: SystemTime,
+        size: u64,
+        digest: &ContentHash,
+    ) -> Result<()>;
+
+    fn mark_exists_in_old_target_batch(&self, run: RunId, files: &[CataloguedFile]) -> Result<()>;
+
+    fn mark_pending_digest(
+        &self,
+        run: RunId,
+        path: &Path,
+        last_modified: SystemTime,
+        size: u64,
+        partial: Option<&PartialDigest>,
+    ) -> Result<()>;
+
+    fn is_pending_digest(&self, path: &Path, last_modified: SystemTime, size: u64) -> Result<bool>;
+
+    fn has_partial_digests(&self) -> Result<bool>;
+
+    fn pending_paths_with_partial_digest(
+        &self,
+        size: u64,
+        partial: &PartialDigest,
+    ) -> Result<Vec<PathBuf>>;
+
+    fn old_target_paths_with_metadata(
+        &self,
+        last_modified: SystemTime,
+        size: u64,
+    ) -> Result<Vec<PathBuf>>;
+
+    fn old_target_paths_with_digest(&self, digest: &ContentHash) -> Result<Vec<PathBuf>>;
+
+    fn move_old_target(
+        &self,
+        from: &Path,
+        to: &Path,
+        last_modified: SystemTime,
+        size: u64,
+    ) -> Result<bool>;
+
+    fn mark_exists_in_target(
+        &self,
+        run: RunId,
+        path: &Path,
+        last_modified: SystemTime,
+        size: u64,
+        digest: &ContentHash,
+    ) -> Result<()>;
+
+    fn target_paths_with_digest(&self, digest: &ContentHash) -> Result<Vec<PathBuf>>;
+
+    fn has_target_files(&self) -> Result<bool>;
+
+    fn exists_in_target(&self, digest: &ContentHash) -> Result<bool>;
+
+    fn has_target_digests_by(&self, algorithm: HashAlgorithm) -> Result<bool>;
+
+    fn target_digests(&self) -> Result<Vec<ContentHash>>;
+
+    fn was_transferred_from_source(
+        &self,
+        namespace: &str,
+        path: &Path,
+        last_modified: SystemTime,
+        size: u64,
+    ) -> Result<WasTransferredFromSourceResult>;
+
+    fn mark_transferred_from_source(
+        &self,
+        run: RunId,
+        namespace: &str,
+        path: &Path,
+        digest: &ContentHash,
+        last_modified: SystemTime,
+        size: u64,
+    ) -> Result<()>;
+
+    fn mark_transferred_from_source_batch(
+        &self,
+        run: RunId,
+        namespace: &str,
+        sources: &[TransferredSource],
+    ) -> Result<()>;
+
+    fn set_source_target(&self, namespace: &str, path: &Path, target: &Path) -> Result<()>;
+
+    fn source_paths_with_metadata(
+        &self,
+        namespace: &str,
+        last_modified: SystemTime,
+        size: u64,
+    ) -> Result<Vec<PathBuf>>;
+
+    fn source_paths_with_digest(
+        &self,
+        namespace: &str,
+        digest: &ContentHash,
+    ) -> Result<Vec<PathBuf>>;
+
+    fn rename_source(
+        &self,
+        run: RunId,
+        namespace: &str,
+        from: &Path,
+        to: &Path,
+        last_modified: SystemTime,
+        size: u64,
+    ) -> Result<bool>;
+
+    fn update_source(
+        &self,
+        run: RunId,
+        namespace: &str,
+        path: &Path,
+        digest: &ContentHash,
+        last_modified: SystemTime,
+        size: u64,
+    ) -> Result<()>;
+
+    fn next_version(&self, namespace: &str, path: &Path) -> Result<u32>;
+
+    fn record_version(
+        &self,
+        run: RunId,
+        namespace: &str,
+        original: &ContentHash,
+        version: &SourceVersion,
+    ) -> Result<()>;
+
+    fn acquire_lease(&self, name: &str, holder: &str, ttl: Duration) -> Result<()>;
+
+    fn release_lease(&self, name: &str, holder: &str) -> Result<()>;
+
+    fn break_lease(&self, name: &str, machine: &str) -> Result<usize>;
+
+    fn begin_run(&self, namespace: &str) -> Result<RunId>;
+
+    fn finish_run(&self, run: RunId, status: RunStatus) -> Result<()>;
+
+    fn roll_back_run(&self, run: RunId) -> Result<()>;
+
+    fn record_snapshot(&self, run: RunId, snapshot: &str) -> Result<()>;
+
+    fn record_run_args(&self, run: RunId, args: &str) -> Result<()>;
+
+    fn record_collision(
+        &self,
+        run: RunId,
+        namespace: &str,
+        path: &Path,
+        target_path: &Path,
+        resolution: &str,
+    ) -> Result<()>;
+
+    fn record_run_counts(&self, run: RunId, counts: &RunCounts) -> Result<()>;
+
+    fn record_run_failures(&self, run: RunId, kind: FailureKind, files: u64) -> Result<()>;
+
+    fn journal_transfer(
+        &self,
+        run: RunId,
+        namespace: &str,
+        transfer: &PendingTransfer,
+    ) -> Result<()>;
+
+    fn forget_pending_transfer(&self, namespace: &str, path: &Path) -> Result<()>;
+
+    fn pending_transfers(&self, namespace: &str) -> Result<Vec<PendingTransfer>>;
+
+    fn record_live_photo(
+        &self,
+        run: RunId,
+        namespace: &str,
+        still: &Path,
+        video: &Path,
+    ) -> Result<()>;
+
+    fn record_sidecar(
+        &self,
+        run: RunId,
+        namespace: &str,
+        parent: &Path,
+        sidecar: &Path,
+    ) -> Result<()>;
+
+    fn record_failed_transfer(
+        &self,
+        run: RunId,
+        namespace: &str,
+        path: &Path,
+        reason: &str,
+    ) -> Result<()>;
+
+    fn failed_transfers(&self, namespace: &str) -> Result<Vec<PathBuf>>;
+
+    fn forget_failed_transfer(&self, namespace: &str, path: &Path) -> Result<()>;
+
+    fn dir_signatures(&self) -> Result<Vec<(PathBuf, DirSignature)>>;
+
+    fn review_decisions(&self, namespace: &str) -> Result<Vec<(PathBuf, ReviewDecision)>>;
+
+    fn review_decision(&self, namespace: &str, path: &Path) -> Result<Option<ReviewDecision>>;
+
+    fn ignored_paths(&self) -> Result<Vec<IgnoredPath>>;
+
+    fn record_classified_out(&self, namespace: &str, file: &ClassifiedOut) -> Result<()>;
+
+    fn classified_out(&self, namespace: &str) -> Result<Vec<ClassifiedOut>>;
+
+    fn is_tombstoned(&self, digest: &ContentHash) -> Result<bool>;
+
+    fn replace_dir_signatures(&self, signatures: &[(PathBuf, DirSignature)]) -> Result<()>;
+
+    fn record_deleted_source(
+        &self,
+        run: RunId,
+        namespace: &str,
+        path: &Path,
+        digest: &ContentHash,
+        size: u64,
+    ) -> Result<()>;
+
+    fn record_transcoded(&self, run: RunId, path: &Path, output_digest: &ContentHash)
+    -> Result<()>;
+
+    fn transcoded_digest(&self, path: &Path) -> Result<Option<ContentHash>>;
+
+    fn record_encrypted(&self, run: RunId, path: &Path, stored_digest: &ContentHash) -> Result<()>;
+
+    fn record_transform(&self, run: RunId, path: &Path, transform: Transform) -> Result<()>;
+
+    fn transform_of(&self, path: &Path) -> Result<Transform>;
+
+    fn record_media_metadata(&self, digest: &ContentHash, metadata: &MediaMetadata) -> Result<()>;
+
+    fn record_image_fingerprint(
+        &self,
+        digest: &ContentHash,
+        fingerprint: &ImageFingerprint,
+    ) -> Result<()>;
+
+    fn icloud_assets(&self, namespace: &str) -> Result<HashSet<String>>;
+
+    fn record_icloud_asset(
+        &self,
+        namespace: &str,
+        asset_id: &str,
+        path: &Path,
+        digest: &ContentHash,
+    ) -> Result<()>;
+}
+
+impl Catalogue for PhotoSyncStore {
+    fn exists_in_old_target(
+        &self,
+        path: &Path,
+        last_modified: SystemTime,
+        size: u64,
+    ) -> Result<WasTransferredFromSourceResult> {
+        self.exists_in_old_target(path, last_modified, size)
+    }
+
+    fn mark_exists_in_old_target(
+        &self,
+        run: RunId,
+        path: &Path,
+        last_modified: SystemTime,
+        size: u64,
+        digest: &ContentHash,
+    ) -> Result<()> {
+        self.mark_exists_in_old_target(run, path, last_modified, size, digest)
+    }
+
+    fn mark_exists_in_old_target_batch(&self, run: RunId, files: &[CataloguedFile]) -> Result<()> {
+        self.mark_exists_in_old_target_batch(run, files)
+    }
+
+    fn mark_pending_digest(
+        &self,
+        run: RunId,
+        path: &Path,
+        last_modified: SystemTime,
+        size: u64,
+        partial: Option<&PartialDigest>,
+    ) -> Result<()> {
+        self.mark_pending_digest(run, path, last_modified, size, partial)
+    }
+
+    fn is_pending_digest(&self, path: &Path, last_modified: SystemTime, size: u64) -> Result<bool> {
+        self.is_pending_digest(path, last_modified, size)
+    }
+
+    fn has_partial_digests(&self) -> Result<bool> {
+        self.has_partial_digests()
+    }
+
+    fn pending_paths_with_partial_digest(
+        &self,
+        size: u64,
+        partial: &PartialDigest,
+    ) -> Result<Vec<PathBuf>> {
+        self.pending_paths_with_partial_digest(size, partial)
+    }
+
+    fn old_target_paths_with_metadata(
+        &self,
+        last_modified: SystemTime,
+        size: u64,
+    ) -> Result<Vec<PathBuf>> {
+        self.old_target_paths_with_metadata(last_modified, size)
+    }
+
+    fn old_target_paths_with_digest(&self, digest: &ContentHash) -> Result<Vec<PathBuf>> {
+        self.old_target_paths_with_digest(digest)
+    }
+
+    fn move_old_target(
+        &self,
+        from: &Path,
+        to: &Path,
+        last_modified: SystemTime,
+        size: u64,
+    ) -> Result<bool> {
+        self.move_old_target(from, to, last_modified, size)
+    }
+
+    fn mark_exists_in_target(
+        &self,
+        run: RunId,
+        path: &Path,
+        last_modified: SystemTime,
+        size: u64,
+        digest: &ContentHash,
+    ) -> Result<()> {
+        self.mark_exists_in_target(run, path, last_modified, size, digest)
+    }
+
+    fn target_paths_with_digest(&self, digest: &ContentHash) -> Result<Vec<PathBuf>> {
+        self.target_paths_with_digest(digest)
+    }
+
+    fn has_target_files(&self) -> Result<bool> {
+        self.has_target_files()
+    }
+
+    fn exists_in_target(&self, digest: &ContentHash) -> Result<bool> {
+        self.exists_in_target(digest)
+    }
+
+    fn has_target_digests_by(&self, algorithm: HashAlgorithm) -> Result<bool> {
+        self.has_target_digests_by(algorithm)
+    }
+
+    fn target_digests(&self) -> Result<Vec<ContentHash>> {
+        self.target_digests()
+    }
+
+    fn was_transferred_from_source(
+        &self,
+        namespace: &str,
+        path: &Path,
+        last_modified: SystemTime,
+        size: u64,
+    ) -> Result<WasTransferredFromSourceResult> {
+        self.was_transferred_from_source(namespace, path, last_modified, size)
+    }
+
+    fn mark_transferred_from_source(
+        &self,
+        run: RunId,
+        namespace: &str,
+        path: &Path,
+        digest: &ContentHash,
+        last_modified: SystemTime,
+        size: u64,
+    ) -> Result<()> {
+        self.mark_transferred_from_source(run, namespace, path, digest, last_modified, size)
+    }
+
+    fn mark_transferred_from_source_batch(
+        &self,
+        run: RunId,
+        namespace: &str,
+        sources: &[TransferredSource],
+    ) -> Result<()> {
+        self.mark_transferred_from_source_batch(run, namespace, sources)
+    }
+
+    fn set_source_target(&self, namespace: &str, path: &Path, target: &Path) -> Result<()> {
+        self.set_source_target(namespace, path, target)
+    }
+
+    fn source_paths_with_metadata(
+        &self,
+        namespace: &str,
+        last_modified: SystemTime,
+        size: u64,
+    ) -> Result<Vec<PathBuf>> {
+        self.source_paths_with_metadata(namespace, last_modified, size)
+    }
+
+    fn source_paths_with_digest(
+        &self,
+        namespace: &str,
+        digest: &ContentHash,
+    ) -> Result<Vec<PathBuf>> {
+        self.source_paths_with_digest(namespace, digest)
+    }
+
+    fn rename_source(
+        &self,
+        run: RunId,
+        namespace: &str,
+        from: &Path,
+        to: &Path,
+        last_modified: SystemTime,
+        size: u64,
+    ) -> Result<bool> {
+        self.rename_source(run, namespace, from, to, last_modified, size)
+    }
+
+    fn update_source(
+        &self,
+        run: RunId,
+        namespace: &str,
+        path: &Path,
+        digest: &ContentHash,
+        last_modified: SystemTime,
+        size: u64,
+    ) -> Result<()> {
+        self.update_source(run, namespace, path, digest, last_modified, size)
+    }
+
+    fn next_version(&self, namespace: &str, path: &Path) -> Result<u32> {
+        self.next_version(namespace, path)
+    }
+
+    fn record_version(
+        &self,
+        run: RunId,
+        namespace: &str,
+        original: &ContentHash,
+        version: &SourceVersion,
+    ) -> Result<()> {
+        self.record_version(run, namespace, original, version)
+    }
+
+    fn acquire_lease(&self, name: &str, holder: &str, ttl: Duration) -> Result<()> {
+        self.acquire_lease(name, holder, ttl)
+    }
+
+    fn release_lease(&self, name: &str, holder: &str) -> Result<()> {
+        self.release_lease(name, holder)
+    }
+
+    fn break_lease(&self, name: &str, machine: &str) -> Result<usize> {
+        self.break_lease(name, machine)
+    }
+
+    fn begin_run(&self, namespace: &str) -> Result<RunId> {
+        self.begin_run(namespace)
+    }
+
+    fn finish_run(&self, run: RunId, status: RunStatus) -> Result<()> {
+        self.finish_run(run, status)
+    }
+
+    fn roll_back_run(&self, run: RunId) -> Result<()> {
+        self.roll_back_run(run)
+    }
+
+    fn record_snapshot(&self, run: RunId, snapshot: &str) -> Result<()> {
+        self.record_snapshot(run, snapshot)
+    }
+
+    fn record_run_args(&self, run: RunId, args: &str) -> Result<()> {
+        self.record_run_args(run, args)
+    }
+
+    fn record_collision(
+        &self,
+        run: RunId,
+        namespace: &str,
+        path: &Path,
+        target_path: &Path,
+        resolution: &str,
+    ) -> Result<()> {
+        self.record_collision(run, namespace, path, target_path, resolution)
+    }
+
+    fn record_run_counts(&self, run: RunId, counts: &RunCounts) -> Result<()> {
+        self.record_run_counts(run, counts)
+    }
+
+    fn record_run_failures(&self, run: RunId, kind: FailureKind, files: u64) -> Result<()> {
+        self.record_run_failures(run, kind, files)
+    }
+
+    fn journal_transfer(
+        &self,
+        run: RunId,
+        namespace: &str,
+        transfer: &PendingTransfer,
+    ) -> Result<()> {
+        self.journal_transfer(run, namespace, transfer)
+    }
+
+    fn forget_pending_transfer(&self, namespace: &str, path: &Path) -> Result<()> {
+        self.forget_pending_transfer(namespace, path)
+    }
+
+    fn pending_transfers(&self, namespace: &str) -> Result<Vec<PendingTransfer>> {
+        self.pending_transfers(namespace)
+    }
+
+    fn record_live_photo(
+        &self,
+        run: RunId,
+        namespace: &str,
+        still: &Path,
+        video: &Path,
+    ) -> Result<()> {
+        self.record_live_photo(run, namespace, still, video)
+    }
+
+    fn record_sidecar(
+        &self,
+        run: RunId,
+        namespace: &str,
+        parent: &Path,
+        sidecar: &Path,
+    ) -> Result<()> {
+        self.record_sidecar(run, namespace, parent, sidecar)
+    }
+
+    fn record_failed_transfer(
+        &self,
+        run: RunId,
+        namespace: &str,
+        path: &Path,
+        reason: &str,
+    ) -> Result<()> {
+        self.record_failed_transfer(run, namespace, path, reason)
+    }
+
+    fn failed_transfers(&self, namespace: &str) -> Result<Vec<PathBuf>> {
+        self.failed_transfers(namespace)
+    }
+
+    fn forget_failed_transfer(&self, namespace: &str, path: &Path) -> Result<()> {
+        self.forget_failed_transfer(namespace, path)
+    }
+
+    fn dir_signatures(&self) -> Result<Vec<(PathBuf, DirSignature)>> {
+        self.dir_signatures()
+    }
+
+    fn review_decisions(&self, namespace: &str) -> Result<Vec<(PathBuf, ReviewDecision)>> {
+        self.review_decisions(namespace)
+    }
+
+    fn review_decision(&self, namespace: &str, path: &Path) -> Result<Option<ReviewDecision>> {
+        self.review_decision(namespace, path)
+    }
+
+    fn ignored_paths(&self) -> Result<Vec<IgnoredPath>> {
+        self.ignored_paths()
+    }
+
+    fn record_classified_out(&self, namespace: &str, file: &ClassifiedOut) -> Result<()> {
+        self.record_classified_out(namespace, file)
+    }
+
+    fn classified_out(&self, namespace: &str) -> Result<Vec<ClassifiedOut>> {
+        self.classified_out(namespace)
+    }
+
+    fn is_tombstoned(&self, digest: &ContentHash) -> Result<bool> {
+        self.is_tombstoned(digest)
+    }
+
+    fn replace_dir_signatures(&self, signatures: &[(PathBuf, DirSignature)]) -> Result<()> {
+        self.replace_dir_signatures(signatures)
+    }
+
+    fn record_deleted_source(
+        &self,
+        run: RunId,
+        namespace: &str,
+        path: &Path,
+        digest: &ContentHash,
+        size: u64,
+    ) -> Result<()> {
+        self.record_deleted_source(run, namespace, path, digest, size)
+    }
+
+    fn record_transcoded(
+        &self,
+        run: RunId,
+        path: &Path,
+        output_digest: &ContentHash,
+    ) -> Result<()> {
+        self.record_transcoded(run, path, output_digest)
+    }
+
+    fn transcoded_digest(&self, path: &Path) -> Result<Option<ContentHash>> {
+        self.transcoded_digest(path)
+    }
+
+    fn record_encrypted(&self, run: RunId, path: &Path, stored_digest: &ContentHash) -> Result<()> {
+        self.record_encrypted(run, path, stored_digest)
+    }
+
+    fn record_transform(&self, run: RunId, path: &Path, transform: Transform) -> Result<()> {
+        self.record_transform(run, path, transform)
+    }
+
+    fn transform_of(&self, path: &Path) -> Result<Transform> {
+        self.transform_of(path)
+    }
+
+    fn record_media_metadata(&self, digest: &ContentHash, metadata: &MediaMetadata) -> Result<()> {
+        self.record_media_metadata(digest, metadata)
+    }
+
+    fn record_image_fingerprint(
+        &self,
+        digest: &ContentHash,
+        fingerprint: &ImageFingerprint,
+    ) -> Result<()> {
+        self.record_image_fingerprint(digest, fingerprint)
+    }
+
+    fn icloud_assets(&self, namespace: &str) -> Result<HashSet<String>> {
+        self.icloud_assets(namespace)
+    }
+
+    fn record_icloud_asset(
+        &self,
+        namespace: &str,
+        asset_id: &str,
+        path: &Path,
+        digest: &ContentHash,
+    ) -> Result<()> {
+        self.record_icloud_asset(namespace, asset_id, path, digest)
+    }
+}
@@ -0,0 +1,138 @@
+//! `manifest`: a `SHA256SUMS` file of everything catalogued in the out directory, written from the
+//! catalogue without hashing anything, so the archive can be checked with `sha256sum -c` (or
+//! `shasum -c` on BSD and macOS) on a machine without this tool. Copies which are compressed,
+//! encrypted or chunked are left out, as what's catalogued is the digest of what they hold, as are
+//! those hashed with something other than SHA-256. Transcoded copies are listed with the digest of
+//! what they were transcoded to.
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use clap::ValueEnum;
+use eyre::{Result, WrapErr};
+use tracing::warn;
+
+use crate::{
+    ChecksumManifestArgs, digest::HashAlgorithm, encrypt::is_encrypted, store::PhotoSyncStore,
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum ChecksumStyle {
+    /// `<digest>  <path>`, as `sha256sum` writes.
+    #[default]
+    Gnu,
+    /// `SHA256 (<path>) = <digest>`, as `sha256sum --tag` and BSD's `sha256` write.
+    Bsd,
+}
+
+pub fn checksum_manifest(args: ChecksumManifestArgs) -> Result<()> {
+    let store = PhotoSyncStore::new(args.database_file)?;
+    let mut files = store.target_files()?;
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    let transcoded = store.transcoded_files()?;
+    let transformed = store.transformed_files()?;
+    let mut left_out = 0;
+    let listed = files.iter().filter_map(|file| {
+        // a transcoded copy holds what it was transcoded to, not what it's catalogued as.
+        let digest = transcoded.get(&file.path).copied().unwrap_or(file.digest);
+        let plain = digest.algorithm() == HashAlgorithm::Sha256
+            && !transformed.contains_key(&file.path)
+            && !is_encrypted(&file.path);
+        left_out += usize::from(!plain);
+        plain.then(|| (file.path.as_path(), digest.to_string()))
+    });
+    match &args.output {
+        Some(output) => {
+            let file =
+                File::create(output).wrap_err_with(|| format!("could not create {output:?}"))?;
+            write_checksums(BufWriter::new(file), args.style, listed)?
+        }
+        None => write_checksums(io::stdout().lock(), args.style, listed)?,
+    }
+    if left_out > 0 {
+        warn!("left out {left_out} files not archived as they are or not hashed with SHA-256");
+    }
+    Ok(())
+}
+
+/// Writes a line for each of `files`, a path relative to the out directory and its hex digest.
+fn write_checksums<'a>(
+    mut writer: impl Write,
+    style: ChecksumStyle,
+    files: impl Iterator<Item = (&'a Path, String)>,
+) -> Result<()> {
+    for (path, digest) in files {
+        let (escaped, name) = escape(path);
+        if escaped {
+            writer.write_all(b"\\")?;
+        }
+        match style {
+            ChecksumStyle::Gnu => {
+                writer.write_all(format!("{digest}  ").as_bytes())?;
+                writer.write_all(&name)?;
+            }
+            ChecksumStyle::Bsd => {
+                writer.write_all(b"SHA256 (")?;
+                writer.write_all(&name)?;
+                writer.write_all(format!(") = {digest}").as_bytes())?;
+            }
+        }
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// `path`'s bytes as coreutils writes them in a checksum file, and whether they had to be escaped,
+/// which the line is then marked as by starting with a backslash.
+fn escape(path: &Path) -> (bool, Vec<u8>) {
+    #[cfg(unix)]
+    let bytes = {
+        use std::os::unix::ffi::OsStrExt;
+        path.as_os_str().as_bytes().to_vec()
+    };
+    #[cfg(not(unix))]
+    let bytes = path.to_string_lossy().replace('\\', "/").into_bytes();
+    if !bytes.iter().any(|b| matches!(b, b'\\' | b'\n' | b'\r')) {
+        return (false, bytes);
+    }
+    let mut escaped = Vec::with_capacity(bytes.len() + 2);
+    for byte in bytes {
+        match byte {
+            b'\\' => escaped.extend_from_slice(b"\\\\"),
+            b'\n' => escaped.extend_from_slice(b"\\n"),
+            b'\r' => escaped.extend_from_slice(b"\\r"),
+            byte => escaped.push(byte),
+        }
+    }
+    (true, escaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_are_as_coreutils_writes_them() {
+        let digest = "ab".repeat(32);
+        let files = [
+            (Path::new("2019/IMG_0001.JPG"), digest.clone()),
+            (Path::new("2019/odd\nname.jpg"), digest.clone()),
+        ];
+        let mut gnu = Vec::new();
+        write_checksums(&mut gnu, ChecksumStyle::Gnu, files.iter().cloned()).unwrap();
+        assert_eq!(
+            String::from_utf8(gnu).unwrap(),
+            format!("{digest}  2019/IMG_0001.JPG\n\\{digest}  2019/odd\\nname.jpg\n")
+        );
+        let mut bsd = Vec::new();
+        write_checksums(&mut bsd, ChecksumStyle::Bsd, files[..1].iter().cloned()).unwrap();
+        assert_eq!(
+            String::from_utf8(bsd).unwrap(),
+            format!("SHA256 (2019/IMG_0001.JPG) = {digest}\n")
+        );
+    }
+}
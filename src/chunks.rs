@@ -0,0 +1,331 @@
+//! Storing archived copies as content-defined chunks in a repository in the out directory, so that
+//! files which are mostly the same (e.g. a re-edited video) share their storage. Chunks are
+//! appended to numbered pack files, and each archived file is replaced by a manifest of its chunks.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Take, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use eyre::{Result, eyre};
+use tempfile::NamedTempFile;
+use walkdir::DirEntry;
+
+use crate::digest::{ContentHash, digest_reader};
+
+/// Added to the names of chunk manifests.
+const EXTENSION: &str = "chunks";
+/// The repository's directory in the out directory.
+const REPOSITORY_DIR: &str = ".chunk-repository";
+const MIN_CHUNK: usize = 256 * 1024;
+const MAX_CHUNK: usize = 4 * 1024 * 1024;
+/// Cuts chunks an average of 1MiB past the minimum.
+const BOUNDARY_MASK: u64 = (1 << 20) - 1;
+/// Packs are started afresh past this size.
+const PACK_SIZE: u64 = 128 * 1024 * 1024;
+
+/// Random values for the gear hash, from splitmix64 so they never change between versions.
+const GEAR: [u64; 256] = {
+    let mut table = [0; 256];
+    let mut state: u64 = 0;
+    let mut idx = 0;
+    while idx < 256 {
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        table[idx] = z ^ (z >> 31);
+        idx += 1;
+    }
+    table
+};
+
+/// Where a chunk is, as written in the index and in manifests.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct ChunkLocation {
+    pack: u32,
+    offset: u64,
+    length: u64,
+}
+
+pub struct ChunkRepository {
+    root: PathBuf,
+    state: Mutex<State>,
+}
+
+struct State {
+    index: HashMap<ContentHash, ChunkLocation>,
+    index_file: File,
+    pack: u32,
+    pack_file: File,
+}
+
+impl ChunkRepository {
+    /// Opens the repository in `out_dir`, creating it if need be.
+    pub fn open(out_dir: &Path) -> Result<Self> {
+        let root = out_dir.join(REPOSITORY_DIR);
+        fs::create_dir_all(root.join("packs"))?;
+        let index_path = root.join("index");
+        let mut index = HashMap::new();
+        if index_path.exists() {
+            for line in BufReader::new(File::open(&index_path)?).lines() {
+                let (digest, location) = parse_line(&line?)?;
+                index.insert(digest, location);
+            }
+        }
+        let pack = index.values().map(|l| l.pack).max().unwrap_or(1);
+        let state = State {
+            index,
+            index_file: OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(index_path)?,
+            pack,
+            pack_file: open_pack(&root, pack)?,
+        };
+        Ok(Self {
+            root,
+            state: Mutex::new(state),
+        })
+    }
+
+    /// Stores the content of `from` as chunks, returning its manifest staged in `temp_dir`.
+    pub fn store(&self, from: &Path, temp_dir: &Path) -> Result<NamedTempFile> {
+        let mut reader = BufReader::new(File::open(from)?);
+        let mut manifest = NamedTempFile::new_in(temp_dir)?;
+        let mut new_lines = String::new();
+        let mut chunk = Vec::with_capacity(MAX_CHUNK);
+
+        loop {
+            // the file is read and hashed unlocked, so other files can be stored alongside it.
+            next_chunk(&mut reader, &mut chunk)?;
+            if chunk.is_empty() {
+                break;
+            }
+            let digest = digest_reader(&mut &chunk[..])?;
+            let mut state = self.state.lock().unwrap();
+            let location = match state.index.get(&digest) {
+                Some(location) => *location,
+                None => {
+                    let location = state.append(&self.root, &chunk)?;
+                    state.index.insert(digest, location);
+                    new_lines.push_str(&format_line(&digest, &location));
+                    location
+                }
+            };
+            drop(state);
+            manifest.write_all(format_line(&digest, &location).as_bytes())?;
+        }
+        // a chunk another file found in the index may not be synced yet, but this sync is of the
+        // pack it's in, or of a later one, and packs are synced before they're moved on from.
+        let mut state = self.state.lock().unwrap();
+        state.pack_file.sync_data()?;
+        state.index_file.write_all(new_lines.as_bytes())?;
+        state.index_file.sync_data()?;
+        drop(state);
+
+        manifest.as_file().sync_all()?;
+        Ok(manifest)
+    }
+}
+
+impl State {
+    fn append(&mut self, root: &Path, chunk: &[u8]) -> Result<ChunkLocation> {
+        let mut offset = self.pack_file.metadata()?.len();
+        if offset >= PACK_SIZE {
+            self.pack_file.sync_data()?;
+            self.pack += 1;
+            self.pack_file = open_pack(root, self.pack)?;
+            offset = 0;
+        }
+        self.pack_file.write_all(chunk)?;
+        Ok(ChunkLocation {
+            pack: self.pack,
+            offset,
+            length: chunk.len() as u64,
+        })
+    }
+}
+
+fn pack_path(root: &Path, pack: u32) -> PathBuf {
+    root.join("packs").join(format!("{pack:06}.pack"))
+}
+
+fn open_pack(root: &Path, pack: u32) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(pack_path(root, pack))
+}
+
+fn format_line(digest: &ContentHash, location: &ChunkLocation) -> String {
+    format!(
+        "{digest} {} {} {}\n",
+        location.pack, location.offset, location.length
+    )
+}
+
+fn parse_line(line: &str) -> Result<(ContentHash, ChunkLocation)> {
+    let bad = || eyre!("bad chunk entry {line:?}");
+    let mut fields = line.split(' ');
+    let mut field = || fields.next().ok_or_else(bad);
+    let digest = field()?.parse()?;
+    let location = ChunkLocation {
+        pack: field()?.parse()?,
+        offset: field()?.parse()?,
+        length: field()?.parse()?,
+    };
+    Ok((digest, location))
+}
+
+/// Reads the next chunk of `reader` into `chunk`, which is left empty at the end. Chunks end where
+/// a gear hash of the bytes before hits a boundary, so edits only change the chunks they're in.
+fn next_chunk(reader: &mut impl BufRead, chunk: &mut Vec<u8>) -> io::Result<()> {
+    chunk.clear();
+    let mut hash: u64 = 0;
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let mut used = 0;
+        let mut boundary = false;
+        for &byte in buf {
+            used += 1;
+            hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+            let len = chunk.len() + used;
+            if (len >= MIN_CHUNK && hash & BOUNDARY_MASK == 0) || len >= MAX_CHUNK {
+                boundary = true;
+                break;
+            }
+        }
+        chunk.extend_from_slice(&buf[..used]);
+        reader.consume(used);
+        if boundary {
+            return Ok(());
+        }
+    }
+}
+
+/// Where the manifest of a file destined for `path` goes.
+pub fn chunked_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".");
+    name.push(EXTENSION);
+    PathBuf::from(name)
+}
+
+pub fn is_chunked(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == EXTENSION)
+}
+
+/// Whether a walk of an archive directory has reached its chunk repository, whose packs are
+/// catalogued through the manifests referring to them rather than as files.
+pub fn is_repository(entry: &DirEntry) -> bool {
+    entry.depth() == 1 && entry.file_name() == REPOSITORY_DIR
+}
+
+/// Reads a file back from its manifest, from the repository in a directory above it.
+pub fn open_chunked(manifest: &Path) -> io::Result<ChunkedReader> {
+    let root = manifest
+        .ancestors()
+        .map(|dir| dir.join(REPOSITORY_DIR))
+        .find(|root| root.is_dir())
+        .ok_or_else(|| io::Error::other(format!("no chunk repository holds {manifest:?}")))?;
+    let chunks = BufReader::new(File::open(manifest)?)
+        .lines()
+        .map(|line| parse_line(&line?).map_err(io::Error::other))
+        .collect::<io::Result<_>>()?;
+    Ok(ChunkedReader {
+        root,
+        chunks,
+        current: None,
+    })
+}
+
+/// The content of a chunked file, read a chunk at a time.
+pub struct ChunkedReader {
+    root: PathBuf,
+    chunks: VecDeque<(ContentHash, ChunkLocation)>,
+    current: Option<Take<File>>,
+}
+
+impl Read for ChunkedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(current) = &mut self.current {
+                let read = current.read(buf)?;
+                if read > 0 || buf.is_empty() {
+                    return Ok(read);
+                }
+            }
+            let Some((_, location)) = self.chunks.pop_front() else {
+                return Ok(0);
+            };
+            let mut pack = File::open(pack_path(&self.root, location.pack))?;
+            pack.seek(SeekFrom::Start(location.offset))?;
+            self.current = Some(pack.take(location.length));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::digest::digest;
+
+    #[test]
+    fn similar_files_share_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let out_dir = dir.path();
+        // xorshift, so the content has no runs that would make chunking degenerate.
+        let mut state: u64 = 1;
+        let original: Vec<u8> = (0..6 * 1024 * 1024)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        let mut edited = original.clone();
+        edited.splice(3_000_000..3_000_000, *b"an edit in the middle");
+        fs::write(out_dir.join("original.mov"), &original).unwrap();
+        fs::write(out_dir.join("edited.mov"), &edited).unwrap();
+
+        let pack_bytes = || {
+            let pack = pack_path(&out_dir.join(REPOSITORY_DIR), 1);
+            pack.metadata().unwrap().len()
+        };
+
+        let repository = ChunkRepository::open(out_dir).unwrap();
+        let manifest = repository
+            .store(&out_dir.join("original.mov"), out_dir)
+            .unwrap();
+        manifest
+            .persist(out_dir.join("original.mov.chunks"))
+            .unwrap();
+        assert_eq!(pack_bytes(), original.len() as u64);
+        // reopened, to check the index is read back.
+        let repository = ChunkRepository::open(out_dir).unwrap();
+        let manifest = repository
+            .store(&out_dir.join("edited.mov"), out_dir)
+            .unwrap();
+        manifest.persist(out_dir.join("edited.mov.chunks")).unwrap();
+        let new_bytes = pack_bytes() - original.len() as u64;
+        assert!(new_bytes < MAX_CHUNK as u64 * 2, "{new_bytes} new bytes");
+
+        for name in ["original.mov", "edited.mov"] {
+            let manifest = chunked_path(&out_dir.join(name));
+            assert!(is_chunked(&manifest));
+            assert_eq!(
+                digest_reader(&mut open_chunked(&manifest).unwrap()).unwrap(),
+                digest(&out_dir.join(name)).unwrap()
+            );
+        }
+    }
+}
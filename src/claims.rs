@@ -0,0 +1,81 @@
+//! Coordinating the workers of a run, so that when two new files have the same content only one
+//! of them writes it to the out directory.
+
+use std::{
+    collections::HashSet,
+    sync::{Condvar, Mutex},
+};
+
+use crate::digest::ContentHash;
+
+/// The contents some worker is currently deciding whether to write.
+#[derive(Default)]
+pub struct DigestClaims {
+    held: Mutex<HashSet<ContentHash>>,
+    released: Condvar,
+}
+
+impl DigestClaims {
+    /// Claims `digest`, first waiting for any other worker holding it to finish, by when it will
+    /// have catalogued the content if it wrote it.
+    pub fn claim(&self, digest: ContentHash) -> Claim<'_> {
+        let held = self.held.lock().unwrap();
+        let mut held = self
+            .released
+            .wait_while(held, |held| held.contains(&digest))
+            .unwrap();
+        held.insert(digest);
+        Claim {
+            claims: self,
+            digest,
+        }
+    }
+}
+
+/// A claim on some content, released when dropped.
+pub struct Claim<'a> {
+    claims: &'a DigestClaims,
+    digest: ContentHash,
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        self.claims.held.lock().unwrap().remove(&self.digest);
+        self.claims.released.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicBool, Ordering},
+        thread,
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::digest::digest_reader;
+
+    #[test]
+    fn claims_are_exclusive() {
+        let claims = DigestClaims::default();
+        let (a, b) = (
+            digest_reader(&mut &b"a"[..]).unwrap(),
+            digest_reader(&mut &b"b"[..]).unwrap(),
+        );
+        let claimed = AtomicBool::new(false);
+        let first = claims.claim(a);
+        thread::scope(|s| {
+            s.spawn(|| {
+                let _second = claims.claim(a);
+                claimed.store(true, Ordering::SeqCst);
+            });
+            // other content isn't held up.
+            drop(claims.claim(b));
+            thread::sleep(Duration::from_millis(50));
+            assert!(!claimed.load(Ordering::SeqCst));
+            drop(first);
+        });
+        assert!(claimed.load(Ordering::SeqCst));
+    }
+}
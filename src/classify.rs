@@ -0,0 +1,198 @@
+//! Telling screenshots and the frames of bursts from other photos, so `--skip-screenshots` and
+//! `--collapse-bursts best-only` can leave them out. Screenshots are known by their name or by the
+//! "Screenshot" comment iOS gives them, and burst frames by the burst UUID iPhones write into
+//! their EXIF maker note, which every frame of a burst shares.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use clap::ValueEnum;
+use exif::{Exif, In, Reader, Tag, Value};
+
+use crate::metadata::MediaType;
+
+/// What to transfer of a burst.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum BurstPolicy {
+    /// Every frame.
+    #[default]
+    All,
+    /// Only the largest frame, which is usually the sharpest, of those in its directory.
+    BestOnly,
+}
+
+/// The prefix of the maker note iPhones write, before its IFD.
+const APPLE_MAKER_NOTE: &[u8] = b"Apple iOS\0";
+/// The maker note tag holding the burst UUID.
+const BURST_UUID: u16 = 0x000b;
+
+/// Whether the photo named `name`, with the EXIF data `exif`, whose content starts with `header`,
+/// is a screenshot.
+pub fn is_screenshot(name: &Path, exif: Option<&Exif>, header: &[u8]) -> bool {
+    let stem = name
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    if ["screenshot", "screen shot", "simulator screen shot"]
+        .iter()
+        .any(|prefix| stem.starts_with(prefix))
+    {
+        return true;
+    }
+    let commented = exif
+        .and_then(|exif| exif.get_field(Tag::UserComment, In::PRIMARY))
+        .is_some_and(|field| match &field.value {
+            // after the 8 bytes naming its character set.
+            Value::Undefined(comment, _) => comment.get(8..) == Some(b"Screenshot"),
+            _ => false,
+        });
+    // PNGs keep the comment in their XMP rather than EXIF.
+    commented || header.windows(12).any(|window| window == b">Screenshot<")
+}
+
+/// The UUID of the burst the photo with the EXIF data `exif` is a frame of, if it is one.
+pub fn burst_id(exif: &Exif) -> Option<String> {
+    let Value::Undefined(note, _) = &exif.get_field(Tag::MakerNote, In::PRIMARY)?.value else {
+        return None;
+    };
+    apple_burst_id(note)
+}
+
+/// The burst UUID in the Apple maker note `note`, a big-endian IFD whose offsets count from the
+/// start of the note.
+fn apple_burst_id(note: &[u8]) -> Option<String> {
+    let rest = note.strip_prefix(APPLE_MAKER_NOTE)?;
+    // a version, then the byte order.
+    if rest.get(2..4)? != b"MM" {
+        return None;
+    }
+    let u16_at = |at: usize| Some(u16::from_be_bytes(note.get(at..at + 2)?.try_into().ok()?));
+    let u32_at = |at: usize| Some(u32::from_be_bytes(note.get(at..at + 4)?.try_into().ok()?));
+    let ifd = APPLE_MAKER_NOTE.len() + 4;
+    for entry in 0..usize::from(u16_at(ifd)?) {
+        let at = ifd + 2 + entry * 12;
+        if u16_at(at)? != BURST_UUID {
+            continue;
+        }
+        let count = u32_at(at + 4)? as usize;
+        // values of up to 4 bytes are kept in the entry itself.
+        let value = if count <= 4 {
+            note.get(at + 8..at + 8 + count)?
+        } else {
+            let offset = u32_at(at + 8)? as usize;
+            note.get(offset..offset + count)?
+        };
+        let id = String::from_utf8_lossy(value);
+        let id = id.trim_end_matches('\0');
+        return (!id.is_empty()).then(|| id.to_string());
+    }
+    None
+}
+
+/// The burst UUID of the photo at `path`, read from its EXIF data.
+fn burst_id_of(path: &Path) -> Option<String> {
+    let file = File::open(path).ok()?;
+    let exif = Reader::new()
+        .read_from_container(&mut BufReader::new(file))
+        .ok()?;
+    burst_id(&exif)
+}
+
+/// The best frame of each burst in a directory, found once for each directory a burst's frames
+/// are transferred from. Each directory is read outside the lock, so frames in other directories
+/// needn't wait for it.
+#[derive(Default)]
+pub struct Bursts {
+    best: Mutex<HashMap<PathBuf, Arc<OnceLock<BestFrames>>>>,
+}
+
+/// The path of the best frame of each burst, by burst UUID.
+type BestFrames = HashMap<String, PathBuf>;
+
+impl Bursts {
+    /// Whether the photo at `path`, a frame of the burst `id`, is its best frame.
+    pub fn is_best(&self, path: &Path, id: &str) -> bool {
+        let Some(dir) = path.parent() else {
+            return true;
+        };
+        let best = Arc::clone(
+            (self.best.lock().unwrap())
+                .entry(dir.to_path_buf())
+                .or_default(),
+        );
+        let best = best.get_or_init(|| best_frames(dir));
+        best.get(id).is_none_or(|best| best == path)
+    }
+}
+
+/// The largest frame of each burst with frames in `dir`, by burst UUID, the first by name of
+/// those the same size.
+fn best_frames(dir: &Path) -> BestFrames {
+    let mut frames: Vec<(PathBuf, u64)> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let metadata = entry.metadata().ok()?;
+            let path = entry.path();
+            (metadata.is_file() && MediaType::of(&path) == MediaType::Photo)
+                .then_some((path, metadata.len()))
+        })
+        .collect();
+    frames.sort();
+    let mut best: HashMap<String, (PathBuf, u64)> = HashMap::new();
+    for (path, size) in frames {
+        let Some(id) = burst_id_of(&path) else {
+            continue;
+        };
+        match best.get(&id) {
+            Some((_, best_size)) if *best_size >= size => {}
+            _ => {
+                best.insert(id, (path, size));
+            }
+        }
+    }
+    best.into_iter().map(|(id, (path, _))| (id, path)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn burst_ids_are_read_from_apple_maker_notes() {
+        let uuid = b"4C1B8E1A-5B6E-4F52-9D83-2C3B6F1A7E90\0";
+        let mut note = APPLE_MAKER_NOTE.to_vec();
+        note.extend([0, 1]);
+        note.extend(b"MM");
+        note.extend(2u16.to_be_bytes());
+        let values = note.len() + 2 * 12 + 4;
+        // another tag, kept in its entry, then the burst UUID, kept after the IFD.
+        note.extend([0, 1, 0, 9, 0, 0, 0, 1, 0, 0, 0, 7]);
+        note.extend(BURST_UUID.to_be_bytes());
+        note.extend(2u16.to_be_bytes());
+        note.extend((uuid.len() as u32).to_be_bytes());
+        note.extend((values as u32).to_be_bytes());
+        note.extend([0; 4]);
+        note.extend(uuid);
+        assert_eq!(
+            apple_burst_id(&note).as_deref(),
+            Some("4C1B8E1A-5B6E-4F52-9D83-2C3B6F1A7E90")
+        );
+        assert_eq!(apple_burst_id(b"Nikon\0\0\0\0\0MM"), None);
+
+        let screenshot = |name: &str, bytes: &[u8]| is_screenshot(Path::new(name), None, bytes);
+        assert!(screenshot("Screenshot 2023-07-14 at 18.03.27.png", b""));
+        assert!(screenshot("Screen Shot 2019-01-01 at 12.00.00.png", b""));
+        assert!(screenshot(
+            "IMG_0001.PNG",
+            b"<rdf:li xml:lang=\"x-default\">Screenshot</rdf:li>"
+        ));
+        assert!(!screenshot("IMG_0001.HEIC", b"a photo of a screenshot"));
+    }
+}
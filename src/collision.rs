@@ -0,0 +1,73 @@
+//! What's done when a new file's place in the out directory is already taken by a file the
+//! catalogue doesn't know of, e.g. one copied in by hand, or one of the same name from another in
+//! directory.
+
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+use clap::ValueEnum;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum CollisionPolicy {
+    /// Fail the new file, even if the file there has the same content.
+    Error,
+    /// Keep the file there if it has the same content, and otherwise leave the new file
+    /// untransferred for `--interactive` to review.
+    Skip,
+    /// Keep the file there if it has the same content, and otherwise archive the new file under
+    /// the next free name, e.g. `IMG_1234 (2).HEIC`.
+    #[default]
+    Rename,
+    /// Keep the file there if it has the same content, and otherwise fail the new file.
+    OverwriteIfIdentical,
+}
+
+/// `path` if `is_taken` says it's free, and otherwise the first of `NAME (2).EXT`,
+/// `NAME (3).EXT` and so on which is.
+pub fn free_path(path: &Path, is_taken: impl Fn(&Path) -> bool) -> PathBuf {
+    let name = path.file_name().unwrap_or(path.as_os_str());
+    let stem = Path::new(name).file_stem().unwrap_or(name);
+    let extension = Path::new(name).extension();
+    let mut free = path.to_path_buf();
+    for n in 2.. {
+        if !is_taken(&free) {
+            break;
+        }
+        let mut numbered = OsString::from(stem);
+        numbered.push(format!(" ({n})"));
+        if let Some(extension) = extension {
+            numbered.push(".");
+            numbered.push(extension);
+        }
+        free = path.with_file_name(numbered);
+    }
+    free
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn taken_names_are_numbered() {
+        let taken = [
+            Path::new("2019/IMG_1234.HEIC"),
+            Path::new("2019/IMG_1234 (2).HEIC"),
+        ];
+        let is_taken = |path: &Path| taken.contains(&path);
+        assert_eq!(
+            free_path(Path::new("2019/IMG_1234.HEIC"), is_taken),
+            Path::new("2019/IMG_1234 (3).HEIC")
+        );
+        assert_eq!(
+            free_path(Path::new("2019/IMG_1235.HEIC"), is_taken),
+            Path::new("2019/IMG_1235.HEIC")
+        );
+        assert_eq!(
+            free_path(Path::new("README"), |path| path == Path::new("README")),
+            Path::new("README (2)")
+        );
+    }
+}
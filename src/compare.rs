@@ -0,0 +1,118 @@
+//! Comparing two directory trees by content, e.g. an old backup disk against the archive before
+//! wiping it.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    path::PathBuf,
+};
+
+use eyre::Result;
+
+use crate::{
+    CompareArgs,
+    digest::{ContentHash, digest_tree},
+};
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Comparison {
+    /// Content found only in A, with its paths there.
+    only_in_a: Vec<BTreeSet<PathBuf>>,
+    only_in_b: Vec<BTreeSet<PathBuf>>,
+    /// Content in both, but not at the same paths.
+    moved: Vec<(BTreeSet<PathBuf>, BTreeSet<PathBuf>)>,
+    /// Content in both at the same paths.
+    identical: usize,
+}
+
+pub fn compare(args: CompareArgs) -> Result<()> {
+    let (a, b) = rayon::join(|| digest_tree(&args.dir_a), || digest_tree(&args.dir_b));
+    let comparison = compare_trees(a?, b?);
+
+    for paths in &comparison.only_in_a {
+        println!("only in A: {}", list(paths));
+    }
+    for paths in &comparison.only_in_b {
+        println!("only in B: {}", list(paths));
+    }
+    for (in_a, in_b) in &comparison.moved {
+        println!("moved: {} in A is {} in B", list(in_a), list(in_b));
+    }
+    println!(
+        "{} only in A, {} only in B, {} at different paths, {} identical",
+        comparison.only_in_a.len(),
+        comparison.only_in_b.len(),
+        comparison.moved.len(),
+        comparison.identical
+    );
+    Ok(())
+}
+
+fn compare_trees(a: HashMap<PathBuf, ContentHash>, b: HashMap<PathBuf, ContentHash>) -> Comparison {
+    // keyed on the content, ordered so the report is stable.
+    let mut contents = BTreeMap::<ContentHash, (BTreeSet<PathBuf>, BTreeSet<PathBuf>)>::new();
+    for (path, digest) in a {
+        contents.entry(digest).or_default().0.insert(path);
+    }
+    for (path, digest) in b {
+        contents.entry(digest).or_default().1.insert(path);
+    }
+
+    let mut comparison = Comparison::default();
+    for (in_a, in_b) in contents.into_values() {
+        if in_b.is_empty() {
+            comparison.only_in_a.push(in_a);
+        } else if in_a.is_empty() {
+            comparison.only_in_b.push(in_b);
+        } else if in_a == in_b {
+            comparison.identical += 1;
+        } else {
+            comparison.moved.push((in_a, in_b));
+        }
+    }
+    comparison
+}
+
+fn list(paths: &BTreeSet<PathBuf>) -> String {
+    paths
+        .iter()
+        .map(|p| format!("{p:?}"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn trees_are_compared_by_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+        for (path, content) in [
+            ("a/same.jpg", "same"),
+            ("b/same.jpg", "same"),
+            ("a/old/moved.jpg", "moved"),
+            ("b/new/moved.jpg", "moved"),
+            ("a/gone.jpg", "gone"),
+            ("b/added.jpg", "added"),
+        ] {
+            let path = dir.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+
+        let comparison = compare_trees(digest_tree(&a).unwrap(), digest_tree(&b).unwrap());
+        let set = |path: &str| BTreeSet::from([PathBuf::from(path)]);
+        assert_eq!(
+            comparison,
+            Comparison {
+                only_in_a: vec![set("gone.jpg")],
+                only_in_b: vec![set("added.jpg")],
+                moved: vec![(set("old/moved.jpg"), set("new/moved.jpg"))],
+                identical: 1,
+            }
+        );
+    }
+}
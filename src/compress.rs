@@ -0,0 +1,182 @@
+//! Storing archived copies zstd-compressed, for archives of huge video on expensive storage. The
+//! catalogue keeps the original content's digest and size, so compressed copies dedupe against
+//! uncompressed ones, and how each copy was stored, as an original's name can end in `.zst` too.
+
+use std::{
+    ffi::OsString,
+    fs::File,
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    chunks::{is_chunked, open_chunked},
+    digest::{ContentHash, HashAlgorithm},
+    encrypt::is_encrypted,
+};
+use eyre::Result;
+
+/// Added to the names of compressed copies.
+const EXTENSION: &str = "zst";
+/// The level copies are compressed at when not given one, e.g. when restored by a repair.
+const DEFAULT_LEVEL: i32 = 3;
+
+/// Where the compressed copy of a file destined for `path` goes.
+pub fn compressed_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".");
+    name.push(EXTENSION);
+    PathBuf::from(name)
+}
+
+fn is_compressed(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == EXTENSION)
+}
+
+/// How an archived copy's content was stored, as catalogued when it was written.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Transform {
+    /// As it is.
+    #[default]
+    None,
+    /// Compressed with zstd.
+    Compressed,
+    /// Split into chunks kept in the chunk repository, with the copy listing them.
+    Chunked,
+}
+
+impl Transform {
+    /// How a copy at `path` was stored, going by its name, for copies which weren't catalogued as
+    /// they were written, e.g. adopted ones.
+    pub fn by_name(path: &Path) -> Self {
+        if is_compressed(path) {
+            Self::Compressed
+        } else if is_chunked(path) {
+            Self::Chunked
+        } else {
+            Self::None
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Compressed => "compressed",
+            Self::Chunked => "chunked",
+        }
+    }
+}
+
+impl ToSql for Transform {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::Borrowed(ValueRef::Text(
+            self.as_str().as_bytes(),
+        )))
+    }
+}
+
+impl FromSql for Transform {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_str()? {
+            "none" => Ok(Self::None),
+            "compressed" => Ok(Self::Compressed),
+            "chunked" => Ok(Self::Chunked),
+            other => Err(FromSqlError::Other(
+                format!("unknown transform {other:?}").into(),
+            )),
+        }
+    }
+}
+
+/// The path a copy at `path`, stored as `transform`, had before compression or chunking.
+pub fn original_path(path: &Path, transform: Transform) -> PathBuf {
+    match transform {
+        Transform::None => path.to_path_buf(),
+        Transform::Compressed | Transform::Chunked => path.with_extension(""),
+    }
+}
+
+/// Compresses `from` into `to`.
+pub fn compress(from: &Path, to: &mut File, level: i32) -> io::Result<()> {
+    zstd::stream::copy_encode(File::open(from)?, to, level)
+}
+
+/// Opens an archived copy stored as `transform`, decompressing or reassembling it as need be.
+/// Encrypted copies can't be opened without an identity, so are refused.
+pub fn open_archived(path: &Path, transform: Transform) -> io::Result<Box<dyn Read>> {
+    if is_encrypted(path) {
+        return Err(io::Error::other(format!("{path:?} is encrypted")));
+    }
+    Ok(match transform {
+        Transform::None => Box::new(File::open(path)?),
+        Transform::Compressed => Box::new(zstd::stream::read::Decoder::new(File::open(path)?)?),
+        Transform::Chunked => Box::new(open_chunked(path)?),
+    })
+}
+
+/// Copies the content of `from`, stored as `from_transform`, into `into`, the staged copy of a
+/// file to be stored as `to_transform`. Chunked copies can only be written by a sync, as their
+/// chunks go in its repository.
+pub fn copy_archived(
+    from: &Path,
+    from_transform: Transform,
+    into: &mut File,
+    to_transform: Transform,
+) -> io::Result<()> {
+    let mut content = open_archived(from, from_transform)?;
+    match to_transform {
+        Transform::None => io::copy(&mut content, into).map(|_| ()),
+        Transform::Compressed => zstd::stream::copy_encode(content, into, DEFAULT_LEVEL),
+        Transform::Chunked => Err(io::Error::other("chunked copies can't be written here")),
+    }
+}
+
+/// The digest of the original content of an archived copy stored as `transform`, as the
+/// catalogue has it.
+pub fn digest_archived(
+    path: &Path,
+    transform: Transform,
+    algorithm: HashAlgorithm,
+) -> Result<ContentHash> {
+    algorithm.digest_reader(&mut open_archived(path, transform)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn compressed_copies_digest_as_the_original() {
+        let dir = tempfile::tempdir().unwrap();
+        let original = dir.path().join("clip.mov");
+        fs::write(&original, "video ".repeat(1000)).unwrap();
+        let compressed = compressed_path(&original);
+        assert_eq!(compressed, dir.path().join("clip.mov.zst"));
+        assert_eq!(Transform::by_name(&compressed), Transform::Compressed);
+        assert_eq!(original_path(&compressed, Transform::Compressed), original);
+
+        compress(&original, &mut File::create(&compressed).unwrap(), 3).unwrap();
+        assert!(compressed.metadata().unwrap().len() < original.metadata().unwrap().len());
+        for algorithm in HashAlgorithm::ALL {
+            let expected = algorithm.digest(&original).unwrap();
+            let archived = digest_archived(&compressed, Transform::Compressed, algorithm);
+            assert_eq!(archived.unwrap(), expected);
+            let archived = digest_archived(&original, Transform::None, algorithm);
+            assert_eq!(archived.unwrap(), expected);
+        }
+
+        // an original named like a compressed copy is read as it is, as it was catalogued.
+        let named = dir.path().join("notes.zst");
+        fs::write(&named, "not zstd").unwrap();
+        assert_eq!(original_path(&named, Transform::None), named);
+        assert_eq!(
+            digest_archived(&named, Transform::None, HashAlgorithm::Sha256).unwrap(),
+            HashAlgorithm::Sha256.digest(&named).unwrap()
+        );
+    }
+}
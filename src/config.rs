@@ -0,0 +1,167 @@
+//! Sync arguments kept in a TOML file, used with `sync --config`. Keys are the long argument names,
+//! e.g. `in_dir = "/photos"`, and flags are set with `true`. Named jobs for `run-all` are tables
+//! under `jobs`, e.g. `[jobs.laptop]`, whose arguments override those at the top level. One job is
+//! synced on its own by naming it as the profile, e.g. `sync --config sync.toml --profile laptop`.
+
+use std::path::Path;
+
+use eyre::{Result, WrapErr, bail};
+use toml::{Table, Value};
+
+/// The arguments the config file at `path` stands for, leaving out its jobs.
+pub fn config_args(path: &Path) -> Result<Vec<String>> {
+    let mut table = read_table(path)?;
+    table.remove(JOBS);
+    table_args(path, table)
+}
+
+/// The name of each job in the config file at `path`, with the arguments it adds to those at the
+/// top level.
+pub fn config_jobs(path: &Path) -> Result<Vec<(String, Vec<String>)>> {
+    let Some(jobs) = read_table(path)?.remove(JOBS) else {
+        bail!("{path:?} has no [{JOBS}.<name>] tables");
+    };
+    jobs_args(path, jobs)
+}
+
+/// The arguments the job `name` in the config file at `path` adds to those at the top level, if
+/// it has such a job.
+pub fn config_job(path: &Path, name: &str) -> Result<Option<Vec<String>>> {
+    let Some(jobs) = read_table(path)?.remove(JOBS) else {
+        return Ok(None);
+    };
+    let jobs = jobs_args(path, jobs)?;
+    Ok(jobs
+        .into_iter()
+        .find_map(|(job, args)| (job == name).then_some(args)))
+}
+
+fn jobs_args(path: &Path, jobs: Value) -> Result<Vec<(String, Vec<String>)>> {
+    let Value::Table(jobs) = jobs else {
+        bail!("{JOBS} in {path:?} must be a table of jobs");
+    };
+    jobs.into_iter()
+        .map(|(name, job)| match job {
+            Value::Table(job) => Ok((name, table_args(path, job)?)),
+            _ => bail!("job {name:?} in {path:?} must be a table"),
+        })
+        .collect()
+}
+
+const JOBS: &str = "jobs";
+
+fn read_table(path: &Path) -> Result<Table> {
+    let text =
+        std::fs::read_to_string(path).wrap_err_with(|| format!("could not read {path:?}"))?;
+    text.parse()
+        .wrap_err_with(|| format!("{path:?} is not valid TOML"))
+}
+
+fn table_args(path: &Path, table: Table) -> Result<Vec<String>> {
+    let mut args = Vec::new();
+    for (key, value) in table {
+        if key == "config" {
+            bail!("{path:?} sets config, but config files can't include each other");
+        }
+        let flag = format!("--{}", key.replace('_', "-"));
+        let values = match value {
+            Value::Array(values) => values,
+            value => vec![value],
+        };
+        for value in values {
+            match value {
+                Value::Boolean(true) => args.push(flag.clone()),
+                Value::Boolean(false) => {}
+                // `=` keeps values starting with `-` from being read as flags.
+                Value::String(s) => args.push(format!("{flag}={s}")),
+                Value::Integer(_) | Value::Float(_) => args.push(format!("{flag}={value}")),
+                value => {
+                    bail!("{key} in {path:?} must be a string, number or boolean, not {value}")
+                }
+            }
+        }
+    }
+    Ok(args)
+}
+
+/// Writes a config file setting each of `values`.
+pub fn write_config(path: &Path, values: &[(&str, String)]) -> Result<()> {
+    let table: Table = values
+        .iter()
+        .map(|(key, value)| (key.to_string(), Value::String(value.clone())))
+        .collect();
+    std::fs::write(path, toml::to_string(&table)?)
+        .wrap_err_with(|| format!("could not write {path:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_becomes_arguments() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sync.toml");
+        std::fs::write(
+            &path,
+            r#"
+                in_dir = "/photos"
+                machine-id = "-laptop"
+                roll_back_on_abort = true
+                plugin = []
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config_args(&path).unwrap(),
+            [
+                "--in-dir=/photos",
+                "--machine-id=-laptop",
+                "--roll-back-on-abort"
+            ]
+        );
+
+        write_config(&path, &[("out_dir", "/nas".into())]).unwrap();
+        assert_eq!(config_args(&path).unwrap(), ["--out-dir=/nas"]);
+
+        std::fs::write(&path, "config = \"other.toml\"").unwrap();
+        assert!(config_args(&path).is_err());
+    }
+
+    #[test]
+    fn jobs_are_kept_apart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sync.toml");
+        std::fs::write(
+            &path,
+            r#"
+                database_file = "/nas/photos.sqlite"
+                [jobs.laptop]
+                in_dir = "/photos"
+                [jobs.phone]
+                in_dir = "/phone"
+                skip_hidden = true
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config_args(&path).unwrap(),
+            ["--database-file=/nas/photos.sqlite"]
+        );
+        assert_eq!(
+            config_jobs(&path).unwrap(),
+            [
+                ("laptop".to_string(), vec!["--in-dir=/photos".to_string()]),
+                (
+                    "phone".to_string(),
+                    vec!["--in-dir=/phone".to_string(), "--skip-hidden".to_string()]
+                ),
+            ]
+        );
+        assert_eq!(
+            config_job(&path, "laptop").unwrap(),
+            Some(vec!["--in-dir=/photos".to_string()])
+        );
+        assert_eq!(config_job(&path, "tablet").unwrap(), None);
+    }
+}
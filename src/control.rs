@@ -0,0 +1,227 @@
+//! Letting other programs, e.g. a menubar app or a web panel, follow and steer a run they didn't
+//! start, over a Unix domain socket. Each line sent is a command, answered with a line of JSON:
+//!
+//! - `progress`: the run's counters so far, and why it's paused if it is.
+//! - `files`: the files being hashed or transferred right now.
+//! - `pause` and `resume`: as SIGUSR1 and SIGUSR2 do.
+//! - `abort`: finishes the files in flight, then fails the run.
+
+// the socket is only served on Unix.
+#![cfg_attr(not(unix), allow(dead_code))]
+
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use eyre::Result;
+use serde_json::{Value, json};
+
+use crate::{
+    metrics::RunStats,
+    pause::{PauseControl, PauseReason},
+};
+
+/// The files being worked on, for `files`.
+#[derive(Default)]
+pub struct CurrentFiles(Mutex<BTreeSet<PathBuf>>);
+
+impl CurrentFiles {
+    /// Lists `path` until the returned guard is dropped.
+    pub fn working_on(&self, path: &Path) -> WorkingOn<'_> {
+        self.0.lock().unwrap().insert(path.to_path_buf());
+        WorkingOn {
+            files: self,
+            path: path.to_path_buf(),
+        }
+    }
+
+    fn list(&self) -> Vec<PathBuf> {
+        self.0.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+pub struct WorkingOn<'a> {
+    files: &'a CurrentFiles,
+    path: PathBuf,
+}
+
+impl Drop for WorkingOn<'_> {
+    fn drop(&mut self) {
+        self.files.0.lock().unwrap().remove(&self.path);
+    }
+}
+
+/// What the control socket can see and do.
+#[derive(Clone, Copy)]
+pub struct Control<'a> {
+    pub pause: &'a PauseControl,
+    pub stats: &'a RunStats,
+    pub current: &'a CurrentFiles,
+}
+
+impl Control<'_> {
+    fn handle(&self, command: &str) -> Value {
+        match command.trim() {
+            "progress" => {
+                let stats = self.stats;
+                let paused: Vec<_> = self
+                    .pause
+                    .reasons()
+                    .iter()
+                    .map(|r| format!("{r:?}"))
+                    .collect();
+                json!({
+                    "files_indexed": stats.files_indexed.as_u64(),
+                    "files_detected": stats.files_detected.as_u64(),
+                    "files_transferred": stats.files_transferred.as_u64(),
+                    "bytes_transferred": stats.bytes_transferred.as_u64(),
+                    "files_failed": stats.files_failed.as_u64(),
+                    "paused": paused,
+                })
+            }
+            "files" => json!({ "files": self.current.list() }),
+            "pause" => {
+                self.pause.pause(PauseReason::Requested);
+                json!({ "ok": true })
+            }
+            "resume" => {
+                self.pause.resume(PauseReason::Requested);
+                json!({ "ok": true })
+            }
+            "abort" => {
+                self.pause.abort();
+                json!({ "ok": true })
+            }
+            other => json!({ "error": format!("unknown command {other:?}") }),
+        }
+    }
+}
+
+/// Serves `control` on a socket at `path`, if given, while `f` runs.
+#[cfg(unix)]
+pub fn with_control_socket<T>(
+    path: Option<&Path>,
+    control: Control,
+    f: impl FnOnce() -> Result<T>,
+) -> Result<T> {
+    use std::{
+        fs,
+        io::{BufRead, BufReader, ErrorKind, Write},
+        os::unix::net::{UnixListener, UnixStream},
+        sync::atomic::{AtomicBool, Ordering},
+        thread,
+        time::Duration,
+    };
+
+    use eyre::{WrapErr, bail};
+    use tracing::{info, warn};
+
+    /// How often the server checks whether the run has finished.
+    const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+    let Some(path) = path else {
+        return f();
+    };
+    if path.exists() {
+        // left behind by a run which didn't get to remove it, unless something still answers.
+        if UnixStream::connect(path).is_ok() {
+            bail!("{path:?} is already in use by another run");
+        }
+        fs::remove_file(path)?;
+    }
+    let listener =
+        UnixListener::bind(path).wrap_err_with(|| format!("could not listen on {path:?}"))?;
+    listener.set_nonblocking(true)?;
+    info!("accepting progress and control commands on {path:?}");
+
+    let done = AtomicBool::new(false);
+    let serve = |stream: UnixStream| -> Result<()> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(POLL_INTERVAL))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream;
+        let mut line = String::new();
+        while !done.load(Ordering::SeqCst) {
+            match reader.read_line(&mut line) {
+                Ok(0) => break,
+                Ok(_) => {
+                    writeln!(writer, "{}", control.handle(&line))?;
+                    line.clear();
+                }
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    };
+
+    let result = thread::scope(|s| {
+        s.spawn(|| {
+            while !done.load(Ordering::SeqCst) {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        s.spawn(|| {
+                            if let Err(e) = serve(stream) {
+                                warn!("control connection failed: {e}");
+                            }
+                        });
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+                    Err(e) => {
+                        warn!("control socket failed, so no longer accepting commands: {e}");
+                        return;
+                    }
+                }
+            }
+        });
+        let result = f();
+        done.store(true, Ordering::SeqCst);
+        result
+    });
+    let _ = fs::remove_file(path);
+    result
+}
+
+#[cfg(not(unix))]
+pub fn with_control_socket<T>(
+    path: Option<&Path>,
+    _control: Control,
+    f: impl FnOnce() -> Result<T>,
+) -> Result<T> {
+    eyre::ensure!(path.is_none(), "control sockets are only supported on Unix");
+    f()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_are_answered() {
+        let (pause, stats, current) = Default::default();
+        let control = Control {
+            pause: &pause,
+            stats: &stats,
+            current: &current,
+        };
+        stats.files_transferred.fetch_add(3);
+        let working = current.working_on(Path::new("a.jpg"));
+
+        assert_eq!(control.handle("files\n"), json!({ "files": ["a.jpg"] }));
+        drop(working);
+        assert_eq!(control.handle("files"), json!({ "files": [] }));
+
+        control.handle("pause");
+        let progress = control.handle("progress");
+        assert_eq!(progress["files_transferred"], 3);
+        assert_eq!(progress["paused"], json!(["Requested"]));
+        control.handle("resume");
+        assert_eq!(control.handle("progress")["paused"], json!([]));
+
+        control.handle("abort");
+        assert!(pause.wait_if_paused().is_err());
+        assert!(control.handle("stop").get("error").is_some());
+    }
+}
@@ -0,0 +1,78 @@
+//! Copying new files' content in large blocks, as `io::copy`'s small buffer keeps copies to
+//! network storage well below the speed of the link.
+
+use std::io::{self, ErrorKind, Read, Write};
+
+/// The default size of the blocks copied, in KiB.
+pub const DEFAULT_BUFFER_KIB: usize = 1024;
+
+/// Copies everything `from` has to `to`, filling a buffer of `buffer_size` bytes before each write
+/// so that writes are as large as possible. Returns how many bytes were copied.
+pub fn copy(from: &mut impl Read, to: &mut impl Write, buffer_size: usize) -> io::Result<u64> {
+    let mut buffer = vec![0; buffer_size.max(1)];
+    let mut copied = 0;
+    loop {
+        let filled = fill(from, &mut buffer)?;
+        if filled == 0 {
+            return Ok(copied);
+        }
+        to.write_all(&buffer[..filled])?;
+        copied += filled as u64;
+    }
+}
+
+/// Reads into `buffer` until it's full or `from` runs out, returning how much was read.
+fn fill(from: &mut impl Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match from.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Gives at most 7 bytes per read, like a slow network filesystem.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(self.0.len()).min(7);
+            buf[..len].copy_from_slice(&self.0[..len]);
+            self.0 = &self.0[len..];
+            Ok(len)
+        }
+    }
+
+    /// Records the size of each write.
+    #[derive(Default)]
+    struct Writes(Vec<usize>, Vec<u8>);
+
+    impl Write for Writes {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.push(buf.len());
+            self.1.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn short_reads_are_gathered_into_full_blocks() {
+        let content: Vec<u8> = (0..250).collect();
+        let mut writes = Writes::default();
+        assert_eq!(copy(&mut Trickle(&content), &mut writes, 100).unwrap(), 250);
+        assert_eq!(writes.0, [100, 100, 50]);
+        assert_eq!(writes.1, content);
+    }
+}
@@ -0,0 +1,138 @@
+//! `db check` and `db repair`: whether a catalogue can still be trusted, e.g. after a power loss
+//! mid-run, and rebuilding what can be rebuilt of it.
+
+use std::{fs, path::Path, time::SystemTime};
+
+use eyre::{Result, bail};
+
+use crate::store::{HealthCheck, PhotoSyncStore};
+
+pub fn check(database_file: &Path) -> Result<()> {
+    let store = PhotoSyncStore::new(database_file.to_path_buf())?;
+    let check = store.health_check(SystemTime::now())?;
+    print!("{}", describe(&check));
+    if !check.is_healthy() {
+        bail!("{database_file:?} failed its health check");
+    }
+    Ok(())
+}
+
+/// Rebuilds the index of digests and vacuums the database, then checks it again. Anything still
+/// wrong is in the catalogue's own rows, and needs looking at by hand.
+pub fn repair(database_file: &Path) -> Result<()> {
+    let store = PhotoSyncStore::new(database_file.to_path_buf())?;
+    let before = store.health_check(SystemTime::now())?;
+    let digests = store.reindex_digests()?;
+    println!(
+        "reindexed {digests} distinct digests, {} of which were miscounted",
+        before.stale_digests
+    );
+    let size = || fs::metadata(database_file).map(|metadata| metadata.len());
+    let unvacuumed = size()?;
+    store.vacuum()?;
+    println!(
+        "vacuumed the database from {}MB to {}MB",
+        unvacuumed / 1_000_000,
+        size()? / 1_000_000
+    );
+    let after = store.health_check(SystemTime::now())?;
+    print!("{}", describe(&after));
+    if !after.is_healthy() {
+        bail!("{database_file:?} still fails its health check, which repairing can't fix");
+    }
+    Ok(())
+}
+
+fn describe(check: &HealthCheck) -> String {
+    if check.is_healthy() {
+        return "no problems found\n".to_owned();
+    }
+    let mut description = String::new();
+    for message in &check.integrity {
+        description += &format!("integrity check: {message}\n");
+    }
+    for (problems, what) in [
+        (&check.duplicate_paths, "duplicate paths"),
+        (&check.malformed_digests, "malformed digests"),
+        (&check.implausible_mtimes, "implausible modification times"),
+    ] {
+        for (table, rows) in problems {
+            description += &format!("{table}: {rows} rows with {what}\n");
+        }
+    }
+    if check.unresolved_sources > 0 {
+        description += &format!(
+            "source_files: {} rows whose content isn't in either out directory\n",
+            check.unresolved_sources
+        );
+    }
+    if check.stale_digests > 0 {
+        description += &format!(
+            "digests: {} miscounted, run `db repair` to fix\n",
+            check.stale_digests
+        );
+    }
+    description
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::digest::ContentHash;
+
+    #[test]
+    fn checks_find_what_repairs_fix() {
+        let dir = tempfile::tempdir().unwrap();
+        let database_file = dir.path().join("db.sqlite");
+        let store = PhotoSyncStore::new(database_file.clone()).unwrap();
+        let run = store.begin_run("").unwrap();
+        let now = SystemTime::now();
+        let (a, b) = (ContentHash::new_for_tests(1), ContentHash::new_for_tests(2));
+        store
+            .mark_exists_in_target(run, Path::new("a.jpg"), now, 1, &a)
+            .unwrap();
+        store
+            .mark_transferred_from_source(run, "", Path::new("a.jpg"), &a, now, 1)
+            .unwrap();
+        assert!(store.health_check(now).unwrap().is_healthy());
+        assert!(check(&database_file).is_ok());
+
+        let future = now + Duration::from_secs(2 * 365 * 24 * 60 * 60);
+        store
+            .mark_transferred_from_source(run, "", Path::new("b.jpg"), &b, future, 1)
+            .unwrap();
+        store
+            .load_rows([Ok((
+                "target_files".to_owned(),
+                vec![
+                    ("path".to_owned(), String::from("c.jpg").into()),
+                    ("mtime".to_owned(), 1.into()),
+                    ("size".to_owned(), 1.into()),
+                    ("digest".to_owned(), vec![0u8; 3].into()),
+                ],
+            ))])
+            .unwrap();
+        let found = store.health_check(now).unwrap();
+        assert_eq!(
+            found,
+            HealthCheck {
+                malformed_digests: vec![("target_files", 1)],
+                implausible_mtimes: vec![("source_files", 1)],
+                unresolved_sources: 1,
+                ..HealthCheck::default()
+            }
+        );
+        assert!(describe(&found).contains("target_files: 1 rows with malformed digests"));
+
+        // a digest counted without its triggers, as an old version of the tool would write it.
+        rusqlite::Connection::open(&database_file)
+            .unwrap()
+            .execute_batch("DELETE FROM digests")
+            .unwrap();
+        assert_eq!(store.health_check(now).unwrap().stale_digests, 3);
+        assert!(repair(&database_file).is_err());
+        assert_eq!(store.health_check(now).unwrap().stale_digests, 0);
+    }
+}
@@ -0,0 +1,270 @@
+//! `db export --format jsonl` and `db import`: the whole catalogue as one JSON object per row, to
+//! move it to another machine or keep in backups in a form which doesn't need sqlite to read.
+//! Paths are written as text, digests as hex and times as RFC 3339, and the first line names the
+//! schema version the rows are of, which a database imported into must be at.
+
+use std::{
+    fs,
+    io::{BufRead, Write},
+    path::Path,
+};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use clap::ValueEnum;
+use eyre::{OptionExt, Result, WrapErr, bail, eyre};
+use rusqlite::types::{FromSql, ToSql, Value, ValueRef};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, json};
+
+use crate::{
+    digest::ContentHash,
+    store::{LATEST_SCHEMA_VERSION, PhotoSyncStore},
+};
+
+/// Columns holding a path, stored as its bytes.
+const PATH_COLUMNS: &[&str] = &[
+    "path",
+    "from_path",
+    "to_path",
+    "target_path",
+    "source_path",
+    "work_dir",
+    "still_path",
+    "video_path",
+    "sidecar_path",
+    "parent_path",
+    "trashed_to",
+];
+
+/// Columns holding a content digest.
+const DIGEST_COLUMNS: &[&str] = &["digest", "output_digest"];
+
+/// Columns holding a time, stored as seconds since the epoch.
+const TIME_COLUMNS: &[&str] = &[
+    "mtime",
+    "last_verified",
+    "started_at",
+    "finished_at",
+    "expires_at",
+    "updated_at",
+    "applied_at",
+    "repaired_at",
+    "created_at",
+    "archived_at",
+    "deleted_at",
+    "failed_at",
+    "decided_at",
+    "added_at",
+    "downloaded_at",
+];
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum ExportFormat {
+    /// The file rows, as a manifest which `sync --manifest` and the like read.
+    #[default]
+    Manifest,
+    /// Every row of every table, which `db import` can rebuild the database from.
+    Jsonl,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    schema_version: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Row {
+    table: String,
+    row: Map<String, serde_json::Value>,
+}
+
+/// Writes every row of `store`'s catalogue to `writer`.
+pub fn export_jsonl(store: &PhotoSyncStore, mut writer: impl Write) -> Result<()> {
+    serde_json::to_writer(
+        &mut writer,
+        &Header {
+            schema_version: LATEST_SCHEMA_VERSION,
+        },
+    )?;
+    writer.write_all(b"\n")?;
+    store.dump_rows(|table, columns, values| {
+        let row = columns
+            .iter()
+            .zip(values)
+            .map(|(column, value)| Ok((column.clone(), encode(column, value)?)))
+            .collect::<Result<_>>()?;
+        let row = Row {
+            table: table.to_string(),
+            row,
+        };
+        serde_json::to_writer(&mut writer, &row)?;
+        writer.write_all(b"\n")?;
+        Ok(())
+    })?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Builds a database at `database_file`, which mustn't exist yet, from an export read from
+/// `reader`. Nothing is left behind if it can't be.
+pub fn import_jsonl(reader: impl BufRead, database_file: &Path) -> Result<()> {
+    if database_file.exists() {
+        bail!("{database_file:?} already exists; import into a new database");
+    }
+    let imported = import_into(reader, database_file);
+    if imported.is_err() {
+        for suffix in ["", "-wal", "-shm"] {
+            let mut path = database_file.as_os_str().to_owned();
+            path.push(suffix);
+            let _ = fs::remove_file(path);
+        }
+    }
+    imported
+}
+
+fn import_into(reader: impl BufRead, database_file: &Path) -> Result<()> {
+    let mut lines = reader.lines();
+    let header = lines.next().ok_or_eyre("the export is empty")??;
+    let Header { schema_version } =
+        serde_json::from_str(&header).wrap_err("the export has no header")?;
+    if schema_version != LATEST_SCHEMA_VERSION {
+        bail!(
+            "the export is of schema version {schema_version}, but this version of the tool \
+             writes {LATEST_SCHEMA_VERSION}; import it with the version which exported it"
+        );
+    }
+    let store = PhotoSyncStore::new(database_file.to_path_buf())?;
+    store.load_rows(lines.enumerate().map(|(idx, line)| {
+        let Row { table, row } = serde_json::from_str(&line?)
+            .wrap_err_with(|| format!("line {} of the export isn't a row", idx + 2))?;
+        let values = row
+            .into_iter()
+            .map(|(column, value)| Ok((column.clone(), decode(&column, value)?)))
+            .collect::<Result<_>>()?;
+        Ok((table, values))
+    }))
+}
+
+/// `value`, of `column`, as it's exported.
+fn encode(column: &str, value: Value) -> Result<serde_json::Value> {
+    Ok(match value {
+        Value::Null => serde_json::Value::Null,
+        Value::Integer(secs) if TIME_COLUMNS.contains(&column) => {
+            let time = DateTime::<Utc>::from_timestamp(secs, 0)
+                .ok_or_else(|| eyre!("{secs} in {column} isn't a time"))?;
+            json!(time.to_rfc3339_opts(SecondsFormat::Secs, true))
+        }
+        Value::Integer(n) => json!(n),
+        Value::Real(n) => json!(n),
+        Value::Text(text) => json!(text),
+        Value::Blob(bytes) if DIGEST_COLUMNS.contains(&column) => {
+            json!(ContentHash::column_result(ValueRef::Blob(&bytes))?.to_string())
+        }
+        Value::Blob(bytes) if PATH_COLUMNS.contains(&column) => match String::from_utf8(bytes) {
+            Ok(path) => json!(path),
+            Err(e) => json!({ "hex": hex(e.as_bytes()) }),
+        },
+        Value::Blob(bytes) => json!({ "hex": hex(&bytes) }),
+    })
+}
+
+/// The value of `column` exported as `value`.
+fn decode(column: &str, value: serde_json::Value) -> Result<Value> {
+    Ok(match value {
+        serde_json::Value::Null => Value::Null,
+        serde_json::Value::Bool(b) => Value::Integer(b.into()),
+        serde_json::Value::Number(n) => match n.as_i64() {
+            Some(n) => Value::Integer(n),
+            None => Value::Real(n.as_f64().ok_or_eyre("a number too large")?),
+        },
+        serde_json::Value::String(time) if TIME_COLUMNS.contains(&column) => {
+            Value::Integer(DateTime::parse_from_rfc3339(&time)?.timestamp())
+        }
+        serde_json::Value::String(digest) if DIGEST_COLUMNS.contains(&column) => {
+            match digest.parse::<ContentHash>()?.to_sql()? {
+                rusqlite::types::ToSqlOutput::Borrowed(value) => value.into(),
+                rusqlite::types::ToSqlOutput::Owned(value) => value,
+                _ => bail!("{digest:?} can't be stored"),
+            }
+        }
+        serde_json::Value::String(path) if PATH_COLUMNS.contains(&column) => {
+            Value::Blob(path.into_bytes())
+        }
+        serde_json::Value::String(text) => Value::Text(text),
+        serde_json::Value::Object(object) => match object.get("hex") {
+            Some(serde_json::Value::String(hex)) => Value::Blob(unhex(hex)?),
+            _ => bail!("{column} has an object which isn't a hex blob"),
+        },
+        serde_json::Value::Array(_) => bail!("{column} has an array"),
+    })
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn unhex(hex: &str) -> Result<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        bail!("{hex:?} isn't hex");
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|idx| Ok(u8::from_str_radix(&hex[idx..idx + 2], 16)?))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, time::SystemTime};
+
+    use super::*;
+    use crate::digest::HashAlgorithm;
+
+    #[test]
+    fn exports_rebuild_the_catalogue() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = PhotoSyncStore::new(dir.path().join("old.sqlite")).unwrap();
+        let run = store.begin_run("laptop").unwrap();
+        let taken = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_500_000_000);
+        let digest = HashAlgorithm::Blake3
+            .digest_reader(&mut &b"alpha"[..])
+            .unwrap();
+        store
+            .mark_exists_in_old_target(run, Path::new("2017/IMG_1.JPG"), taken, 5, &digest)
+            .unwrap();
+        #[cfg(unix)]
+        let odd = {
+            use std::os::unix::ffi::OsStrExt;
+            PathBuf::from(std::ffi::OsStr::from_bytes(b"2017/caf\xe9.jpg"))
+        };
+        #[cfg(not(unix))]
+        let odd = PathBuf::from("2017/cafe.jpg");
+        store
+            .mark_exists_in_old_target(run, &odd, taken, 5, &digest)
+            .unwrap();
+
+        let mut export = Vec::new();
+        export_jsonl(&store, &mut export).unwrap();
+        let text = String::from_utf8(export.clone()).unwrap();
+        assert!(text.contains(r#""path":"2017/IMG_1.JPG""#));
+        assert!(text.contains(r#""mtime":"2017-07-14T02:40:00Z""#));
+        assert!(text.contains(&format!(r#""digest":"{digest}""#)));
+
+        let imported = dir.path().join("new.sqlite");
+        import_jsonl(&export[..], &imported).unwrap();
+        let mut reexport = Vec::new();
+        export_jsonl(
+            &PhotoSyncStore::new(imported.clone()).unwrap(),
+            &mut reexport,
+        )
+        .unwrap();
+        assert_eq!(String::from_utf8(reexport).unwrap(), text);
+        assert!(import_jsonl(&export[..], &imported).is_err());
+
+        let broken = dir.path().join("broken.sqlite");
+        let mut truncated = export.clone();
+        truncated.extend_from_slice(b"{\"table\":\"nonsense\",\"row\":{}}\n");
+        assert!(import_jsonl(&truncated[..], &broken).is_err());
+        assert!(!broken.exists());
+    }
+}
@@ -0,0 +1,123 @@
+//! `db stats`: totals over a catalogue, for checking on the state of a long-lived database
+//! without opening it in sqlite3.
+
+use std::{fs, path::Path, time::SystemTime};
+
+use chrono::{DateTime, Local};
+use eyre::Result;
+
+use crate::store::{Archive, CatalogueStats, PhotoSyncStore};
+
+pub fn print_stats(database_file: &Path, largest: usize) -> Result<()> {
+    let store = PhotoSyncStore::new(database_file.to_path_buf())?;
+    let stats = store.catalogue_stats(largest)?;
+    // the write-ahead log holds what's yet to be checkpointed into the database itself.
+    let mut wal = database_file.as_os_str().to_owned();
+    wal.push("-wal");
+    let size = [database_file, Path::new(&wal)]
+        .iter()
+        .filter_map(|path| fs::metadata(path).ok())
+        .map(|metadata| metadata.len())
+        .sum::<u64>();
+    print!("{}", summarise(&stats, size));
+    Ok(())
+}
+
+fn summarise(stats: &CatalogueStats, database_bytes: u64) -> String {
+    let mut summary = format!("database: {}MB\n", database_bytes / 1_000_000);
+    for (table, files, bytes) in &stats.tables {
+        summary += &format!("{table}: {files} files, {}MB\n", bytes / 1_000_000);
+    }
+    summary += &format!(
+        "{} distinct contents, {} duplicate files\n",
+        stats.unique_digests, stats.duplicates
+    );
+    if !stats.largest.is_empty() {
+        summary += "largest archived files:\n";
+    }
+    for (archive, path, size) in &stats.largest {
+        let dir = match archive {
+            Archive::OldOut => "old",
+            Archive::Out => "out",
+        };
+        summary += &format!("    {dir}:{path:?} ({}MB)\n", size / 1_000_000);
+    }
+    let time = |time: SystemTime| {
+        DateTime::<Local>::from(time)
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string()
+    };
+    for run in &stats.last_runs {
+        let finished = match run.finished_at {
+            Some(finished_at) => format!(", finished {}", time(finished_at)),
+            None => String::new(),
+        };
+        summary += &format!(
+            "last run of {:?}: {} ({}), started {}{finished}\n",
+            run.namespace,
+            run.id,
+            run.status,
+            time(run.started_at)
+        );
+    }
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::{digest::ContentHash, store::RunStatus};
+
+    #[test]
+    fn stats_total_each_table() {
+        let store = PhotoSyncStore::new_for_tests().unwrap();
+        let run = store.begin_run("laptop").unwrap();
+        let now = SystemTime::now();
+        let (a, b) = (ContentHash::new_for_tests(1), ContentHash::new_for_tests(2));
+        let old = |path: &str, size, digest| {
+            store
+                .mark_exists_in_old_target(run, Path::new(path), now, size, digest)
+                .unwrap()
+        };
+        old("1998/scan.jpg", 3_000_000, &a);
+        old("1998/scan copy.jpg", 3_000_000, &a);
+        store
+            .mark_transferred_from_source(run, "laptop", Path::new("IMG_1.MOV"), &b, now, 9_000_000)
+            .unwrap();
+        store
+            .mark_exists_in_target(run, Path::new("IMG_1.MOV"), now, 9_000_000, &b)
+            .unwrap();
+        store.finish_run(run, RunStatus::Succeeded).unwrap();
+
+        let stats = store.catalogue_stats(2).unwrap();
+        assert_eq!(
+            stats.tables,
+            [
+                ("source_files", 1, 9_000_000),
+                ("old_target_files", 2, 6_000_000),
+                ("target_files", 1, 9_000_000),
+                ("pending_digests", 0, 0),
+            ]
+        );
+        assert_eq!((stats.unique_digests, stats.duplicates), (2, 1));
+        assert_eq!(
+            stats.largest,
+            [
+                (Archive::Out, PathBuf::from("IMG_1.MOV"), 9_000_000),
+                (
+                    Archive::OldOut,
+                    PathBuf::from("1998/scan copy.jpg"),
+                    3_000_000
+                ),
+            ]
+        );
+        assert_eq!(stats.last_runs.len(), 1);
+        assert_eq!(stats.last_runs[0].status, "succeeded");
+        let summary = summarise(&stats, 4_000_000);
+        assert!(summary.contains("database: 4MB\n"));
+        assert!(summary.contains(r#"    out:"IMG_1.MOV" (9MB)"#));
+        assert!(summary.contains(r#"last run of "laptop": 1 (succeeded)"#));
+    }
+}
@@ -0,0 +1,104 @@
+//! Timing every statement run against the catalogue, so that runs bound by the store rather than
+//! by the disks can be told apart and the statements responsible found.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use rusqlite::{
+    Connection,
+    trace::{TraceEvent, TraceEventCodes},
+};
+use tracing::info;
+
+/// How many statements are listed in the summary.
+const SUMMARY_LEN: usize = 20;
+
+/// sqlite only takes a plain function, so what's traced is kept globally.
+static STATEMENTS: Mutex<Option<HashMap<String, StatementStats>>> = Mutex::new(None);
+static LOG_EACH: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StatementStats {
+    pub count: u64,
+    pub total: Duration,
+}
+
+/// Starts timing the statements run on `conn`, printing each as it finishes if `log_each`.
+pub fn trace(conn: &Connection, log_each: bool) {
+    LOG_EACH.store(log_each, Ordering::SeqCst);
+    conn.trace_v2(TraceEventCodes::SQLITE_TRACE_PROFILE, Some(record));
+}
+
+fn record(event: TraceEvent<'_>) {
+    let TraceEvent::Profile(statement, duration) = event else {
+        return;
+    };
+    let sql = statement.sql();
+    let sql = sql.split_whitespace().collect::<Vec<_>>().join(" ");
+    if LOG_EACH.load(Ordering::SeqCst) {
+        info!("db: {:.3}ms {sql}", duration.as_secs_f64() * 1000.0);
+    }
+    let mut statements = STATEMENTS.lock().unwrap();
+    let stats = statements.get_or_insert_default().entry(sql).or_default();
+    stats.count += 1;
+    stats.total += duration;
+}
+
+/// Every statement traced so far, those which took longest in total first.
+pub fn statements() -> Vec<(String, StatementStats)> {
+    let statements = STATEMENTS.lock().unwrap();
+    let mut statements: Vec<_> = statements
+        .iter()
+        .flatten()
+        .map(|(sql, stats)| (sql.clone(), *stats))
+        .collect();
+    statements.sort_by(|a, b| b.1.total.cmp(&a.1.total).then_with(|| a.0.cmp(&b.0)));
+    statements
+}
+
+/// Prints the statements which took longest, and how long was spent in the store overall.
+pub fn print_summary() {
+    let statements = statements();
+    let total: Duration = statements.iter().map(|(_, s)| s.total).sum();
+    let count: u64 = statements.iter().map(|(_, s)| s.count).sum();
+    info!(
+        "ran {count} statements against the catalogue, taking {:.1}s:",
+        total.as_secs_f64()
+    );
+    for (sql, stats) in statements.into_iter().take(SUMMARY_LEN) {
+        info!(
+            "    {:>8.1}ms {:>8}x {sql}",
+            stats.total.as_secs_f64() * 1000.0,
+            stats.count
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn statements_are_counted() {
+        let conn = Connection::open_in_memory().unwrap();
+        trace(&conn, false);
+        conn.execute_batch("CREATE TABLE traced (x INTEGER)")
+            .unwrap();
+        for x in 0..3 {
+            conn.execute("INSERT INTO traced (x)\n    VALUES (?1)", [x])
+                .unwrap();
+        }
+        let statements = statements();
+        let insert = statements
+            .iter()
+            .find(|(sql, _)| sql == "INSERT INTO traced (x) VALUES (?1)")
+            .unwrap();
+        assert_eq!(insert.1.count, 3);
+    }
+}
@@ -0,0 +1,181 @@
+//! Replacing duplicate files in the archive with hardlinks to one copy, reclaiming their space.
+
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, Read},
+    path::{Path, PathBuf},
+};
+
+use eyre::Result;
+
+use crate::{
+    DedupeArgs,
+    platform::same_file,
+    store::{Archive, PhotoSyncStore},
+    trash::Trash,
+};
+
+/// How much of each file is compared at a time.
+const CHUNK: u64 = 1024 * 1024;
+
+pub fn dedupe(args: DedupeArgs) -> Result<()> {
+    let store = PhotoSyncStore::new(args.database_file)?;
+    let dir_of = |archive| match archive {
+        Archive::OldOut => args.old_out_dir.as_deref(),
+        Archive::Out => args.out_dir.as_deref(),
+    };
+    let trashes = [Archive::OldOut, Archive::Out].map(|archive| {
+        Some((
+            archive,
+            Trash::new(dir_of(archive)?, args.trash_retention_days),
+        ))
+    });
+    let trash_of = |archive| {
+        trashes
+            .iter()
+            .flatten()
+            .find_map(|(a, trash)| (*a == archive).then_some(trash))
+            .expect("files are only deduped in the directories given")
+    };
+
+    let mut reclaimed = 0;
+    let mut linked = 0;
+    for group in store.duplicates()? {
+        let paths: Vec<(Archive, PathBuf)> = group
+            .files
+            .iter()
+            .filter_map(|(archive, path)| Some((*archive, dir_of(*archive)?.join(path))))
+            .filter(|(_, path)| path.is_file())
+            .collect();
+        let Some(((_, keeper), others)) = paths.split_first() else {
+            continue;
+        };
+        for (archive, other) in others {
+            match link_if_identical(keeper, other, trash_of(*archive), args.dry_run) {
+                Ok(true) => {
+                    println!("{other:?} -> {keeper:?}");
+                    reclaimed += group.size;
+                    linked += 1;
+                }
+                Ok(false) => {}
+                Err(e) => println!("could not link {other:?} to {keeper:?}: {e}"),
+            }
+        }
+    }
+
+    let verb = if args.dry_run {
+        "would reclaim"
+    } else {
+        "reclaimed"
+    };
+    println!(
+        "{verb} {}MB by linking {linked} duplicate files, once they're emptied from the trash",
+        reclaimed / 1_000_000
+    );
+    if !args.dry_run {
+        for (_, trash) in trashes.iter().flatten() {
+            trash.empty_expired()?;
+        }
+    }
+    Ok(())
+}
+
+/// Replaces `duplicate` with a hardlink to `keeper` if their bytes are identical and they aren't
+/// already linked, returning whether it did (or, on a dry run, would have). What was at
+/// `duplicate` is kept in `trash`.
+fn link_if_identical(
+    keeper: &Path,
+    duplicate: &Path,
+    trash: &Trash,
+    dry_run: bool,
+) -> Result<bool> {
+    if same_file(keeper, duplicate)? == Some(true) {
+        return Ok(false);
+    }
+    // the catalogue may be stale, so nothing is linked on the strength of a digest alone.
+    if !identical(keeper, duplicate)? {
+        println!("{duplicate:?} no longer matches {keeper:?}, leaving it");
+        return Ok(false);
+    }
+    if dry_run {
+        return Ok(true);
+    }
+
+    let name = duplicate.file_name().unwrap_or_default().to_string_lossy();
+    let staged = duplicate.with_file_name(format!(".{name}.dedupe"));
+    trash.keep_copy(duplicate)?;
+    fs::hard_link(keeper, &staged)?;
+    // renamed over the duplicate, so it's never missing even if this is interrupted.
+    if let Err(e) = fs::rename(&staged, duplicate) {
+        let _ = fs::remove_file(&staged);
+        return Err(e.into());
+    }
+    Ok(true)
+}
+
+fn identical(a: &Path, b: &Path) -> io::Result<bool> {
+    if a.metadata()?.len() != b.metadata()?.len() {
+        return Ok(false);
+    }
+    same_content(File::open(a)?, File::open(b)?)
+}
+
+/// Whether two readers yield the same bytes.
+pub fn same_content(a: impl Read, b: impl Read) -> io::Result<bool> {
+    let (mut a, mut b) = (BufReader::new(a), BufReader::new(b));
+    let (mut chunk_a, mut chunk_b) = (Vec::new(), Vec::new());
+    loop {
+        chunk_a.clear();
+        chunk_b.clear();
+        a.by_ref().take(CHUNK).read_to_end(&mut chunk_a)?;
+        b.by_ref().take(CHUNK).read_to_end(&mut chunk_b)?;
+        if chunk_a != chunk_b {
+            return Ok(false);
+        }
+        if chunk_a.is_empty() {
+            return Ok(true);
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identical_files_are_linked() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name| dir.path().join(name);
+        fs::write(path("keeper.jpg"), "photo").unwrap();
+        fs::write(path("duplicate.jpg"), "photo").unwrap();
+        fs::write(path("stale.jpg"), "other").unwrap();
+        let trash = Trash::new(dir.path(), 30);
+
+        assert!(
+            link_if_identical(&path("keeper.jpg"), &path("duplicate.jpg"), &trash, true).unwrap()
+        );
+        assert_eq!(
+            same_file(&path("keeper.jpg"), &path("duplicate.jpg")).unwrap(),
+            Some(false)
+        );
+
+        assert!(
+            link_if_identical(&path("keeper.jpg"), &path("duplicate.jpg"), &trash, false).unwrap()
+        );
+        assert_eq!(
+            same_file(&path("keeper.jpg"), &path("duplicate.jpg")).unwrap(),
+            Some(true)
+        );
+        assert_eq!(fs::read_to_string(path("duplicate.jpg")).unwrap(), "photo");
+        // already linked, so there's nothing more to reclaim.
+        assert!(
+            !link_if_identical(&path("keeper.jpg"), &path("duplicate.jpg"), &trash, false).unwrap()
+        );
+
+        assert!(
+            !link_if_identical(&path("keeper.jpg"), &path("stale.jpg"), &trash, false).unwrap()
+        );
+        // the duplicate replaced is kept in the trash.
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 4);
+    }
+}
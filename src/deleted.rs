@@ -0,0 +1,73 @@
+//! Reporting files transferred from the in directory which have since been deleted from it, e.g.
+//! by iCloud's storage optimisation or by accident, so the archive's copy can be looked after.
+
+use std::{collections::HashSet, path::Path};
+
+use eyre::Result;
+
+use crate::{
+    DeletedArgs,
+    store::{PhotoSyncStore, SourceFile},
+};
+
+pub fn deleted(args: DeletedArgs) -> Result<()> {
+    let store = PhotoSyncStore::new(args.database_file)?;
+    let mut deleted = find_deleted(store.source_files()?, &args.in_dir, &args.machine_id);
+    // files `--move` removed were meant to go.
+    let moved: HashSet<_> = store
+        .deleted_sources(&args.machine_id)?
+        .into_iter()
+        .collect();
+    deleted.retain(|file| !moved.contains(&file.path));
+    for file in &deleted {
+        println!("DELETED {:?} ({} bytes)", file.path, file.size);
+    }
+    let bytes: u64 = deleted.iter().map(|file| file.size).sum();
+    println!(
+        "{} transferred files ({}MB) have been deleted from {:?}",
+        deleted.len(),
+        bytes / 1_000_000,
+        args.in_dir
+    );
+    Ok(())
+}
+
+/// The files in `namespace` which are no longer in `in_dir`.
+fn find_deleted(files: Vec<SourceFile>, in_dir: &Path, namespace: &str) -> Vec<SourceFile> {
+    files
+        .into_iter()
+        .filter(|file| file.namespace == namespace && !in_dir.join(&file.path).exists())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use super::*;
+    use crate::digest::ContentHash;
+
+    #[test]
+    fn only_files_gone_from_this_source_are_deleted() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("kept.jpg"), "a").unwrap();
+        let file = |namespace: &str, path: &str| SourceFile {
+            namespace: namespace.to_string(),
+            path: PathBuf::from(path),
+            size: 1,
+            digest: ContentHash::new_for_tests(1),
+            in_old_target: false,
+        };
+        let deleted = find_deleted(
+            vec![
+                file("laptop", "kept.jpg"),
+                file("laptop", "gone.jpg"),
+                file("phone", "elsewhere.jpg"),
+            ],
+            dir.path(),
+            "laptop",
+        );
+        assert_eq!(deleted.len(), 1);
+        assert_eq!(deleted[0].path, Path::new("gone.jpg"));
+    }
+}
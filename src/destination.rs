@@ -0,0 +1,93 @@
+//! Making sure the out directory is still the archive, and not e.g. the empty mount point left
+//! behind when a NAS drops off the network, which would otherwise be filled with files that the
+//! catalogue then believes are safely archived.
+
+use std::{fs, path::PathBuf};
+
+use eyre::{Result, WrapErr, bail};
+use tempfile::NamedTempFile;
+use walkdir::DirEntry;
+
+use crate::devices::device_of;
+
+/// The file marking a directory as an archive. It's written when the first run starts, and must
+/// be there for every run after.
+pub const MARKER: &str = ".photo-sync-destination";
+
+/// Checks that the out directory stays the filesystem a run started on, and stays writable.
+pub struct Destination {
+    out_dir: PathBuf,
+    device: u64,
+}
+
+impl Destination {
+    /// Checks `out_dir` before a run. `catalogued` is whether the catalogue already knows of files
+    /// in it, in which case it must already be marked.
+    pub fn open(out_dir: PathBuf, catalogued: bool) -> Result<Self> {
+        let marker = out_dir.join(MARKER);
+        if !marker.exists() {
+            if catalogued {
+                bail!(
+                    "{out_dir:?} is missing {MARKER}, although the catalogue has files in it; if it's a mount, is it still mounted? (--mark-destination marks it, if it's known to be)"
+                );
+            }
+            fs::create_dir_all(&out_dir)?;
+            fs::write(
+                &marker,
+                "this directory is a photo archive; don't remove this file\n",
+            )
+            .wrap_err_with(|| format!("could not mark {out_dir:?} as an archive"))?;
+        }
+        let destination = Self {
+            device: device_of(&out_dir),
+            out_dir,
+        };
+        destination.check()?;
+        Ok(destination)
+    }
+
+    /// Fails if the out directory has lost its marker, moved to another filesystem or can't be
+    /// written to.
+    pub fn check(&self) -> Result<()> {
+        if !self.out_dir.join(MARKER).exists() {
+            bail!("{:?} has lost its {MARKER}", self.out_dir);
+        }
+        if device_of(&self.out_dir) != self.device {
+            bail!(
+                "{:?} is on a different filesystem than when the run started",
+                self.out_dir
+            );
+        }
+        // a read-only remount only shows up when something is written.
+        NamedTempFile::new_in(&self.out_dir)
+            .wrap_err_with(|| format!("could not write to {:?}", self.out_dir))?;
+        Ok(())
+    }
+}
+
+/// Whether a walk of an archive directory has reached its marker, which isn't part of the archive.
+pub fn is_marker(entry: &DirEntry) -> bool {
+    entry.depth() == 1 && entry.file_name() == MARKER
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_a_marked_directory_is_trusted() {
+        let dir = tempfile::tempdir().unwrap();
+        let out_dir = dir.path().join("out");
+
+        // an empty catalogue is a first run, so the directory is marked.
+        let destination = Destination::open(out_dir.clone(), false).unwrap();
+        assert!(out_dir.join(MARKER).is_file());
+        Destination::open(out_dir.clone(), true).unwrap();
+
+        // e.g. the mount dropped, leaving the mount point behind.
+        fs::remove_file(out_dir.join(MARKER)).unwrap();
+        assert!(destination.check().is_err());
+        assert!(Destination::open(out_dir.clone(), true).is_err());
+        assert!(!out_dir.join(MARKER).exists());
+    }
+}
@@ -0,0 +1,126 @@
+//! Spreading work across threads by the device each file is on, so that every device is read one
+//! file at a time in walk order. Spinning disks then see mostly sequential reads, rather than the
+//! seeks of many threads reading all over them at once.
+
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc,
+    },
+    thread,
+};
+
+use eyre::Result;
+
+/// How many items may wait for a device's worker before the caller blocks.
+const QUEUE_DEPTH: usize = 1024;
+
+/// The device holding `path`, or 0 where that can't be told.
+pub fn device_of(path: &Path) -> u64 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        path.symlink_metadata().map_or(0, |m| m.dev())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        0
+    }
+}
+
+/// Runs `work` on each of `items`, one at a time and in order for each device, but with devices
+/// in parallel. Stops at the first error, and otherwise returns what `work` returned `Some` for.
+pub fn try_map_per_device<T: Send, R: Send>(
+    items: impl IntoIterator<Item = T>,
+    device: impl Fn(&T) -> u64,
+    work: impl Fn(T) -> Result<Option<R>> + Sync,
+) -> Result<Vec<R>> {
+    let failed = AtomicBool::new(false);
+    thread::scope(|s| {
+        let mut queues = HashMap::new();
+        let mut workers = Vec::new();
+        for item in items {
+            if failed.load(Ordering::SeqCst) {
+                break;
+            }
+            let queue = queues.entry(device(&item)).or_insert_with(|| {
+                let (queue, items) = mpsc::sync_channel::<T>(QUEUE_DEPTH);
+                let (work, failed) = (&work, &failed);
+                workers.push(s.spawn(move || {
+                    let mut results = Vec::new();
+                    for item in items {
+                        match work(item) {
+                            Ok(Some(result)) => results.push(result),
+                            Ok(None) => {}
+                            Err(e) => {
+                                failed.store(true, Ordering::SeqCst);
+                                return Err(e);
+                            }
+                        }
+                    }
+                    Ok(results)
+                }));
+                queue
+            });
+            // a worker which has failed has stopped listening, and its error is returned below.
+            let _ = queue.send(item);
+        }
+        // closed, so the workers finish once they've drained them.
+        drop(queues);
+
+        let mut results = Vec::new();
+        for worker in workers {
+            results.extend(worker.join().expect("device worker panicked")?);
+        }
+        Ok(results)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use eyre::bail;
+
+    use super::*;
+
+    #[test]
+    fn devices_are_worked_through_in_order() {
+        let items = (0..100).map(|n| (n % 3, n));
+        let done = Mutex::new(Vec::new());
+        let results = try_map_per_device(
+            items.clone(),
+            |(device, _)| *device,
+            |(device, n)| {
+                done.lock().unwrap().push((device, n));
+                Ok((n % 10 == 0).then_some(n))
+            },
+        )
+        .unwrap();
+        let mut results = results;
+        results.sort();
+        assert_eq!(results, (0..100).step_by(10).collect::<Vec<_>>());
+        let done = done.into_inner().unwrap();
+        for device in 0..3 {
+            let order: Vec<_> = done.iter().filter(|(d, _)| *d == device).collect();
+            assert!(order.is_sorted(), "{order:?}");
+            assert_eq!(
+                order.len(),
+                items.clone().filter(|(d, _)| *d == device).count()
+            );
+        }
+
+        let failed = try_map_per_device(
+            0..100,
+            |_| 0,
+            |n| match n {
+                5 => bail!("failed on {n}"),
+                _ => Ok(Some(n)),
+            },
+        );
+        assert_eq!(failed.unwrap_err().to_string(), "failed on 5");
+    }
+}
@@ -0,0 +1,264 @@
+//! `diff`: the source files which differ between two states of the in directories, as one JSON
+//! object per line, to review what a sync will find new before it's left to run unattended, or
+//! what a run did. The in directories as they are now are compared against the catalogue, or the
+//! catalogue after one run against it after another.
+
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use eyre::{Result, WrapErr, ensure};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use walkdir::WalkDir;
+
+use crate::{
+    DiffArgs,
+    digest::HashAlgorithm,
+    ignorelist::IgnoreList,
+    platform::FileInfo,
+    sources::{self, Source},
+    store::{Change, PhotoSyncStore, SourceChange, WasTransferredFromSourceResult},
+    syncignore::SyncIgnores,
+    trash,
+};
+
+pub fn diff(args: DiffArgs) -> Result<()> {
+    let store = PhotoSyncStore::new(args.database_file)?;
+    let changes = match args.runs.as_deref() {
+        Some(&[from, to]) => {
+            ensure!(from < to, "run {from} must come before run {to}");
+            store.source_changes_between(from, to)?
+        }
+        _ => {
+            let sources = sources::sources(&args.in_dir, &args.machine_id)?;
+            uncatalogued_changes(&store, &sources, args.hash_algo)?
+        }
+    };
+    match args.output {
+        Some(output) => {
+            let file =
+                File::create(&output).wrap_err_with(|| format!("could not create {output:?}"))?;
+            write_changes(BufWriter::new(file), &changes)
+        }
+        None => write_changes(io::stdout().lock(), &changes),
+    }
+}
+
+fn write_changes(mut writer: impl Write, changes: &[SourceChange]) -> Result<()> {
+    for change in changes {
+        serde_json::to_writer(&mut writer, change)?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// How each of `sources` differs from what the catalogue has of it, hashing the files which are
+/// new or changed by `algorithm`. Files `.syncignore` files or `ignore add` leave out are too, but
+/// every other file is listed, whatever the filters a sync is given would leave out.
+fn uncatalogued_changes(
+    store: &PhotoSyncStore,
+    sources: &[Source],
+    algorithm: HashAlgorithm,
+) -> Result<Vec<SourceChange>> {
+    let mut changes = Vec::new();
+    let mut seen = HashSet::new();
+    let ignore_list = IgnoreList::new(&store.ignored_paths()?)?;
+    for source in sources {
+        let ignores = SyncIgnores::new(&source.dir);
+        let is_ignored = |full_path: &Path, is_dir| {
+            ignores.is_ignored(full_path, is_dir)
+                || (full_path.strip_prefix(&source.dir))
+                    .is_ok_and(|path| ignore_list.is_ignored(path))
+        };
+        // walked first, as the `.syncignore` files are read as it goes.
+        let files = WalkDir::new(&source.dir)
+            .into_iter()
+            .filter_entry(|entry| {
+                entry.depth() == 0
+                    || !trash::is_trash(entry)
+                        && !is_ignored(entry.path(), entry.file_type().is_dir())
+            })
+            .filter(|entry| !matches!(entry, Ok(entry) if !entry.file_type().is_file()))
+            .collect::<walkdir::Result<Vec<_>>>()?;
+        let found: Vec<(PathBuf, Option<SourceChange>)> = files
+            .into_par_iter()
+            .map(|entry| {
+                let path = entry.path().strip_prefix(&source.dir)?.to_path_buf();
+                let FileInfo { size, modified } = FileInfo::of(entry.path())?;
+                let (change, previous_digest) = match store.was_transferred_from_source(
+                    &source.namespace,
+                    &path,
+                    modified,
+                    size,
+                )? {
+                    WasTransferredFromSourceResult::Transferred => return Ok((path, None)),
+                    WasTransferredFromSourceResult::New => (Change::Added, None),
+                    WasTransferredFromSourceResult::NewMetadata { digest, .. } => {
+                        (Change::Changed, Some(digest))
+                    }
+                };
+                let digest = algorithm
+                    .digest(entry.path())
+                    .wrap_err_with(|| format!("could not hash {:?}", entry.path()))?;
+                // a digest by another algorithm is never equal, so it's taken to have changed.
+                let change = match previous_digest {
+                    Some(previous) if previous == digest => Change::Touched,
+                    _ => change,
+                };
+                let change = SourceChange {
+                    change,
+                    namespace: source.namespace.clone(),
+                    path: path.clone(),
+                    size,
+                    digest,
+                    previous_digest,
+                };
+                Ok::<_, eyre::Error>((path, Some(change)))
+            })
+            .collect::<Result<_>>()?;
+        for (path, change) in found {
+            seen.insert((source.namespace.as_str(), path));
+            changes.extend(change);
+        }
+    }
+    for file in store.source_files()? {
+        let Some(source) = sources.iter().find(|s| s.namespace == file.namespace) else {
+            continue;
+        };
+        // one ignored since it was transferred is left out, rather than gone.
+        if !seen.contains(&(file.namespace.as_str(), file.path.clone()))
+            && !source.dir.join(&file.path).exists()
+        {
+            changes.push(SourceChange {
+                change: Change::Removed,
+                namespace: file.namespace,
+                path: file.path,
+                size: file.size,
+                digest: file.digest,
+                previous_digest: None,
+            });
+        }
+    }
+    changes.sort_by(|a, b| (&a.namespace, &a.path).cmp(&(&b.namespace, &b.path)));
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path, time::SystemTime};
+
+    use super::*;
+    use crate::{digest::ContentHash, sources::SourceKind};
+
+    #[test]
+    fn changes_are_listed_against_the_catalogue_and_between_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = PhotoSyncStore::new_for_tests().unwrap();
+        let algorithm = HashAlgorithm::Sha256;
+        let catalogue = |run, path: &str| {
+            let full_path = dir.path().join(path);
+            let info = FileInfo::of(&full_path).unwrap();
+            let digest = algorithm.digest(&full_path).unwrap();
+            store
+                .mark_transferred_from_source(
+                    run,
+                    "",
+                    Path::new(path),
+                    &digest,
+                    info.modified,
+                    info.size,
+                )
+                .unwrap();
+            digest
+        };
+        for (path, content) in [("same.jpg", "same"), ("edited.jpg", "before")] {
+            fs::write(dir.path().join(path), content).unwrap();
+        }
+        let first = store.begin_run("").unwrap();
+        let same = catalogue(first, "same.jpg");
+        let before = catalogue(first, "edited.jpg");
+        let gone = ContentHash::new_for_tests(1);
+        store
+            .mark_transferred_from_source(
+                first,
+                "",
+                Path::new("gone.jpg"),
+                &gone,
+                SystemTime::now(),
+                4,
+            )
+            .unwrap();
+        fs::write(dir.path().join("edited.jpg"), "after editing").unwrap();
+        fs::write(dir.path().join("new.jpg"), "new").unwrap();
+        // as a sync would, these are left out.
+        fs::write(dir.path().join(".syncignore"), "skipped.jpg").unwrap();
+        fs::write(dir.path().join("skipped.jpg"), "skipped").unwrap();
+        fs::write(dir.path().join("ignored.jpg"), "ignored").unwrap();
+        store
+            .add_ignored_path(Path::new("ignored.jpg"), false)
+            .unwrap();
+
+        let source = Source {
+            dir: dir.path().to_path_buf(),
+            namespace: String::new(),
+            kind: SourceKind::default(),
+        };
+        let changes = uncatalogued_changes(&store, &[source], algorithm).unwrap();
+        let listed: Vec<_> = (changes.iter())
+            .map(|c| (c.path.to_str().unwrap(), c.change, c.previous_digest))
+            .collect();
+        assert_eq!(
+            listed,
+            [
+                ("edited.jpg", Change::Changed, Some(before)),
+                ("gone.jpg", Change::Removed, None),
+                ("new.jpg", Change::Added, None),
+            ]
+        );
+
+        let second = store.begin_run("").unwrap();
+        let new = catalogue(second, "new.jpg");
+        store
+            .record_deleted_source(second, "", Path::new("same.jpg"), &same, 4)
+            .unwrap();
+        let info = FileInfo::of(&dir.path().join("edited.jpg")).unwrap();
+        for (from, to) in [
+            ("edited.jpg", "moved.jpg"),
+            ("moved.jpg", "moved again.jpg"),
+        ] {
+            store
+                .rename_source(
+                    second,
+                    "",
+                    Path::new(from),
+                    Path::new(to),
+                    info.modified,
+                    info.size,
+                )
+                .unwrap();
+        }
+        let between = store.source_changes_between(first, second).unwrap();
+        let listed: Vec<_> = (between.iter())
+            .map(|c| (c.path.to_str().unwrap(), c.change, c.digest))
+            .collect();
+        assert_eq!(
+            listed,
+            [
+                ("edited.jpg", Change::Removed, before),
+                ("moved again.jpg", Change::Added, before),
+                ("new.jpg", Change::Added, new),
+                ("same.jpg", Change::Removed, same),
+            ]
+        );
+        assert!(
+            store
+                .source_changes_between(second, second + 1)
+                .unwrap()
+                .is_empty()
+        );
+    }
+}
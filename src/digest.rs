@@ -1,53 +1,297 @@
-use eyre::Result;
+use eyre::{Result, ensure};
 use std::{
+    collections::HashMap,
+    fmt::{self, Display},
     fs::File,
-    io::{self, Write},
-    path::Path,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
 };
 
-use rusqlite::{ToSql, types::FromSql};
+use clap::ValueEnum;
+use rayon::iter::{ParallelBridge, ParallelIterator};
+use rusqlite::{
+    ToSql,
+    types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef},
+};
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use walkdir::WalkDir;
+
+use crate::{destination, trash};
+
+/// Both algorithms give 256 bit digests.
+const DIGEST_BYTES: usize = 32;
+/// How much of a large file is read at a time to be hashed across threads, enough for each of
+/// many cores to have a good share of it.
+const PARALLEL_CHUNK_BYTES: usize = 16 * 1024 * 1024;
+
+/// How file contents are hashed. Catalogues can hold digests from either, e.g. once
+/// `--hash-algo` is changed, and each is only ever compared with digests of the same algorithm.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    ValueEnum,
+    Serialize,
+    Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    /// Several times faster than SHA-256, particularly on machines with many cores.
+    Blake3,
+}
+
+impl HashAlgorithm {
+    pub const ALL: [Self; 2] = [Self::Sha256, Self::Blake3];
+
+    /// How digests of this algorithm are prefixed when written out. SHA-256 digests aren't, as
+    /// they were the only ones before.
+    pub fn prefix(self) -> &'static str {
+        match self {
+            Self::Sha256 => "",
+            Self::Blake3 => "blake3:",
+        }
+    }
+
+    /// The length of this algorithm's digests as the catalogue stores them.
+    pub fn stored_length(self) -> usize {
+        self.prefix().len() + DIGEST_BYTES
+    }
+
+    pub fn digest(self, path: &Path) -> Result<ContentHash> {
+        self.digest_reader(&mut File::open(path)?)
+    }
+
+    pub fn digest_reader(self, reader: &mut impl Read) -> Result<ContentHash> {
+        let mut hasher = Hasher::new(self);
+        io::copy(reader, &mut hasher)?;
+        Ok(hasher.finalise())
+    }
 
-const SHA256_BYTES: usize = 32;
+    /// The same digest as [`Self::digest_reader`] gives, of large content, hashing it on every
+    /// thread of the current rayon pool which isn't busy, and reading on while it does. Only
+    /// BLAKE3, whose tree of chunks can be hashed in any order, can be split so; SHA-256 is hashed
+    /// as usual.
+    pub fn digest_reader_parallel(self, reader: &mut (impl Read + Send)) -> Result<ContentHash> {
+        match self {
+            Self::Sha256 => self.digest_reader(reader),
+            Self::Blake3 => digest_blake3_in_chunks(reader, PARALLEL_CHUNK_BYTES),
+        }
+    }
+}
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
-pub struct Sha256Hash([u8; SHA256_BYTES]);
+/// The digest of some content, and the algorithm it was taken with. Serialized as lowercase hex,
+/// as it is displayed, prefixed with the algorithm unless it's SHA-256.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct ContentHash {
+    algorithm: HashAlgorithm,
+    bytes: [u8; DIGEST_BYTES],
+}
 
-impl Sha256Hash {
+impl ContentHash {
     #[cfg(test)]
     pub fn new_for_tests(id: u8) -> Self {
-        Self([id; SHA256_BYTES])
+        Self {
+            algorithm: HashAlgorithm::Sha256,
+            bytes: [id; DIGEST_BYTES],
+        }
+    }
+
+    pub fn algorithm(&self) -> HashAlgorithm {
+        self.algorithm
+    }
+
+    pub fn as_bytes(&self) -> &[u8; DIGEST_BYTES] {
+        &self.bytes
     }
 }
 
-impl ToSql for Sha256Hash {
-    fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
-        self.0.to_sql()
+impl Display for ContentHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.algorithm.prefix())?;
+        self.bytes.iter().try_for_each(|b| write!(f, "{b:02x}"))
+    }
+}
+
+impl FromStr for ContentHash {
+    type Err = eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (algorithm, hex) = match s.strip_prefix(HashAlgorithm::Blake3.prefix()) {
+            Some(hex) => (HashAlgorithm::Blake3, hex),
+            None => (HashAlgorithm::Sha256, s),
+        };
+        ensure!(
+            hex.len() == DIGEST_BYTES * 2 && hex.is_ascii(),
+            "{s:?} is not a hex digest"
+        );
+        let mut bytes = [0; DIGEST_BYTES];
+        for (idx, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[idx * 2..idx * 2 + 2], 16)?;
+        }
+        Ok(Self { algorithm, bytes })
     }
 }
 
-impl FromSql for Sha256Hash {
-    fn column_result(value: rusqlite::types::ValueRef<'_>) -> rusqlite::types::FromSqlResult<Self> {
-        Ok(Self(FromSql::column_result(value)?))
+impl From<ContentHash> for String {
+    fn from(value: ContentHash) -> Self {
+        value.to_string()
+    }
+}
+
+impl TryFrom<String> for ContentHash {
+    type Error = eyre::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        value.parse()
+    }
+}
+
+/// Stored as the digest's bytes, prefixed like its text, so that existing SHA-256 digests are
+/// read as they always were, and digests of different algorithms never compare equal.
+impl ToSql for ContentHash {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(match self.algorithm {
+            HashAlgorithm::Sha256 => ToSqlOutput::Borrowed(ValueRef::Blob(&self.bytes)),
+            algorithm => {
+                let mut blob = algorithm.prefix().as_bytes().to_vec();
+                blob.extend_from_slice(&self.bytes);
+                ToSqlOutput::from(blob)
+            }
+        })
+    }
+}
+
+impl FromSql for ContentHash {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        let blob = value.as_blob()?;
+        let (algorithm, bytes) = HashAlgorithm::ALL
+            .into_iter()
+            .rev()
+            .find_map(|algorithm| {
+                Some((algorithm, blob.strip_prefix(algorithm.prefix().as_bytes())?))
+            })
+            .expect("SHA-256 digests have no prefix");
+        let bytes = bytes
+            .try_into()
+            .map_err(|_| FromSqlError::InvalidBlobSize {
+                expected_size: DIGEST_BYTES,
+                blob_size: bytes.len(),
+            })?;
+        Ok(Self { algorithm, bytes })
+    }
+}
+
+/// Hashes `reader` with BLAKE3 a chunk of `chunk_bytes` at a time, each across threads while the
+/// next is read.
+fn digest_blake3_in_chunks(
+    reader: &mut (impl Read + Send),
+    chunk_bytes: usize,
+) -> Result<ContentHash> {
+    let mut hasher = blake3::Hasher::new();
+    let (mut chunk, mut next) = (vec![0; chunk_bytes], vec![0; chunk_bytes]);
+    let mut len = read_chunk(reader, &mut chunk)?;
+    while len > 0 {
+        let (_, read) = rayon::join(
+            || hasher.update_rayon(&chunk[..len]),
+            || read_chunk(reader, &mut next),
+        );
+        len = read?;
+        std::mem::swap(&mut chunk, &mut next);
+    }
+    Ok(ContentHash {
+        algorithm: HashAlgorithm::Blake3,
+        bytes: hasher.finalize().into(),
+    })
+}
+
+/// Fills `buf` from `reader`, unless it ends first, returning how much was read.
+fn read_chunk(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+enum Hasher {
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Sha256 => Self::Sha256(Sha256::new()),
+            HashAlgorithm::Blake3 => Self::Blake3(Box::default()),
+        }
+    }
+
+    fn finalise(self) -> ContentHash {
+        match self {
+            Self::Sha256(hasher) => ContentHash {
+                algorithm: HashAlgorithm::Sha256,
+                bytes: hasher.finalize().into(),
+            },
+            Self::Blake3(hasher) => ContentHash {
+                algorithm: HashAlgorithm::Blake3,
+                bytes: hasher.finalize().into(),
+            },
+        }
+    }
+}
+
+impl Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Sha256(hasher) => hasher.update(buf),
+            Self::Blake3(hasher) => {
+                hasher.update(buf);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
     }
 }
 
 pub struct DigestWriter<W: Write> {
     inner: W,
-    sha256: Sha256,
+    hasher: Hasher,
 }
 
 impl<W: Write> DigestWriter<W> {
     pub fn new(inner: W) -> Self {
+        Self::with_algorithm(inner, HashAlgorithm::default())
+    }
+
+    pub fn with_algorithm(inner: W, algorithm: HashAlgorithm) -> Self {
         Self {
             inner,
-            sha256: Sha256::new(),
+            hasher: Hasher::new(algorithm),
         }
     }
 
-    pub fn finalise(mut self) -> Result<Sha256Hash> {
+    pub fn finalise(mut self) -> Result<ContentHash> {
         self.inner.flush()?;
-        Ok(Sha256Hash(self.sha256.finalize().into()))
+        Ok(self.hasher.finalise())
     }
 }
 
@@ -55,7 +299,7 @@ impl<W: Write> Write for DigestWriter<W> {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
         let written = self.inner.write(buf)?;
         if written > 0 {
-            self.sha256.update(&buf[..written]);
+            self.hasher.write_all(&buf[..written])?;
         }
         Ok(written)
     }
@@ -65,9 +309,72 @@ impl<W: Write> Write for DigestWriter<W> {
     }
 }
 
-pub fn digest(path: &Path) -> Result<Sha256Hash> {
-    let mut hasher = Sha256::new();
-    let mut file = File::open(path)?;
-    io::copy(&mut file, &mut hasher)?;
-    Ok(Sha256Hash(hasher.finalize().into()))
+/// The SHA-256 digest of the file at `path`.
+pub fn digest(path: &Path) -> Result<ContentHash> {
+    HashAlgorithm::default().digest(path)
+}
+
+pub fn digest_reader(reader: &mut impl Read) -> Result<ContentHash> {
+    HashAlgorithm::default().digest_reader(reader)
+}
+
+/// Digests every file under `dir`, in parallel, keyed by their paths relative to it.
+pub fn digest_tree(dir: &Path) -> Result<HashMap<PathBuf, ContentHash>> {
+    WalkDir::new(dir)
+        .into_iter()
+        .filter_entry(|entry| !trash::is_trash(entry) && !destination::is_marker(entry))
+        .par_bridge()
+        .filter(|entry| !matches!(entry, Ok(entry) if !entry.file_type().is_file()))
+        .map(|entry| {
+            let entry = entry?;
+            let digest = digest(entry.path())?;
+            Ok((entry.path().strip_prefix(dir)?.to_path_buf(), digest))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    use super::*;
+
+    #[test]
+    fn digests_keep_their_algorithm() {
+        let sha256 = HashAlgorithm::Sha256
+            .digest_reader(&mut &b"photo"[..])
+            .unwrap();
+        let blake3 = HashAlgorithm::Blake3
+            .digest_reader(&mut &b"photo"[..])
+            .unwrap();
+        assert_ne!(sha256, blake3);
+        assert!(blake3.to_string().starts_with("blake3:"));
+
+        let conn = Connection::open_in_memory().unwrap();
+        let stored = |digest: ContentHash| {
+            conn.query_row("SELECT ?1, length(?1)", [digest], |r| {
+                Ok((r.get::<_, ContentHash>(0)?, r.get::<_, usize>(1)?))
+            })
+            .unwrap()
+        };
+        for digest in [sha256, blake3] {
+            assert_eq!(digest.to_string().parse::<ContentHash>().unwrap(), digest);
+            assert_eq!(stored(digest).0, digest);
+        }
+        // as they were stored before there was a choice.
+        assert_eq!(stored(sha256).1, DIGEST_BYTES);
+
+        // hashing in chunks across threads gives the same digest, whether or not the content
+        // fills the last chunk.
+        let content: Vec<u8> = (0..3_000_000u32).map(|n| (n % 251) as u8).collect();
+        for len in [0, 1_000_000, 2_000_000, content.len()] {
+            let content = &content[..len];
+            assert_eq!(
+                digest_blake3_in_chunks(&mut &content[..], 1_000_000).unwrap(),
+                HashAlgorithm::Blake3
+                    .digest_reader(&mut &content[..])
+                    .unwrap()
+            );
+        }
+    }
 }
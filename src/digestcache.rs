@@ -0,0 +1,95 @@
+//! The digests of everything archived, held in memory for `--cache-digests`, so whether new
+//! content is a duplicate is mostly answered without a query to the catalogue. The set is split
+//! into shards by the digest's first byte, so threads checking different digests rarely wait on
+//! each other, and only ever grows: a digest missing from it is looked up in the catalogue, and
+//! added if it's there, e.g. archived by another machine since the set was loaded.
+
+use std::{collections::HashSet, sync::OnceLock, sync::RwLock};
+
+use eyre::Result;
+use tracing::info;
+
+use crate::{catalogue::Catalogue, digest::ContentHash};
+
+const SHARDS: usize = 64;
+
+/// The archive's digests, empty and unused until loaded.
+pub struct DigestCache {
+    shards: [RwLock<HashSet<ContentHash>>; SHARDS],
+    loaded: OnceLock<()>,
+}
+
+impl Default for DigestCache {
+    fn default() -> Self {
+        Self {
+            shards: std::array::from_fn(|_| RwLock::default()),
+            loaded: OnceLock::new(),
+        }
+    }
+}
+
+impl DigestCache {
+    /// Loads every digest in `store`'s archive, unless they're already loaded.
+    pub fn load(&self, store: &dyn Catalogue) -> Result<()> {
+        if self.loaded.get().is_some() {
+            return Ok(());
+        }
+        let digests = store.target_digests()?;
+        info!("cached {} archived digests", digests.len());
+        for digest in digests {
+            self.shard(&digest).write().unwrap().insert(digest);
+        }
+        let _ = self.loaded.set(());
+        Ok(())
+    }
+
+    /// Whether `digest` is archived, asking `store` only if it isn't cached, or if nothing has
+    /// been loaded.
+    pub fn exists_in_target(&self, store: &dyn Catalogue, digest: &ContentHash) -> Result<bool> {
+        if self.loaded.get().is_none() {
+            return store.exists_in_target(digest);
+        }
+        if self.shard(digest).read().unwrap().contains(digest) {
+            return Ok(true);
+        }
+        let exists = store.exists_in_target(digest)?;
+        if exists {
+            self.shard(digest).write().unwrap().insert(*digest);
+        }
+        Ok(exists)
+    }
+
+    fn shard(&self, digest: &ContentHash) -> &RwLock<HashSet<ContentHash>> {
+        &self.shards[usize::from(digest.as_bytes()[0]) % SHARDS]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, time::SystemTime};
+
+    use super::*;
+    use crate::store::PhotoSyncStore;
+
+    #[test]
+    fn misses_fall_back_to_the_catalogue() {
+        let store = PhotoSyncStore::new_for_tests().unwrap();
+        let run = store.begin_run("").unwrap();
+        let now = SystemTime::now();
+        let (a, b) = (ContentHash::new_for_tests(1), ContentHash::new_for_tests(2));
+        store
+            .mark_exists_in_target(run, Path::new("a"), now, 1, &a)
+            .unwrap();
+
+        let cache = DigestCache::default();
+        cache.load(&store).unwrap();
+        assert!(cache.exists_in_target(&store, &a).unwrap());
+        assert!(!cache.exists_in_target(&store, &b).unwrap());
+        // archived since it was loaded.
+        store
+            .mark_exists_in_target(run, Path::new("b"), now, 1, &b)
+            .unwrap();
+        assert!(cache.exists_in_target(&store, &b).unwrap());
+        assert!(cache.shard(&b).read().unwrap().contains(&b));
+    }
+}
@@ -0,0 +1,201 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use eyre::{Result, bail};
+use walkdir::WalkDir;
+
+use crate::{
+    SyncArgs,
+    fsinfo::{free_space, network_filesystem},
+    platform::same_filesystem,
+    store::{LATEST_SCHEMA_VERSION, inspect_database},
+};
+
+/// Checks that the environment `args` would sync in is fit for it, printing what to fix if not.
+pub fn doctor(args: &SyncArgs) -> Result<()> {
+    let mut report = Report::default();
+
+    let in_dirs = args.in_dir.iter().map(|in_dir| ("in", in_dir.dir()));
+    let out_dir = args.out_dir.as_deref().map(|dir| ("out", dir));
+    let dirs: Vec<_> = in_dirs
+        .chain([
+            ("old out", args.old_out_dir.as_path()),
+            ("temp", &args.temp_dir),
+        ])
+        .chain(out_dir)
+        .collect();
+    let missing: Vec<_> = dirs.iter().filter(|(_, dir)| !dir.is_dir()).collect();
+    for (name, dir) in &missing {
+        report.warn(format!("the {name} directory {dir:?} doesn't exist"));
+    }
+    if missing.is_empty() {
+        report.ok("all directories exist");
+        if let Some(out_dir) = &args.out_dir {
+            check_destination(&mut report, args, out_dir)?;
+        }
+    }
+
+    match (&args.database_file, &args.catalogue_addr) {
+        (_, Some(addr)) => report.ok(format!("the catalogue is hosted by {addr}")),
+        (Some(database_file), None) => check_database(&mut report, database_file)?,
+        (None, None) => unreachable!("clap requires a database file or catalogue address"),
+    }
+
+    match report.warnings {
+        0 => {
+            println!("no problems found");
+            Ok(())
+        }
+        warnings => bail!("found {warnings} problems"),
+    }
+}
+
+#[derive(Default)]
+struct Report {
+    warnings: usize,
+}
+
+impl Report {
+    fn ok(&mut self, message: impl AsRef<str>) {
+        println!("ok: {}", message.as_ref());
+    }
+
+    fn warn(&mut self, message: impl AsRef<str>) {
+        self.warnings += 1;
+        println!("warning: {}", message.as_ref());
+    }
+}
+
+fn check_destination(report: &mut Report, args: &SyncArgs, out_dir: &Path) -> Result<()> {
+    let temp_dir = &args.temp_dir;
+    match same_filesystem(out_dir, temp_dir)? {
+        Some(true) => report.ok("the temp and out directories share a filesystem"),
+        Some(false) => report.warn(format!(
+            "{temp_dir:?} and {out_dir:?} are on different filesystems, so copies can't be moved \
+             into place; pick a temp directory on the same filesystem as the out directory"
+        )),
+        None => report.ok("can't tell whether the temp and out directories share a filesystem"),
+    }
+
+    let probe = tempfile::tempdir_in(out_dir)?;
+    let file = probe.path().join("probe");
+    fs::write(&file, b"probe")?;
+    match fs::hard_link(&file, probe.path().join("link")) {
+        Ok(()) => report.ok("the out directory supports hardlinks"),
+        Err(e) => report.warn(format!(
+            "the out directory doesn't support hardlinks ({e}), so duplicates can't be linked"
+        )),
+    }
+    #[cfg(unix)]
+    match xattr::set(&file, "user.photo-sync.probe", b"probe") {
+        Ok(()) => report.ok("the out directory supports extended attributes"),
+        Err(e) => report.warn(format!(
+            "the out directory doesn't support extended attributes ({e}), so they can't be \
+             preserved"
+        )),
+    }
+    #[cfg(not(unix))]
+    report.warn("extended attributes are only supported on Unix, so they can't be preserved");
+    match fs::write(probe.path().join("n".repeat(255)), b"probe") {
+        Ok(()) => report.ok("the out directory supports 255 byte file names"),
+        Err(e) => report.warn(format!(
+            "the out directory doesn't support 255 byte file names ({e}), so files with long \
+             names will fail to copy"
+        )),
+    }
+
+    // the whole of the in directories is the most a sync could need to write.
+    let needed: u64 = (args.in_dir.iter())
+        .flat_map(|in_dir| WalkDir::new(in_dir.dir()))
+        .filter_map(|entry| entry.ok()?.metadata().ok())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .sum();
+    let Some(free) = free_space(out_dir)? else {
+        report.ok("can't tell how much space the out directory has free");
+        return Ok(());
+    };
+    if free >= needed {
+        report.ok(format!(
+            "the out directory has {}MB free, enough for the in directory's {}MB",
+            free / 1_000_000,
+            needed / 1_000_000
+        ));
+    } else {
+        report.warn(format!(
+            "the out directory has {}MB free, which may not be enough for the in directory's {}MB",
+            free / 1_000_000,
+            needed / 1_000_000
+        ));
+    }
+    Ok(())
+}
+
+fn check_database(report: &mut Report, database_file: &Path) -> Result<()> {
+    let dir = match database_file.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    match network_filesystem(&dir)? {
+        None => report.ok("the database isn't on a network filesystem"),
+        Some(kind) => report.warn(format!(
+            "the database is on a {kind} filesystem, where sqlite's locking is unreliable and \
+             the catalogue may be corrupted; keep it on a local disk, host it with `serve`, \
+             or pass --network-database"
+        )),
+    }
+
+    if !database_file.exists() {
+        report.ok(format!(
+            "{database_file:?} will be created by the first sync"
+        ));
+        return Ok(());
+    }
+    let health = inspect_database(database_file)?;
+    report.ok(format!(
+        "the database uses the {} journal",
+        health.journal_mode
+    ));
+    if health.schema_version > LATEST_SCHEMA_VERSION {
+        report.warn(format!(
+            "the database has schema version {}, newer than this version of the tool supports \
+             ({LATEST_SCHEMA_VERSION}); upgrade before syncing",
+            health.schema_version
+        ));
+    } else {
+        report.ok(format!(
+            "the database has schema version {} of {LATEST_SCHEMA_VERSION}",
+            health.schema_version
+        ));
+    }
+    if health.quick_check == "ok" {
+        report.ok("the database passes sqlite's quick check");
+    } else {
+        report.warn(format!(
+            "the database fails sqlite's quick check ({}); restore it from a backup",
+            health.quick_check
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::PhotoSyncStore;
+
+    #[test]
+    fn database_checks_use_existing_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let database_file = dir.path().join("db.sqlite");
+        let mut report = Report::default();
+        check_database(&mut report, &database_file).unwrap();
+        assert!(!database_file.exists());
+
+        PhotoSyncStore::new(database_file.clone()).unwrap();
+        check_database(&mut report, &database_file).unwrap();
+        assert_eq!(report.warnings, 0);
+    }
+}
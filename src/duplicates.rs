@@ -0,0 +1,125 @@
+//! Reporting the sets of files transferred from in directories with the same content as each other
+//! or as a file in the old out directory, i.e. the redundant shots deduplication kept only once.
+
+use std::collections::BTreeMap;
+
+use eyre::Result;
+use serde::Serialize;
+
+use crate::{
+    ReportDuplicatesArgs,
+    digest::ContentHash,
+    store::{CataloguedFile, PhotoSyncStore, SourceFile},
+};
+
+/// Files with the same content, of which the archive holds one copy.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+struct DuplicateSet {
+    digest: ContentHash,
+    size: u64,
+    /// `old:<path>` for files in the old out directory, and `<namespace>:<path>` for those
+    /// transferred from an in directory.
+    files: Vec<String>,
+    /// The bytes not archived again because of this set.
+    saved_bytes: u64,
+}
+
+pub fn report_duplicates(args: ReportDuplicatesArgs) -> Result<()> {
+    let store = PhotoSyncStore::new(args.database_file)?;
+    let sets = find_duplicates(store.source_files()?, store.old_target_files()?);
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&sets)?);
+        return Ok(());
+    }
+    for set in &sets {
+        println!(
+            "{} ({} bytes, {}MB saved):",
+            set.digest,
+            set.size,
+            set.saved_bytes / 1_000_000
+        );
+        for file in &set.files {
+            println!("    {file}");
+        }
+    }
+    println!(
+        "{} sets of duplicates, saving {}MB",
+        sets.len(),
+        sets.iter().map(|set| set.saved_bytes).sum::<u64>() / 1_000_000
+    );
+    Ok(())
+}
+
+/// Groups source files by their content, keeping the groups of more than one file or of a file
+/// whose content was already in the old out directory, most bytes saved first.
+fn find_duplicates(sources: Vec<SourceFile>, old_target: Vec<CataloguedFile>) -> Vec<DuplicateSet> {
+    let mut by_digest = BTreeMap::<_, (u64, Vec<String>, Vec<String>)>::new();
+    for file in sources {
+        let (size, _, transferred) = by_digest.entry(file.digest).or_default();
+        *size = file.size;
+        transferred.push(format!("{}:{}", file.namespace, file.path.display()));
+    }
+    for file in old_target {
+        if let Some((_, old, _)) = by_digest.get_mut(&file.digest) {
+            old.push(format!("old:{}", file.path.display()));
+        }
+    }
+    let mut sets: Vec<_> = by_digest
+        .into_iter()
+        .filter_map(|(digest, (size, old, transferred))| {
+            // every copy transferred is saved when the content was already in the old out
+            // directory, and all but the one archived otherwise.
+            let saved = transferred.len() - usize::from(old.is_empty());
+            (saved > 0).then(|| DuplicateSet {
+                digest,
+                size,
+                files: old.into_iter().chain(transferred).collect(),
+                saved_bytes: size * saved as u64,
+            })
+        })
+        .collect();
+    sets.sort_by_key(|set| std::cmp::Reverse(set.saved_bytes));
+    sets
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, time::SystemTime};
+
+    use super::*;
+
+    #[test]
+    fn sets_include_old_out_copies() {
+        let source = |path: &str, id| SourceFile {
+            namespace: "laptop".into(),
+            path: PathBuf::from(path),
+            size: 10 * u64::from(id),
+            digest: ContentHash::new_for_tests(id),
+            in_old_target: false,
+        };
+        let old = |path: &str, id| CataloguedFile {
+            path: PathBuf::from(path),
+            mtime: SystemTime::UNIX_EPOCH,
+            size: 10 * u64::from(id),
+            digest: ContentHash::new_for_tests(id),
+        };
+        let sets = find_duplicates(
+            vec![
+                source("a.jpg", 1),
+                source("copy of a.jpg", 1),
+                source("b.jpg", 2),
+                source("c.jpg", 3),
+            ],
+            vec![old("2019/c.jpg", 3), old("2019/d.jpg", 4)],
+        );
+        assert_eq!(
+            sets.iter().map(|set| set.files.clone()).collect::<Vec<_>>(),
+            [
+                vec!["old:2019/c.jpg", "laptop:c.jpg"],
+                vec!["laptop:a.jpg", "laptop:copy of a.jpg"],
+            ]
+        );
+        assert_eq!(sets[0].saved_bytes, 30);
+        assert_eq!(sets[1].saved_bytes, 10);
+    }
+}
@@ -0,0 +1,91 @@
+//! Encrypting archived copies with age, for archives kept on rented storage. The catalogue keeps
+//! the plaintext's digest, so encrypted copies still dedupe, and `restore` decrypts them. It also
+//! keeps the digest of what was stored, which `verify` and `bundle` check them against, as they
+//! can't be read without an identity.
+
+use std::{
+    ffi::OsString,
+    fs::File,
+    io::{self, BufReader, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use age::{Identity, IdentityFile, x25519::Recipient};
+use eyre::{Result, WrapErr, eyre};
+
+/// Added to the names of encrypted copies.
+const EXTENSION: &str = "age";
+
+pub fn parse_recipients(recipients: &[String]) -> Result<Vec<Recipient>> {
+    recipients
+        .iter()
+        .map(|r| r.parse().map_err(|e| eyre!("bad age recipient {r:?}: {e}")))
+        .collect()
+}
+
+/// Where the encrypted copy of a file destined for `path` goes.
+pub fn encrypted_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".");
+    name.push(EXTENSION);
+    PathBuf::from(name)
+}
+
+pub fn is_encrypted(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == EXTENSION)
+}
+
+/// Encrypts `from` into `to` for every one of `recipients`.
+pub fn encrypt(from: &Path, to: impl Write, recipients: &[Recipient]) -> Result<()> {
+    let encryptor = age::Encryptor::with_recipients(recipients.iter().map(|r| r as _))?;
+    let mut writer = encryptor.wrap_output(to)?;
+    io::copy(&mut File::open(from)?, &mut writer)?;
+    writer.finish()?.flush()?;
+    Ok(())
+}
+
+/// Reads the identities (private keys) in an age identity file, as written by `age-keygen`.
+pub fn load_identities(path: &Path) -> Result<Vec<Box<dyn Identity>>> {
+    let file = IdentityFile::from_file(path.to_string_lossy().into_owned())
+        .wrap_err_with(|| format!("could not read identities from {path:?}"))?;
+    Ok(file.into_identities()?)
+}
+
+/// Decrypts the encrypted copy at `path` into `to`.
+pub fn decrypt(path: &Path, to: &mut impl Write, identities: &[Box<dyn Identity>]) -> Result<()> {
+    let decryptor = age::Decryptor::new(BufReader::new(File::open(path)?))?;
+    let mut reader = decryptor.decrypt(identities.iter().map(|i| &**i))?;
+    io::copy(&mut reader as &mut dyn Read, to)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use age::x25519;
+
+    use super::*;
+
+    #[test]
+    fn encrypted_copies_decrypt_with_the_identity() {
+        let dir = tempfile::tempdir().unwrap();
+        let original = dir.path().join("a.jpg");
+        fs::write(&original, "photo").unwrap();
+        let identity = x25519::Identity::generate();
+        let recipients = parse_recipients(&[identity.to_public().to_string()]).unwrap();
+        assert!(parse_recipients(&["age1nonsense".to_string()]).is_err());
+
+        let encrypted = encrypted_path(&original);
+        assert!(is_encrypted(&encrypted));
+        encrypt(&original, File::create(&encrypted).unwrap(), &recipients).unwrap();
+        assert_ne!(fs::read(&encrypted).unwrap(), b"photo");
+
+        let mut decrypted = Vec::new();
+        let identities: Vec<Box<dyn Identity>> = vec![Box::new(identity)];
+        decrypt(&encrypted, &mut decrypted, &identities).unwrap();
+        assert_eq!(decrypted, b"photo");
+        let stranger: Vec<Box<dyn Identity>> = vec![Box::new(x25519::Identity::generate())];
+        assert!(decrypt(&encrypted, &mut Vec::new(), &stranger).is_err());
+    }
+}
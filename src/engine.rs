@@ -0,0 +1,1038 @@
+//! Driving a sync from other programs, e.g. integration tests, a phase at a time rather than
+//! through the command line. Hooks, pausing, leases and the other extras of `sync` are left to the
+//! caller.
+
+use std::{ffi::OsString, path::PathBuf, sync::mpsc, thread};
+
+use clap::{Args, FromArgMatches};
+use eyre::{Result, bail};
+
+use crate::{
+    SyncArgs, SyncContext, SyncResources,
+    catalogue::Catalogue,
+    destination::Destination,
+    detect_new_files, ensure_old_out_dir_properly_indexed,
+    journal::recover_interrupted,
+    lock::InstanceLock,
+    manifest::read_manifest,
+    metrics::RunStats,
+    mode::ExecutionMode,
+    netdb::LocalCopy,
+    pause::PauseControl,
+    remote::RemoteCatalogue,
+    sources::{Source, sources},
+    store::{PhotoSyncStore, RunId, RunStatus},
+    transfer::transfer_new_files,
+};
+
+/// What a sync has done so far.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SyncReport {
+    /// The run the sync is catalogued as.
+    pub run: i64,
+    /// Files in the old out directory hashed because they were new or changed.
+    pub files_indexed: u64,
+    /// Files in the in directory found to need transferring.
+    pub files_detected: u64,
+    /// Files written to the out directory, or which would have been in a dry run.
+    pub files_transferred: u64,
+    pub bytes_transferred: u64,
+    /// Files which couldn't be transferred.
+    pub files_failed: u64,
+}
+
+/// A sync run against its catalogue. The run is recorded as succeeded by
+/// [`SyncEngine::finish`], and as aborted if the engine is dropped without it.
+pub struct SyncEngine {
+    args: SyncArgs,
+    store: Box<dyn Catalogue>,
+    // the copy a dry run syncs against, which must outlive the store.
+    _dry_run_copy: Option<LocalCopy>,
+    _lock: Option<InstanceLock>,
+    resources: SyncResources,
+    source: Source,
+    pause: PauseControl,
+    stats: RunStats,
+    run: RunId,
+    finished: bool,
+}
+
+impl SyncEngine {
+    /// Starts a sync with arguments as `sync` takes them on the command line, e.g.
+    /// `["--in-dir", "/photos", ...]`.
+    pub fn from_args<I, T>(args: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let command = SyncArgs::augment_args(clap::Command::new("sync")).no_binary_name(true);
+        let matches = command.try_get_matches_from(args)?;
+        let mut args = SyncArgs::from_arg_matches(&matches)?;
+        args.choose_catalogue(&matches)?;
+        Self::new(args)
+    }
+
+    /// Opens the catalogue `args` name and begins a run in it. Only one in directory is synced
+    /// at a time.
+    pub fn new(args: SyncArgs) -> Result<Self> {
+        let Ok([source]) = <[_; 1]>::try_from(sources(&args.in_dir, &args.machine_id)?) else {
+            bail!("the engine syncs one in directory at a time");
+        };
+        let mode = ExecutionMode::new(args.dry_run);
+        let mut lock = None;
+        let mut dry_run_copy = None;
+        let store: Box<dyn Catalogue> = if args.ephemeral_db {
+            let store = PhotoSyncStore::new_in_memory()?;
+            if let Some(seed_manifest) = &args.seed_manifest {
+                store.add_manifest_entries(&read_manifest(seed_manifest)?)?;
+            }
+            Box::new(store)
+        } else {
+            match (&args.database_file, &args.catalogue_addr) {
+                (_, Some(addr)) => Box::new(RemoteCatalogue::connect(addr.clone())?),
+                (Some(database_file), None) => {
+                    lock = Some(InstanceLock::acquire(database_file)?);
+                    let (store, copy) = mode.open_store(database_file, args.network_database)?;
+                    if copy.is_some() && mode.is_live() {
+                        bail!(
+                            "{database_file:?} would be synced against a local copy, which only the command line copies back"
+                        );
+                    }
+                    dry_run_copy = copy;
+                    Box::new(store)
+                }
+                (None, None) => bail!("a database file or catalogue address is needed"),
+            }
+        };
+        if mode.is_live()
+            && let Some(out_dir) = &args.out_dir
+        {
+            let catalogued = store.has_target_files()? && !args.mark_destination;
+            Destination::open(out_dir.clone(), catalogued)?;
+        }
+        let resources = SyncResources::new(&args)?;
+        let run = store.begin_run(&args.machine_id)?;
+        let engine = Self {
+            args,
+            store,
+            _dry_run_copy: dry_run_copy,
+            _lock: lock,
+            resources,
+            source,
+            pause: PauseControl::default(),
+            stats: RunStats::default(),
+            run,
+            finished: false,
+        };
+        if mode.is_live() {
+            recover_interrupted(&engine.context())?;
+        }
+        Ok(engine)
+    }
+
+    fn context(&self) -> SyncContext<'_> {
+        self.resources.context(
+            &*self.store,
+            &self.pause,
+            self.run,
+            &self.args,
+            &self.stats,
+            &self.source,
+        )
+    }
+
+    /// Catalogues the files in the old out directory, hashing those which are new or changed.
+    pub fn index_old_target(&self) -> Result<()> {
+        ensure_old_out_dir_properly_indexed(&self.context())
+    }
+
+    /// Finds the files in the in directory which need transferring, relative to it.
+    pub fn detect_new(&self) -> Result<Vec<PathBuf>> {
+        let (new_files, new_files_rx) = mpsc::sync_channel(0);
+        thread::scope(|s| {
+            let detection = s.spawn(|| detect_new_files(&self.context(), None, new_files));
+            let detected = new_files_rx.into_iter().collect();
+            detection.join().expect("detection thread panicked")?;
+            Ok(detected)
+        })
+    }
+
+    /// Transfers `files`, as found by [`SyncEngine::detect_new`], into the out directory.
+    pub fn transfer(&self, files: Vec<PathBuf>) -> Result<SyncReport> {
+        let (queue, queued) = mpsc::sync_channel(files.len());
+        for file in files {
+            queue.send(file)?;
+        }
+        drop(queue);
+        transfer_new_files(&self.context(), queued)?;
+        Ok(self.report())
+    }
+
+    /// What the sync has done so far.
+    pub fn report(&self) -> SyncReport {
+        let stats = &self.stats;
+        SyncReport {
+            run: self.run,
+            files_indexed: stats.files_indexed.as_u64(),
+            files_detected: stats.files_detected.as_u64(),
+            files_transferred: stats.files_transferred.as_u64(),
+            bytes_transferred: stats.bytes_transferred.as_u64(),
+            files_failed: stats.files_failed.as_u64(),
+        }
+    }
+
+    /// Records the run as succeeded.
+    pub fn finish(mut self) -> Result<SyncReport> {
+        self.finished = true;
+        (self.store).record_run_counts(self.run, &self.stats.counts())?;
+        self.store.finish_run(self.run, RunStatus::Succeeded)?;
+        Ok(self.report())
+    }
+}
+
+impl Drop for SyncEngine {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.store.finish_run(self.run, RunStatus::Aborted);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        fs::{self, OpenOptions},
+        io::{self, Write},
+        path::Path,
+        time::{Duration, SystemTime},
+    };
+
+    use tempfile::{NamedTempFile, TempDir};
+
+    use super::*;
+    use crate::{
+        backend::{LocalDir, TargetBackend},
+        compress::Transform,
+        digest::{ContentHash, HashAlgorithm},
+        manifest::ManifestEntry,
+    };
+
+    /// An out directory whose copies are corrupted as they're stored, as a failing disk's might be.
+    struct CorruptingDir(LocalDir);
+
+    impl TargetBackend for CorruptingDir {
+        fn location(&self, path: &Path) -> PathBuf {
+            self.0.location(path)
+        }
+
+        fn exists(&self, path: &Path) -> Result<bool> {
+            self.0.exists(path)
+        }
+
+        fn modified(&self, path: &Path) -> Result<Option<SystemTime>> {
+            self.0.modified(path)
+        }
+
+        fn put(&self, staged: NamedTempFile, path: &Path) -> io::Result<SystemTime> {
+            let modified = self.0.put(staged, path)?;
+            let mut stored = OpenOptions::new().append(true).open(self.location(path))?;
+            stored.write_all(b"flipped bits")?;
+            Ok(modified)
+        }
+
+        fn digest(
+            &self,
+            path: &Path,
+            transform: Transform,
+            algorithm: HashAlgorithm,
+        ) -> Result<ContentHash> {
+            self.0.digest(path, transform, algorithm)
+        }
+
+        fn remove(&self, path: &Path) -> Result<()> {
+            self.0.remove(path)
+        }
+    }
+
+    /// A directory holding `in`, `out`, `old` and `tmp` directories to sync between.
+    fn test_dir() -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["in", "out", "old", "tmp"] {
+            fs::create_dir(dir.path().join(name)).unwrap();
+        }
+        dir
+    }
+
+    /// An engine syncing `in` within `dir` to `out`, catalogued in `db.sqlite`, with
+    /// `extra_args` added to the command line. One giving the directories or catalogue replaces
+    /// the default.
+    fn test_engine(dir: &Path, extra_args: &[&str]) -> SyncEngine {
+        SyncEngine::from_args(test_args(dir, extra_args)).unwrap()
+    }
+
+    /// The command line [`test_engine`] starts a sync with.
+    fn test_args(dir: &Path, extra_args: &[&str]) -> Vec<String> {
+        let defaults = [
+            ("in-dir", "in"),
+            ("out-dir", "out"),
+            ("old-out-dir", "old"),
+            ("temp-dir", "tmp"),
+            ("database-file", "db.sqlite"),
+        ];
+        (defaults.into_iter())
+            .filter(|(name, _)| {
+                let flag = format!("--{name}=");
+                !extra_args.iter().any(|arg| arg.starts_with(&flag))
+            })
+            .map(|(name, file)| format!("--{name}={}", dir.join(file).display()))
+            .chain(extra_args.iter().map(|arg| arg.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn phases_can_be_run_one_at_a_time() {
+        let dir = test_dir();
+        let path = |name: &str| dir.path().join(name);
+        fs::write(path("in/new.jpg"), "new photo").unwrap();
+        fs::write(path("in/archived.jpg"), "old photo").unwrap();
+        fs::write(path("old/archived.jpg"), "old photo").unwrap();
+
+        let engine = test_engine(dir.path(), &["--include-small-files"]);
+        engine.index_old_target().unwrap();
+        let mut detected = engine.detect_new().unwrap();
+        detected.sort();
+        assert_eq!(
+            detected,
+            [PathBuf::from("archived.jpg"), PathBuf::from("new.jpg")]
+        );
+        let report = engine.transfer(detected).unwrap();
+        assert_eq!((report.files_indexed, report.files_transferred), (1, 1));
+        assert_eq!(engine.finish().unwrap().run, 1);
+        assert_eq!(
+            fs::read_to_string(path("out/new.jpg")).unwrap(),
+            "new photo"
+        );
+    }
+
+    #[test]
+    fn new_files_are_transferred_while_detection_is_still_going() {
+        let dir = test_dir();
+        let path = |name: &str| dir.path().join(name);
+        for name in ["a.jpg", "b.jpg", "c.jpg"] {
+            fs::write(path("in").join(name), name).unwrap();
+        }
+        let engine = test_engine(dir.path(), &["--include-small-files"]);
+        let (new_files, detected) = mpsc::sync_channel(0);
+        thread::scope(|s| {
+            let detection = s.spawn(|| detect_new_files(&engine.context(), None, new_files));
+            let first = detected.recv().unwrap();
+            let (queue, queued) = mpsc::sync_channel(1);
+            queue.send(first.clone()).unwrap();
+            drop(queue);
+            transfer_new_files(&engine.context(), queued).unwrap();
+            assert!(path("out").join(&first).exists());
+            // detection is still waiting to hand over the next file.
+            assert!(!detection.is_finished());
+
+            transfer_new_files(&engine.context(), detected).unwrap();
+            detection.join().unwrap().unwrap();
+        });
+        assert_eq!(engine.finish().unwrap().files_transferred, 3);
+        for name in ["a.jpg", "b.jpg", "c.jpg"] {
+            assert_eq!(fs::read_to_string(path("out").join(name)).unwrap(), name);
+        }
+    }
+
+    #[test]
+    fn exports_since_a_run_hold_only_what_later_runs_catalogued() {
+        let dir = test_dir();
+        let path = |name: &str| dir.path().join(name);
+        let sync = || {
+            let engine = test_engine(dir.path(), &["--include-small-files"]);
+            let detected = engine.detect_new().unwrap();
+            engine.transfer(detected).unwrap();
+            engine.finish().unwrap().run
+        };
+        fs::write(path("in/a.jpg"), "first photo").unwrap();
+        let first = sync();
+        fs::write(path("in/b.jpg"), "second photo").unwrap();
+        sync();
+
+        let arg = |name: &str, file: &str| format!("--{name}={}", path(file).display());
+        crate::run(
+            [
+                "photo-sync".into(),
+                "db".into(),
+                arg("database-file", "db.sqlite"),
+                "export".into(),
+                format!("--since={first}"),
+                arg("output", "delta.jsonl"),
+            ]
+            .map(OsString::from)
+            .into(),
+        )
+        .unwrap();
+        let paths: Vec<_> = read_manifest(&path("delta.jsonl"))
+            .unwrap()
+            .into_iter()
+            .map(|entry| match entry {
+                ManifestEntry::Source { path, .. } => ("source", path),
+                ManifestEntry::Target { path, .. } => ("target", path),
+                ManifestEntry::OldTarget { path, .. } => ("old target", path),
+            })
+            .collect();
+        assert_eq!(
+            paths,
+            [("target", "b.jpg".into()), ("source", "b.jpg".into())]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn special_files_are_skipped_or_with_strict_fail_the_sync() {
+        use std::{
+            ffi::CString,
+            os::unix::{ffi::OsStrExt, net::UnixListener},
+        };
+
+        let dir = test_dir();
+        let path = |name: &str| dir.path().join(name);
+        fs::write(path("in/a.jpg"), "photo").unwrap();
+        let _sockets = [path("in/stray.sock"), path("old/stray.sock")].map(UnixListener::bind);
+        let fifo = CString::new(path("in/pipe").as_os_str().as_bytes()).unwrap();
+        // SAFETY: the path is a valid C string which outlives the call.
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) }, 0);
+
+        let strict = test_engine(dir.path(), &["--include-small-files", "--strict"]);
+        let err = strict.index_old_target().unwrap_err();
+        assert!(err.to_string().contains("is a socket"), "{err}");
+        let err = strict.detect_new().unwrap_err();
+        assert!(err.to_string().contains("not a file"), "{err}");
+        drop(strict);
+
+        let sync = test_engine(dir.path(), &["--include-small-files"]);
+        sync.index_old_target().unwrap();
+        let detected = sync.detect_new().unwrap();
+        assert_eq!(detected, [PathBuf::from("a.jpg")]);
+        let report = sync.transfer(detected).unwrap();
+        assert_eq!((report.files_transferred, report.files_failed), (1, 0));
+        sync.finish().unwrap();
+        assert!(!path("out/pipe").exists() && !path("out/stray.sock").exists());
+    }
+
+    #[test]
+    fn large_files_copy_intact_through_small_buffers_flushed_and_uncached() {
+        let dir = test_dir();
+        let path = |name: &str| dir.path().join(name);
+        let video: Vec<u8> = (0..300_000u32).map(|i| (i * 31 % 251) as u8).collect();
+        fs::write(path("in/clip.mov"), &video).unwrap();
+        let engine = test_engine(
+            dir.path(),
+            &["--copy-buffer-kib=1", "--fsync", "--drop-page-cache"],
+        );
+        let detected = engine.detect_new().unwrap();
+        let report = engine.transfer(detected).unwrap();
+        assert_eq!(
+            (report.files_transferred, report.bytes_transferred),
+            (1, 300_000)
+        );
+        engine.finish().unwrap();
+
+        assert_eq!(fs::read(path("out/clip.mov")).unwrap(), video);
+        let store = PhotoSyncStore::new(path("db.sqlite")).unwrap();
+        let [archived] = &store.target_files().unwrap()[..] else {
+            panic!("one copy is catalogued");
+        };
+        assert_eq!(archived.size, 300_000);
+    }
+
+    #[test]
+    fn nested_paths_are_catalogued_the_same_on_every_platform() {
+        let dir = test_dir();
+        let path = |name: &str| dir.path().join(name);
+        let nested = Path::new("2024").join("trip").join("a.jpg");
+        fs::create_dir_all(path("in").join(nested.parent().unwrap())).unwrap();
+        fs::write(path("in").join(&nested), "photo").unwrap();
+        let engine = test_engine(dir.path(), &["--include-small-files"]);
+        let detected = engine.detect_new().unwrap();
+        assert_eq!(detected, [nested.as_path()]);
+        engine.transfer(detected).unwrap();
+        engine.finish().unwrap();
+
+        assert_eq!(
+            fs::read_to_string(path("out").join(&nested)).unwrap(),
+            "photo"
+        );
+        // with `/` between components, even on Windows, so the catalogue can move between them.
+        let conn = rusqlite::Connection::open(path("db.sqlite")).unwrap();
+        for table in ["source_files", "target_files"] {
+            let stored: Vec<u8> = conn
+                .query_row(&format!("SELECT path FROM {table}"), [], |r| r.get(0))
+                .unwrap();
+            assert_eq!(stored, b"2024/trip/a.jpg", "{table}");
+        }
+        let store = PhotoSyncStore::new(path("db.sqlite")).unwrap();
+        assert_eq!(store.source_files().unwrap()[0].path, nested);
+    }
+
+    #[test]
+    fn moving_removes_archived_sources() {
+        let dir = test_dir();
+        let path = |name: &str| dir.path().join(name);
+        fs::write(path("in/new.jpg"), "new photo").unwrap();
+        fs::write(path("in/archived.jpg"), "old photo").unwrap();
+        fs::write(path("old/archived.jpg"), "old photo").unwrap();
+
+        let engine = test_engine(dir.path(), &["--include-small-files", "--move"]);
+        engine.index_old_target().unwrap();
+        let detected = engine.detect_new().unwrap();
+        engine.transfer(detected).unwrap();
+        engine.finish().unwrap();
+
+        assert_eq!(fs::read_dir(path("in")).unwrap().count(), 0);
+        assert_eq!(
+            fs::read_to_string(path("out/new.jpg")).unwrap(),
+            "new photo"
+        );
+        let store = PhotoSyncStore::new(path("db.sqlite")).unwrap();
+        assert_eq!(store.deleted_sources("").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn changing_hash_algorithm_still_deduplicates() {
+        let dir = test_dir();
+        let path = |name: &str| dir.path().join(name);
+        fs::write(path("in/a.jpg"), "photo").unwrap();
+        let sync = |hash_algo: &str| {
+            let hash_algo = format!("--hash-algo={hash_algo}");
+            let engine = test_engine(dir.path(), &["--include-small-files", &hash_algo]);
+            let detected = engine.detect_new().unwrap();
+            engine.transfer(detected).unwrap();
+            engine.finish().unwrap()
+        };
+        assert_eq!(sync("sha256").files_transferred, 1);
+
+        // the same content under another name is recognised by its SHA-256 digest.
+        fs::write(path("in/b.jpg"), "photo").unwrap();
+        fs::write(path("in/c.jpg"), "another photo").unwrap();
+        let report = sync("blake3");
+        assert_eq!((report.files_detected, report.files_transferred), (2, 1));
+        assert!(!path("out/b.jpg").exists());
+    }
+
+    #[test]
+    fn large_archived_files_hashed_across_threads_still_deduplicate() {
+        let dir = test_dir();
+        let path = |name: &str| dir.path().join(name);
+        let video: Vec<u8> = (0..3_000_000u32).map(|n| (n % 251) as u8).collect();
+        fs::write(path("old/clip.mov"), &video).unwrap();
+        fs::write(path("in/clip copy.mov"), &video).unwrap();
+        let engine = test_engine(
+            dir.path(),
+            &["--hash-algo=blake3", "--parallel-hash-min-size=1M"],
+        );
+        engine.index_old_target().unwrap();
+        let detected = engine.detect_new().unwrap();
+        let report = engine.transfer(detected).unwrap();
+        assert_eq!((report.files_indexed, report.files_transferred), (1, 0));
+        engine.finish().unwrap();
+
+        let store = PhotoSyncStore::new(path("db.sqlite")).unwrap();
+        let [indexed] = &store.old_target_files().unwrap()[..] else {
+            panic!("the archived video is catalogued");
+        };
+        let whole = HashAlgorithm::Blake3
+            .digest_reader(&mut &video[..])
+            .unwrap();
+        assert_eq!(indexed.digest, whole);
+        assert!(!path("out/clip copy.mov").exists());
+
+        // SHA-256 can't be split, so asking to is refused rather than ignored.
+        let args = test_args(dir.path(), &["--parallel-hash-min-size=1M"]);
+        let err = SyncEngine::from_args(args).err().unwrap();
+        assert!(err.to_string().contains("--hash-algo blake3"), "{err}");
+    }
+
+    #[test]
+    fn archives_walked_in_parallel_are_catalogued_as_walked_in_order() {
+        let dir = test_dir();
+        let path = |name: &str| dir.path().join(name);
+        for year in 2015..2025 {
+            fs::create_dir_all(path(&format!("old/{year}/Holiday"))).unwrap();
+            for n in 0..20 {
+                let name = format!("{year}/{}IMG_{n:04}.JPG", ["", "Holiday/"][n % 2]);
+                fs::write(path("old").join(&name), &name).unwrap();
+            }
+        }
+        let catalogued = |database: &str, extra_args: &[&str]| {
+            let database_file = format!("--database-file={}", path(database).display());
+            let engine = test_engine(
+                dir.path(),
+                &[&[database_file.as_str()], extra_args].concat(),
+            );
+            engine.index_old_target().unwrap();
+            assert_eq!(engine.finish().unwrap().files_indexed, 200);
+            let store = PhotoSyncStore::new(path(database)).unwrap();
+            let mut files: Vec<_> = (store.old_target_files().unwrap().into_iter())
+                .map(|file| (file.path, file.size, file.digest))
+                .collect();
+            files.sort();
+            files
+        };
+        assert_eq!(
+            catalogued("parallel.sqlite", &[]),
+            catalogued("in-order.sqlite", &["--sequential-per-device"])
+        );
+    }
+
+    #[test]
+    fn live_photo_videos_go_alone_when_their_still_is_left_out() {
+        let dir = test_dir();
+        let path = |name: &str| dir.path().join(name);
+        fs::write(path("in/IMG_0001.HEIC"), "still").unwrap();
+        fs::write(path("in/IMG_0001.MOV"), "video").unwrap();
+        let engine = test_engine(dir.path(), &["--include-small-files", "--exclude=*.HEIC"]);
+        let detected = engine.detect_new().unwrap();
+        assert_eq!(detected, [PathBuf::from("IMG_0001.MOV")]);
+        engine.transfer(detected).unwrap();
+        engine.finish().unwrap();
+        assert!(path("out/IMG_0001.MOV").is_file());
+        assert!(!path("out/IMG_0001.HEIC").exists());
+        let store = PhotoSyncStore::new(path("db.sqlite")).unwrap();
+        assert!(store.live_photos("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn sidecars_go_alone_when_their_photo_is_left_out() {
+        let dir = test_dir();
+        let path = |name: &str| dir.path().join(name);
+        fs::write(path("in/IMG_0001.JPG"), "photo").unwrap();
+        fs::write(path("in/IMG_0001.AAE"), "aae").unwrap();
+        let engine = test_engine(dir.path(), &["--include-small-files", "--max-size=4"]);
+        let detected = engine.detect_new().unwrap();
+        assert_eq!(detected, [PathBuf::from("IMG_0001.AAE")]);
+        engine.transfer(detected).unwrap();
+        engine.finish().unwrap();
+        assert!(path("out/IMG_0001.AAE").is_file());
+    }
+
+    #[test]
+    fn file_filters_leave_out_live_photo_videos() {
+        let dir = test_dir();
+        let path = |name: &str| dir.path().join(name);
+        fs::write(path("in/IMG_0001.HEIC"), "still").unwrap();
+        fs::write(path("in/IMG_0001.MOV"), "video").unwrap();
+        let engine = test_engine(dir.path(), &["--include-small-files", "--extensions=heic"]);
+        let detected = engine.detect_new().unwrap();
+        assert_eq!(detected, [PathBuf::from("IMG_0001.HEIC")]);
+        let report = engine.transfer(detected).unwrap();
+        assert_eq!(report.files_transferred, 1);
+        engine.finish().unwrap();
+        assert!(!path("out/IMG_0001.MOV").exists());
+    }
+
+    #[test]
+    fn chunk_repositories_are_not_indexed_as_archived_files() {
+        let dir = test_dir();
+        let path = |name: &str| dir.path().join(name);
+        fs::write(path("in/a.jpg"), "photo").unwrap();
+        let old_out_dir = format!("--old-out-dir={}", path("out").display());
+        let sync = || {
+            let engine = test_engine(
+                dir.path(),
+                &["--include-small-files", "--chunked", &old_out_dir],
+            );
+            engine.index_old_target().unwrap();
+            let detected = engine.detect_new().unwrap();
+            engine.transfer(detected).unwrap();
+            engine.finish().unwrap()
+        };
+        assert_eq!(sync().files_transferred, 1);
+
+        fs::write(path("in/b.jpg"), "another photo").unwrap();
+        // the packs are appended to, so would be found to have been rewritten.
+        assert_eq!(sync().files_transferred, 1);
+        let store = PhotoSyncStore::new(path("db.sqlite")).unwrap();
+        let mut archived: Vec<_> = (store.target_files().unwrap().into_iter())
+            .map(|file| file.path)
+            .collect();
+        archived.sort();
+        assert_eq!(
+            archived,
+            [PathBuf::from("a.jpg.chunks"), PathBuf::from("b.jpg.chunks")]
+        );
+    }
+
+    #[test]
+    fn copies_are_read_as_they_were_stored_whatever_their_names() {
+        let dir = test_dir();
+        let path = |name: &str| dir.path().join(name);
+        // named like a compressed copy, but not one.
+        fs::write(path("in/notes.zst"), "not zstd").unwrap();
+        let sync = |extra_args: &[&str]| {
+            let args = [&["--include-small-files", "--paranoid"], extra_args].concat();
+            let engine = test_engine(dir.path(), &args);
+            let detected = engine.detect_new().unwrap();
+            engine.transfer(detected).unwrap();
+            engine.finish().unwrap()
+        };
+        // read back as it was written, rather than decompressed.
+        assert_eq!(sync(&[]).files_transferred, 1);
+        assert_eq!(
+            fs::read_to_string(path("out/notes.zst")).unwrap(),
+            "not zstd"
+        );
+
+        fs::write(path("in/clip.mov"), "video").unwrap();
+        assert_eq!(sync(&["--compress"]).files_transferred, 1);
+        let store = PhotoSyncStore::new(path("db.sqlite")).unwrap();
+        assert_eq!(
+            store.transformed_files().unwrap(),
+            HashMap::from([(PathBuf::from("clip.mov.zst"), Transform::Compressed)])
+        );
+        assert_eq!(
+            store.transform_of(Path::new("notes.zst")).unwrap(),
+            Transform::None
+        );
+    }
+
+    #[test]
+    fn priority_directories_are_detected_and_transferred_first() {
+        let dir = test_dir();
+        let path = |name: &str| dir.path().join(name);
+        for name in ["in/2023", "in/2024"] {
+            fs::create_dir(path(name)).unwrap();
+        }
+        for name in [
+            "2023/a.jpg",
+            "2023/b.jpg",
+            "top.jpg",
+            "2024/c.jpg",
+            "2024/d.jpg",
+        ] {
+            fs::write(path("in").join(name), name).unwrap();
+        }
+        let engine = test_engine(
+            dir.path(),
+            &[
+                "--include-small-files",
+                "--priority-dir=2024",
+                "--max-parallel-files=1",
+            ],
+        );
+        let detected = engine.detect_new().unwrap();
+        let in_2024 = |path: &Path| path.starts_with("2024");
+        assert_eq!(detected.len(), 5);
+        assert!(
+            detected[..2].iter().all(|path| in_2024(path)),
+            "{detected:?}"
+        );
+        engine.transfer(detected).unwrap();
+        engine.finish().unwrap();
+
+        let store = PhotoSyncStore::new(path("db.sqlite")).unwrap();
+        let transferred: Vec<_> = store.source_files().unwrap();
+        assert_eq!(transferred.len(), 5);
+        assert!(transferred[..2].iter().all(|file| in_2024(&file.path)));
+    }
+
+    #[test]
+    fn overlapping_subdirectories_are_walked_once() {
+        let cases: [(&[&str], &[&str]); 2] = [
+            (
+                &["--only-subdir=2024,2024/Holiday"],
+                &["2024/Holiday/a.jpg", "2024/b.jpg"],
+            ),
+            (
+                &["--only-subdir=2024/Holiday", "--priority-dir=2024/Holiday"],
+                &["2024/Holiday/a.jpg"],
+            ),
+        ];
+        for (scope, expected) in cases {
+            let dir = test_dir();
+            let path = |name: &str| dir.path().join(name);
+            fs::create_dir_all(path("in/2024/Holiday")).unwrap();
+            fs::create_dir(path("in/2023")).unwrap();
+            for name in ["2024/Holiday/a.jpg", "2024/b.jpg", "2023/c.jpg"] {
+                fs::write(path("in").join(name), name).unwrap();
+            }
+            let engine = test_engine(dir.path(), &[&["--include-small-files"], scope].concat());
+            let mut detected = engine.detect_new().unwrap();
+            detected.sort();
+            let expected: Vec<PathBuf> = expected.iter().map(PathBuf::from).collect();
+            assert_eq!(detected, expected, "{scope:?}");
+            let report = engine.transfer(detected).unwrap();
+            assert_eq!(report.files_transferred, expected.len() as u64, "{scope:?}");
+            engine.finish().unwrap();
+        }
+    }
+
+    #[test]
+    fn files_too_small_to_be_intact_are_reported_rather_than_transferred() {
+        let dir = test_dir();
+        let path = |name: &str| dir.path().join(name);
+        fs::write(path("in/empty.jpg"), "").unwrap();
+        fs::write(path("in/placeholder.MOV"), [0; 1024]).unwrap();
+        fs::write(path("in/photo.jpg"), [0; 2048]).unwrap();
+
+        let sync = test_engine(dir.path(), &[]);
+        let detected = sync.detect_new().unwrap();
+        assert_eq!(detected, [PathBuf::from("photo.jpg")]);
+        let report = sync.transfer(detected).unwrap();
+        assert_eq!((report.files_transferred, report.files_failed), (1, 2));
+        sync.finish().unwrap();
+        let store = PhotoSyncStore::new(path("db.sqlite")).unwrap();
+        assert_eq!(
+            store.run_failures(report.run).unwrap(),
+            [("corrupt".to_string(), 2)]
+        );
+        assert!(!path("out/empty.jpg").exists());
+        assert!(!path("out/placeholder.MOV").exists());
+
+        let sync = test_engine(dir.path(), &["--include-small-files"]);
+        let mut detected = sync.detect_new().unwrap();
+        detected.sort();
+        assert_eq!(
+            detected,
+            [PathBuf::from("empty.jpg"), PathBuf::from("placeholder.MOV")]
+        );
+        let report = sync.transfer(detected).unwrap();
+        assert_eq!((report.files_transferred, report.files_failed), (2, 0));
+        sync.finish().unwrap();
+        assert_eq!(fs::read(path("out/placeholder.MOV")).unwrap(), [0; 1024]);
+        assert!(path("out/empty.jpg").exists());
+    }
+
+    #[test]
+    fn files_the_plugin_fails_for_fail_alone() {
+        // traps when asked about `c…`, and puts `b…` nowhere.
+        const PLUGIN: &str = r#"
+            (module
+              (memory (export "memory") 1)
+              (func (export "alloc") (param $len i32) (result i32)
+                (i32.const 1024))
+              (func (export "accept") (param $ptr i32) (param $len i32) (result i32)
+                (if (i32.eq (i32.load8_u (local.get $ptr)) (i32.const 99))
+                  (then (unreachable)))
+                (i32.const 1))
+              (func (export "destination") (param $ptr i32) (param $len i32) (result i64)
+                (if (i32.eq (i32.load8_u (local.get $ptr)) (i32.const 98))
+                  (then (return (i64.const 0))))
+                (i64.or
+                  (i64.shl (i64.extend_i32_u (local.get $ptr)) (i64.const 32))
+                  (i64.extend_i32_u (local.get $len)))))
+        "#;
+        let dir = test_dir();
+        let path = |name: &str| dir.path().join(name);
+        fs::write(path("plugin.wasm"), wat::parse_str(PLUGIN).unwrap()).unwrap();
+        for name in ["a.jpg", "b.jpg", "c.jpg"] {
+            fs::write(path(&format!("in/{name}")), name).unwrap();
+        }
+        let plugin = path("plugin.wasm").into_os_string().into_string().unwrap();
+
+        let sync = test_engine(dir.path(), &["--include-small-files", "--plugin", &plugin]);
+        let mut detected = sync.detect_new().unwrap();
+        detected.sort();
+        assert_eq!(detected, [PathBuf::from("a.jpg"), PathBuf::from("b.jpg")]);
+        let report = sync.transfer(detected).unwrap();
+        assert_eq!((report.files_transferred, report.files_failed), (1, 2));
+        sync.finish().unwrap();
+        assert_eq!(fs::read(path("out/a.jpg")).unwrap(), b"a.jpg");
+        let store = PhotoSyncStore::new(path("db.sqlite")).unwrap();
+        assert_eq!(
+            store.run_failures(report.run).unwrap(),
+            [("hook failed".to_string(), 2)]
+        );
+    }
+
+    #[test]
+    fn paired_apple_double_files_go_with_whichever_copy_is_archived() {
+        let dir = test_dir();
+        let path = |name: &str| dir.path().join(name);
+        let sync = |files: &[&str]| {
+            for name in files {
+                fs::write(path(&format!("in/{name}")), "photo").unwrap();
+            }
+            let args = ["--include-small-files", "--apple-double=pair"];
+            let engine = test_engine(dir.path(), &args);
+            let detected = engine.detect_new().unwrap();
+            let report = engine.transfer(detected).unwrap();
+            engine.finish().unwrap();
+            assert_eq!(report.files_failed, 0);
+        };
+        sync(&["a.jpg"]);
+        // a duplicate's goes with the copy archived already.
+        fs::write(path("in/._b.jpg"), "metadata").unwrap();
+        sync(&["b.jpg"]);
+        assert!(!path("out/b.jpg").exists());
+        assert_eq!(fs::read(path("out/._a.jpg")).unwrap(), b"metadata");
+        // as does one whose place is taken by the same content.
+        for name in ["in/c.jpg", "out/c.jpg"] {
+            fs::write(path(name), "another photo").unwrap();
+        }
+        fs::write(path("in/._c.jpg"), "metadata").unwrap();
+        sync(&[]);
+        assert_eq!(fs::read(path("out/._c.jpg")).unwrap(), b"metadata");
+
+        let store = PhotoSyncStore::new(path("db.sqlite")).unwrap();
+        let mut archived: Vec<_> = (store.target_files().unwrap().into_iter())
+            .map(|file| file.path)
+            .collect();
+        archived.sort();
+        assert_eq!(
+            archived,
+            ["._a.jpg", "._c.jpg", "a.jpg", "c.jpg"].map(PathBuf::from)
+        );
+    }
+
+    #[test]
+    fn copies_which_dont_read_back_are_removed_rather_than_catalogued() {
+        let dir = test_dir();
+        let path = |name: &str| dir.path().join(name);
+        fs::write(path("in/a.jpg"), "photo").unwrap();
+        let mut engine = test_engine(dir.path(), &["--include-small-files", "--paranoid"]);
+        engine.resources.backend = Box::new(CorruptingDir(LocalDir {
+            dir: path("out"),
+            fsync: false,
+            paranoid: false,
+        }));
+        let detected = engine.detect_new().unwrap();
+        let report = engine.transfer(detected).unwrap();
+        assert_eq!((report.files_transferred, report.files_failed), (0, 1));
+        engine.finish().unwrap();
+
+        assert!(!path("out/a.jpg").exists());
+        let store = PhotoSyncStore::new(path("db.sqlite")).unwrap();
+        assert_eq!(
+            store.run_failures(report.run).unwrap(),
+            [("digest mismatch".to_string(), 1)]
+        );
+        assert!(store.target_files().unwrap().is_empty());
+        assert!(store.source_files().unwrap().is_empty());
+    }
+
+    #[test]
+    fn taken_places_are_resolved_as_on_collision_says() {
+        let sync = |policy: &[&str]| {
+            let dir = test_dir();
+            let path = |name: &str| dir.path().join(name);
+            for (name, content) in [("a.jpg", "new"), ("b.jpg", "same"), ("c.jpg", "free")] {
+                fs::write(path("in").join(name), content).unwrap();
+            }
+            // copied in by hand, so uncatalogued.
+            fs::write(path("out/a.jpg"), "other").unwrap();
+            fs::write(path("out/b.jpg"), "same").unwrap();
+            let engine = test_engine(dir.path(), &[&["--include-small-files"], policy].concat());
+            let detected = engine.detect_new().unwrap();
+            let report = engine.transfer(detected).unwrap();
+            engine.finish().unwrap();
+
+            // the run carries on past them to the file with a free place.
+            assert_eq!(fs::read_to_string(path("out/c.jpg")).unwrap(), "free");
+            assert_eq!(fs::read_to_string(path("out/a.jpg")).unwrap(), "other");
+            assert_eq!(fs::read_to_string(path("out/b.jpg")).unwrap(), "same");
+            let renamed = fs::read_to_string(path("out/a (2).jpg")).ok();
+            let store = PhotoSyncStore::new(path("db.sqlite")).unwrap();
+            let mut left: Vec<_> = (store.skipped_collisions(report.run).unwrap().into_iter())
+                .map(|(_, path, _)| path)
+                .collect();
+            left.sort();
+            (report.files_transferred, report.files_failed, renamed, left)
+        };
+        let (a, b) = (PathBuf::from("a.jpg"), PathBuf::from("b.jpg"));
+        // renamed is the default.
+        assert_eq!(sync(&[]), (2, 0, Some("new".into()), vec![]));
+        assert_eq!(
+            sync(&["--on-collision=rename"]),
+            (2, 0, Some("new".into()), vec![])
+        );
+        assert_eq!(
+            sync(&["--on-collision=skip"]),
+            (1, 0, None, vec![a.clone()])
+        );
+        assert_eq!(
+            sync(&["--on-collision=overwrite-if-identical"]),
+            (1, 1, None, vec![a.clone()])
+        );
+        // even the file of the same content.
+        assert_eq!(sync(&["--on-collision=error"]), (1, 2, None, vec![a, b]));
+    }
+
+    #[test]
+    fn icloud_downloads_are_deleted_once_archived() {
+        let dir = test_dir();
+        let path = |name: &str| dir.path().join(name);
+        // a session directory `icloud login` signed in to, with two originals downloaded.
+        fs::create_dir(path("session")).unwrap();
+        fs::write(path("session/session.json"), "{}").unwrap();
+        for (id, content) in [("master-1", "new"), ("master-2", "archived")] {
+            fs::create_dir_all(path("session/originals").join(id)).unwrap();
+            let original = path("session/originals").join(id).join("IMG_0001.HEIC");
+            fs::write(&original, content).unwrap();
+            // mid-July 2023, so the month is the same in every timezone.
+            (OpenOptions::new().write(true).open(original).unwrap())
+                .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(1_689_400_000))
+                .unwrap();
+        }
+        fs::write(path("old/archived.heic"), "archived").unwrap();
+
+        let in_dir = format!("--in-dir={}", path("session").display());
+        let engine = test_engine(dir.path(), &["--include-small-files", &in_dir]);
+        engine.index_old_target().unwrap();
+        let detected = engine.detect_new().unwrap();
+        assert_eq!(engine.transfer(detected).unwrap().files_transferred, 1);
+        engine.finish().unwrap();
+        assert_eq!(
+            fs::read_to_string(path("out/2023/07/IMG_0001.HEIC")).unwrap(),
+            "new"
+        );
+        // both the one just archived and the one archived already.
+        assert_eq!(fs::read_dir(path("session/originals")).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn library_originals_are_numbered_past_names_already_archived() {
+        let dir = test_dir();
+        let path = |name: &str| dir.path().join(name);
+        let package = "Photos Library.photoslibrary";
+        fs::create_dir_all(path("out/2023/07")).unwrap();
+        for name in ["database", "originals/A"] {
+            fs::create_dir_all(path(package).join(name)).unwrap();
+        }
+        let conn =
+            rusqlite::Connection::open(path(package).join("database/Photos.sqlite")).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE ZASSET (Z_PK INTEGER PRIMARY KEY, ZDIRECTORY VARCHAR, \
+                 ZFILENAME VARCHAR, ZDATECREATED TIMESTAMP, ZUUID VARCHAR);
+             CREATE TABLE ZADDITIONALASSETATTRIBUTES (Z_PK INTEGER PRIMARY KEY, ZASSET INTEGER, \
+                 ZORIGINALFILENAME VARCHAR);
+             -- mid-July 2023, so the month is the same in every timezone.
+             INSERT INTO ZASSET VALUES (1, 'A', 'AAAA.heic', 711000000, 'AAAA');
+             INSERT INTO ZADDITIONALASSETATTRIBUTES VALUES (1, 1, 'IMG_0001.HEIC');",
+        )
+        .unwrap();
+        drop(conn);
+        fs::write(
+            path(package).join("originals/A/AAAA.heic"),
+            "from the library",
+        )
+        .unwrap();
+        // another camera's photo of the same name, archived from elsewhere.
+        fs::write(path("out/2023/07/IMG_0001.HEIC"), "from elsewhere").unwrap();
+
+        let in_dir = format!("--in-dir={}", path(package).display());
+        let engine = test_engine(dir.path(), &["--include-small-files", &in_dir]);
+        let detected = engine.detect_new().unwrap();
+        assert_eq!(engine.transfer(detected).unwrap().files_transferred, 1);
+        engine.finish().unwrap();
+        assert_eq!(
+            fs::read_to_string(path("out/2023/07/IMG_0001 (2).HEIC")).unwrap(),
+            "from the library"
+        );
+        assert_eq!(
+            fs::read_to_string(path("out/2023/07/IMG_0001.HEIC")).unwrap(),
+            "from elsewhere"
+        );
+    }
+}
@@ -0,0 +1,194 @@
+//! Why files couldn't be transferred, counted for each run, and the exit codes a sync fails with,
+//! so a scheduler can tell a full disk from a flaky network share without reading the log:
+//!
+//! - 1: any error not listed here.
+//! - 3: the catalogue failed, e.g. the database is corrupt or the catalogue server went away.
+//! - 10 to 18: more files failed than `--max-failures` allows, by the most common reason, as
+//!   [`FailureKind::exit_code`] gives. A run which fails outright as the disk is full exits 13.
+
+use std::{collections::BTreeMap, fmt, io};
+
+use eyre::Result;
+use serde::{Deserialize, Serialize};
+
+/// The exit code of a sync which failed outright, for a reason with no code of its own.
+const GENERAL_ERROR: u8 = 1;
+/// The exit code of a sync whose catalogue failed.
+const STORE_ERROR: u8 = 3;
+
+/// Why a file couldn't be transferred.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum FailureKind {
+    PermissionDenied,
+    /// The file was deleted or moved while it was being transferred.
+    Vanished,
+    /// The file was written to while it was being copied, or its metadata has changed since it
+    /// was transferred.
+    ChangedWhileCopying,
+    DiskFull,
+    /// The archived copy didn't read back as what was written.
+    DigestMismatch,
+    /// The file looks corrupt, or too small for its type to be intact, so wasn't transferred.
+    Corrupt,
+    /// The file's place in the out directory is taken, as `--on-collision` says it can't be.
+    Collided,
+    /// Its per-file hook, the plugin, or carrying over its AppleDouble file, failed.
+    Hook,
+    /// Any other I/O error, or one which couldn't be told apart.
+    Other,
+}
+
+impl FailureKind {
+    /// The kind of failure `e` is, while reading or writing a file.
+    pub fn of_io(e: &io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::PermissionDenied | io::ErrorKind::ReadOnlyFilesystem => {
+                Self::PermissionDenied
+            }
+            io::ErrorKind::NotFound => Self::Vanished,
+            io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded => Self::DiskFull,
+            _ => Self::Other,
+        }
+    }
+
+    /// The kind of failure `e` is, going by the first I/O error it was caused by.
+    pub fn of(e: &eyre::Report) -> Self {
+        (e.chain())
+            .find_map(|cause| cause.downcast_ref::<io::Error>())
+            .map_or(Self::Other, Self::of_io)
+    }
+
+    /// How it's described, as it's kept in the catalogue.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::PermissionDenied => "permission denied",
+            Self::Vanished => "vanished",
+            Self::ChangedWhileCopying => "changed while copying",
+            Self::DiskFull => "disk full",
+            Self::DigestMismatch => "digest mismatch",
+            Self::Corrupt => "corrupt",
+            Self::Collided => "collided",
+            Self::Hook => "hook failed",
+            Self::Other => "other",
+        }
+    }
+
+    /// What a sync exits with when too many files failed, most of them for this reason.
+    pub fn exit_code(self) -> u8 {
+        match self {
+            Self::PermissionDenied => 10,
+            Self::Vanished => 11,
+            Self::ChangedWhileCopying => 12,
+            Self::DiskFull => 13,
+            Self::DigestMismatch => 14,
+            Self::Corrupt => 15,
+            Self::Collided => 16,
+            Self::Hook => 17,
+            Self::Other => 18,
+        }
+    }
+}
+
+impl fmt::Display for FailureKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// More files failed than `--max-failures` allows.
+#[derive(Debug)]
+pub struct TooManyFailures {
+    pub failed: u64,
+    pub max: u64,
+    /// Why most of them failed.
+    pub most_common: FailureKind,
+}
+
+impl fmt::Display for TooManyFailures {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} files failed, more than the {} allowed, most of them as: {}",
+            self.failed, self.max, self.most_common
+        )
+    }
+}
+
+impl std::error::Error for TooManyFailures {}
+
+/// Fails if more files have failed, as many as `failures` says for each reason, than `max`
+/// allows, if given.
+pub fn check_failures(failures: &BTreeMap<FailureKind, u64>, max: Option<u64>) -> Result<()> {
+    let failed = failures.values().sum();
+    let Some(max) = max.filter(|max| failed > *max) else {
+        return Ok(());
+    };
+    Err(TooManyFailures {
+        failed,
+        max,
+        most_common: most_common(failures),
+    }
+    .into())
+}
+
+/// The reason most files failed for, the first of them if there's a tie.
+fn most_common(failures: &BTreeMap<FailureKind, u64>) -> FailureKind {
+    let most = failures.values().copied().max().unwrap_or_default();
+    (failures.iter())
+        .find(|(_, files)| **files == most)
+        .map_or(FailureKind::Other, |(kind, _)| *kind)
+}
+
+/// What the process exits with after failing with `e`.
+pub fn exit_code(e: &eyre::Report) -> u8 {
+    if let Some(too_many) = e.downcast_ref::<TooManyFailures>() {
+        return too_many.most_common.exit_code();
+    }
+    if e.chain()
+        .any(|cause| cause.downcast_ref::<rusqlite::Error>().is_some())
+    {
+        return STORE_ERROR;
+    }
+    match FailureKind::of(e) {
+        FailureKind::DiskFull => FailureKind::DiskFull.exit_code(),
+        _ => GENERAL_ERROR,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use eyre::WrapErr;
+
+    use super::*;
+    use crate::metrics::RunStats;
+
+    #[test]
+    fn exit_codes_tell_failures_apart() {
+        let stats = RunStats::default();
+        stats.record_failures(FailureKind::Vanished, 1);
+        stats.record_failures(FailureKind::DiskFull, 2);
+        let failures = stats.failures();
+        assert!(check_failures(&failures, None).is_ok());
+        assert!(check_failures(&failures, Some(3)).is_ok());
+        let too_many = check_failures(&failures, Some(2)).unwrap_err();
+        assert_eq!(exit_code(&too_many), 13);
+
+        // a later batch of a `--watch` is checked by its own failures.
+        let before = stats.failures();
+        assert!(check_failures(&stats.failures_since(&before), Some(2)).is_ok());
+        stats.record_failures(FailureKind::Corrupt, 3);
+        let too_many = check_failures(&stats.failures_since(&before), Some(2)).unwrap_err();
+        assert_eq!(exit_code(&too_many), 15);
+
+        let full = Err::<(), _>(io::Error::from(io::ErrorKind::StorageFull))
+            .wrap_err("could not write the copy")
+            .unwrap_err();
+        assert_eq!(FailureKind::of(&full), FailureKind::DiskFull);
+        assert_eq!(exit_code(&full), 13);
+        let store = Err::<(), _>(rusqlite::Error::InvalidQuery)
+            .wrap_err("could not catalogue a file")
+            .unwrap_err();
+        assert_eq!(exit_code(&store), STORE_ERROR);
+        assert_eq!(exit_code(&eyre::eyre!("no --in-dir")), GENERAL_ERROR);
+    }
+}
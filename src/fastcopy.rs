@@ -0,0 +1,145 @@
+//! Putting a new file's content in its temporary file without reading and writing it through the
+//! process, where the filesystems allow: by cloning it, so the two share their blocks until either
+//! is written, or failing that with `copy_file_range`, which copies within the kernel, or even on
+//! the file server. The new file is still read once, to hash it, but its copy is never written
+//! from here.
+
+use std::{fs::File, io, path::Path};
+
+use clap::ValueEnum;
+use tempfile::NamedTempFile;
+
+/// How new files are copied to the temporary directory.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum CopyStrategy {
+    /// Clone the file, or copy it within the kernel, where the filesystems allow, and otherwise
+    /// read it and write its copy.
+    #[default]
+    Auto,
+    /// Always read the file and write its copy.
+    Read,
+}
+
+/// How a file was copied without being read and written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(not(any(target_os = "linux", target_os = "macos")), allow(dead_code))]
+pub enum FastCopy {
+    Cloned,
+    CopiedInKernel,
+}
+
+/// A temporary file in `temp_dir` holding the content of `from`, opened from `from_path`, copied
+/// without being read, or `None` if that can't be done between these filesystems.
+#[cfg(target_os = "linux")]
+pub fn fast_copy(
+    from: &File,
+    _from_path: &Path,
+    temp_dir: &Path,
+) -> io::Result<Option<(NamedTempFile, FastCopy)>> {
+    use std::os::fd::AsRawFd;
+
+    let temp = NamedTempFile::new_in(temp_dir)?;
+    let (from_fd, to_fd) = (from.as_raw_fd(), temp.as_file().as_raw_fd());
+    // SAFETY: both descriptors are open for the duration of the call.
+    if unsafe { libc::ioctl(to_fd, libc::FICLONE, from_fd) } == 0 {
+        return Ok(Some((temp, FastCopy::Cloned)));
+    }
+    let e = io::Error::last_os_error();
+    if !unsupported(&e) {
+        return Err(e);
+    }
+    // the offsets are given, so neither file's position moves, and `from` is then read from its
+    // start to hash it.
+    let len = from.metadata()?.len();
+    let (mut off_in, mut off_out) = (0i64, 0i64);
+    while (off_in as u64) < len {
+        let remaining = (len - off_in as u64) as usize;
+        // SAFETY: both descriptors are open, and the offsets outlive the call.
+        let copied = unsafe {
+            libc::copy_file_range(from_fd, &mut off_in, to_fd, &mut off_out, remaining, 0)
+        };
+        if copied < 0 {
+            let e = io::Error::last_os_error();
+            if off_in == 0 && unsupported(&e) {
+                return Ok(None);
+            }
+            return Err(e);
+        }
+        // it shrank while being copied, which the caller's check for changes catches.
+        if copied == 0 {
+            break;
+        }
+    }
+    Ok(Some((temp, FastCopy::CopiedInKernel)))
+}
+
+/// Whether `e` says files can't be cloned or copied so between these filesystems, rather than
+/// that something went wrong.
+#[cfg(target_os = "linux")]
+fn unsupported(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::EXDEV | libc::EOPNOTSUPP | libc::ENOTTY | libc::EINVAL | libc::ENOSYS)
+    )
+}
+
+/// A temporary file in `temp_dir` holding the content of `from`, opened from `from_path`, copied
+/// without being read, or `None` if that can't be done between these filesystems.
+#[cfg(target_os = "macos")]
+pub fn fast_copy(
+    _from: &File,
+    from_path: &Path,
+    temp_dir: &Path,
+) -> io::Result<Option<(NamedTempFile, FastCopy)>> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let from = CString::new(from_path.as_os_str().as_bytes())?;
+    // clonefile makes the file it clones to, so it's given a free name rather than an open file.
+    let cloned = tempfile::Builder::new().make_in(temp_dir, |path| {
+        let to = CString::new(path.as_os_str().as_bytes())?;
+        // SAFETY: both are valid NUL-terminated paths.
+        if unsafe { libc::clonefile(from.as_ptr(), to.as_ptr(), 0) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        File::options().read(true).write(true).open(path)
+    });
+    match cloned {
+        Ok(temp) => Ok(Some((temp, FastCopy::Cloned))),
+        Err(e) if matches!(e.raw_os_error(), Some(libc::EXDEV | libc::ENOTSUP)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn fast_copy(
+    _from: &File,
+    _from_path: &Path,
+    _temp_dir: &Path,
+) -> io::Result<Option<(NamedTempFile, FastCopy)>> {
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn copies_leave_the_original_to_be_hashed() {
+        let dir = tempfile::tempdir().unwrap();
+        let (original, temp_dir) = (dir.path().join("IMG_0001.JPG"), dir.path().join("tmp"));
+        fs::create_dir(&temp_dir).unwrap();
+        let content: Vec<u8> = (0..100_000u32).map(|n| (n % 251) as u8).collect();
+        fs::write(&original, &content).unwrap();
+        let mut from = File::open(&original).unwrap();
+
+        // some filesystems can do neither, which leaves the copy to be read and written.
+        if let Some((copy, _)) = fast_copy(&from, &original, &temp_dir).unwrap() {
+            assert_eq!(fs::read(copy.path()).unwrap(), content);
+            let mut read = Vec::new();
+            io::Read::read_to_end(&mut from, &mut read).unwrap();
+            assert_eq!(read, content);
+        }
+    }
+}
@@ -0,0 +1,136 @@
+//! Capping how many files a run's workers hold open at once, within the process's limit on open
+//! files, so that many workers don't fail with "too many open files", e.g. on macOS where the
+//! default limit is 256.
+
+use std::sync::{Condvar, Mutex};
+
+/// Open files kept back from the budget for the catalogue and its journal, logs and the like.
+const RESERVED: usize = 32;
+/// The budget where the limit can't be read.
+const DEFAULT_BUDGET: usize = 512;
+/// How many files one transfer holds open at once: the source, the staged copy, a compressed or
+/// encrypted copy of that, and the destination's directory.
+pub const FILES_PER_TRANSFER: usize = 4;
+
+/// How many more files the workers may open.
+pub struct FdBudget {
+    total: usize,
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+impl FdBudget {
+    pub fn new(total: usize) -> Self {
+        Self {
+            total,
+            available: Mutex::new(total),
+            released: Condvar::new(),
+        }
+    }
+
+    /// A budget of what the process's limit on open files leaves, raising the limit as far as
+    /// allowed first.
+    pub fn from_limit() -> Self {
+        let total = open_files_limit().map_or(DEFAULT_BUDGET, |limit| {
+            limit.saturating_sub(RESERVED).max(FILES_PER_TRANSFER)
+        });
+        Self::new(total)
+    }
+
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    /// Waits until `files` more can be opened, or the whole budget if it's smaller, and holds
+    /// them until the permit is dropped.
+    pub fn acquire(&self, files: usize) -> FdPermit<'_> {
+        let files = files.min(self.total);
+        let available = self.available.lock().unwrap();
+        let mut available = self
+            .released
+            .wait_while(available, |available| *available < files)
+            .unwrap();
+        *available -= files;
+        FdPermit {
+            budget: self,
+            files,
+        }
+    }
+}
+
+/// Files some worker may have open, given back to the budget when dropped.
+pub struct FdPermit<'a> {
+    budget: &'a FdBudget,
+    files: usize,
+}
+
+impl Drop for FdPermit<'_> {
+    fn drop(&mut self) {
+        *self.budget.available.lock().unwrap() += self.files;
+        self.budget.released.notify_all();
+    }
+}
+
+/// The soft limit on open files, first raised to the hard limit where it's lower. macOS refuses
+/// soft limits above `OPEN_MAX` whatever the hard limit, so that's as far as it's raised.
+#[cfg(unix)]
+fn open_files_limit() -> Option<usize> {
+    const OPEN_MAX: libc::rlim_t = 10240;
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: `limit` is a valid rlimit to write to.
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return None;
+    }
+    let wanted = limit.rlim_max.min(OPEN_MAX);
+    if wanted > limit.rlim_cur {
+        let raised = libc::rlimit {
+            rlim_cur: wanted,
+            rlim_max: limit.rlim_max,
+        };
+        // SAFETY: `raised` is a valid rlimit to read.
+        if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &raised) } == 0 {
+            limit.rlim_cur = wanted;
+        }
+    }
+    usize::try_from(limit.rlim_cur).ok()
+}
+
+#[cfg(not(unix))]
+fn open_files_limit() -> Option<usize> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicBool, Ordering},
+        thread,
+        time::Duration,
+    };
+
+    use super::*;
+
+    #[test]
+    fn workers_wait_for_the_budget() {
+        assert!(FdBudget::from_limit().total() >= FILES_PER_TRANSFER);
+        let budget = FdBudget::new(5);
+        let acquired = AtomicBool::new(false);
+        let first = budget.acquire(FILES_PER_TRANSFER);
+        thread::scope(|s| {
+            s.spawn(|| {
+                let _second = budget.acquire(FILES_PER_TRANSFER);
+                acquired.store(true, Ordering::SeqCst);
+            });
+            // what's left can still be used, and asking for more than there is takes it all.
+            drop(budget.acquire(1));
+            thread::sleep(Duration::from_millis(50));
+            assert!(!acquired.load(Ordering::SeqCst));
+            drop(first);
+        });
+        assert!(acquired.load(Ordering::SeqCst));
+        drop(budget.acquire(100));
+    }
+}
@@ -0,0 +1,385 @@
+//! `--exclude` and `--include` globs, deciding which files under the in and old out directories
+//! are considered at all, e.g. to leave out `.DS_Store` files and thumbnail caches,
+//! `--extensions`, `--min-size` and `--max-size`, deciding which new files count as photos, and
+//! `--since`, `--until` and `--only-subdir`, narrowing a sync to part of the in directory.
+
+use std::{
+    collections::HashSet,
+    fmt::{self, Display},
+    path::{Component, Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use chrono::{Local, NaiveDate, NaiveDateTime, TimeZone};
+use eyre::{Result, WrapErr};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use serde::Serialize;
+
+/// Excluded unless `--no-default-excludes` is given: files and directories operating systems and
+/// NASes leave behind, which are never photos.
+const DEFAULT_EXCLUDES: [&str; 8] = [
+    ".DS_Store",
+    ".Spotlight-V100",
+    ".Trashes",
+    ".fseventsd",
+    ".thumbnails",
+    "@eaDir",
+    "Thumbs.db",
+    "desktop.ini",
+];
+
+pub struct PathFilters {
+    exclude: Globs,
+    /// When there are none, every file which isn't excluded is included.
+    include: Option<Globs>,
+}
+
+impl PathFilters {
+    pub fn new(exclude: &[String], include: &[String], default_excludes: bool) -> Result<Self> {
+        let defaults = default_excludes.then_some(DEFAULT_EXCLUDES.as_slice());
+        let exclude = defaults
+            .into_iter()
+            .flatten()
+            .copied()
+            .chain(exclude.iter().map(String::as_str));
+        Ok(Self {
+            exclude: Globs::new(exclude)?,
+            include: (!include.is_empty())
+                .then(|| Globs::new(include.iter().map(String::as_str)))
+                .transpose()?,
+        })
+    }
+
+    /// Whether `path`, relative to the directory walked, is considered. Directories are only
+    /// excluded, so that included files within them are still found.
+    pub fn admits(&self, path: &Path, is_dir: bool) -> bool {
+        if self.exclude.matches(path) {
+            return false;
+        }
+        is_dir
+            || self
+                .include
+                .as_ref()
+                .is_none_or(|include| include.matches(path))
+    }
+}
+
+/// Globs without a `/`, which match a name anywhere, as in `.gitignore`, and those with one,
+/// which match the whole relative path. Both ignore case, as the filesystems photos come from
+/// mostly do.
+pub struct Globs {
+    names: GlobSet,
+    paths: GlobSet,
+}
+
+impl Globs {
+    pub fn new<'a>(globs: impl IntoIterator<Item = &'a str>) -> Result<Self> {
+        let (mut names, mut paths) = (GlobSetBuilder::new(), GlobSetBuilder::new());
+        for glob in globs {
+            let (set, pattern) = match glob.strip_prefix('/') {
+                Some(pattern) => (&mut paths, pattern),
+                None if glob.contains('/') => (&mut paths, glob),
+                None => (&mut names, glob),
+            };
+            set.add(
+                GlobBuilder::new(pattern)
+                    .case_insensitive(true)
+                    .literal_separator(true)
+                    .build()
+                    .wrap_err_with(|| format!("{glob:?} is not a valid glob"))?,
+            );
+        }
+        Ok(Self {
+            names: names.build()?,
+            paths: paths.build()?,
+        })
+    }
+
+    pub fn matches(&self, path: &Path) -> bool {
+        path.file_name()
+            .is_some_and(|name| self.names.is_match(name))
+            || self.paths.is_match(path)
+    }
+}
+
+/// Why a new file was left out by [`FileFilters`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LeftOut {
+    Extension,
+    Size,
+    Modified,
+}
+
+/// `--extensions`, `--min-size`, `--max-size`, `--since` and `--until`.
+#[derive(Default)]
+pub struct FileFilters {
+    /// Lowercase, without the dot. When there are none, every extension is allowed.
+    extensions: Option<HashSet<String>>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    since: Option<SystemTime>,
+    until: Option<SystemTime>,
+}
+
+impl FileFilters {
+    pub fn new(extensions: &[String], min_size: Option<u64>, max_size: Option<u64>) -> Self {
+        Self {
+            extensions: (!extensions.is_empty()).then(|| {
+                extensions
+                    .iter()
+                    .map(|ext| ext.trim_start_matches('.').to_lowercase())
+                    .collect()
+            }),
+            min_size,
+            max_size,
+            ..Self::default()
+        }
+    }
+
+    /// Also leaves out new files last modified before `since` or after `until`.
+    pub fn modified_between(self, since: Option<SystemTime>, until: Option<SystemTime>) -> Self {
+        Self {
+            since,
+            until,
+            ..self
+        }
+    }
+
+    /// Why the new file at `path`, of `size` bytes and last modified at `modified`, is left out,
+    /// if it is.
+    pub fn leaves_out(&self, path: &Path, size: u64, modified: SystemTime) -> Option<LeftOut> {
+        if let Some(extensions) = &self.extensions {
+            let extension = path.extension().and_then(|ext| ext.to_str());
+            if !extension.is_some_and(|ext| extensions.contains(&ext.to_lowercase())) {
+                return Some(LeftOut::Extension);
+            }
+        }
+        if self.min_size.is_some_and(|min| size < min)
+            || self.max_size.is_some_and(|max| size > max)
+        {
+            return Some(LeftOut::Size);
+        }
+        if self.since.is_some_and(|since| modified < since)
+            || self.until.is_some_and(|until| modified > until)
+        {
+            return Some(LeftOut::Modified);
+        }
+        None
+    }
+}
+
+/// What part of the in directory a sync was narrowed to, as its summary records it.
+#[derive(Debug, Default, Serialize)]
+pub struct SyncScope {
+    /// `--since` and `--until`, as Unix times.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub since: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub until: Option<u64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub only_subdirs: Vec<PathBuf>,
+}
+
+impl SyncScope {
+    pub fn new(
+        since: Option<SystemTime>,
+        until: Option<SystemTime>,
+        only_subdirs: &[PathBuf],
+    ) -> Self {
+        let unix = |time: SystemTime| {
+            time.duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        };
+        Self {
+            since: since.map(unix),
+            until: until.map(unix),
+            only_subdirs: only_subdirs.to_vec(),
+        }
+    }
+
+    /// Whether the whole in directory was synced.
+    pub fn is_everything(&self) -> bool {
+        self.since.is_none() && self.until.is_none() && self.only_subdirs.is_empty()
+    }
+}
+
+impl Display for SyncScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let local = |secs: u64| {
+            let time = chrono::DateTime::from_timestamp(secs as i64, 0).unwrap_or_default();
+            time.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S")
+        };
+        let mut parts = Vec::new();
+        if let Some(since) = self.since {
+            parts.push(format!("modified since {}", local(since)));
+        }
+        if let Some(until) = self.until {
+            parts.push(format!("modified until {}", local(until)));
+        }
+        if !self.only_subdirs.is_empty() {
+            parts.push(format!("within {:?}", self.only_subdirs));
+        }
+        f.write_str(&parts.join(", "))
+    }
+}
+
+/// Parses a local date, e.g. `2024-01-31`, a local date and time, e.g. `2024-01-31 18:30` or
+/// `2024-01-31T18:30:00`, or a time that long ago, in days, weeks or hours, e.g. `30d`.
+pub fn parse_time(s: &str) -> Result<SystemTime, String> {
+    let s = s.trim();
+    let ago = |unit: u64| {
+        let count: u64 = s[..s.len() - 1]
+            .parse()
+            .map_err(|_| format!("{s:?} is not a time, e.g. 2024-01-31 or 30d"))?;
+        count
+            .checked_mul(unit)
+            .and_then(|secs| SystemTime::now().checked_sub(Duration::from_secs(secs)))
+            .ok_or_else(|| format!("{s:?} is too long ago"))
+    };
+    match s.chars().last() {
+        Some('h') => return ago(60 * 60),
+        Some('d') => return ago(24 * 60 * 60),
+        Some('w') => return ago(7 * 24 * 60 * 60),
+        _ => {}
+    }
+    let naive = match NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        Ok(date) => date.and_hms_opt(0, 0, 0),
+        Err(_) => [
+            "%Y-%m-%d %H:%M",
+            "%Y-%m-%dT%H:%M",
+            "%Y-%m-%d %H:%M:%S",
+            "%Y-%m-%dT%H:%M:%S",
+        ]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(s, format).ok()),
+    };
+    let naive = naive
+        .ok_or_else(|| format!("{s:?} is not a time, e.g. 2024-01-31, 2024-01-31 18:30 or 30d"))?;
+    // the earlier, when the clocks go back.
+    let local = Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .ok_or_else(|| format!("{s:?} doesn't exist in the local timezone"))?;
+    Ok(local.into())
+}
+
+/// Parses a directory within the in directory, which mustn't lead out of it.
+pub fn parse_subdir(s: &str) -> Result<PathBuf, String> {
+    let path = PathBuf::from(s);
+    if !path
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(format!(
+            "{s:?} must be a path within the in directory, e.g. 2024/Holiday"
+        ));
+    }
+    Ok(path)
+}
+
+/// Parses a size in bytes, optionally with a `K`, `M`, `G` or `T` suffix (powers of 1000, as the
+/// rest of the output reports sizes), e.g. `20K` or `4G`.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let digits = s.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let multiplier = match s[digits.len()..].to_ascii_uppercase().trim_end_matches('B') {
+        "" => 1,
+        "K" => 1_000,
+        "M" => 1_000_000,
+        "G" => 1_000_000_000,
+        "T" => 1_000_000_000_000,
+        suffix => {
+            return Err(format!(
+                "unknown size suffix {suffix:?}, expected K, M, G or T"
+            ));
+        }
+    };
+    let number: u64 = digits
+        .parse()
+        .map_err(|_| format!("{s:?} is not a size, e.g. 500K or 2G"))?;
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("{s:?} is too large"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn excludes_win_over_includes() {
+        let filters = PathFilters::new(
+            &["*.aae".into(), "2019/drafts".into()],
+            &["*.heic".into(), "*.jpg".into()],
+            true,
+        )
+        .unwrap();
+        let admits = |path: &str, is_dir| filters.admits(Path::new(path), is_dir);
+
+        assert!(admits("2019/IMG_0001.HEIC", false));
+        assert!(admits("2019/IMG_0001.jpg", false));
+        assert!(!admits("2019/IMG_0001.AAE", false));
+        assert!(!admits("2019/notes.pdf", false));
+        assert!(!admits("2019/.DS_Store", false));
+        assert!(!admits("2019/drafts", true));
+        // directories are walked for the files they include.
+        assert!(admits("2019/holiday", true));
+        assert!(!admits("@eaDir", true));
+
+        let everything = PathFilters::new(&[], &[], false).unwrap();
+        assert!(everything.admits(Path::new(".DS_Store"), false));
+    }
+
+    #[test]
+    fn new_files_are_left_out_by_type_size_and_time() {
+        let filters = FileFilters::new(
+            &["heic".into(), ".JPG".into()],
+            Some(parse_size("10K").unwrap()),
+            Some(parse_size("2GB").unwrap()),
+        );
+        let now = SystemTime::now();
+        let leaves_out = |path: &str, size| filters.leaves_out(Path::new(path), size, now);
+
+        assert_eq!(leaves_out("2019/IMG_0001.HEIC", 2_000_000), None);
+        assert_eq!(leaves_out("2019/IMG_0001.jpg", 2_000_000_000), None);
+        assert_eq!(
+            leaves_out("2019/scan.pdf", 2_000_000),
+            Some(LeftOut::Extension)
+        );
+        assert_eq!(
+            leaves_out("2019/README", 2_000_000),
+            Some(LeftOut::Extension)
+        );
+        assert_eq!(leaves_out("2019/thumb.jpg", 9_999), Some(LeftOut::Size));
+        assert_eq!(
+            leaves_out("2019/huge.heic", 2_000_000_001),
+            Some(LeftOut::Size)
+        );
+        assert_eq!(
+            FileFilters::new(&[], None, None).leaves_out(Path::new("a"), 0, now),
+            None
+        );
+
+        let since = parse_time("2024-01-31").unwrap();
+        let until = parse_time("2024-02-29 18:30").unwrap();
+        assert!(since < until && until < parse_time("30d").unwrap());
+        let filters = FileFilters::default().modified_between(Some(since), Some(until));
+        let modified_at = |modified| filters.leaves_out(Path::new("a.jpg"), 1, modified);
+        assert_eq!(modified_at(since), None);
+        assert_eq!(modified_at(until), None);
+        assert_eq!(
+            modified_at(since - Duration::from_secs(1)),
+            Some(LeftOut::Modified)
+        );
+        assert_eq!(modified_at(now), Some(LeftOut::Modified));
+        assert!(parse_time("yesterday").is_err());
+        assert!(parse_time(&format!("{}w", u64::MAX / 2)).is_err());
+        assert!(parse_subdir("../elsewhere").is_err());
+        assert!(parse_subdir("/photos").is_err());
+
+        assert_eq!(parse_size("512"), Ok(512));
+        assert!(parse_size("5X").is_err());
+        assert!(parse_size("K").is_err());
+    }
+}
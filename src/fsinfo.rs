@@ -0,0 +1,101 @@
+//! What the OS can tell us about the filesystem a path is on.
+
+use std::path::Path;
+#[cfg(unix)]
+use std::{ffi::CString, io, mem::MaybeUninit, os::unix::ffi::OsStrExt};
+
+use eyre::Result;
+
+#[cfg(unix)]
+fn c_path(path: &Path) -> Result<CString> {
+    Ok(CString::new(path.as_os_str().as_bytes())?)
+}
+
+/// The bytes available to unprivileged users on the filesystem holding `path`, if that can be
+/// told on this platform.
+#[cfg(unix)]
+pub fn free_space(path: &Path) -> Result<Option<u64>> {
+    let c_path = c_path(path)?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `c_path` is NUL terminated, and `stat` is only read once statvfs has filled it in.
+    if unsafe { libc::statvfs(c_path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let stat = unsafe { stat.assume_init() };
+    #[allow(clippy::unnecessary_cast, clippy::useless_conversion)]
+    Ok(Some(u64::from(stat.f_bavail) * u64::from(stat.f_frsize)))
+}
+
+#[cfg(not(unix))]
+pub fn free_space(_path: &Path) -> Result<Option<u64>> {
+    Ok(None)
+}
+
+/// The kind of network filesystem holding `path`, if it's on one. File locking, which sqlite
+/// relies on, is unreliable on these.
+#[cfg(target_os = "linux")]
+pub fn network_filesystem(path: &Path) -> Result<Option<&'static str>> {
+    const NETWORK_FILESYSTEMS: &[(i64, &str)] = &[
+        (0x6969, "NFS"),
+        (0x517b, "SMB"),
+        (0xff53_4d42, "CIFS"),
+        (0xfe53_4d42, "SMB2"),
+        (0x0000_564c, "NCP"),
+        (0x6b41_4653, "AFS"),
+        (0x00c3_6400, "Ceph"),
+        (0x0102_1997, "9P"),
+        // most network mounts not covered above, e.g. sshfs, are FUSE filesystems.
+        (0x6573_5546, "FUSE"),
+    ];
+    let c_path = c_path(path)?;
+    let mut stat = MaybeUninit::<libc::statfs>::uninit();
+    // SAFETY: as for statvfs above.
+    if unsafe { libc::statfs(c_path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let stat = unsafe { stat.assume_init() };
+    #[allow(clippy::unnecessary_cast)]
+    let kind = stat.f_type as i64;
+    Ok(NETWORK_FILESYSTEMS
+        .iter()
+        .find(|(magic, _)| *magic == kind)
+        .map(|(_, name)| *name))
+}
+
+/// The kind of network filesystem holding `path`, if it's on one. File locking, which sqlite
+/// relies on, is unreliable on these.
+#[cfg(target_os = "macos")]
+pub fn network_filesystem(path: &Path) -> Result<Option<&'static str>> {
+    const NETWORK_FILESYSTEMS: &[&str] = &["nfs", "smbfs", "afpfs", "webdav", "macfuse", "osxfuse"];
+    let c_path = c_path(path)?;
+    let mut stat = MaybeUninit::<libc::statfs>::uninit();
+    // SAFETY: as for statvfs above.
+    if unsafe { libc::statfs(c_path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let stat = unsafe { stat.assume_init() };
+    // SAFETY: the OS NUL terminates the type name.
+    let kind = unsafe { std::ffi::CStr::from_ptr(stat.f_fstypename.as_ptr()) };
+    Ok(NETWORK_FILESYSTEMS
+        .iter()
+        .find(|name| kind.to_bytes() == name.as_bytes())
+        .copied())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn network_filesystem(_path: &Path) -> Result<Option<&'static str>> {
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn temp_dir_is_inspectable() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(free_space(dir.path()).unwrap().unwrap() > 0);
+        network_filesystem(dir.path()).unwrap();
+        assert!(free_space(&dir.path().join("missing")).is_err());
+    }
+}
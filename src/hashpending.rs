@@ -0,0 +1,94 @@
+//! Hashing the files in the old out directory which `--trust-size-mtime` catalogued on their
+//! metadata alone, so that their content can be deduplicated against.
+
+use std::path::Path;
+
+use eyre::Result;
+
+use crate::{
+    HashPendingArgs,
+    digest::HashAlgorithm,
+    platform::FileInfo,
+    store::{PhotoSyncStore, RunId, RunStatus},
+};
+
+pub fn hash_pending(args: HashPendingArgs) -> Result<()> {
+    let store = PhotoSyncStore::new(args.database_file)?;
+    let run = store.begin_run(&args.machine_id)?;
+    let result = hash_all(&store, run, &args.old_out_dir, args.limit, args.hash_algo);
+    let status = match &result {
+        Ok(_) => RunStatus::Succeeded,
+        Err(_) => RunStatus::Aborted,
+    };
+    store.finish_run(run, status)?;
+    let (hashed, gone) = result?;
+    println!("hashed {hashed} files, forgot {gone} which have since gone");
+    Ok(())
+}
+
+/// Hashes up to `limit` pending files, returning how many were hashed and how many had gone.
+fn hash_all(
+    store: &PhotoSyncStore,
+    run: RunId,
+    old_out_dir: &Path,
+    limit: Option<usize>,
+    algorithm: HashAlgorithm,
+) -> Result<(usize, usize)> {
+    let (mut hashed, mut gone) = (0, 0);
+    for path in store
+        .pending_digests()?
+        .into_iter()
+        .take(limit.unwrap_or(usize::MAX))
+    {
+        let full_path = old_out_dir.join(&path);
+        if !full_path.is_file() {
+            store.forget_pending_digest(&path)?;
+            gone += 1;
+            continue;
+        }
+        // hashed as it is now, which may have changed since it was catalogued.
+        let info = FileInfo::of(&full_path)?;
+        let digest = algorithm.digest(&full_path)?;
+        store.mark_exists_in_old_target(run, &path, info.modified, info.size, &digest)?;
+        hashed += 1;
+    }
+    Ok((hashed, gone))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf, time::SystemTime};
+
+    use super::*;
+    use crate::digest::digest;
+
+    #[test]
+    fn pending_files_are_hashed_or_forgotten() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.jpg"), "photo").unwrap();
+        let store = PhotoSyncStore::new_for_tests().unwrap();
+        let run = store.begin_run("").unwrap();
+        let now = SystemTime::now();
+        for path in ["a.jpg", "gone.jpg"] {
+            store
+                .mark_pending_digest(run, Path::new(path), now, 5, None)
+                .unwrap();
+        }
+        assert!(store.is_pending_digest(Path::new("a.jpg"), now, 5).unwrap());
+
+        assert_eq!(
+            hash_all(&store, run, dir.path(), None, HashAlgorithm::Sha256).unwrap(),
+            (1, 1)
+        );
+        assert_eq!(store.pending_digests().unwrap(), Vec::<PathBuf>::new());
+        let catalogued = store.old_target_files().unwrap();
+        assert_eq!(catalogued.len(), 1);
+        assert_eq!(
+            (&*catalogued[0].path, catalogued[0].digest),
+            (
+                Path::new("a.jpg"),
+                digest(&dir.path().join("a.jpg")).unwrap()
+            )
+        );
+    }
+}
@@ -0,0 +1,138 @@
+//! `history`: the runs recorded in a catalogue and what each added to it, e.g. to see what last
+//! night's run archived without comparing snapshots of the directories.
+
+use std::time::SystemTime;
+
+use chrono::{DateTime, Local};
+use eyre::{Result, bail};
+
+use crate::{
+    HistoryArgs,
+    store::{AddedFile, PhotoSyncStore, RunRecord},
+};
+
+pub fn history(args: HistoryArgs) -> Result<()> {
+    let store = PhotoSyncStore::new(args.database_file)?;
+    let Some(id) = args.run else {
+        for run in store.runs(args.limit, None)? {
+            println!("{}", describe(&run));
+        }
+        return Ok(());
+    };
+    let Some(run) = store.runs(1, Some(id))?.pop() else {
+        bail!("there's no run {id} in the catalogue");
+    };
+    println!("{}", describe(&run));
+    if let Some(args) = &run.args {
+        println!("    {args}");
+    }
+    for (kind, files) in store.run_failures(id)? {
+        println!("    {files} failed: {kind}");
+    }
+    for file in store.files_added_by_run(id)? {
+        match file {
+            AddedFile::Source(namespace, path) => println!("transferred {namespace}:{path:?}"),
+            AddedFile::OldOut(path) => println!("indexed old:{path:?}"),
+            AddedFile::Out(path) => println!("archived out:{path:?}"),
+        }
+    }
+    Ok(())
+}
+
+/// A line saying when `run` was, and what it did.
+fn describe(run: &RunRecord) -> String {
+    let time = |time: SystemTime| {
+        DateTime::<Local>::from(time)
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string()
+    };
+    let mut line = format!(
+        "run {} of {:?} ({}), started {}",
+        run.id,
+        run.namespace,
+        run.status,
+        time(run.started_at)
+    );
+    if let Some(took) = (run.finished_at).and_then(|f| f.duration_since(run.started_at).ok()) {
+        line += &format!(", took {}s", took.as_secs());
+    }
+    if let Some(counts) = &run.counts {
+        line += &format!(
+            ": {} new files, {} transferred ({}MB), {} deduplicated, {} failed",
+            counts.files_detected,
+            counts.files_transferred,
+            counts.bytes_transferred / 1_000_000,
+            counts.files_deduplicated,
+            counts.files_failed
+        );
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::*;
+    use crate::{
+        digest::ContentHash,
+        store::{RunCounts, RunStatus},
+    };
+
+    #[test]
+    fn runs_are_listed_with_what_they_added() {
+        let store = PhotoSyncStore::new_for_tests().unwrap();
+        let now = SystemTime::now();
+        let digest = ContentHash::new_for_tests(1);
+        let first = store.begin_run("laptop").unwrap();
+        store
+            .mark_exists_in_old_target(first, Path::new("1998/scan.jpg"), now, 10, &digest)
+            .unwrap();
+        store.finish_run(first, RunStatus::Succeeded).unwrap();
+
+        let second = store.begin_run("laptop").unwrap();
+        store.record_run_args(second, "sync --in-dir x").unwrap();
+        store
+            .mark_transferred_from_source(
+                second,
+                "laptop",
+                Path::new("IMG_1.JPG"),
+                &digest,
+                now,
+                10,
+            )
+            .unwrap();
+        let counts = RunCounts {
+            files_detected: 1,
+            files_deduplicated: 1,
+            ..RunCounts::default()
+        };
+        store.record_run_counts(second, &counts).unwrap();
+        store.finish_run(second, RunStatus::Succeeded).unwrap();
+
+        let runs = store.runs(10, None).unwrap();
+        assert_eq!(
+            runs.iter().map(|run| run.id).collect::<Vec<_>>(),
+            [second, first]
+        );
+        assert_eq!(runs[0].args.as_deref(), Some("sync --in-dir x"));
+        assert_eq!(runs[1].counts, None);
+        let line = describe(&runs[0]);
+        assert!(line.starts_with(&format!(
+            r#"run {second} of "laptop" (succeeded), started "#
+        )));
+        assert!(line.ends_with(": 1 new files, 0 transferred (0MB), 1 deduplicated, 0 failed"));
+
+        assert_eq!(
+            store.files_added_by_run(second).unwrap(),
+            [AddedFile::Source(
+                "laptop".into(),
+                PathBuf::from("IMG_1.JPG")
+            )]
+        );
+        assert_eq!(
+            store.files_added_by_run(first).unwrap(),
+            [AddedFile::OldOut(PathBuf::from("1998/scan.jpg"))]
+        );
+    }
+}
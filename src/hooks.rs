@@ -0,0 +1,35 @@
+use std::process::Command;
+
+use eyre::{Result, bail, eyre};
+
+/// Runs a user supplied hook through `sh -c`, with `envs` set in its environment. Hook failures
+/// are reported as such, so they can't be mistaken for failures of the sync itself.
+pub fn run_hook(hook: &str, command: &str, envs: &[(&str, &str)]) -> Result<()> {
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .envs(envs.iter().copied())
+        .status()
+        .map_err(|e| eyre!("{hook} hook `{command}` could not be started: {e}"))?;
+    if !status.success() {
+        bail!("{hook} hook `{command}` failed: {status}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hook_sees_environment_and_reports_failure() {
+        run_hook(
+            "test",
+            r#"test "$PHOTO_SYNC_PATH" = a.jpg"#,
+            &[("PHOTO_SYNC_PATH", "a.jpg")],
+        )
+        .unwrap();
+        let err = run_hook("test", "exit 3", &[]).unwrap_err();
+        assert!(err.to_string().starts_with("test hook `exit 3` failed"));
+    }
+}
@@ -0,0 +1,119 @@
+//! Files iCloud Drive has evicted to save space, which are in the in directory in name only.
+//! Current macOS keeps them as "dataless" files, whose size is that of the content in iCloud and
+//! which are downloaded as they're read, and older versions as hidden `.<name>.icloud` stubs.
+//! iCloud for Windows keeps them as placeholders which are downloaded as they're read, too.
+//! None is hashed as it is: it's downloaded first, or skipped and reported.
+
+use std::{
+    fs::File,
+    io,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use clap::ValueEnum;
+use eyre::{Result, WrapErr, ensure};
+use tracing::debug;
+
+use crate::platform::is_dataless;
+
+/// What to do with a file iCloud Drive hasn't downloaded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum PlaceholderPolicy {
+    /// Ask iCloud Drive to download it, and transfer it once it has.
+    Download,
+    /// Leave it, reporting it as not downloaded, for a later run to transfer.
+    Skip,
+}
+
+/// How often a file being downloaded is checked on.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Whether the file at `path` is a placeholder for content only in iCloud.
+pub fn is_placeholder(path: &Path) -> bool {
+    path.metadata().is_ok_and(|metadata| is_dataless(&metadata))
+}
+
+/// The file an older macOS's stub `.<name>.icloud` stands for, `<name>` beside it, if `path` is
+/// one.
+pub fn stub_target(path: &Path) -> Option<PathBuf> {
+    let name = path.file_name()?.to_str()?;
+    let name = name.strip_prefix('.')?.strip_suffix(".icloud")?;
+    (!name.is_empty()).then(|| path.with_file_name(name))
+}
+
+/// The stub an older macOS leaves in place of `path` once it's evicted.
+pub fn stub_of(path: &Path) -> Option<PathBuf> {
+    let mut name = std::ffi::OsString::from(".");
+    name.push(path.file_name()?);
+    name.push(".icloud");
+    Some(path.with_file_name(name))
+}
+
+/// Asks iCloud Drive to download `path`, without waiting for it to.
+pub fn request_download(path: &Path) -> Result<()> {
+    // iCloud for Windows downloads placeholders as they're read, and has no brctl.
+    if cfg!(windows) {
+        let path = path.to_path_buf();
+        thread::spawn(move || {
+            if let Err(e) =
+                File::open(&path).and_then(|mut file| io::copy(&mut file, &mut io::sink()))
+            {
+                debug!("could not download {path:?}: {e}");
+            }
+        });
+        return Ok(());
+    }
+    let status = Command::new("brctl")
+        .arg("download")
+        .arg(path)
+        .stdout(Stdio::null())
+        .status()
+        .wrap_err("could not run brctl")?;
+    ensure!(status.success(), "brctl could not download {path:?}");
+    Ok(())
+}
+
+/// Asks iCloud Drive to download `path`, then waits up to `timeout` for it to have. Returns
+/// whether it did in time.
+pub fn download(path: &Path, timeout: Duration) -> Result<bool> {
+    request_download(path)?;
+    let deadline = Instant::now() + timeout;
+    while is_placeholder(path) {
+        if Instant::now() >= deadline {
+            return Ok(false);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn stubs_stand_for_the_file_beside_them() {
+        assert_eq!(
+            stub_target(Path::new("2019/.IMG_0001.JPG.icloud")),
+            Some(PathBuf::from("2019/IMG_0001.JPG"))
+        );
+        assert_eq!(stub_target(Path::new("2019/IMG_0001.JPG.icloud")), None);
+        assert_eq!(stub_target(Path::new(".icloud")), None);
+        assert_eq!(stub_target(Path::new("..icloud")), None);
+        let stub = stub_of(Path::new("2019/IMG_0001.JPG")).unwrap();
+        assert_eq!(stub, Path::new("2019/.IMG_0001.JPG.icloud"));
+        assert_eq!(stub_target(&stub), Some(PathBuf::from("2019/IMG_0001.JPG")));
+
+        // downloaded files, and those on other filesystems, aren't placeholders.
+        let dir = tempfile::tempdir().unwrap();
+        let photo = dir.path().join("IMG_0001.JPG");
+        fs::write(&photo, "photo").unwrap();
+        assert!(!is_placeholder(&photo));
+        assert!(!is_placeholder(&dir.path().join("missing.JPG")));
+    }
+}
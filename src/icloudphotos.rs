@@ -0,0 +1,835 @@
+//! iCloud Photos as an in directory, for machines with no Photos library or iCloud Drive to sync
+//! from, reached through the web API icloud.com uses. `icloud login` signs in, asking for a
+//! two-factor code the first time, and keeps the session and the token which trusts this machine
+//! in a session directory. Given as an `--in-dir`, the session directory's library is listed as
+//! each run starts, and each original not yet downloaded is downloaded into it as
+//! `originals/<asset ID>/<name>`, dated when it was taken, a page of the listing at a time; the run
+//! syncs each page's as it would any other file before downloading the next, archiving each as
+//! `YYYY/MM/<name>`, and deletes each download once it's archived, as `--move` would, so the
+//! library is never kept twice. Each asset's ID is catalogued, so it's only downloaded once; one
+//! which fails to download is counted as a failed file, and tried again next run.
+
+use std::{
+    fs::{self, File},
+    io::{self, BufRead, IsTerminal, Write},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    time::{Duration, SystemTime},
+};
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+use chrono::{DateTime, Datelike, Local};
+use eyre::{Result, WrapErr, bail, ensure, eyre};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use tempfile::NamedTempFile;
+use tracing::{debug, info, warn};
+
+use crate::{
+    ICloudArgs, ICloudCommand, SyncContext, bydate, copy, digest::DigestWriter,
+    failures::FailureKind, notify::config_string, timed_phase,
+};
+
+const AUTH_ENDPOINT: &str = "https://idmsa.apple.com/appleauth/auth";
+const SETUP_ENDPOINT: &str = "https://setup.icloud.com/setup/ws/1";
+/// The key icloud.com identifies itself to Apple's sign in with.
+const WIDGET_KEY: &str = "d39ba9916b7251055b22c7f910e2ea796ee65e98b2ddecea8f5dde8d9d1a815d";
+/// How many records the library is listed in at a time.
+const PAGE_SIZE: usize = 100;
+/// What's kept between runs in the session directory, besides curl's cookies.
+const SESSION: &str = "session.json";
+/// Where originals are downloaded to, within the session directory.
+const ORIGINALS: &str = "originals";
+/// The variable the password is taken from, rather than being asked for.
+const PASSWORD_VAR: &str = "PHOTO_SYNC_ICLOUD_PASSWORD";
+/// Where originals are downloaded to before they're moved into place, so that one cut short is
+/// never synced.
+const PARTIAL: &str = "partial";
+
+/// What's kept between runs in the session directory, besides curl's cookies.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Session {
+    apple_id: String,
+    session_id: Option<String>,
+    session_token: Option<String>,
+    scnt: Option<String>,
+    account_country: Option<String>,
+    /// The token which spares this machine a two-factor code when signing in again.
+    trust_token: Option<String>,
+    dsid: Option<String>,
+    /// Where the library's records are listed from.
+    ckdatabase_url: Option<String>,
+}
+
+/// An original in the library, as listed.
+#[derive(Debug, PartialEq, Eq)]
+struct Asset {
+    /// The ID of its master record, which stays the same however the asset is edited.
+    id: String,
+    name: String,
+    size: u64,
+    taken: SystemTime,
+    url: String,
+}
+
+/// A response, as curl reported it.
+struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn json(&self) -> Result<Value> {
+        serde_json::from_str(&self.body)
+            .wrap_err_with(|| format!("the response wasn't JSON: {}", self.body.trim()))
+    }
+}
+
+/// The web API, reached through `curl`, with its cookies and session kept in `dir`.
+struct Client {
+    dir: PathBuf,
+    session: Session,
+}
+
+pub fn icloud(args: ICloudArgs) -> Result<()> {
+    fs::create_dir_all(&args.session_dir)?;
+    match args.command {
+        ICloudCommand::Login { apple_id } => {
+            let mut client = Client::open(&args.session_dir, &apple_id)?;
+            client.login(&password(&apple_id)?)?;
+            println!("signed in as {apple_id}");
+        }
+    }
+    Ok(())
+}
+
+/// The password for `apple_id`, from [`PASSWORD_VAR`], or else asked for without echoing it.
+fn password(apple_id: &str) -> Result<String> {
+    if let Some(password) = std::env::var_os(PASSWORD_VAR) {
+        return (password.into_string()).map_err(|_| eyre!("{PASSWORD_VAR} isn't valid UTF-8"));
+    }
+    ensure!(
+        io::stdin().is_terminal(),
+        "a password is needed, and stdin isn't a terminal to ask for one; set {PASSWORD_VAR} or \
+         run `icloud login` interactively"
+    );
+    print!("password for {apple_id}: ");
+    io::stdout().flush()?;
+    let password = read_line_unechoed()?;
+    println!();
+    Ok(password.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Reads a line from the terminal on stdin, with echoing turned off while it's typed.
+#[cfg(unix)]
+fn read_line_unechoed() -> Result<String> {
+    use std::os::fd::AsRawFd;
+
+    let fd = io::stdin().as_raw_fd();
+    let mut termios = std::mem::MaybeUninit::<libc::termios>::uninit();
+    // SAFETY: `fd` is stdin, which stays open, and `termios` is only read once tcgetattr has
+    // filled it in.
+    if unsafe { libc::tcgetattr(fd, termios.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let echoing = unsafe { termios.assume_init() };
+    let mut unechoed = echoing;
+    unechoed.c_lflag &= !libc::ECHO;
+    // SAFETY: as for tcgetattr, with a `termios` it filled in.
+    if unsafe { libc::tcsetattr(fd, libc::TCSAFLUSH, &unechoed) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let mut line = String::new();
+    let read = io::stdin().lock().read_line(&mut line);
+    // SAFETY: as above.
+    unsafe { libc::tcsetattr(fd, libc::TCSAFLUSH, &echoing) };
+    read?;
+    Ok(line)
+}
+
+#[cfg(not(unix))]
+fn read_line_unechoed() -> Result<String> {
+    bail!("the password can't be asked for on this platform; set {PASSWORD_VAR}")
+}
+
+/// Whether `dir` is a session directory `icloud login` signed in to.
+pub fn is_session_dir(dir: &Path) -> bool {
+    dir.join(SESSION).is_file()
+}
+
+/// The library signed in to in a session directory, as an in directory.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ICloudLibrary {
+    session_dir: PathBuf,
+}
+
+impl ICloudLibrary {
+    /// The library signed in to in `session_dir`, whose originals are downloaded to
+    /// [`Self::originals`].
+    pub fn open(session_dir: &Path) -> Result<Self> {
+        let library = Self {
+            session_dir: session_dir.to_path_buf(),
+        };
+        fs::create_dir_all(library.originals())?;
+        Ok(library)
+    }
+
+    /// The directory originals are downloaded to, which their files are found under.
+    pub fn originals(&self) -> PathBuf {
+        self.session_dir.join(ORIGINALS)
+    }
+
+    /// Where the original downloaded to `path`, under [`Self::originals`], is archived: by the
+    /// month it was taken, as it was dated when downloaded, under its own name.
+    pub fn archived_as(&self, path: &Path) -> Option<PathBuf> {
+        let modified = fs::metadata(self.originals().join(path))
+            .and_then(|metadata| metadata.modified())
+            .ok()?;
+        let taken = DateTime::<Local>::from(modified);
+        Some(bydate::dated_path(
+            path,
+            (taken.year(), taken.month()),
+            |_| false,
+        ))
+    }
+}
+
+/// Downloads the originals of `library` not yet downloaded into `ctx`'s in directory, a page of
+/// the listing at a time, handing each page's downloads to `sync` before going on, and returns how
+/// many were. A dry run only counts them.
+pub fn download_new(
+    ctx: &SyncContext,
+    library: &ICloudLibrary,
+    mut sync: impl FnMut(&[PathBuf]) -> Result<()>,
+) -> Result<u64> {
+    let namespace = &ctx.source.namespace;
+    let mut client = Client::load(&library.session_dir)?;
+    client.resume()?;
+    let partial_dir = library.session_dir.join(PARTIAL);
+    fs::create_dir_all(&partial_dir)?;
+    let downloaded_before = ctx.store.icloud_assets(namespace)?;
+    let mut downloaded = 0;
+    let mut failed = 0;
+    let mut offset = 0;
+    loop {
+        let (assets, listed) = timed_phase(ctx, "downloading", || client.list(offset))?;
+        if listed == 0 {
+            break;
+        }
+        offset += listed;
+        let mut page = Vec::new();
+        for asset in assets {
+            if downloaded_before.contains(&asset.id) {
+                continue;
+            }
+            ctx.pause.wait_if_paused()?;
+            if !ctx.mode.is_live() {
+                downloaded += 1;
+                continue;
+            }
+            let result = timed_phase(ctx, "downloading", || {
+                download_asset(ctx, &client, library, &partial_dir, &asset)
+            });
+            match result {
+                Ok(original) => {
+                    downloaded += 1;
+                    page.push(original);
+                }
+                Err(e) => {
+                    warn!("could not download {:?}: {e:#}", asset.name);
+                    let kind = FailureKind::of(&e);
+                    ctx.stats.files_failed.fetch_add(1);
+                    ctx.stats.record_failures(kind, 1);
+                    ctx.store.record_run_failures(ctx.run, kind, 1)?;
+                    failed += 1;
+                }
+            }
+        }
+        if !page.is_empty() {
+            sync(&page)?;
+        }
+    }
+    if ctx.mode.is_live() {
+        info!("downloaded {downloaded} originals from iCloud Photos");
+    } else {
+        info!("dry run: would have downloaded {downloaded} originals from iCloud Photos");
+    }
+    if failed > 0 {
+        warn!("{failed} originals could not be downloaded, and will be tried again next run");
+    }
+    Ok(downloaded)
+}
+
+/// Downloads `asset` into `library`'s originals, by way of `partial_dir`, and catalogues it as
+/// downloaded, returning where it was downloaded to.
+fn download_asset(
+    ctx: &SyncContext,
+    client: &Client,
+    library: &ICloudLibrary,
+    partial_dir: &Path,
+    asset: &Asset,
+) -> Result<PathBuf> {
+    let mut staged = NamedTempFile::new_in(partial_dir)?;
+    let mut writer = DigestWriter::with_algorithm(staged.as_file_mut(), ctx.args.hash_algo);
+    client.fetch(&asset.url, &mut writer)?;
+    let digest = writer.finalise()?;
+    let size = staged.as_file().metadata()?.len();
+    ensure!(
+        size == asset.size,
+        "{:?} was {size} bytes rather than {}",
+        asset.name,
+        asset.size
+    );
+    staged.as_file().set_modified(asset.taken)?;
+    staged.as_file().sync_all()?;
+    // under its ID, so two assets with the same name are two source files.
+    let path = Path::new(&asset.id).join(&asset.name);
+    let original = library.originals().join(&path);
+    fs::create_dir_all(library.originals().join(&asset.id))?;
+    staged
+        .persist(&original)
+        .wrap_err_with(|| format!("could not move {:?} into place", asset.name))?;
+    ctx.store
+        .record_icloud_asset(&ctx.source.namespace, &asset.id, &path, &digest)?;
+    debug!("downloaded {:?} to {original:?}", asset.name);
+    Ok(original)
+}
+
+impl Client {
+    /// The session kept in `dir`, for `apple_id`, starting afresh if it was another account's.
+    fn open(dir: &Path, apple_id: &str) -> Result<Self> {
+        let mut client = Self::load(dir)?;
+        // another account's session is no use.
+        if client.session.apple_id != apple_id {
+            client.session = Session {
+                apple_id: apple_id.to_string(),
+                ..Session::default()
+            };
+            // emptied rather than removed, so it stays private.
+            fs::write(dir.join("cookies.txt"), "")?;
+        }
+        Ok(client)
+    }
+
+    /// The session kept in `dir`, whichever account's it is.
+    fn load(dir: &Path) -> Result<Self> {
+        let session = match fs::read(dir.join(SESSION)) {
+            Ok(json) => serde_json::from_slice(&json)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Session::default(),
+            Err(e) => return Err(e.into()),
+        };
+        // curl writes the cookies, which are as good as the password, as it finds the file.
+        let mut cookies = File::options();
+        cookies.append(true).create(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut cookies, 0o600);
+        cookies.open(dir.join("cookies.txt"))?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            session,
+        })
+    }
+
+    /// Writes the session out, by way of a temporary file, which is only ever readable by its
+    /// owner.
+    fn save(&self) -> Result<()> {
+        let mut staged = NamedTempFile::new_in(&self.dir)?;
+        staged.write_all(&serde_json::to_vec_pretty(&self.session)?)?;
+        staged.persist(self.dir.join(SESSION))?;
+        Ok(())
+    }
+
+    /// Signs in with `password`, asking for a two-factor code unless this machine is trusted.
+    fn login(&mut self, password: &str) -> Result<()> {
+        let body = json!({
+            "accountName": self.session.apple_id,
+            "password": password,
+            "rememberMe": true,
+            "trustTokens": self.session.trust_token.iter().collect::<Vec<_>>(),
+        });
+        let response = self.auth_request(
+            "POST",
+            &format!("{AUTH_ENDPOINT}/signin?isRememberMeEnabled=true"),
+            Some(&body),
+        )?;
+        match response.status {
+            200 => {}
+            // a two-factor code is needed.
+            409 => self.verify()?,
+            401 | 403 => bail!("iCloud rejected the Apple ID or password"),
+            status => bail!("signing in failed with {status}: {}", response.body.trim()),
+        }
+        self.account_login()
+    }
+
+    /// Asks for the code sent to a trusted device, and has this machine trusted from then on.
+    fn verify(&mut self) -> Result<()> {
+        ensure!(
+            io::stdin().is_terminal(),
+            "a two-factor code is needed, and stdin isn't a terminal to ask for one; run `icloud \
+             login` interactively"
+        );
+        print!("two-factor code: ");
+        io::stdout().flush()?;
+        let mut code = String::new();
+        io::stdin().lock().read_line(&mut code)?;
+        let body = json!({ "securityCode": { "code": code.trim() } });
+        let response = self.auth_request(
+            "POST",
+            &format!("{AUTH_ENDPOINT}/verify/trusteddevice/securitycode"),
+            Some(&body),
+        )?;
+        ensure!(
+            matches!(response.status, 200 | 204),
+            "the two-factor code was rejected ({})",
+            response.status
+        );
+        let response = self.auth_request("GET", &format!("{AUTH_ENDPOINT}/2sv/trust"), None)?;
+        ensure!(
+            matches!(response.status, 200 | 204),
+            "could not have this machine trusted ({})",
+            response.status
+        );
+        Ok(())
+    }
+
+    /// Exchanges the session token sign in gave for a session with iCloud's services.
+    fn account_login(&mut self) -> Result<()> {
+        let session_token = (self.session.session_token.clone())
+            .ok_or_else(|| eyre!("signing in gave no session token"))?;
+        let body = json!({
+            "accountCountryCode": self.session.account_country,
+            "dsWebAuthToken": session_token,
+            "extended_login": true,
+            "trustToken": self.session.trust_token.clone().unwrap_or_default(),
+        });
+        let response = self.request(
+            "POST",
+            &format!("{SETUP_ENDPOINT}/accountLogin"),
+            &[],
+            Some(&body),
+        )?;
+        ensure!(
+            response.status == 200,
+            "iCloud turned down the session ({}): {}",
+            response.status,
+            response.body.trim()
+        );
+        self.account(&response.json()?)
+    }
+
+    /// Picks up the session `login` left, renewing it with the session token if it has expired.
+    fn resume(&mut self) -> Result<()> {
+        let response = self.request("POST", &format!("{SETUP_ENDPOINT}/validate"), &[], None)?;
+        if response.status == 200 {
+            return self.account(&response.json()?);
+        }
+        if self.session.session_token.is_some() && self.account_login().is_ok() {
+            return Ok(());
+        }
+        bail!("the iCloud session has expired; sign in again with `icloud login`")
+    }
+
+    /// Keeps what an account's details say of where its library is.
+    fn account(&mut self, account: &Value) -> Result<()> {
+        let url = account["webservices"]["ckdatabasews"]["url"]
+            .as_str()
+            .ok_or_else(|| eyre!("the account has no iCloud Photos service"))?;
+        self.session.ckdatabase_url = Some(url.to_string());
+        self.session.dsid = account["dsInfo"]["dsid"].as_str().map(String::from);
+        self.save()
+    }
+
+    /// Lists the records at `offset` in the library, by date taken, returning the originals among
+    /// them and how many assets were listed.
+    fn list(&self, offset: usize) -> Result<(Vec<Asset>, usize)> {
+        let url = self
+            .session
+            .ckdatabase_url
+            .as_deref()
+            .ok_or_else(|| eyre!("not signed in; run `icloud login`"))?;
+        let url = format!(
+            "{url}/database/1/com.apple.photos.cloud/production/private/records/query?\
+             remapEnums=true&dsid={}",
+            self.session.dsid.as_deref().unwrap_or_default()
+        );
+        let body = json!({
+            "query": {
+                "recordType": "CPLAssetAndMasterByAssetDateWithoutHiddenOrDeleted",
+                "filterBy": [
+                    {
+                        "fieldName": "startRank",
+                        "fieldValue": { "type": "INT64", "value": offset },
+                        "comparator": "EQUALS",
+                    },
+                    {
+                        "fieldName": "direction",
+                        "fieldValue": { "type": "STRING", "value": "ASCENDING" },
+                        "comparator": "EQUALS",
+                    },
+                ],
+            },
+            "resultsLimit": PAGE_SIZE * 2,
+            "zoneID": { "zoneName": "PrimarySync" },
+        });
+        let response = self.request("POST", &url, &[], Some(&body))?;
+        ensure!(
+            response.status == 200,
+            "listing the library failed with {}: {}",
+            response.status,
+            response.body.trim()
+        );
+        parse_records(&response.json()?)
+    }
+
+    /// Streams the content at `url` into `writer`.
+    fn fetch(&self, url: &str, writer: &mut impl Write) -> Result<()> {
+        let mut child = Self::spawn(
+            self.curl().stdout(Stdio::piped()).stderr(Stdio::piped()),
+            url,
+            &[],
+            None,
+        )?;
+        let mut stdout = child.stdout.take().expect("stdout is piped");
+        let copied = copy::copy(&mut stdout, writer, 1024 * 1024);
+        drop(stdout);
+        let output = child.wait_with_output()?;
+        ensure!(
+            output.status.success(),
+            "could not download {url}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        copied?;
+        Ok(())
+    }
+
+    /// A request to Apple's sign in, which carries its own headers in both directions.
+    fn auth_request(&mut self, method: &str, url: &str, body: Option<&Value>) -> Result<Response> {
+        let mut headers = vec![
+            (
+                "X-Apple-OAuth-Client-Id".to_string(),
+                WIDGET_KEY.to_string(),
+            ),
+            ("X-Apple-OAuth-Client-Type".into(), "firstPartyAuth".into()),
+            (
+                "X-Apple-OAuth-Redirect-URI".into(),
+                "https://www.icloud.com".into(),
+            ),
+            ("X-Apple-OAuth-Require-Grant-Code".into(), "true".into()),
+            ("X-Apple-OAuth-Response-Mode".into(), "web_message".into()),
+            ("X-Apple-OAuth-Response-Type".into(), "code".into()),
+            ("X-Apple-OAuth-State".into(), WIDGET_KEY.into()),
+            ("X-Apple-Widget-Key".into(), WIDGET_KEY.into()),
+        ];
+        if let Some(scnt) = &self.session.scnt {
+            headers.push(("scnt".into(), scnt.clone()));
+        }
+        if let Some(session_id) = &self.session.session_id {
+            headers.push(("X-Apple-ID-Session-Id".into(), session_id.clone()));
+        }
+        let response = self.request(method, url, &headers, body)?;
+        let keep = |header, value: &mut Option<String>| {
+            if let Some(header) = response.header(header) {
+                *value = Some(header.to_string());
+            }
+        };
+        keep("X-Apple-ID-Session-Id", &mut self.session.session_id);
+        keep("X-Apple-Session-Token", &mut self.session.session_token);
+        keep("scnt", &mut self.session.scnt);
+        keep(
+            "X-Apple-ID-Account-Country",
+            &mut self.session.account_country,
+        );
+        keep("X-Apple-TwoSV-Trust-Token", &mut self.session.trust_token);
+        self.save()?;
+        Ok(response)
+    }
+
+    fn request(
+        &self,
+        method: &str,
+        url: &str,
+        headers: &[(String, String)],
+        body: Option<&Value>,
+    ) -> Result<Response> {
+        let dumped = NamedTempFile::new_in(&self.dir)?;
+        let mut command = self.curl();
+        command
+            .args(["--request", method, "--write-out", "\n%{http_code}"])
+            .arg("--dump-header")
+            .arg(dumped.path())
+            .args(["--header", "Content-Type: application/json"])
+            .args(["--header", "Accept: application/json"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let child = Self::spawn(&mut command, url, headers, body)?;
+        let output = child.wait_with_output()?;
+        ensure!(
+            output.status.success(),
+            "curl could not reach {url}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        let output = String::from_utf8_lossy(&output.stdout);
+        let (body, status) = output.rsplit_once('\n').unwrap_or(("", &output));
+        let status = status
+            .trim()
+            .parse()
+            .map_err(|_| eyre!("curl gave no status for {url}"))?;
+        let headers = fs::read_to_string(dumped.path())?
+            .lines()
+            .filter_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                Some((name.trim().to_string(), value.trim().to_string()))
+            })
+            .collect();
+        debug!("{method} {url}: {status}");
+        Ok(Response {
+            status,
+            headers,
+            body: body.to_string(),
+        })
+    }
+
+    /// A curl command carrying the session's cookies, and keeping any it's given, which is told
+    /// the rest of the request by [`Self::spawn`].
+    fn curl(&self) -> Command {
+        let cookies = self.dir.join("cookies.txt");
+        let mut command = Command::new("curl");
+        command
+            .args(["--silent", "--show-error", "--location"])
+            .arg("--cookie")
+            .arg(&cookies)
+            .arg("--cookie-jar")
+            .arg(&cookies)
+            .args(["--header", "Origin: https://www.icloud.com"])
+            .args(["--header", "Referer: https://www.icloud.com/"])
+            .args(["--config", "-"])
+            .stdin(Stdio::piped());
+        command
+    }
+
+    /// Starts `command`, one of [`Self::curl`]'s, requesting `url` with `headers` and `body`. They
+    /// carry the session, so are given in a config on its standard input rather than its command
+    /// line, where other users could see them.
+    fn spawn(
+        command: &mut Command,
+        url: &str,
+        headers: &[(String, String)],
+        body: Option<&Value>,
+    ) -> Result<Child> {
+        let mut config = format!("url = {}\n", config_string(url));
+        for (name, value) in headers {
+            let header = config_string(&format!("{name}: {value}"));
+            config.push_str(&format!("header = {header}\n"));
+        }
+        if let Some(body) = body {
+            config.push_str(&format!(
+                "data-raw = {}\n",
+                config_string(&body.to_string())
+            ));
+        }
+        let mut child = command.spawn().wrap_err("could not run curl")?;
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        stdin.write_all(config.as_bytes())?;
+        drop(stdin);
+        Ok(child)
+    }
+}
+
+/// The originals among a page of `records`, each an asset followed by the master record of its
+/// original, and how many assets there were.
+fn parse_records(records: &Value) -> Result<(Vec<Asset>, usize)> {
+    let records = records["records"]
+        .as_array()
+        .ok_or_else(|| eyre!("the library listing had no records"))?;
+    let masters: Vec<&Value> = records
+        .iter()
+        .filter(|record| record["recordType"] == "CPLMaster")
+        .collect();
+    let mut assets = Vec::new();
+    let mut listed = 0;
+    for record in records
+        .iter()
+        .filter(|record| record["recordType"] == "CPLAsset")
+    {
+        listed += 1;
+        let fields = &record["fields"];
+        let Some(master_id) = fields["masterRef"]["value"]["recordName"].as_str() else {
+            continue;
+        };
+        let Some(master) = masters
+            .iter()
+            .find(|master| master["recordName"] == master_id)
+        else {
+            warn!(
+                "the asset {:?} has no original listed",
+                record["recordName"]
+            );
+            continue;
+        };
+        let master = &master["fields"];
+        let original = &master["resOriginalRes"]["value"];
+        let (Some(url), Some(size)) = (original["downloadURL"].as_str(), original["size"].as_u64())
+        else {
+            warn!("the original {master_id:?} can't be downloaded");
+            continue;
+        };
+        let name = master["filenameEnc"]["value"]
+            .as_str()
+            .and_then(|encoded| BASE64_STANDARD.decode(encoded).ok())
+            .and_then(|name| String::from_utf8(name).ok())
+            // never a path, which could escape its dated folder.
+            .filter(|name| !name.is_empty() && !name.contains('/') && name != "." && name != "..")
+            .unwrap_or_else(|| master_id.to_string());
+        let taken = fields["assetDate"]["value"]
+            .as_u64()
+            .map(|millis| SystemTime::UNIX_EPOCH + Duration::from_millis(millis))
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        assets.push(Asset {
+            id: master_id.to_string(),
+            name,
+            size,
+            taken,
+            url: url.to_string(),
+        });
+    }
+    Ok((assets, listed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn sessions_are_only_readable_by_their_owner() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let client = Client::open(dir.path(), "someone@example.com").unwrap();
+        client.save().unwrap();
+        for name in [SESSION, "cookies.txt"] {
+            let mode = fs::metadata(dir.path().join(name))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600, "{name}");
+        }
+    }
+
+    #[test]
+    fn assets_are_paired_with_their_originals() {
+        let records = json!({
+            "records": [
+                {
+                    "recordName": "asset-1",
+                    "recordType": "CPLAsset",
+                    "fields": {
+                        "masterRef": { "value": { "recordName": "master-1" } },
+                        "assetDate": { "value": 1_689_400_000_000u64 },
+                    },
+                },
+                {
+                    "recordName": "master-1",
+                    "recordType": "CPLMaster",
+                    "fields": {
+                        "filenameEnc": { "value": BASE64_STANDARD.encode("IMG_0001.HEIC") },
+                        "resOriginalRes": {
+                            "value": { "downloadURL": "https://example.com/1", "size": 1234 },
+                        },
+                    },
+                },
+                {
+                    "recordName": "asset-2",
+                    "recordType": "CPLAsset",
+                    "fields": {
+                        "masterRef": { "value": { "recordName": "master-2" } },
+                    },
+                },
+                {
+                    "recordName": "master-2",
+                    "recordType": "CPLMaster",
+                    "fields": {
+                        "filenameEnc": { "value": BASE64_STANDARD.encode("../escape.jpg") },
+                        "resOriginalRes": {
+                            "value": { "downloadURL": "https://example.com/2", "size": 1 },
+                        },
+                    },
+                },
+                {
+                    "recordName": "asset-3",
+                    "recordType": "CPLAsset",
+                    "fields": {
+                        "masterRef": { "value": { "recordName": "master-3" } },
+                    },
+                },
+                {
+                    "recordName": "master-3",
+                    "recordType": "CPLMaster",
+                    "fields": {
+                        "filenameEnc": { "value": BASE64_STANDARD.encode(".") },
+                        "resOriginalRes": {
+                            "value": { "downloadURL": "https://example.com/3", "size": 2 },
+                        },
+                    },
+                },
+            ],
+        });
+        let (assets, listed) = parse_records(&records).unwrap();
+        assert_eq!(listed, 3);
+        assert_eq!(
+            assets,
+            [
+                Asset {
+                    id: "master-1".into(),
+                    name: "IMG_0001.HEIC".into(),
+                    size: 1234,
+                    taken: SystemTime::UNIX_EPOCH + Duration::from_secs(1_689_400_000),
+                    url: "https://example.com/1".into(),
+                },
+                Asset {
+                    id: "master-2".into(),
+                    name: "master-2".into(),
+                    size: 1,
+                    taken: SystemTime::UNIX_EPOCH,
+                    url: "https://example.com/2".into(),
+                },
+                Asset {
+                    id: "master-3".into(),
+                    name: "master-3".into(),
+                    size: 2,
+                    taken: SystemTime::UNIX_EPOCH,
+                    url: "https://example.com/3".into(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn downloads_are_archived_by_when_they_were_taken() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join(SESSION), "{}").unwrap();
+        assert!(is_session_dir(dir.path()));
+        let library = ICloudLibrary::open(dir.path()).unwrap();
+        let path = Path::new("master-1/IMG_0001.HEIC");
+        fs::create_dir(library.originals().join("master-1")).unwrap();
+        let original = File::create(library.originals().join(path)).unwrap();
+        // mid-month, so that it's July in every timezone.
+        (original.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(1_689_400_000)))
+            .unwrap();
+
+        assert_eq!(
+            library.archived_as(path),
+            Some(PathBuf::from("2023/07/IMG_0001.HEIC"))
+        );
+        assert_eq!(library.archived_as(Path::new("master-2/gone.jpg")), None);
+    }
+}
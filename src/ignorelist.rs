@@ -0,0 +1,120 @@
+//! `ignore`: source files never to be transferred, e.g. corrupt camera artifacts or an app's junk
+//! which would otherwise fail and be reported on every run. The list is kept in the catalogue, so
+//! it applies to every machine syncing into it, and holds paths relative to the in directory,
+//! which leave out a directory's files along with it, and globs, which match as `--exclude` does.
+
+use std::{
+    collections::HashSet,
+    path::{Component, Path, PathBuf},
+};
+
+use chrono::{DateTime, Local};
+use eyre::{Result, bail, ensure};
+
+use crate::{
+    IgnoreArgs, IgnoreCommand,
+    filters::Globs,
+    store::{IgnoredPath, PhotoSyncStore},
+};
+
+pub struct IgnoreList {
+    paths: HashSet<PathBuf>,
+    globs: Globs,
+}
+
+impl IgnoreList {
+    pub fn new(ignored: &[IgnoredPath]) -> Result<Self> {
+        let (globs, paths): (Vec<_>, Vec<_>) = ignored.iter().partition(|ignored| ignored.glob);
+        let globs: Vec<String> = globs
+            .iter()
+            .map(|ignored| ignored.path.to_string_lossy().into_owned())
+            .collect();
+        Ok(Self {
+            paths: paths
+                .into_iter()
+                .map(|ignored| ignored.path.clone())
+                .collect(),
+            globs: Globs::new(globs.iter().map(String::as_str))?,
+        })
+    }
+
+    /// Whether `path`, relative to the in directory, is never to be transferred.
+    pub fn is_ignored(&self, path: &Path) -> bool {
+        path.ancestors().any(|p| self.paths.contains(p)) || self.globs.matches(path)
+    }
+}
+
+pub fn ignore(args: IgnoreArgs) -> Result<()> {
+    let store = PhotoSyncStore::new(args.database_file)?;
+    match args.command {
+        IgnoreCommand::Add { path, glob } => {
+            if glob {
+                // checked before it's stored, as every sync would otherwise fail on it.
+                Globs::new([path.to_string_lossy().as_ref()])?;
+            } else {
+                check_relative(&path)?;
+            }
+            if store.add_ignored_path(&path, glob)? {
+                println!("{path:?} will never be transferred");
+            } else {
+                println!("{path:?} was already ignored");
+            }
+        }
+        IgnoreCommand::List => {
+            for ignored in store.ignored_paths()? {
+                let added = DateTime::<Local>::from(ignored.added_at).format("%Y-%m-%d");
+                let kind = if ignored.glob { "glob" } else { "path" };
+                println!("{added}  {kind}  {}", ignored.path.display());
+            }
+        }
+        IgnoreCommand::Remove { path } => {
+            ensure!(store.remove_ignored_path(&path)?, "{path:?} isn't ignored");
+            println!("{path:?} will be transferred again");
+        }
+    }
+    Ok(())
+}
+
+/// Fails unless `path` is relative to the in directory, as the catalogue's paths are.
+fn check_relative(path: &Path) -> Result<()> {
+    let relative = path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    if !relative {
+        bail!("{path:?} must be relative to the in directory, e.g. `2019/IMG_0001.JPG`");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+
+    #[test]
+    fn paths_ignore_what_is_under_them_and_globs_match_as_excludes_do() {
+        let ignored = |path: &str, glob| IgnoredPath {
+            path: PathBuf::from(path),
+            glob,
+            added_at: SystemTime::UNIX_EPOCH,
+        };
+        let list = IgnoreList::new(&[
+            ignored("2019/broken.jpg", false),
+            ignored("Junk", false),
+            ignored("*.tmp", true),
+            ignored("/2020/*.aae", true),
+        ])
+        .unwrap();
+        assert!(list.is_ignored(Path::new("2019/broken.jpg")));
+        assert!(!list.is_ignored(Path::new("2019/broken.jpg.jpg")));
+        assert!(list.is_ignored(Path::new("Junk/IMG_0001.JPG")));
+        assert!(!list.is_ignored(Path::new("2019/Junk/IMG_0001.JPG")));
+        assert!(list.is_ignored(Path::new("2021/deep/upload.TMP")));
+        assert!(list.is_ignored(Path::new("2020/IMG_0001.AAE")));
+        assert!(!list.is_ignored(Path::new("2021/IMG_0001.AAE")));
+        assert!(check_relative(Path::new("2019/IMG_0001.JPG")).is_ok());
+        assert!(check_relative(Path::new("/photos/2019/IMG_0001.JPG")).is_err());
+        assert!(check_relative(Path::new("../2019/IMG_0001.JPG")).is_err());
+    }
+}
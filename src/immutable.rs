@@ -0,0 +1,101 @@
+//! Marking archived files immutable, so nothing (including a buggy future run) can change them in
+//! place. The flag is `chattr +i` on Linux, which needs CAP_LINUX_IMMUTABLE, and `chflags uchg` on
+//! macOS.
+
+use std::path::Path;
+
+use eyre::Result;
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+use eyre::bail;
+use walkdir::WalkDir;
+
+use crate::chunks;
+
+/// The inode flag behind `chattr +i`, which libc doesn't define.
+#[cfg(target_os = "linux")]
+const FS_IMMUTABLE_FL: libc::c_int = 0x10;
+
+#[cfg(target_os = "linux")]
+pub fn set_immutable(path: &Path, immutable: bool) -> Result<()> {
+    use std::{fs::File, io, os::fd::AsRawFd};
+
+    let file = File::open(path)?;
+    let mut flags: libc::c_int = 0;
+    // SAFETY: the descriptor is open for the duration, and the flags are a C int to the kernel.
+    if unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_GETFLAGS, &mut flags) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let updated = if immutable {
+        flags | FS_IMMUTABLE_FL
+    } else {
+        flags & !FS_IMMUTABLE_FL
+    };
+    if updated != flags
+        && unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_SETFLAGS, &updated) } != 0
+    {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+pub fn set_immutable(path: &Path, immutable: bool) -> Result<()> {
+    use std::{ffi::CString, io, os::macos::fs::MetadataExt, os::unix::ffi::OsStrExt};
+
+    let flags = path.symlink_metadata()?.st_flags();
+    let updated = if immutable {
+        flags | libc::UF_IMMUTABLE
+    } else {
+        flags & !libc::UF_IMMUTABLE
+    };
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    // SAFETY: `c_path` is NUL terminated.
+    if updated != flags && unsafe { libc::chflags(c_path.as_ptr(), updated) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn set_immutable(_path: &Path, _immutable: bool) -> Result<()> {
+    bail!("marking files immutable isn't supported on this platform")
+}
+
+/// Clears the immutable flag from every file under `dir`, returning how many files there were.
+pub fn unlock(dir: &Path) -> Result<u64> {
+    let mut unlocked = 0;
+    // chunk packs are appended to, so were never marked.
+    for entry in WalkDir::new(dir)
+        .into_iter()
+        .filter_entry(|entry| !chunks::is_repository(entry))
+    {
+        let entry = entry?;
+        if entry.file_type().is_file() {
+            set_immutable(entry.path(), false)?;
+            unlocked += 1;
+        }
+    }
+    Ok(unlocked)
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn immutable_files_cannot_be_changed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.jpg");
+        fs::write(&path, "photo").unwrap();
+        // unprivileged users, and some filesystems (e.g. tmpfs), can't set the flag.
+        if set_immutable(&path, true).is_err() {
+            return;
+        }
+        assert!(fs::write(&path, "changed").is_err());
+        assert!(fs::remove_file(&path).is_err());
+        assert_eq!(unlock(dir.path()).unwrap(), 1);
+        fs::write(&path, "changed").unwrap();
+    }
+}
@@ -0,0 +1,218 @@
+//! `--incremental-index`, which leaves out of phase 1 the files of directories of the old out
+//! directory which look as they did when their files were last all indexed, rather than statting
+//! and looking up each of the hundreds of thousands of files in an archive on every run. A
+//! directory's signature is taken from the walk, so each of its files is still statted, but not
+//! looked up. A file rewritten in place with the same size, which leaves its directory's signature
+//! as it was, isn't noticed until a run without it.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    iter,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use walkdir::DirEntry;
+
+use crate::store::DirSignature;
+
+/// How long before a walk a directory must have last changed for its signature to be kept, as a
+/// file added within the same tick of a coarse clock wouldn't change it.
+const SETTLE_TIME: Duration = Duration::from_secs(2);
+
+/// The directories met in a walk of the old out directory, and which of them are unchanged.
+pub struct DirSignatures {
+    previous: HashMap<PathBuf, DirSignature>,
+    started: SystemTime,
+    current: Vec<(PathBuf, DirSignature)>,
+    /// The directory the walk is in, and those it's in, innermost last.
+    open: Vec<OpenDir>,
+    /// Entries to be passed on before any more are taken from the walk.
+    ready: VecDeque<walkdir::Result<DirEntry>>,
+}
+
+/// A directory whose entries the walk is still meeting.
+struct OpenDir {
+    path: PathBuf,
+    depth: usize,
+    /// When it last changed, unless that was too soon before the walk to tell, or couldn't be
+    /// told for it or one of its entries.
+    mtime: Option<SystemTime>,
+    entries: u64,
+    size: u64,
+    /// Its files, while it looks as it did, as whether it's unchanged isn't known until the walk
+    /// has met them all.
+    held: Option<Vec<DirEntry>>,
+}
+
+impl DirSignatures {
+    /// For a walk starting at `started`, against the signatures recorded after the last.
+    pub fn new(previous: Vec<(PathBuf, DirSignature)>, started: SystemTime) -> Self {
+        Self {
+            previous: previous.into_iter().collect(),
+            started,
+            current: Vec::new(),
+            open: Vec::new(),
+            ready: VecDeque::new(),
+        }
+    }
+
+    /// The signatures of the directories walked, to be recorded once their files are indexed.
+    pub fn into_signatures(self) -> Vec<(PathBuf, DirSignature)> {
+        self.current
+    }
+
+    /// Takes the signature of the directory of `entry` as it's met, and holds it back if it's a
+    /// file of a directory which may be unchanged.
+    fn meet(&mut self, root: &Path, entry: DirEntry) {
+        self.close(entry.depth());
+        let metadata = entry.metadata().ok();
+        if let Some(dir) = self.open.last_mut() {
+            dir.entries += 1;
+            match &metadata {
+                Some(metadata) if !metadata.is_dir() => dir.size += metadata.len(),
+                Some(_) => {}
+                None => {
+                    dir.mtime = None;
+                    self.ready
+                        .extend(dir.held.take().into_iter().flatten().map(Ok));
+                }
+            }
+        }
+        let Ok(path) = entry.path().strip_prefix(root) else {
+            self.ready.push_back(Ok(entry));
+            return;
+        };
+        if !entry.file_type().is_dir() {
+            match self.open.last_mut().and_then(|dir| dir.held.as_mut()) {
+                Some(held) => held.push(entry),
+                None => self.ready.push_back(Ok(entry)),
+            }
+            return;
+        }
+        let mtime = metadata.and_then(|metadata| settled_mtime(&metadata, self.started));
+        let looks_unchanged =
+            mtime.is_some() && self.previous.get(path).map(|signature| signature.mtime) == mtime;
+        self.open.push(OpenDir {
+            path: path.to_path_buf(),
+            depth: entry.depth(),
+            mtime,
+            entries: 0,
+            size: 0,
+            held: looks_unchanged.then(Vec::new),
+        });
+        self.ready.push_back(Ok(entry));
+    }
+
+    /// Signs the directories the walk has left, now it's at `depth`, passing on the files held
+    /// back of those which changed.
+    fn close(&mut self, depth: usize) {
+        while self.open.last().is_some_and(|dir| dir.depth >= depth) {
+            let dir = self.open.pop().unwrap();
+            let signature = dir.mtime.map(|mtime| DirSignature {
+                mtime,
+                entries: dir.entries,
+                size: dir.size,
+            });
+            if signature.is_none() || self.previous.get(&dir.path) != signature.as_ref() {
+                self.ready.extend(dir.held.into_iter().flatten().map(Ok));
+            }
+            if let Some(signature) = signature {
+                self.current.push((dir.path, signature));
+            }
+        }
+    }
+}
+
+/// The entries of `walk` of the directory `root`, but with `signatures` the files of directories
+/// unchanged since they were signed. Directories must be met before what's in them.
+pub fn leave_out_unchanged<'a>(
+    mut signatures: Option<&'a mut DirSignatures>,
+    root: &'a Path,
+    mut walk: impl Iterator<Item = walkdir::Result<DirEntry>> + 'a,
+) -> impl Iterator<Item = walkdir::Result<DirEntry>> + 'a {
+    iter::from_fn(move || {
+        let Some(signatures) = signatures.as_deref_mut() else {
+            return walk.next();
+        };
+        loop {
+            if let Some(entry) = signatures.ready.pop_front() {
+                return Some(entry);
+            }
+            match walk.next() {
+                Some(Ok(entry)) => signatures.meet(root, entry),
+                Some(Err(e)) => return Some(Err(e)),
+                None if signatures.open.is_empty() => return None,
+                None => signatures.close(0),
+            }
+        }
+    })
+}
+
+/// When the directory with `metadata` last changed, unless it was too soon before `started` to
+/// tell.
+fn settled_mtime(metadata: &std::fs::Metadata, started: SystemTime) -> Option<SystemTime> {
+    let mtime = metadata.modified().ok()?;
+    if started.duration_since(mtime).unwrap_or_default() < SETTLE_TIME {
+        return None;
+    }
+    // the catalogue keeps whole seconds, so they're all that's compared.
+    let since_epoch = mtime.duration_since(SystemTime::UNIX_EPOCH).ok()?;
+    Some(SystemTime::UNIX_EPOCH + Duration::from_secs(since_epoch.as_secs()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use walkdir::WalkDir;
+
+    use super::*;
+    use crate::store::PhotoSyncStore;
+
+    #[test]
+    fn files_of_unchanged_directories_are_left_out() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("2019")).unwrap();
+        fs::write(dir.path().join("2019/a.jpg"), b"alpha").unwrap();
+        let later = SystemTime::now() + Duration::from_secs(60);
+        let walk = |signatures: &mut DirSignatures| -> Vec<PathBuf> {
+            let entries = WalkDir::new(dir.path()).into_iter();
+            leave_out_unchanged(Some(signatures), dir.path(), entries)
+                .map(|entry| entry.unwrap())
+                .filter(|entry| entry.file_type().is_file())
+                .map(|entry| entry.path().strip_prefix(dir.path()).unwrap().to_owned())
+                .collect()
+        };
+        let a = || vec![PathBuf::from("2019/a.jpg")];
+
+        let mut first = DirSignatures::new(Vec::new(), later);
+        assert_eq!(walk(&mut first), a());
+        let recorded = first.into_signatures();
+        let year = &recorded.iter().find(|(path, _)| path == Path::new("2019"));
+        assert_eq!(year.unwrap().1.entries, 1);
+        assert_eq!(year.unwrap().1.size, 5);
+
+        // as they are after being recorded by one run and read back by the next.
+        let store = PhotoSyncStore::new_for_tests().unwrap();
+        store.replace_dir_signatures(&recorded).unwrap();
+        let recorded = store.dir_signatures().unwrap();
+        let mut second = DirSignatures::new(recorded.clone(), later);
+        assert!(walk(&mut second).is_empty());
+        assert_eq!(second.into_signatures().len(), recorded.len());
+
+        // a file rewritten in place leaves its directory's modification time as it was.
+        fs::write(dir.path().join("2019/a.jpg"), b"alphabet").unwrap();
+        let mut rewritten = DirSignatures::new(recorded.clone(), later);
+        assert_eq!(walk(&mut rewritten), a());
+
+        fs::write(dir.path().join("2019/b.jpg"), b"beta").unwrap();
+        let mut third = DirSignatures::new(recorded, later);
+        assert_eq!(walk(&mut third).len(), 2);
+
+        // a directory which has only just changed could change again unnoticed.
+        let mut racy = DirSignatures::new(Vec::new(), SystemTime::now());
+        assert_eq!(walk(&mut racy).len(), 2);
+        assert!(racy.into_signatures().is_empty());
+    }
+}
@@ -0,0 +1,134 @@
+use std::{
+    io::{self, BufRead, IsTerminal, Write},
+    path::{Path, PathBuf},
+};
+
+use eyre::{Result, bail, ensure};
+
+use crate::{InitArgs, config::write_config, platform::same_filesystem, store::PhotoSyncStore};
+
+/// Checks the directories of a new sync, creates its database and writes its config file.
+pub fn init(args: InitArgs) -> Result<()> {
+    ensure!(
+        args.force || !args.config.exists(),
+        "{:?} already exists, pass --force to replace it",
+        args.config
+    );
+    let interactive = io::stdin().is_terminal();
+
+    let in_dir = existing_dir(args.in_dir, "directory to copy photos from", interactive)?;
+    let old_out_dir = existing_dir(
+        args.old_out_dir,
+        "directory of photos already archived",
+        interactive,
+    )?;
+    let out_dir = existing_dir(args.out_dir, "directory to copy new photos to", interactive)?;
+    let temp_dir = existing_dir(
+        args.temp_dir,
+        "directory to stage copies in, on the same filesystem as the out directory",
+        interactive,
+    )?;
+    // staged copies are renamed into place, which can't cross filesystems.
+    ensure!(
+        same_filesystem(&temp_dir, &out_dir)? != Some(false),
+        "{temp_dir:?} and {out_dir:?} are on different filesystems, so copies couldn't be moved \
+         into place; pick a temp directory on the same filesystem as the out directory"
+    );
+
+    let database_file = match args.database_file {
+        Some(database_file) => database_file,
+        None => prompt("database file to create", interactive)?,
+    };
+    let database_file = std::path::absolute(database_file)?;
+    PhotoSyncStore::new(database_file.clone())?;
+
+    let mut values = vec![
+        ("in_dir", text(&in_dir)?),
+        ("old_out_dir", text(&old_out_dir)?),
+        ("out_dir", text(&out_dir)?),
+        ("temp_dir", text(&temp_dir)?),
+        ("database_file", text(&database_file)?),
+    ];
+    if let Some(machine_id) = args.machine_id {
+        values.push(("machine_id", machine_id));
+    }
+    write_config(&args.config, &values)?;
+
+    println!("created database {database_file:?}");
+    println!("wrote {:?}:", args.config);
+    for (key, value) in &values {
+        println!("  {key} = {value}");
+    }
+    println!("run `sync --config {}` to sync", args.config.display());
+    Ok(())
+}
+
+fn existing_dir(dir: Option<PathBuf>, description: &str, interactive: bool) -> Result<PathBuf> {
+    let dir = match dir {
+        Some(dir) => dir,
+        None => prompt(description, interactive)?,
+    };
+    ensure!(dir.is_dir(), "{dir:?} is not a directory");
+    Ok(dir.canonicalize()?)
+}
+
+fn prompt(description: &str, interactive: bool) -> Result<PathBuf> {
+    if !interactive {
+        bail!("no {description} given, and stdin isn't a terminal to ask for one");
+    }
+    print!("{description}: ");
+    io::stdout().flush()?;
+    let mut line = String::new();
+    io::stdin().lock().read_line(&mut line)?;
+    let line = line.trim();
+    ensure!(!line.is_empty(), "no {description} given");
+    Ok(line.into())
+}
+
+fn text(path: &Path) -> Result<String> {
+    match path.to_str() {
+        Some(text) => Ok(text.to_string()),
+        None => bail!("{path:?} is not UTF-8, so can't be written to a config file"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::config_args;
+
+    #[test]
+    fn init_writes_a_usable_config() {
+        let dir = tempfile::tempdir().unwrap();
+        for sub in ["in", "old", "out", "tmp"] {
+            std::fs::create_dir(dir.path().join(sub)).unwrap();
+        }
+        let config = dir.path().join("sync.toml");
+        let args = || InitArgs {
+            config: config.clone(),
+            force: false,
+            in_dir: Some(dir.path().join("in")),
+            out_dir: Some(dir.path().join("out")),
+            old_out_dir: Some(dir.path().join("old")),
+            temp_dir: Some(dir.path().join("tmp")),
+            database_file: Some(dir.path().join("db.sqlite")),
+            machine_id: None,
+        };
+        init(args()).unwrap();
+        assert!(dir.path().join("db.sqlite").exists());
+        let root = dir.path().canonicalize().unwrap();
+        assert!(
+            config_args(&config)
+                .unwrap()
+                .contains(&format!("--in-dir={}", root.join("in").display()))
+        );
+
+        // an existing setup is only replaced when asked.
+        assert!(init(args()).is_err());
+        init(InitArgs {
+            force: true,
+            ..args()
+        })
+        .unwrap();
+    }
+}
@@ -0,0 +1,195 @@
+//! Running every job defined in a config file, e.g. one per device syncing into one archive, with
+//! a summary of each at the end.
+
+use std::{
+    collections::VecDeque,
+    ffi::OsString,
+    path::Path,
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use eyre::{Result, WrapErr, bail};
+
+use crate::{
+    Cli, Command, RunAllArgs, SyncArgs, cli_command, config::config_jobs, metrics::RunStats,
+    profile::expand_saved_args, run_sync,
+};
+
+struct Job {
+    name: String,
+    args: SyncArgs,
+}
+
+struct Summary {
+    name: String,
+    result: Result<()>,
+    elapsed: Duration,
+    stats: RunStats,
+}
+
+pub fn run_all(args: RunAllArgs) -> Result<()> {
+    let mut jobs = Vec::new();
+    for (name, job_args) in config_jobs(&args.config)? {
+        if args.jobs.is_empty() || args.jobs.contains(&name) {
+            // every job is parsed up front, so that a mistake in one is found before any run.
+            let sync_args = parse_job(&args.config, &name, job_args)?;
+            jobs.push(Job {
+                name,
+                args: sync_args,
+            });
+        }
+    }
+    if let Some(unknown) = args
+        .jobs
+        .iter()
+        .find(|name| !jobs.iter().any(|job| job.name == **name))
+    {
+        bail!("no job named {unknown:?} in {:?}", args.config);
+    }
+    let total = jobs.len();
+
+    let groups = Mutex::new(VecDeque::from(by_catalogue(jobs)));
+    let summaries = Mutex::new(Vec::with_capacity(total));
+    let workers = args.parallel.get().min(groups.lock().unwrap().len());
+    thread::scope(|s| {
+        for _ in 0..workers {
+            s.spawn(|| {
+                while let Some(group) = groups.lock().unwrap().pop_front() {
+                    for job in group {
+                        println!("starting job {:?}", job.name);
+                        let stats = RunStats::default();
+                        let started = Instant::now();
+                        let result = run_sync(&job.args, &stats);
+                        summaries.lock().unwrap().push(Summary {
+                            name: job.name,
+                            result,
+                            elapsed: started.elapsed(),
+                            stats,
+                        });
+                    }
+                }
+            });
+        }
+    });
+
+    let mut summaries = summaries.into_inner().unwrap();
+    summaries.sort_by(|a, b| a.name.cmp(&b.name));
+    println!("job summaries:");
+    for summary in &summaries {
+        let stats = &summary.stats;
+        let done = format!(
+            "{:.1}s, transferred {} files ({}MB), {} failed",
+            summary.elapsed.as_secs_f64(),
+            stats.files_transferred,
+            stats.bytes_transferred.as_u64() / 1_000_000,
+            stats.files_failed
+        );
+        match &summary.result {
+            Ok(()) => println!("    {}: succeeded in {done}", summary.name),
+            Err(e) => println!("    {}: FAILED after {done}: {e}", summary.name),
+        }
+    }
+    let failed = summaries.iter().filter(|s| s.result.is_err()).count();
+    if failed > 0 {
+        bail!("{failed} of {total} jobs failed");
+    }
+    Ok(())
+}
+
+/// The sync arguments of the job `name`: those at the top level of the config file, overridden by
+/// the job's own, and expanded as `sync --config` would be.
+fn parse_job(config: &Path, name: &str, job_args: Vec<String>) -> Result<SyncArgs> {
+    let mut config_arg = OsString::from("--config=");
+    config_arg.push(config);
+    let mut args = vec![OsString::from("icloud-photo-synchroniser"), "sync".into()];
+    args.push(config_arg);
+    // given as if on the command line, so that they take precedence over the top level's.
+    args.extend(job_args.into_iter().map(OsString::from));
+
+    let args = expand_saved_args(args)?;
+    let matches = cli_command(&args)
+        .try_get_matches_from(&args)
+        .wrap_err_with(|| format!("job {name:?} has invalid arguments"))?;
+    match Cli::from_matches(&matches)?.command {
+        Some(Command::Sync(args)) => Ok(args),
+        _ => unreachable!("jobs are parsed as syncs"),
+    }
+}
+
+/// Groups jobs sharing a catalogue, which must run in turn, keeping them in order.
+fn by_catalogue(jobs: Vec<Job>) -> Vec<Vec<Job>> {
+    let mut groups = Vec::<(Option<String>, Vec<Job>)>::new();
+    for job in jobs {
+        let catalogue = catalogue_of(&job.args);
+        match groups
+            .iter_mut()
+            .find(|(c, _)| catalogue.is_some() && *c == catalogue)
+        {
+            Some((_, group)) => group.push(job),
+            None => groups.push((catalogue, vec![job])),
+        }
+    }
+    groups.into_iter().map(|(_, group)| group).collect()
+}
+
+/// Identifies the catalogue a job syncs against, or `None` if it's the job's own.
+fn catalogue_of(args: &SyncArgs) -> Option<String> {
+    if args.ephemeral_db {
+        return None;
+    }
+    match (&args.catalogue_addr, &args.database_file) {
+        (Some(addr), _) => Some(format!("addr:{addr}")),
+        (None, Some(database_file)) => Some(format!("file:{}", database_file.display())),
+        (None, None) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jobs_sharing_a_catalogue_run_in_turn() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("sync.toml");
+        std::fs::write(
+            &config,
+            r#"
+                database_file = "/nas/photos.sqlite"
+                out_dir = "/nas/out"
+                old_out_dir = "/nas/old"
+                temp_dir = "/tmp"
+                [jobs.laptop]
+                in_dir = "/photos"
+                [jobs.phone]
+                in_dir = "/phone"
+                [jobs.scratch]
+                in_dir = "/scratch"
+                ephemeral_db = true
+                [jobs.work]
+                in_dir = "/work"
+                database_file = "/nas/work.sqlite"
+            "#,
+        )
+        .unwrap();
+
+        let jobs = config_jobs(&config)
+            .unwrap()
+            .into_iter()
+            .map(|(name, job_args)| Job {
+                args: parse_job(&config, &name, job_args).unwrap(),
+                name,
+            })
+            .collect();
+        let groups: Vec<Vec<_>> = by_catalogue(jobs)
+            .into_iter()
+            .map(|group| group.into_iter().map(|job| job.name).collect())
+            .collect();
+        assert_eq!(
+            groups,
+            [vec!["laptop", "phone"], vec!["scratch"], vec!["work"]]
+        );
+    }
+}
@@ -0,0 +1,176 @@
+//! Cleaning up after transfers interrupted by the process being killed, which leave their
+//! temporary files behind, and may have written a file to the out directory without cataloguing
+//! it.
+
+use std::{
+    fs, io,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use eyre::{Result, WrapErr};
+
+use crate::{
+    SyncContext,
+    encrypt::is_encrypted,
+    platform::FileInfo,
+    store::{PendingWrite, WasTransferredFromSourceResult},
+};
+use tracing::info;
+
+/// Removes the temporary files of every transfer the catalogue has as under way, and catalogues
+/// what they finished writing.
+pub fn recover_interrupted(ctx: &SyncContext) -> Result<()> {
+    let SyncContext { store, .. } = ctx;
+    for transfer in store.pending_transfers(&ctx.source.namespace)? {
+        match fs::remove_dir_all(&transfer.work_dir) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                return Err(e)
+                    .wrap_err_with(|| format!("could not remove {:?}", transfer.work_dir));
+            }
+            _ => {}
+        }
+        match &transfer.writing {
+            Some(writing) if catalogue_written(ctx, &transfer.path, writing)? => info!(
+                "catalogued {:?}, written by an interrupted transfer of {:?}",
+                writing.target_path, transfer.path
+            ),
+            _ => info!(
+                "cleaned up after an interrupted transfer of {:?}",
+                transfer.path
+            ),
+        }
+        store.forget_pending_transfer(&ctx.source.namespace, &transfer.path)?;
+    }
+    Ok(())
+}
+
+/// Catalogues the file an interrupted transfer of `path` was writing, returning whether it got as
+/// far as writing it.
+fn catalogue_written(ctx: &SyncContext, path: &Path, writing: &PendingWrite) -> Result<bool> {
+    let SyncContext {
+        store,
+        run,
+        backend,
+        ..
+    } = ctx;
+    // files are put in place whole, so one which is there is complete, but it could be another's
+    // which was in the way. encrypted files can't be read back, so are taken on trust.
+    let Some(modified) = backend.modified(&writing.target_path)? else {
+        return Ok(false);
+    };
+    if !is_encrypted(&writing.target_path)
+        && backend.digest(
+            &writing.target_path,
+            writing.transform,
+            writing.digest.algorithm(),
+        )? != writing.digest
+    {
+        return Ok(false);
+    }
+    store.mark_exists_in_target(
+        *run,
+        &writing.target_path,
+        modified,
+        writing.size,
+        &writing.digest,
+    )?;
+    store.record_transform(*run, &writing.target_path, writing.transform)?;
+
+    // the source needn't be copied again, if it hasn't changed since. times are catalogued to
+    // the second.
+    let seconds = |t: SystemTime| t.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).ok();
+    let unchanged = FileInfo::of(&ctx.source.dir.join(path)).is_ok_and(|source| {
+        seconds(source.modified) == seconds(writing.last_modified) && source.size == writing.size
+    });
+    if unchanged
+        && store.was_transferred_from_source(
+            &ctx.source.namespace,
+            path,
+            writing.last_modified,
+            writing.size,
+        )? == WasTransferredFromSourceResult::New
+    {
+        store.mark_transferred_from_source(
+            *run,
+            &ctx.source.namespace,
+            path,
+            &writing.digest,
+            writing.last_modified,
+            writing.size,
+        )?;
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::{
+        SyncEngine,
+        compress::Transform,
+        digest::digest,
+        store::{PendingTransfer, PhotoSyncStore},
+    };
+
+    #[test]
+    fn interrupted_transfers_are_cleaned_up_and_catalogued() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name);
+        for name in ["in", "out", "old", "tmp/work", "tmp/other work"] {
+            fs::create_dir_all(path(name)).unwrap();
+        }
+        fs::write(path("in/written.jpg"), "written photo").unwrap();
+        fs::write(path("in/copying.jpg"), "photo being copied").unwrap();
+        fs::write(path("out/written.jpg"), "written photo").unwrap();
+        fs::write(path("tmp/work/.tmp123"), "photo be").unwrap();
+
+        // as left by a run killed while these were being transferred.
+        let store = PhotoSyncStore::new(path("db.sqlite")).unwrap();
+        let run = store.begin_run("").unwrap();
+        let source = FileInfo::of(&path("in/written.jpg")).unwrap();
+        let pending = [
+            PendingTransfer {
+                path: PathBuf::from("written.jpg"),
+                work_dir: path("tmp/other work"),
+                writing: Some(PendingWrite {
+                    target_path: PathBuf::from("written.jpg"),
+                    digest: digest(&path("in/written.jpg")).unwrap(),
+                    last_modified: source.modified,
+                    size: source.size,
+                    transform: Transform::None,
+                }),
+            },
+            PendingTransfer {
+                path: PathBuf::from("copying.jpg"),
+                work_dir: path("tmp/work"),
+                writing: None,
+            },
+        ];
+        for transfer in &pending {
+            store.journal_transfer(run, "", transfer).unwrap();
+        }
+        drop(store);
+
+        let arg = |name: &str, dir: &str| format!("--{name}={}", path(dir).display());
+        let engine = SyncEngine::from_args([
+            arg("in-dir", "in"),
+            arg("out-dir", "out"),
+            arg("old-out-dir", "old"),
+            arg("temp-dir", "tmp"),
+            arg("database-file", "db.sqlite"),
+            "--include-small-files".into(),
+            "--mark-destination".into(),
+        ])
+        .unwrap();
+        assert!(!path("tmp/work").exists() && !path("tmp/other work").exists());
+        assert_eq!(engine.detect_new().unwrap(), [PathBuf::from("copying.jpg")]);
+        engine.finish().unwrap();
+
+        let store = PhotoSyncStore::new(path("db.sqlite")).unwrap();
+        assert!(store.pending_transfers("").unwrap().is_empty());
+        assert!(store.is_known_target(Path::new("written.jpg")).unwrap());
+    }
+}
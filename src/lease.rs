@@ -0,0 +1,72 @@
+use std::{
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+    time::Duration,
+};
+
+use eyre::Result;
+
+use crate::catalogue::Catalogue;
+use tracing::{debug, warn};
+
+/// The single lease guarding a sync run against the catalogue.
+const SYNC_LEASE: &str = "sync";
+
+/// How long a lease survives without being renewed, e.g. if its holder was killed.
+const LEASE_TTL: Duration = Duration::from_secs(10 * 60);
+
+const LEASE_RENEWAL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Who holds the sync lease for this process, on `machine_id`.
+pub fn sync_lease_holder(machine_id: &str) -> String {
+    format!("{machine_id}#{}", std::process::id())
+}
+
+/// The machine `holder` holds a lease for, whose runs are in its namespace.
+pub fn holder_machine(holder: &str) -> &str {
+    holder
+        .rsplit_once('#')
+        .map_or(holder, |(machine, _)| machine)
+}
+
+/// Releases the sync lease for `--force-unlock` on `machine_id`, if it has expired or is this
+/// machine's own, recording the runs its holder never finished as aborted.
+pub fn break_sync_lease(store: &dyn Catalogue, machine_id: &str) -> Result<()> {
+    let aborted = store.break_lease(SYNC_LEASE, machine_id)?;
+    if aborted > 0 {
+        warn!("recorded {aborted} unfinished runs as aborted");
+    }
+    Ok(())
+}
+
+/// Runs `f` while holding the catalogue's sync lease, so that machines sharing a catalogue take it
+/// in turns to sync. The lease is renewed in the background and released once `f` returns.
+pub fn with_sync_lease<T>(
+    store: &dyn Catalogue,
+    holder: &str,
+    f: impl FnOnce() -> Result<T>,
+) -> Result<T> {
+    store.acquire_lease(SYNC_LEASE, holder, LEASE_TTL)?;
+    debug!("acquired catalogue lease as {holder:?}");
+
+    let result = thread::scope(|s| {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let renewer = s.spawn(move || {
+            loop {
+                match stopped.recv_timeout(LEASE_RENEWAL_INTERVAL) {
+                    Err(RecvTimeoutError::Timeout) => {
+                        store.acquire_lease(SYNC_LEASE, holder, LEASE_TTL)?
+                    }
+                    Ok(()) | Err(RecvTimeoutError::Disconnected) => return Ok::<_, eyre::Error>(()),
+                }
+            }
+        });
+        let result = f();
+        drop(stop);
+        let renewed = renewer.join().expect("lease renewal thread panicked");
+        result.and_then(|r| renewed.map(|()| r))
+    });
+
+    store.release_lease(SYNC_LEASE, holder)?;
+    result
+}
@@ -0,0 +1,3271 @@
+//! Syncing photos from an in directory, e.g. iCloud Photos' downloads, into an archive without
+//! copying anything the archive already holds. The command line is run by [`run_cli`], and a sync
+//! can be driven from other programs with [`SyncEngine`].
+
+use std::{
+    cell::Cell,
+    collections::{BTreeSet, HashMap, HashSet},
+    ffi::OsString,
+    fs::File,
+    io::{self, BufReader, BufWriter},
+    net::SocketAddr,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::{
+        Mutex, OnceLock,
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, SyncSender},
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use age::x25519::Recipient;
+use chrono::Local;
+use clap::{
+    ArgMatches, Args, CommandFactory, FromArgMatches, Parser, Subcommand, error::ErrorKind,
+    parser::ValueSource,
+};
+use eyre::{Result, WrapErr, bail, ensure};
+use rayon::iter::{ParallelBridge, ParallelIterator};
+use tracing::{debug, info, info_span, warn};
+
+use crate::{
+    appledouble::AppleDoublePolicy,
+    backend::{OutUrl, TargetBackend},
+    batch::WriteBatch,
+    cas::{Layout, ViewLink},
+    catalogue::Catalogue,
+    checksums::ChecksumStyle,
+    chunks::ChunkRepository,
+    claims::DigestClaims,
+    classify::{BurstPolicy, Bursts},
+    collision::CollisionPolicy,
+    control::{Control, CurrentFiles, with_control_socket},
+    dbexport::{ExportFormat, export_jsonl, import_jsonl},
+    destination::Destination,
+    digest::{ContentHash, HashAlgorithm},
+    digestcache::DigestCache,
+    failures::FailureKind,
+    fastcopy::CopyStrategy,
+    fdbudget::FdBudget,
+    filters::{FileFilters, LeftOut, PathFilters, SyncScope},
+    hooks::run_hook,
+    icloud::PlaceholderPolicy,
+    ignorelist::IgnoreList,
+    incremental::DirSignatures,
+    lease::with_sync_lease,
+    links::DedupeMode,
+    lock::{LockArgs, LockedPolicy},
+    logging::LogLevel,
+    manifest::{read_manifest, write_manifest, write_manifest_to},
+    metrics::{RunStats, summary_json, with_metrics_endpoint, write_summary_json, write_textfile},
+    mode::ExecutionMode,
+    netdb::NetworkDatabasePolicy,
+    notify::WebhookUrl,
+    order::{NewFile, TransferOrder},
+    partial::PartialDigest,
+    pause::{PauseControl, PauseReason},
+    platform::{FileInfo, special_kind},
+    plugin::{NoPlugin, SyncPlugin, WasmPlugin},
+    profile::{expand_saved_args, validate_profile_args},
+    progress::PhaseProgress,
+    remote::RemoteCatalogue,
+    sau64::SimpleAtomicU64,
+    snapshot::{SnapshotKind, take_snapshot},
+    sources::{InDir, Source, SourceKind},
+    store::{
+        CataloguedFile, ClassifiedOut, PhotoSyncStore, ReviewDecision, RunId, RunStatus,
+        TransferredSource, WasTransferredFromSourceResult,
+    },
+    symlinks::SymlinkPolicy,
+    syncignore::{SYNCIGNORE, SyncIgnores},
+    throttle::{Throttle, ThrottledReader},
+    timing::{FileTimings, Work},
+    transcode::{CommandConverter, SystemConverter, TranscodeTarget, Transcoder},
+    transferlog::TransferLog,
+    tuning::{StoreTuning, Synchronous, TempStore},
+    window::TimeWindow,
+};
+
+/// Prints a line as `std::println!` does, but above any progress bars rather than over them. As
+/// it's defined before the modules, it's the `println!` they all use.
+macro_rules! println {
+    () => {
+        $crate::progress::suspend(|| ::std::println!())
+    };
+    ($($arg:tt)*) => {{
+        let line = format!($($arg)*);
+        $crate::progress::suspend(|| ::std::println!("{line}"))
+    }};
+}
+
+mod adopt;
+mod appledouble;
+mod archiveonly;
+mod backend;
+mod batch;
+mod bundle;
+mod bydate;
+mod cas;
+mod catalogue;
+mod checksums;
+mod chunks;
+mod claims;
+mod classify;
+mod collision;
+mod compare;
+mod compress;
+mod config;
+mod control;
+mod copy;
+mod dbcheck;
+mod dbexport;
+mod dbstats;
+mod dbtrace;
+mod dedupe;
+mod deleted;
+mod destination;
+mod devices;
+mod diff;
+mod digest;
+mod digestcache;
+mod doctor;
+mod duplicates;
+mod encrypt;
+mod engine;
+mod failures;
+mod fastcopy;
+mod fdbudget;
+mod filters;
+mod fsinfo;
+mod hashpending;
+mod history;
+mod hooks;
+mod icloud;
+mod icloudphotos;
+mod ignorelist;
+mod immutable;
+mod incremental;
+mod init;
+mod jobs;
+mod journal;
+mod lease;
+mod links;
+mod livephoto;
+mod lock;
+mod logging;
+mod manifest;
+mod manual;
+mod media;
+mod metadata;
+mod metrics;
+mod migrate;
+mod missing;
+mod mode;
+mod netdb;
+mod niceness;
+mod notify;
+mod order;
+mod orphans;
+mod paranoid;
+mod parity;
+mod partial;
+mod pause;
+mod phash;
+mod photoslibrary;
+mod platform;
+mod plugin;
+mod power;
+mod profile;
+mod progress;
+mod propagate;
+mod prune;
+mod remote;
+mod restore;
+mod review;
+mod samenames;
+mod sau64;
+mod savings;
+mod scrub;
+mod selftest;
+mod sftp;
+mod sidecar;
+mod similar;
+mod snapshot;
+mod sources;
+mod store;
+mod symlinks;
+mod syncignore;
+mod throttle;
+mod timing;
+mod tombstones;
+mod transcode;
+mod transfer;
+mod transferlog;
+mod trash;
+mod tuning;
+mod variants;
+mod verify;
+mod versions;
+mod watch;
+mod window;
+
+pub use engine::{SyncEngine, SyncReport};
+pub use failures::exit_code;
+
+#[derive(Parser, Debug)]
+// later occurrences of an argument override earlier ones, which is how profiles are overridden.
+#[command(
+    args_conflicts_with_subcommands = true,
+    args_override_self = true,
+    after_help = "Arguments can also be set through the PHOTO_SYNC_* environment variables shown \
+    in each command's help. Arguments given on the command line take precedence over those from a \
+    profile, then those from a config file, then environment variables."
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    // syncing is the default when no subcommand is given.
+    #[command(flatten)]
+    sync: Option<SyncArgs>,
+}
+
+// parsed once, so the size of the sync arguments doesn't matter.
+#[allow(clippy::large_enum_variant)]
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Index the old out directory, then copy new files from the in directory to the out directory.
+    Sync(SyncArgs),
+    /// Run each of the jobs in a config file, in turn or a few at a time, and summarise how each
+    /// went.
+    RunAll(RunAllArgs),
+    /// Host a catalogue for clients syncing with `--catalogue-addr`.
+    Serve(ServeArgs),
+    /// Manage named sets of sync arguments stored in the database, used with `sync --profile`.
+    Profile(ProfileArgs),
+    /// Set up a new sync: check its directories, create its database and write a config file for
+    /// `sync --config`. Anything not given is asked for.
+    Init(InitArgs),
+    /// Check the directories and database a sync would use, and warn about anything which would
+    /// make it fail or put the catalogue at risk. Takes the same arguments as `sync`.
+    Doctor(SyncArgs),
+    /// Sync a scratch tree of known files twice, and check the results, to confirm syncing behaves
+    /// on this platform and filesystem.
+    SelfTest(SelfTestArgs),
+    /// Rehash files in the old out directory, those verified longest ago first, and report any
+    /// which are missing or no longer match their catalogued digest.
+    Scrub(ScrubArgs),
+    /// Write XOR parity over files in the old out directory not yet covered by any, in sets of up
+    /// to a given size, so that `scrub --repair` can rebuild any one damaged file in a set.
+    Parity(ParityArgs),
+    /// Pack out directory files into numbered tar bundles of a fixed size for cold storage, e.g.
+    /// BD-R discs or Glacier, recording which bundle holds each file.
+    Bundle(BundleArgs),
+    /// Report files in the out directory the catalogue doesn't know about, e.g. ones copied in by
+    /// hand, or adopt them into the catalogue.
+    Orphans(OrphansArgs),
+    /// Hash the files in the old out directory which `--trust-size-mtime` catalogued without
+    /// hashing, e.g. from a background job.
+    HashPending(HashPendingArgs),
+    /// Report catalogued files which are gone from the out (and old out) directory, telling apart
+    /// those whose content survives elsewhere, and re-transfer any the in directory still has.
+    Missing(MissingArgs),
+    /// Catalogue an existing archive as the out directory, hashing every file in it, so that
+    /// nothing already there is copied again.
+    Adopt(AdoptArgs),
+    /// Replace files in the archive with the same content as another with hardlinks to it, after
+    /// checking their bytes match, and report the space reclaimed.
+    Dedupe(DedupeArgs),
+    /// Report how many transferred files were duplicates, the space that saved, and which folders
+    /// they came from.
+    Savings(SavingsArgs),
+    /// Report the sets of transferred files with the same content as each other or as a file in
+    /// the old out directory, with their paths and sizes and the space not archiving them again
+    /// saved.
+    ReportDuplicates(ReportDuplicatesArgs),
+    /// List the catalogue rows of files gone from the old out or in directory, and with
+    /// `--delete-stale`, remove them, so new files aren't deduplicated against content which is
+    /// no longer there.
+    Prune(PruneArgs),
+    /// List the latest runs and what each did, or with `--run`, the files one added to the
+    /// catalogue.
+    History(HistoryArgs),
+    /// Write a `SHA256SUMS` file of everything catalogued in the out directory, from the
+    /// catalogue rather than hashing it again, for checking the archive with `sha256sum -c` from
+    /// the out directory.
+    Manifest(ChecksumManifestArgs),
+    /// Report files transferred from the in directory which have since been deleted from it, e.g.
+    /// by iCloud's storage optimisation, with their total size.
+    Deleted(DeletedArgs),
+    /// Report archived content with no copy left in the in directory, i.e. the photos which exist
+    /// only in the archive and most need a backup of their own.
+    ArchiveOnly(ArchiveOnlyArgs),
+    /// Report file names used in more than one archive folder for different content, e.g. two
+    /// unrelated IMG_0001.JPG, which are easily confused when browsing by hand.
+    SameNames(SameNamesArgs),
+    /// Report images archived at several resolutions, e.g. an original alongside iCloud's
+    /// "optimised" download, recommending the largest to keep. HEIC images can't be decoded, so
+    /// are left out.
+    Variants(VariantsArgs),
+    /// Report images which look the same but whose bytes differ, e.g. re-saves, iCloud re-encodes
+    /// and burst frames, which exact deduplication keeps every copy of. HEIC images can't be
+    /// decoded, so are left out.
+    FindSimilar(FindSimilarArgs),
+    /// Read the EXIF metadata of archived content into the catalogue, e.g. content archived
+    /// before syncs indexed it.
+    ReindexMetadata(ReindexMetadataArgs),
+    /// List the versions `--keep-versions` archived of a changed file in the in directory.
+    Versions(VersionsArgs),
+    /// Delete old versions archived by `--keep-versions`, by how many are kept and how old they
+    /// are, so that repeatedly edited files don't grow the archive without bound.
+    PruneVersions(PruneVersionsArgs),
+    /// Compare two directory trees by content, listing what is only in one of them and what is in
+    /// both at different paths, e.g. before wiping an old backup disk.
+    Compare(CompareArgs),
+    /// Build a tree of links to the archive mirroring the in directories it was transferred from,
+    /// e.g. to browse an out directory written with `--layout cas`. Each machine's files are under
+    /// a directory named after it.
+    ExportView(ExportViewArgs),
+    /// List the source files which differ between the in directories and the catalogue, i.e. what a
+    /// sync would find new, or between two runs, i.e. what the later runs did, as one JSON object
+    /// per line. `.syncignore` files and `ignore add` are honoured, but not the filters, e.g.
+    /// `--exclude` or `--extensions`, given to a sync.
+    Diff(DiffArgs),
+    /// Check the archive still holds what the catalogue says was transferred into it.
+    Verify(VerifyArgs),
+    /// Clear the immutable flag set by `--immutable` from every file under a directory, e.g. to
+    /// reorganise the archive by hand.
+    Unlock(UnlockArgs),
+    /// Copy files back out of the archive, decrypting and decompressing them and checking their
+    /// content against the catalogue.
+    Restore(RestoreArgs),
+    /// Keep a list in the catalogue of source files never to be transferred, e.g. corrupt files
+    /// which would otherwise fail on every run.
+    Ignore(IgnoreArgs),
+    /// Keep a list in the catalogue of content deleted from the out directory on purpose, which
+    /// is never transferred again though the in directory still has it.
+    Tombstone(TombstoneArgs),
+    /// Sign in to iCloud Photos, so that its originals can be synced by giving the session
+    /// directory as an in directory, on machines with no library to sync from.
+    #[clap(name = "icloud")]
+    ICloud(ICloudArgs),
+    /// Work with the catalogue database directly.
+    Db(DbArgs),
+    /// Print a shell completion script for the given shell, e.g. `completions bash`.
+    Completions { shell: clap_complete::Shell },
+    /// Print the manual page, in roff format.
+    Manpage,
+}
+
+/// The arguments to `sync`, parsed from the command line.
+#[derive(Args, Debug)]
+pub struct SyncArgs {
+    /// A directory to copy photos from. May be repeated, giving all but one as `<label>=<path>`;
+    /// the files of each labelled directory are catalogued as `<machine id>/<label>`, while
+    /// deduplication spans them all. A macOS Photos library's package may be given, whose
+    /// originals are archived as `YYYY/MM/<original name>`, or an `icloud login` session
+    /// directory, whose library's new originals are downloaded into it and archived likewise.
+    #[clap(long, env = "PHOTO_SYNC_IN_DIR", required = true)]
+    in_dir: Vec<InDir>,
+    #[clap(long, env = "PHOTO_SYNC_OUT_DIR", required_unless_present = "out_url")]
+    out_dir: Option<PathBuf>,
+    /// Archive new files somewhere other than an out directory: an S3-compatible bucket, e.g. on
+    /// Backblaze B2, given as `s3://<bucket>/<prefix>`, or a NAS reached over SFTP, given as
+    /// `sftp://[<user>@]<host>[:<port>]/<path>`. Buckets are reached with `curl`, signing requests
+    /// with the credentials in AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY, and NASes with
+    /// OpenSSH's `sftp`, which uses the usual SSH keys and config. Options which need the
+    /// archived files on a local disk can't be used with it.
+    #[clap(
+        long,
+        env = "PHOTO_SYNC_OUT_URL",
+        conflicts_with_all = [
+            "out_dir", "chunked", "immutable", "paranoid", "move_sources", "dedupe_mode",
+            "symlinks", "apple_double", "preserve_xattrs", "mark_destination",
+            "propagate_deletions"
+        ]
+    )]
+    out_url: Option<OutUrl>,
+    /// The endpoint of the `--out-url` bucket's service, e.g.
+    /// `https://s3.us-west-004.backblazeb2.com`. Defaults to AWS's for AWS_REGION.
+    #[clap(long, env = "PHOTO_SYNC_S3_ENDPOINT", requires = "out_url")]
+    s3_endpoint: Option<String>,
+    #[clap(long, env = "PHOTO_SYNC_OLD_OUT_DIR")]
+    old_out_dir: PathBuf,
+    #[clap(
+        long,
+        env = "PHOTO_SYNC_DATABASE",
+        required_unless_present_any = ["catalogue_addr", "ephemeral_db"]
+    )]
+    database_file: Option<PathBuf>,
+    /// Use the catalogue hosted by `serve` at this address instead of a local database file. Takes
+    /// precedence over a database file from PHOTO_SYNC_DATABASE, but not `--database-file`.
+    #[clap(long, env = "PHOTO_SYNC_CATALOGUE_ADDR")]
+    catalogue_addr: Option<String>,
+    #[clap(long, env = "PHOTO_SYNC_TEMP_DIR")]
+    temp_dir: PathBuf,
+    /// Identifies this machine when several machines feed the same catalogue. Source files are
+    /// tracked separately per machine, while deduplication spans all of them.
+    #[clap(long, env = "PHOTO_SYNC_MACHINE_ID", default_value = "")]
+    machine_id: String,
+    /// Shell command run before anything else, e.g. to mount the source. The run is aborted if the
+    /// hook fails.
+    #[clap(long, env = "PHOTO_SYNC_PRE_HOOK")]
+    pre_hook: Option<String>,
+    /// Shell command run once the run finishes, with PHOTO_SYNC_STATUS set to `success` or
+    /// `failure`.
+    #[clap(long, env = "PHOTO_SYNC_POST_HOOK")]
+    post_hook: Option<String>,
+    /// Post the run's summary as JSON to this URL, as `--summary-json` writes it, as soon as the
+    /// run finishes or fails, e.g. to a chat or alerting service's webhook. It's never shown, as
+    /// it usually carries a token.
+    #[clap(long, env = "PHOTO_SYNC_NOTIFY_WEBHOOK", hide_env_values = true)]
+    notify_webhook: Option<WebhookUrl>,
+    /// Shell command run as soon as the run finishes or fails, e.g. to send an email, with the
+    /// run's summary as JSON on its standard input and PHOTO_SYNC_STATUS set to `success` or
+    /// `failure`.
+    #[clap(long, env = "PHOTO_SYNC_NOTIFY_COMMAND")]
+    notify_command: Option<String>,
+    /// Only notify of runs which fail.
+    #[clap(long, env = "PHOTO_SYNC_NOTIFY_FAILURES_ONLY")]
+    notify_failures_only: bool,
+    /// Shell command run for every file written to the out directory, with PHOTO_SYNC_PATH and
+    /// PHOTO_SYNC_DIGEST set (e.g. to trigger indexing).
+    #[clap(long, env = "PHOTO_SYNC_FILE_HOOK")]
+    file_hook: Option<String>,
+    /// WebAssembly plugin deciding which files to sync and where to write them. See `plugin.rs`
+    /// for the interface it must implement.
+    #[clap(long, env = "PHOTO_SYNC_PLUGIN")]
+    plugin: Option<PathBuf>,
+    /// If the run fails, delete the catalogue rows it wrote rather than just marking it aborted.
+    /// Files already written to the out directory are kept either way.
+    #[clap(long, env = "PHOTO_SYNC_ROLL_BACK_ON_ABORT")]
+    roll_back_on_abort: bool,
+    /// Use the arguments of the `--config` file's job of this name, e.g. `[jobs.icloud-to-nas]`,
+    /// or failing that, those saved in the database under it. Arguments given on the command line
+    /// take precedence over the saved ones.
+    #[clap(long, env = "PHOTO_SYNC_PROFILE")]
+    profile: Option<String>,
+    /// TOML file of arguments keyed by their long names, e.g. `in_dir = "/photos"`, as written by
+    /// `init`. Arguments given on the command line or by a profile take precedence over its.
+    #[clap(long, env = "PHOTO_SYNC_CONFIG")]
+    config: Option<PathBuf>,
+    /// What to do with macOS `._name` AppleDouble files whose data file `name` is also in the in
+    /// directory.
+    #[clap(
+        long,
+        env = "PHOTO_SYNC_APPLE_DOUBLE",
+        value_enum,
+        default_value_t = AppleDoublePolicy::Independent
+    )]
+    apple_double: AppleDoublePolicy,
+    /// Copy the extended attributes of files in the in directory to their copies, e.g. the Finder
+    /// tags and comments macOS keeps in them.
+    #[clap(long, env = "PHOTO_SYNC_PRESERVE_XATTRS")]
+    preserve_xattrs: bool,
+    /// What to do with symbolic links in the in and old out directories.
+    #[clap(
+        long,
+        env = "PHOTO_SYNC_SYMLINKS",
+        value_enum,
+        default_value_t = SymlinkPolicy::Follow
+    )]
+    symlinks: SymlinkPolicy,
+    /// Fail when the in or old out directory holds a FIFO, socket or device node, rather than
+    /// skipping and reporting it.
+    #[clap(long, env = "PHOTO_SYNC_STRICT")]
+    strict: bool,
+    /// Mark the out directory as the archive even though the catalogue already knows of files in
+    /// it, e.g. for archives synced to before runs checked for the marker. Only use this once the
+    /// out directory is known to be mounted.
+    #[clap(long)]
+    mark_destination: bool,
+    /// Report what would be hashed, copied or skipped, and how many bytes would be copied, without
+    /// changing the database or the out directory.
+    #[clap(long, env = "PHOTO_SYNC_DRY_RUN", conflicts_with = "catalogue_addr")]
+    dry_run: bool,
+    /// Once synced, keep running, and sync the files created or changed in the in directory as
+    /// they appear, each batch as a run of its own. Runs until killed, or aborted over the control
+    /// socket.
+    #[clap(long, env = "PHOTO_SYNC_WATCH", conflicts_with = "dry_run")]
+    watch: bool,
+    /// Once synced, go through the files which couldn't be transferred, and those left out as
+    /// their place in the out directory was taken, in the terminal, and decide whether each is to
+    /// be transferred again, ignored from now on or quarantined under `.quarantine/` in the out
+    /// directory. Later runs follow the decisions.
+    #[clap(
+        long,
+        env = "PHOTO_SYNC_INTERACTIVE",
+        conflicts_with_all = ["watch", "catalogue_addr", "ephemeral_db", "dry_run"]
+    )]
+    interactive: bool,
+    /// Once synced, move the copies in the out directory of files since deleted from the in
+    /// directory into its `.trash` directory, and forget them, after asking. Copies of content
+    /// another source file still has, and files `--move` deleted, are kept.
+    #[clap(
+        long,
+        env = "PHOTO_SYNC_PROPAGATE_DELETIONS",
+        conflicts_with_all = ["watch", "catalogue_addr", "ephemeral_db"]
+    )]
+    propagate_deletions: bool,
+    /// Before syncing, tombstone the content of catalogued files since deleted from the out
+    /// directory, taking them to have been culled on purpose, so their content isn't transferred
+    /// again, and forget them.
+    #[clap(
+        long,
+        env = "PHOTO_SYNC_PROPAGATE_TARGET_DELETIONS",
+        conflicts_with_all = ["catalogue_addr", "ephemeral_db"]
+    )]
+    propagate_target_deletions: bool,
+    /// Don't ask before `--propagate-deletions` moves copies to the trash.
+    #[clap(long, requires = "propagate_deletions")]
+    yes: bool,
+    /// How many days files moved aside into the `.trash` directory are kept before it's emptied.
+    #[clap(
+        long,
+        env = "PHOTO_SYNC_TRASH_RETENTION_DAYS",
+        default_value_t = 30,
+        requires = "propagate_deletions"
+    )]
+    trash_retention_days: u64,
+    /// Listen on a Unix domain socket at this path for commands to report progress and the files
+    /// being worked on, or to pause, resume or abort the run, so that other programs can follow it.
+    #[clap(long, env = "PHOTO_SYNC_CONTROL_SOCKET")]
+    control_socket: Option<PathBuf>,
+    /// Write the outcome of the run here when it finishes, for node_exporter's textfile collector
+    /// (e.g. `/var/lib/node_exporter/photo_sync.prom`).
+    #[clap(long, env = "PHOTO_SYNC_METRICS_FILE")]
+    metrics_file: Option<PathBuf>,
+    /// Serve the run's counters and phase durations over HTTP at `/metrics` on this address (e.g.
+    /// `127.0.0.1:9090`) while it runs, for Prometheus to scrape; most useful with `--watch`.
+    #[clap(long, env = "PHOTO_SYNC_METRICS_ADDR")]
+    metrics_addr: Option<SocketAddr>,
+    /// Fail the run if more files than this couldn't be transferred, exiting with a code saying
+    /// why most of them couldn't: 10 permission denied, 11 vanished, 12 changed while copying, 13
+    /// disk full, 14 digest mismatch, 15 corrupt, 16 collided, 17 hook failed, 18 anything else.
+    /// By default a run succeeds however many files fail.
+    #[clap(long, env = "PHOTO_SYNC_MAX_FAILURES")]
+    max_failures: Option<u64>,
+    /// How much is logged to standard output.
+    #[clap(long, env = "PHOTO_SYNC_LOG_LEVEL", value_enum, default_value_t = LogLevel::Info)]
+    log_level: LogLevel,
+    /// Also log to this file, appending to it, down to what happened to each file whatever
+    /// `--log-level` is.
+    #[clap(long, env = "PHOTO_SYNC_LOG_FILE")]
+    log_file: Option<PathBuf>,
+    /// Write the outcome of the run here as JSON when it finishes: what was indexed, transferred,
+    /// deduplicated and failed, with byte totals and how long each phase took.
+    #[clap(long, env = "PHOTO_SYNC_SUMMARY_JSON")]
+    summary_json: Option<PathBuf>,
+    /// Write a CSV file with a row for every file the transfer phase processes: its path, outcome,
+    /// size, how long it took and its digest.
+    #[clap(long, env = "PHOTO_SYNC_TRANSFER_LOG")]
+    transfer_log: Option<PathBuf>,
+    /// What to do if the database is on a network filesystem (e.g. NFS or SMB), where sqlite's
+    /// locking is unreliable.
+    #[clap(
+        long,
+        env = "PHOTO_SYNC_NETWORK_DATABASE",
+        value_enum,
+        default_value_t = NetworkDatabasePolicy::Refuse
+    )]
+    network_database: NetworkDatabasePolicy,
+    /// What to do if another sync is already running against the database.
+    #[clap(
+        long,
+        env = "PHOTO_SYNC_IF_LOCKED",
+        value_enum,
+        default_value_t = LockedPolicy::Fail
+    )]
+    if_locked: LockedPolicy,
+    /// Break the catalogue's sync lease if it has expired or is this machine's own, and record the
+    /// runs its holder never finished as aborted. Only for when a sync is known to be stuck or
+    /// dead, as two syncing at once can archive the same file twice. The database's lock file is
+    /// removed too, but not while another sync holds its lock.
+    #[clap(long, env = "PHOTO_SYNC_FORCE_UNLOCK")]
+    force_unlock: bool,
+    /// Sync against an empty in-memory catalogue rather than a database, leaving any database
+    /// untouched. Takes precedence over `--database-file` and `--catalogue-addr`.
+    #[clap(long, env = "PHOTO_SYNC_EPHEMERAL_DB")]
+    ephemeral_db: bool,
+    /// Fill the in-memory catalogue from this manifest, as written by `--write-manifest`.
+    #[clap(long, env = "PHOTO_SYNC_SEED_MANIFEST", requires = "ephemeral_db")]
+    seed_manifest: Option<PathBuf>,
+    /// Write the in-memory catalogue to this manifest, of one JSON object per line, when the run
+    /// finishes.
+    #[clap(long, env = "PHOTO_SYNC_WRITE_MANIFEST", requires = "ephemeral_db")]
+    write_manifest: Option<PathBuf>,
+    /// Snapshot the filesystem holding the archive after each successful run, recording the
+    /// snapshot's name against the run.
+    #[clap(
+        long,
+        env = "PHOTO_SYNC_SNAPSHOT",
+        value_enum,
+        requires = "snapshot_target"
+    )]
+    snapshot: Option<SnapshotKind>,
+    /// What to snapshot: the ZFS dataset, the btrfs subvolume, or the command to run.
+    #[clap(long, env = "PHOTO_SYNC_SNAPSHOT_TARGET", requires = "snapshot")]
+    snapshot_target: Option<String>,
+    /// Once each transferred file is verified, mark it immutable (`chattr +i` or `chflags uchg`)
+    /// so nothing can change it in place. Undo with the `unlock` subcommand.
+    #[clap(long, env = "PHOTO_SYNC_IMMUTABLE")]
+    immutable: bool,
+    /// Store transferred files zstd-compressed, with `.zst` added to their names. The catalogue
+    /// keeps their original digest and size, and `restore` decompresses them.
+    #[clap(long, env = "PHOTO_SYNC_COMPRESS")]
+    compress: bool,
+    /// The zstd level to compress at, from 1 (fastest) to 19 (smallest).
+    #[clap(long, env = "PHOTO_SYNC_COMPRESSION_LEVEL", default_value_t = 3)]
+    compression_level: i32,
+    /// Encrypt transferred files with age to these recipients (`age1...` public keys), adding
+    /// `.age` to their names. The catalogue keeps their plaintext digest, and `restore` decrypts
+    /// them given an identity.
+    #[clap(long, env = "PHOTO_SYNC_ENCRYPT_TO", value_delimiter = ',')]
+    encrypt_to: Vec<String>,
+    /// Store transferred files as content-defined chunks in a repository in the out directory, so
+    /// files which are mostly the same (e.g. re-edited videos) share storage. Each file is kept as
+    /// a list of its chunks, with `.chunks` added to its name, and `restore` reassembles it.
+    #[clap(long, env = "PHOTO_SYNC_CHUNKED", conflicts_with_all = ["compress", "encrypt_to"])]
+    chunked: bool,
+    /// Archive HEIC and HEIF photos as JPEGs, given as `jpeg` or `jpeg:<quality>`, e.g. for
+    /// galleries which can't show HEIC. They're still catalogued by the original's digest, so
+    /// sources dedupe as before, and the JPEG's digest is kept to verify the copy against.
+    #[clap(
+        long,
+        env = "PHOTO_SYNC_TRANSCODE_HEIC",
+        conflicts_with_all = ["compress", "chunked", "move_sources"]
+    )]
+    transcode_heic: Option<TranscodeTarget>,
+    /// Shell command converting `--transcode-heic` photos, run with PHOTO_SYNC_INPUT,
+    /// PHOTO_SYNC_OUTPUT and PHOTO_SYNC_QUALITY set. Defaults to `heif-convert`, or `sips` on
+    /// macOS.
+    #[clap(long, env = "PHOTO_SYNC_HEIC_CONVERTER", requires = "transcode_heic")]
+    heic_converter: Option<String>,
+    /// Check that images decode and that videos and HEIF files aren't truncated before archiving
+    /// them. Files which look corrupt are reported rather than transferred, so they're tried again
+    /// on the next run.
+    #[clap(long, env = "PHOTO_SYNC_VALIDATE_MEDIA")]
+    validate_media: bool,
+    /// Also store a perceptual hash of each new image, which `find-similar` and `variants` would
+    /// otherwise decode the archive to compute. Deduplication still goes by exact content. HEIC
+    /// photos can't be decoded, so are counted as not fingerprinted.
+    #[clap(long, env = "PHOTO_SYNC_PERCEPTUAL_HASH")]
+    perceptual_hash: bool,
+    /// Don't transfer screenshots, known by their name or the comment iOS gives them. They're
+    /// catalogued as left out, so they aren't read again until they change.
+    #[clap(long, env = "PHOTO_SYNC_SKIP_SCREENSHOTS")]
+    skip_screenshots: bool,
+    /// Which frames of each burst to transfer. With `best-only`, the largest of the frames in
+    /// its directory, which is usually the sharpest, and the rest are catalogued as left out.
+    #[clap(
+        long,
+        env = "PHOTO_SYNC_COLLAPSE_BURSTS",
+        value_enum,
+        default_value_t = BurstPolicy::All
+    )]
+    collapse_bursts: BurstPolicy,
+    /// Make every check there is, for irreplaceable photos where correctness matters more than
+    /// throughput: flush each copy and the catalogue to disk, read each copy back from the disk and
+    /// remove it if it doesn't match, compare new files byte for byte with the archived copy they
+    /// duplicate rather than trusting its digest, and skip files which change while being copied.
+    #[clap(long, env = "PHOTO_SYNC_PARANOID")]
+    paranoid: bool,
+    /// Flush each copy, and its entry in its directory, to disk before cataloguing it, so that a
+    /// file the catalogue says was transferred survives a power cut. `--paranoid` does too.
+    #[clap(long, env = "PHOTO_SYNC_FSYNC")]
+    fsync: bool,
+    /// How new and changed files are hashed. BLAKE3 is several times faster than SHA-256, and
+    /// files already catalogued by another algorithm are still recognised as duplicates.
+    #[clap(
+        long,
+        env = "PHOTO_SYNC_HASH_ALGO",
+        value_enum,
+        default_value_t = HashAlgorithm::Sha256
+    )]
+    hash_algo: HashAlgorithm,
+    /// Load the digest of everything archived into memory when transferring starts, so checking
+    /// whether new content is a duplicate mostly needn't query the catalogue. Worth it for large
+    /// archives, at the cost of about 50 bytes of memory per archived file.
+    #[clap(long, env = "PHOTO_SYNC_CACHE_DIGESTS")]
+    cache_digests: bool,
+    /// Hash files in the old out directory at least this large, e.g. long videos, across every
+    /// idle thread rather than on one, so they don't hold up the end of indexing. Only BLAKE3
+    /// digests can be split so, so this needs `--hash-algo blake3`, with which it defaults to 64M.
+    #[clap(
+        long,
+        env = "PHOTO_SYNC_PARALLEL_HASH_MIN_SIZE",
+        value_parser = filters::parse_size
+    )]
+    parallel_hash_min_size: Option<u64>,
+    /// When a file already transferred changes, e.g. a photo edited and exported again, archive
+    /// its new content as a version under `versions/` in the out directory, keeping what was
+    /// transferred before. Otherwise changed files are reported for manual intervention.
+    #[clap(long, env = "PHOTO_SYNC_KEEP_VERSIONS")]
+    keep_versions: bool,
+    /// Delete files from the in directory once their content is archived, after reading back the
+    /// copy written or comparing them byte for byte with the copy already there, e.g. to drain a
+    /// download folder rather than mirror it.
+    #[clap(long = "move", env = "PHOTO_SYNC_MOVE")]
+    move_sources: bool,
+    /// What to do with new files whose content is already archived: leave them out of the out
+    /// directory, or link them there to the archived copy, keeping the in directory's folders
+    /// without storing the content twice. Only copies stored as they are can be linked to.
+    #[clap(
+        long,
+        env = "PHOTO_SYNC_DEDUPE_MODE",
+        value_enum,
+        default_value_t = DedupeMode::Skip,
+        conflicts_with_all = ["compress", "encrypt_to", "chunked"]
+    )]
+    dedupe_mode: DedupeMode,
+    /// How the out directory is laid out: mirroring the in directory, or with `cas`, naming each
+    /// content after its digest, `ab/cd/<digest>.<ext>`, so no two can collide. `export-view`
+    /// builds a browsable tree of links to a content-addressed archive.
+    #[clap(long, env = "PHOTO_SYNC_LAYOUT", value_enum, default_value_t)]
+    layout: Layout,
+    /// Write new files into `YYYY/MM/` directories of the out directory, by when they were taken
+    /// according to their EXIF data, or when they were last modified if they have none. Where
+    /// each file went is catalogued, so later runs don't file it again.
+    #[clap(long, env = "PHOTO_SYNC_ORGANIZE_BY_DATE")]
+    organize_by_date: bool,
+    /// What to do when a new file's place in the out directory is already taken by a file the
+    /// catalogue doesn't know of, e.g. one copied there by hand. Files of the same content are
+    /// always compared by hashing the one there. Files failed count towards `--max-failures`,
+    /// and the run carries on with the rest.
+    #[clap(
+        long,
+        env = "PHOTO_SYNC_ON_COLLISION",
+        value_enum,
+        default_value_t = CollisionPolicy::Rename
+    )]
+    on_collision: CollisionPolicy,
+    /// Transfer empty files, and photos and videos too small to be intact, rather than listing
+    /// them to be downloaded again.
+    #[clap(long, env = "PHOTO_SYNC_INCLUDE_SMALL_FILES")]
+    include_small_files: bool,
+    /// The order to transfer new files in. By default they're transferred in the order they're
+    /// found, starting straight away; any other order waits until the in directory is scanned.
+    #[clap(long, env = "PHOTO_SYNC_ORDER", value_enum)]
+    order: Option<TransferOrder>,
+    /// Directories of the in directory to detect and transfer new files from before any others,
+    /// e.g. the current year's.
+    #[clap(long, env = "PHOTO_SYNC_PRIORITY_DIR", value_delimiter = ',')]
+    priority_dir: Vec<PathBuf>,
+    /// Leave out files and directories matching these globs, e.g. `*.aae`, in both the in and old
+    /// out directories. Globs without a `/` match names anywhere, and others paths relative to
+    /// the directory; case is ignored. System files like `.DS_Store` are left out too.
+    #[clap(long, env = "PHOTO_SYNC_EXCLUDE", value_delimiter = ',')]
+    exclude: Vec<String>,
+    /// Only consider files matching one of these globs, e.g. `*.heic`, unless they're excluded.
+    #[clap(long, env = "PHOTO_SYNC_INCLUDE", value_delimiter = ',')]
+    include: Vec<String>,
+    /// Consider system files like `.DS_Store` and `Thumbs.db` too, unless they're excluded.
+    #[clap(long, env = "PHOTO_SYNC_NO_DEFAULT_EXCLUDES")]
+    no_default_excludes: bool,
+    /// Only transfer new files with one of these extensions, ignoring case, e.g.
+    /// `heic,jpg,mov`, leaving out the PDFs and the like exports have mixed in.
+    #[clap(long, env = "PHOTO_SYNC_EXTENSIONS", value_delimiter = ',')]
+    extensions: Vec<String>,
+    /// Leave out new files smaller than this, in bytes or with a K, M, G or T suffix.
+    #[clap(long, env = "PHOTO_SYNC_MIN_SIZE", value_parser = filters::parse_size)]
+    min_size: Option<u64>,
+    /// Leave out new files larger than this, e.g. `2G` to leave out long screen recordings.
+    #[clap(long, env = "PHOTO_SYNC_MAX_SIZE", value_parser = filters::parse_size)]
+    max_size: Option<u64>,
+    /// Only transfer new files last modified since this local date or time, e.g. `2024-01-31`
+    /// or `2024-01-31 18:30`, or this long ago, e.g. `30d`, `2w` or `12h`.
+    #[clap(long, env = "PHOTO_SYNC_SINCE", value_parser = filters::parse_time)]
+    since: Option<SystemTime>,
+    /// Only transfer new files last modified before this, given as `--since` is.
+    #[clap(long, env = "PHOTO_SYNC_UNTIL", value_parser = filters::parse_time)]
+    until: Option<SystemTime>,
+    /// Only look for new files in this directory within the in directory, e.g. an album's folder,
+    /// rather than scanning all of it. May be given more than once.
+    #[clap(long, env = "PHOTO_SYNC_ONLY_SUBDIR", value_delimiter = ',', value_parser = filters::parse_subdir)]
+    only_subdir: Vec<PathBuf>,
+    /// Hold copying into the out directory (strictly, into the temp directory, which must share
+    /// its filesystem) to this rate, e.g. so a sync to a network share doesn't saturate the
+    /// uplink. Independent of scrubbing's `--max-bytes-per-second`.
+    #[clap(long, env = "PHOTO_SYNC_MAX_UPLOAD_BYTES_PER_SECOND")]
+    max_upload_bytes_per_second: Option<u64>,
+    /// Transfer at most this many files to the out directory at once, if fewer than
+    /// `--max-parallel-files`.
+    #[clap(long, env = "PHOTO_SYNC_MAX_CONCURRENT_UPLOADS")]
+    max_concurrent_uploads: Option<NonZeroUsize>,
+    /// Hold reading files, both hashing the old out directory and copying new ones, to this many
+    /// megabits per second in all, e.g. `400` to leave a NAS on gigabit room for other use.
+    #[clap(
+        long,
+        env = "PHOTO_SYNC_MAX_READ_MBPS",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    max_read_mbps: Option<u64>,
+    /// Hash or copy at most this many files at once. Defaults to one per CPU.
+    #[clap(long, env = "PHOTO_SYNC_MAX_PARALLEL_FILES")]
+    max_parallel_files: Option<NonZeroUsize>,
+    /// Hold at most this many files open at once across all workers. Defaults to what the
+    /// process's limit on open files allows, after raising it as far as possible.
+    #[clap(long, env = "PHOTO_SYNC_MAX_OPEN_FILES")]
+    max_open_files: Option<NonZeroUsize>,
+    /// Copy new files in blocks of this many KiB. Larger blocks help reach the speed of fast
+    /// network storage.
+    #[clap(
+        long,
+        env = "PHOTO_SYNC_COPY_BUFFER_KIB",
+        default_value_t = copy::DEFAULT_BUFFER_KIB,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..=1024 * 1024)
+    )]
+    copy_buffer_kib: usize,
+    /// How new files are copied to `--temp-dir`: `auto` clones them, or copies them within the
+    /// kernel, where the in and temporary directories' filesystems allow, so their content is read
+    /// only to hash it, and otherwise reads and writes them as `read` always does.
+    #[clap(long, env = "PHOTO_SYNC_COPY_STRATEGY", value_enum, default_value_t)]
+    copy_strategy: CopyStrategy,
+    /// Once each new file is copied and hashed, drop it from the page cache, so that copying
+    /// multi-GB videos doesn't push out everything else cached. Only has an effect on Linux.
+    #[clap(long, env = "PHOTO_SYNC_DROP_PAGE_CACHE")]
+    drop_page_cache: bool,
+    /// Print how fast each new file was copied.
+    #[clap(long, env = "PHOTO_SYNC_REPORT_THROUGHPUT")]
+    report_throughput: bool,
+    /// How many more times to try transferring a file which couldn't be opened or read, e.g. one
+    /// iCloud Drive is still downloading. Files which still fail are retried first by the next
+    /// run.
+    #[clap(long, env = "PHOTO_SYNC_RETRIES", default_value_t = 2)]
+    retries: u32,
+    /// Seconds to wait before retrying a file, doubling with each retry.
+    #[clap(long, env = "PHOTO_SYNC_RETRY_DELAY", default_value_t = 1)]
+    retry_delay: u64,
+    /// What to do with files iCloud Drive has evicted, whose content is only in iCloud.
+    #[clap(
+        long,
+        env = "PHOTO_SYNC_PLACEHOLDERS",
+        value_enum,
+        default_value_t = PlaceholderPolicy::Download
+    )]
+    placeholders: PlaceholderPolicy,
+    /// Seconds to wait for iCloud Drive to download an evicted file before leaving it for a later
+    /// run.
+    #[clap(long, env = "PHOTO_SYNC_DOWNLOAD_TIMEOUT", default_value_t = 300)]
+    download_timeout: u64,
+    /// Run with this CPU niceness, from 0 to 19 (lowest priority).
+    #[clap(long, env = "PHOTO_SYNC_NICE", value_parser = clap::value_parser!(i32).range(0..=19))]
+    nice: Option<i32>,
+    /// Only read and write files when no other process wants the disk (the idle I/O class on
+    /// Linux, or throttled I/O on macOS).
+    #[clap(long, env = "PHOTO_SYNC_IDLE_IO")]
+    idle_io: bool,
+    /// Only do work between these local times, e.g. `01:00-07:00`, pausing (rather than
+    /// stopping) outside them.
+    #[clap(long, env = "PHOTO_SYNC_ONLY_BETWEEN")]
+    only_between: Option<TimeWindow>,
+    /// Work through the files on each device one at a time in directory order, rather than many
+    /// at once, so spinning disks read sequentially instead of seeking back and forth. Devices are
+    /// still worked on in parallel.
+    #[clap(long, env = "PHOTO_SYNC_SEQUENTIAL_PER_DEVICE")]
+    sequential_per_device: bool,
+    /// Catalogue new files in the old out directory on their size and modification time alone,
+    /// rather than hashing them, for `hash-pending` to hash later (e.g. in the background). Until
+    /// then, new files with the same content are transferred again rather than deduplicated.
+    #[clap(long, env = "PHOTO_SYNC_TRUST_SIZE_MTIME")]
+    trust_size_mtime: bool,
+    /// Catalogue new files in the old out directory on a quick fingerprint of their size and
+    /// first and last 64KiB, rather than hashing them whole, which is left until a new file shares
+    /// their fingerprint (or `hash-pending`). Makes the first sync against a large archive far
+    /// quicker.
+    #[clap(
+        long,
+        env = "PHOTO_SYNC_QUICK_INDEX",
+        conflicts_with = "trust_size_mtime"
+    )]
+    quick_index: bool,
+    /// Leave out of indexing the old out directory the files of directories which, going by
+    /// their modification time and number of entries, are as they were when last indexed. Files
+    /// rewritten in place there aren't noticed until a run without it.
+    #[clap(long, env = "PHOTO_SYNC_INCREMENTAL_INDEX")]
+    incremental_index: bool,
+    /// Print how many files took how long to process, as well as the slowest of them.
+    #[clap(long, env = "PHOTO_SYNC_TIMING_HISTOGRAM")]
+    timing_histogram: bool,
+    /// How much of the catalogue sqlite may cache in memory, in MB.
+    #[clap(long, env = "PHOTO_SYNC_DB_CACHE_SIZE_MB")]
+    db_cache_size_mb: Option<u64>,
+    /// How much of the catalogue sqlite may memory map, in MB.
+    #[clap(long, env = "PHOTO_SYNC_DB_MMAP_SIZE_MB")]
+    db_mmap_size_mb: Option<u64>,
+    /// Where sqlite keeps temporary tables and indexes.
+    #[clap(long, env = "PHOTO_SYNC_DB_TEMP_STORE", value_enum)]
+    db_temp_store: Option<TempStore>,
+    /// How often sqlite waits for writes to reach the disk. `normal` is much faster than `full`,
+    /// and with a write-ahead log only risks the last transactions on power loss.
+    #[clap(long, env = "PHOTO_SYNC_DB_SYNCHRONOUS", value_enum)]
+    db_synchronous: Option<Synchronous>,
+    /// Print every statement run against the catalogue with how long it took, and once the run
+    /// is over, which took longest in total and how long was spent in the catalogue compared to
+    /// hashing and transferring files.
+    #[clap(long, env = "PHOTO_SYNC_DB_TRACE")]
+    db_trace: bool,
+    /// Pause while the machine is running on battery, resuming once it's plugged in.
+    #[clap(long, env = "PHOTO_SYNC_PAUSE_ON_BATTERY")]
+    pause_on_battery: bool,
+    /// Pause while the machine is running on a battery charged below this percentage.
+    #[clap(long, env = "PHOTO_SYNC_MIN_BATTERY_PERCENT", value_parser = clap::value_parser!(u8).range(1..=100))]
+    min_battery_percent: Option<u8>,
+}
+
+#[derive(Args, Debug)]
+struct ServeArgs {
+    #[clap(long, env = "PHOTO_SYNC_DATABASE")]
+    database_file: PathBuf,
+    /// Address to listen on. There is no authentication, so only expose this to trusted networks.
+    #[clap(long, env = "PHOTO_SYNC_LISTEN", default_value = "127.0.0.1:7878")]
+    listen: String,
+}
+
+#[derive(Args, Debug)]
+struct ProfileArgs {
+    #[clap(long, env = "PHOTO_SYNC_DATABASE")]
+    database_file: PathBuf,
+    #[command(subcommand)]
+    command: ProfileCommand,
+}
+
+#[derive(Args, Debug)]
+struct InitArgs {
+    /// Where to write the config file.
+    #[clap(long, env = "PHOTO_SYNC_CONFIG", default_value = "photo-sync.toml")]
+    config: PathBuf,
+    /// Replace the config file if it already exists.
+    #[clap(long)]
+    force: bool,
+    #[clap(long, env = "PHOTO_SYNC_IN_DIR")]
+    in_dir: Option<PathBuf>,
+    #[clap(long, env = "PHOTO_SYNC_OUT_DIR")]
+    out_dir: Option<PathBuf>,
+    #[clap(long, env = "PHOTO_SYNC_OLD_OUT_DIR")]
+    old_out_dir: Option<PathBuf>,
+    #[clap(long, env = "PHOTO_SYNC_TEMP_DIR")]
+    temp_dir: Option<PathBuf>,
+    #[clap(long, env = "PHOTO_SYNC_DATABASE")]
+    database_file: Option<PathBuf>,
+    #[clap(long, env = "PHOTO_SYNC_MACHINE_ID")]
+    machine_id: Option<String>,
+}
+
+#[derive(Args, Debug)]
+struct SelfTestArgs {
+    /// Directory to build the scratch tree in, so that its filesystem is the one tested. Defaults
+    /// to the system temp directory.
+    #[clap(long)]
+    dir: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct ScrubArgs {
+    #[clap(long, env = "PHOTO_SYNC_DATABASE")]
+    database_file: PathBuf,
+    #[command(flatten)]
+    lock: LockArgs,
+    #[clap(long, env = "PHOTO_SYNC_OLD_OUT_DIR")]
+    old_out_dir: PathBuf,
+    /// Only rehash files not verified in this many days.
+    #[clap(long, default_value_t = 30)]
+    older_than_days: u64,
+    /// Stop after rehashing this many files.
+    #[clap(long)]
+    limit: Option<usize>,
+    /// Read no faster than this, so a scrub doesn't starve other users of the disk.
+    #[clap(long)]
+    max_bytes_per_second: Option<u64>,
+    /// Keep running, waiting for files to become due rather than exiting.
+    #[clap(long)]
+    continuous: bool,
+    /// Restore missing or corrupt files from another catalogued copy of their content, in the old
+    /// out directory or the in directory, and record it in the catalogue's audit log.
+    #[clap(long)]
+    repair: bool,
+    /// In directory to look for copies in when repairing.
+    #[clap(long, env = "PHOTO_SYNC_IN_DIR")]
+    in_dir: Option<PathBuf>,
+    /// Namespace the in directory's files were catalogued under.
+    #[clap(long, env = "PHOTO_SYNC_MACHINE_ID", default_value = "")]
+    machine_id: String,
+    /// Directory of parity written by `parity`, to rebuild files from when repairing.
+    #[clap(long, env = "PHOTO_SYNC_PARITY_DIR")]
+    parity_dir: Option<PathBuf>,
+    /// Print the audit log of past repairs instead of scrubbing.
+    #[clap(long)]
+    list_repairs: bool,
+    /// How many days files moved aside into the `.trash` directory are kept before it's emptied.
+    #[clap(long, env = "PHOTO_SYNC_TRASH_RETENTION_DAYS", default_value_t = 30)]
+    trash_retention_days: u64,
+}
+
+#[derive(Args, Debug)]
+struct ParityArgs {
+    #[clap(long, env = "PHOTO_SYNC_DATABASE")]
+    database_file: PathBuf,
+    #[clap(long, env = "PHOTO_SYNC_OLD_OUT_DIR")]
+    old_out_dir: PathBuf,
+    /// Directory to keep parity files in, ideally on a different disk to the archive.
+    #[clap(long, env = "PHOTO_SYNC_PARITY_DIR")]
+    parity_dir: PathBuf,
+    /// Most megabytes of files covered by each parity set. Each set's parity is as large as its
+    /// largest file.
+    #[clap(long, default_value_t = 1024)]
+    set_size_mb: u64,
+}
+
+#[derive(Args, Debug)]
+struct BundleArgs {
+    #[clap(long, env = "PHOTO_SYNC_DATABASE")]
+    database_file: PathBuf,
+    #[clap(long, env = "PHOTO_SYNC_OUT_DIR")]
+    out_dir: PathBuf,
+    /// Directory to write the numbered bundles to, for copying off to cold storage.
+    #[clap(long, env = "PHOTO_SYNC_BUNDLE_DIR")]
+    bundle_dir: PathBuf,
+    /// Most megabytes per bundle, e.g. 25000 for a BD-R disc. Files larger than this get a bundle
+    /// to themselves.
+    #[clap(long, default_value_t = 25_000)]
+    bundle_size_mb: u64,
+    /// Also write the last bundle, even though it isn't full yet.
+    #[clap(long)]
+    flush: bool,
+    /// Print which bundles hold this file in the out directory, instead of bundling.
+    #[clap(long)]
+    find: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct OrphansArgs {
+    #[clap(long, env = "PHOTO_SYNC_DATABASE")]
+    database_file: PathBuf,
+    #[command(flatten)]
+    lock: LockArgs,
+    #[clap(long, env = "PHOTO_SYNC_OUT_DIR")]
+    out_dir: PathBuf,
+    /// Hash and record the files found, rather than only reporting them.
+    #[clap(long)]
+    adopt: bool,
+    /// Namespace the adoption run is recorded under.
+    #[clap(long, env = "PHOTO_SYNC_MACHINE_ID", default_value = "")]
+    machine_id: String,
+}
+
+#[derive(Args, Debug)]
+struct HashPendingArgs {
+    #[clap(long, env = "PHOTO_SYNC_DATABASE")]
+    database_file: PathBuf,
+    #[command(flatten)]
+    lock: LockArgs,
+    #[clap(long, env = "PHOTO_SYNC_OLD_OUT_DIR")]
+    old_out_dir: PathBuf,
+    /// Namespace the hashing run is recorded under.
+    #[clap(long, env = "PHOTO_SYNC_MACHINE_ID", default_value = "")]
+    machine_id: String,
+    /// Hash at most this many files, to spread the work over several runs.
+    #[clap(long)]
+    limit: Option<usize>,
+    #[clap(
+        long,
+        env = "PHOTO_SYNC_HASH_ALGO",
+        value_enum,
+        default_value_t = HashAlgorithm::Sha256
+    )]
+    hash_algo: HashAlgorithm,
+}
+
+#[derive(Args, Debug)]
+struct MissingArgs {
+    #[clap(long, env = "PHOTO_SYNC_DATABASE")]
+    database_file: PathBuf,
+    #[command(flatten)]
+    lock: LockArgs,
+    #[clap(long, env = "PHOTO_SYNC_OUT_DIR")]
+    out_dir: PathBuf,
+    /// Also check the files catalogued in the old out directory.
+    #[clap(long, env = "PHOTO_SYNC_OLD_OUT_DIR")]
+    old_out_dir: Option<PathBuf>,
+    /// In directory to look for missing content in.
+    #[clap(long, env = "PHOTO_SYNC_IN_DIR")]
+    in_dir: Option<PathBuf>,
+    /// Namespace the in directory's files were catalogued under.
+    #[clap(long, env = "PHOTO_SYNC_MACHINE_ID", default_value = "")]
+    machine_id: String,
+    /// Copy missing files back from the in directory where it still has their content.
+    #[clap(long, requires = "in_dir")]
+    retransfer: bool,
+}
+
+#[derive(Args, Debug)]
+struct AdoptArgs {
+    #[clap(long, env = "PHOTO_SYNC_DATABASE")]
+    database_file: PathBuf,
+    #[command(flatten)]
+    lock: LockArgs,
+    /// The archive to adopt, which should then be synced to as the out directory.
+    #[clap(long)]
+    dir: PathBuf,
+    /// Namespace the adoption run is recorded under.
+    #[clap(long, env = "PHOTO_SYNC_MACHINE_ID", default_value = "")]
+    machine_id: String,
+    #[clap(
+        long,
+        env = "PHOTO_SYNC_HASH_ALGO",
+        value_enum,
+        default_value_t = HashAlgorithm::Sha256
+    )]
+    hash_algo: HashAlgorithm,
+}
+
+#[derive(Args, Debug)]
+struct DedupeArgs {
+    #[clap(long, env = "PHOTO_SYNC_DATABASE")]
+    database_file: PathBuf,
+    #[command(flatten)]
+    lock: LockArgs,
+    /// Directories to dedupe, which must be on the same filesystem to be linked across. Files in
+    /// a directory which isn't given are left alone.
+    #[clap(long, env = "PHOTO_SYNC_OLD_OUT_DIR")]
+    old_out_dir: Option<PathBuf>,
+    #[clap(long, env = "PHOTO_SYNC_OUT_DIR")]
+    out_dir: Option<PathBuf>,
+    /// Report what would be linked without changing anything.
+    #[clap(long)]
+    dry_run: bool,
+    /// How many days files moved aside into the `.trash` directory are kept before it's emptied.
+    #[clap(long, env = "PHOTO_SYNC_TRASH_RETENTION_DAYS", default_value_t = 30)]
+    trash_retention_days: u64,
+}
+
+#[derive(Args, Debug)]
+struct PruneArgs {
+    #[clap(long, env = "PHOTO_SYNC_DATABASE")]
+    database_file: PathBuf,
+    #[command(flatten)]
+    lock: LockArgs,
+    /// Look for the files catalogued in the old out directory.
+    #[clap(
+        long,
+        env = "PHOTO_SYNC_OLD_OUT_DIR",
+        required_unless_present = "in_dir"
+    )]
+    old_out_dir: Option<PathBuf>,
+    /// Look for the files transferred from the in directory.
+    #[clap(long, env = "PHOTO_SYNC_IN_DIR")]
+    in_dir: Option<PathBuf>,
+    /// Namespace the in directory's files were catalogued under.
+    #[clap(long, env = "PHOTO_SYNC_MACHINE_ID", default_value = "")]
+    machine_id: String,
+    /// Remove the stale rows, rather than only listing them.
+    #[clap(long)]
+    delete_stale: bool,
+}
+
+#[derive(Args, Debug)]
+struct HistoryArgs {
+    #[clap(long, env = "PHOTO_SYNC_DATABASE")]
+    database_file: PathBuf,
+    /// How many of the latest runs to list.
+    #[clap(long, default_value_t = 20)]
+    limit: usize,
+    /// List the files this run added, by the number `history` lists it under.
+    #[clap(long)]
+    run: Option<RunId>,
+}
+
+#[derive(Args, Debug)]
+struct ChecksumManifestArgs {
+    #[clap(long, env = "PHOTO_SYNC_DATABASE")]
+    database_file: PathBuf,
+    #[clap(long, value_enum, default_value_t)]
+    style: ChecksumStyle,
+    /// Where to write the checksums, e.g. `<out dir>/SHA256SUMS`. Defaults to standard output.
+    #[clap(long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct DeletedArgs {
+    #[clap(long, env = "PHOTO_SYNC_DATABASE")]
+    database_file: PathBuf,
+    #[clap(long, env = "PHOTO_SYNC_IN_DIR")]
+    in_dir: PathBuf,
+    #[clap(long, env = "PHOTO_SYNC_MACHINE_ID", default_value = "")]
+    machine_id: String,
+}
+
+#[derive(Args, Debug)]
+struct ArchiveOnlyArgs {
+    #[clap(long, env = "PHOTO_SYNC_DATABASE")]
+    database_file: PathBuf,
+    #[clap(long, env = "PHOTO_SYNC_IN_DIR")]
+    in_dir: PathBuf,
+    #[clap(long, env = "PHOTO_SYNC_MACHINE_ID", default_value = "")]
+    machine_id: String,
+}
+
+#[derive(Args, Debug)]
+struct SameNamesArgs {
+    #[clap(long, env = "PHOTO_SYNC_DATABASE")]
+    database_file: PathBuf,
+}
+
+#[derive(Args, Debug)]
+struct RunAllArgs {
+    /// TOML file whose `[jobs.<name>]` tables each hold a job's arguments, on top of the
+    /// arguments at its top level which all jobs share.
+    #[clap(long, env = "PHOTO_SYNC_CONFIG")]
+    config: PathBuf,
+    /// How many jobs may run at once. Jobs sharing a catalogue always run in turn, as only one
+    /// sync may hold it at a time.
+    #[clap(long, env = "PHOTO_SYNC_PARALLEL_JOBS", default_value = "1")]
+    parallel: NonZeroUsize,
+    /// Only run these jobs, rather than all of them.
+    #[clap(long = "job")]
+    jobs: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+struct VersionsArgs {
+    #[clap(long, env = "PHOTO_SYNC_DATABASE")]
+    database_file: PathBuf,
+    #[clap(long, env = "PHOTO_SYNC_MACHINE_ID", default_value = "")]
+    machine_id: String,
+    /// The file's path, relative to the in directory.
+    path: PathBuf,
+}
+
+#[derive(Args, Debug)]
+struct PruneVersionsArgs {
+    #[clap(long, env = "PHOTO_SYNC_DATABASE")]
+    database_file: PathBuf,
+    #[command(flatten)]
+    lock: LockArgs,
+    #[clap(long, env = "PHOTO_SYNC_OUT_DIR")]
+    out_dir: PathBuf,
+    #[clap(long, env = "PHOTO_SYNC_MACHINE_ID", default_value = "")]
+    machine_id: String,
+    /// Keep this many of each file's latest versions. The latest is always kept, as is the
+    /// content first transferred.
+    #[clap(long)]
+    keep_last: Option<usize>,
+    /// Keep versions archived within this many days. With `--keep-last` too, a version is kept if
+    /// either keeps it.
+    #[clap(long)]
+    keep_newer_than_days: Option<u64>,
+    /// Report what would be pruned without changing anything.
+    #[clap(long)]
+    dry_run: bool,
+    /// How many days files moved aside into the `.trash` directory are kept before it's emptied.
+    #[clap(long, env = "PHOTO_SYNC_TRASH_RETENTION_DAYS", default_value_t = 30)]
+    trash_retention_days: u64,
+}
+
+#[derive(Args, Debug)]
+struct VariantsArgs {
+    #[clap(long, env = "PHOTO_SYNC_DATABASE")]
+    database_file: PathBuf,
+    #[clap(long, env = "PHOTO_SYNC_OLD_OUT_DIR")]
+    old_out_dir: Option<PathBuf>,
+    #[clap(long, env = "PHOTO_SYNC_OUT_DIR")]
+    out_dir: Option<PathBuf>,
+    /// Most bits of perceptual hash which may differ between variants of the same image.
+    #[clap(long, default_value_t = 4, value_parser = clap::value_parser!(u32).range(0..=7))]
+    max_distance: u32,
+}
+
+#[derive(Args, Debug)]
+struct FindSimilarArgs {
+    #[clap(long, env = "PHOTO_SYNC_DATABASE")]
+    database_file: PathBuf,
+    #[clap(long, env = "PHOTO_SYNC_OLD_OUT_DIR")]
+    old_out_dir: Option<PathBuf>,
+    #[clap(long, env = "PHOTO_SYNC_OUT_DIR")]
+    out_dir: Option<PathBuf>,
+    /// Most bits of perceptual hash which may differ between similar images; 0 only finds images
+    /// which hash the same.
+    #[clap(long, default_value_t = 2, value_parser = clap::value_parser!(u32).range(0..=7))]
+    threshold: u32,
+}
+
+#[derive(Args, Debug)]
+struct ReindexMetadataArgs {
+    #[clap(long, env = "PHOTO_SYNC_DATABASE")]
+    database_file: PathBuf,
+    #[command(flatten)]
+    lock: LockArgs,
+    #[clap(long, env = "PHOTO_SYNC_OLD_OUT_DIR")]
+    old_out_dir: Option<PathBuf>,
+    #[clap(long, env = "PHOTO_SYNC_OUT_DIR")]
+    out_dir: Option<PathBuf>,
+    /// Read content already indexed again too, e.g. after an upgrade which reads more of it.
+    #[clap(long)]
+    all: bool,
+}
+
+#[derive(Args, Debug)]
+struct SavingsArgs {
+    #[clap(long, env = "PHOTO_SYNC_DATABASE")]
+    database_file: PathBuf,
+    /// How many folders to list.
+    #[clap(long, default_value_t = 10)]
+    top: usize,
+}
+
+#[derive(Args, Debug)]
+struct ReportDuplicatesArgs {
+    #[clap(long, env = "PHOTO_SYNC_DATABASE")]
+    database_file: PathBuf,
+    /// Print the sets as JSON rather than text.
+    #[clap(long)]
+    json: bool,
+}
+
+#[derive(Args, Debug)]
+struct CompareArgs {
+    dir_a: PathBuf,
+    dir_b: PathBuf,
+}
+
+#[derive(Args, Debug)]
+struct ExportViewArgs {
+    #[clap(long, env = "PHOTO_SYNC_DATABASE")]
+    database_file: PathBuf,
+    #[clap(long, env = "PHOTO_SYNC_OUT_DIR")]
+    out_dir: PathBuf,
+    /// Also link to copies in the old out directory, of content not in the out directory.
+    #[clap(long, env = "PHOTO_SYNC_OLD_OUT_DIR")]
+    old_out_dir: Option<PathBuf>,
+    /// Where to build the tree. Links already there are left alone, so it can be brought up to
+    /// date after later syncs.
+    view_dir: PathBuf,
+    #[clap(long, value_enum, default_value_t)]
+    link: ViewLink,
+}
+
+#[derive(Args, Debug)]
+struct DiffArgs {
+    #[clap(long, env = "PHOTO_SYNC_DATABASE")]
+    database_file: PathBuf,
+    /// The in directories to compare against the catalogue, given as they are to `sync`.
+    #[clap(
+        long,
+        env = "PHOTO_SYNC_IN_DIR",
+        required_unless_present = "runs",
+        conflicts_with = "runs"
+    )]
+    in_dir: Vec<InDir>,
+    #[clap(long, env = "PHOTO_SYNC_MACHINE_ID", default_value = "")]
+    machine_id: String,
+    /// Instead list what the runs after the first of these did, up to and including the second,
+    /// by the numbers `history` lists them under.
+    #[clap(long, num_args = 2, value_names = ["FROM", "TO"])]
+    runs: Option<Vec<RunId>>,
+    /// What new and changed files are hashed with.
+    #[clap(
+        long,
+        env = "PHOTO_SYNC_HASH_ALGO",
+        value_enum,
+        default_value_t = HashAlgorithm::Sha256
+    )]
+    hash_algo: HashAlgorithm,
+    /// Where to write the changes. Defaults to standard output.
+    #[clap(long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct VerifyArgs {
+    #[clap(long, env = "PHOTO_SYNC_DATABASE")]
+    database_file: PathBuf,
+    #[clap(long, env = "PHOTO_SYNC_IN_DIR")]
+    in_dir: Option<PathBuf>,
+    #[clap(long, env = "PHOTO_SYNC_OUT_DIR")]
+    out_dir: PathBuf,
+    /// Also count copies in the old out directory as archived.
+    #[clap(long, env = "PHOTO_SYNC_OLD_OUT_DIR")]
+    old_out_dir: Option<PathBuf>,
+    #[clap(long, env = "PHOTO_SYNC_MACHINE_ID", default_value = "")]
+    machine_id: String,
+    /// For every transferred file still in the in directory, check an intact copy of its content
+    /// is in the archive.
+    #[clap(long, requires = "in_dir")]
+    against_source: bool,
+    /// Rehash the files in the out directory written by the machine's most recent run, a quick
+    /// check after a sync that doesn't read the whole archive.
+    #[clap(long, conflicts_with = "run")]
+    last_run: bool,
+    /// Rehash the files in the out directory written by this run.
+    #[clap(long)]
+    run: Option<RunId>,
+    /// Rehash every file in the out directory, and the old out directory if given, reporting
+    /// catalogued files which are missing or have changed, e.g. through bit rot, and files the
+    /// catalogue doesn't know of.
+    #[clap(long)]
+    full: bool,
+    /// Check that both the still and the video of every Live Photo transferred are still archived
+    /// intact.
+    #[clap(long)]
+    live_photos: bool,
+}
+
+#[derive(Args, Debug)]
+struct RestoreArgs {
+    #[clap(long, env = "PHOTO_SYNC_DATABASE")]
+    database_file: PathBuf,
+    #[command(flatten)]
+    lock: LockArgs,
+    #[clap(long, env = "PHOTO_SYNC_OUT_DIR")]
+    out_dir: PathBuf,
+    /// Directory to restore files into, at their original paths.
+    #[clap(long)]
+    to: PathBuf,
+    /// The age identity file to decrypt encrypted files with.
+    #[clap(long, env = "PHOTO_SYNC_AGE_IDENTITY")]
+    identity: Option<PathBuf>,
+    /// Files or directories in the out directory to restore. Defaults to all of it.
+    paths: Vec<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct UnlockArgs {
+    /// The directory, e.g. the out directory, whose files are made mutable again.
+    dir: PathBuf,
+}
+
+#[derive(Args, Debug)]
+struct DbArgs {
+    #[clap(long, env = "PHOTO_SYNC_DATABASE")]
+    database_file: PathBuf,
+    #[command(flatten)]
+    lock: LockArgs,
+    #[command(subcommand)]
+    command: DbCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum DbCommand {
+    /// Write the catalogue's file rows as a manifest of one JSON object per line, or with
+    /// `--format jsonl`, the whole database.
+    Export {
+        #[clap(long, value_enum, default_value_t)]
+        format: ExportFormat,
+        /// Only export rows written by runs after this one, e.g. for off-site replication to pick
+        /// up just what's new. Rows not written by any run are left out.
+        #[clap(long)]
+        since: Option<RunId>,
+        /// Where to write the export. Defaults to standard output.
+        #[clap(long)]
+        output: Option<PathBuf>,
+    },
+    /// Build the database, which mustn't exist yet, from a `db export --format jsonl`, e.g. to
+    /// move it to another machine.
+    Import {
+        /// The export to read. Defaults to standard input.
+        input: Option<PathBuf>,
+    },
+    /// Bring the database's schema up to date, backing it up to `<database>.v<version>.bak`
+    /// first. Syncs migrate databases they open too, but without a backup.
+    Migrate {
+        /// Only say which migrations are pending.
+        #[clap(long)]
+        dry_run: bool,
+        #[clap(long)]
+        no_backup: bool,
+    },
+    /// Print totals over the catalogue: files and bytes in each table, distinct contents and
+    /// duplicates, the largest archived files, the database's size and each machine's last run.
+    Stats {
+        /// How many of the largest archived files to list.
+        #[clap(long, default_value_t = 10)]
+        largest: usize,
+    },
+    /// Rebuild the index of the archive's digests that duplicates are looked up in, from the
+    /// catalogue's rows, and refresh sqlite's query planning statistics.
+    Reindex,
+    /// Check the database's integrity and that its rows hold together, e.g. after a power loss,
+    /// failing if they don't.
+    Check,
+    /// Rebuild the index of digests, vacuum the database and check it again.
+    Repair,
+}
+
+#[derive(Subcommand, Debug)]
+enum ProfileCommand {
+    /// Save (or replace) a profile, e.g. `profile save phone -- --in-dir /photos --out-dir /nas`.
+    Save {
+        name: String,
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+    List,
+    Delete {
+        name: String,
+    },
+}
+
+#[derive(Args, Debug)]
+struct IgnoreArgs {
+    #[clap(long, env = "PHOTO_SYNC_DATABASE")]
+    database_file: PathBuf,
+    #[command(flatten)]
+    lock: LockArgs,
+    #[command(subcommand)]
+    command: IgnoreCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum IgnoreCommand {
+    /// Never transfer a source file, or everything under a directory, given relative to the in
+    /// directory, e.g. `ignore add 2019/IMG_0001.JPG`.
+    Add {
+        path: PathBuf,
+        /// Take the path as a glob, which without a `/` matches a name anywhere, e.g.
+        /// `ignore add --glob '*.tmp'`.
+        #[clap(long)]
+        glob: bool,
+    },
+    List,
+    /// Transfer a path or glob `add` ignored again.
+    Remove {
+        path: PathBuf,
+    },
+}
+
+#[derive(Args, Debug)]
+struct TombstoneArgs {
+    #[clap(long, env = "PHOTO_SYNC_DATABASE")]
+    database_file: PathBuf,
+    #[command(flatten)]
+    lock: LockArgs,
+    #[command(subcommand)]
+    command: TombstoneCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum TombstoneCommand {
+    /// Never transfer the content of these files again, e.g. photos in the out directory about
+    /// to be deleted from it.
+    Add {
+        #[clap(required = true)]
+        files: Vec<PathBuf>,
+        /// How the files are hashed, which should be the `--hash-algo` syncs use.
+        #[clap(
+            long,
+            env = "PHOTO_SYNC_HASH_ALGO",
+            value_enum,
+            default_value_t = HashAlgorithm::Sha256
+        )]
+        hash_algo: HashAlgorithm,
+    },
+    List,
+    /// Transfer tombstoned content again, given by its digest as `list` shows it.
+    Remove {
+        digest: ContentHash,
+    },
+}
+
+#[derive(Args, Debug)]
+struct ICloudArgs {
+    /// Where the signed-in session is kept between runs, which should be private to you. Given as
+    /// an `--in-dir`, its library's originals are downloaded into it and synced.
+    #[clap(long, env = "PHOTO_SYNC_ICLOUD_SESSION_DIR")]
+    session_dir: PathBuf,
+    #[command(subcommand)]
+    command: ICloudCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum ICloudCommand {
+    /// Sign in, asking for a two-factor code unless this machine is already trusted. The password
+    /// is taken from PHOTO_SYNC_ICLOUD_PASSWORD, or else asked for, so it's never on the command
+    /// line for other users to see.
+    Login {
+        #[clap(long, env = "PHOTO_SYNC_ICLOUD_APPLE_ID")]
+        apple_id: String,
+    },
+}
+
+/// The parser for `args`. When a subcommand is given, the sync arguments accepted without one
+/// mustn't read the environment, or clap would take them as given alongside it.
+fn cli_command(args: &[OsString]) -> clap::Command {
+    let mut command = Cli::command();
+    command.build();
+    let subcommand_given = args
+        .get(1)
+        .and_then(|arg| arg.to_str())
+        .is_some_and(|arg| command.find_subcommand(arg).is_some());
+    if subcommand_given {
+        command.mut_args(|arg| arg.env(None::<&str>))
+    } else {
+        command
+    }
+}
+
+impl Cli {
+    /// The command `matches` give, with their sync arguments' catalogue chosen as
+    /// [`SyncArgs::choose_catalogue`] does.
+    fn from_matches(matches: &ArgMatches) -> Result<Self, clap::Error> {
+        let mut cli = Cli::from_arg_matches(matches)?;
+        match (&mut cli.command, &mut cli.sync) {
+            (Some(Command::Sync(args) | Command::Doctor(args)), _) => {
+                let (_, matches) = matches.subcommand().expect("a subcommand was given");
+                args.choose_catalogue(matches)?;
+            }
+            (None, Some(args)) => args.choose_catalogue(matches)?,
+            _ => {}
+        }
+        Ok(cli)
+    }
+}
+
+impl SyncArgs {
+    /// Drops a database file which came from PHOTO_SYNC_DATABASE when a catalogue address is also
+    /// given, so that the variable can be set for every sync. Given any other way, the two
+    /// conflict, as clap can't tell them apart to say so itself.
+    fn choose_catalogue(&mut self, matches: &ArgMatches) -> Result<(), clap::Error> {
+        if self.database_file.is_none() || self.catalogue_addr.is_none() {
+            return Ok(());
+        }
+        if matches.value_source("database_file") == Some(ValueSource::EnvVariable) {
+            self.database_file = None;
+            return Ok(());
+        }
+        Err(clap::Error::raw(
+            ErrorKind::ArgumentConflict,
+            "the argument '--database-file <DATABASE_FILE>' cannot be used with \
+             '--catalogue-addr <CATALOGUE_ADDR>'\n",
+        ))
+    }
+}
+
+impl Command {
+    /// The database this command changes the catalogue of, or archives catalogued in, if it does,
+    /// which it mustn't do while a sync is, and how it waits its turn.
+    fn changed_database(&self) -> Option<(&Path, &LockArgs)> {
+        let (database_file, lock) = match self {
+            Command::Scrub(args) if args.repair => (&args.database_file, &args.lock),
+            Command::Orphans(args) if args.adopt => (&args.database_file, &args.lock),
+            Command::HashPending(args) => (&args.database_file, &args.lock),
+            Command::Missing(args) if args.retransfer => (&args.database_file, &args.lock),
+            Command::Adopt(args) => (&args.database_file, &args.lock),
+            Command::Dedupe(args) if !args.dry_run => (&args.database_file, &args.lock),
+            Command::Prune(args) if args.delete_stale => (&args.database_file, &args.lock),
+            Command::ReindexMetadata(args) => (&args.database_file, &args.lock),
+            Command::PruneVersions(args) if !args.dry_run => (&args.database_file, &args.lock),
+            Command::Restore(args) => (&args.database_file, &args.lock),
+            Command::Ignore(IgnoreArgs {
+                database_file,
+                lock,
+                command: IgnoreCommand::Add { .. } | IgnoreCommand::Remove { .. },
+            }) => (database_file, lock),
+            Command::Tombstone(TombstoneArgs {
+                database_file,
+                lock,
+                command: TombstoneCommand::Add { .. } | TombstoneCommand::Remove { .. },
+            }) => (database_file, lock),
+            Command::Db(DbArgs {
+                database_file,
+                lock,
+                command:
+                    DbCommand::Import { .. }
+                    | DbCommand::Migrate { dry_run: false, .. }
+                    | DbCommand::Reindex
+                    | DbCommand::Repair,
+            }) => (database_file, lock),
+            _ => return None,
+        };
+        Some((database_file, lock))
+    }
+}
+
+/// Runs the command given on the command line.
+pub fn run_cli() -> Result<()> {
+    run(std::env::args_os().collect())
+}
+
+/// Runs the command `args` gives, the first of them being the binary's name.
+fn run(args: Vec<OsString>) -> Result<()> {
+    let args = expand_saved_args(args)?;
+    let cli =
+        Cli::from_matches(&cli_command(&args).get_matches_from(&args)).unwrap_or_else(|e| e.exit());
+    // held until the command is done, as syncs hold it.
+    let _lock = match cli.command.as_ref().and_then(Command::changed_database) {
+        Some((database_file, lock)) => match lock.acquire(database_file)? {
+            Some(lock) => Some(lock),
+            None => return Ok(()),
+        },
+        None => None,
+    };
+    match cli.command {
+        Some(Command::Sync(args)) => sync(args),
+        Some(Command::RunAll(args)) => jobs::run_all(args),
+        Some(Command::Serve(args)) => serve(args),
+        Some(Command::Profile(args)) => profile(args),
+        Some(Command::Init(args)) => init::init(args),
+        Some(Command::Doctor(args)) => doctor::doctor(&args),
+        Some(Command::SelfTest(args)) => selftest::self_test(args),
+        Some(Command::Scrub(args)) => scrub::scrub(args),
+        Some(Command::Parity(args)) => parity::parity(args),
+        Some(Command::Bundle(args)) => bundle::bundle(args),
+        Some(Command::Orphans(args)) => orphans::orphans(args),
+        Some(Command::HashPending(args)) => hashpending::hash_pending(args),
+        Some(Command::Missing(args)) => missing::missing(args),
+        Some(Command::Adopt(args)) => adopt::adopt(args),
+        Some(Command::Dedupe(args)) => dedupe::dedupe(args),
+        Some(Command::Savings(args)) => savings::savings(args),
+        Some(Command::ReportDuplicates(args)) => duplicates::report_duplicates(args),
+        Some(Command::Prune(args)) => prune::prune(args),
+        Some(Command::History(args)) => history::history(args),
+        Some(Command::Manifest(args)) => checksums::checksum_manifest(args),
+        Some(Command::Deleted(args)) => deleted::deleted(args),
+        Some(Command::ArchiveOnly(args)) => archiveonly::archive_only(args),
+        Some(Command::SameNames(args)) => samenames::same_names(args),
+        Some(Command::Variants(args)) => variants::variants(args),
+        Some(Command::FindSimilar(args)) => similar::find_similar(args),
+        Some(Command::ReindexMetadata(args)) => metadata::reindex_metadata(args),
+        Some(Command::Versions(args)) => versions::versions(args),
+        Some(Command::PruneVersions(args)) => versions::prune_versions(args),
+        Some(Command::Compare(args)) => compare::compare(args),
+        Some(Command::ExportView(args)) => cas::export_view(args),
+        Some(Command::Diff(args)) => diff::diff(args),
+        Some(Command::Verify(args)) => verify::verify(args),
+        Some(Command::Unlock(args)) => {
+            let unlocked = immutable::unlock(&args.dir)?;
+            println!("unlocked {unlocked} files in {:?}", args.dir);
+            Ok(())
+        }
+        Some(Command::Restore(args)) => restore::restore(args),
+        Some(Command::Ignore(args)) => ignorelist::ignore(args),
+        Some(Command::Tombstone(args)) => tombstones::tombstone(args),
+        Some(Command::ICloud(args)) => icloudphotos::icloud(args),
+        Some(Command::Db(args)) => db(args),
+        Some(Command::Completions { shell }) => {
+            manual::completions(shell, &mut io::stdout());
+            Ok(())
+        }
+        Some(Command::Manpage) => manual::manpage(&mut io::stdout()),
+        None => sync(
+            cli.sync
+                .expect("clap requires sync args without a subcommand"),
+        ),
+    }
+}
+
+fn serve(args: ServeArgs) -> Result<()> {
+    logging::init(LogLevel::default(), None)?;
+    let store = PhotoSyncStore::new(args.database_file)?;
+    remote::serve(&store, args.listen)
+}
+
+fn profile(args: ProfileArgs) -> Result<()> {
+    let store = PhotoSyncStore::new(args.database_file)?;
+    match args.command {
+        ProfileCommand::Save { name, args } => {
+            validate_profile_args(&args)?;
+            store.save_profile(&name, &args)?;
+            println!("saved profile {name:?}");
+        }
+        ProfileCommand::List => {
+            for (name, args) in store.profiles()? {
+                println!("{name}: {}", args.join(" "));
+            }
+        }
+        ProfileCommand::Delete { name } => {
+            ensure!(store.delete_profile(&name)?, "no profile named {name:?}");
+            println!("deleted profile {name:?}");
+        }
+    }
+    Ok(())
+}
+
+fn db(args: DbArgs) -> Result<()> {
+    match args.command {
+        DbCommand::Export {
+            format: ExportFormat::Manifest,
+            since,
+            output,
+        } => {
+            let store = PhotoSyncStore::new(args.database_file)?;
+            let entries = store.manifest_entries(since)?;
+            match output {
+                Some(output) => write_manifest(&output, &entries)?,
+                None => write_manifest_to(io::stdout().lock(), &entries)?,
+            }
+        }
+        DbCommand::Export {
+            format: ExportFormat::Jsonl,
+            since,
+            output,
+        } => {
+            ensure!(since.is_none(), "--since only applies to manifests");
+            let store = PhotoSyncStore::new(args.database_file)?;
+            match output {
+                Some(output) => {
+                    let file = File::create(&output)
+                        .wrap_err_with(|| format!("could not create {output:?}"))?;
+                    export_jsonl(&store, BufWriter::new(file))?
+                }
+                None => export_jsonl(&store, io::stdout().lock())?,
+            }
+        }
+        DbCommand::Import { input } => match input {
+            Some(input) => {
+                let file =
+                    File::open(&input).wrap_err_with(|| format!("could not open {input:?}"))?;
+                import_jsonl(BufReader::new(file), &args.database_file)?
+            }
+            None => import_jsonl(io::stdin().lock(), &args.database_file)?,
+        },
+        // opening the store would migrate it without a backup.
+        DbCommand::Migrate { dry_run, no_backup } => {
+            migrate::migrate(&args.database_file, dry_run, no_backup)?
+        }
+        DbCommand::Stats { largest } => dbstats::print_stats(&args.database_file, largest)?,
+        DbCommand::Reindex => {
+            let store = PhotoSyncStore::new(args.database_file)?;
+            let digests = store.reindex_digests()?;
+            println!("reindexed {digests} distinct digests");
+        }
+        DbCommand::Check => dbcheck::check(&args.database_file)?,
+        DbCommand::Repair => dbcheck::repair(&args.database_file)?,
+    }
+    Ok(())
+}
+
+fn sync(args: SyncArgs) -> Result<()> {
+    run_sync(&args, &RunStats::default())
+}
+
+/// Runs a sync, counting what it does in `stats`.
+fn run_sync(args: &SyncArgs, stats: &RunStats) -> Result<()> {
+    logging::init(args.log_level, args.log_file.as_deref())
+        .wrap_err_with(|| format!("could not open the log file {:?}", args.log_file))?;
+    debug!("starting syncing with configuration: {args:?}");
+    // before any worker threads are started, so they inherit it.
+    niceness::lower_priority(args.nice, args.idle_io)?;
+
+    let started = SystemTime::now();
+
+    // overlapping runs against one database would race on its rows and on the out directory.
+    let _lock = match (&args.database_file, &args.catalogue_addr) {
+        (Some(database_file), None) if !args.ephemeral_db => {
+            let lock = LockArgs {
+                if_locked: args.if_locked,
+                force_unlock: args.force_unlock,
+            };
+            match lock.acquire(database_file) {
+                Ok(Some(lock)) => Some(lock),
+                // before the hooks run, as this run never starts.
+                Ok(None) => return Ok(()),
+                Err(e) => {
+                    report_outcome(args, Some(&e), started, stats);
+                    return Err(e);
+                }
+            }
+        }
+        _ => None,
+    };
+
+    if let Some(pre_hook) = &args.pre_hook
+        && let Err(e) = run_hook("pre-run", pre_hook, &[])
+    {
+        report_outcome(args, Some(&e), started, stats);
+        return Err(e);
+    }
+
+    let mut result = sync_with_hooks_run(args, stats);
+    report_outcome(args, result.as_ref().err(), started, stats);
+
+    if args.propagate_deletions
+        && result.is_ok()
+        && let (Some(database_file), Some(out_dir)) = (&args.database_file, &args.out_dir)
+    {
+        result = sources::sources(&args.in_dir, &args.machine_id).and_then(|sources| {
+            propagate::propagate_deletions(database_file, &sources, out_dir, args)
+        });
+    }
+
+    if args.interactive
+        && result.is_ok()
+        && let Some(database_file) = &args.database_file
+    {
+        result = sources::sources(&args.in_dir, &args.machine_id).and_then(|sources| {
+            review::review(
+                database_file,
+                &sources,
+                &args.machine_id,
+                args.out_dir.as_deref(),
+                args.hash_algo,
+            )
+        });
+    }
+
+    if let Some(post_hook) = &args.post_hook {
+        let status = if result.is_ok() { "success" } else { "failure" };
+        let hook_result = run_hook("post-run", post_hook, &[("PHOTO_SYNC_STATUS", status)]);
+        // the sync's own error is the more important one to surface.
+        if let (Err(e), Err(_)) = (&hook_result, &result) {
+            warn!("{e}");
+        } else {
+            hook_result?;
+        }
+    }
+
+    result
+}
+
+/// Writes the metrics file and JSON summary, and sends notifications, if asked for. Failing to is
+/// reported, but doesn't fail the run.
+fn report_outcome(
+    args: &SyncArgs,
+    error: Option<&eyre::Report>,
+    started: SystemTime,
+    stats: &RunStats,
+) {
+    let duration = started.elapsed().unwrap_or_default();
+    let machine_id = &args.machine_id;
+    let succeeded = error.is_none();
+    let error = error.map(|e| format!("{e:#}"));
+    let scope = SyncScope::new(args.since, args.until, &args.only_subdir);
+    if let Some(metrics_file) = &args.metrics_file
+        && let Err(e) = write_textfile(
+            metrics_file,
+            machine_id,
+            succeeded,
+            started,
+            duration,
+            stats,
+        )
+    {
+        warn!("could not write metrics to {metrics_file:?}: {e}");
+    }
+    if let Some(summary_json) = &args.summary_json
+        && let Err(e) = write_summary_json(
+            summary_json,
+            machine_id,
+            error.as_deref(),
+            started,
+            duration,
+            &scope,
+            stats,
+        )
+    {
+        warn!("could not write the run summary to {summary_json:?}: {e}");
+    }
+
+    if succeeded && args.notify_failures_only
+        || (args.notify_webhook.is_none() && args.notify_command.is_none())
+    {
+        return;
+    }
+    let summary = match summary_json(
+        machine_id,
+        error.as_deref(),
+        started,
+        duration,
+        &scope,
+        stats,
+    ) {
+        Ok(summary) => summary,
+        Err(e) => {
+            warn!("could not summarise the run to notify of it: {e}");
+            return;
+        }
+    };
+    if let Some(url) = &args.notify_webhook
+        && let Err(e) = notify::post_webhook(url, &summary)
+    {
+        warn!("could not notify the webhook: {e}");
+    }
+    if let Some(command) = &args.notify_command
+        && let Err(e) = notify::run_notify_command(command, succeeded, &summary)
+    {
+        warn!("{e}");
+    }
+}
+
+fn sync_with_hooks_run(args: &SyncArgs, stats: &RunStats) -> Result<()> {
+    if args.ephemeral_db {
+        return sync_ephemeral(args, stats);
+    }
+
+    let mut local_copy = None;
+    let mode = ExecutionMode::new(args.dry_run);
+    let store: Box<dyn Catalogue> = match (&args.database_file, &args.catalogue_addr) {
+        (_, Some(addr)) => Box::new(RemoteCatalogue::connect(addr.clone())?),
+        (Some(database_file), None) => {
+            let (store, copy) = mode.open_store(database_file, args.network_database)?;
+            store.tune(&StoreTuning {
+                cache_size_mb: args.db_cache_size_mb,
+                mmap_size_mb: args.db_mmap_size_mb,
+                temp_store: args.db_temp_store,
+                synchronous: args
+                    .db_synchronous
+                    .or(args.paranoid.then_some(Synchronous::Full)),
+            })?;
+            if args.db_trace {
+                store.trace();
+            }
+            if args.propagate_target_deletions
+                && let Some(out_dir) = &args.out_dir
+            {
+                let tombstoned =
+                    tombstones::propagate_target_deletions(&store, out_dir, args.dry_run)?;
+                if tombstoned > 0 {
+                    info!("tombstoned {tombstoned} files deleted from the out directory");
+                }
+            }
+            local_copy = copy;
+            Box::new(store)
+        }
+        (None, None) => unreachable!("clap requires a database file or catalogue address"),
+    };
+    if args.force_unlock {
+        lease::break_sync_lease(&*store, &args.machine_id)?;
+    }
+
+    let result = sync_with_store(args, stats, &*store);
+    drop(store);
+    // the copy is returned even if the run failed, as it records how far the run got.
+    let copied_back = local_copy.map(|copy| mode.finish(copy)).transpose();
+    result.and(copied_back.map(|_| ()))
+}
+
+fn sync_ephemeral(args: &SyncArgs, stats: &RunStats) -> Result<()> {
+    let store = PhotoSyncStore::new_in_memory()?;
+    if let Some(seed_manifest) = &args.seed_manifest {
+        let entries = read_manifest(seed_manifest)?;
+        store.add_manifest_entries(&entries)?;
+        info!("seeded catalogue with {} entries", entries.len());
+    }
+
+    let result = sync_with_store(args, stats, &store);
+    // written even if the run failed, as it records how far the run got.
+    let written = args
+        .write_manifest
+        .as_deref()
+        .filter(|_| !args.dry_run)
+        .map(|path| write_manifest(path, &store.manifest_entries(None)?))
+        .transpose();
+    result.and(written.map(|_| ()))
+}
+
+fn sync_with_store(args: &SyncArgs, stats: &RunStats, store: &dyn Catalogue) -> Result<()> {
+    debug!("store successfully created");
+    let resources = SyncResources::new(args)?;
+    debug!(
+        "holding at most {} files open at once",
+        resources.fds.total()
+    );
+
+    let pause = PauseControl::with_signal_handlers()?;
+    #[cfg(unix)]
+    debug!(
+        "send SIGUSR1 to process {} to pause, and SIGUSR2 to resume",
+        std::process::id()
+    );
+    if let Some(window) = args.only_between {
+        pause.watch(
+            PauseReason::OutsideWindow,
+            WINDOW_CHECK_INTERVAL,
+            move || !window.contains(Local::now().time()),
+        );
+    }
+    if args.pause_on_battery || args.min_battery_percent.is_some() {
+        // fails up front on machines it can't be told for, rather than never pausing.
+        power::power_status()?;
+        let (always, min_charge) = (args.pause_on_battery, args.min_battery_percent);
+        pause.watch(
+            PauseReason::OnBattery,
+            POWER_CHECK_INTERVAL,
+            move || match power::power_status() {
+                Ok(status) => status.is_some_and(|s| s.should_pause(always, min_charge)),
+                Err(e) => {
+                    warn!("could not tell whether on battery: {e}");
+                    false
+                }
+            },
+        );
+    }
+
+    // an archive which is already missing fails the run, while one lost partway through pauses it.
+    // a dry run writes nothing to it, so has nothing to guard.
+    if !args.dry_run
+        && let Some(out_dir) = &args.out_dir
+    {
+        let catalogued = store.has_target_files()? && !args.mark_destination;
+        let destination = Destination::open(out_dir.clone(), catalogued)?;
+        pause.watch(
+            PauseReason::DestinationUnavailable,
+            DESTINATION_CHECK_INTERVAL,
+            move || match destination.check() {
+                Ok(()) => false,
+                Err(e) => {
+                    warn!("the out directory is unavailable: {e:#}");
+                    true
+                }
+            },
+        );
+    }
+
+    let control = Control {
+        pause: &pause,
+        stats,
+        current: &resources.current,
+    };
+    let sources = sources::sources(&args.in_dir, &args.machine_id)?;
+    if args.watch && sources.len() > 1 {
+        bail!("--watch can only watch one in directory");
+    }
+    let mode = ExecutionMode::new(args.dry_run);
+    let lease_holder = lease::sync_lease_holder(&args.machine_id);
+    // a run of the whole of each in directory, or when watching, of just what changed in it.
+    let sync_run = |changed: Option<&[PathBuf]>| {
+        with_sync_lease(store, &lease_holder, || {
+            let run = store.begin_run(&args.machine_id)?;
+            info!("started run {run}");
+            let (counted_before, failed_before) = (stats.counts(), stats.failures());
+            let command_line = notify::redact_webhook(
+                std::env::args_os().map(|arg| arg.to_string_lossy().into_owned()),
+            );
+            store.record_run_args(run, &command_line.join(" "))?;
+
+            let result = sources.iter().enumerate().try_for_each(|(idx, source)| {
+                if sources.len() > 1 {
+                    info!("syncing {:?} as {:?}", source.dir, source.namespace);
+                }
+                let ctx = resources.context(store, &pause, run, args, stats, source);
+                // the old out directory is the same for every in directory.
+                run_phases(&ctx, changed, idx == 0)
+            });
+            // checked before the run is finished, so one with too many failures is recorded so, and
+            // by this run's failures alone, as a `--watch` counts them over every run.
+            let result = result.and_then(|()| {
+                let failed = stats.failures_since(&failed_before);
+                failures::check_failures(&failed, args.max_failures)
+            });
+            if !mode.is_live() {
+                info!(
+                    "dry run: would have copied {} files ({}MB), without changing the catalogue or {:?}",
+                    stats.files_transferred,
+                    stats.bytes_transferred.as_u64() / 1_000_000,
+                    resources.backend.location(Path::new(""))
+                );
+            }
+            let timings = &resources.timings;
+            timings.print(args.timing_histogram);
+            if args.db_trace {
+                dbtrace::print_summary();
+                info!(
+                    "summed across threads, {:.1}s went on hashing the old out directory and {:.1}s on transferring new files, including time in the catalogue",
+                    timings.spent(Work::Hashing).as_secs_f64(),
+                    timings.spent(Work::Transferring).as_secs_f64()
+                );
+            }
+            let status = match (&result, args.roll_back_on_abort) {
+                (Ok(()), _) => RunStatus::Succeeded,
+                (Err(_), false) => RunStatus::Aborted,
+                (Err(_), true) => match store.roll_back_run(run) {
+                    Ok(()) => RunStatus::RolledBack,
+                    Err(e) => {
+                        warn!(
+                            "could not roll back run {run}, so its rows are left in place: {e:#}"
+                        );
+                        RunStatus::Aborted
+                    }
+                },
+            };
+            store.record_run_counts(run, &stats.counts().since(counted_before))?;
+            store.finish_run(run, status)?;
+            info!("finished run {run}: {status:?}");
+            result?;
+
+            // taken while the lease is held, so no other machine's run is caught half done.
+            if let (Some(kind), Some(target)) = (args.snapshot, &args.snapshot_target)
+                && mode.is_live()
+            {
+                let snapshot = take_snapshot(kind, target, run)?;
+                store.record_snapshot(run, &snapshot)?;
+                info!("took snapshot {snapshot}");
+            }
+            Ok(())
+        })
+    };
+    with_metrics_endpoint(args.metrics_addr, &args.machine_id, stats, || {
+        with_control_socket(args.control_socket.as_deref(), control, || {
+            sync_run(None)?;
+            if args.watch {
+                watch::watch_in_dir(&sources[0].dir, &pause, |changed| sync_run(Some(changed)))?;
+            }
+            Ok(())
+        })
+    })
+}
+
+/// What a sync holds for the whole run, besides the catalogue and how it's paused.
+struct SyncResources {
+    plugin: Box<dyn SyncPlugin>,
+    transfer_log: Option<TransferLog>,
+    recipients: Vec<Recipient>,
+    chunks: Option<ChunkRepository>,
+    upload_throttle: Throttle,
+    read_throttle: Throttle,
+    timings: FileTimings,
+    claims: DigestClaims,
+    digests: DigestCache,
+    bursts: Bursts,
+    fds: FdBudget,
+    current: CurrentFiles,
+    other_algorithms: OnceLock<Vec<HashAlgorithm>>,
+    has_partial_digests: OnceLock<bool>,
+    filters: PathFilters,
+    file_filters: FileFilters,
+    transferred_sources: WriteBatch<TransferredSource>,
+    transcoder: Option<Transcoder>,
+    backend: Box<dyn TargetBackend>,
+}
+
+impl SyncResources {
+    fn new(args: &SyncArgs) -> Result<Self> {
+        // rather than quietly hashing on one thread regardless.
+        ensure!(
+            args.parallel_hash_min_size.is_none() || args.hash_algo == HashAlgorithm::Blake3,
+            "--parallel-hash-min-size needs --hash-algo blake3, as only BLAKE3 digests can be \
+             split across threads"
+        );
+        let plugin: Box<dyn SyncPlugin> = match &args.plugin {
+            Some(path) => Box::new(WasmPlugin::load(path)?),
+            None => Box::new(NoPlugin),
+        };
+        let transfer_log = args
+            .transfer_log
+            .as_deref()
+            .map(TransferLog::create)
+            .transpose()?;
+        // opening the repository creates it, which a dry run mustn't.
+        let chunks = (args.out_dir.as_deref())
+            .filter(|_| args.chunked && !args.dry_run)
+            .map(ChunkRepository::open)
+            .transpose()?;
+        Ok(Self {
+            plugin,
+            transfer_log,
+            recipients: encrypt::parse_recipients(&args.encrypt_to)?,
+            chunks,
+            upload_throttle: Throttle::new(args.max_upload_bytes_per_second),
+            read_throttle: Throttle::new(args.max_read_mbps.map(throttle::megabits_to_bytes)),
+            timings: FileTimings::default(),
+            claims: DigestClaims::default(),
+            digests: DigestCache::default(),
+            bursts: Bursts::default(),
+            fds: match args.max_open_files {
+                Some(files) => FdBudget::new(files.get()),
+                None => FdBudget::from_limit(),
+            },
+            current: CurrentFiles::default(),
+            other_algorithms: OnceLock::new(),
+            has_partial_digests: OnceLock::new(),
+            filters: PathFilters::new(&args.exclude, &args.include, !args.no_default_excludes)?,
+            file_filters: FileFilters::new(&args.extensions, args.min_size, args.max_size)
+                .modified_between(args.since, args.until),
+            transferred_sources: WriteBatch::default(),
+            transcoder: args.transcode_heic.map(|target| Transcoder {
+                target,
+                converter: match &args.heic_converter {
+                    Some(command) => Box::new(CommandConverter(command.clone())),
+                    None => Box::new(SystemConverter),
+                },
+            }),
+            backend: backend::open(args)?,
+        })
+    }
+
+    fn context<'a>(
+        &'a self,
+        store: &'a dyn Catalogue,
+        pause: &'a PauseControl,
+        run: RunId,
+        args: &'a SyncArgs,
+        stats: &'a RunStats,
+        source: &'a Source,
+    ) -> SyncContext<'a> {
+        SyncContext {
+            store,
+            plugin: &*self.plugin,
+            pause,
+            run,
+            args,
+            stats,
+            transfer_log: self.transfer_log.as_ref(),
+            recipients: &self.recipients,
+            chunks: self.chunks.as_ref(),
+            upload_throttle: &self.upload_throttle,
+            read_throttle: &self.read_throttle,
+            timings: &self.timings,
+            claims: &self.claims,
+            digests: &self.digests,
+            bursts: &self.bursts,
+            fds: &self.fds,
+            current: &self.current,
+            other_algorithms: &self.other_algorithms,
+            has_partial_digests: &self.has_partial_digests,
+            filters: &self.filters,
+            file_filters: &self.file_filters,
+            transferred_sources: &self.transferred_sources,
+            transcoder: self.transcoder.as_ref(),
+            backend: &*self.backend,
+            mode: ExecutionMode::new(args.dry_run),
+            source,
+        }
+    }
+}
+
+/// Runs the phases of a sync of `ctx`'s in directory, detecting new files among just the
+/// `changed` paths if given, and then leaving the old out directory alone. Otherwise, it's
+/// indexed first if `index_old_out_dir`.
+fn run_phases(
+    ctx: &SyncContext,
+    changed: Option<&[PathBuf]>,
+    index_old_out_dir: bool,
+) -> Result<()> {
+    if changed.is_none() {
+        if ctx.mode.is_live() {
+            journal::recover_interrupted(ctx)?;
+        }
+        // first, we make sure that the old out directory has been properly indexed,
+        // so all of its files have been hashed and recorded.
+        if index_old_out_dir {
+            timed_phase(ctx, "hashing", || {
+                in_pool(ctx.args.max_parallel_files, || {
+                    ensure_old_out_dir_properly_indexed(ctx)
+                })
+            })?;
+        }
+        // each page of downloads is synced, and so deleted, before the next is downloaded, and
+        // whatever an earlier run left is found by the walk of the whole in directory after.
+        if let SourceKind::ICloud(library) = &ctx.source.kind {
+            icloudphotos::download_new(ctx, library, |downloaded| {
+                detect_and_transfer(ctx, Some(downloaded))
+            })?;
+        }
+    }
+    detect_and_transfer(ctx, changed)
+}
+
+/// Runs phases 2 and 3 over `ctx`'s in directory, or just the `changed` paths in it if given.
+fn detect_and_transfer(ctx: &SyncContext, changed: Option<&[PathBuf]>) -> Result<()> {
+    // phases 2 and 3 run concurrently, so copying starts as soon as the first new file is
+    // found rather than once the whole source has been scanned.
+    let (new_files, new_files_rx) = mpsc::sync_channel(NEW_FILE_QUEUE_DEPTH);
+    thread::scope(|s| {
+        let detection = s.spawn(|| {
+            timed_phase(ctx, "detecting", || {
+                detect_new_files(ctx, changed, new_files)
+            })
+        });
+        let threads = [ctx.args.max_parallel_files, ctx.args.max_concurrent_uploads];
+        let transferred = timed_phase(ctx, "transferring", || {
+            in_pool(threads.into_iter().flatten().min(), || {
+                transfer::transfer_new_files(ctx, new_files_rx)
+            })
+        });
+        // if the transfer failed, detection stops as soon as it next finds a new file.
+        let detected = detection.join().expect("detection thread panicked");
+        transferred.and(detected)
+    })
+}
+
+/// Runs `work` on a pool of `threads` threads, or if `None`, rayon's global pool of one per CPU.
+fn in_pool<T: Send>(
+    threads: Option<NonZeroUsize>,
+    work: impl FnOnce() -> Result<T> + Send,
+) -> Result<T> {
+    match threads {
+        Some(threads) => rayon::ThreadPoolBuilder::new()
+            .num_threads(threads.get())
+            .build()?
+            .install(work),
+        None => work(),
+    }
+}
+
+/// Runs `phase`, adding how long it took to the run's stats, whether or not it succeeded.
+fn timed_phase<T>(ctx: &SyncContext, name: &'static str, phase: impl FnOnce() -> T) -> T {
+    let _span = info_span!("phase", name).entered();
+    let started = Instant::now();
+    let result = phase();
+    ctx.stats.record_phase(name, started.elapsed());
+    result
+}
+
+/// How often `--only-between` checks the time.
+const WINDOW_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often `--pause-on-battery` and `--min-battery-percent` check the power supply.
+const POWER_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often the out directory is checked to still be the archive, and writable.
+const DESTINATION_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How many detected files may wait for a transfer worker before detection blocks.
+const NEW_FILE_QUEUE_DEPTH: usize = 1024;
+
+/// The size from which BLAKE3 digests are taken across threads, unless
+/// `--parallel-hash-min-size` says otherwise.
+const PARALLEL_HASH_MIN_SIZE: u64 = 64 * 1024 * 1024;
+
+/// What the phases of a sync run share.
+struct SyncContext<'a> {
+    store: &'a dyn Catalogue,
+    plugin: &'a dyn SyncPlugin,
+    pause: &'a PauseControl,
+    run: RunId,
+    args: &'a SyncArgs,
+    stats: &'a RunStats,
+    transfer_log: Option<&'a TransferLog>,
+    recipients: &'a [Recipient],
+    chunks: Option<&'a ChunkRepository>,
+    /// Holds writes to the out directory to `--max-upload-bytes-per-second`.
+    upload_throttle: &'a Throttle,
+    read_throttle: &'a Throttle,
+    timings: &'a FileTimings,
+    /// Content being transferred, so that duplicates within a run are only written once.
+    claims: &'a DigestClaims,
+    /// The archive's digests, for `--cache-digests`.
+    digests: &'a DigestCache,
+    /// The best frame of each burst, for `--collapse-bursts best-only`.
+    bursts: &'a Bursts,
+    fds: &'a FdBudget,
+    /// Files being hashed or transferred, for the control socket.
+    current: &'a CurrentFiles,
+    /// The algorithms besides `--hash-algo` which archived files are catalogued by, once looked up.
+    other_algorithms: &'a OnceLock<Vec<HashAlgorithm>>,
+    /// Whether any file in the old out directory waits on a `--quick-index` fingerprint, once
+    /// looked up.
+    has_partial_digests: &'a OnceLock<bool>,
+    /// `--exclude` and `--include`.
+    filters: &'a PathFilters,
+    /// `--extensions`, `--min-size` and `--max-size`.
+    file_filters: &'a FileFilters,
+    /// Transferred files yet to be catalogued as sources, which are written a batch at a time.
+    transferred_sources: &'a WriteBatch<TransferredSource>,
+    /// `--transcode-heic`.
+    transcoder: Option<&'a Transcoder>,
+    /// Where transferred files are archived.
+    backend: &'a dyn TargetBackend,
+    mode: ExecutionMode,
+    /// The in directory being synced.
+    source: &'a Source,
+}
+
+fn ensure_old_out_dir_properly_indexed(ctx: &SyncContext) -> Result<()> {
+    info!("starting phase 1: ensuring old data hashed");
+    let old_out_dir = &ctx.args.old_out_dir;
+    let store = ctx.store;
+    let bytes_processed = SimpleAtomicU64::default();
+    let files_processed = SimpleAtomicU64::default();
+    let files_found = SimpleAtomicU64::default();
+    let progress = PhaseProgress::new(
+        "hashing",
+        &files_processed,
+        Some(&bytes_processed),
+        Some(&files_found),
+    );
+    let special = Mutex::new(Vec::new());
+    let indexed = WriteBatch::default();
+    let catalogue = |file: CataloguedFile| {
+        if let Some(batch) = indexed.push(file) {
+            store.mark_exists_in_old_target_batch(ctx.run, &batch)?;
+        }
+        Ok::<_, eyre::Error>(())
+    };
+    let index = |entry: walkdir::Result<walkdir::DirEntry>| {
+        let entry = entry?;
+        if let Some(kind) = special_kind(entry.file_type()) {
+            ensure!(
+                !ctx.args.strict,
+                "{:?} is a {kind}, not a file",
+                entry.path()
+            );
+            special.lock().unwrap().push((entry.into_path(), kind));
+            return Ok(());
+        }
+        if !entry.file_type().is_file() {
+            return Ok(());
+        }
+        let path = entry.path().strip_prefix(old_out_dir)?.to_path_buf();
+        ctx.pause.wait_if_paused()?;
+
+        files_processed.fetch_add(1);
+        progress.tick();
+        let full_path = old_out_dir.join(&path);
+        let _working = ctx.current.working_on(&full_path);
+
+        let FileInfo {
+            size,
+            modified: last_modified,
+        } = FileInfo::of(&full_path)?;
+
+        let exists_in_old_target = store.exists_in_old_target(&path, last_modified, size)?;
+        // moves within the archive keep their rows, rather than growing the catalogue.
+        let moved_from = |candidates: Vec<PathBuf>, unique: bool| {
+            let mut gone = candidates
+                .into_iter()
+                .filter(|from| !old_out_dir.join(from).exists());
+            let from = if unique {
+                renamed_from(old_out_dir, gone.collect())
+            } else {
+                gone.next()
+            };
+            let Some(from) = from else {
+                return Ok(false);
+            };
+            let moved = store.move_old_target(&from, &path, last_modified, size)?;
+            if moved {
+                info!("{from:?} was moved to {path:?}");
+            }
+            Ok::<_, eyre::Error>(moved)
+        };
+        match exists_in_old_target {
+            WasTransferredFromSourceResult::New => {
+                let candidates = store.old_target_paths_with_metadata(last_modified, size)?;
+                if moved_from(candidates, true)? {
+                    return Ok(());
+                }
+                if ctx.args.trust_size_mtime || ctx.args.quick_index {
+                    if store.is_pending_digest(&path, last_modified, size)? {
+                        return Ok(());
+                    }
+                    let partial = (ctx.args.quick_index)
+                        .then(|| partial_digest(ctx, &full_path, size))
+                        .transpose()?;
+                    store.mark_pending_digest(
+                        ctx.run,
+                        &path,
+                        last_modified,
+                        size,
+                        partial.as_ref(),
+                    )?;
+                    return Ok(());
+                }
+                let digest = timed_digest(ctx, &full_path, size, ctx.args.hash_algo)?;
+                bytes_processed.fetch_add(size);
+                ctx.stats.files_indexed.fetch_add(1);
+                ctx.stats.bytes_indexed.fetch_add(size);
+                let candidates = store.old_target_paths_with_digest(&digest)?;
+                if moved_from(candidates, false)? {
+                    return Ok(());
+                }
+                catalogue(CataloguedFile {
+                    path,
+                    mtime: last_modified,
+                    size,
+                    digest,
+                })?;
+            }
+            WasTransferredFromSourceResult::Transferred => {}
+            WasTransferredFromSourceResult::NewMetadata {
+                last_modified,
+                size,
+                digest: old_digest,
+            } => {
+                // rehashed as it was catalogued, to tell whether it changed.
+                let new_digest = timed_digest(ctx, &full_path, size, old_digest.algorithm())?;
+                if old_digest != new_digest {
+                    // another file may have been moved over this one.
+                    let candidates = store.old_target_paths_with_digest(&new_digest)?;
+                    if moved_from(candidates, false)? {
+                        return Ok(());
+                    }
+                }
+                ensure!(
+                    old_digest == new_digest,
+                    "unexpected rewrite of file {full_path:?}, digest changed"
+                );
+                bytes_processed.fetch_add(size);
+                ctx.stats.files_indexed.fetch_add(1);
+                ctx.stats.bytes_indexed.fetch_add(size);
+                catalogue(CataloguedFile {
+                    path,
+                    mtime: last_modified,
+                    size,
+                    digest: new_digest,
+                })?;
+            }
+        }
+
+        Ok::<_, eyre::Error>(())
+    };
+    // recorded only once the walk has indexed everything in the directories it signed.
+    let (mut signatures, mut counted_signatures) = if ctx.args.incremental_index {
+        let previous = ctx.store.dir_signatures()?;
+        let started = SystemTime::now();
+        (
+            Some(DirSignatures::new(previous.clone(), started)),
+            Some(DirSignatures::new(previous, started)),
+        )
+    } else {
+        (None, None)
+    };
+    let walk = walk_old_out_dir(ctx, signatures.as_mut());
+    let finished = AtomicBool::new(false);
+    let walked = thread::scope(|s| {
+        // paths are hashed as the walk finds them rather than collected up front, as an archive
+        // can hold millions of files, so they're counted for the total by a walk of their own.
+        // It's quiet, as the walk indexing them reports what it skips.
+        s.spawn(|| {
+            let count = || {
+                for entry in walk_old_out_dir(ctx, counted_signatures.as_mut()) {
+                    if finished.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    if entry.is_ok_and(|entry| entry.file_type().is_file()) {
+                        files_found.fetch_add(1);
+                    }
+                }
+            };
+            tracing::subscriber::with_default(tracing::subscriber::NoSubscriber::default(), count);
+        });
+        let walked = if ctx.args.sequential_per_device {
+            let device = |entry: &walkdir::Result<walkdir::DirEntry>| match entry {
+                Ok(entry) => devices::device_of(entry.path()),
+                Err(_) => 0,
+            };
+            devices::try_map_per_device(walk, device, |entry| index(entry).map(|()| None::<()>))
+                .map(|_| ())
+        } else {
+            walk.par_bridge().try_for_each(index)
+        };
+        finished.store(true, Ordering::Relaxed);
+        walked
+    });
+    // written even if the walk failed, so what was hashed needn't be again.
+    store.mark_exists_in_old_target_batch(ctx.run, &indexed.take())?;
+    walked?;
+    if let Some(signatures) = signatures {
+        ctx.store
+            .replace_dir_signatures(&signatures.into_signatures())?;
+    }
+
+    drop(progress);
+    report_special(&special.into_inner().unwrap());
+    info!("finished phase 1: ensuring old data hashed");
+    Ok(())
+}
+
+/// Walks the old out directory as phase 1 indexes it, leaving out what isn't archived photos and
+/// what `--exclude` does, and with `--incremental-index` the files of directories `signatures`
+/// finds unchanged.
+fn walk_old_out_dir<'a>(
+    ctx: &'a SyncContext,
+    signatures: Option<&'a mut DirSignatures>,
+) -> impl Iterator<Item = walkdir::Result<walkdir::DirEntry>> + Send + 'a {
+    let old_out_dir = &ctx.args.old_out_dir;
+    let policy = ctx.args.symlinks;
+    let walk = symlinks::walk(old_out_dir, policy)
+        .into_iter()
+        .filter_entry(move |entry| {
+            let is_dir = entry.file_type().is_dir();
+            !trash::is_trash(entry)
+                && !destination::is_marker(entry)
+                && !chunks::is_repository(entry)
+                && symlinks::admits(entry, policy, old_out_dir)
+                && (entry.path().strip_prefix(old_out_dir))
+                    .is_ok_and(|path| ctx.filters.admits(path, is_dir))
+        })
+        .filter_map(symlinks::skip_unwalkable);
+    incremental::leave_out_unchanged(signatures, old_out_dir, walk)
+}
+
+/// Hashes `path`, of `size` bytes, by `algorithm`, recording how long it took.
+fn timed_digest(
+    ctx: &SyncContext,
+    path: &Path,
+    size: u64,
+    algorithm: HashAlgorithm,
+) -> Result<ContentHash> {
+    if !ctx.mode.is_live() {
+        info!("would hash {path:?} ({size} bytes)");
+    }
+    let _files = ctx.fds.acquire(1);
+    let started = Instant::now();
+    let mut reader = ThrottledReader::new(File::open(path)?, ctx.read_throttle);
+    let parallel = algorithm == HashAlgorithm::Blake3
+        && size >= (ctx.args.parallel_hash_min_size).unwrap_or(PARALLEL_HASH_MIN_SIZE);
+    let digest = if parallel {
+        algorithm.digest_reader_parallel(&mut reader)?
+    } else {
+        algorithm.digest_reader(&mut reader)?
+    };
+    ctx.timings
+        .record(Work::Hashing, path, size, started.elapsed());
+    Ok(digest)
+}
+
+/// The `--quick-index` fingerprint of the file in the old out directory at `path`.
+fn partial_digest(ctx: &SyncContext, path: &Path, size: u64) -> Result<PartialDigest> {
+    let _files = ctx.fds.acquire(1);
+    let started = Instant::now();
+    let partial = PartialDigest::of(
+        &mut ThrottledReader::new(File::open(path)?, ctx.read_throttle),
+        size,
+    )?;
+    ctx.timings
+        .record(Work::Hashing, path, size, started.elapsed());
+    Ok(partial)
+}
+
+/// Hashes in full the files in the old out directory catalogued by `--quick-index` with the same
+/// fingerprint as `staged`, a new file, so that it's deduplicated against them.
+fn promote_partial_digests(ctx: &SyncContext, staged: &Path, size: u64) -> Result<()> {
+    let has_partial_digests = match ctx.has_partial_digests.get() {
+        Some(has) => *has,
+        None => {
+            let has = ctx.store.has_partial_digests()?;
+            *ctx.has_partial_digests.get_or_init(|| has)
+        }
+    };
+    if !has_partial_digests {
+        return Ok(());
+    }
+    let partial = PartialDigest::of_file(staged)?;
+    for path in ctx
+        .store
+        .pending_paths_with_partial_digest(size, &partial)?
+    {
+        let full_path = ctx.args.old_out_dir.join(&path);
+        // gone since, which `hash-pending` will notice.
+        let Ok(info) = FileInfo::of(&full_path) else {
+            continue;
+        };
+        let digest = timed_digest(ctx, &full_path, info.size, ctx.args.hash_algo)?;
+        debug!("hashed {full_path:?} in full, as a new file shares its fingerprint");
+        ctx.store
+            .mark_exists_in_old_target(ctx.run, &path, info.modified, info.size, &digest)?;
+    }
+    Ok(())
+}
+
+/// Reports the special files a walk skipped.
+fn report_special(special: &[(PathBuf, &str)]) {
+    if special.is_empty() {
+        return;
+    }
+    for (path, kind) in special {
+        warn!("skipped {path:?}, a {kind}, which has no content to sync");
+    }
+}
+
+/// Whether the source file at `path` has been transferred as it is now.
+fn source_is_transferred(ctx: &SyncContext, path: &Path) -> Result<bool> {
+    let info = FileInfo::of(&ctx.source.dir.join(path))?;
+    Ok(ctx.store.was_transferred_from_source(
+        &ctx.source.namespace,
+        path,
+        info.modified,
+        info.size,
+    )? == WasTransferredFromSourceResult::Transferred)
+}
+
+/// The catalogued path a new source file was renamed from, if exactly one of the catalogued files
+/// with its metadata, `candidates`, has gone from `in_dir`.
+fn renamed_from(in_dir: &Path, candidates: Vec<PathBuf>) -> Option<PathBuf> {
+    let mut gone = candidates
+        .into_iter()
+        .filter(|path| !in_dir.join(path).exists());
+    let from = gone.next()?;
+    // with several, which became which can't be told from metadata alone.
+    gone.next().is_none().then_some(from)
+}
+
+/// Finds the new files in the in directory, or only among the `changed` paths in it, if given.
+fn detect_new_files(
+    ctx: &SyncContext,
+    changed: Option<&[PathBuf]>,
+    new_files: SyncSender<PathBuf>,
+) -> Result<()> {
+    info!("starting phase 2: detecting new files");
+    let in_dir = &ctx.source.dir;
+    let mut failures = Vec::new();
+    let files_processed = SimpleAtomicU64::default();
+    let progress = PhaseProgress::new("detecting", &files_processed, None, None);
+    let mut rejected = 0usize;
+    let mut plugin_failed = 0u64;
+    let mut companions = 0usize;
+    let mut live_videos = 0usize;
+    let mut sidecars = 0usize;
+    let mut renamed = 0usize;
+    let mut too_small = Vec::new();
+    // new files held back to be sorted, when they're not transferred as they're found.
+    let mut held = Vec::new();
+    // priority directories are walked first, and skipped when walking the rest. One within
+    // another is pruned from its walk, so it's only a directory given twice which is dropped.
+    let mut priority_dirs: Vec<PathBuf> = Vec::new();
+    if changed.is_none() {
+        for dir in ctx.args.priority_dir.iter().map(|dir| in_dir.join(dir)) {
+            if !priority_dirs.contains(&dir) {
+                priority_dirs.push(dir);
+            }
+        }
+    }
+    for dir in &priority_dirs {
+        ensure!(dir.is_dir(), "priority directory {dir:?} doesn't exist");
+    }
+    // `--only-subdir` walks just those directories, rather than the whole in directory.
+    let only_subdirs: BTreeSet<PathBuf> = (ctx.args.only_subdir.iter())
+        .map(|dir| in_dir.join(dir))
+        .collect();
+    for dir in &only_subdirs {
+        ensure!(
+            dir.is_dir(),
+            "directory {dir:?} given to --only-subdir doesn't exist"
+        );
+    }
+    let only_subdirs = watch::outermost(only_subdirs);
+    let in_scope = |path: &Path| {
+        only_subdirs.is_empty() || only_subdirs.iter().any(|dir| path.starts_with(dir))
+    };
+    let scope = SyncScope::new(ctx.args.since, ctx.args.until, &ctx.args.only_subdir);
+    if !scope.is_everything() {
+        info!("only looking for new files {scope}");
+    }
+    let ignores = SyncIgnores::new(in_dir);
+    let mut excluded = 0usize;
+    let mut filtered = 0usize;
+    let (mut wrong_extension, mut wrong_size, mut wrong_time) = (0usize, 0usize, 0usize);
+    let admitted = |path: &Path, is_dir| {
+        path.strip_prefix(in_dir)
+            .is_ok_and(|path| ctx.filters.admits(path, is_dir))
+    };
+    let policy = ctx.args.symlinks;
+    let mut preserved = 0usize;
+    let mut special = Vec::new();
+    let mut not_downloaded = 0usize;
+    // files review set aside are never transferred.
+    let set_aside: HashSet<PathBuf> = (ctx.store.review_decisions(&ctx.source.namespace)?)
+        .into_iter()
+        .filter(|(_, decision)| *decision != ReviewDecision::Retransfer)
+        .map(|(path, _)| in_dir.join(path))
+        .collect();
+    let mut reviewed = 0usize;
+    // as are those `ignore add` listed.
+    let ignore_list = IgnoreList::new(&ctx.store.ignored_paths()?)?;
+    let is_listed = |path: &Path| {
+        path.strip_prefix(in_dir)
+            .is_ok_and(|path| ignore_list.is_ignored(path))
+    };
+    let mut listed = 0usize;
+    // and those left out for their kind before, while it's still left out and they're unchanged.
+    let left_out_classes: Vec<&str> = [
+        ("tombstoned", true),
+        ("screenshot", ctx.args.skip_screenshots),
+        ("burst", ctx.args.collapse_bursts == BurstPolicy::BestOnly),
+    ]
+    .into_iter()
+    .filter_map(|(class, left_out)| left_out.then_some(class))
+    .collect();
+    let classified_out: HashMap<PathBuf, ClassifiedOut> =
+        (ctx.store.classified_out(&ctx.source.namespace)?)
+            .into_iter()
+            .filter(|file| left_out_classes.contains(&file.class.as_str()))
+            .map(|file| (file.path.clone(), file))
+            .collect();
+    let mut classified = 0usize;
+    // a file only waits to go along with another which this run will transfer, as otherwise it
+    // would wait for good.
+    let will_transfer = |other: &Path| -> Result<bool> {
+        let full_path = in_dir.join(other);
+        if ignores.is_ignored(&full_path, false)
+            || !admitted(&full_path, false)
+            || set_aside.contains(&full_path)
+            || is_listed(&full_path)
+        {
+            return Ok(false);
+        }
+        let FileInfo { size, modified } = FileInfo::of(&full_path)?;
+        if ctx.file_filters.leaves_out(other, size, modified).is_some()
+            || (classified_out.get(other)).is_some_and(|file| file.is_current(modified, size))
+            || (!ctx.args.include_small_files && media::is_implausibly_small(other, size))
+        {
+            return Ok(false);
+        }
+        let wanted = match ctx.store.was_transferred_from_source(
+            &ctx.source.namespace,
+            other,
+            modified,
+            size,
+        )? {
+            WasTransferredFromSourceResult::New => true,
+            WasTransferredFromSourceResult::NewMetadata { .. } => ctx.args.keep_versions,
+            WasTransferredFromSourceResult::Transferred => false,
+        };
+        // one the plugin fails for is left to be found, and to fail, on its own.
+        Ok(wanted && ctx.plugin.accept(other).unwrap_or(false))
+    };
+    // files which failed to transfer last time are retried first, and skipped when walking the
+    // rest.
+    let mut retried = Vec::new();
+    if changed.is_none() {
+        for path in ctx.store.failed_transfers(&ctx.source.namespace)? {
+            let full_path = in_dir.join(&path);
+            if set_aside.contains(&full_path) {
+                continue;
+            }
+            if !in_scope(&full_path) {
+                continue;
+            }
+            if full_path.is_file() && !ignore_list.is_ignored(&path) {
+                retried.push(full_path);
+            } else {
+                ctx.store
+                    .forget_failed_transfer(&ctx.source.namespace, &path)?;
+            }
+        }
+    }
+    let retried_files: HashSet<&Path> = retried.iter().map(PathBuf::as_path).collect();
+    // the walk only checks what's below each root against the ignore files.
+    let unignored = |path: &&PathBuf| {
+        !path
+            .ancestors()
+            .take_while(|dir| *dir != in_dir)
+            .any(|p| ignores.is_ignored(p, p.is_dir()) || !admitted(p, p.is_dir()))
+    };
+    let roots: Vec<PathBuf> = match changed {
+        Some(changed) => (changed.iter().filter(unignored))
+            .filter(|path| !set_aside.contains(*path) && !is_listed(path) && in_scope(path))
+            .cloned()
+            .collect(),
+        None if !only_subdirs.is_empty() => {
+            let priority_dirs: Vec<&PathBuf> =
+                priority_dirs.iter().filter(|dir| in_scope(dir)).collect();
+            // a subdirectory given which is a priority directory has been walked already.
+            (retried.iter().filter(unignored))
+                .chain(priority_dirs.iter().copied())
+                .chain(
+                    only_subdirs
+                        .iter()
+                        .filter(|subdir| !priority_dirs.contains(subdir)),
+                )
+                .cloned()
+                .collect()
+        }
+        None => (retried.iter().filter(unignored))
+            .chain(&priority_dirs)
+            .chain([in_dir])
+            .cloned()
+            .collect(),
+    };
+    for root in &roots {
+        let walk = symlinks::walk(root, policy)
+            .into_iter()
+            .filter_entry(|entry| {
+                if entry.path() == root {
+                    return true;
+                }
+                if !symlinks::admits(entry, policy, in_dir) {
+                    return false;
+                }
+                // the rules themselves aren't photos.
+                if entry.file_name() == SYNCIGNORE {
+                    return false;
+                }
+                if ignores.is_ignored(entry.path(), entry.file_type().is_dir()) {
+                    excluded += 1;
+                    return false;
+                }
+                if !admitted(entry.path(), entry.file_type().is_dir()) {
+                    filtered += 1;
+                    return false;
+                }
+                if set_aside.contains(entry.path()) {
+                    reviewed += 1;
+                    return false;
+                }
+                if is_listed(entry.path()) {
+                    listed += 1;
+                    return false;
+                }
+                !priority_dirs.iter().any(|dir| dir == entry.path())
+                    && !retried_files.contains(entry.path())
+            });
+        for path in walk.filter_map(symlinks::skip_unwalkable) {
+            ctx.pause.wait_if_paused()?;
+            let path = path?;
+            if path.file_type().is_dir() {
+                continue;
+            }
+            if policy == SymlinkPolicy::Preserve && path.path_is_symlink() {
+                let link = path.path().strip_prefix(in_dir)?;
+                if !ctx.mode.is_live() {
+                    info!("would recreate the link {link:?} in the out directory");
+                } else if let Some(out_dir) = &ctx.args.out_dir {
+                    let failed = Cell::new(false);
+                    let destination = |p: &Path| {
+                        let destination = ctx.plugin.destination(p);
+                        failed.set(destination.is_err());
+                        destination
+                    };
+                    match symlinks::preserve(in_dir, link, out_dir, destination) {
+                        Ok(true) => preserved += 1,
+                        Ok(false) => {}
+                        Err(e) if failed.get() => {
+                            warn!("the plugin failed for {link:?}: {e:#}");
+                            plugin_failed += 1;
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+                continue;
+            }
+            if let Some(kind) = special_kind(path.file_type()) {
+                ensure!(
+                    !ctx.args.strict,
+                    "{:?} is a {kind}, not a file",
+                    path.path()
+                );
+                special.push((path.into_path(), kind));
+                continue;
+            }
+            // an older macOS's stand-in for a file only in iCloud, which is found once it's
+            // downloaded.
+            if let Some(target) = icloud::stub_target(path.path()) {
+                if ctx.args.placeholders == PlaceholderPolicy::Download
+                    && ctx.mode.is_live()
+                    && let Err(e) = icloud::request_download(&target)
+                {
+                    warn!("could not download {target:?}: {e}");
+                }
+                not_downloaded += 1;
+                continue;
+            }
+            let FileInfo {
+                size,
+                modified: last_modified,
+            } = FileInfo::from_metadata(&path.metadata()?)?;
+            let path = path.path().strip_prefix(in_dir)?.to_path_buf();
+            // these go wherever their data file goes, if anywhere.
+            if ctx.args.apple_double != AppleDoublePolicy::Independent
+                && let Some(data_file) = appledouble::data_file_of(&path)
+                && in_dir.join(data_file).is_file()
+            {
+                companions += 1;
+                continue;
+            }
+            // partners are left out as any other file would be, rather than going along.
+            match ctx.file_filters.leaves_out(&path, size, last_modified) {
+                Some(LeftOut::Extension) => {
+                    wrong_extension += 1;
+                    continue;
+                }
+                Some(LeftOut::Size) => {
+                    wrong_size += 1;
+                    continue;
+                }
+                Some(LeftOut::Modified) => {
+                    wrong_time += 1;
+                    continue;
+                }
+                None => {}
+            }
+            // a Live Photo's video goes with its still, unless the still isn't to be transferred.
+            if let Some(still) = livephoto::still_of(in_dir, &path)
+                && will_transfer(&still)?
+            {
+                live_videos += 1;
+                continue;
+            }
+            // as is a sidecar with the file it belongs to.
+            if let Some(parent) = sidecar::parent_of(in_dir, &path)
+                && will_transfer(&parent)?
+            {
+                sidecars += 1;
+                continue;
+            }
+            match ctx.plugin.accept(&path) {
+                Ok(true) => {}
+                Ok(false) => {
+                    rejected += 1;
+                    continue;
+                }
+                Err(e) => {
+                    warn!("the plugin failed for {path:?}: {e:#}");
+                    plugin_failed += 1;
+                    continue;
+                }
+            }
+            if classified_out
+                .get(&path)
+                .is_some_and(|file| file.is_current(last_modified, size))
+            {
+                classified += 1;
+                continue;
+            }
+            match ctx.store.was_transferred_from_source(
+                &ctx.source.namespace,
+                &path,
+                last_modified,
+                size,
+            )? {
+                WasTransferredFromSourceResult::New => {
+                    let candidates = ctx.store.source_paths_with_metadata(
+                        &ctx.source.namespace,
+                        last_modified,
+                        size,
+                    )?;
+                    if let Some(from) = renamed_from(in_dir, candidates)
+                        && ctx.store.rename_source(
+                            ctx.run,
+                            &ctx.source.namespace,
+                            &from,
+                            &path,
+                            last_modified,
+                            size,
+                        )?
+                    {
+                        info!("{from:?} was renamed to {path:?}");
+                        renamed += 1;
+                    } else if !ctx.args.include_small_files
+                        && media::is_implausibly_small(&path, size)
+                    {
+                        too_small.push((path, size));
+                    } else {
+                        debug!("{path:?} is new");
+                        ctx.stats.files_detected.fetch_add(1);
+                        if ctx.args.order.is_some() {
+                            held.push(NewFile {
+                                path,
+                                size,
+                                modified: last_modified,
+                            });
+                        } else if new_files.send(path).is_err() {
+                            // the transfer has given up, and will report why.
+                            return Ok(());
+                        }
+                    }
+                }
+                WasTransferredFromSourceResult::Transferred => {}
+                WasTransferredFromSourceResult::NewMetadata { .. } if ctx.args.keep_versions => {
+                    debug!("{path:?} has changed, so is transferred as a new version");
+                    ctx.stats.files_detected.fetch_add(1);
+                    if ctx.args.order.is_some() {
+                        held.push(NewFile {
+                            path,
+                            size,
+                            modified: last_modified,
+                        });
+                    } else if new_files.send(path).is_err() {
+                        return Ok(());
+                    }
+                }
+                WasTransferredFromSourceResult::NewMetadata {
+                    last_modified: old_last_modified,
+                    size: old_size,
+                    ..
+                } => {
+                    warn!(
+                        "file {path:?} was already transferred but with a different size ({old_size} vs {size}) or last modified ({old_last_modified:?} vs {last_modified:?}). skipping for manual intervention."
+                    );
+                    failures.push(path);
+                }
+            }
+            files_processed.fetch_add(1);
+            progress.tick();
+        }
+        if let Some(order) = ctx.args.order {
+            order::sort(&mut held, order);
+            for file in held.drain(..) {
+                if new_files.send(file.path).is_err() {
+                    return Ok(());
+                }
+            }
+        }
+    }
+    if rejected > 0 {
+        info!("{rejected} files were rejected by the plugin");
+    }
+    report_special(&special);
+    if preserved > 0 {
+        info!("{preserved} links were recreated in the out directory");
+    }
+    if excluded > 0 {
+        info!("{excluded} files and directories were excluded by {SYNCIGNORE} files");
+    }
+    if filtered > 0 {
+        info!("{filtered} files and directories were left out by --exclude and --include");
+    }
+    if reviewed > 0 {
+        info!("{reviewed} files were left out as review decided to ignore or quarantine them");
+    }
+    if listed > 0 {
+        info!("{listed} files and directories were left out by the ignore list");
+    }
+    if classified > 0 {
+        info!(
+            "{classified} screenshots, burst frames and tombstoned files were left out, as they \
+             were before"
+        );
+    }
+    if wrong_extension > 0 {
+        info!("{wrong_extension} files were left out by --extensions");
+    }
+    if wrong_size > 0 {
+        info!("{wrong_size} files were left out by --min-size and --max-size");
+    }
+    if wrong_time > 0 {
+        info!("{wrong_time} files were left out by --since and --until");
+    }
+    if renamed > 0 {
+        info!("{renamed} files were renamed in the source");
+    }
+    if companions > 0 {
+        info!(
+            "{companions} AppleDouble files were handled as {:?}",
+            ctx.args.apple_double
+        );
+    }
+    if live_videos > 0 {
+        info!("{live_videos} Live Photo videos are transferred along with their stills");
+    }
+    if sidecars > 0 {
+        info!("{sidecars} sidecars are transferred along with the files they belong to");
+    }
+    if not_downloaded > 0 {
+        info!("{not_downloaded} files are only in iCloud, so are left for a later run");
+        ctx.stats
+            .files_not_downloaded
+            .fetch_add(not_downloaded as u64);
+    }
+    // counted as failed, for `--max-failures`, as files which couldn't be transferred are.
+    for (kind, files) in [
+        (FailureKind::ChangedWhileCopying, failures.len() as u64),
+        (FailureKind::Corrupt, too_small.len() as u64),
+        (FailureKind::Hook, plugin_failed),
+    ] {
+        if files > 0 {
+            ctx.stats.files_failed.fetch_add(files);
+            ctx.stats.record_failures(kind, files);
+            ctx.store.record_run_failures(ctx.run, kind, files)?;
+        }
+    }
+    ctx.stats
+        .files_metadata_changed
+        .fetch_add(failures.len() as u64);
+    if !failures.is_empty() {
+        warn!(
+            "{} files have changed metadata since they were transferred",
+            failures.len()
+        );
+    }
+    for (path, size) in too_small {
+        warn!(
+            "not transferring {path:?}: at {size} bytes it's empty or too small for its type, \
+             most likely a failed download to fetch again"
+        );
+    }
+    info!("finished phase 2: detecting new files");
+    Ok(())
+}
@@ -0,0 +1,99 @@
+//! `--dedupe-mode`: putting new files whose content is already archived in the out directory as
+//! links to the archived copy, so the in directory's folders are mirrored without storing the
+//! same bytes twice.
+
+use std::{fs, io, path::Path};
+
+use clap::ValueEnum;
+
+use crate::platform::set_archive_permissions;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum DedupeMode {
+    /// Leave duplicates out of the out directory.
+    #[default]
+    Skip,
+    /// Hardlink duplicates to the archived copy, if it's on the same filesystem.
+    Hardlink,
+    /// Clone the archived copy, sharing its blocks, on filesystems which support it, e.g. APFS,
+    /// btrfs and XFS.
+    Reflink,
+}
+
+impl DedupeMode {
+    /// Creates `link` as a link to `existing`.
+    pub fn link(self, existing: &Path, link: &Path) -> io::Result<()> {
+        match self {
+            Self::Skip => Ok(()),
+            Self::Hardlink => fs::hard_link(existing, link),
+            Self::Reflink => {
+                reflink(existing, link)?;
+                set_archive_permissions(link)
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn reflink(existing: &Path, link: &Path) -> io::Result<()> {
+    use std::{fs::File, os::fd::AsRawFd};
+
+    let from = File::open(existing)?;
+    let to = File::options().write(true).create_new(true).open(link)?;
+    // SAFETY: both descriptors are open for the duration of the call.
+    if unsafe { libc::ioctl(to.as_raw_fd(), libc::FICLONE, from.as_raw_fd()) } != 0 {
+        let e = io::Error::last_os_error();
+        // the empty file created to clone into.
+        fs::remove_file(link)?;
+        return Err(e);
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+fn reflink(existing: &Path, link: &Path) -> io::Result<()> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let from = CString::new(existing.as_os_str().as_bytes())?;
+    let to = CString::new(link.as_os_str().as_bytes())?;
+    // SAFETY: both are valid NUL-terminated paths.
+    if unsafe { libc::clonefile(from.as_ptr(), to.as_ptr(), 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn reflink(_existing: &Path, _link: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "files can't be cloned on this platform",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::platform::same_file;
+
+    #[test]
+    fn duplicates_are_linked_to_the_archived_copy() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name);
+        fs::write(path("archived.jpg"), "photo").unwrap();
+
+        DedupeMode::Hardlink
+            .link(&path("archived.jpg"), &path("hardlink.jpg"))
+            .unwrap();
+        assert_ne!(
+            same_file(&path("archived.jpg"), &path("hardlink.jpg")).unwrap(),
+            Some(false)
+        );
+
+        // temporary directories are rarely on filesystems which clone.
+        match DedupeMode::Reflink.link(&path("archived.jpg"), &path("reflink.jpg")) {
+            Ok(()) => assert_eq!(fs::read(path("reflink.jpg")).unwrap(), b"photo"),
+            Err(_) => assert!(!path("reflink.jpg").exists()),
+        }
+    }
+}
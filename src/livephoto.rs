@@ -0,0 +1,82 @@
+//! Live Photos, which iCloud downloads as a still image and a short video beside it with the same
+//! name, e.g. `IMG_1234.HEIC` and `IMG_1234.MOV`. The video is transferred along with its still,
+//! into the same directory, so the two aren't separated.
+
+use std::path::{Path, PathBuf};
+
+const STILL_EXTENSIONS: [&str; 3] = ["heic", "jpg", "jpeg"];
+const VIDEO_EXTENSIONS: [&str; 1] = ["mov"];
+
+/// The video of the Live Photo whose still is `path` in `in_dir`, if it is one.
+pub fn video_of(in_dir: &Path, path: &Path) -> Option<PathBuf> {
+    has_extension(path, &STILL_EXTENSIONS)
+        .then(|| sibling(in_dir, path, &VIDEO_EXTENSIONS))
+        .flatten()
+}
+
+/// The still of the Live Photo whose video is `path` in `in_dir`, if it is one.
+pub fn still_of(in_dir: &Path, path: &Path) -> Option<PathBuf> {
+    has_extension(path, &VIDEO_EXTENSIONS)
+        .then(|| sibling(in_dir, path, &STILL_EXTENSIONS))
+        .flatten()
+}
+
+fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| extensions.contains(&e.to_lowercase().as_str()))
+}
+
+/// The file beside `path` with the same stem and one of `extensions`, trying the case of its own
+/// extension first, so that a case-insensitive filesystem gives the name as iCloud wrote it.
+fn sibling(in_dir: &Path, path: &Path, extensions: &[&str]) -> Option<PathBuf> {
+    let uppercase = path
+        .extension()?
+        .to_str()?
+        .chars()
+        .all(|c| c.is_ascii_uppercase());
+    extensions
+        .iter()
+        .flat_map(|e| {
+            let cases = [e.to_uppercase(), e.to_string()];
+            if uppercase {
+                cases
+            } else {
+                let [upper, lower] = cases;
+                [lower, upper]
+            }
+        })
+        .map(|e| path.with_extension(e))
+        .find(|candidate| in_dir.join(candidate).is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn stills_and_videos_find_each_other() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in [
+            "IMG_1.HEIC",
+            "IMG_1.MOV",
+            "img_2.jpg",
+            "img_2.mov",
+            "IMG_3.HEIC",
+            "clip.MOV",
+        ] {
+            fs::write(dir.path().join(name), name).unwrap();
+        }
+        let video = |path: &str| video_of(dir.path(), Path::new(path));
+        let still = |path: &str| still_of(dir.path(), Path::new(path));
+
+        assert_eq!(video("IMG_1.HEIC"), Some(PathBuf::from("IMG_1.MOV")));
+        assert_eq!(still("IMG_1.MOV"), Some(PathBuf::from("IMG_1.HEIC")));
+        assert_eq!(video("img_2.jpg"), Some(PathBuf::from("img_2.mov")));
+        assert_eq!(video("IMG_3.HEIC"), None);
+        assert_eq!(still("clip.MOV"), None);
+        assert_eq!(video("IMG_1.MOV"), None);
+    }
+}
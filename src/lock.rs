@@ -0,0 +1,205 @@
+use std::{
+    fs::{self, File, OpenOptions, TryLockError},
+    io,
+    path::{Path, PathBuf},
+};
+
+use clap::{Args, ValueEnum};
+use eyre::{Result, bail, eyre};
+use tracing::{info, warn};
+
+/// What a sync, or a command changing data, does when another is already using the same database.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum LockedPolicy {
+    /// Fail.
+    #[default]
+    Fail,
+    /// Say a sync is already in progress and exit successfully, e.g. when run by cron.
+    Exit,
+    /// Wait for the other sync to finish.
+    Wait,
+}
+
+/// How a command changing the catalogue or the archive takes the database's lock.
+#[derive(Args, Clone, Copy, Debug)]
+pub struct LockArgs {
+    /// What to do if a sync, or another command changing data, is already using the database.
+    #[clap(
+        long,
+        env = "PHOTO_SYNC_IF_LOCKED",
+        value_enum,
+        default_value_t = LockedPolicy::Fail
+    )]
+    pub if_locked: LockedPolicy,
+    /// Remove the database's lock file before taking the lock, e.g. one left by another user which
+    /// can't be opened. Refused while another instance holds the lock.
+    #[clap(long, env = "PHOTO_SYNC_FORCE_UNLOCK")]
+    pub force_unlock: bool,
+}
+
+impl LockArgs {
+    /// Takes the lock on `database_file`, first breaking it if forced. Gives `None` if the
+    /// command is to exit.
+    pub fn acquire(&self, database_file: &Path) -> Result<Option<InstanceLock>> {
+        if self.force_unlock {
+            force_unlock(database_file)?;
+        }
+        InstanceLock::acquire_or(database_file, self.if_locked)
+    }
+}
+
+/// An exclusive lock on a database, held by a running sync, or a command changing the catalogue or
+/// the archive, for as long as this is alive. The OS
+/// releases it if the process dies, so a crashed run never leaves it stale.
+pub struct InstanceLock {
+    _file: File,
+}
+
+impl InstanceLock {
+    pub fn acquire(database_file: &Path) -> Result<Self> {
+        let lock = Self::acquire_or(database_file, LockedPolicy::Fail)?;
+        Ok(lock.expect("only exiting gives no lock"))
+    }
+
+    /// Takes the lock, doing as `policy` says if another sync holds it. Gives `None` if the sync
+    /// is to exit.
+    pub fn acquire_or(database_file: &Path, policy: LockedPolicy) -> Result<Option<Self>> {
+        let lock_path = lock_path(database_file);
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&lock_path)?;
+        match (file.try_lock(), policy) {
+            (Ok(()), _) => Ok(Some(Self { _file: file })),
+            (Err(TryLockError::WouldBlock), LockedPolicy::Fail) => bail!(
+                "another instance is already using {database_file:?} (lock held on {lock_path:?})"
+            ),
+            (Err(TryLockError::WouldBlock), LockedPolicy::Exit) => {
+                info!("{database_file:?} is already in use, so exiting");
+                Ok(None)
+            }
+            (Err(TryLockError::WouldBlock), LockedPolicy::Wait) => {
+                info!("{database_file:?} is already in use, waiting for it to be free");
+                file.lock()?;
+                Ok(Some(Self { _file: file }))
+            }
+            (Err(TryLockError::Error(e)), _) => Err(e.into()),
+        }
+    }
+}
+
+/// Removes the lock file of `database_file`, e.g. one left by another user which can't be opened
+/// to lock. Refused while another instance holds the lock, as removing the file wouldn't stop it,
+/// and the next run would lock a new file and use the database alongside it.
+pub fn force_unlock(database_file: &Path) -> Result<()> {
+    let lock_path = lock_path(database_file);
+    let file = match File::open(&lock_path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => {
+            return Err(eyre!(e).wrap_err(format!(
+                "could not check whether the lock file {lock_path:?} is held"
+            )));
+        }
+    };
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => bail!(
+            "another instance is still using {database_file:?}, so its lock can't be forced (lock \
+             held on {lock_path:?})"
+        ),
+        Err(TryLockError::Error(e)) => return Err(e.into()),
+    }
+    // removed while it's held, so no other instance can take it in between.
+    fs::remove_file(&lock_path)?;
+    warn!("removed the lock file {lock_path:?}");
+    Ok(())
+}
+
+fn lock_path(database_file: &Path) -> PathBuf {
+    let mut path = database_file.as_os_str().to_owned();
+    path.push(".lock");
+    path.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn second_lock_fails_until_first_is_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let database_file = dir.path().join("db.sqlite");
+
+        let lock = InstanceLock::acquire(&database_file).unwrap();
+        let err = InstanceLock::acquire(&database_file).err().unwrap();
+        assert!(err.to_string().contains("already using"));
+
+        let exited = InstanceLock::acquire_or(&database_file, LockedPolicy::Exit).unwrap();
+        assert!(exited.is_none());
+
+        let _waited = std::thread::scope(|s| {
+            let waiter =
+                s.spawn(|| InstanceLock::acquire_or(&database_file, LockedPolicy::Wait).unwrap());
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            assert!(!waiter.is_finished());
+            drop(lock);
+            waiter.join().unwrap().unwrap()
+        });
+        assert!(InstanceLock::acquire(&database_file).is_err());
+    }
+
+    #[test]
+    fn held_locks_cannot_be_forced() {
+        let dir = tempfile::tempdir().unwrap();
+        let database_file = dir.path().join("db.sqlite");
+        force_unlock(&database_file).unwrap();
+
+        let lock = InstanceLock::acquire(&database_file).unwrap();
+        let err = force_unlock(&database_file).unwrap_err();
+        assert!(err.to_string().contains("still using"), "{err}");
+        // so the lock still keeps others out.
+        assert!(InstanceLock::acquire(&database_file).is_err());
+
+        drop(lock);
+        force_unlock(&database_file).unwrap();
+        assert!(!lock_path(&database_file).exists());
+        InstanceLock::acquire(&database_file).unwrap();
+    }
+
+    #[test]
+    fn commands_which_change_data_wait_their_turn() {
+        let dir = tempfile::tempdir().unwrap();
+        let database_file = dir.path().join("db.sqlite");
+        let run = |args: &[&str]| {
+            let database = format!("--database-file={}", database_file.display());
+            let (command, args) = args.split_first().unwrap();
+            let args = ["photo-sync", command, &database]
+                .into_iter()
+                .chain(args.iter().copied());
+            crate::run(args.map(|arg| arg.into()).collect())
+        };
+        run(&["db", "reindex"]).unwrap();
+
+        let _sync = InstanceLock::acquire(&database_file).unwrap();
+        for args in [
+            &["dedupe"][..],
+            &["hash-pending", "--old-out-dir=old"],
+            &["prune", "--old-out-dir=old", "--delete-stale"],
+            &["tombstone", "remove", &"0".repeat(64)],
+            &["ignore", "add", "a.jpg"],
+            &["db", "reindex"],
+        ] {
+            let err = run(args).err().unwrap();
+            assert!(err.to_string().contains("already using"), "{args:?}: {err}");
+        }
+        run(&["dedupe", "--if-locked=exit"]).unwrap();
+        let err = run(&["db", "--force-unlock", "reindex"]).err().unwrap();
+        assert!(err.to_string().contains("still using"), "{err}");
+        // whereas reports only read, so can run alongside.
+        run(&["dedupe", "--dry-run"]).unwrap();
+        run(&["ignore", "list"]).unwrap();
+        run(&["history"]).unwrap();
+    }
+}
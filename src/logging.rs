@@ -0,0 +1,157 @@
+//! Where a sync's log goes: the terminal, above any progress bars, at `--log-level`, and with
+//! `--log-file`, a file which gets everything down to per-file debug events, for finding out what
+//! went wrong with a run nobody watched, e.g. under launchd.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, IsTerminal, Write},
+    path::Path,
+    sync::{
+        Mutex, Once,
+        atomic::{AtomicBool, AtomicU8, Ordering},
+    },
+};
+
+use clap::ValueEnum;
+use tracing::{Level, Metadata};
+use tracing_subscriber::{filter::filter_fn, fmt, prelude::*};
+
+use crate::progress;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+#[repr(u8)]
+pub enum LogLevel {
+    /// Only errors.
+    Error,
+    /// Errors, and files skipped or only partly carried over.
+    Warn,
+    /// Also the progress of each phase and what a run did, but not each file.
+    #[default]
+    Info,
+    /// Also what happened to each file.
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    fn of(level: &Level) -> Self {
+        match *level {
+            Level::ERROR => Self::Error,
+            Level::WARN => Self::Warn,
+            Level::INFO => Self::Info,
+            Level::DEBUG => Self::Debug,
+            Level::TRACE => Self::Trace,
+        }
+    }
+}
+
+/// The most detailed events shown on the terminal.
+static TERMINAL_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+/// The `--log-file` of the sync running, if it has one.
+static LOG_FILE: Mutex<Option<File>> = Mutex::new(None);
+static LOGGING_TO_FILE: AtomicBool = AtomicBool::new(false);
+
+/// Sends the events of the sync about to run to the terminal at `level`, and to `log_file`. The
+/// subscriber is installed by the first sync, and later ones, e.g. the jobs of `run-all`, just
+/// change where it logs.
+pub fn init(level: LogLevel, log_file: Option<&Path>) -> io::Result<()> {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let terminal = fmt::layer()
+            .with_writer(|| Terminal)
+            .with_ansi(io::stdout().is_terminal())
+            .without_time()
+            .with_target(false)
+            .with_filter(filter_fn(|metadata| {
+                enabled(metadata, TERMINAL_LEVEL.load(Ordering::Relaxed))
+            }));
+        let file = fmt::layer()
+            .with_writer(|| LogFile)
+            .with_ansi(false)
+            .with_filter(filter_fn(|metadata| {
+                LOGGING_TO_FILE.load(Ordering::Relaxed) && enabled(metadata, LogLevel::Debug as u8)
+            }));
+        // fails if the program embedding this one has installed a subscriber of its own, which
+        // is then left to it.
+        let _ = tracing_subscriber::registry()
+            .with(terminal)
+            .with(file)
+            .try_init();
+    });
+    TERMINAL_LEVEL.store(level as u8, Ordering::Relaxed);
+    let file = log_file
+        .map(|path| OpenOptions::new().create(true).append(true).open(path))
+        .transpose()?;
+    LOGGING_TO_FILE.store(file.is_some(), Ordering::Relaxed);
+    *LOG_FILE.lock().unwrap() = file;
+    Ok(())
+}
+
+/// Whether an event is logged at `max`, a [`LogLevel`]. Libraries only get to log their warnings
+/// and errors, as their debug events are about their own workings rather than the sync.
+fn enabled(metadata: &Metadata, max: u8) -> bool {
+    let level = LogLevel::of(metadata.level());
+    if !metadata.target().starts_with(env!("CARGO_CRATE_NAME")) {
+        return level as u8 <= (LogLevel::Warn as u8).min(max);
+    }
+    level as u8 <= max
+}
+
+/// Standard output, written with the progress bars cleared so events aren't drawn over.
+struct Terminal;
+
+impl Write for Terminal {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        progress::suspend(|| io::stdout().write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+struct LogFile;
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut *LOG_FILE.lock().unwrap() {
+            Some(file) => file.write(buf),
+            None => Ok(buf.len()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut *LOG_FILE.lock().unwrap() {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tracing::{debug, trace, warn};
+
+    use super::*;
+
+    #[test]
+    fn the_log_file_gets_debug_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sync.log");
+        init(LogLevel::Error, Some(&path)).unwrap();
+        debug!("copied {:?}", "first-logged.jpg");
+        trace!("read 4096 bytes of fourth-logged.jpg");
+        warn!("skipped second-logged.jpg");
+        init(LogLevel::Error, None).unwrap();
+        debug!("copied third-logged.jpg");
+
+        let log = fs::read_to_string(&path).unwrap();
+        assert!(log.contains("DEBUG"));
+        assert!(log.contains(r#"copied "first-logged.jpg""#));
+        assert!(log.contains("skipped second-logged.jpg"));
+        assert!(!log.contains("fourth-logged.jpg"));
+        assert!(!log.contains("third-logged.jpg"));
+    }
+}
@@ -0,0 +1,103 @@
+//! Catalogue contents as a file of JSON lines, one row per line, so a catalogue can be carried
+//! between runs without a database.
+
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use eyre::{Result, WrapErr};
+use serde::{Deserialize, Serialize};
+
+use crate::digest::ContentHash;
+
+/// A catalogue row. Modification times are in seconds since the epoch, as the catalogue keeps
+/// them.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ManifestEntry {
+    OldTarget {
+        path: String,
+        mtime: i64,
+        size: u64,
+        digest: ContentHash,
+    },
+    /// A file in the out directory.
+    Target {
+        path: String,
+        mtime: i64,
+        size: u64,
+        digest: ContentHash,
+    },
+    Source {
+        namespace: String,
+        path: String,
+        mtime: i64,
+        size: u64,
+        digest: ContentHash,
+    },
+}
+
+pub fn read_manifest(path: &Path) -> Result<Vec<ManifestEntry>> {
+    let file = File::open(path).wrap_err_with(|| format!("could not open {path:?}"))?;
+    BufReader::new(file)
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !matches!(line, Ok(line) if line.trim().is_empty()))
+        .map(|(idx, line)| {
+            serde_json::from_str(&line?)
+                .wrap_err_with(|| format!("line {} of {path:?} is not a manifest entry", idx + 1))
+        })
+        .collect()
+}
+
+pub fn write_manifest(path: &Path, entries: &[ManifestEntry]) -> Result<()> {
+    let file = File::create(path).wrap_err_with(|| format!("could not create {path:?}"))?;
+    write_manifest_to(BufWriter::new(file), entries)
+}
+
+pub fn write_manifest_to(mut writer: impl Write, entries: &[ManifestEntry]) -> Result<()> {
+    for entry in entries {
+        serde_json::to_writer(&mut writer, entry)?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifest_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("manifest.jsonl");
+        let entries = [
+            ManifestEntry::OldTarget {
+                path: "2020/a.jpg".into(),
+                mtime: 1_600_000_000,
+                size: 5,
+                digest: ContentHash::new_for_tests(0xab),
+            },
+            ManifestEntry::Source {
+                namespace: "laptop".into(),
+                path: "b.jpg".into(),
+                mtime: 1_700_000_000,
+                size: 7,
+                digest: ContentHash::new_for_tests(2),
+            },
+        ];
+        write_manifest(&path, &entries).unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert!(text.starts_with(&format!(
+            r#"{{"kind":"old_target","path":"2020/a.jpg","mtime":1600000000,"size":5,"digest":"{}"}}"#,
+            "ab".repeat(32)
+        )));
+        assert_eq!(read_manifest(&path).unwrap(), entries);
+
+        std::fs::write(&path, r#"{"kind":"source","digest":"zz"}"#).unwrap();
+        assert!(read_manifest(&path).is_err());
+    }
+}
@@ -0,0 +1,48 @@
+//! Shell completion scripts and the manual page, generated from the command line's definition so
+//! they never fall behind it.
+
+use std::io::Write;
+
+use clap::CommandFactory;
+use clap_complete::Shell;
+use eyre::Result;
+
+use crate::Cli;
+
+/// Writes the completion script for `shell` to `out`.
+pub fn completions(shell: Shell, out: &mut dyn Write) {
+    let mut command = Cli::command();
+    let name = command.get_name().to_string();
+    clap_complete::generate(shell, &mut command, name, out);
+}
+
+/// Writes the manual page, in roff format, to `out`.
+pub fn manpage(out: &mut dyn Write) -> Result<()> {
+    Ok(clap_mangen::Man::new(Cli::command()).render(out)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn completions_and_manual_cover_subcommands_and_flags() {
+        for shell in [Shell::Bash, Shell::Zsh, Shell::Fish] {
+            let mut script = Vec::new();
+            completions(shell, &mut script);
+            let script = String::from_utf8(script).unwrap();
+            for expected in ["in-dir", "on-collision", "prune-versions", "hash-pending"] {
+                assert!(
+                    script.contains(expected),
+                    "{shell} completions lack {expected}"
+                );
+            }
+        }
+
+        let mut page = Vec::new();
+        manpage(&mut page).unwrap();
+        let page = String::from_utf8(page).unwrap();
+        assert!(page.contains(".TH"), "{page}");
+        assert!(page.contains("in\\-dir"));
+    }
+}
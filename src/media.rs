@@ -0,0 +1,176 @@
+//! Checking that photos and videos are intact before they're archived, so that files broken by an
+//! interrupted download or a failing card are flagged rather than faithfully kept forever.
+
+use std::{
+    fs::File,
+    io::{BufReader, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use eyre::Result;
+use image::{ImageFormat, ImageReader};
+
+/// File extensions of ISO base media files (QuickTime, MP4 and HEIF), which are a sequence of
+/// length-prefixed boxes ("atoms"). Each is paired with the top-level boxes it can't do without.
+const BOX_FORMATS: &[(&str, &[&[u8; 4]])] = &[
+    ("mov", &[b"moov"]),
+    ("mp4", &[b"moov"]),
+    ("m4v", &[b"moov"]),
+    ("3gp", &[b"moov"]),
+    ("heic", &[b"ftyp", b"meta"]),
+    ("heif", &[b"ftyp", b"meta"]),
+    ("avif", &[b"ftyp", b"meta"]),
+];
+
+/// The smallest plausible sizes of photos and videos, by extension. Smaller files are almost
+/// always failed downloads, e.g. the ~1KB placeholders iCloud leaves behind.
+const MINIMUM_SIZES: &[(&str, u64)] = &[
+    ("jpg", 1024),
+    ("jpeg", 1024),
+    ("heic", 1024),
+    ("heif", 1024),
+    ("dng", 16 * 1024),
+    ("mov", 16 * 1024),
+    ("mp4", 16 * 1024),
+    ("m4v", 16 * 1024),
+];
+
+/// Whether a file named `name` of `size` bytes is empty, or too small to be what its name says.
+pub fn is_implausibly_small(name: &Path, size: u64) -> bool {
+    let extension = name
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    let minimum = MINIMUM_SIZES
+        .iter()
+        .find(|(e, _)| *e == extension)
+        .map_or(1, |(_, minimum)| *minimum);
+    size < minimum
+}
+
+/// Looks for signs that the file at `path` is corrupt, going by `name` to tell what it should be,
+/// and describes the first one found. Files of other types are assumed to be fine.
+pub fn find_corruption(path: &Path, name: &Path) -> Result<Option<String>> {
+    let extension = name
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    if let Some((_, required)) = BOX_FORMATS.iter().find(|(e, _)| *e == extension) {
+        return check_boxes(path, required);
+    }
+    let Ok(format) = ImageFormat::from_path(name) else {
+        return Ok(None);
+    };
+    if format == ImageFormat::Jpeg && !has_jpeg_end(path)? {
+        return Ok(Some(
+            "JPEG has no end of image marker, so is truncated".into(),
+        ));
+    }
+    let decoded = ImageReader::with_format(BufReader::new(File::open(path)?), format).decode();
+    Ok(decoded
+        .err()
+        .map(|e| format!("could not decode image: {e}")))
+}
+
+/// Whether the JPEG at `path` has an end of image marker. Some cameras append data after it (e.g.
+/// the video of a motion photo), so it's looked for anywhere rather than only at the very end.
+fn has_jpeg_end(path: &Path) -> Result<bool> {
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+    Ok(bytes.windows(2).any(|w| w == [0xff, 0xd9]))
+}
+
+/// Walks the top-level boxes of the file at `path`, checking that they exactly fill it and that
+/// the `required` ones are present.
+fn check_boxes(path: &Path, required: &[&[u8; 4]]) -> Result<Option<String>> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let mut found = Vec::new();
+    let mut offset = 0;
+    while offset < len {
+        let mut header = [0; 8];
+        if file.read_exact(&mut header).is_err() {
+            return Ok(Some(format!("box header at {offset} is cut off")));
+        }
+        let kind: [u8; 4] = header[4..].try_into()?;
+        let size = match u32::from_be_bytes(header[..4].try_into()?) {
+            // the box runs to the end of the file.
+            0 => len - offset,
+            // the real size follows the type.
+            1 => {
+                let mut size = [0; 8];
+                if file.read_exact(&mut size).is_err() {
+                    return Ok(Some(format!("box header at {offset} is cut off")));
+                }
+                u64::from_be_bytes(size)
+            }
+            size => u64::from(size),
+        };
+        if size < 8 || offset + size > len {
+            let kind = String::from_utf8_lossy(&kind);
+            return Ok(Some(format!(
+                "'{kind}' box at {offset} claims {size} bytes but the file ends at {len}"
+            )));
+        }
+        found.push(kind);
+        offset += size;
+        file.seek(SeekFrom::Start(offset))?;
+    }
+    for kind in required {
+        if !found.contains(kind) {
+            let kind = String::from_utf8_lossy(*kind);
+            return Ok(Some(format!("has no '{kind}' box")));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+
+    #[test]
+    fn broken_media_is_found() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name| dir.path().join(name);
+        let check = |name| find_corruption(&path(name), Path::new(name)).unwrap();
+
+        RgbImage::from_fn(64, 64, |x, y| Rgb([x as u8, y as u8, 0]))
+            .save(path("good.jpg"))
+            .unwrap();
+        let jpeg = fs::read(path("good.jpg")).unwrap();
+        fs::write(path("truncated.jpg"), &jpeg[..jpeg.len() / 2]).unwrap();
+        fs::write(path("garbage.png"), "not a png").unwrap();
+        fs::write(path("notes.txt"), "anything").unwrap();
+        assert_eq!(check("good.jpg"), None);
+        assert!(check("truncated.jpg").unwrap().contains("truncated"));
+        assert!(check("garbage.png").unwrap().contains("decode"));
+        assert_eq!(check("notes.txt"), None);
+        assert!(is_implausibly_small(Path::new("empty.txt"), 0));
+        assert!(is_implausibly_small(Path::new("placeholder.MOV"), 1000));
+        assert!(!is_implausibly_small(Path::new("note.txt"), 1000));
+
+        let atom = |kind: &[u8; 4], body: &[u8]| {
+            let mut atom = (8 + body.len() as u32).to_be_bytes().to_vec();
+            atom.extend_from_slice(kind);
+            atom.extend_from_slice(body);
+            atom
+        };
+        let movie = [
+            atom(b"ftyp", b"qt  "),
+            atom(b"mdat", &[0; 100]),
+            atom(b"moov", &[0; 20]),
+        ]
+        .concat();
+        fs::write(path("good.MOV"), &movie).unwrap();
+        fs::write(path("truncated.mov"), &movie[..movie.len() - 10]).unwrap();
+        fs::write(path("no moov.mp4"), &movie[..movie.len() - 28]).unwrap();
+        assert_eq!(check("good.MOV"), None);
+        assert!(check("truncated.mov").unwrap().contains("'moov' box"));
+        assert!(check("no moov.mp4").unwrap().contains("no 'moov'"));
+    }
+}
@@ -0,0 +1,287 @@
+//! What each photo and video is, read from its EXIF data once when its content is first archived
+//! and kept in the catalogue, so the library can be queried by when and with what it was taken
+//! without touching the files. `reindex-metadata` fills it in for content archived before.
+
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{BufReader, Cursor, Read},
+    path::Path,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use chrono::{NaiveDate, NaiveDateTime};
+use exif::{Exif, In, Reader, Tag, Value};
+use eyre::Result;
+use image::ImageReader;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    ReindexMetadataArgs, classify,
+    compress::{Transform, open_archived, original_path},
+    store::PhotoSyncStore,
+};
+
+const PHOTO_EXTENSIONS: &[&str] = &[
+    "heic", "heif", "avif", "jpg", "jpeg", "png", "gif", "webp", "tif", "tiff", "dng",
+];
+const VIDEO_EXTENSIONS: &[&str] = &["mov", "mp4", "m4v", "3gp", "avi", "mkv"];
+/// How much of the start of a photo is read for its dimensions and the XMP a PNG screenshot is
+/// marked in, which come before its pixels.
+const HEADER_BYTES: u64 = 256 * 1024;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MediaType {
+    Photo,
+    Video,
+    #[default]
+    Other,
+}
+
+impl MediaType {
+    /// What a file named `name` is, going by its extension.
+    pub fn of(name: &Path) -> Self {
+        let extension = name
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        if PHOTO_EXTENSIONS.contains(&extension.as_str()) {
+            Self::Photo
+        } else if VIDEO_EXTENSIONS.contains(&extension.as_str()) {
+            Self::Video
+        } else {
+            Self::Other
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Photo => "photo",
+            Self::Video => "video",
+            Self::Other => "other",
+        }
+    }
+}
+
+/// What the catalogue keeps about each archived photo or video.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MediaMetadata {
+    pub media_type: MediaType,
+    /// When it was taken, from EXIF `DateTimeOriginal`, in the camera's local time.
+    pub taken_at: Option<NaiveDateTime>,
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+    /// Whether it records where it was taken.
+    pub has_gps: bool,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub screenshot: bool,
+    /// The UUID of the burst it's a frame of.
+    pub burst_id: Option<String>,
+}
+
+/// Reads the metadata of the file at `path`, an archived copy stored as `transform` or a staged
+/// one, going by `name` to tell what it is. Only photos are read; videos carry no EXIF data.
+pub fn read_metadata(path: &Path, transform: Transform, name: &Path) -> Result<MediaMetadata> {
+    let mut metadata = MediaMetadata {
+        media_type: MediaType::of(name),
+        ..MediaMetadata::default()
+    };
+    if metadata.media_type != MediaType::Photo {
+        return Ok(metadata);
+    }
+    let mut header = Vec::new();
+    open_archived(path, transform)?
+        .take(HEADER_BYTES)
+        .read_to_end(&mut header)?;
+    // the EXIF data may be anywhere in a HEIC, so it's sought out in a plain copy, while a
+    // compressed or chunked one can't be sought within without reading it all.
+    let exif = match transform {
+        Transform::None => {
+            Reader::new().read_from_container(&mut BufReader::new(File::open(path)?))
+        }
+        Transform::Compressed | Transform::Chunked => {
+            let mut bytes = Vec::new();
+            open_archived(path, transform)?.read_to_end(&mut bytes)?;
+            Reader::new().read_from_container(&mut Cursor::new(bytes))
+        }
+    }
+    .ok();
+    metadata.screenshot = classify::is_screenshot(name, exif.as_ref(), &header);
+    if let Some(exif) = exif {
+        metadata.burst_id = classify::burst_id(&exif);
+        metadata.taken_at = taken_at(&exif);
+        metadata.camera_make = text(&exif, Tag::Make);
+        metadata.camera_model = text(&exif, Tag::Model);
+        metadata.has_gps = exif.get_field(Tag::GPSLatitude, In::PRIMARY).is_some();
+        let dimension = |tag| exif.get_field(tag, In::PRIMARY)?.value.get_uint(0);
+        metadata.width = dimension(Tag::PixelXDimension).or_else(|| dimension(Tag::ImageWidth));
+        metadata.height = dimension(Tag::PixelYDimension).or_else(|| dimension(Tag::ImageLength));
+    }
+    // images without EXIF dimensions, e.g. PNGs and screenshots, are measured by their header.
+    if (metadata.width.is_none() || metadata.height.is_none())
+        && let Ok((width, height)) = ImageReader::new(Cursor::new(&header))
+            .with_guessed_format()?
+            .into_dimensions()
+    {
+        (metadata.width, metadata.height) = (Some(width), Some(height));
+    }
+    Ok(metadata)
+}
+
+fn taken_at(exif: &Exif) -> Option<NaiveDateTime> {
+    let Value::Ascii(values) = &exif.get_field(Tag::DateTimeOriginal, In::PRIMARY)?.value else {
+        return None;
+    };
+    let taken = exif::DateTime::from_ascii(values.first()?).ok()?;
+    // cameras whose clock was never set record zeroes, which aren't a date.
+    NaiveDate::from_ymd_opt(taken.year.into(), taken.month.into(), taken.day.into())?.and_hms_opt(
+        taken.hour.into(),
+        taken.minute.into(),
+        taken.second.into(),
+    )
+}
+
+fn text(exif: &Exif, tag: Tag) -> Option<String> {
+    let Value::Ascii(values) = &exif.get_field(tag, In::PRIMARY)?.value else {
+        return None;
+    };
+    let text = String::from_utf8_lossy(values.first()?);
+    let text = text.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+pub fn reindex_metadata(args: ReindexMetadataArgs) -> Result<()> {
+    let store = PhotoSyncStore::new(args.database_file)?;
+    let indexed = if args.all {
+        HashSet::new()
+    } else {
+        store.media_metadata_digests()?
+    };
+    let mut archived = Vec::new();
+    if let Some(dir) = &args.old_out_dir {
+        let files = store.old_target_files()?.into_iter();
+        archived.extend(files.map(|f| (dir, Transform::None, f)));
+    }
+    if let Some(dir) = &args.out_dir {
+        let transforms = store.transformed_files()?;
+        archived.extend(store.target_files()?.into_iter().map(|f| {
+            let transform = transforms.get(&f.path).copied().unwrap_or_default();
+            (dir, transform, f)
+        }));
+    }
+    // content is indexed once, from whichever copy comes first.
+    let mut seen = HashSet::new();
+    archived.retain(|(_, _, file)| !indexed.contains(&file.digest) && seen.insert(file.digest));
+
+    let unreadable = AtomicUsize::new(0);
+    let reindexed = archived.len();
+    archived
+        .into_par_iter()
+        .try_for_each(|(dir, transform, file)| -> Result<()> {
+            let path = dir.join(&file.path);
+            match read_metadata(&path, transform, &original_path(&file.path, transform)) {
+                Ok(metadata) => store.record_media_metadata(&file.digest, &metadata)?,
+                Err(e) => {
+                    println!("could not read {path:?}: {e}");
+                    unreadable.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            Ok(())
+        })?;
+    let unreadable = unreadable.into_inner();
+    println!(
+        "indexed the metadata of {} files, {unreadable} could not be read",
+        reindexed - unreadable
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use exif::{Field, Rational, experimental::Writer};
+    use image::{ImageFormat, RgbImage};
+
+    use super::*;
+
+    #[test]
+    fn exif_metadata_is_read_from_photos() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut jpeg = Vec::new();
+        RgbImage::new(32, 16)
+            .write_to(&mut Cursor::new(&mut jpeg), ImageFormat::Jpeg)
+            .unwrap();
+        let ascii = |tag, text: &str| Field {
+            tag,
+            ifd_num: In::PRIMARY,
+            value: Value::Ascii(vec![text.as_bytes().to_vec()]),
+        };
+        let fields = [
+            ascii(Tag::Make, "Apple"),
+            ascii(Tag::Model, "iPhone 12"),
+            ascii(Tag::DateTimeOriginal, "2021:07:14 18:03:27"),
+            Field {
+                tag: Tag::GPSLatitude,
+                ifd_num: In::PRIMARY,
+                value: Value::Rational(vec![Rational::from((51, 1)); 3]),
+            },
+        ];
+        let mut writer = Writer::new();
+        fields.iter().for_each(|field| writer.push_field(field));
+        let mut tiff = Cursor::new(Vec::new());
+        writer.write(&mut tiff, false).unwrap();
+        // an APP1 segment holding the EXIF data, right after the start of image marker.
+        let mut app1 = b"Exif\0\0".to_vec();
+        app1.extend(tiff.into_inner());
+        let mut tagged = jpeg[..2].to_vec();
+        tagged.extend([0xff, 0xe1]);
+        tagged.extend(u16::try_from(app1.len() + 2).unwrap().to_be_bytes());
+        tagged.extend(app1);
+        tagged.extend(&jpeg[2..]);
+        fs::write(dir.path().join("tagged"), tagged).unwrap();
+        fs::write(dir.path().join("plain"), jpeg).unwrap();
+
+        let tagged = read_metadata(
+            &dir.path().join("tagged"),
+            Transform::None,
+            Path::new("IMG_0001.JPG"),
+        )
+        .unwrap();
+        assert_eq!(
+            tagged,
+            MediaMetadata {
+                media_type: MediaType::Photo,
+                taken_at: NaiveDate::from_ymd_opt(2021, 7, 14)
+                    .unwrap()
+                    .and_hms_opt(18, 3, 27),
+                camera_make: Some("Apple".into()),
+                camera_model: Some("iPhone 12".into()),
+                has_gps: true,
+                width: Some(32),
+                height: Some(16),
+                screenshot: false,
+                burst_id: None,
+            }
+        );
+        let plain = read_metadata(
+            &dir.path().join("plain"),
+            Transform::None,
+            Path::new("screenshot.jpg"),
+        )
+        .unwrap();
+        assert_eq!((plain.taken_at, plain.has_gps), (None, false));
+        assert_eq!((plain.width, plain.height), (Some(32), Some(16)));
+        assert!(plain.screenshot);
+        // videos aren't read at all.
+        let video = read_metadata(
+            &dir.path().join("missing"),
+            Transform::None,
+            Path::new("IMG_0001.MOV"),
+        )
+        .unwrap();
+        assert_eq!(video.media_type, MediaType::Video);
+    }
+}
@@ -0,0 +1,533 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{BufRead, BufReader, ErrorKind, Write as _},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::Path,
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use eyre::{Result, WrapErr};
+use serde::Serialize;
+use tempfile::NamedTempFile;
+use tracing::{info, warn};
+
+use crate::{failures::FailureKind, filters::SyncScope, sau64::SimpleAtomicU64, store::RunCounts};
+
+/// What a run has done so far, for reporting once it is over.
+#[derive(Default)]
+pub struct RunStats {
+    /// Files in the old out directory hashed because they were new or changed.
+    pub files_indexed: SimpleAtomicU64,
+    pub bytes_indexed: SimpleAtomicU64,
+    /// Files in the in directory found to need transferring.
+    pub files_detected: SimpleAtomicU64,
+    /// Files written to the out directory.
+    pub files_transferred: SimpleAtomicU64,
+    pub bytes_transferred: SimpleAtomicU64,
+    /// New files whose content was already archived, so weren't written again.
+    pub files_deduplicated: SimpleAtomicU64,
+    pub bytes_deduplicated: SimpleAtomicU64,
+    /// Files already transferred whose size or modification time has since changed, which are
+    /// left for manual intervention.
+    pub files_metadata_changed: SimpleAtomicU64,
+    pub files_failed_to_open: SimpleAtomicU64,
+    pub files_failed_to_copy: SimpleAtomicU64,
+    /// Files whose content was only in iCloud, which weren't downloaded. They aren't failures.
+    pub files_not_downloaded: SimpleAtomicU64,
+    /// Files which couldn't be transferred, or whose hooks failed.
+    pub files_failed: SimpleAtomicU64,
+    /// New images `--perceptual-hash` couldn't fingerprint, e.g. HEIC photos, which can't be
+    /// decoded.
+    pub images_not_fingerprinted: SimpleAtomicU64,
+    /// Why those files failed.
+    failures: Mutex<BTreeMap<FailureKind, u64>>,
+    /// How long each phase has taken, summed over the syncs of a `--watch` run.
+    phases: Mutex<BTreeMap<&'static str, Duration>>,
+}
+
+impl RunStats {
+    /// What's been counted so far, as kept with each run.
+    pub fn counts(&self) -> RunCounts {
+        RunCounts {
+            files_detected: self.files_detected.as_u64(),
+            files_transferred: self.files_transferred.as_u64(),
+            bytes_transferred: self.bytes_transferred.as_u64(),
+            files_deduplicated: self.files_deduplicated.as_u64(),
+            files_failed: self.files_failed.as_u64(),
+        }
+    }
+
+    pub fn record_phase(&self, phase: &'static str, took: Duration) {
+        *self.phases.lock().unwrap().entry(phase).or_default() += took;
+    }
+
+    /// Counts `files` as having failed for the reason `kind`.
+    pub fn record_failures(&self, kind: FailureKind, files: u64) {
+        *self.failures.lock().unwrap().entry(kind).or_default() += files;
+    }
+
+    /// How many files have failed for each reason.
+    pub fn failures(&self) -> BTreeMap<FailureKind, u64> {
+        self.failures.lock().unwrap().clone()
+    }
+
+    /// How many files have failed for each reason since `earlier`, what [`RunStats::failures`]
+    /// was then, counts being kept over a whole `--watch`.
+    pub fn failures_since(
+        &self,
+        earlier: &BTreeMap<FailureKind, u64>,
+    ) -> BTreeMap<FailureKind, u64> {
+        let mut failures = self.failures();
+        failures.retain(|kind, files| {
+            *files -= earlier.get(kind).copied().unwrap_or_default();
+            *files > 0
+        });
+        failures
+    }
+}
+
+/// What `--summary-json` writes once a run is over.
+#[derive(Debug, Serialize)]
+struct RunSummary<'a> {
+    machine_id: &'a str,
+    succeeded: bool,
+    /// Why the run failed, if it did.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+    started_at: u64,
+    duration_seconds: f64,
+    /// What the sync was narrowed to, unless it was the whole in directory.
+    #[serde(skip_serializing_if = "SyncScope::is_everything")]
+    scope: &'a SyncScope,
+    files_indexed: u64,
+    bytes_indexed: u64,
+    files_detected: u64,
+    files_transferred: u64,
+    bytes_transferred: u64,
+    files_deduplicated: u64,
+    bytes_deduplicated: u64,
+    files_metadata_changed: u64,
+    files_failed_to_open: u64,
+    files_failed_to_copy: u64,
+    files_not_downloaded: u64,
+    files_failed: u64,
+    images_not_fingerprinted: u64,
+    /// How many files failed for each reason.
+    failures: BTreeMap<&'static str, u64>,
+    phase_seconds: BTreeMap<&'static str, f64>,
+}
+
+/// Writes the outcome of a run to `path` as a JSON object, e.g. for a dashboard, replacing it
+/// atomically as [`write_textfile`] does.
+pub fn write_summary_json(
+    path: &Path,
+    machine_id: &str,
+    error: Option<&str>,
+    started: SystemTime,
+    duration: Duration,
+    scope: &SyncScope,
+    stats: &RunStats,
+) -> Result<()> {
+    replace_file(
+        path,
+        &summary_json(machine_id, error, started, duration, scope, stats)?,
+    )
+}
+
+/// The outcome of a run as a JSON object, as `--summary-json` writes it and notifications send it.
+pub fn summary_json(
+    machine_id: &str,
+    error: Option<&str>,
+    started: SystemTime,
+    duration: Duration,
+    scope: &SyncScope,
+    stats: &RunStats,
+) -> Result<Vec<u8>> {
+    let summary = RunSummary {
+        machine_id,
+        succeeded: error.is_none(),
+        error,
+        started_at: started.duration_since(UNIX_EPOCH)?.as_secs(),
+        duration_seconds: duration.as_secs_f64(),
+        scope,
+        files_indexed: stats.files_indexed.as_u64(),
+        bytes_indexed: stats.bytes_indexed.as_u64(),
+        files_detected: stats.files_detected.as_u64(),
+        files_transferred: stats.files_transferred.as_u64(),
+        bytes_transferred: stats.bytes_transferred.as_u64(),
+        files_deduplicated: stats.files_deduplicated.as_u64(),
+        bytes_deduplicated: stats.bytes_deduplicated.as_u64(),
+        files_metadata_changed: stats.files_metadata_changed.as_u64(),
+        files_failed_to_open: stats.files_failed_to_open.as_u64(),
+        files_failed_to_copy: stats.files_failed_to_copy.as_u64(),
+        files_not_downloaded: stats.files_not_downloaded.as_u64(),
+        files_failed: stats.files_failed.as_u64(),
+        images_not_fingerprinted: stats.images_not_fingerprinted.as_u64(),
+        failures: (stats.failures().into_iter())
+            .map(|(kind, files)| (kind.name(), files))
+            .collect(),
+        phase_seconds: stats
+            .phases
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(phase, took)| (*phase, took.as_secs_f64()))
+            .collect(),
+    };
+    Ok(serde_json::to_vec_pretty(&summary)?)
+}
+
+/// Writes the outcome of a run to `path` in the format of node_exporter's textfile collector, so
+/// one-shot runs can be monitored. The file is replaced atomically, so it's never read half
+/// written.
+pub fn write_textfile(
+    path: &Path,
+    machine_id: &str,
+    succeeded: bool,
+    started: SystemTime,
+    duration: Duration,
+    stats: &RunStats,
+) -> Result<()> {
+    let labels = format!("{{machine_id=\"{}\"}}", escape(machine_id));
+    let metrics = [
+        (
+            "last_run_success",
+            "Whether the last run succeeded.",
+            u64::from(succeeded) as f64,
+        ),
+        (
+            "last_run_timestamp_seconds",
+            "When the last run started.",
+            started.duration_since(UNIX_EPOCH)?.as_secs_f64(),
+        ),
+        (
+            "last_run_duration_seconds",
+            "How long the last run took.",
+            duration.as_secs_f64(),
+        ),
+        (
+            "last_run_files_indexed",
+            "Files in the old out directory hashed by the last run.",
+            stats.files_indexed.as_u64() as f64,
+        ),
+        (
+            "last_run_files_detected",
+            "New files found in the in directory by the last run.",
+            stats.files_detected.as_u64() as f64,
+        ),
+        (
+            "last_run_files_transferred",
+            "Files written to the out directory by the last run.",
+            stats.files_transferred.as_u64() as f64,
+        ),
+        (
+            "last_run_bytes_transferred",
+            "Bytes written to the out directory by the last run.",
+            stats.bytes_transferred.as_u64() as f64,
+        ),
+        (
+            "last_run_files_failed",
+            "Files the last run failed to transfer, or whose hooks failed.",
+            stats.files_failed.as_u64() as f64,
+        ),
+        (
+            "last_run_files_not_downloaded",
+            "Files the last run left as they were only in iCloud.",
+            stats.files_not_downloaded.as_u64() as f64,
+        ),
+    ];
+
+    let mut text = String::new();
+    for (name, help, value) in metrics {
+        writeln!(text, "# HELP photo_sync_{name} {help}")?;
+        writeln!(text, "# TYPE photo_sync_{name} gauge")?;
+        writeln!(text, "photo_sync_{name}{labels} {value}")?;
+    }
+    replace_file(path, text.as_bytes())
+}
+
+/// What `--metrics-addr` publishes: the counters of the run so far, summed over the syncs of a
+/// `--watch` run, in Prometheus's text format.
+pub fn exposition(machine_id: &str, stats: &RunStats) -> Result<String> {
+    let labels = format!("machine_id=\"{}\"", escape(machine_id));
+    let counters = [
+        (
+            "files_indexed",
+            "Files in the old out directory hashed.",
+            &stats.files_indexed,
+        ),
+        (
+            "bytes_indexed",
+            "Bytes of the old out directory hashed.",
+            &stats.bytes_indexed,
+        ),
+        (
+            "files_detected",
+            "New files found in the in directory.",
+            &stats.files_detected,
+        ),
+        (
+            "files_transferred",
+            "Files written to the out directory.",
+            &stats.files_transferred,
+        ),
+        (
+            "bytes_transferred",
+            "Bytes written to the out directory.",
+            &stats.bytes_transferred,
+        ),
+        (
+            "files_deduplicated",
+            "New files whose content was already archived.",
+            &stats.files_deduplicated,
+        ),
+        (
+            "bytes_deduplicated",
+            "Bytes of new files whose content was already archived.",
+            &stats.bytes_deduplicated,
+        ),
+        (
+            "files_failed",
+            "Files which couldn't be transferred, or whose hooks failed.",
+            &stats.files_failed,
+        ),
+        (
+            "files_not_downloaded",
+            "Files left as they were only in iCloud.",
+            &stats.files_not_downloaded,
+        ),
+    ];
+
+    let mut text = String::new();
+    for (name, help, counter) in counters {
+        writeln!(text, "# HELP photo_sync_{name}_total {help}")?;
+        writeln!(text, "# TYPE photo_sync_{name}_total counter")?;
+        writeln!(
+            text,
+            "photo_sync_{name}_total{{{labels}}} {}",
+            counter.as_u64()
+        )?;
+    }
+    writeln!(
+        text,
+        "# HELP photo_sync_phase_seconds_total Time spent in each phase."
+    )?;
+    writeln!(text, "# TYPE photo_sync_phase_seconds_total counter")?;
+    for (phase, took) in stats.phases.lock().unwrap().iter() {
+        writeln!(
+            text,
+            "photo_sync_phase_seconds_total{{{labels},phase=\"{phase}\"}} {}",
+            took.as_secs_f64()
+        )?;
+    }
+    Ok(text)
+}
+
+/// Serves [`exposition`] over HTTP at `addr`, if given, while `f` runs, for Prometheus to scrape.
+pub fn with_metrics_endpoint<T>(
+    addr: Option<SocketAddr>,
+    machine_id: &str,
+    stats: &RunStats,
+    f: impl FnOnce() -> Result<T>,
+) -> Result<T> {
+    let Some(addr) = addr else {
+        return f();
+    };
+    let listener =
+        TcpListener::bind(addr).wrap_err_with(|| format!("could not listen on {addr}"))?;
+    info!(
+        "serving metrics on http://{}/metrics",
+        listener.local_addr()?
+    );
+    serve_metrics_while(listener, machine_id, stats, f)
+}
+
+fn serve_metrics_while<T>(
+    listener: TcpListener,
+    machine_id: &str,
+    stats: &RunStats,
+    f: impl FnOnce() -> Result<T>,
+) -> Result<T> {
+    /// How often the server checks whether the run has finished.
+    const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+    listener.set_nonblocking(true)?;
+    let done = AtomicBool::new(false);
+    thread::scope(|s| {
+        s.spawn(|| {
+            while !done.load(Ordering::SeqCst) {
+                match listener.accept() {
+                    // scrapes are rare and quick to answer, so one at a time is plenty.
+                    Ok((stream, _)) => {
+                        if let Err(e) = answer_scrape(stream, machine_id, stats) {
+                            warn!("metrics request failed: {e}");
+                        }
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+                    Err(e) => {
+                        warn!("metrics endpoint failed, so no longer serving metrics: {e}");
+                        return;
+                    }
+                }
+            }
+        });
+        let result = f();
+        done.store(true, Ordering::SeqCst);
+        result
+    })
+}
+
+/// Answers one HTTP request on `stream`: the metrics for `GET /metrics`, and not found for
+/// anything else.
+fn answer_scrape(stream: TcpStream, machine_id: &str, stats: &RunStats) -> Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // the headers, which don't matter.
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+    let (status, body) = match request.split_whitespace().take(2).collect::<Vec<_>>()[..] {
+        ["GET", "/metrics"] => ("200 OK", exposition(machine_id, stats)?),
+        _ => ("404 Not Found", "not found\n".to_owned()),
+    };
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    Ok(())
+}
+
+/// Replaces `path` with `contents` in one rename, so it's never seen half written.
+fn replace_file(path: &Path, contents: &[u8]) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut file = NamedTempFile::new_in(dir)?;
+    file.write_all(contents)?;
+    file.persist(path)?;
+    Ok(())
+}
+
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn textfile_holds_run_metrics() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("photo_sync.prom");
+        let stats = RunStats::default();
+        stats.files_transferred.fetch_add(3);
+        write_textfile(
+            &path,
+            "my \"laptop\"",
+            true,
+            UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            Duration::from_millis(1500),
+            &stats,
+        )
+        .unwrap();
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        let labels = r#"{machine_id="my \"laptop\""}"#;
+        for line in [
+            format!("photo_sync_last_run_success{labels} 1"),
+            format!("photo_sync_last_run_timestamp_seconds{labels} 1700000000"),
+            format!("photo_sync_last_run_duration_seconds{labels} 1.5"),
+            format!("photo_sync_last_run_files_transferred{labels} 3"),
+        ] {
+            assert!(text.lines().any(|l| l == line), "{line} not in {text}");
+        }
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn endpoint_serves_counters_while_running() {
+        let stats = RunStats::default();
+        stats.bytes_transferred.fetch_add(2048);
+        stats.record_phase("transferring", Duration::from_millis(250));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let get = |path: &str| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+            let mut response = String::new();
+            std::io::Read::read_to_string(&mut stream, &mut response).unwrap();
+            response
+        };
+        let (metrics, missing) = serve_metrics_while(listener, "laptop", &stats, || {
+            Ok((get("/metrics"), get("/")))
+        })
+        .unwrap();
+
+        assert!(metrics.starts_with("HTTP/1.1 200 OK\r\n"), "{metrics}");
+        for line in [
+            "# TYPE photo_sync_bytes_transferred_total counter",
+            r#"photo_sync_bytes_transferred_total{machine_id="laptop"} 2048"#,
+            r#"photo_sync_phase_seconds_total{machine_id="laptop",phase="transferring"} 0.25"#,
+        ] {
+            assert!(
+                metrics.lines().any(|l| l == line),
+                "{line} not in {metrics}"
+            );
+        }
+        assert!(missing.starts_with("HTTP/1.1 404"), "{missing}");
+    }
+
+    #[test]
+    fn summary_holds_counts_and_phase_durations() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("summary.json");
+        let stats = RunStats::default();
+        stats.files_deduplicated.fetch_add(2);
+        stats.record_failures(FailureKind::Vanished, 1);
+        stats.record_phase("hashing", Duration::from_millis(500));
+        stats.record_phase("hashing", Duration::from_millis(1000));
+        write_summary_json(
+            &path,
+            "laptop",
+            Some("the out directory is full"),
+            UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            Duration::from_secs(2),
+            &SyncScope {
+                only_subdirs: vec!["2024".into()],
+                ..SyncScope::default()
+            },
+            &stats,
+        )
+        .unwrap();
+
+        let summary: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(summary["succeeded"], false);
+        assert_eq!(summary["error"], "the out directory is full");
+        assert_eq!(summary["started_at"], 1_700_000_000);
+        assert_eq!(summary["files_deduplicated"], 2);
+        assert_eq!(summary["failures"]["vanished"], 1);
+        assert_eq!(summary["phase_seconds"]["hashing"], 1.5);
+        assert_eq!(
+            summary["scope"],
+            serde_json::json!({"only_subdirs": ["2024"]})
+        );
+    }
+}
@@ -0,0 +1,102 @@
+//! `db migrate`: bringing a database's schema up to date explicitly, with a backup first, rather
+//! than as a side effect of the next sync opening it.
+
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Local};
+use eyre::{Result, bail};
+
+use crate::store::{LATEST_SCHEMA_VERSION, PhotoSyncStore, backup_database, inspect_database};
+
+/// Applies the migrations `database_file` is missing, backing it up first unless `no_backup`.
+/// With `dry_run`, only says which would be applied.
+pub fn migrate(database_file: &Path, dry_run: bool, no_backup: bool) -> Result<()> {
+    if !database_file.exists() {
+        bail!("there is no database at {database_file:?}");
+    }
+    let version = inspect_database(database_file)?.schema_version;
+    if version > LATEST_SCHEMA_VERSION {
+        bail!(
+            "the database has schema version {version}, newer than this version of the tool \
+             supports ({LATEST_SCHEMA_VERSION}); upgrade before migrating it"
+        );
+    }
+    if version == LATEST_SCHEMA_VERSION {
+        println!("the database is already at schema version {version}");
+        return Ok(());
+    }
+    println!(
+        "the database is at schema version {version}; migrations {}..={LATEST_SCHEMA_VERSION} \
+         are pending",
+        version + 1
+    );
+    if dry_run {
+        return Ok(());
+    }
+
+    if !no_backup {
+        let backup = backup_path(database_file, version);
+        backup_database(database_file, &backup)?;
+        println!("backed up the database to {backup:?}");
+    }
+    let store = PhotoSyncStore::new(database_file.to_path_buf())?;
+    for (version, applied_at) in store.schema_history()? {
+        println!(
+            "schema version {version} applied at {}",
+            DateTime::<Local>::from(applied_at).format("%Y-%m-%d %H:%M:%S")
+        );
+    }
+    Ok(())
+}
+
+/// `<database>.v<version>.bak`, beside the database.
+fn backup_path(database_file: &Path, version: usize) -> PathBuf {
+    let mut name = database_file.as_os_str().to_owned();
+    name.push(format!(".v{version}.bak"));
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    use super::*;
+
+    #[test]
+    fn old_databases_are_backed_up_and_migrated() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("photos.db");
+        Connection::open(&path)
+            .unwrap()
+            .execute_batch("CREATE TABLE legacy (x INTEGER)")
+            .unwrap();
+
+        migrate(&path, true, false).unwrap();
+        assert_eq!(inspect_database(&path).unwrap().schema_version, 0);
+
+        migrate(&path, false, false).unwrap();
+        assert_eq!(
+            inspect_database(&path).unwrap().schema_version,
+            LATEST_SCHEMA_VERSION
+        );
+        assert_eq!(
+            inspect_database(&backup_path(&path, 0))
+                .unwrap()
+                .schema_version,
+            0
+        );
+        let history = PhotoSyncStore::new(path.clone())
+            .unwrap()
+            .schema_history()
+            .unwrap();
+        assert_eq!(history.len(), LATEST_SCHEMA_VERSION);
+
+        // databases written by a newer version are left alone.
+        Connection::open(&path)
+            .unwrap()
+            .pragma_update(None, "user_version", LATEST_SCHEMA_VERSION + 1)
+            .unwrap();
+        assert!(migrate(&path, false, true).is_err());
+        assert!(PhotoSyncStore::new(path).is_err());
+    }
+}
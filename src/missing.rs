@@ -0,0 +1,187 @@
+//! Finding catalogued files which have gone from the out or old out directory, e.g. deleted by
+//! accident or lost with a disk, and re-transferring them from the in directory where it still has
+//! their content.
+
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use eyre::{Result, bail};
+
+use crate::{
+    MissingArgs,
+    compress::Transform,
+    digest::ContentHash,
+    scrub::restore,
+    store::{CataloguedFile, FileCopy, PhotoSyncStore},
+};
+
+/// What became of a catalogued file which is no longer on disk.
+#[derive(Debug, PartialEq, Eq)]
+enum Missing {
+    /// Its content is still in the archive at another path, so nothing was lost.
+    Duplicated,
+    /// Its content is only in the in directory, at this path.
+    InSource(PathBuf),
+    /// Its content is nowhere to be found.
+    Lost,
+}
+
+pub fn missing(args: MissingArgs) -> Result<()> {
+    let store = PhotoSyncStore::new(args.database_file)?;
+    let mut dirs = vec![(args.out_dir.as_path(), store.target_files()?)];
+    if let Some(old_out_dir) = &args.old_out_dir {
+        dirs.push((old_out_dir, store.old_target_files()?));
+    }
+
+    let missing = find_missing(&store, &dirs, args.in_dir.as_deref(), &args.machine_id)?;
+    let transforms = store.transformed_files()?;
+    let mut unrecovered = 0;
+    for (dir, file, found) in missing {
+        let full_path = dir.join(&file.path);
+        match found {
+            Missing::Duplicated => {
+                println!("MISSING {full_path:?}, but its content is elsewhere in the archive");
+            }
+            Missing::InSource(source) if args.retransfer => {
+                // only the out directory's copies are stored otherwise than as they are.
+                let transform = if dir == args.out_dir {
+                    transforms.get(&file.path).copied().unwrap_or_default()
+                } else {
+                    Transform::None
+                };
+                restore(&source, Transform::None, &full_path, transform, file.mtime)?;
+                println!("re-transferred {full_path:?} from {source:?}");
+            }
+            Missing::InSource(source) => {
+                println!("MISSING {full_path:?}, can be re-transferred from {source:?}");
+                unrecovered += 1;
+            }
+            Missing::Lost => {
+                println!("LOST {full_path:?} ({})", file.digest);
+                unrecovered += 1;
+            }
+        }
+    }
+    if unrecovered > 0 {
+        bail!("{unrecovered} files are missing from the archive");
+    }
+    Ok(())
+}
+
+/// Finds the catalogued files missing from each directory, and where their content can still be
+/// found. Files skipped as duplicates have no row of their own, so are only affected when every
+/// copy of their content is missing.
+fn find_missing<'a>(
+    store: &PhotoSyncStore,
+    dirs: &[(&'a Path, Vec<CataloguedFile>)],
+    in_dir: Option<&Path>,
+    namespace: &str,
+) -> Result<Vec<(&'a Path, CataloguedFile, Missing)>> {
+    let mut present = HashSet::new();
+    let mut gone = Vec::new();
+    for (dir, files) in dirs {
+        for file in files {
+            if dir.join(&file.path).is_file() {
+                present.insert(file.digest);
+            } else {
+                gone.push((*dir, file));
+            }
+        }
+    }
+
+    let mut missing = Vec::with_capacity(gone.len());
+    for (dir, file) in gone {
+        let found = if present.contains(&file.digest) {
+            Missing::Duplicated
+        } else if let Some(source) = source_copy(store, in_dir, namespace, &file.digest)? {
+            Missing::InSource(source)
+        } else {
+            Missing::Lost
+        };
+        missing.push((dir, file.clone(), found));
+    }
+    Ok(missing)
+}
+
+/// A file in the in directory which still has the content `digest`.
+fn source_copy(
+    store: &PhotoSyncStore,
+    in_dir: Option<&Path>,
+    namespace: &str,
+    expected: &ContentHash,
+) -> Result<Option<PathBuf>> {
+    let Some(in_dir) = in_dir else {
+        return Ok(None);
+    };
+    for copy in store.copies_of(expected, namespace)? {
+        if let FileCopy::Source(path) = copy {
+            let path = in_dir.join(path);
+            if path.is_file() && expected.algorithm().digest(&path).ok() == Some(*expected) {
+                return Ok(Some(path));
+            }
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, time::SystemTime};
+
+    use super::*;
+    use crate::digest::digest;
+
+    #[test]
+    fn missing_files_are_told_apart() {
+        let dir = tempfile::tempdir().unwrap();
+        let (in_dir, out_dir) = (dir.path().join("in"), dir.path().join("out"));
+        fs::create_dir_all(&in_dir).unwrap();
+        fs::create_dir_all(&out_dir).unwrap();
+        let store = PhotoSyncStore::new_for_tests().unwrap();
+        let run = store.begin_run("laptop").unwrap();
+        let now = SystemTime::now();
+        let add = |path: &str, content: &str, on_disk: bool, in_source: bool| {
+            fs::write(out_dir.join(path), content).unwrap();
+            let digest = digest(&out_dir.join(path)).unwrap();
+            if !on_disk {
+                fs::remove_file(out_dir.join(path)).unwrap();
+            }
+            if in_source {
+                fs::write(in_dir.join(path), content).unwrap();
+                store
+                    .mark_transferred_from_source(run, "laptop", Path::new(path), &digest, now, 1)
+                    .unwrap();
+            }
+            store
+                .mark_exists_in_target(run, Path::new(path), now, 1, &digest)
+                .unwrap();
+        };
+        add("kept.jpg", "a", true, true);
+        add("dup.jpg", "a", false, false);
+        add("retransferable.jpg", "b", false, true);
+        add("lost.jpg", "c", false, false);
+
+        let dirs = [(out_dir.as_path(), store.target_files().unwrap())];
+        let found = |in_dir| {
+            find_missing(&store, &dirs, in_dir, "laptop")
+                .unwrap()
+                .into_iter()
+                .map(|(_, file, found)| (file.path, found))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            found(Some(&in_dir)),
+            [
+                (PathBuf::from("dup.jpg"), Missing::Duplicated),
+                (PathBuf::from("lost.jpg"), Missing::Lost),
+                (
+                    PathBuf::from("retransferable.jpg"),
+                    Missing::InSource(in_dir.join("retransferable.jpg"))
+                ),
+            ]
+        );
+        assert_eq!(found(None)[2].1, Missing::Lost);
+    }
+}
@@ -0,0 +1,84 @@
+//! Whether a sync changes anything, or only reports what it would do.
+
+use std::path::Path;
+
+use eyre::Result;
+
+use crate::{
+    netdb::{self, LocalCopy, NetworkDatabasePolicy},
+    store::PhotoSyncStore,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExecutionMode {
+    /// Writes to the catalogue and the out directory.
+    Live,
+    /// Reports what would be hashed, copied or skipped. The phases still run against a catalogue,
+    /// so that e.g. duplicates within the run are told apart, but it's a copy which is thrown
+    /// away afterwards, and nothing is written to the out directory.
+    DryRun,
+}
+
+impl ExecutionMode {
+    pub fn new(dry_run: bool) -> Self {
+        if dry_run { Self::DryRun } else { Self::Live }
+    }
+
+    pub fn is_live(self) -> bool {
+        self == Self::Live
+    }
+
+    /// Opens the store in `database_file` to sync against, and the local copy it's in, if any,
+    /// to be passed to [`ExecutionMode::finish`] once the store has been dropped.
+    pub fn open_store(
+        self,
+        database_file: &Path,
+        policy: NetworkDatabasePolicy,
+    ) -> Result<(PhotoSyncStore, Option<LocalCopy>)> {
+        match self {
+            Self::Live => netdb::open_store(database_file, policy),
+            Self::DryRun => {
+                let copy = LocalCopy::create(database_file)?;
+                let store = PhotoSyncStore::new(copy.path())?;
+                Ok((store, Some(copy)))
+            }
+        }
+    }
+
+    /// Replaces the database with the local copy synced against, except in a dry run, where the
+    /// copy is thrown away.
+    pub fn finish(self, copy: LocalCopy) -> Result<()> {
+        match self {
+            Self::Live => copy.copy_back(),
+            Self::DryRun => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dry_runs_leave_the_database_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let database_file = dir.path().join("db.sqlite");
+        PhotoSyncStore::new(database_file.clone())
+            .unwrap()
+            .begin_run("laptop")
+            .unwrap();
+
+        for mode in [ExecutionMode::DryRun, ExecutionMode::Live] {
+            let (store, copy) = mode
+                .open_store(&database_file, NetworkDatabasePolicy::Refuse)
+                .unwrap();
+            store.begin_run("laptop").unwrap();
+            drop(store);
+            if let Some(copy) = copy {
+                mode.finish(copy).unwrap();
+            }
+        }
+        let store = PhotoSyncStore::new(database_file).unwrap();
+        assert_eq!(store.last_run("laptop").unwrap(), Some(2));
+    }
+}
@@ -0,0 +1,138 @@
+//! Opening a database which may be on a network filesystem, where sqlite's locking is unreliable
+//! and a catalogue can be corrupted without any error being reported.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use clap::ValueEnum;
+use eyre::{Result, WrapErr, bail};
+use tempfile::{NamedTempFile, TempDir};
+
+use crate::{fsinfo::network_filesystem, store::PhotoSyncStore};
+use tracing::info;
+
+/// What to do when the database is on a network filesystem.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum NetworkDatabasePolicy {
+    /// Refuse to sync.
+    Refuse,
+    /// Hold an exclusive lock on the database for the whole run, with a rollback journal.
+    Exclusive,
+    /// Sync against a copy on local disk, and replace the database with it once the run is over.
+    LocalCopy,
+}
+
+/// Opens the store in `database_file`, following `policy` if it is on a network filesystem. If a
+/// local copy is made, it must be copied back once the store has been dropped.
+pub fn open_store(
+    database_file: &Path,
+    policy: NetworkDatabasePolicy,
+) -> Result<(PhotoSyncStore, Option<LocalCopy>)> {
+    let Some(kind) = network_filesystem(parent(database_file))? else {
+        return Ok((PhotoSyncStore::new(database_file.to_path_buf())?, None));
+    };
+    match policy {
+        NetworkDatabasePolicy::Refuse => bail!(
+            "{database_file:?} is on a {kind} filesystem, where sqlite's locking is unreliable \
+             and the catalogue could be silently corrupted. Move it to a local disk, host it with \
+             `serve`, or pass --network-database exclusive or local-copy"
+        ),
+        NetworkDatabasePolicy::Exclusive => {
+            info!("{database_file:?} is on a {kind} filesystem, locking it exclusively");
+            let store = PhotoSyncStore::new_exclusive(database_file.to_path_buf())?;
+            Ok((store, None))
+        }
+        NetworkDatabasePolicy::LocalCopy => {
+            info!("{database_file:?} is on a {kind} filesystem, syncing against a local copy");
+            let copy = LocalCopy::create(database_file)?;
+            let store = PhotoSyncStore::new(copy.path())?;
+            Ok((store, Some(copy)))
+        }
+    }
+}
+
+/// A copy of a database on local disk.
+pub struct LocalCopy {
+    dir: TempDir,
+    original: PathBuf,
+}
+
+impl LocalCopy {
+    pub fn create(original: &Path) -> Result<Self> {
+        let copy = Self {
+            dir: tempfile::tempdir()?,
+            original: original.to_path_buf(),
+        };
+        if original.exists() {
+            fs::copy(original, copy.path())
+                .wrap_err_with(|| format!("could not copy {original:?} to local disk"))?;
+        }
+        // writes still in the write-ahead log of a database open elsewhere, which sqlite reads
+        // into the copy when it's opened.
+        let wal = wal_of(original);
+        if wal.exists() {
+            fs::copy(&wal, wal_of(&copy.path()))
+                .wrap_err_with(|| format!("could not copy {wal:?} to local disk"))?;
+        }
+        Ok(copy)
+    }
+
+    pub fn path(&self) -> PathBuf {
+        self.dir.path().join("catalogue.sqlite")
+    }
+
+    /// Replaces the original database with the copy, in one rename so that it's never seen half
+    /// written.
+    pub fn copy_back(self) -> Result<()> {
+        let mut staged = NamedTempFile::new_in(parent(&self.original))?;
+        fs::copy(self.path(), staged.path())?;
+        staged.as_file_mut().sync_all()?;
+        staged.persist(&self.original).wrap_err_with(|| {
+            format!("could not copy the catalogue back to {:?}", self.original)
+        })?;
+        Ok(())
+    }
+}
+
+fn wal_of(database_file: &Path) -> PathBuf {
+    let mut wal = database_file.as_os_str().to_owned();
+    wal.push("-wal");
+    PathBuf::from(wal)
+}
+
+fn parent(path: &Path) -> &Path {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn local_copy_replaces_the_original() {
+        let dir = tempfile::tempdir().unwrap();
+        let original = dir.path().join("db.sqlite");
+        let args = vec!["--machine-id".to_string(), "laptop".to_string()];
+        PhotoSyncStore::new(original.clone())
+            .unwrap()
+            .save_profile("before", &args)
+            .unwrap();
+
+        let copy = LocalCopy::create(&original).unwrap();
+        let store = PhotoSyncStore::new(copy.path()).unwrap();
+        assert_eq!(store.profile("before").unwrap(), Some(args.clone()));
+        store.save_profile("after", &args).unwrap();
+        drop(store);
+        // changes only reach the original once copied back.
+        let profile = |name| PhotoSyncStore::new(original.clone()).unwrap().profile(name);
+        assert_eq!(profile("after").unwrap(), None);
+
+        copy.copy_back().unwrap();
+        assert_eq!(profile("after").unwrap(), Some(args));
+    }
+}
@@ -0,0 +1,107 @@
+//! Lowering the CPU and I/O priority of a run, so that a background sync goes unnoticed by
+//! everything else on the machine. Threads inherit the priority of the thread which starts them,
+//! so this must be done before any are started.
+
+use eyre::Result;
+#[cfg(not(unix))]
+use eyre::bail;
+
+/// `ioprio_set`'s arguments, which libc doesn't define.
+#[cfg(target_os = "linux")]
+mod ioprio {
+    pub const WHO_PROCESS: libc::c_int = 1;
+    pub const CLASS_IDLE: libc::c_int = 3;
+    pub const CLASS_SHIFT: libc::c_int = 13;
+}
+
+/// `setiopolicy_np`'s arguments.
+#[cfg(target_os = "macos")]
+mod iopolicy {
+    pub const TYPE_DISK: libc::c_int = 0;
+    pub const SCOPE_PROCESS: libc::c_int = 0;
+    pub const THROTTLE: libc::c_int = 3;
+
+    unsafe extern "C" {
+        pub fn setiopolicy_np(
+            iotype: libc::c_int,
+            scope: libc::c_int,
+            policy: libc::c_int,
+        ) -> libc::c_int;
+    }
+}
+
+/// Sets the CPU niceness of the current thread, and of any it goes on to start, to `nice`, and
+/// their I/O priority to idle (only using the disk when nothing else wants it) if `idle_io`.
+#[cfg(unix)]
+pub fn lower_priority(nice: Option<i32>, idle_io: bool) -> Result<()> {
+    use std::io;
+
+    if let Some(nice) = nice {
+        // SAFETY: plain integer arguments. On Linux, 0 is the calling thread.
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+    }
+    if idle_io {
+        set_idle_io()?;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn set_idle_io() -> Result<()> {
+    use ioprio::*;
+
+    let priority = CLASS_IDLE << CLASS_SHIFT;
+    // SAFETY: plain integer arguments. 0 is the calling thread.
+    if unsafe { libc::syscall(libc::SYS_ioprio_set, WHO_PROCESS, 0, priority) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+fn set_idle_io() -> Result<()> {
+    use iopolicy::*;
+
+    // SAFETY: plain integer arguments.
+    if unsafe { setiopolicy_np(TYPE_DISK, SCOPE_PROCESS, THROTTLE) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "macos"))))]
+fn set_idle_io() -> Result<()> {
+    eyre::bail!("idle I/O priority isn't supported on this platform")
+}
+
+#[cfg(not(unix))]
+pub fn lower_priority(nice: Option<i32>, idle_io: bool) -> Result<()> {
+    if nice.is_some() || idle_io {
+        bail!("lowering priority isn't supported on this platform");
+    }
+    Ok(())
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn priority_is_lowered() {
+        // in its own thread, so other tests are unaffected.
+        std::thread::spawn(|| {
+            lower_priority(Some(19), true).unwrap();
+            let nice = unsafe { libc::getpriority(libc::PRIO_PROCESS, 0) };
+            assert_eq!(nice, 19);
+            let priority = unsafe { libc::syscall(libc::SYS_ioprio_get, ioprio::WHO_PROCESS, 0) };
+            assert_eq!(
+                priority as libc::c_int >> ioprio::CLASS_SHIFT,
+                ioprio::CLASS_IDLE
+            );
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -0,0 +1,186 @@
+//! `--notify-webhook` and `--notify-command`, telling whoever looks after an unattended sync how
+//! each run went as soon as it finishes or fails, with the run's summary as JSON. Webhooks are
+//! posted with `curl`, so its proxy settings and certificates apply. A webhook's URL usually
+//! carries the token it's posted with, so it's never logged, is masked in the command line each
+//! run records, and reaches curl on its standard input rather than its command line.
+
+use std::{
+    convert::Infallible,
+    fmt,
+    io::Write,
+    process::{Command, Stdio},
+    str::FromStr,
+};
+
+use eyre::{Result, WrapErr, bail, eyre};
+
+/// Where curl is told to write the response, which isn't wanted.
+const NULL_DEVICE: &str = if cfg!(windows) { "NUL" } else { "/dev/null" };
+
+/// How long a webhook may take to answer.
+const WEBHOOK_TIMEOUT_SECS: u32 = 30;
+
+/// The option a webhook's URL is given with.
+const WEBHOOK_OPTION: &str = "--notify-webhook";
+
+/// What a webhook's URL is shown as.
+const REDACTED: &str = "<redacted>";
+
+/// A webhook's URL, which is never shown.
+#[derive(Clone)]
+pub struct WebhookUrl(String);
+
+impl FromStr for WebhookUrl {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(s.to_string()))
+    }
+}
+
+impl fmt::Debug for WebhookUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+/// `args`, a command line, with the URL given to `--notify-webhook` masked.
+pub fn redact_webhook(args: impl IntoIterator<Item = String>) -> Vec<String> {
+    let mut redacted = Vec::new();
+    let mut url_next = false;
+    for arg in args {
+        let arg = if url_next {
+            REDACTED.to_string()
+        } else if arg.starts_with(&format!("{WEBHOOK_OPTION}=")) {
+            format!("{WEBHOOK_OPTION}={REDACTED}")
+        } else {
+            arg
+        };
+        url_next = arg == WEBHOOK_OPTION;
+        redacted.push(arg);
+    }
+    redacted
+}
+
+/// Posts `summary` to `url` as JSON.
+pub fn post_webhook(url: &WebhookUrl, summary: &[u8]) -> Result<()> {
+    // the URL is given in a config on curl's standard input rather than its command line, where
+    // other users could see it, and so the summary is too.
+    let config = format!(
+        "url = {}\ndata-raw = {}\n",
+        config_string(&url.0),
+        config_string(&String::from_utf8_lossy(summary))
+    );
+    let output = pipe(
+        Command::new("curl")
+            .args(["--silent", "--show-error", "--fail", "--max-time"])
+            .arg(WEBHOOK_TIMEOUT_SECS.to_string())
+            .args(["--header", "Content-Type: application/json"])
+            .args(["--output", NULL_DEVICE, "--config", "-"]),
+        config.as_bytes(),
+    )
+    .wrap_err("could not run curl")?;
+    if !output.status.success() {
+        bail!(
+            "posting to the webhook failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// `value` quoted for a curl config, which unescapes it.
+pub(crate) fn config_string(value: &str) -> String {
+    let escaped = (value.replace('\\', "\\\\").replace('"', "\\\""))
+        .replace('\n', "\\n")
+        .replace('\r', "\\r");
+    format!("\"{escaped}\"")
+}
+
+/// Runs `command` through `sh -c`, with `summary` on its standard input and PHOTO_SYNC_STATUS set
+/// to `success` or `failure`.
+pub fn run_notify_command(command: &str, succeeded: bool, summary: &[u8]) -> Result<()> {
+    let status = if succeeded { "success" } else { "failure" };
+    let output = pipe(
+        Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .env("PHOTO_SYNC_STATUS", status),
+        summary,
+    )
+    .map_err(|e| eyre!("notify command `{command}` could not be started: {e}"))?;
+    if !output.status.success() {
+        bail!("notify command `{command}` failed: {}", output.status);
+    }
+    Ok(())
+}
+
+/// Runs `command` with `input` on its standard input, collecting its standard error.
+fn pipe(command: &mut Command, input: &[u8]) -> std::io::Result<std::process::Output> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    // a command which exits without reading it all is its own business.
+    let _ = stdin.write_all(input);
+    drop(stdin);
+    child.wait_with_output()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_are_given_the_summary() {
+        let dir = tempfile::tempdir().unwrap();
+        let received = dir.path().join("received");
+        let command = format!(
+            r#"test "$PHOTO_SYNC_STATUS" = failure && cat > "{}""#,
+            received.display()
+        );
+        run_notify_command(&command, false, br#"{"succeeded":false}"#).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&received).unwrap(),
+            r#"{"succeeded":false}"#
+        );
+        let err = run_notify_command(&command, true, b"{}").unwrap_err();
+        assert!(err.to_string().contains("failed"));
+    }
+
+    #[test]
+    fn config_strings_are_quoted_for_curl() {
+        assert_eq!(
+            config_string("{\"path\":\"a\\\\b.jpg\",\"error\":\"one\ntwo\"}"),
+            r#""{\"path\":\"a\\\\b.jpg\",\"error\":\"one\ntwo\"}""#
+        );
+    }
+
+    #[test]
+    fn webhook_urls_are_never_shown() {
+        let args = [
+            "sync",
+            "--notify-webhook",
+            "https://hooks.example.com/T0KEN",
+        ]
+        .into_iter()
+        .chain([
+            "--notify-webhook=https://hooks.example.com/T0KEN",
+            "--in-dir=/a",
+        ])
+        .map(String::from);
+        assert_eq!(
+            redact_webhook(args),
+            [
+                "sync",
+                "--notify-webhook",
+                "<redacted>",
+                "--notify-webhook=<redacted>",
+                "--in-dir=/a"
+            ]
+        );
+        let url: WebhookUrl = "https://hooks.example.com/T0KEN".parse().unwrap();
+        assert_eq!(format!("{:?}", Some(url)), "Some(<redacted>)");
+    }
+}
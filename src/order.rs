@@ -0,0 +1,76 @@
+//! Orders in which new files can be transferred. Any order but the walk's own means holding new
+//! files back until the whole in directory has been scanned.
+
+use std::{path::PathBuf, time::SystemTime};
+
+use clap::ValueEnum;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum TransferOrder {
+    /// Smallest files first, protecting as many files as possible if a run is cut short.
+    SmallestFirst,
+    /// Most recently modified files first, protecting recent photos before old ones.
+    NewestFirst,
+    /// By path.
+    Path,
+    /// Shuffled, e.g. to spread a run's load across several disks.
+    Random,
+}
+
+/// A new file found in the in directory.
+#[derive(Debug)]
+pub struct NewFile {
+    pub path: PathBuf,
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+pub fn sort(files: &mut [NewFile], order: TransferOrder) {
+    match order {
+        TransferOrder::SmallestFirst => files.sort_by_key(|f| f.size),
+        TransferOrder::NewestFirst => files.sort_by_key(|f| std::cmp::Reverse(f.modified)),
+        TransferOrder::Path => files.sort_by(|a, b| a.path.cmp(&b.path)),
+        TransferOrder::Random => fastrand::shuffle(files),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn files_are_sorted() {
+        let file = |path: &str, size, age: u64| NewFile {
+            path: PathBuf::from(path),
+            size,
+            modified: SystemTime::UNIX_EPOCH + Duration::from_secs(1_000 - age),
+        };
+        let mut files = [
+            file("b.jpg", 3, 10),
+            file("a.jpg", 2, 20),
+            file("c.mov", 1, 0),
+        ];
+        let sorted = |files: &mut [NewFile], order| {
+            sort(files, order);
+            files
+                .iter()
+                .map(|f| f.path.to_str().unwrap())
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        assert_eq!(
+            sorted(&mut files, TransferOrder::SmallestFirst),
+            "c.mov a.jpg b.jpg"
+        );
+        assert_eq!(sorted(&mut files, TransferOrder::Path), "a.jpg b.jpg c.mov");
+        assert_eq!(
+            sorted(&mut files, TransferOrder::NewestFirst),
+            "c.mov b.jpg a.jpg"
+        );
+        // shuffling loses nothing.
+        sort(&mut files, TransferOrder::Random);
+        assert_eq!(sorted(&mut files, TransferOrder::Path), "a.jpg b.jpg c.mov");
+    }
+}
@@ -0,0 +1,134 @@
+//! Finding files in the out directory which the catalogue doesn't know about, e.g. ones copied in
+//! by hand, so the two can't drift apart unnoticed.
+
+use std::path::Path;
+
+use eyre::Result;
+use walkdir::WalkDir;
+
+use crate::{
+    OrphansArgs, appledouble, chunks, destination,
+    digest::{ContentHash, digest},
+    platform::FileInfo,
+    store::{PhotoSyncStore, RunStatus},
+    trash,
+};
+
+pub fn orphans(args: OrphansArgs) -> Result<()> {
+    let store = PhotoSyncStore::new(args.database_file)?;
+    let run = args
+        .adopt
+        .then(|| store.begin_run(&args.machine_id))
+        .transpose()?;
+
+    let result = find_orphans(&store, &args.out_dir, |path, info, digest, duplicate| {
+        let content = if duplicate {
+            "a duplicate of catalogued content"
+        } else {
+            "new content"
+        };
+        match run {
+            Some(run) => {
+                store.mark_exists_in_target(run, path, info.modified, info.size, digest)?;
+                println!("adopted {path:?}, {content}");
+            }
+            None => println!("ORPHAN {path:?}: {content}"),
+        }
+        Ok(())
+    });
+
+    if let Some(run) = run {
+        let status = match &result {
+            Ok(_) => RunStatus::Succeeded,
+            Err(_) => RunStatus::Aborted,
+        };
+        store.finish_run(run, status)?;
+    }
+    let count = result?;
+    println!(
+        "{count} files in {:?} were unknown to the catalogue",
+        args.out_dir
+    );
+    Ok(())
+}
+
+/// Calls `found` for every file in `out_dir` without a catalogue row, with whether its content is
+/// already catalogued elsewhere, and returns how many there were.
+fn find_orphans(
+    store: &PhotoSyncStore,
+    out_dir: &Path,
+    mut found: impl FnMut(&Path, &FileInfo, &ContentHash, bool) -> Result<()>,
+) -> Result<usize> {
+    let mut count = 0;
+    for entry in WalkDir::new(out_dir).into_iter().filter_entry(|entry| {
+        !trash::is_trash(entry) && !destination::is_marker(entry) && !chunks::is_repository(entry)
+    }) {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let path = entry.path().strip_prefix(out_dir)?.to_path_buf();
+        if store.is_known_target(&path)? || is_carried_over_companion(store, &path)? {
+            continue;
+        }
+        let info = FileInfo::of(entry.path())?;
+        let digest = digest(entry.path())?;
+        let duplicate = store.exists_in_target(&digest)?;
+        found(&path, &info, &digest, duplicate)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// AppleDouble companions are carried over next to their data files without a row of their own.
+fn is_carried_over_companion(store: &PhotoSyncStore, path: &Path) -> Result<bool> {
+    Ok(match appledouble::data_file_of(path) {
+        Some(data_file) => store.is_known_target(&data_file)?,
+        None => false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf, time::SystemTime};
+
+    use super::*;
+
+    #[test]
+    fn orphans_are_files_without_rows() {
+        let dir = tempfile::tempdir().unwrap();
+        let out_dir = dir.path();
+        fs::create_dir_all(out_dir.join("2020")).unwrap();
+        for (path, content) in [
+            ("2020/synced.jpg", "a"),
+            ("2020/._synced.jpg", "resource fork"),
+            ("2020/copied in.jpg", "b"),
+            ("dup.jpg", "a"),
+        ] {
+            fs::write(out_dir.join(path), content).unwrap();
+        }
+        let store = PhotoSyncStore::new_for_tests().unwrap();
+        let run = store.begin_run("").unwrap();
+        let synced = Path::new("2020/synced.jpg");
+        let digest_a = digest(&out_dir.join(synced)).unwrap();
+        store
+            .mark_exists_in_target(run, synced, SystemTime::now(), 1, &digest_a)
+            .unwrap();
+
+        let mut orphans = Vec::<(PathBuf, bool)>::new();
+        let count = find_orphans(&store, out_dir, |path, _, _, duplicate| {
+            orphans.push((path.to_path_buf(), duplicate));
+            Ok(())
+        })
+        .unwrap();
+        orphans.sort();
+        assert_eq!(count, 2);
+        assert_eq!(
+            orphans,
+            [
+                (PathBuf::from("2020/copied in.jpg"), false),
+                (PathBuf::from("dup.jpg"), true)
+            ]
+        );
+    }
+}
@@ -0,0 +1,81 @@
+//! The extra checks `--paranoid` makes when transferring, trading throughput for certainty that
+//! what's archived is what was in the in directory.
+
+use std::{fs::File, io, path::Path};
+
+use eyre::Result;
+
+use crate::{
+    compress::{Transform, open_archived},
+    dedupe::same_content,
+    platform::{FileInfo, drop_cached_pages},
+};
+
+/// Whether the in directory's file still has the size and modification time it had before it was
+/// copied, so that the copy isn't of a file half-way through being written.
+pub fn unchanged_since(path: &Path, before: &FileInfo) -> io::Result<bool> {
+    let now = FileInfo::of(path)?;
+    Ok(now.size == before.size && now.modified == before.modified)
+}
+
+/// Whether the archived copy `existing`, stored as `transform`, has exactly the bytes of `staged`,
+/// rather than only a matching catalogue digest. Encrypted copies can't be read, so never match.
+pub fn matches_archived(existing: &Path, transform: Transform, staged: &Path) -> Result<bool> {
+    let Ok(content) = open_archived(existing, transform) else {
+        return Ok(false);
+    };
+    Ok(same_content(content, File::open(staged)?).unwrap_or(false))
+}
+
+/// Flushes the directory entry of a newly persisted file, so it survives a crash as well as its
+/// bytes do.
+pub fn sync_parent(path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    if let Some(dir) = path.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+/// Drops the cached pages of a newly written and flushed file, so that reading it back reads what
+/// reached the disk, e.g. through a USB enclosure which corrupts writes, rather than what was
+/// written to memory.
+/// Elsewhere than Linux, there's no way to drop them, so it's read back from memory.
+pub fn evict_from_cache(path: &Path) -> io::Result<()> {
+    drop_cached_pages(&File::open(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::compress::{compress, compressed_path};
+
+    #[test]
+    fn archived_copies_are_compared_byte_for_byte() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name);
+        fs::write(path("staged.jpg"), "photo").unwrap();
+        fs::write(path("same.jpg"), "photo").unwrap();
+        fs::write(path("rotten.jpg"), "phot0").unwrap();
+        let compressed = compressed_path(&path("same.jpg"));
+        compress(
+            &path("same.jpg"),
+            &mut File::create(&compressed).unwrap(),
+            3,
+        )
+        .unwrap();
+
+        let matches = |existing: &Path, transform| {
+            matches_archived(existing, transform, &path("staged.jpg")).unwrap()
+        };
+        assert!(matches(&path("same.jpg"), Transform::None));
+        assert!(matches(&compressed, Transform::Compressed));
+        assert!(!matches(&compressed, Transform::None));
+        assert!(!matches(&path("rotten.jpg"), Transform::None));
+        assert!(!matches(&path("gone.jpg"), Transform::None));
+    }
+}
@@ -0,0 +1,222 @@
+//! XOR parity over sets of archived files, so that any one damaged file in a set can be rebuilt
+//! from the rest of the set without keeping a second full copy of the archive.
+
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use eyre::{Result, WrapErr};
+use tempfile::NamedTempFile;
+
+use crate::{
+    ParityArgs,
+    digest::{ContentHash, DigestWriter, digest},
+    store::{ParityMember, ParitySet, PhotoSyncStore},
+};
+
+/// How much of each file is XORed at a time.
+const WINDOW: u64 = 16 * 1024 * 1024;
+
+pub fn parity(args: ParityArgs) -> Result<()> {
+    let store = PhotoSyncStore::new(args.database_file)?;
+    let max_set_bytes = args.set_size_mb * 1024 * 1024;
+
+    let mut groups = Vec::<Vec<ParityMember>>::new();
+    let mut group_bytes = 0;
+    for file in store.files_without_parity()? {
+        match groups.last_mut() {
+            Some(group) if group_bytes + file.size <= max_set_bytes => {
+                group_bytes += file.size;
+                group.push(file);
+            }
+            _ => {
+                group_bytes = file.size;
+                groups.push(vec![file]);
+            }
+        }
+    }
+
+    let mut created = 0;
+    for group in groups {
+        if let Some(set) = create_set(&args.old_out_dir, &args.parity_dir, group)? {
+            store.add_parity_set(&set, SystemTime::now())?;
+            created += 1;
+        }
+    }
+    println!("created {created} parity sets");
+    Ok(())
+}
+
+/// Writes parity for those of `members` which still match their catalogued digest.
+fn create_set(
+    old_out_dir: &Path,
+    parity_dir: &Path,
+    members: Vec<ParityMember>,
+) -> Result<Option<ParitySet>> {
+    let mut intact = Vec::with_capacity(members.len());
+    for member in members {
+        // parity over damaged content would rebuild the damage.
+        if is_intact(&old_out_dir.join(&member.path), &member.digest) {
+            intact.push(member);
+        } else {
+            println!(
+                "{:?} doesn't match the catalogue, leaving it out of parity",
+                member.path
+            );
+        }
+    }
+    let Some(len) = intact.iter().map(|m| m.size).max() else {
+        return Ok(None);
+    };
+
+    let mut staged = NamedTempFile::new_in(parity_dir)?;
+    let mut writer = DigestWriter::new(staged.as_file_mut());
+    for offset in (0..len).step_by(WINDOW as usize) {
+        let mut window = vec![0; WINDOW.min(len - offset) as usize];
+        for member in &intact {
+            xor_into(&mut window, &old_out_dir.join(&member.path), offset)?;
+        }
+        writer.write_all(&window)?;
+    }
+    let digest = writer.finalise()?;
+    staged.as_file().sync_all()?;
+    staged.persist(parity_file(parity_dir, &digest))?;
+    Ok(Some(ParitySet {
+        digest,
+        members: intact,
+    }))
+}
+
+/// Rebuilds the content `expected` of `path` from its parity set, if the set's parity and every
+/// other member are intact. The rebuilt file is staged in `parity_dir`.
+pub fn rebuild(
+    store: &PhotoSyncStore,
+    old_out_dir: &Path,
+    parity_dir: &Path,
+    path: &Path,
+    expected: &ContentHash,
+) -> Result<Option<(NamedTempFile, ContentHash)>> {
+    let Some(set) = store.parity_set_of(path, expected)? else {
+        return Ok(None);
+    };
+    let parity = parity_file(parity_dir, &set.digest);
+    if digest(&parity).ok() != Some(set.digest) {
+        println!("parity {parity:?} is missing or damaged");
+        return Ok(None);
+    }
+    let mut size = 0;
+    for member in &set.members {
+        if member.path == path {
+            size = member.size;
+        } else if !is_intact(&old_out_dir.join(&member.path), &member.digest) {
+            println!("{:?} is damaged too, so parity can't help", member.path);
+            return Ok(None);
+        }
+    }
+
+    let mut staged = NamedTempFile::new_in(parity_dir)?;
+    let mut writer = DigestWriter::with_algorithm(staged.as_file_mut(), expected.algorithm());
+    for offset in (0..size).step_by(WINDOW as usize) {
+        let mut window = vec![0; WINDOW.min(size - offset) as usize];
+        xor_into(&mut window, &parity, offset)?;
+        for member in set.members.iter().filter(|m| m.path != path) {
+            xor_into(&mut window, &old_out_dir.join(&member.path), offset)?;
+        }
+        writer.write_all(&window)?;
+    }
+    if writer.finalise()? != *expected {
+        return Ok(None);
+    }
+    Ok(Some((staged, set.digest)))
+}
+
+/// Whether the file at `path` has the content `expected`, hashed as it was.
+fn is_intact(path: &Path, expected: &ContentHash) -> bool {
+    expected.algorithm().digest(path).ok() == Some(*expected)
+}
+
+fn parity_file(parity_dir: &Path, digest: &ContentHash) -> PathBuf {
+    parity_dir.join(format!("{digest}.parity"))
+}
+
+/// XORs the bytes of `path` from `offset` into `window`. Files shorter than the window count as
+/// padded with zeroes.
+fn xor_into(window: &mut [u8], path: &Path, offset: u64) -> Result<()> {
+    let mut file = File::open(path).wrap_err_with(|| format!("could not open {path:?}"))?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut bytes = Vec::with_capacity(window.len());
+    file.take(window.len() as u64).read_to_end(&mut bytes)?;
+    window.iter_mut().zip(bytes).for_each(|(w, b)| *w ^= b);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn parity_rebuilds_any_one_member() {
+        let dir = tempfile::tempdir().unwrap();
+        let (old_out_dir, parity_dir) = (dir.path().join("old"), dir.path().join("parity"));
+        fs::create_dir_all(&old_out_dir).unwrap();
+        fs::create_dir_all(&parity_dir).unwrap();
+        let store = PhotoSyncStore::new_for_tests().unwrap();
+        let run = store.begin_run("").unwrap();
+        let contents = [
+            ("a.jpg", "short"),
+            ("b.jpg", "a longer photo"),
+            ("c.jpg", ""),
+        ];
+        for (path, content) in contents {
+            let full_path = old_out_dir.join(path);
+            fs::write(&full_path, content).unwrap();
+            store
+                .mark_exists_in_old_target(
+                    run,
+                    Path::new(path),
+                    SystemTime::now(),
+                    content.len() as u64,
+                    &digest(&full_path).unwrap(),
+                )
+                .unwrap();
+        }
+
+        let set = create_set(
+            &old_out_dir,
+            &parity_dir,
+            store.files_without_parity().unwrap(),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(set.members.len(), 3);
+        store.add_parity_set(&set, SystemTime::now()).unwrap();
+        assert_eq!(store.files_without_parity().unwrap(), []);
+
+        for (path, content) in contents {
+            let good = digest(&old_out_dir.join(path)).unwrap();
+            fs::write(old_out_dir.join(path), "rot").unwrap();
+            let (rebuilt, parity) =
+                rebuild(&store, &old_out_dir, &parity_dir, Path::new(path), &good)
+                    .unwrap()
+                    .unwrap();
+            assert_eq!(parity, set.digest);
+            assert_eq!(fs::read_to_string(rebuilt.path()).unwrap(), content);
+            fs::write(old_out_dir.join(path), content).unwrap();
+        }
+
+        // two damaged members are beyond a single parity file.
+        fs::write(old_out_dir.join("a.jpg"), "rot").unwrap();
+        let b = &set.members[1];
+        fs::write(old_out_dir.join("b.jpg"), "rot").unwrap();
+        assert!(
+            rebuild(&store, &old_out_dir, &parity_dir, &b.path, &b.digest)
+                .unwrap()
+                .is_none()
+        );
+    }
+}
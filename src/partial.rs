@@ -0,0 +1,88 @@
+//! Quick fingerprints of files in the old out directory, for `--quick-index`: a digest of their
+//! size and their first and last 64KiB, which tells nearly all photos and videos apart for a
+//! fraction of the cost of reading them whole. A file is only hashed in full once a new file
+//! shares its fingerprint, as only then could they be duplicates.
+
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use serde::{Deserialize, Serialize};
+
+/// How much of each end of a file is read.
+const SAMPLE_BYTES: u64 = 64 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct PartialDigest([u8; 32]);
+
+impl PartialDigest {
+    /// The fingerprint of `size` bytes of content read from `reader`.
+    pub fn of(reader: &mut (impl Read + Seek), size: u64) -> io::Result<Self> {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&size.to_le_bytes());
+        io::copy(&mut reader.take(SAMPLE_BYTES.min(size)), &mut hasher)?;
+        // the tail, which is left out where it would overlap the head.
+        let tail = size.saturating_sub(SAMPLE_BYTES).max(SAMPLE_BYTES);
+        if tail < size {
+            reader.seek(SeekFrom::Start(tail))?;
+            io::copy(&mut reader.take(size - tail), &mut hasher)?;
+        }
+        Ok(Self(*hasher.finalize().as_bytes()))
+    }
+
+    pub fn of_file(path: &Path) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        let size = file.metadata()?.len();
+        Self::of(&mut file, size)
+    }
+}
+
+impl ToSql for PartialDigest {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::Borrowed(ValueRef::Blob(&self.0)))
+    }
+}
+
+impl FromSql for PartialDigest {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        let blob = value.as_blob()?;
+        let bytes = blob.try_into().map_err(|_| FromSqlError::InvalidBlobSize {
+            expected_size: 32,
+            blob_size: blob.len(),
+        })?;
+        Ok(Self(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn only_the_ends_are_fingerprinted() {
+        let partial = |content: &[u8]| {
+            PartialDigest::of(&mut Cursor::new(content), content.len() as u64).unwrap()
+        };
+        let mut video = vec![1; 1_000_000];
+        let original = partial(&video);
+        // a change in the middle goes unnoticed, which the full digest then catches.
+        video[500_000] = 2;
+        assert_eq!(partial(&video), original);
+        video[999_999] = 2;
+        assert_ne!(partial(&video), original);
+        let changed = partial(&video);
+        video[0] = 2;
+        assert_ne!(partial(&video), changed);
+        // in files shorter than the two ends together, every byte is read, and only once.
+        let mut short = vec![0; 100_000];
+        let unchanged = partial(&short);
+        short[70_000] = 1;
+        assert_ne!(partial(&short), unchanged);
+        assert_eq!(partial(b"small"), partial(b"small"));
+    }
+}
@@ -0,0 +1,178 @@
+use std::{
+    sync::{
+        Arc, Condvar, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::Duration,
+};
+
+use eyre::{Result, ensure};
+#[cfg(unix)]
+use signal_hook::{
+    consts::{SIGUSR1, SIGUSR2},
+    iterator::Signals,
+};
+use tracing::info;
+
+/// Why a run is paused. It resumes once none of its reasons apply any more.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(not(unix), allow(dead_code))]
+pub enum PauseReason {
+    /// SIGUSR1 was received, and SIGUSR2 hasn't been since.
+    Signal,
+    /// It's outside the hours given by `--only-between`.
+    OutsideWindow,
+    /// The machine is on battery, per `--pause-on-battery` or `--min-battery-percent`.
+    OnBattery,
+    /// The out directory has stopped being the archive, e.g. its mount dropped or went read-only.
+    DestinationUnavailable,
+    /// It was asked to over the control socket.
+    Requested,
+}
+
+/// Lets a run be suspended without being abandoned. Workers call [`PauseControl::wait_if_paused`]
+/// before picking up new work, so files already in flight are finished first.
+#[derive(Default)]
+pub struct PauseControl {
+    paused: Mutex<Vec<PauseReason>>,
+    resumed: Condvar,
+    aborted: AtomicBool,
+}
+
+impl PauseControl {
+    /// Creates a control which is paused by SIGUSR1 and resumed by SIGUSR2.
+    #[cfg(unix)]
+    pub fn with_signal_handlers() -> Result<Arc<Self>> {
+        let control = Arc::new(Self::default());
+        let mut signals = Signals::new([SIGUSR1, SIGUSR2])?;
+        let handler = control.clone();
+        thread::spawn(move || {
+            for signal in signals.forever() {
+                match signal {
+                    SIGUSR1 => handler.pause(PauseReason::Signal),
+                    SIGUSR2 => handler.resume(PauseReason::Signal),
+                    _ => unreachable!("only registered for SIGUSR1 and SIGUSR2"),
+                }
+            }
+        });
+        Ok(control)
+    }
+
+    /// Creates a control which is never paused, as there are no signals to pause it with.
+    #[cfg(not(unix))]
+    pub fn with_signal_handlers() -> Result<Arc<Self>> {
+        Ok(Arc::default())
+    }
+
+    pub fn pause(&self, reason: PauseReason) {
+        let mut paused = self.paused.lock().unwrap();
+        if !paused.contains(&reason) {
+            info!("pausing ({reason:?}): in-flight files will finish, but no new work will start");
+            paused.push(reason);
+        }
+    }
+
+    pub fn resume(&self, reason: PauseReason) {
+        let mut paused = self.paused.lock().unwrap();
+        let Some(idx) = paused.iter().position(|r| *r == reason) else {
+            return;
+        };
+        paused.remove(idx);
+        if paused.is_empty() {
+            info!("resuming");
+            self.resumed.notify_all();
+        } else {
+            info!("{reason:?} no longer applies, but still paused for {paused:?}");
+        }
+    }
+
+    /// Stops workers from starting new work, as if paused for good, and wakes any which are
+    /// paused so that they can give up.
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub fn abort(&self) {
+        info!("aborting: in-flight files will finish, but no new work will start");
+        let _paused = self.paused.lock().unwrap();
+        self.aborted.store(true, Ordering::SeqCst);
+        self.resumed.notify_all();
+    }
+
+    /// The reasons the run is paused for, if it is.
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub fn reasons(&self) -> Vec<PauseReason> {
+        self.paused.lock().unwrap().clone()
+    }
+
+    /// Blocks while paused, and fails once aborted.
+    pub fn wait_if_paused(&self) -> Result<()> {
+        let paused = self.paused.lock().unwrap();
+        drop(
+            self.resumed
+                .wait_while(paused, |paused| {
+                    !paused.is_empty() && !self.aborted.load(Ordering::SeqCst)
+                })
+                .unwrap(),
+        );
+        ensure!(!self.aborted.load(Ordering::SeqCst), "the run was aborted");
+        Ok(())
+    }
+
+    /// Pauses for `reason` whenever `applies` says it does, checking now and then every
+    /// `interval` for the rest of the process.
+    pub fn watch(
+        self: &Arc<Self>,
+        reason: PauseReason,
+        interval: Duration,
+        applies: impl Fn() -> bool + Send + 'static,
+    ) {
+        let update = move |control: &Self| match applies() {
+            true => control.pause(reason),
+            false => control.resume(reason),
+        };
+        // checked before returning, so that no work starts when it shouldn't.
+        update(self);
+        let control = self.clone();
+        thread::spawn(move || {
+            loop {
+                thread::sleep(interval);
+                update(&control);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn workers_wait_until_resumed() {
+        let control = PauseControl::default();
+        let worked = AtomicBool::new(false);
+        control.pause(PauseReason::Signal);
+        control.pause(PauseReason::OutsideWindow);
+        thread::scope(|s| {
+            s.spawn(|| {
+                control.wait_if_paused().unwrap();
+                worked.store(true, Ordering::SeqCst);
+            });
+            thread::sleep(Duration::from_millis(50));
+            control.resume(PauseReason::Signal);
+            thread::sleep(Duration::from_millis(50));
+            // still outside the window.
+            assert!(!worked.load(Ordering::SeqCst));
+            control.resume(PauseReason::OutsideWindow);
+        });
+        assert!(worked.load(Ordering::SeqCst));
+
+        // aborting lets paused workers give up rather than wait forever.
+        control.pause(PauseReason::Requested);
+        thread::scope(|s| {
+            let waiting = s.spawn(|| control.wait_if_paused());
+            thread::sleep(Duration::from_millis(50));
+            control.abort();
+            assert!(waiting.join().unwrap().is_err());
+        });
+    }
+}
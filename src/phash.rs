@@ -0,0 +1,106 @@
+//! Perceptual hashes of images, which stay (nearly) the same when an image is resized or
+//! recompressed, so that the same photo can be recognised across different files. HEIC and HEIF
+//! photos, which most iPhones take, can't be decoded without libheif, which isn't linked, so they
+//! are counted as left out rather than fingerprinted.
+
+use std::{
+    io::{Cursor, Read},
+    path::Path,
+};
+
+use eyre::Result;
+use image::{ImageFormat, ImageReader, imageops::FilterType};
+use serde::{Deserialize, Serialize};
+
+use crate::compress::{Transform, open_archived};
+
+/// An image's dimensions and perceptual hash.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageFingerprint {
+    pub width: u32,
+    pub height: u32,
+    /// A difference hash: whether each pixel of a 9x8 greyscale thumbnail is brighter than the
+    /// one to its right.
+    pub hash: u64,
+}
+
+impl ImageFingerprint {
+    pub fn pixels(&self) -> u64 {
+        u64::from(self.width) * u64::from(self.height)
+    }
+
+    /// How many bits of the two hashes differ. Small distances mean the same picture.
+    pub fn distance(&self, other: &Self) -> u32 {
+        (self.hash ^ other.hash).count_ones()
+    }
+}
+
+/// Whether a file named `name` looks like an image which can be fingerprinted.
+pub fn is_image(name: &Path) -> bool {
+    ImageFormat::from_path(name).is_ok()
+}
+
+/// Whether a file named `name` is a HEIC or HEIF image, which can't be decoded to fingerprint it.
+pub fn is_heic(name: &Path) -> bool {
+    name.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            ["heic", "heif"]
+                .iter()
+                .any(|heic| extension.eq_ignore_ascii_case(heic))
+        })
+}
+
+/// Decodes the archived image at `path`, stored as `transform`, and fingerprints it.
+pub fn fingerprint(path: &Path, transform: Transform) -> Result<ImageFingerprint> {
+    let mut bytes = Vec::new();
+    open_archived(path, transform)?.read_to_end(&mut bytes)?;
+    let image = ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()?
+        .decode()?;
+    let thumbnail = image.grayscale().resize_exact(9, 8, FilterType::Triangle);
+    let luma = thumbnail.to_luma8();
+    let mut hash = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            let brighter = luma.get_pixel(x, y).0[0] > luma.get_pixel(x + 1, y).0[0];
+            hash = (hash << 1) | u64::from(brighter);
+        }
+    }
+    Ok(ImageFingerprint {
+        width: image.width(),
+        height: image.height(),
+        hash,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GrayImage, Luma, imageops};
+
+    use super::*;
+
+    #[test]
+    fn resized_images_hash_alike() {
+        let dir = tempfile::tempdir().unwrap();
+        let original = GrayImage::from_fn(640, 480, |x, y| {
+            Luma([((x * 7 + y * 3) % 256) as u8 ^ ((x / 40 + y / 40) % 2 * 255) as u8])
+        });
+        original.save(dir.path().join("original.png")).unwrap();
+        imageops::resize(&original, 160, 120, FilterType::Triangle)
+            .save(dir.path().join("small.jpg"))
+            .unwrap();
+        let other = GrayImage::from_fn(640, 480, |x, _| Luma([(x % 256) as u8]));
+        other.save(dir.path().join("other.png")).unwrap();
+
+        let original = fingerprint(&dir.path().join("original.png"), Transform::None).unwrap();
+        let small = fingerprint(&dir.path().join("small.jpg"), Transform::None).unwrap();
+        let other = fingerprint(&dir.path().join("other.png"), Transform::None).unwrap();
+        assert_eq!((original.width, small.width), (640, 160));
+        assert!(original.distance(&small) <= 4, "{original:?} {small:?}");
+        assert!(original.distance(&other) > 10, "{original:?} {other:?}");
+        assert!(is_image(Path::new("a.JPG")));
+        assert!(!is_image(Path::new("a.mov")));
+        assert!(!is_image(Path::new("IMG_0001.HEIC")) && is_heic(Path::new("IMG_0001.HEIC")));
+    }
+}
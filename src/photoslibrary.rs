@@ -0,0 +1,174 @@
+//! A macOS Photos library as an in directory, read in place rather than exported first. Its
+//! originals are stored as `originals/<n>/<UUID>.<ext>`, so the library's database is read for
+//! the name each was imported with and when it was taken, and each is archived as
+//! `YYYY/MM/<original name>`, as `--organize-by-date` lays out other files. Originals of the same
+//! name are numbered here, and a name already taken in the out directory when an original is
+//! transferred, as `--organize-by-date` numbers it. Only libraries of Photos 5 (macOS 10.15) and
+//! later, which keep their originals there, are read.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Datelike, Local};
+use eyre::{Result, WrapErr, bail};
+use rusqlite::{Connection, OpenFlags, OptionalExtension};
+
+use crate::bydate;
+
+/// Where the library's database is, within its package.
+const DATABASE: &str = "database/Photos.sqlite";
+/// Where the library's originals are, within its package.
+const ORIGINALS: &str = "originals";
+/// The Unix time of Core Data's epoch, 2001-01-01, which the database's times count from.
+const CORE_DATA_EPOCH: i64 = 978_307_200;
+
+/// What's known of a library's originals, loaded when a sync starts.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct PhotosLibrary {
+    /// Where each original is archived, by its path under `originals`.
+    archived_as: HashMap<PathBuf, PathBuf>,
+    /// Where each asset is archived, by its UUID, for the videos of Live Photos, which are stored
+    /// as `<UUID>_3.mov` beside their still but aren't assets of their own.
+    by_uuid: HashMap<String, PathBuf>,
+}
+
+/// Whether `dir` is a Photos library's package.
+pub fn is_photos_library(dir: &Path) -> bool {
+    dir.join(DATABASE).is_file()
+}
+
+/// The directory of the library `package`'s originals, which its files are found under.
+pub fn originals(package: &Path) -> PathBuf {
+    package.join(ORIGINALS)
+}
+
+impl PhotosLibrary {
+    /// Reads the originals of the library `package`.
+    pub fn open(package: &Path) -> Result<Self> {
+        if !originals(package).is_dir() {
+            bail!(
+                "{package:?} has no {ORIGINALS} directory; only libraries of Photos 5 (macOS \
+                 10.15) and later can be synced"
+            );
+        }
+        let database = package.join(DATABASE);
+        // Photos may have it open, so it's only read.
+        let conn = Connection::open_with_flags(&database, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .wrap_err_with(|| format!("could not open {database:?}"))?;
+        // the table of assets was renamed in macOS 11.
+        let table: Option<String> = conn
+            .query_row(
+                "SELECT name FROM sqlite_master WHERE type='table' \
+                 AND name IN ('ZASSET', 'ZGENERICASSET') ORDER BY name LIMIT 1",
+                [],
+                |r| r.get(0),
+            )
+            .optional()?;
+        let Some(table) = table else {
+            bail!("{database:?} has no table of assets; is it a Photos library?");
+        };
+        let mut stmt = conn.prepare(&format!(
+            "SELECT a.ZDIRECTORY, a.ZFILENAME, a.ZDATECREATED, a.ZUUID, aa.ZORIGINALFILENAME \
+             FROM {table} a LEFT JOIN ZADDITIONALASSETATTRIBUTES aa ON aa.ZASSET = a.Z_PK \
+             WHERE a.ZDIRECTORY IS NOT NULL AND a.ZFILENAME IS NOT NULL \
+             ORDER BY a.ZDATECREATED, a.Z_PK"
+        ))?;
+        let assets = stmt.query_map([], |r| {
+            Ok((
+                r.get::<_, String>(0)?,
+                r.get::<_, String>(1)?,
+                r.get::<_, Option<f64>>(2)?,
+                r.get::<_, Option<String>>(3)?,
+                r.get::<_, Option<String>>(4)?,
+            ))
+        })?;
+        let mut library = Self::default();
+        let mut taken = HashSet::new();
+        for asset in assets {
+            let (dir, file_name, created, uuid, original_name) = asset?;
+            let stored = Path::new(&dir).join(&file_name);
+            let name = original_name.unwrap_or(file_name);
+            let created = created
+                .and_then(|secs| DateTime::from_timestamp(CORE_DATA_EPOCH + secs as i64, 0))
+                .unwrap_or_default()
+                .with_timezone(&Local);
+            let archived_as = bydate::dated_path(
+                Path::new(&name),
+                (created.year(), created.month()),
+                |path| taken.contains(path),
+            );
+            taken.insert(archived_as.clone());
+            if let Some(uuid) = uuid {
+                library.by_uuid.insert(uuid, archived_as.clone());
+            }
+            library.archived_as.insert(stored, archived_as);
+        }
+        Ok(library)
+    }
+
+    /// Where the original at `path`, under `originals`, is archived, or `None` for a file the
+    /// database didn't know of when the library was read, e.g. one imported since.
+    pub fn archived_as(&self, path: &Path) -> Option<PathBuf> {
+        if let Some(archived_as) = self.archived_as.get(path) {
+            return Some(archived_as.clone());
+        }
+        let (uuid, _) = path.file_stem()?.to_str()?.split_once('_')?;
+        let still = self.by_uuid.get(uuid)?;
+        Some(still.with_extension(path.extension()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn originals_are_archived_by_date_under_their_original_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let package = dir.path().join("Photos Library.photoslibrary");
+        fs::create_dir_all(package.join("database")).unwrap();
+        fs::create_dir_all(package.join("originals/A")).unwrap();
+        assert!(!is_photos_library(&package));
+        let conn = Connection::open(package.join(DATABASE)).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE ZASSET (Z_PK INTEGER PRIMARY KEY, ZDIRECTORY VARCHAR, \
+                 ZFILENAME VARCHAR, ZDATECREATED TIMESTAMP, ZUUID VARCHAR);
+             CREATE TABLE ZADDITIONALASSETATTRIBUTES (Z_PK INTEGER PRIMARY KEY, ZASSET INTEGER, \
+                 ZORIGINALFILENAME VARCHAR);
+             -- mid-July 2023, and mid-August, so the month is the same in every timezone.
+             INSERT INTO ZASSET VALUES (1, 'A', 'AAAA.heic', 711000000, 'AAAA');
+             INSERT INTO ZASSET VALUES (2, 'B', 'BBBB.heic', 711000100, 'BBBB');
+             INSERT INTO ZASSET VALUES (3, 'C', 'CCCC.jpeg', 713600000, 'CCCC');
+             INSERT INTO ZADDITIONALASSETATTRIBUTES VALUES (1, 1, 'IMG_0001.HEIC');
+             INSERT INTO ZADDITIONALASSETATTRIBUTES VALUES (2, 2, 'IMG_0001.HEIC');",
+        )
+        .unwrap();
+        drop(conn);
+        assert!(is_photos_library(&package));
+
+        let library = PhotosLibrary::open(&package).unwrap();
+        let archived_as = |path: &str| library.archived_as(Path::new(path));
+        assert_eq!(
+            archived_as("A/AAAA.heic"),
+            Some(PathBuf::from("2023/07/IMG_0001.HEIC"))
+        );
+        assert_eq!(
+            archived_as("B/BBBB.heic"),
+            Some(PathBuf::from("2023/07/IMG_0001 (2).HEIC"))
+        );
+        // without an original name, it keeps the one it's stored under.
+        assert_eq!(
+            archived_as("C/CCCC.jpeg"),
+            Some(PathBuf::from("2023/08/CCCC.jpeg"))
+        );
+        assert_eq!(
+            archived_as("A/AAAA_3.mov"),
+            Some(PathBuf::from("2023/07/IMG_0001.mov"))
+        );
+        assert_eq!(archived_as("D/DDDD.heic"), None);
+    }
+}
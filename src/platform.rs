@@ -0,0 +1,298 @@
+//! File metadata read and written the same way on every platform, degrading gracefully where a
+//! platform lacks something (e.g. permission bits).
+
+use std::{
+    fs::{File, FileTimes, FileType, Metadata},
+    io,
+    path::Path,
+    time::SystemTime,
+};
+
+/// The metadata used to tell whether a file has changed since it was last seen.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileInfo {
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+impl FileInfo {
+    pub fn from_metadata(metadata: &Metadata) -> io::Result<Self> {
+        Ok(Self {
+            size: metadata.len(),
+            modified: metadata.modified()?,
+        })
+    }
+
+    pub fn of(path: &Path) -> io::Result<Self> {
+        Self::from_metadata(&path.metadata()?)
+    }
+}
+
+/// Makes a file written to the out directory readable by everyone, whatever the umask. Where
+/// there are no permission bits, files are left as created.
+pub fn set_archive_permissions(path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o644))
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        Ok(())
+    }
+}
+
+/// Creates `dir` and whichever directories between it and `root` are missing, one at a time, so
+/// that one another transfer creates meanwhile is taken as it is rather than failing either. Each
+/// created is readable by everyone whatever the umask and, where files have owners, owned as
+/// `root` is, e.g. by the user whose archive it is when syncing as root.
+pub fn create_archive_dirs(root: &Path, dir: &Path) -> io::Result<()> {
+    if dir.is_dir() {
+        return Ok(());
+    }
+    let Ok(relative) = dir.strip_prefix(root) else {
+        return Err(io::Error::other(format!("{dir:?} isn't in {root:?}")));
+    };
+    std::fs::create_dir_all(root)?;
+    let owner = root.metadata()?;
+    let mut current = root.to_path_buf();
+    for component in relative.components() {
+        current.push(component);
+        match std::fs::create_dir(&current) {
+            Ok(()) => set_dir_permissions(&current, &owner)?,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists && current.is_dir() => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Gives the directory `path` the permissions of every other in the out directory, and the owner
+/// of the one whose metadata is `owner` where it may be given away.
+fn set_dir_permissions(path: &Path, owner: &Metadata) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))?;
+        let created = path.metadata()?;
+        if (created.uid(), created.gid()) != (owner.uid(), owner.gid()) {
+            match std::os::unix::fs::chown(path, Some(owner.uid()), Some(owner.gid())) {
+                // only root may give files away, so others' are left theirs.
+                Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {}
+                result => result?,
+            }
+        }
+        Ok(())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (path, owner);
+        Ok(())
+    }
+}
+
+/// Gives `file` the modification time of the file whose metadata is `source`, and its access time
+/// where the platform keeps one, so that copies sort by date as the originals do.
+pub fn copy_times(source: &Metadata, file: &File) -> io::Result<()> {
+    let mut times = FileTimes::new().set_modified(source.modified()?);
+    if let Ok(accessed) = source.accessed() {
+        times = times.set_accessed(accessed);
+    }
+    file.set_times(times)
+}
+
+/// Copies the extended attributes of `from` to `to`, e.g. the Finder tags macOS keeps in
+/// `com.apple.metadata:_kMDItemUserTags`. Where there are none, e.g. on Windows, there's nothing
+/// to copy.
+#[cfg(unix)]
+pub fn copy_xattrs(from: &Path, to: &Path) -> io::Result<()> {
+    if !xattr::SUPPORTED_PLATFORM {
+        return Ok(());
+    }
+    for name in xattr::list(from)? {
+        if let Some(value) = xattr::get(from, &name)? {
+            xattr::set(to, &name, &value)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn copy_xattrs(_from: &Path, _to: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Tells the kernel `file`'s cached pages won't be read again, so they're dropped rather than
+/// pushing out what will be. Only Linux can be told, and only pages already written to disk go.
+pub fn drop_cached_pages(file: &File) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::fd::AsRawFd;
+
+        // SAFETY: the descriptor is open for the duration of the call.
+        let result =
+            unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+        if result != 0 {
+            return Err(io::Error::from_raw_os_error(result));
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = file;
+    Ok(())
+}
+
+/// Whether `a` and `b` are on the same filesystem, so a file can be renamed from one to the other,
+/// or `None` if that can't be told on this platform.
+pub fn same_filesystem(a: &Path, b: &Path) -> io::Result<Option<bool>> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        Ok(Some(a.metadata()?.dev() == b.metadata()?.dev()))
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (a, b);
+        Ok(None)
+    }
+}
+
+/// Whether `a` and `b` are links to the same file, or `None` if that can't be told on this
+/// platform.
+pub fn same_file(a: &Path, b: &Path) -> io::Result<Option<bool>> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let (a, b) = (a.metadata()?, b.metadata()?);
+        Ok(Some(a.dev() == b.dev() && a.ino() == b.ino()))
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (a, b);
+        Ok(None)
+    }
+}
+
+/// Whether the file with `metadata` is dataless: its content is only in the cloud, e.g. iCloud
+/// Drive or iCloud for Windows, to be downloaded when it's read. Only macOS and Windows have such
+/// files.
+pub fn is_dataless(metadata: &Metadata) -> bool {
+    #[cfg(target_os = "macos")]
+    {
+        use std::os::macos::fs::MetadataExt;
+        // `SF_DATALESS` from `<sys/stat.h>`, which the libc crate doesn't have.
+        const SF_DATALESS: u32 = 0x4000_0000;
+        metadata.st_flags() & SF_DATALESS != 0
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::MetadataExt;
+        // the attribute of cloud files' placeholders, which the windows crate would be needed for.
+        const FILE_ATTRIBUTE_RECALL_ON_DATA_ACCESS: u32 = 0x0040_0000;
+        metadata.file_attributes() & FILE_ATTRIBUTE_RECALL_ON_DATA_ACCESS != 0
+    }
+    #[cfg(not(any(target_os = "macos", windows)))]
+    {
+        let _ = metadata;
+        false
+    }
+}
+
+/// What kind of special file this is, e.g. a FIFO, which is neither a regular file nor a
+/// directory nor a link, so has no content to sync. Opening some, e.g. FIFOs, blocks forever.
+pub fn special_kind(file_type: FileType) -> Option<&'static str> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        if file_type.is_fifo() {
+            return Some("FIFO");
+        }
+        if file_type.is_socket() {
+            return Some("socket");
+        }
+        if file_type.is_block_device() {
+            return Some("block device");
+        }
+        if file_type.is_char_device() {
+            return Some("character device");
+        }
+    }
+    if file_type.is_file() || file_type.is_dir() || file_type.is_symlink() {
+        None
+    } else {
+        Some("special file")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn archived_files_are_readable() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.jpg");
+        std::fs::write(&path, b"alpha").unwrap();
+        set_archive_permissions(&path).unwrap();
+        assert_eq!(FileInfo::of(&path).unwrap().size, 5);
+        assert_ne!(same_filesystem(dir.path(), &path).unwrap(), Some(false));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(path.metadata().unwrap().permissions().mode() & 0o777, 0o644);
+            let socket = dir.path().join("stray.sock");
+            let _listener = std::os::unix::net::UnixListener::bind(&socket).unwrap();
+            let file_type = |path: &Path| path.symlink_metadata().unwrap().file_type();
+            assert_eq!(special_kind(file_type(&socket)), Some("socket"));
+            assert_eq!(
+                special_kind(file_type(Path::new("/dev/null"))),
+                Some("character device")
+            );
+        }
+        assert_eq!(special_kind(path.metadata().unwrap().file_type()), None);
+    }
+
+    #[test]
+    fn missing_directories_are_created_alike() {
+        let dir = tempfile::tempdir().unwrap();
+        let nested = dir.path().join("2019/07/14");
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| create_archive_dirs(dir.path(), &nested).unwrap());
+            }
+        });
+        assert!(nested.is_dir());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{MetadataExt, PermissionsExt};
+            for created in ["2019", "2019/07", "2019/07/14"] {
+                let metadata = dir.path().join(created).metadata().unwrap();
+                assert_eq!(metadata.permissions().mode() & 0o777, 0o755);
+                assert_eq!(metadata.uid(), dir.path().metadata().unwrap().uid());
+            }
+        }
+        create_archive_dirs(dir.path(), &nested).unwrap();
+
+        std::fs::write(dir.path().join("2020"), b"not a directory").unwrap();
+        assert!(create_archive_dirs(dir.path(), &dir.path().join("2020/01")).is_err());
+        assert!(create_archive_dirs(&nested, &dir.path().join("2021")).is_err());
+    }
+
+    #[test]
+    fn copies_keep_the_original_times() {
+        let dir = tempfile::tempdir().unwrap();
+        let (original, copy) = (dir.path().join("a.jpg"), dir.path().join("b.jpg"));
+        std::fs::write(&original, b"alpha").unwrap();
+        let taken = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_500_000_000);
+        File::options()
+            .write(true)
+            .open(&original)
+            .unwrap()
+            .set_modified(taken)
+            .unwrap();
+        std::fs::write(&copy, b"alpha").unwrap();
+
+        copy_times(&original.metadata().unwrap(), &File::open(&copy).unwrap()).unwrap();
+        assert_eq!(FileInfo::of(&copy).unwrap().modified, taken);
+    }
+}
@@ -0,0 +1,233 @@
+//! User supplied customisation of which files are synced, and where they are written.
+//!
+//! A WebAssembly plugin must export its `memory` and an `alloc(len: i32) -> i32` function returning
+//! a buffer the host can write a path into. It may then export either of:
+//!
+//! - `accept(ptr: i32, len: i32) -> i32`, returning non-zero if the file whose UTF-8 path (relative
+//!   to the in directory) is at `ptr..ptr + len` should be synced.
+//! - `destination(ptr: i32, len: i32) -> i64`, returning the path relative to the out directory
+//!   that the file should be written to, packed as `(ptr << 32) | len`, or -1 to leave it as is.
+//!
+//! Plugins get no imports, so they can't touch the filesystem or network, and each call is given
+//! a budget of fuel, so one which loops forever fails the file rather than hanging the sync.
+
+use std::{
+    path::{Component, Path, PathBuf},
+    sync::Mutex,
+};
+
+use eyre::{ContextCompat, Result, ensure, eyre};
+use wasmi::{Config, Engine, Instance, Linker, Memory, Module, Store, TypedFunc};
+
+/// The fuel each call into a plugin gets, roughly an instruction each: far more than rewriting a
+/// path takes, but used up in about a second.
+const FUEL_PER_CALL: u64 = 100_000_000;
+
+/// Customises the sync without changing the pipeline itself.
+pub trait SyncPlugin: Sync {
+    /// Whether `path`, relative to the in directory, should be synced at all.
+    fn accept(&self, _path: &Path) -> Result<bool> {
+        Ok(true)
+    }
+
+    /// The path, relative to the out directory, that the file at `path` should be written to.
+    fn destination(&self, path: &Path) -> Result<PathBuf> {
+        Ok(path.to_path_buf())
+    }
+}
+
+/// Syncs everything, to the same relative path it had in the source.
+pub struct NoPlugin;
+
+impl SyncPlugin for NoPlugin {}
+
+struct WasmInstance {
+    store: Store<()>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    accept: Option<TypedFunc<(i32, i32), i32>>,
+    destination: Option<TypedFunc<(i32, i32), i64>>,
+    /// The fuel each call gets.
+    fuel: u64,
+}
+
+impl WasmInstance {
+    /// Tops the fuel back up for another call.
+    fn refuel(&mut self) -> Result<()> {
+        self.store
+            .set_fuel(self.fuel)
+            .map_err(|e| eyre!("could not fuel the plugin: {e}"))
+    }
+
+    fn write_path(&mut self, path: &Path) -> Result<(i32, i32)> {
+        let path = path
+            .to_str()
+            .wrap_err_with(|| format!("plugins need UTF-8 paths, got {path:?}"))?;
+        let len = i32::try_from(path.len())?;
+        self.refuel()?;
+        let ptr = self.alloc.call(&mut self.store, len)?;
+        self.memory
+            .write(&mut self.store, ptr as usize, path.as_bytes())
+            .map_err(|e| eyre!("plugin returned an invalid buffer: {e}"))?;
+        Ok((ptr, len))
+    }
+}
+
+/// A plugin compiled to WebAssembly, see the module docs for the interface it must implement.
+pub struct WasmPlugin(Mutex<WasmInstance>);
+
+impl WasmPlugin {
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::load_with_fuel(path, FUEL_PER_CALL)
+    }
+
+    /// Loads the plugin at `path`, giving each call `fuel`.
+    fn load_with_fuel(path: &Path, fuel: u64) -> Result<Self> {
+        let engine = Engine::new(Config::default().consume_fuel(true));
+        let module = Module::new(&engine, &std::fs::read(path)?)
+            .map_err(|e| eyre!("could not load plugin {path:?}: {e}"))?;
+        let mut store = Store::new(&engine, ());
+        let instance: Instance = Linker::new(&engine)
+            .instantiate(&mut store, &module)
+            .and_then(|pre| pre.start(&mut store))
+            .map_err(|e| eyre!("could not instantiate plugin {path:?}: {e}"))?;
+
+        let memory = instance
+            .get_memory(&store, "memory")
+            .wrap_err("plugin must export its memory")?;
+        let alloc = instance
+            .get_typed_func(&store, "alloc")
+            .map_err(|e| eyre!("plugin must export alloc(i32) -> i32: {e}"))?;
+        let accept = instance.get_typed_func(&store, "accept").ok();
+        let destination = instance.get_typed_func(&store, "destination").ok();
+        ensure!(
+            accept.is_some() || destination.is_some(),
+            "plugin {path:?} exports neither accept nor destination"
+        );
+
+        Ok(Self(Mutex::new(WasmInstance {
+            store,
+            memory,
+            alloc,
+            accept,
+            destination,
+            fuel,
+        })))
+    }
+}
+
+impl SyncPlugin for WasmPlugin {
+    fn accept(&self, path: &Path) -> Result<bool> {
+        let mut instance = self.0.lock().unwrap();
+        let Some(accept) = instance.accept else {
+            return Ok(true);
+        };
+        let (ptr, len) = instance.write_path(path)?;
+        instance.refuel()?;
+        Ok(accept.call(&mut instance.store, (ptr, len))? != 0)
+    }
+
+    fn destination(&self, path: &Path) -> Result<PathBuf> {
+        let mut instance = self.0.lock().unwrap();
+        let Some(destination) = instance.destination else {
+            return Ok(path.to_path_buf());
+        };
+        let (ptr, len) = instance.write_path(path)?;
+        instance.refuel()?;
+        let packed = destination.call(&mut instance.store, (ptr, len))?;
+        if packed == -1 {
+            return Ok(path.to_path_buf());
+        }
+
+        // read in place, as the length is the plugin's to choose.
+        let (ptr, len) = ((packed >> 32) as u32 as usize, packed as u32 as usize);
+        let buf = (instance.memory.data(&instance.store))
+            .get(ptr..ptr.saturating_add(len))
+            .wrap_err("plugin returned an invalid destination buffer")?;
+        let destination = PathBuf::from(std::str::from_utf8(buf)?);
+        // the out directory must stay the only place we write to, and not be written over itself.
+        ensure!(
+            destination.components().next().is_some()
+                && destination
+                    .components()
+                    .all(|c| matches!(c, Component::Normal(_))),
+            "plugin rewrote {path:?} to {destination:?}, which is not a plain relative path"
+        );
+        Ok(destination)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Rejects paths starting with `.` and moves everything else under `photos/`.
+    const PLUGIN: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (data (i32.const 0) "photos/")
+          (global $next (mut i32) (i32.const 1024))
+          (func (export "alloc") (param $len i32) (result i32)
+            (local $ptr i32)
+            (local.set $ptr (global.get $next))
+            (global.set $next (i32.add (global.get $next) (local.get $len)))
+            (local.get $ptr))
+          (func (export "accept") (param $ptr i32) (param $len i32) (result i32)
+            (i32.ne (i32.load8_u (local.get $ptr)) (i32.const 46)))
+          (func (export "destination") (param $ptr i32) (param $len i32) (result i64)
+            (memory.copy (i32.const 7) (local.get $ptr) (local.get $len))
+            (i64.extend_i32_u (i32.add (local.get $len) (i32.const 7)))))
+    "#;
+
+    #[test]
+    fn wasm_plugin_filters_and_rewrites() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("plugin.wasm");
+        std::fs::write(&path, wat::parse_str(PLUGIN).unwrap()).unwrap();
+        let plugin = WasmPlugin::load(&path).unwrap();
+
+        assert!(plugin.accept(Path::new("IMG_0001.HEIC")).unwrap());
+        assert!(!plugin.accept(Path::new(".DS_Store")).unwrap());
+        assert_eq!(
+            plugin.destination(Path::new("IMG_0001.HEIC")).unwrap(),
+            Path::new("photos/IMG_0001.HEIC")
+        );
+    }
+
+    /// Never accepts a file, looping forever instead, and returns `packed` as the destination.
+    fn misbehaving(packed: i64) -> String {
+        format!(
+            r#"
+            (module
+              (memory (export "memory") 1)
+              (func (export "alloc") (param $len i32) (result i32)
+                (i32.const 1024))
+              (func (export "accept") (param $ptr i32) (param $len i32) (result i32)
+                (loop $forever (br $forever))
+                (i32.const 1))
+              (func (export "destination") (param $ptr i32) (param $len i32) (result i64)
+                (i64.const {packed})))
+            "#
+        )
+    }
+
+    #[test]
+    fn misbehaving_plugins_fail_the_call() {
+        let dir = tempfile::tempdir().unwrap();
+        let load = |packed: i64| {
+            let path = dir.path().join(format!("{packed}.wasm"));
+            std::fs::write(&path, wat::parse_str(misbehaving(packed)).unwrap()).unwrap();
+            WasmPlugin::load_with_fuel(&path, 10_000).unwrap()
+        };
+        let photo = Path::new("IMG_0001.HEIC");
+
+        let plugin = load(0);
+        let err = plugin.accept(photo).unwrap_err();
+        assert!(format!("{err:#}").contains("fuel"), "{err:#}");
+        // nothing, which would be the out directory itself.
+        assert!(plugin.destination(photo).is_err());
+        // far more than its memory, which isn't allocated to be read into.
+        assert!(load(0xffff_ffff).destination(photo).is_err());
+        assert!(load(-2).destination(photo).is_err());
+    }
+}
@@ -0,0 +1,132 @@
+//! Telling whether a laptop is running on battery, so that a long sync can wait to be plugged in
+//! rather than flattening it.
+
+use std::{fs, path::Path};
+
+use eyre::Result;
+
+/// Where Linux lists power supplies.
+#[cfg(target_os = "linux")]
+const POWER_SUPPLIES: &str = "/sys/class/power_supply";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PowerStatus {
+    pub on_battery: bool,
+    /// The battery's charge, as a percentage.
+    pub charge: Option<u8>,
+}
+
+impl PowerStatus {
+    /// Whether work should wait: only ever on battery, and then either always or when the charge
+    /// is below `min_charge`.
+    pub fn should_pause(&self, always: bool, min_charge: Option<u8>) -> bool {
+        let low = match (self.charge, min_charge) {
+            (Some(charge), Some(min_charge)) => charge < min_charge,
+            _ => false,
+        };
+        self.on_battery && (always || low)
+    }
+}
+
+/// The machine's power status, or `None` if it has no battery.
+#[cfg(target_os = "linux")]
+pub fn power_status() -> Result<Option<PowerStatus>> {
+    read_power_supplies(Path::new(POWER_SUPPLIES))
+}
+
+#[cfg(target_os = "macos")]
+pub fn power_status() -> Result<Option<PowerStatus>> {
+    let output = std::process::Command::new("pmset")
+        .args(["-g", "batt"])
+        .output()?;
+    eyre::ensure!(output.status.success(), "pmset failed: {}", output.status);
+    Ok(parse_pmset(&String::from_utf8_lossy(&output.stdout)))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn power_status() -> Result<Option<PowerStatus>> {
+    eyre::bail!("telling whether this machine is on battery isn't supported on this platform")
+}
+
+/// Reads a directory laid out like Linux's `/sys/class/power_supply`, with a directory per
+/// supply holding its `type`, and `online` for mains or `capacity` for batteries.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn read_power_supplies(dir: &Path) -> Result<Option<PowerStatus>> {
+    let read = |supply: &Path, name| {
+        fs::read_to_string(supply.join(name))
+            .map(|s| s.trim().to_string())
+            .unwrap_or_default()
+    };
+    let (mut on_mains, mut charge, mut has_battery) = (false, None, false);
+    for entry in fs::read_dir(dir)? {
+        let supply = entry?.path();
+        match read(&supply, "type").as_str() {
+            "Mains" | "USB" => on_mains |= read(&supply, "online") == "1",
+            "Battery" => {
+                has_battery = true;
+                charge = charge.or(read(&supply, "capacity").parse().ok());
+            }
+            _ => {}
+        }
+    }
+    Ok(has_battery.then_some(PowerStatus {
+        on_battery: !on_mains,
+        charge,
+    }))
+}
+
+/// Parses the output of `pmset -g batt`, e.g.
+/// `Now drawing from 'Battery Power'\n -InternalBattery-0 (id=1234)\t54%; discharging; ...`.
+#[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+fn parse_pmset(output: &str) -> Option<PowerStatus> {
+    let battery = output.lines().find(|l| l.contains("InternalBattery"))?;
+    let charge = battery
+        .split_whitespace()
+        .find_map(|word| word.strip_suffix("%;")?.parse().ok());
+    Some(PowerStatus {
+        on_battery: output.contains("'Battery Power'"),
+        charge,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn power_status_is_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let supply = |name: &str, files: &[(&str, &str)]| {
+            fs::create_dir(dir.path().join(name)).unwrap();
+            for (file, content) in files {
+                fs::write(dir.path().join(name).join(file), format!("{content}\n")).unwrap();
+            }
+        };
+        supply("AC", &[("type", "Mains"), ("online", "0")]);
+        assert_eq!(read_power_supplies(dir.path()).unwrap(), None);
+        supply("BAT0", &[("type", "Battery"), ("capacity", "41")]);
+        let status = read_power_supplies(dir.path()).unwrap().unwrap();
+        assert_eq!(
+            status,
+            PowerStatus {
+                on_battery: true,
+                charge: Some(41)
+            }
+        );
+        assert!(status.should_pause(false, Some(50)));
+        assert!(!status.should_pause(false, Some(40)));
+        fs::write(dir.path().join("AC/online"), "1").unwrap();
+        let status = read_power_supplies(dir.path()).unwrap().unwrap();
+        assert!(!status.should_pause(true, Some(50)));
+
+        let pmset = "Now drawing from 'Battery Power'\n -InternalBattery-0 (id=4653155)\t54%; discharging; 4:12 remaining present: true\n";
+        assert_eq!(
+            parse_pmset(pmset),
+            Some(PowerStatus {
+                on_battery: true,
+                charge: Some(54)
+            })
+        );
+        assert_eq!(parse_pmset("Now drawing from 'AC Power'\n"), None);
+    }
+}
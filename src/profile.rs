@@ -0,0 +1,167 @@
+use std::{ffi::OsString, path::PathBuf};
+
+use clap::{ArgMatches, Args, Command, builder::Resettable};
+use eyre::{ContextCompat, Result, bail, eyre};
+
+use crate::{
+    SyncArgs, cli_command,
+    config::{config_args, config_job},
+    store::PhotoSyncStore,
+};
+
+/// Arguments which locate the profile, and so can't come from it.
+const RESERVED_ARGS: &[&str] = &[
+    "--database-file",
+    "--catalogue-addr",
+    "--profile",
+    "--config",
+];
+
+/// Expands `sync --config FILE` and `sync --profile NAME` into the arguments they stand for. These
+/// are placed ahead of the arguments given on the command line, which therefore take precedence,
+/// with the profile's arguments placed after, and so overriding, the config file's. A profile is a
+/// job of the config file, if it has one of that name, or else is saved in the database.
+pub fn expand_saved_args(args: Vec<OsString>) -> Result<Vec<OsString>> {
+    let Some((matches, insert_at)) = sync_matches(&args) else {
+        return Ok(args);
+    };
+    let mut expanded = args;
+    let mut config_len = 0;
+    if let Some(config) = matches.get_one::<PathBuf>("config") {
+        let saved = config_args(config)?;
+        config_len = saved.len();
+        expanded.splice(insert_at..insert_at, saved.into_iter().map(OsString::from));
+    }
+
+    // parsed again, as the config file may hold the profile or the database it is in.
+    let Some((matches, _)) = sync_matches(&expanded) else {
+        return Ok(expanded);
+    };
+    let Some(name) = matches.get_one::<String>("profile") else {
+        return Ok(expanded);
+    };
+    let job = match matches.get_one::<PathBuf>("config") {
+        Some(config) => config_job(config, name)?,
+        None => None,
+    };
+    let saved = match job {
+        Some(job) => job,
+        None => {
+            let database_file = matches.get_one::<PathBuf>("database_file").wrap_err(
+                "--profile needs --database-file, as that is where profiles are stored, or a \
+                 --config with a job of that name",
+            )?;
+            PhotoSyncStore::new(database_file.clone())?
+                .profile(name)?
+                .wrap_err_with(|| format!("no profile named {name:?} in {database_file:?}"))?
+        }
+    };
+    println!("using profile {name:?}: {saved:?}");
+
+    let insert_at = insert_at + config_len;
+    expanded.splice(insert_at..insert_at, saved.into_iter().map(OsString::from));
+    Ok(expanded)
+}
+
+/// The sync arguments in `args`, and the index they start at, if `args` are for a sync (or for a
+/// check of one).
+fn sync_matches(args: &[OsString]) -> Option<(ArgMatches, usize)> {
+    let Ok(matches) = cli_command(args)
+        .ignore_errors(true)
+        .try_get_matches_from(args)
+    else {
+        // the real parse will report whatever is wrong.
+        return None;
+    };
+    // subcommands must come first, so their arguments start right after them.
+    match matches.subcommand() {
+        Some(("sync" | "doctor", sync_matches)) => Some((sync_matches.clone(), 2)),
+        Some(_) => None,
+        None => Some((matches, 1)),
+    }
+}
+
+/// Checks that `args` would be accepted by `sync`, once the arguments locating the profile are
+/// added back.
+pub fn validate_profile_args(args: &[String]) -> Result<()> {
+    if let Some(reserved) = args
+        .iter()
+        .find(|a| RESERVED_ARGS.iter().any(|r| a.split('=').next() == Some(r)))
+    {
+        bail!("{reserved} can't be saved in a profile, it must be given on the command line");
+    }
+    SyncArgs::augment_args(Command::new("profile"))
+        .mut_args(|a| a.required(false).required_unless_present(Resettable::Reset))
+        .no_binary_name(true)
+        .try_get_matches_from(
+            args.iter()
+                .map(String::as_str)
+                .chain(["--database-file", "unused"]),
+        )
+        .map_err(|e| eyre!("not valid sync arguments: {e}"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn profile_args_are_validated() {
+        validate_profile_args(&strings(&["--in-dir", "/photos", "--machine-id", "laptop"]))
+            .unwrap();
+        assert!(validate_profile_args(&strings(&["--no-such-flag"])).is_err());
+        assert!(validate_profile_args(&strings(&["--profile=other"])).is_err());
+        assert!(validate_profile_args(&strings(&["--database-file", "db"])).is_err());
+    }
+
+    #[test]
+    fn profiles_can_be_jobs_of_the_config_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("sync.toml");
+        std::fs::write(
+            &config,
+            r#"
+                out_dir = "/nas"
+                [jobs.icloud-to-nas]
+                in_dir = "/icloud"
+                [jobs.sdcard-to-nas]
+                in_dir = "/sdcard"
+            "#,
+        )
+        .unwrap();
+        let config = config.to_str().unwrap();
+        let args = |args: &[&str]| -> Vec<OsString> { args.iter().map(OsString::from).collect() };
+        assert_eq!(
+            expand_saved_args(args(&[
+                "photo-sync",
+                "--config",
+                config,
+                "--profile",
+                "sdcard-to-nas",
+                "--out-dir",
+                "/other"
+            ]))
+            .unwrap(),
+            args(&[
+                "photo-sync",
+                "--out-dir=/nas",
+                "--in-dir=/sdcard",
+                "--config",
+                config,
+                "--profile",
+                "sdcard-to-nas",
+                "--out-dir",
+                "/other"
+            ])
+        );
+        // with no such job, the profile is looked for in the database, which isn't given.
+        let missing =
+            expand_saved_args(args(&["photo-sync", "--config", config, "--profile", "x"]));
+        assert!(missing.unwrap_err().to_string().contains("--database-file"));
+    }
+}
@@ -0,0 +1,165 @@
+//! How far each phase of a sync has got: a bar with its rate and ETA when stdout is a terminal,
+//! or a line every so often when it isn't, e.g. under cron.
+
+use std::{
+    io::{self, IsTerminal},
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use indicatif::{HumanDuration, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+
+use crate::sau64::SimpleAtomicU64;
+use tracing::info;
+
+/// How often progress is logged when there are no bars.
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The bars of the phases running at once, e.g. detection and transfer, when stdout is a terminal.
+static BARS: LazyLock<Option<MultiProgress>> = LazyLock::new(|| {
+    io::stdout()
+        .is_terminal()
+        .then(|| MultiProgress::with_draw_target(ProgressDrawTarget::stdout()))
+});
+
+/// Runs `print` with the bars cleared, so that what it prints isn't drawn over.
+pub fn suspend<R>(print: impl FnOnce() -> R) -> R {
+    match &*BARS {
+        Some(bars) => bars.suspend(print),
+        None => print(),
+    }
+}
+
+/// The progress of one phase, read from the counters it keeps.
+pub struct PhaseProgress<'a> {
+    phase: &'static str,
+    files: &'a SimpleAtomicU64,
+    bytes: Option<&'a SimpleAtomicU64>,
+    /// How many files the phase has to get through, when it's known up front or as it goes.
+    total: Option<&'a SimpleAtomicU64>,
+    started: Instant,
+    bar: Option<ProgressBar>,
+    last_logged: Mutex<Instant>,
+}
+
+impl<'a> PhaseProgress<'a> {
+    pub fn new(
+        phase: &'static str,
+        files: &'a SimpleAtomicU64,
+        bytes: Option<&'a SimpleAtomicU64>,
+        total: Option<&'a SimpleAtomicU64>,
+    ) -> Self {
+        let bar = BARS.as_ref().map(|bars| {
+            let template = if total.is_some() {
+                "{prefix} {wide_bar} {pos}/{len} files, {msg}, {eta} left"
+            } else {
+                "{prefix} {spinner} {pos} files, {msg}"
+            };
+            let bar = bars.add(ProgressBar::no_length());
+            bar.set_style(ProgressStyle::with_template(template).expect("template is valid"));
+            bar.set_prefix(phase);
+            bar.enable_steady_tick(Duration::from_millis(200));
+            bar
+        });
+        let started = Instant::now();
+        let progress = Self {
+            phase,
+            files,
+            bytes,
+            total,
+            started,
+            bar,
+            last_logged: Mutex::new(started),
+        };
+        progress.tick();
+        progress
+    }
+
+    /// Shows the counters as they are now.
+    pub fn tick(&self) {
+        let files = self.files.as_u64();
+        let total = self.total.map(SimpleAtomicU64::as_u64);
+        let bytes = self.bytes.map(SimpleAtomicU64::as_u64);
+        let elapsed = self.started.elapsed();
+        if let Some(bar) = &self.bar {
+            if let Some(total) = total {
+                bar.set_length(total);
+            }
+            bar.set_position(files);
+            bar.set_message(bytes.map_or_else(String::new, |b| throughput(b, elapsed)));
+            return;
+        }
+
+        let mut last_logged = self.last_logged.lock().unwrap();
+        if last_logged.elapsed() < LOG_INTERVAL {
+            return;
+        }
+        *last_logged = Instant::now();
+        info!("{}", log_line(self.phase, files, total, bytes, elapsed));
+    }
+}
+
+/// How much `bytes` is, and how fast it went in `elapsed`.
+fn throughput(bytes: u64, elapsed: Duration) -> String {
+    let megabytes = bytes as f64 / 1e6;
+    let seconds = elapsed.as_secs_f64().max(f64::EPSILON);
+    format!("{megabytes:.0}MB at {:.1}MB/s", megabytes / seconds)
+}
+
+/// The line logged in place of a bar, for `phase` having got through `files` of `total` files,
+/// and `bytes`, in `elapsed`.
+fn log_line(
+    phase: &str,
+    files: u64,
+    total: Option<u64>,
+    bytes: Option<u64>,
+    elapsed: Duration,
+) -> String {
+    let mut line = format!("{phase}: processed {files}");
+    if let Some(total) = total {
+        line += &format!(" of {total}");
+    }
+    line += " files";
+    if let Some(bytes) = bytes {
+        line += &format!(", {}", throughput(bytes, elapsed));
+    }
+    if let Some(total) = total
+        && files > 0
+    {
+        let remaining = elapsed.mul_f64(total.saturating_sub(files) as f64 / files as f64);
+        line += &format!(", about {} left", HumanDuration(remaining));
+    }
+    line
+}
+
+impl Drop for PhaseProgress<'_> {
+    fn drop(&mut self) {
+        if let (Some(bars), Some(bar)) = (&*BARS, &self.bar) {
+            bar.finish_and_clear();
+            bars.remove(bar);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_logged_without_a_bar_give_the_rate_and_time_left() {
+        let minute = Duration::from_secs(60);
+        assert_eq!(
+            log_line("transferring", 25, Some(100), Some(600_000_000), minute),
+            "transferring: processed 25 of 100 files, 600MB at 10.0MB/s, about 3 minutes left"
+        );
+        assert_eq!(
+            log_line("detecting", 40, None, None, minute),
+            "detecting: processed 40 files"
+        );
+        // nothing is known of how long is left until the first file is through.
+        assert_eq!(
+            log_line("hashing", 0, Some(10), Some(0), Duration::ZERO),
+            "hashing: processed 0 of 10 files, 0MB at 0.0MB/s"
+        );
+    }
+}
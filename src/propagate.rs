@@ -0,0 +1,234 @@
+//! `--propagate-deletions`: once synced, moving the copies of files deleted from the in directory
+//! into the out directory's trash, so the archive follows the library rather than keeping
+//! everything ever transferred. Only copies written for the deleted file go: content another
+//! source file still has, and files `--move` deleted itself, are kept.
+
+use std::{
+    collections::HashSet,
+    io::{self, BufRead, IsTerminal, Write},
+    path::{Path, PathBuf},
+};
+
+use eyre::{Result, WrapErr};
+use tracing::{info, warn};
+
+use crate::{
+    SyncArgs,
+    digest::ContentHash,
+    icloud,
+    sources::Source,
+    store::PhotoSyncStore,
+    trash::{TRASH_DIR, Trash},
+};
+
+/// The copy in the out directory of a source file since deleted from the in directory.
+#[derive(Debug, PartialEq, Eq)]
+struct DeletedCopy {
+    path: PathBuf,
+    digest: ContentHash,
+    target_path: PathBuf,
+}
+
+/// Moves the copies of files deleted from each of `sources` into the trash, once confirmed.
+pub fn propagate_deletions(
+    database_file: &Path,
+    sources: &[Source],
+    out_dir: &Path,
+    args: &SyncArgs,
+) -> Result<()> {
+    let store = PhotoSyncStore::new(database_file.to_path_buf())?;
+    let trash = Trash::new(out_dir, args.trash_retention_days);
+    for source in sources {
+        let copies = deleted_copies(&store, source)?;
+        if copies.is_empty() {
+            continue;
+        }
+        for copy in &copies {
+            warn!(
+                "{:?} was deleted from {:?}, leaving its copy {:?}",
+                copy.path, source.dir, copy.target_path
+            );
+        }
+        let count = copies.len();
+        if args.dry_run {
+            info!("would move {count} copies of deleted files to the trash");
+            continue;
+        }
+        if !args.yes && !confirm(count, out_dir)? {
+            info!("left {count} copies of deleted files; move them to the trash with --yes");
+            continue;
+        }
+        move_to_trash(&store, &source.namespace, out_dir, &trash, &copies)?;
+        info!(
+            "moved {count} copies of files deleted from {:?} to the trash",
+            source.dir
+        );
+    }
+    let emptied = trash.empty_expired()?;
+    if emptied > 0 {
+        info!("emptied {emptied} days of expired trash");
+    }
+    Ok(())
+}
+
+/// The copies of files transferred from `source` which are gone from it and were the only source
+/// file with their content. Nothing is, if every file is gone, as the in directory is more likely
+/// unmounted than emptied.
+fn deleted_copies(store: &PhotoSyncStore, source: &Source) -> Result<Vec<DeletedCopy>> {
+    let targets = store.source_targets(&source.namespace)?;
+    let moved: HashSet<PathBuf> = (store.deleted_sources(&source.namespace)?)
+        .into_iter()
+        .collect();
+    let mut gone = Vec::new();
+    for (path, digest, target_path) in &targets {
+        let in_path = source.dir.join(path);
+        // a file which can't be looked at, e.g. on a share which has dropped, isn't known to be
+        // gone, so nothing is propagated rather than trashing its copy.
+        let missing = match in_path.symlink_metadata() {
+            Ok(_) => false,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => true,
+            Err(e) => {
+                return Err(e).wrap_err_with(|| {
+                    format!("could not tell whether {in_path:?} was deleted, so not propagating")
+                });
+            }
+        };
+        // an evicted file is still in the library, as is a dangling link.
+        let evicted = icloud::stub_of(&in_path).is_some_and(|stub| stub.exists());
+        if missing && !evicted && !moved.contains(path) {
+            gone.push((path, digest, target_path));
+        }
+    }
+    if !targets.is_empty() && gone.len() == targets.len() {
+        warn!(
+            "every file transferred from {:?} is gone from it, so not propagating deletions; is it \
+             mounted?",
+            source.dir
+        );
+        return Ok(Vec::new());
+    }
+    let mut copies = Vec::new();
+    for (path, digest, target_path) in gone {
+        if !store.shares_content(&source.namespace, path, digest)? {
+            copies.push(DeletedCopy {
+                path: path.clone(),
+                digest: *digest,
+                target_path: target_path.clone(),
+            });
+        }
+    }
+    Ok(copies)
+}
+
+/// Moves `copies` into the trash, if they're still there, and forgets them and their source files.
+fn move_to_trash(
+    store: &PhotoSyncStore,
+    namespace: &str,
+    out_dir: &Path,
+    trash: &Trash,
+    copies: &[DeletedCopy],
+) -> Result<()> {
+    for copy in copies {
+        let out_path = out_dir.join(&copy.target_path);
+        let trashed_to = match out_path.symlink_metadata() {
+            Ok(_) => Some(trash.discard(&out_path)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        let trashed_to = trashed_to
+            .as_deref()
+            .map(|to| to.strip_prefix(out_dir).unwrap_or(to));
+        store.record_propagated_deletion(
+            namespace,
+            &copy.path,
+            &copy.digest,
+            &copy.target_path,
+            trashed_to,
+        )?;
+    }
+    Ok(())
+}
+
+/// Asks whether to move `count` copies to the trash, which without a terminal to ask on, they
+/// aren't.
+fn confirm(count: usize, out_dir: &Path) -> Result<bool> {
+    if !io::stdin().is_terminal() {
+        return Ok(false);
+    }
+    print!(
+        "move {count} copies to {:?}? [y/N] ",
+        out_dir.join(TRASH_DIR)
+    );
+    io::stdout().flush()?;
+    let mut line = String::new();
+    io::stdin().lock().read_line(&mut line)?;
+    Ok(matches!(line.trim(), "y" | "Y" | "yes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, time::SystemTime};
+
+    use super::*;
+    use crate::{sources::SourceKind, store::TransferredSource};
+
+    #[test]
+    fn only_copies_no_other_file_has_are_trashed() {
+        let dir = tempfile::tempdir().unwrap();
+        let (in_dir, out_dir) = (dir.path().join("in"), dir.path().join("out"));
+        fs::create_dir_all(&in_dir).unwrap();
+        fs::create_dir_all(out_dir.join("2019")).unwrap();
+        let store = PhotoSyncStore::new_for_tests().unwrap();
+        let run = store.begin_run("laptop").unwrap();
+        let transferred = |name: &str, digest: u8, written: bool| {
+            fs::write(out_dir.join(name), name).unwrap();
+            TransferredSource {
+                path: PathBuf::from(name),
+                digest: ContentHash::new_for_tests(digest),
+                last_modified: SystemTime::UNIX_EPOCH,
+                size: 1,
+                target_path: written.then(|| PathBuf::from(name)),
+            }
+        };
+        let sources = [
+            transferred("2019/deleted.jpg", 1, true),
+            transferred("2019/shared.jpg", 2, true),
+            transferred("2019/shared copy.jpg", 2, false),
+            transferred("2019/kept.jpg", 3, true),
+        ];
+        store
+            .mark_transferred_from_source_batch(run, "laptop", &sources)
+            .unwrap();
+        let source = Source {
+            dir: in_dir.clone(),
+            namespace: "laptop".into(),
+            kind: SourceKind::Directory,
+        };
+
+        // nothing is in the in directory, as if it weren't mounted.
+        assert!(deleted_copies(&store, &source).unwrap().is_empty());
+        // nor is a file which can't be looked at taken to be deleted.
+        fs::write(in_dir.join("2019"), "").unwrap();
+        assert!(deleted_copies(&store, &source).is_err());
+        fs::remove_file(in_dir.join("2019")).unwrap();
+
+        fs::create_dir_all(in_dir.join("2019")).unwrap();
+        fs::write(in_dir.join("2019/kept.jpg"), "").unwrap();
+        fs::write(in_dir.join("2019/shared copy.jpg"), "").unwrap();
+        let copies = deleted_copies(&store, &source).unwrap();
+        assert_eq!(
+            copies
+                .iter()
+                .map(|copy| copy.path.as_path())
+                .collect::<Vec<_>>(),
+            [Path::new("2019/deleted.jpg")]
+        );
+
+        let trash = Trash::new(&out_dir, 30);
+        move_to_trash(&store, "laptop", &out_dir, &trash, &copies).unwrap();
+        assert!(!out_dir.join("2019/deleted.jpg").exists());
+        assert!(out_dir.join("2019/shared.jpg").exists());
+        assert!(deleted_copies(&store, &source).unwrap().is_empty());
+        assert_eq!(store.source_targets("laptop").unwrap().len(), 2);
+    }
+}
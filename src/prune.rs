@@ -0,0 +1,112 @@
+//! Pruning the rows of files which have gone from the old out or in directory. Otherwise they stay
+//! in the catalogue for good, and new files with their content are skipped as duplicates of copies
+//! which no longer exist.
+
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use eyre::{Result, bail};
+use walkdir::WalkDir;
+
+use crate::{PruneArgs, chunks, store::PhotoSyncStore, trash};
+
+pub fn prune(args: PruneArgs) -> Result<()> {
+    let store = PhotoSyncStore::new(args.database_file)?;
+    let mut stale_old = Vec::new();
+    if let Some(old_out_dir) = &args.old_out_dir {
+        let catalogued = store.old_target_files()?.into_iter().map(|file| file.path);
+        stale_old = find_stale(catalogued, old_out_dir)?;
+        for path in &stale_old {
+            println!("STALE old:{path:?}");
+        }
+    }
+    let mut stale_sources = Vec::new();
+    if let Some(in_dir) = &args.in_dir {
+        let catalogued = (store.source_files()?.into_iter())
+            .filter(|file| file.namespace == args.machine_id)
+            .map(|file| file.path);
+        stale_sources = find_stale(catalogued, in_dir)?;
+        for path in &stale_sources {
+            println!("STALE in:{path:?}");
+        }
+    }
+    let stale = stale_old.len() + stale_sources.len();
+    if args.delete_stale {
+        store.prune(&stale_old, &args.machine_id, &stale_sources)?;
+        println!("pruned {stale} rows");
+    } else {
+        println!("{stale} rows are stale; remove them with --delete-stale");
+    }
+    Ok(())
+}
+
+/// The `catalogued` paths with no file under `dir`, found by walking it once rather than looking
+/// for each in turn.
+fn find_stale(catalogued: impl IntoIterator<Item = PathBuf>, dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut present = HashSet::new();
+    for entry in WalkDir::new(dir)
+        .into_iter()
+        .filter_entry(|entry| !trash::is_trash(entry) && !chunks::is_repository(entry))
+    {
+        let entry = entry?;
+        if !entry.file_type().is_dir() {
+            present.insert(entry.path().strip_prefix(dir)?.to_path_buf());
+        }
+    }
+    let catalogued: Vec<_> = catalogued.into_iter().collect();
+    // most likely a disk which isn't mounted, rather than every file having gone.
+    if present.is_empty() && !catalogued.is_empty() {
+        bail!("{dir:?} has no files in it; is it mounted?");
+    }
+    Ok(catalogued
+        .into_iter()
+        .filter(|path| !present.contains(path))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, time::SystemTime};
+
+    use super::*;
+    use crate::digest::ContentHash;
+
+    #[test]
+    fn only_rows_of_gone_files_are_pruned() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("2019")).unwrap();
+        fs::write(dir.path().join("2019/kept.jpg"), "a").unwrap();
+        let paths = |paths: &[&str]| paths.iter().map(PathBuf::from).collect::<Vec<_>>();
+        let catalogued = paths(&["2019/kept.jpg", "2019/gone.jpg", "2019"]);
+        let stale = find_stale(catalogued, dir.path()).unwrap();
+        assert_eq!(stale, paths(&["2019/gone.jpg", "2019"]));
+        let empty = tempfile::tempdir().unwrap();
+        assert!(find_stale(paths(&["2019/kept.jpg"]), empty.path()).is_err());
+
+        let store = PhotoSyncStore::new_for_tests().unwrap();
+        let run = store.begin_run("laptop").unwrap();
+        let (now, digest) = (SystemTime::now(), ContentHash::new_for_tests(1));
+        for path in ["kept.jpg", "gone.jpg"] {
+            store
+                .mark_exists_in_old_target(run, Path::new(path), now, 1, &digest)
+                .unwrap();
+            for namespace in ["laptop", "phone"] {
+                store
+                    .mark_transferred_from_source(run, namespace, Path::new(path), &digest, now, 1)
+                    .unwrap();
+            }
+        }
+        let gone = paths(&["gone.jpg"]);
+        store.prune(&gone, "laptop", &gone).unwrap();
+        let old: Vec<_> = store.old_target_files().unwrap();
+        assert_eq!(old.len(), 1);
+        assert_eq!(old[0].path, Path::new("kept.jpg"));
+        let sources: Vec<_> = (store.source_files().unwrap().into_iter())
+            .map(|file| (file.namespace, file.path))
+            .collect();
+        assert_eq!(sources.len(), 3);
+        assert!(!sources.contains(&("laptop".into(), "gone.jpg".into())));
+    }
+}
@@ -0,0 +1,1594 @@
+use std::{
+    collections::HashSet,
+    io::{self, BufRead, BufReader, ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    path::{Path, PathBuf},
+    sync::{Condvar, Mutex},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use eyre::{Result, bail, eyre};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    catalogue::Catalogue,
+    compress::Transform,
+    digest::{ContentHash, HashAlgorithm},
+    failures::FailureKind,
+    metadata::MediaMetadata,
+    partial::PartialDigest,
+    phash::ImageFingerprint,
+    store::{
+        CataloguedFile, ClassifiedOut, DirSignature, IgnoredPath, PendingTransfer, ReviewDecision,
+        RunCounts, RunId, RunStatus, SourceVersion, TransferredSource,
+        WasTransferredFromSourceResult,
+    },
+};
+
+/// A catalogue call, sent as a single line of JSON. Each request is answered by a single line
+/// holding a [`Response`].
+#[derive(Debug, Serialize, Deserialize)]
+enum Request {
+    ExistsInOldTarget {
+        path: PathBuf,
+        last_modified: SystemTime,
+        size: u64,
+    },
+    MarkExistsInOldTarget {
+        run: RunId,
+        path: PathBuf,
+        last_modified: SystemTime,
+        size: u64,
+        digest: ContentHash,
+    },
+    MarkExistsInOldTargetBatch {
+        run: RunId,
+        files: Vec<CataloguedFile>,
+    },
+    MarkPendingDigest {
+        run: RunId,
+        path: PathBuf,
+        last_modified: SystemTime,
+        size: u64,
+        partial: Option<PartialDigest>,
+    },
+    IsPendingDigest {
+        path: PathBuf,
+        last_modified: SystemTime,
+        size: u64,
+    },
+    HasPartialDigests,
+    PendingPathsWithPartialDigest {
+        size: u64,
+        partial: PartialDigest,
+    },
+    OldTargetPathsWithMetadata {
+        last_modified: SystemTime,
+        size: u64,
+    },
+    OldTargetPathsWithDigest {
+        digest: ContentHash,
+    },
+    TargetPathsWithDigest {
+        digest: ContentHash,
+    },
+    HasTargetFiles,
+    MoveOldTarget {
+        from: PathBuf,
+        to: PathBuf,
+        last_modified: SystemTime,
+        size: u64,
+    },
+    MarkExistsInTarget {
+        run: RunId,
+        path: PathBuf,
+        last_modified: SystemTime,
+        size: u64,
+        digest: ContentHash,
+    },
+    ExistsInTarget {
+        digest: ContentHash,
+    },
+    HasTargetDigestsBy {
+        algorithm: HashAlgorithm,
+    },
+    TargetDigests,
+    WasTransferredFromSource {
+        namespace: String,
+        path: PathBuf,
+        last_modified: SystemTime,
+        size: u64,
+    },
+    MarkTransferredFromSource {
+        run: RunId,
+        namespace: String,
+        path: PathBuf,
+        digest: ContentHash,
+        last_modified: SystemTime,
+        size: u64,
+    },
+    MarkTransferredFromSourceBatch {
+        run: RunId,
+        namespace: String,
+        sources: Vec<TransferredSource>,
+    },
+    SetSourceTarget {
+        namespace: String,
+        path: PathBuf,
+        target: PathBuf,
+    },
+    SourcePathsWithMetadata {
+        namespace: String,
+        last_modified: SystemTime,
+        size: u64,
+    },
+    SourcePathsWithDigest {
+        namespace: String,
+        digest: ContentHash,
+    },
+    RenameSource {
+        run: RunId,
+        namespace: String,
+        from: PathBuf,
+        to: PathBuf,
+        last_modified: SystemTime,
+        size: u64,
+    },
+    UpdateSource {
+        run: RunId,
+        namespace: String,
+        path: PathBuf,
+        digest: ContentHash,
+        last_modified: SystemTime,
+        size: u64,
+    },
+    NextVersion {
+        namespace: String,
+        path: PathBuf,
+    },
+    RecordVersion {
+        run: RunId,
+        namespace: String,
+        original: ContentHash,
+        version: SourceVersion,
+    },
+    AcquireLease {
+        name: String,
+        holder: String,
+        ttl: Duration,
+    },
+    ReleaseLease {
+        name: String,
+        holder: String,
+    },
+    BreakLease {
+        name: String,
+        machine: String,
+    },
+    BeginRun {
+        namespace: String,
+    },
+    FinishRun {
+        run: RunId,
+        status: RunStatus,
+    },
+    RollBackRun {
+        run: RunId,
+    },
+    RecordSnapshot {
+        run: RunId,
+        snapshot: String,
+    },
+    RecordRunArgs {
+        run: RunId,
+        args: String,
+    },
+    RecordRunCounts {
+        run: RunId,
+        counts: RunCounts,
+    },
+    RecordRunFailures {
+        run: RunId,
+        kind: FailureKind,
+        files: u64,
+    },
+    RecordCollision {
+        run: RunId,
+        namespace: String,
+        path: PathBuf,
+        target_path: PathBuf,
+        resolution: String,
+    },
+    JournalTransfer {
+        run: RunId,
+        namespace: String,
+        transfer: PendingTransfer,
+    },
+    ForgetPendingTransfer {
+        namespace: String,
+        path: PathBuf,
+    },
+    PendingTransfers {
+        namespace: String,
+    },
+    RecordLivePhoto {
+        run: RunId,
+        namespace: String,
+        still: PathBuf,
+        video: PathBuf,
+    },
+    RecordSidecar {
+        run: RunId,
+        namespace: String,
+        parent: PathBuf,
+        sidecar: PathBuf,
+    },
+    RecordFailedTransfer {
+        run: RunId,
+        namespace: String,
+        path: PathBuf,
+        reason: String,
+    },
+    FailedTransfers {
+        namespace: String,
+    },
+    ForgetFailedTransfer {
+        namespace: String,
+        path: PathBuf,
+    },
+    DirSignatures,
+    ReviewDecisions {
+        namespace: String,
+    },
+    ReviewDecision {
+        namespace: String,
+        path: PathBuf,
+    },
+    IgnoredPaths,
+    RecordClassifiedOut {
+        namespace: String,
+        file: ClassifiedOut,
+    },
+    ClassifiedOut {
+        namespace: String,
+    },
+    IsTombstoned {
+        digest: ContentHash,
+    },
+    ReplaceDirSignatures {
+        signatures: Vec<(PathBuf, DirSignature)>,
+    },
+    RecordDeletedSource {
+        run: RunId,
+        namespace: String,
+        path: PathBuf,
+        digest: ContentHash,
+        size: u64,
+    },
+    RecordTranscoded {
+        run: RunId,
+        path: PathBuf,
+        output_digest: ContentHash,
+    },
+    TranscodedDigest {
+        path: PathBuf,
+    },
+    RecordEncrypted {
+        run: RunId,
+        path: PathBuf,
+        stored_digest: ContentHash,
+    },
+    RecordTransform {
+        run: RunId,
+        path: PathBuf,
+        transform: Transform,
+    },
+    TransformOf {
+        path: PathBuf,
+    },
+    RecordMediaMetadata {
+        digest: ContentHash,
+        metadata: MediaMetadata,
+    },
+    RecordImageFingerprint {
+        digest: ContentHash,
+        fingerprint: ImageFingerprint,
+    },
+    ICloudAssets {
+        namespace: String,
+    },
+    RecordICloudAsset {
+        namespace: String,
+        asset_id: String,
+        path: PathBuf,
+        digest: ContentHash,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Response {
+    Done,
+    Exists(bool),
+    Transferred(WasTransferredFromSourceResult),
+    Run(RunId),
+    Paths(Vec<PathBuf>),
+    Digests(Vec<ContentHash>),
+    Version(u32),
+    Count(usize),
+    PendingTransfers(Vec<PendingTransfer>),
+    DirSignatures(Vec<(PathBuf, DirSignature)>),
+    ReviewDecisions(Vec<(PathBuf, ReviewDecision)>),
+    ReviewDecision(Option<ReviewDecision>),
+    Digest(Option<ContentHash>),
+    Transform(Transform),
+    IgnoredPaths(Vec<IgnoredPath>),
+    ClassifiedOut(Vec<ClassifiedOut>),
+    AssetIds(HashSet<String>),
+    Error(String),
+}
+
+/// How long a connection may sit without sending a request before it's closed, so that a client
+/// which has gone away doesn't hold a thread for good. Clients reconnect once they're closed.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// The longest request line served, which is far more than a batch of writes needs.
+const MAX_LINE: u64 = 64 * 1024 * 1024;
+
+/// The most connections served at once; further clients wait to be accepted until one closes.
+const MAX_CONNECTIONS: usize = 64;
+
+/// Serves `catalogue` to [`RemoteCatalogue`] clients until the process is killed. There is no
+/// authentication, so this must only listen on a trusted network.
+pub fn serve(catalogue: &dyn Catalogue, addr: impl ToSocketAddrs) -> Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    info!("serving catalogue on {}", listener.local_addr()?);
+    let open = Mutex::new(0usize);
+    let closed = Condvar::new();
+    thread::scope(|s| {
+        loop {
+            drop(
+                closed
+                    .wait_while(open.lock().unwrap(), |open| *open >= MAX_CONNECTIONS)
+                    .unwrap(),
+            );
+            let stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                // e.g. the client gave up before it was accepted, or too many files are open;
+                // either way, later clients may still be served.
+                Err(e) => {
+                    warn!("could not accept a catalogue connection: {e}");
+                    continue;
+                }
+            };
+            *open.lock().unwrap() += 1;
+            let (open, closed) = (&open, &closed);
+            s.spawn(move || {
+                let peer = stream.peer_addr().ok();
+                if let Err(e) = handle_connection(catalogue, stream) {
+                    warn!("connection from {peer:?} failed: {e}");
+                }
+                *open.lock().unwrap() -= 1;
+                closed.notify_one();
+            });
+        }
+    })
+}
+
+fn handle_connection(catalogue: &dyn Catalogue, stream: TcpStream) -> Result<()> {
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    stream.set_write_timeout(Some(IDLE_TIMEOUT))?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        match reader.by_ref().take(MAX_LINE).read_line(&mut line) {
+            Ok(0) => return Ok(()),
+            Ok(_) => {}
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        }
+        if !line.ends_with('\n') && line.len() as u64 == MAX_LINE {
+            bail!("request longer than {MAX_LINE} bytes");
+        }
+        let response = match serde_json::from_str(&line) {
+            Ok(request) => dispatch(catalogue, request).unwrap_or_else(|e| {
+                warn!("catalogue request failed: {e}");
+                Response::Error(e.to_string())
+            }),
+            Err(e) => Response::Error(format!("malformed request: {e}")),
+        };
+        serde_json::to_writer(&mut writer, &response)?;
+        writer.write_all(b"\n")?;
+    }
+}
+
+fn dispatch(catalogue: &dyn Catalogue, request: Request) -> Result<Response> {
+    Ok(match request {
+        Request::ExistsInOldTarget {
+            path,
+            last_modified,
+            size,
+        } => Response::Transferred(catalogue.exists_in_old_target(&path, last_modified, size)?),
+        Request::MarkExistsInOldTarget {
+            run,
+            path,
+            last_modified,
+            size,
+            digest,
+        } => {
+            catalogue.mark_exists_in_old_target(run, &path, last_modified, size, &digest)?;
+            Response::Done
+        }
+        Request::MarkExistsInOldTargetBatch { run, files } => {
+            catalogue.mark_exists_in_old_target_batch(run, &files)?;
+            Response::Done
+        }
+        Request::MarkPendingDigest {
+            run,
+            path,
+            last_modified,
+            size,
+            partial,
+        } => {
+            catalogue.mark_pending_digest(run, &path, last_modified, size, partial.as_ref())?;
+            Response::Done
+        }
+        Request::IsPendingDigest {
+            path,
+            last_modified,
+            size,
+        } => Response::Exists(catalogue.is_pending_digest(&path, last_modified, size)?),
+        Request::HasPartialDigests => Response::Exists(catalogue.has_partial_digests()?),
+        Request::PendingPathsWithPartialDigest { size, partial } => {
+            Response::Paths(catalogue.pending_paths_with_partial_digest(size, &partial)?)
+        }
+        Request::OldTargetPathsWithMetadata {
+            last_modified,
+            size,
+        } => Response::Paths(catalogue.old_target_paths_with_metadata(last_modified, size)?),
+        Request::OldTargetPathsWithDigest { digest } => {
+            Response::Paths(catalogue.old_target_paths_with_digest(&digest)?)
+        }
+        Request::TargetPathsWithDigest { digest } => {
+            Response::Paths(catalogue.target_paths_with_digest(&digest)?)
+        }
+        Request::HasTargetFiles => Response::Exists(catalogue.has_target_files()?),
+        Request::MoveOldTarget {
+            from,
+            to,
+            last_modified,
+            size,
+        } => Response::Exists(catalogue.move_old_target(&from, &to, last_modified, size)?),
+        Request::MarkExistsInTarget {
+            run,
+            path,
+            last_modified,
+            size,
+            digest,
+        } => {
+            catalogue.mark_exists_in_target(run, &path, last_modified, size, &digest)?;
+            Response::Done
+        }
+        Request::ExistsInTarget { digest } => {
+            Response::Exists(catalogue.exists_in_target(&digest)?)
+        }
+        Request::HasTargetDigestsBy { algorithm } => {
+            Response::Exists(catalogue.has_target_digests_by(algorithm)?)
+        }
+        Request::TargetDigests => Response::Digests(catalogue.target_digests()?),
+        Request::WasTransferredFromSource {
+            namespace,
+            path,
+            last_modified,
+            size,
+        } => Response::Transferred(catalogue.was_transferred_from_source(
+            &namespace,
+            &path,
+            last_modified,
+            size,
+        )?),
+        Request::MarkTransferredFromSource {
+            run,
+            namespace,
+            path,
+            digest,
+            last_modified,
+            size,
+        } => {
+            catalogue.mark_transferred_from_source(
+                run,
+                &namespace,
+                &path,
+                &digest,
+                last_modified,
+                size,
+            )?;
+            Response::Done
+        }
+        Request::MarkTransferredFromSourceBatch {
+            run,
+            namespace,
+            sources,
+        } => {
+            catalogue.mark_transferred_from_source_batch(run, &namespace, &sources)?;
+            Response::Done
+        }
+        Request::SetSourceTarget {
+            namespace,
+            path,
+            target,
+        } => {
+            catalogue.set_source_target(&namespace, &path, &target)?;
+            Response::Done
+        }
+        Request::SourcePathsWithMetadata {
+            namespace,
+            last_modified,
+            size,
+        } => Response::Paths(catalogue.source_paths_with_metadata(
+            &namespace,
+            last_modified,
+            size,
+        )?),
+        Request::SourcePathsWithDigest { namespace, digest } => {
+            Response::Paths(catalogue.source_paths_with_digest(&namespace, &digest)?)
+        }
+        Request::RenameSource {
+            run,
+            namespace,
+            from,
+            to,
+            last_modified,
+            size,
+        } => Response::Exists(catalogue.rename_source(
+            run,
+            &namespace,
+            &from,
+            &to,
+            last_modified,
+            size,
+        )?),
+        Request::UpdateSource {
+            run,
+            namespace,
+            path,
+            digest,
+            last_modified,
+            size,
+        } => {
+            catalogue.update_source(run, &namespace, &path, &digest, last_modified, size)?;
+            Response::Done
+        }
+        Request::NextVersion { namespace, path } => {
+            Response::Version(catalogue.next_version(&namespace, &path)?)
+        }
+        Request::RecordVersion {
+            run,
+            namespace,
+            original,
+            version,
+        } => {
+            catalogue.record_version(run, &namespace, &original, &version)?;
+            Response::Done
+        }
+        Request::AcquireLease { name, holder, ttl } => {
+            catalogue.acquire_lease(&name, &holder, ttl)?;
+            Response::Done
+        }
+        Request::ReleaseLease { name, holder } => {
+            catalogue.release_lease(&name, &holder)?;
+            Response::Done
+        }
+        Request::BreakLease { name, machine } => {
+            Response::Count(catalogue.break_lease(&name, &machine)?)
+        }
+        Request::BeginRun { namespace } => Response::Run(catalogue.begin_run(&namespace)?),
+        Request::FinishRun { run, status } => {
+            catalogue.finish_run(run, status)?;
+            Response::Done
+        }
+        Request::RollBackRun { run } => {
+            catalogue.roll_back_run(run)?;
+            Response::Done
+        }
+        Request::RecordSnapshot { run, snapshot } => {
+            catalogue.record_snapshot(run, &snapshot)?;
+            Response::Done
+        }
+        Request::RecordRunArgs { run, args } => {
+            catalogue.record_run_args(run, &args)?;
+            Response::Done
+        }
+        Request::RecordRunCounts { run, counts } => {
+            catalogue.record_run_counts(run, &counts)?;
+            Response::Done
+        }
+        Request::RecordRunFailures { run, kind, files } => {
+            catalogue.record_run_failures(run, kind, files)?;
+            Response::Done
+        }
+        Request::RecordCollision {
+            run,
+            namespace,
+            path,
+            target_path,
+            resolution,
+        } => {
+            catalogue.record_collision(run, &namespace, &path, &target_path, &resolution)?;
+            Response::Done
+        }
+        Request::JournalTransfer {
+            run,
+            namespace,
+            transfer,
+        } => {
+            catalogue.journal_transfer(run, &namespace, &transfer)?;
+            Response::Done
+        }
+        Request::ForgetPendingTransfer { namespace, path } => {
+            catalogue.forget_pending_transfer(&namespace, &path)?;
+            Response::Done
+        }
+        Request::PendingTransfers { namespace } => {
+            Response::PendingTransfers(catalogue.pending_transfers(&namespace)?)
+        }
+        Request::RecordLivePhoto {
+            run,
+            namespace,
+            still,
+            video,
+        } => {
+            catalogue.record_live_photo(run, &namespace, &still, &video)?;
+            Response::Done
+        }
+        Request::RecordSidecar {
+            run,
+            namespace,
+            parent,
+            sidecar,
+        } => {
+            catalogue.record_sidecar(run, &namespace, &parent, &sidecar)?;
+            Response::Done
+        }
+        Request::RecordFailedTransfer {
+            run,
+            namespace,
+            path,
+            reason,
+        } => {
+            catalogue.record_failed_transfer(run, &namespace, &path, &reason)?;
+            Response::Done
+        }
+        Request::FailedTransfers { namespace } => {
+            Response::Paths(catalogue.failed_transfers(&namespace)?)
+        }
+        Request::DirSignatures => Response::DirSignatures(catalogue.dir_signatures()?),
+        Request::ReviewDecisions { namespace } => {
+            Response::ReviewDecisions(catalogue.review_decisions(&namespace)?)
+        }
+        Request::ReviewDecision { namespace, path } => {
+            Response::ReviewDecision(catalogue.review_decision(&namespace, &path)?)
+        }
+        Request::IgnoredPaths => Response::IgnoredPaths(catalogue.ignored_paths()?),
+        Request::RecordClassifiedOut { namespace, file } => {
+            catalogue.record_classified_out(&namespace, &file)?;
+            Response::Done
+        }
+        Request::ClassifiedOut { namespace } => {
+            Response::ClassifiedOut(catalogue.classified_out(&namespace)?)
+        }
+        Request::IsTombstoned { digest } => Response::Exists(catalogue.is_tombstoned(&digest)?),
+        Request::ReplaceDirSignatures { signatures } => {
+            catalogue.replace_dir_signatures(&signatures)?;
+            Response::Done
+        }
+        Request::ForgetFailedTransfer { namespace, path } => {
+            catalogue.forget_failed_transfer(&namespace, &path)?;
+            Response::Done
+        }
+        Request::RecordDeletedSource {
+            run,
+            namespace,
+            path,
+            digest,
+            size,
+        } => {
+            catalogue.record_deleted_source(run, &namespace, &path, &digest, size)?;
+            Response::Done
+        }
+        Request::RecordTranscoded {
+            run,
+            path,
+            output_digest,
+        } => {
+            catalogue.record_transcoded(run, &path, &output_digest)?;
+            Response::Done
+        }
+        Request::TranscodedDigest { path } => Response::Digest(catalogue.transcoded_digest(&path)?),
+        Request::RecordEncrypted {
+            run,
+            path,
+            stored_digest,
+        } => {
+            catalogue.record_encrypted(run, &path, &stored_digest)?;
+            Response::Done
+        }
+        Request::RecordTransform {
+            run,
+            path,
+            transform,
+        } => {
+            catalogue.record_transform(run, &path, transform)?;
+            Response::Done
+        }
+        Request::TransformOf { path } => Response::Transform(catalogue.transform_of(&path)?),
+        Request::RecordMediaMetadata { digest, metadata } => {
+            catalogue.record_media_metadata(&digest, &metadata)?;
+            Response::Done
+        }
+        Request::RecordImageFingerprint {
+            digest,
+            fingerprint,
+        } => {
+            catalogue.record_image_fingerprint(&digest, &fingerprint)?;
+            Response::Done
+        }
+        Request::ICloudAssets { namespace } => {
+            Response::AssetIds(catalogue.icloud_assets(&namespace)?)
+        }
+        Request::RecordICloudAsset {
+            namespace,
+            asset_id,
+            path,
+            digest,
+        } => {
+            catalogue.record_icloud_asset(&namespace, &asset_id, &path, &digest)?;
+            Response::Done
+        }
+    })
+}
+
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+/// A catalogue hosted by [`serve`] on another machine. Connections are pooled so that parallel
+/// workers don't queue behind each other's round trips.
+pub struct RemoteCatalogue {
+    addr: String,
+    idle: Mutex<Vec<Connection>>,
+}
+
+impl RemoteCatalogue {
+    pub fn connect(addr: String) -> Result<Self> {
+        let catalogue = Self {
+            addr,
+            idle: Mutex::default(),
+        };
+        // fail fast if the server isn't reachable.
+        let connection = catalogue.open()?;
+        catalogue.idle.lock().unwrap().push(connection);
+        Ok(catalogue)
+    }
+
+    fn open(&self) -> Result<Connection> {
+        let writer = TcpStream::connect(&self.addr)
+            .map_err(|e| eyre!("could not connect to catalogue at {}: {e}", self.addr))?;
+        Ok(Connection {
+            reader: BufReader::new(writer.try_clone()?),
+            writer,
+        })
+    }
+
+    fn call(&self, request: &Request) -> Result<Response> {
+        let pooled = self.idle.lock().unwrap().pop();
+        // the server closes connections left idle, which is only found on using one, before
+        // it's read the request.
+        let exchanged = match pooled {
+            Some(connection) => match Self::exchange(connection, request)? {
+                Some(exchanged) => Some(exchanged),
+                None => Self::exchange(self.open()?, request)?,
+            },
+            None => Self::exchange(self.open()?, request)?,
+        };
+        let Some((connection, response)) = exchanged else {
+            bail!("catalogue server at {} closed the connection", self.addr);
+        };
+
+        self.idle.lock().unwrap().push(connection);
+        match response {
+            Response::Error(e) => bail!("catalogue server error: {e}"),
+            response => Ok(response),
+        }
+    }
+
+    /// Sends `request` over `connection` and reads the response, or `None` if the server had
+    /// closed the connection.
+    fn exchange(
+        mut connection: Connection,
+        request: &Request,
+    ) -> Result<Option<(Connection, Response)>> {
+        let closed = |e: &io::Error| {
+            matches!(
+                e.kind(),
+                ErrorKind::BrokenPipe | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted
+            )
+        };
+        let mut line = serde_json::to_vec(request)?;
+        line.push(b'\n');
+        match connection.writer.write_all(&line) {
+            Err(e) if closed(&e) => return Ok(None),
+            written => written?,
+        }
+        let mut line = String::new();
+        match connection.reader.read_line(&mut line) {
+            Ok(0) => return Ok(None),
+            Err(e) if closed(&e) => return Ok(None),
+            read => read?,
+        };
+        let response = serde_json::from_str(&line)?;
+        Ok(Some((connection, response)))
+    }
+
+    fn call_done(&self, request: &Request) -> Result<()> {
+        match self.call(request)? {
+            Response::Done => Ok(()),
+            other => bail!("unexpected response to {request:?}: {other:?}"),
+        }
+    }
+
+    fn call_transferred(&self, request: &Request) -> Result<WasTransferredFromSourceResult> {
+        match self.call(request)? {
+            Response::Transferred(result) => Ok(result),
+            other => bail!("unexpected response to {request:?}: {other:?}"),
+        }
+    }
+
+    fn call_paths(&self, request: &Request) -> Result<Vec<PathBuf>> {
+        match self.call(request)? {
+            Response::Paths(paths) => Ok(paths),
+            other => bail!("unexpected response to {request:?}: {other:?}"),
+        }
+    }
+}
+
+impl Catalogue for RemoteCatalogue {
+    fn exists_in_old_target(
+        &self,
+        path: &Path,
+        last_modified: SystemTime,
+        size: u64,
+    ) -> Result<WasTransferredFromSourceResult> {
+        self.call_transferred(&Request::ExistsInOldTarget {
+            path: path.to_path_buf(),
+            last_modified,
+            size,
+        })
+    }
+
+    fn mark_exists_in_old_target(
+        &self,
+        run: RunId,
+        path: &Path,
+        last_modified: SystemTime,
+        size: u64,
+        digest: &ContentHash,
+    ) -> Result<()> {
+        self.call_done(&Request::MarkExistsInOldTarget {
+            run,
+            path: path.to_path_buf(),
+            last_modified,
+            size,
+            digest: *digest,
+        })
+    }
+
+    fn mark_exists_in_old_target_batch(&self, run: RunId, files: &[CataloguedFile]) -> Result<()> {
+        self.call_done(&Request::MarkExistsInOldTargetBatch {
+            run,
+            files: files.to_vec(),
+        })
+    }
+
+    fn mark_pending_digest(
+        &self,
+        run: RunId,
+        path: &Path,
+        last_modified: SystemTime,
+        size: u64,
+        partial: Option<&PartialDigest>,
+    ) -> Result<()> {
+        self.call_done(&Request::MarkPendingDigest {
+            run,
+            path: path.to_path_buf(),
+            last_modified,
+            size,
+            partial: partial.copied(),
+        })
+    }
+
+    fn is_pending_digest(&self, path: &Path, last_modified: SystemTime, size: u64) -> Result<bool> {
+        let request = Request::IsPendingDigest {
+            path: path.to_path_buf(),
+            last_modified,
+            size,
+        };
+        match self.call(&request)? {
+            Response::Exists(pending) => Ok(pending),
+            other => bail!("unexpected response to {request:?}: {other:?}"),
+        }
+    }
+
+    fn has_partial_digests(&self) -> Result<bool> {
+        let request = Request::HasPartialDigests;
+        match self.call(&request)? {
+            Response::Exists(exists) => Ok(exists),
+            other => bail!("unexpected response to {request:?}: {other:?}"),
+        }
+    }
+
+    fn pending_paths_with_partial_digest(
+        &self,
+        size: u64,
+        partial: &PartialDigest,
+    ) -> Result<Vec<PathBuf>> {
+        self.call_paths(&Request::PendingPathsWithPartialDigest {
+            size,
+            partial: *partial,
+        })
+    }
+
+    fn old_target_paths_with_metadata(
+        &self,
+        last_modified: SystemTime,
+        size: u64,
+    ) -> Result<Vec<PathBuf>> {
+        self.call_paths(&Request::OldTargetPathsWithMetadata {
+            last_modified,
+            size,
+        })
+    }
+
+    fn old_target_paths_with_digest(&self, digest: &ContentHash) -> Result<Vec<PathBuf>> {
+        self.call_paths(&Request::OldTargetPathsWithDigest { digest: *digest })
+    }
+
+    fn target_paths_with_digest(&self, digest: &ContentHash) -> Result<Vec<PathBuf>> {
+        self.call_paths(&Request::TargetPathsWithDigest { digest: *digest })
+    }
+
+    fn has_target_files(&self) -> Result<bool> {
+        let request = Request::HasTargetFiles;
+        match self.call(&request)? {
+            Response::Exists(exists) => Ok(exists),
+            other => bail!("unexpected response to {request:?}: {other:?}"),
+        }
+    }
+
+    fn move_old_target(
+        &self,
+        from: &Path,
+        to: &Path,
+        last_modified: SystemTime,
+        size: u64,
+    ) -> Result<bool> {
+        let request = Request::MoveOldTarget {
+            from: from.to_path_buf(),
+            to: to.to_path_buf(),
+            last_modified,
+            size,
+        };
+        match self.call(&request)? {
+            Response::Exists(moved) => Ok(moved),
+            other => bail!("unexpected response to {request:?}: {other:?}"),
+        }
+    }
+
+    fn mark_exists_in_target(
+        &self,
+        run: RunId,
+        path: &Path,
+        last_modified: SystemTime,
+        size: u64,
+        digest: &ContentHash,
+    ) -> Result<()> {
+        self.call_done(&Request::MarkExistsInTarget {
+            run,
+            path: path.to_path_buf(),
+            last_modified,
+            size,
+            digest: *digest,
+        })
+    }
+
+    fn exists_in_target(&self, digest: &ContentHash) -> Result<bool> {
+        let request = Request::ExistsInTarget { digest: *digest };
+        match self.call(&request)? {
+            Response::Exists(exists) => Ok(exists),
+            other => bail!("unexpected response to {request:?}: {other:?}"),
+        }
+    }
+
+    fn has_target_digests_by(&self, algorithm: HashAlgorithm) -> Result<bool> {
+        let request = Request::HasTargetDigestsBy { algorithm };
+        match self.call(&request)? {
+            Response::Exists(exists) => Ok(exists),
+            other => bail!("unexpected response to {request:?}: {other:?}"),
+        }
+    }
+
+    fn target_digests(&self) -> Result<Vec<ContentHash>> {
+        let request = Request::TargetDigests;
+        match self.call(&request)? {
+            Response::Digests(digests) => Ok(digests),
+            other => bail!("unexpected response to {request:?}: {other:?}"),
+        }
+    }
+
+    fn was_transferred_from_source(
+        &self,
+        namespace: &str,
+        path: &Path,
+        last_modified: SystemTime,
+        size: u64,
+    ) -> Result<WasTransferredFromSourceResult> {
+        self.call_transferred(&Request::WasTransferredFromSource {
+            namespace: namespace.to_string(),
+            path: path.to_path_buf(),
+            last_modified,
+            size,
+        })
+    }
+
+    fn mark_transferred_from_source(
+        &self,
+        run: RunId,
+        namespace: &str,
+        path: &Path,
+        digest: &ContentHash,
+        last_modified: SystemTime,
+        size: u64,
+    ) -> Result<()> {
+        self.call_done(&Request::MarkTransferredFromSource {
+            run,
+            namespace: namespace.to_string(),
+            path: path.to_path_buf(),
+            digest: *digest,
+            last_modified,
+            size,
+        })
+    }
+
+    fn mark_transferred_from_source_batch(
+        &self,
+        run: RunId,
+        namespace: &str,
+        sources: &[TransferredSource],
+    ) -> Result<()> {
+        self.call_done(&Request::MarkTransferredFromSourceBatch {
+            run,
+            namespace: namespace.to_string(),
+            sources: sources.to_vec(),
+        })
+    }
+
+    fn set_source_target(&self, namespace: &str, path: &Path, target: &Path) -> Result<()> {
+        self.call_done(&Request::SetSourceTarget {
+            namespace: namespace.to_string(),
+            path: path.to_path_buf(),
+            target: target.to_path_buf(),
+        })
+    }
+
+    fn source_paths_with_metadata(
+        &self,
+        namespace: &str,
+        last_modified: SystemTime,
+        size: u64,
+    ) -> Result<Vec<PathBuf>> {
+        self.call_paths(&Request::SourcePathsWithMetadata {
+            namespace: namespace.to_string(),
+            last_modified,
+            size,
+        })
+    }
+
+    fn source_paths_with_digest(
+        &self,
+        namespace: &str,
+        digest: &ContentHash,
+    ) -> Result<Vec<PathBuf>> {
+        self.call_paths(&Request::SourcePathsWithDigest {
+            namespace: namespace.to_string(),
+            digest: *digest,
+        })
+    }
+
+    fn rename_source(
+        &self,
+        run: RunId,
+        namespace: &str,
+        from: &Path,
+        to: &Path,
+        last_modified: SystemTime,
+        size: u64,
+    ) -> Result<bool> {
+        let request = Request::RenameSource {
+            run,
+            namespace: namespace.to_string(),
+            from: from.to_path_buf(),
+            to: to.to_path_buf(),
+            last_modified,
+            size,
+        };
+        match self.call(&request)? {
+            Response::Exists(renamed) => Ok(renamed),
+            other => bail!("unexpected response to {request:?}: {other:?}"),
+        }
+    }
+
+    fn update_source(
+        &self,
+        run: RunId,
+        namespace: &str,
+        path: &Path,
+        digest: &ContentHash,
+        last_modified: SystemTime,
+        size: u64,
+    ) -> Result<()> {
+        self.call_done(&Request::UpdateSource {
+            run,
+            namespace: namespace.to_string(),
+            path: path.to_path_buf(),
+            digest: *digest,
+            last_modified,
+            size,
+        })
+    }
+
+    fn next_version(&self, namespace: &str, path: &Path) -> Result<u32> {
+        let request = Request::NextVersion {
+            namespace: namespace.to_string(),
+            path: path.to_path_buf(),
+        };
+        match self.call(&request)? {
+            Response::Version(version) => Ok(version),
+            other => bail!("unexpected response to {request:?}: {other:?}"),
+        }
+    }
+
+    fn record_version(
+        &self,
+        run: RunId,
+        namespace: &str,
+        original: &ContentHash,
+        version: &SourceVersion,
+    ) -> Result<()> {
+        self.call_done(&Request::RecordVersion {
+            run,
+            namespace: namespace.to_string(),
+            original: *original,
+            version: version.clone(),
+        })
+    }
+
+    fn acquire_lease(&self, name: &str, holder: &str, ttl: Duration) -> Result<()> {
+        self.call_done(&Request::AcquireLease {
+            name: name.to_string(),
+            holder: holder.to_string(),
+            ttl,
+        })
+    }
+
+    fn release_lease(&self, name: &str, holder: &str) -> Result<()> {
+        self.call_done(&Request::ReleaseLease {
+            name: name.to_string(),
+            holder: holder.to_string(),
+        })
+    }
+
+    fn break_lease(&self, name: &str, machine: &str) -> Result<usize> {
+        let request = Request::BreakLease {
+            name: name.to_string(),
+            machine: machine.to_string(),
+        };
+        match self.call(&request)? {
+            Response::Count(aborted) => Ok(aborted),
+            other => bail!("unexpected response to {request:?}: {other:?}"),
+        }
+    }
+
+    fn begin_run(&self, namespace: &str) -> Result<RunId> {
+        let request = Request::BeginRun {
+            namespace: namespace.to_string(),
+        };
+        match self.call(&request)? {
+            Response::Run(run) => Ok(run),
+            other => bail!("unexpected response to {request:?}: {other:?}"),
+        }
+    }
+
+    fn finish_run(&self, run: RunId, status: RunStatus) -> Result<()> {
+        self.call_done(&Request::FinishRun { run, status })
+    }
+
+    fn roll_back_run(&self, run: RunId) -> Result<()> {
+        self.call_done(&Request::RollBackRun { run })
+    }
+
+    fn record_snapshot(&self, run: RunId, snapshot: &str) -> Result<()> {
+        self.call_done(&Request::RecordSnapshot {
+            run,
+            snapshot: snapshot.to_string(),
+        })
+    }
+
+    fn record_run_args(&self, run: RunId, args: &str) -> Result<()> {
+        self.call_done(&Request::RecordRunArgs {
+            run,
+            args: args.to_string(),
+        })
+    }
+
+    fn record_run_counts(&self, run: RunId, counts: &RunCounts) -> Result<()> {
+        self.call_done(&Request::RecordRunCounts {
+            run,
+            counts: *counts,
+        })
+    }
+
+    fn record_run_failures(&self, run: RunId, kind: FailureKind, files: u64) -> Result<()> {
+        self.call_done(&Request::RecordRunFailures { run, kind, files })
+    }
+
+    fn record_collision(
+        &self,
+        run: RunId,
+        namespace: &str,
+        path: &Path,
+        target_path: &Path,
+        resolution: &str,
+    ) -> Result<()> {
+        self.call_done(&Request::RecordCollision {
+            run,
+            namespace: namespace.to_string(),
+            path: path.to_path_buf(),
+            target_path: target_path.to_path_buf(),
+            resolution: resolution.to_string(),
+        })
+    }
+
+    fn journal_transfer(
+        &self,
+        run: RunId,
+        namespace: &str,
+        transfer: &PendingTransfer,
+    ) -> Result<()> {
+        self.call_done(&Request::JournalTransfer {
+            run,
+            namespace: namespace.to_string(),
+            transfer: transfer.clone(),
+        })
+    }
+
+    fn forget_pending_transfer(&self, namespace: &str, path: &Path) -> Result<()> {
+        self.call_done(&Request::ForgetPendingTransfer {
+            namespace: namespace.to_string(),
+            path: path.to_path_buf(),
+        })
+    }
+
+    fn pending_transfers(&self, namespace: &str) -> Result<Vec<PendingTransfer>> {
+        let request = Request::PendingTransfers {
+            namespace: namespace.to_string(),
+        };
+        match self.call(&request)? {
+            Response::PendingTransfers(transfers) => Ok(transfers),
+            other => bail!("unexpected response to {request:?}: {other:?}"),
+        }
+    }
+
+    fn record_live_photo(
+        &self,
+        run: RunId,
+        namespace: &str,
+        still: &Path,
+        video: &Path,
+    ) -> Result<()> {
+        self.call_done(&Request::RecordLivePhoto {
+            run,
+            namespace: namespace.to_string(),
+            still: still.to_path_buf(),
+            video: video.to_path_buf(),
+        })
+    }
+
+    fn record_sidecar(
+        &self,
+        run: RunId,
+        namespace: &str,
+        parent: &Path,
+        sidecar: &Path,
+    ) -> Result<()> {
+        self.call_done(&Request::RecordSidecar {
+            run,
+            namespace: namespace.to_string(),
+            parent: parent.to_path_buf(),
+            sidecar: sidecar.to_path_buf(),
+        })
+    }
+
+    fn record_failed_transfer(
+        &self,
+        run: RunId,
+        namespace: &str,
+        path: &Path,
+        reason: &str,
+    ) -> Result<()> {
+        self.call_done(&Request::RecordFailedTransfer {
+            run,
+            namespace: namespace.to_string(),
+            path: path.to_path_buf(),
+            reason: reason.to_string(),
+        })
+    }
+
+    fn failed_transfers(&self, namespace: &str) -> Result<Vec<PathBuf>> {
+        self.call_paths(&Request::FailedTransfers {
+            namespace: namespace.to_string(),
+        })
+    }
+
+    fn forget_failed_transfer(&self, namespace: &str, path: &Path) -> Result<()> {
+        self.call_done(&Request::ForgetFailedTransfer {
+            namespace: namespace.to_string(),
+            path: path.to_path_buf(),
+        })
+    }
+
+    fn dir_signatures(&self) -> Result<Vec<(PathBuf, DirSignature)>> {
+        let request = Request::DirSignatures;
+        match self.call(&request)? {
+            Response::DirSignatures(signatures) => Ok(signatures),
+            other => bail!("unexpected response to {request:?}: {other:?}"),
+        }
+    }
+
+    fn review_decisions(&self, namespace: &str) -> Result<Vec<(PathBuf, ReviewDecision)>> {
+        let request = Request::ReviewDecisions {
+            namespace: namespace.to_string(),
+        };
+        match self.call(&request)? {
+            Response::ReviewDecisions(decisions) => Ok(decisions),
+            other => bail!("unexpected response to {request:?}: {other:?}"),
+        }
+    }
+
+    fn review_decision(&self, namespace: &str, path: &Path) -> Result<Option<ReviewDecision>> {
+        let request = Request::ReviewDecision {
+            namespace: namespace.to_string(),
+            path: path.to_path_buf(),
+        };
+        match self.call(&request)? {
+            Response::ReviewDecision(decision) => Ok(decision),
+            other => bail!("unexpected response to {request:?}: {other:?}"),
+        }
+    }
+
+    fn ignored_paths(&self) -> Result<Vec<IgnoredPath>> {
+        let request = Request::IgnoredPaths;
+        match self.call(&request)? {
+            Response::IgnoredPaths(ignored) => Ok(ignored),
+            other => bail!("unexpected response to {request:?}: {other:?}"),
+        }
+    }
+
+    fn record_classified_out(&self, namespace: &str, file: &ClassifiedOut) -> Result<()> {
+        self.call_done(&Request::RecordClassifiedOut {
+            namespace: namespace.to_string(),
+            file: file.clone(),
+        })
+    }
+
+    fn classified_out(&self, namespace: &str) -> Result<Vec<ClassifiedOut>> {
+        let request = Request::ClassifiedOut {
+            namespace: namespace.to_string(),
+        };
+        match self.call(&request)? {
+            Response::ClassifiedOut(files) => Ok(files),
+            other => bail!("unexpected response to {request:?}: {other:?}"),
+        }
+    }
+
+    fn is_tombstoned(&self, digest: &ContentHash) -> Result<bool> {
+        let request = Request::IsTombstoned { digest: *digest };
+        match self.call(&request)? {
+            Response::Exists(tombstoned) => Ok(tombstoned),
+            other => bail!("unexpected response to {request:?}: {other:?}"),
+        }
+    }
+
+    fn replace_dir_signatures(&self, signatures: &[(PathBuf, DirSignature)]) -> Result<()> {
+        self.call_done(&Request::ReplaceDirSignatures {
+            signatures: signatures.to_vec(),
+        })
+    }
+
+    fn record_deleted_source(
+        &self,
+        run: RunId,
+        namespace: &str,
+        path: &Path,
+        digest: &ContentHash,
+        size: u64,
+    ) -> Result<()> {
+        self.call_done(&Request::RecordDeletedSource {
+            run,
+            namespace: namespace.to_string(),
+            path: path.to_path_buf(),
+            digest: *digest,
+            size,
+        })
+    }
+
+    fn record_transcoded(
+        &self,
+        run: RunId,
+        path: &Path,
+        output_digest: &ContentHash,
+    ) -> Result<()> {
+        self.call_done(&Request::RecordTranscoded {
+            run,
+            path: path.to_path_buf(),
+            output_digest: *output_digest,
+        })
+    }
+
+    fn transcoded_digest(&self, path: &Path) -> Result<Option<ContentHash>> {
+        let request = Request::TranscodedDigest {
+            path: path.to_path_buf(),
+        };
+        match self.call(&request)? {
+            Response::Digest(digest) => Ok(digest),
+            other => bail!("unexpected response to {request:?}: {other:?}"),
+        }
+    }
+
+    fn record_encrypted(&self, run: RunId, path: &Path, stored_digest: &ContentHash) -> Result<()> {
+        self.call_done(&Request::RecordEncrypted {
+            run,
+            path: path.to_path_buf(),
+            stored_digest: *stored_digest,
+        })
+    }
+
+    fn record_transform(&self, run: RunId, path: &Path, transform: Transform) -> Result<()> {
+        self.call_done(&Request::RecordTransform {
+            run,
+            path: path.to_path_buf(),
+            transform,
+        })
+    }
+
+    fn transform_of(&self, path: &Path) -> Result<Transform> {
+        let request = Request::TransformOf {
+            path: path.to_path_buf(),
+        };
+        match self.call(&request)? {
+            Response::Transform(transform) => Ok(transform),
+            other => bail!("unexpected response to {request:?}: {other:?}"),
+        }
+    }
+
+    fn record_media_metadata(&self, digest: &ContentHash, metadata: &MediaMetadata) -> Result<()> {
+        self.call_done(&Request::RecordMediaMetadata {
+            digest: *digest,
+            metadata: metadata.clone(),
+        })
+    }
+
+    fn record_image_fingerprint(
+        &self,
+        digest: &ContentHash,
+        fingerprint: &ImageFingerprint,
+    ) -> Result<()> {
+        self.call_done(&Request::RecordImageFingerprint {
+            digest: *digest,
+            fingerprint: *fingerprint,
+        })
+    }
+
+    fn icloud_assets(&self, namespace: &str) -> Result<HashSet<String>> {
+        let request = Request::ICloudAssets {
+            namespace: namespace.to_string(),
+        };
+        match self.call(&request)? {
+            Response::AssetIds(ids) => Ok(ids),
+            other => bail!("unexpected response to {request:?}: {other:?}"),
+        }
+    }
+
+    fn record_icloud_asset(
+        &self,
+        namespace: &str,
+        asset_id: &str,
+        path: &Path,
+        digest: &ContentHash,
+    ) -> Result<()> {
+        self.call_done(&Request::RecordICloudAsset {
+            namespace: namespace.to_string(),
+            asset_id: asset_id.to_string(),
+            path: path.to_path_buf(),
+            digest: *digest,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::PhotoSyncStore;
+
+    #[test]
+    fn remote_calls_reach_the_store() {
+        let store = PhotoSyncStore::new_for_tests().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::scope(|s| {
+            s.spawn(|| {
+                let (stream, _) = listener.accept().unwrap();
+                handle_connection(&store, stream).unwrap();
+            });
+
+            let remote = RemoteCatalogue::connect(addr.to_string()).unwrap();
+            let path = Path::new("IMG_0001.HEIC");
+            let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+            let digest = ContentHash::new_for_tests(7);
+
+            assert!(!remote.exists_in_target(&digest).unwrap());
+            let run = remote.begin_run("laptop").unwrap();
+            remote
+                .mark_transferred_from_source(run, "laptop", path, &digest, now, 42)
+                .unwrap();
+            assert!(remote.exists_in_target(&digest).unwrap());
+            assert_eq!(
+                remote
+                    .was_transferred_from_source("laptop", path, now, 42)
+                    .unwrap(),
+                WasTransferredFromSourceResult::Transferred
+            );
+            // errors from the store come back as errors rather than breaking the connection.
+            let ttl = Duration::from_secs(60);
+            remote.acquire_lease("sync", "a", ttl).unwrap();
+            assert!(remote.acquire_lease("sync", "b", ttl).is_err());
+            remote.release_lease("sync", "a").unwrap();
+            drop(remote);
+        });
+    }
+
+    #[test]
+    fn connections_closed_while_idle_are_reopened() {
+        let store = PhotoSyncStore::new_for_tests().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::scope(|s| {
+            s.spawn(|| {
+                // the connection made on connecting, closed as the server does once it's idle.
+                drop(listener.accept().unwrap());
+                let (stream, _) = listener.accept().unwrap();
+                handle_connection(&store, stream).unwrap();
+            });
+
+            let remote = RemoteCatalogue::connect(addr.to_string()).unwrap();
+            let digest = ContentHash::new_for_tests(7);
+            assert!(!remote.exists_in_target(&digest).unwrap());
+            drop(remote);
+        });
+    }
+}
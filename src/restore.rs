@@ -0,0 +1,186 @@
+//! Copying files back out of the archive, undoing whatever was done to store them.
+
+use std::{fs, path::Path};
+
+use age::Identity;
+use eyre::{Result, bail};
+
+use crate::{
+    RestoreArgs,
+    compress::{Transform, digest_archived, original_path},
+    digest::ContentHash,
+    encrypt::{decrypt, is_encrypted, load_identities},
+    scrub::restore as restore_file,
+    store::{CataloguedFile, PhotoSyncStore},
+};
+
+pub fn restore(args: RestoreArgs) -> Result<()> {
+    let store = PhotoSyncStore::new(args.database_file)?;
+    let identities = match &args.identity {
+        Some(path) => load_identities(path)?,
+        None => Vec::new(),
+    };
+    let files: Vec<_> = store
+        .target_files()?
+        .into_iter()
+        .filter(|file| args.paths.is_empty() || args.paths.iter().any(|p| file.path.starts_with(p)))
+        .collect();
+
+    let transcoded = store.transcoded_files()?;
+    let transforms = store.transformed_files()?;
+
+    let mut failed = 0;
+    for file in &files {
+        // a transcoded copy is restored as what it was transcoded to.
+        let expected = transcoded.get(&file.path).copied().unwrap_or(file.digest);
+        let transform = transforms.get(&file.path).copied().unwrap_or_default();
+        match restore_one(
+            &args.out_dir,
+            &args.to,
+            file,
+            &expected,
+            transform,
+            &identities,
+        ) {
+            Ok(()) => println!("restored {:?}", file.path),
+            Err(e) => {
+                println!("could not restore {:?}: {e}", file.path);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        bail!("{failed} of {} files could not be restored", files.len());
+    }
+    println!("restored {} files to {:?}", files.len(), args.to);
+    Ok(())
+}
+
+/// Restores an archived file, stored as `transform`, to its original name under `to`, checking
+/// its content is still `expected`, what was catalogued.
+fn restore_one(
+    out_dir: &Path,
+    to: &Path,
+    file: &CataloguedFile,
+    expected: &ContentHash,
+    transform: Transform,
+    identities: &[Box<dyn Identity>],
+) -> Result<()> {
+    let mut archived = out_dir.join(&file.path);
+    let mut path = file.path.clone();
+    // kept until restored from, when decrypted.
+    let mut _decrypted = None;
+    if is_encrypted(&path) {
+        if identities.is_empty() {
+            bail!("it's encrypted, so an identity is needed to restore it");
+        }
+        path = path.with_extension("");
+        fs::create_dir_all(to)?;
+        let mut decrypted = tempfile::Builder::new().prefix(".").tempfile_in(to)?;
+        decrypt(&archived, decrypted.as_file_mut(), identities)?;
+        archived = decrypted.path().to_path_buf();
+        _decrypted = Some(decrypted);
+    }
+    let restored = to.join(original_path(&path, transform));
+    restore_file(&archived, transform, &restored, Transform::None, file.mtime)?;
+    let actual = digest_archived(&restored, Transform::None, expected.algorithm())?;
+    if actual != *expected {
+        fs::remove_file(&restored)?;
+        bail!("it was catalogued as {expected}, but is now {actual}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, time::SystemTime};
+
+    use super::*;
+    use crate::{compress::compress, digest::digest, encrypt::encrypt};
+
+    #[test]
+    fn archived_files_are_restored_as_they_were() {
+        let dir = tempfile::tempdir().unwrap();
+        let (out_dir, to) = (dir.path().join("out"), dir.path().join("restored"));
+        fs::create_dir_all(&out_dir).unwrap();
+        let original = dir.path().join("clip.mov");
+        fs::write(&original, "video").unwrap();
+        compress(
+            &original,
+            &mut File::create(out_dir.join("clip.mov.zst")).unwrap(),
+            3,
+        )
+        .unwrap();
+        let file = CataloguedFile {
+            path: "clip.mov.zst".into(),
+            mtime: SystemTime::UNIX_EPOCH,
+            size: 5,
+            digest: digest(&original).unwrap(),
+        };
+        let compressed = Transform::Compressed;
+
+        restore_one(&out_dir, &to, &file, &file.digest, compressed, &[]).unwrap();
+        assert_eq!(fs::read_to_string(to.join("clip.mov")).unwrap(), "video");
+
+        // encrypted after compression, as a sync does both.
+        let identity = age::x25519::Identity::generate();
+        encrypt(
+            &out_dir.join("clip.mov.zst"),
+            File::create(out_dir.join("clip.mov.zst.age")).unwrap(),
+            &[identity.to_public()],
+        )
+        .unwrap();
+        let encrypted = CataloguedFile {
+            path: "clip.mov.zst.age".into(),
+            ..file.clone()
+        };
+        fs::remove_file(to.join("clip.mov")).unwrap();
+        assert!(restore_one(&out_dir, &to, &encrypted, &file.digest, compressed, &[]).is_err());
+        restore_one(
+            &out_dir,
+            &to,
+            &encrypted,
+            &file.digest,
+            compressed,
+            &[Box::new(identity)],
+        )
+        .unwrap();
+        assert_eq!(fs::read_to_string(to.join("clip.mov")).unwrap(), "video");
+        assert_eq!(fs::read_dir(&to).unwrap().count(), 1);
+
+        fs::write(&original, "rot").unwrap();
+        compress(
+            &original,
+            &mut File::create(out_dir.join("clip.mov.zst")).unwrap(),
+            3,
+        )
+        .unwrap();
+        assert!(restore_one(&out_dir, &to, &file, &file.digest, compressed, &[]).is_err());
+        assert!(!to.join("clip.mov").exists());
+
+        // a transcoded copy is checked against what it was transcoded to.
+        fs::write(out_dir.join("photo.jpg"), "jpeg").unwrap();
+        let transcoded = CataloguedFile {
+            path: "photo.jpg".into(),
+            ..file.clone()
+        };
+        let output = digest(&out_dir.join("photo.jpg")).unwrap();
+        let plain = Transform::None;
+        assert!(restore_one(&out_dir, &to, &transcoded, &transcoded.digest, plain, &[]).is_err());
+        restore_one(&out_dir, &to, &transcoded, &output, plain, &[]).unwrap();
+        assert_eq!(fs::read_to_string(to.join("photo.jpg")).unwrap(), "jpeg");
+
+        // an original named like a compressed copy is restored as it was stored.
+        fs::write(out_dir.join("notes.zst"), "not zstd").unwrap();
+        let named = CataloguedFile {
+            path: "notes.zst".into(),
+            digest: digest(&out_dir.join("notes.zst")).unwrap(),
+            ..file.clone()
+        };
+        restore_one(&out_dir, &to, &named, &named.digest, plain, &[]).unwrap();
+        assert_eq!(
+            fs::read_to_string(to.join("notes.zst")).unwrap(),
+            "not zstd"
+        );
+    }
+}
@@ -0,0 +1,419 @@
+//! `--interactive`: once a run has finished, a terminal interface for going through the files it
+//! couldn't transfer, the failed transfers still outstanding and the new files whose place in the
+//! out directory was taken, and deciding what's to be done with each. Decisions are kept in the
+//! catalogue, and later runs follow them.
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, IsTerminal},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use chrono::{DateTime, Local};
+use eyre::{Result, bail};
+use ratatui::{
+    DefaultTerminal, Frame,
+    crossterm::event::{self, Event, KeyCode, KeyEventKind},
+    layout::{Constraint, Layout},
+    style::{Modifier, Style},
+    text::Line,
+    widgets::{Block, List, ListItem, ListState, Paragraph, Wrap},
+};
+use tracing::{info, warn};
+
+use crate::{
+    digest::HashAlgorithm,
+    platform::FileInfo,
+    sources::Source,
+    store::{PhotoSyncStore, ReviewDecision, RunId},
+};
+
+/// The directory, at the top of the out directory, copies of quarantined files are set aside in.
+pub const QUARANTINE_DIR: &str = ".quarantine";
+
+const HELP: &str = "↑/↓ move · r retransfer · i ignore forever · x quarantine · u undecide · \
+                    h hash · q done";
+
+/// How many files PageUp and PageDown move by.
+const PAGE: u16 = 10;
+
+enum Problem {
+    Failed {
+        reason: String,
+        attempts: u32,
+        failed_at: SystemTime,
+    },
+    /// Its place in the out directory, `target`, was taken by another file.
+    Collided { target: PathBuf },
+}
+
+/// A source file to be reviewed.
+struct Item {
+    namespace: String,
+    in_dir: PathBuf,
+    path: PathBuf,
+    problem: Problem,
+    decision: Option<ReviewDecision>,
+    digest: Option<String>,
+}
+
+struct Review<'a> {
+    store: &'a PhotoSyncStore,
+    out_dir: Option<&'a Path>,
+    algorithm: HashAlgorithm,
+    items: Vec<Item>,
+    list: ListState,
+    status: String,
+}
+
+/// Reviews what the latest run of `machine_id`, over `sources`, couldn't transfer, if there's
+/// anything and a terminal to review it in.
+pub fn review(
+    database_file: &Path,
+    sources: &[Source],
+    machine_id: &str,
+    out_dir: Option<&Path>,
+    algorithm: HashAlgorithm,
+) -> Result<()> {
+    let store = PhotoSyncStore::new(database_file.to_path_buf())?;
+    let Some(run) = store.latest_run(machine_id)? else {
+        return Ok(());
+    };
+    let items = items(&store, sources, run)?;
+    if items.is_empty() {
+        info!("there's nothing to review");
+        return Ok(());
+    }
+    if !io::stdin().is_terminal() || !io::stdout().is_terminal() {
+        warn!(
+            "not reviewing the {} files which weren't transferred, as there's no terminal",
+            items.len()
+        );
+        return Ok(());
+    }
+    let mut review = Review {
+        store: &store,
+        out_dir,
+        algorithm,
+        items,
+        list: ListState::default().with_selected(Some(0)),
+        status: String::new(),
+    };
+    let mut terminal = ratatui::try_init()?;
+    let reviewed = review.run(&mut terminal);
+    ratatui::try_restore()?;
+    reviewed
+}
+
+/// The outstanding failed transfers from `sources`, and the files `run` left as their place was
+/// taken.
+fn items(store: &PhotoSyncStore, sources: &[Source], run: RunId) -> Result<Vec<Item>> {
+    let mut items = Vec::new();
+    let mut decisions = HashMap::new();
+    for source in sources {
+        for (path, decision) in store.review_decisions(&source.namespace)? {
+            decisions.insert((source.namespace.clone(), path), decision);
+        }
+        for failed in store.failed_transfer_records(&source.namespace)? {
+            items.push(Item {
+                namespace: source.namespace.clone(),
+                in_dir: source.dir.clone(),
+                path: failed.path,
+                problem: Problem::Failed {
+                    reason: failed.reason,
+                    attempts: failed.attempts,
+                    failed_at: failed.failed_at,
+                },
+                decision: None,
+                digest: None,
+            });
+        }
+    }
+    for (namespace, path, target) in store.skipped_collisions(run)? {
+        let Some(source) = sources.iter().find(|s| s.namespace == namespace) else {
+            continue;
+        };
+        items.push(Item {
+            namespace,
+            in_dir: source.dir.clone(),
+            path,
+            problem: Problem::Collided { target },
+            decision: None,
+            digest: None,
+        });
+    }
+    for item in &mut items {
+        item.decision = decisions
+            .get(&(item.namespace.clone(), item.path.clone()))
+            .copied();
+    }
+    Ok(items)
+}
+
+impl Review<'_> {
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> Result<()> {
+        loop {
+            terminal.draw(|frame| self.render(frame))?;
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                KeyCode::Down | KeyCode::Char('j') => self.list.select_next(),
+                KeyCode::Up | KeyCode::Char('k') => self.list.select_previous(),
+                KeyCode::PageDown => self.list.scroll_down_by(PAGE),
+                KeyCode::PageUp => self.list.scroll_up_by(PAGE),
+                KeyCode::Char('r') => self.decide(Some(ReviewDecision::Retransfer)),
+                KeyCode::Char('i') => self.decide(Some(ReviewDecision::Ignore)),
+                KeyCode::Char('x') => self.decide(Some(ReviewDecision::Quarantine)),
+                KeyCode::Char('u') => self.decide(None),
+                KeyCode::Char('h') => self.hash(),
+                _ => {}
+            }
+        }
+    }
+
+    fn selected(&mut self) -> Option<&mut Item> {
+        let idx = self.list.selected()?.min(self.items.len().checked_sub(1)?);
+        self.items.get_mut(idx)
+    }
+
+    /// Carries out and records `decision` for the selected file, or with `None`, forgets what was
+    /// decided.
+    fn decide(&mut self, decision: Option<ReviewDecision>) {
+        let (store, out_dir) = (self.store, self.out_dir);
+        let Some(item) = self.selected() else {
+            return;
+        };
+        self.status = match decide(store, out_dir, item, decision) {
+            Ok(done) => done,
+            Err(e) => format!("couldn't: {e}"),
+        };
+    }
+
+    fn hash(&mut self) {
+        let algorithm = self.algorithm;
+        let Some(item) = self.selected() else {
+            return;
+        };
+        let path = item.in_dir.join(&item.path);
+        match fs::File::open(&path).map(|mut file| algorithm.digest_reader(&mut file)) {
+            Ok(Ok(digest)) => item.digest = Some(digest.to_string()),
+            Ok(Err(e)) => self.status = format!("couldn't hash {path:?}: {e}"),
+            Err(e) => self.status = format!("couldn't open {path:?}: {e}"),
+        }
+    }
+
+    fn render(&mut self, frame: &mut Frame) {
+        let [main, footer] =
+            Layout::vertical([Constraint::Min(3), Constraint::Length(2)]).areas(frame.area());
+        let [list, details] =
+            Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)])
+                .areas(main);
+        let items = self.items.iter().map(|item| {
+            let label = match (item.decision, &item.problem) {
+                (Some(decision), _) => decision.as_str(),
+                (None, Problem::Failed { .. }) => "failed",
+                (None, Problem::Collided { .. }) => "collided",
+            };
+            ListItem::new(format!("{label:<10} {}", item.path.display()))
+        });
+        let title = format!(" {} files to review ", self.items.len());
+        frame.render_stateful_widget(
+            List::new(items)
+                .block(Block::bordered().title(title))
+                .highlight_style(Style::new().add_modifier(Modifier::REVERSED)),
+            list,
+            &mut self.list,
+        );
+        let lines = match self.list.selected().and_then(|idx| self.items.get(idx)) {
+            Some(item) => describe(item, self.out_dir),
+            None => Vec::new(),
+        };
+        frame.render_widget(
+            Paragraph::new(lines)
+                .block(Block::bordered().title(" details "))
+                .wrap(Wrap { trim: false }),
+            details,
+        );
+        frame.render_widget(
+            Paragraph::new(vec![Line::from(self.status.as_str()), Line::from(HELP)]),
+            footer,
+        );
+    }
+}
+
+/// Carries out and records `decision` for `item`, saying what was done.
+fn decide(
+    store: &PhotoSyncStore,
+    out_dir: Option<&Path>,
+    item: &mut Item,
+    decision: Option<ReviewDecision>,
+) -> Result<String> {
+    let Some(decision) = decision else {
+        store.forget_review_decision(&item.namespace, &item.path)?;
+        item.decision = None;
+        return Ok(format!("{:?} is undecided", item.path));
+    };
+    let done = match decision {
+        ReviewDecision::Retransfer => format!(
+            "{:?} will be transferred next run, under a free name if its place is taken",
+            item.path
+        ),
+        ReviewDecision::Ignore => format!("{:?} will never be transferred", item.path),
+        ReviewDecision::Quarantine => {
+            let Some(out_dir) = out_dir else {
+                bail!("there's no out directory to quarantine it in");
+            };
+            let to = out_dir
+                .join(QUARANTINE_DIR)
+                .join(&item.namespace)
+                .join(&item.path);
+            if let Some(parent) = to.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::copy(item.in_dir.join(&item.path), &to)?;
+            format!(
+                "copied {:?} to {to:?}; it will never be transferred",
+                item.path
+            )
+        }
+    };
+    if decision != ReviewDecision::Retransfer {
+        store.forget_failed_transfer(&item.namespace, &item.path)?;
+    }
+    store.record_review_decision(&item.namespace, &item.path, decision)?;
+    item.decision = Some(decision);
+    Ok(done)
+}
+
+/// What's known of `item`, for its details.
+fn describe(item: &Item, out_dir: Option<&Path>) -> Vec<Line<'static>> {
+    let time = |time: SystemTime| {
+        DateTime::<Local>::from(time)
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string()
+    };
+    let file = |path: &Path| match FileInfo::of(path) {
+        Ok(info) => format!("{} bytes, modified {}", info.size, time(info.modified)),
+        Err(e) => format!("unreadable: {e}"),
+    };
+    let in_path = item.in_dir.join(&item.path);
+    let mut lines = vec![
+        Line::from(format!("{in_path:?}")),
+        Line::from(file(&in_path)),
+        Line::from(match &item.digest {
+            Some(digest) => format!("digest {digest}"),
+            None => "not hashed (h to hash)".to_string(),
+        }),
+        Line::from(""),
+    ];
+    match &item.problem {
+        Problem::Failed {
+            reason,
+            attempts,
+            failed_at,
+        } => {
+            lines.push(Line::from(format!(
+                "failed {attempts} times, last at {}:",
+                time(*failed_at)
+            )));
+            lines.push(Line::from(reason.clone()));
+        }
+        Problem::Collided { target } => {
+            let target = match out_dir {
+                Some(out_dir) => out_dir.join(target),
+                None => target.clone(),
+            };
+            lines.push(Line::from(format!("its place, {target:?}, holds")));
+            lines.push(Line::from(file(&target)));
+        }
+    }
+    if let Some(decision) = item.decision {
+        lines.push(Line::from(""));
+        lines.push(Line::from(format!("decided: {}", decision.as_str())));
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use ratatui::{Terminal, backend::TestBackend};
+
+    use super::*;
+    use crate::sources::SourceKind;
+
+    #[test]
+    fn decisions_are_kept_in_the_catalogue() {
+        let dir = tempfile::tempdir().unwrap();
+        let (in_dir, out_dir) = (dir.path().join("in"), dir.path().join("out"));
+        fs::create_dir_all(in_dir.join("2019")).unwrap();
+        fs::write(in_dir.join("2019/broken.jpg"), b"broken").unwrap();
+        fs::write(in_dir.join("2019/junk.jpg"), b"junk").unwrap();
+        let store = PhotoSyncStore::new_for_tests().unwrap();
+        let run = store.begin_run("laptop").unwrap();
+        for name in ["2019/broken.jpg", "2019/junk.jpg"] {
+            store
+                .record_failed_transfer(run, "laptop", Path::new(name), "unreadable")
+                .unwrap();
+        }
+        let sources = [Source {
+            dir: in_dir,
+            namespace: "laptop".into(),
+            kind: SourceKind::Directory,
+        }];
+        let mut items = items(&store, &sources, run).unwrap();
+        assert_eq!(items.len(), 2);
+
+        let mut review = Review {
+            store: &store,
+            out_dir: Some(&out_dir),
+            algorithm: HashAlgorithm::Sha256,
+            items: Vec::new(),
+            list: ListState::default().with_selected(Some(0)),
+            status: String::new(),
+        };
+        decide(
+            &store,
+            Some(&out_dir),
+            &mut items[0],
+            Some(ReviewDecision::Quarantine),
+        )
+        .unwrap();
+        decide(
+            &store,
+            Some(&out_dir),
+            &mut items[1],
+            Some(ReviewDecision::Ignore),
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read(out_dir.join(".quarantine/laptop/2019/broken.jpg")).unwrap(),
+            b"broken"
+        );
+        assert!(store.failed_transfers("laptop").unwrap().is_empty());
+        assert_eq!(
+            store.review_decisions("laptop").unwrap(),
+            [
+                (PathBuf::from("2019/broken.jpg"), ReviewDecision::Quarantine),
+                (PathBuf::from("2019/junk.jpg"), ReviewDecision::Ignore),
+            ]
+        );
+        decide(&store, Some(&out_dir), &mut items[1], None).unwrap();
+        assert_eq!(store.review_decisions("laptop").unwrap().len(), 1);
+
+        review.items = items;
+        let mut terminal = Terminal::new(TestBackend::new(100, 12)).unwrap();
+        terminal.draw(|frame| review.render(frame)).unwrap();
+        let screen: String = (terminal.backend().buffer().content().iter())
+            .map(|cell| cell.symbol())
+            .collect();
+        assert!(screen.contains("quarantine 2019/broken.jpg"));
+        assert!(screen.contains("failed     2019/junk.jpg"));
+    }
+}
@@ -0,0 +1,109 @@
+//! Reporting file names used in more than one folder of the archive for different content, e.g.
+//! two unrelated IMG_0001.JPG, which are easy to mistake for each other when browsing by hand.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::PathBuf,
+};
+
+use eyre::Result;
+
+use crate::{
+    SameNamesArgs,
+    compress::{Transform, original_path},
+    store::{Archive, CataloguedFile, PhotoSyncStore},
+};
+
+pub fn same_names(args: SameNamesArgs) -> Result<()> {
+    let store = PhotoSyncStore::new(args.database_file)?;
+    let archived = store
+        .old_target_files()?
+        .into_iter()
+        .map(|file| (Archive::OldOut, file))
+        .chain(store.target_files()?.into_iter().map(|f| (Archive::Out, f)));
+    let clashes = find_clashes(archived, &store.transformed_files()?);
+    for (name, files) in &clashes {
+        println!("{name}:");
+        for (archive, file) in files {
+            let dir = match archive {
+                Archive::OldOut => "old",
+                Archive::Out => "out",
+            };
+            println!("    {dir}:{} ({})", file.path.display(), file.digest);
+        }
+    }
+    println!(
+        "{} file names are used for different content in different folders",
+        clashes.len()
+    );
+    Ok(())
+}
+
+/// Groups archived files by their name, ignoring case and how they're stored, as `transformed`
+/// says of the out directory's, keeping the names shared by files in different folders with
+/// different content.
+fn find_clashes(
+    archived: impl Iterator<Item = (Archive, CataloguedFile)>,
+    transformed: &HashMap<PathBuf, Transform>,
+) -> BTreeMap<String, Vec<(Archive, CataloguedFile)>> {
+    let mut by_name = BTreeMap::<_, Vec<_>>::new();
+    for (archive, file) in archived {
+        let transform = match archive {
+            Archive::OldOut => Transform::None,
+            Archive::Out => transformed.get(&file.path).copied().unwrap_or_default(),
+        };
+        let Some(name) = original_path(&file.path, transform)
+            .file_name()
+            .map(|n| n.to_owned())
+        else {
+            continue;
+        };
+        by_name
+            .entry(name.to_string_lossy().to_lowercase())
+            .or_default()
+            .push((archive, file));
+    }
+    by_name.retain(|_, files| {
+        let folders: HashSet<PathBuf> = files
+            .iter()
+            .map(|(_, file)| file.path.parent().unwrap_or(&file.path).to_path_buf())
+            .collect();
+        let contents: HashSet<_> = files.iter().map(|(_, file)| file.digest).collect();
+        folders.len() > 1 && contents.len() > 1
+    });
+    by_name
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+    use crate::digest::ContentHash;
+
+    #[test]
+    fn only_names_shared_by_different_content_clash() {
+        let file = |path: &str, id| CataloguedFile {
+            path: PathBuf::from(path),
+            mtime: SystemTime::UNIX_EPOCH,
+            size: 1,
+            digest: ContentHash::new_for_tests(id),
+        };
+        let clashes = find_clashes(
+            [
+                (Archive::OldOut, file("2019/IMG_0001.JPG", 1)),
+                (Archive::Out, file("2020/img_0001.jpg.zst", 2)),
+                (Archive::OldOut, file("2019/IMG_0002.JPG", 3)),
+                (Archive::Out, file("copies/IMG_0002.JPG", 3)),
+                (Archive::Out, file("IMG_0003.JPG", 4)),
+            ]
+            .into_iter(),
+            &HashMap::from([(
+                PathBuf::from("2020/img_0001.jpg.zst"),
+                Transform::Compressed,
+            )]),
+        );
+        assert_eq!(clashes.keys().collect::<Vec<_>>(), ["img_0001.jpg"]);
+        assert_eq!(clashes["img_0001.jpg"].len(), 2);
+    }
+}
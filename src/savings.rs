@@ -0,0 +1,117 @@
+//! How much deduplication has saved over the catalogue's lifetime, and where the duplicates come
+//! from.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+};
+
+use eyre::Result;
+
+use crate::{
+    SavingsArgs,
+    store::{PhotoSyncStore, SourceFile},
+};
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Savings {
+    files: u64,
+    bytes: u64,
+    duplicate_files: u64,
+    duplicate_bytes: u64,
+    /// Duplicate files and bytes by namespace and the folder they were in, most bytes first.
+    folders: Vec<((String, PathBuf), (u64, u64))>,
+}
+
+pub fn savings(args: SavingsArgs) -> Result<()> {
+    let store = PhotoSyncStore::new(args.database_file)?;
+    let savings = summarise(store.source_files()?);
+
+    let percent = |part: u64, whole: u64| part as f64 * 100.0 / whole.max(1) as f64;
+    println!(
+        "{} of {} transferred files were duplicates ({:.1}%)",
+        savings.duplicate_files,
+        savings.files,
+        percent(savings.duplicate_files, savings.files)
+    );
+    println!(
+        "saved {}MB of {}MB ({:.1}%)",
+        savings.duplicate_bytes / 1_000_000,
+        savings.bytes / 1_000_000,
+        percent(savings.duplicate_bytes, savings.bytes)
+    );
+    println!("folders contributing the most duplication:");
+    for ((namespace, folder), (files, bytes)) in savings.folders.iter().take(args.top) {
+        println!(
+            "    {namespace}:{}: {files} files, {}MB",
+            folder.display(),
+            bytes / 1_000_000
+        );
+    }
+    Ok(())
+}
+
+/// A file is a duplicate if its content was already in the old out directory, or was transferred
+/// from an earlier file.
+fn summarise(files: Vec<SourceFile>) -> Savings {
+    let mut savings = Savings::default();
+    let mut seen = HashSet::new();
+    let mut folders = HashMap::<_, (u64, u64)>::new();
+    for file in files {
+        savings.files += 1;
+        savings.bytes += file.size;
+        if !seen.insert(file.digest) || file.in_old_target {
+            savings.duplicate_files += 1;
+            savings.duplicate_bytes += file.size;
+            let folder = match file.path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+                _ => PathBuf::from("."),
+            };
+            let totals = folders.entry((file.namespace, folder)).or_default();
+            totals.0 += 1;
+            totals.1 += file.size;
+        }
+    }
+    savings.folders = folders.into_iter().collect();
+    savings
+        .folders
+        .sort_by(|a, b| b.1.1.cmp(&a.1.1).then_with(|| a.0.cmp(&b.0)));
+    savings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::digest::ContentHash;
+
+    #[test]
+    fn duplicates_are_attributed_to_their_folders() {
+        let file = |path: &str, size, digest, in_old_target| SourceFile {
+            namespace: "laptop".into(),
+            path: path.into(),
+            size,
+            digest: ContentHash::new_for_tests(digest),
+            in_old_target,
+        };
+        let savings = summarise(vec![
+            file("a/1.jpg", 10, 1, false),
+            file("b/1.jpg", 10, 1, false),
+            file("b/2.jpg", 20, 2, true),
+            file("c/3.jpg", 5, 3, false),
+            file("c/copy of 3.jpg", 5, 3, false),
+        ]);
+        assert_eq!(
+            savings,
+            Savings {
+                files: 5,
+                bytes: 50,
+                duplicate_files: 3,
+                duplicate_bytes: 35,
+                folders: vec![
+                    (("laptop".into(), "b".into()), (2, 30)),
+                    (("laptop".into(), "c".into()), (1, 5)),
+                ],
+            }
+        );
+    }
+}
@@ -0,0 +1,342 @@
+//! Rehashing archived files to catch bitrot, visiting those verified longest ago first so that
+//! repeated scrubs cover the whole archive.
+
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use eyre::{Result, WrapErr, bail};
+use tempfile::NamedTempFile;
+
+use crate::{
+    ScrubArgs,
+    compress::{Transform, copy_archived},
+    parity,
+    platform::set_archive_permissions,
+    store::{CataloguedFile, FileCopy, PhotoSyncStore, Repair},
+    throttle::Throttle,
+    trash::Trash,
+};
+
+/// How many files are fetched from the catalogue at a time.
+const BATCH_SIZE: usize = 256;
+/// How long a continuous scrub waits when nothing is due to be verified.
+const IDLE_WAIT: Duration = Duration::from_secs(60 * 60);
+
+pub fn scrub(args: ScrubArgs) -> Result<()> {
+    let store = PhotoSyncStore::new(args.database_file.clone())?;
+    if args.list_repairs {
+        for repair in store.repairs()? {
+            let at = repair.repaired_at.duration_since(SystemTime::UNIX_EPOCH)?;
+            println!(
+                "{} {:?} ({}) from {}",
+                at.as_secs(),
+                repair.path,
+                repair.digest,
+                repair.source
+            );
+        }
+        return Ok(());
+    }
+    let interval = Duration::from_secs(args.older_than_days * 24 * 60 * 60);
+    let throttle = Throttle::new(args.max_bytes_per_second);
+    let trash = Trash::new(&args.old_out_dir, args.trash_retention_days);
+    let mut remaining = args.limit.unwrap_or(usize::MAX);
+    // files already reported as missing, unreadable or corrupt, which stay due until fixed.
+    let mut damaged = HashSet::<PathBuf>::new();
+
+    loop {
+        let before = SystemTime::now() - interval;
+        let batch = BATCH_SIZE.min(remaining) + damaged.len();
+        let candidates: Vec<_> = store
+            .files_to_scrub(before, batch)?
+            .into_iter()
+            .filter(|c| !damaged.contains(&c.path))
+            .take(remaining)
+            .collect();
+        if candidates.is_empty() {
+            if !args.continuous || remaining == 0 {
+                break;
+            }
+            thread::sleep(IDLE_WAIT);
+            continue;
+        }
+
+        for candidate in candidates {
+            remaining -= 1;
+            let full_path = args.old_out_dir.join(&candidate.path);
+            if is_intact(&full_path, &candidate, &throttle)? {
+                store.mark_verified(&candidate.path, SystemTime::now())?;
+                continue;
+            }
+
+            if args.repair
+                && let Some(source) = repair(
+                    &store,
+                    &args.old_out_dir,
+                    &trash,
+                    args.in_dir.as_deref(),
+                    args.parity_dir.as_deref(),
+                    &args.machine_id,
+                    &candidate,
+                )?
+            {
+                println!("REPAIRED {:?} from {source}", candidate.path);
+                store.mark_verified(&candidate.path, SystemTime::now())?;
+            } else {
+                damaged.insert(candidate.path);
+            }
+        }
+    }
+
+    trash.empty_expired()?;
+    if !damaged.is_empty() {
+        bail!("{} files are missing, unreadable or corrupt", damaged.len());
+    }
+    println!("scrub found no problems");
+    Ok(())
+}
+
+/// Whether the archived file at `full_path` still has `expected`'s content, hashed as it was
+/// catalogued: as the bytes in the old out directory, however they're stored. What's wrong with
+/// it is reported if not.
+fn is_intact(full_path: &Path, expected: &CataloguedFile, throttle: &Throttle) -> Result<bool> {
+    match full_path.metadata() {
+        Ok(metadata) => {
+            // one unreadable file is damage to report, not a reason to stop scrubbing the rest.
+            let actual = match expected.digest.algorithm().digest(full_path) {
+                Ok(actual) => actual,
+                Err(e) => {
+                    println!("UNREADABLE {:?}: {e}", expected.path);
+                    return Ok(false);
+                }
+            };
+            throttle.consumed(metadata.len());
+            if actual == expected.digest {
+                return Ok(true);
+            }
+            println!(
+                "MISMATCH {:?}: catalogued as {}, now {actual}",
+                expected.path, expected.digest
+            );
+        }
+        Err(e) => println!("MISSING {:?}: {e}", expected.path),
+    }
+    Ok(false)
+}
+
+/// Restores a damaged file in the old out directory from the first other copy of its content which
+/// is still intact, or failing that from parity, returning where it came from. The damaged file is
+/// kept in `trash`.
+fn repair(
+    store: &PhotoSyncStore,
+    old_out_dir: &Path,
+    trash: &Trash,
+    in_dir: Option<&Path>,
+    parity_dir: Option<&Path>,
+    namespace: &str,
+    damaged: &CataloguedFile,
+) -> Result<Option<String>> {
+    // kept until restored from, when rebuilt from parity.
+    let mut _rebuilt = None;
+    let (path, source) = match intact_copy(store, old_out_dir, in_dir, namespace, damaged)? {
+        Some(copy) => copy,
+        None => {
+            let Some(parity_dir) = parity_dir else {
+                return Ok(None);
+            };
+            let Some((rebuilt, parity)) = parity::rebuild(
+                store,
+                old_out_dir,
+                parity_dir,
+                &damaged.path,
+                &damaged.digest,
+            )?
+            else {
+                return Ok(None);
+            };
+            let path = rebuilt.path().to_path_buf();
+            _rebuilt = Some(rebuilt);
+            (path, format!("parity:{parity}"))
+        }
+    };
+
+    let target = old_out_dir.join(&damaged.path);
+    if target.exists() {
+        trash.keep_copy(&target)?;
+    }
+    // the old out directory's files, and the sources and rebuilds they're restored from, are
+    // kept as they are.
+    restore(
+        &path,
+        Transform::None,
+        &target,
+        Transform::None,
+        damaged.mtime,
+    )
+    .wrap_err_with(|| format!("could not restore {:?} from {path:?}", damaged.path))?;
+    store.record_repair(&Repair {
+        path: damaged.path.clone(),
+        digest: damaged.digest,
+        source: source.clone(),
+        repaired_at: SystemTime::now(),
+    })?;
+    Ok(Some(source))
+}
+
+/// Finds another catalogued copy of `damaged`'s content which is still intact, and describes it.
+fn intact_copy(
+    store: &PhotoSyncStore,
+    old_out_dir: &Path,
+    in_dir: Option<&Path>,
+    namespace: &str,
+    damaged: &CataloguedFile,
+) -> Result<Option<(PathBuf, String)>> {
+    for copy in store.copies_of(&damaged.digest, namespace)? {
+        let (path, source) = match copy {
+            FileCopy::OldTarget(path) if path == damaged.path => continue,
+            FileCopy::OldTarget(path) => {
+                (old_out_dir.join(&path), format!("old:{}", path.display()))
+            }
+            // scrubs don't know where the out directory is.
+            FileCopy::Target(_) => continue,
+            FileCopy::Source(path) => {
+                let Some(in_dir) = in_dir else { continue };
+                (
+                    in_dir.join(&path),
+                    format!("source:{namespace}:{}", path.display()),
+                )
+            }
+        };
+        // other copies can rot or go missing too.
+        if path.is_file() && damaged.digest.algorithm().digest(&path).ok() == Some(damaged.digest) {
+            return Ok(Some((path, source)));
+        }
+    }
+    Ok(None)
+}
+
+/// Replaces `to`, to be stored as `to_transform`, with a copy of the content of `from`, stored as
+/// `from_transform`, with its catalogued modification time so that the next sync doesn't rehash
+/// it.
+pub fn restore(
+    from: &Path,
+    from_transform: Transform,
+    to: &Path,
+    to_transform: Transform,
+    mtime: SystemTime,
+) -> Result<()> {
+    let dir = to.parent().unwrap_or(Path::new("."));
+    fs::create_dir_all(dir)?;
+    let mut staged = NamedTempFile::new_in(dir)?;
+    copy_archived(from, from_transform, staged.as_file_mut(), to_transform)?;
+    staged.as_file().set_modified(mtime)?;
+    staged.as_file().sync_all()?;
+    set_archive_permissions(staged.path())?;
+    staged.persist(to)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::digest::{HashAlgorithm, digest};
+
+    #[test]
+    fn repair_restores_from_an_intact_copy() {
+        let dir = tempfile::tempdir().unwrap();
+        let (old_out_dir, in_dir) = (dir.path().join("old"), dir.path().join("in"));
+        fs::create_dir_all(old_out_dir.join("2020")).unwrap();
+        fs::create_dir_all(&in_dir).unwrap();
+        let store = PhotoSyncStore::new_for_tests().unwrap();
+        let run = store.begin_run("laptop").unwrap();
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let trash = Trash::new(&old_out_dir, 30);
+
+        fs::write(old_out_dir.join("2020/a.jpg"), "rotten").unwrap();
+        fs::write(old_out_dir.join("copy.jpg"), "rotten too").unwrap();
+        fs::write(in_dir.join("a.jpg"), "photo").unwrap();
+        let good = digest(&in_dir.join("a.jpg")).unwrap();
+        for path in ["2020/a.jpg", "copy.jpg"] {
+            store
+                .mark_exists_in_old_target(run, Path::new(path), mtime, 5, &good)
+                .unwrap();
+        }
+        store
+            .mark_transferred_from_source(run, "laptop", Path::new("a.jpg"), &good, mtime, 5)
+            .unwrap();
+
+        let damaged = store
+            .files_to_scrub(SystemTime::now(), 1)
+            .unwrap()
+            .remove(0);
+        assert_eq!(damaged.path, Path::new("2020/a.jpg"));
+        // the duplicate in the old out directory is corrupt too, and the in directory is unknown.
+        assert_eq!(
+            repair(&store, &old_out_dir, &trash, None, None, "laptop", &damaged).unwrap(),
+            None
+        );
+
+        let source = repair(
+            &store,
+            &old_out_dir,
+            &trash,
+            Some(&in_dir),
+            None,
+            "laptop",
+            &damaged,
+        )
+        .unwrap();
+        assert_eq!(source.as_deref(), Some("source:laptop:a.jpg"));
+        let repaired = old_out_dir.join("2020/a.jpg");
+        assert_eq!(fs::read_to_string(&repaired).unwrap(), "photo");
+        assert_eq!(repaired.metadata().unwrap().modified().unwrap(), mtime);
+        let log = store.repairs().unwrap();
+        assert_eq!(log.len(), 1);
+        assert_eq!(
+            (&*log[0].path, log[0].digest),
+            (damaged.path.as_path(), good)
+        );
+    }
+
+    #[test]
+    fn files_are_checked_by_the_algorithm_they_were_hashed_with() {
+        let dir = tempfile::tempdir().unwrap();
+        let (old_out_dir, in_dir) = (dir.path().join("old"), dir.path().join("in"));
+        fs::create_dir_all(&old_out_dir).unwrap();
+        fs::create_dir_all(&in_dir).unwrap();
+        let store = PhotoSyncStore::new_for_tests().unwrap();
+        let run = store.begin_run("laptop").unwrap();
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let throttle = Throttle::new(None);
+
+        fs::write(old_out_dir.join("a.jpg"), "photo").unwrap();
+        fs::write(in_dir.join("a.jpg"), "photo").unwrap();
+        let good = HashAlgorithm::Blake3.digest(&in_dir.join("a.jpg")).unwrap();
+        store
+            .mark_exists_in_old_target(run, Path::new("a.jpg"), mtime, 5, &good)
+            .unwrap();
+        store
+            .mark_transferred_from_source(run, "laptop", Path::new("a.jpg"), &good, mtime, 5)
+            .unwrap();
+        let archived = store
+            .files_to_scrub(SystemTime::now(), 1)
+            .unwrap()
+            .remove(0);
+        assert!(is_intact(&old_out_dir.join("a.jpg"), &archived, &throttle).unwrap());
+
+        fs::write(old_out_dir.join("a.jpg"), "rotten").unwrap();
+        assert!(!is_intact(&old_out_dir.join("a.jpg"), &archived, &throttle).unwrap());
+        // a directory where the file was can't be read, which is reported like any damage.
+        fs::remove_file(old_out_dir.join("a.jpg")).unwrap();
+        fs::create_dir(old_out_dir.join("a.jpg")).unwrap();
+        assert!(!is_intact(&old_out_dir.join("a.jpg"), &archived, &throttle).unwrap());
+        fs::remove_dir(old_out_dir.join("a.jpg")).unwrap();
+        let copy = intact_copy(&store, &old_out_dir, Some(&in_dir), "laptop", &archived).unwrap();
+        assert_eq!(copy.map(|(path, _)| path), Some(in_dir.join("a.jpg")));
+    }
+}
@@ -0,0 +1,146 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use clap::{Args, FromArgMatches};
+use eyre::{Result, ensure};
+use walkdir::WalkDir;
+
+use crate::{
+    SelfTestArgs, SyncArgs,
+    digest::{ContentHash, digest},
+    metrics::RunStats,
+    sync_with_hooks_run,
+};
+
+/// Files synced by the self-test, including duplicates within the source and of the old out
+/// directory.
+const SOURCE_FILES: &[(&str, &str)] = &[
+    ("a.jpg", "alpha"),
+    ("b.jpg", "beta"),
+    ("copies/a.jpg", "alpha"),
+    ("archived.jpg", "archived"),
+    ("empty.jpg", ""),
+    ("nested/deeper/c.jpg", "gamma"),
+];
+const OLD_OUT_FILES: &[(&str, &str)] = &[("2020/archived.jpg", "archived")];
+
+/// Syncs a scratch tree twice with a scratch database, and checks the result is what it should be.
+pub fn self_test(args: SelfTestArgs) -> Result<()> {
+    let scratch = match &args.dir {
+        Some(dir) => tempfile::tempdir_in(dir)?,
+        None => tempfile::tempdir()?,
+    };
+    let root = scratch.path();
+    println!("running self-test in {root:?}");
+    for (dir, files) in [("in", SOURCE_FILES), ("old", OLD_OUT_FILES)] {
+        for (path, contents) in files {
+            let path = root.join(dir).join(path);
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(path, contents)?;
+        }
+    }
+    for dir in ["out", "tmp"] {
+        fs::create_dir(root.join(dir))?;
+    }
+    let sync_args = scratch_sync_args(root)?;
+
+    sync_with_hooks_run(&sync_args, &RunStats::default())?;
+    let out = digests(&root.join("out"))?;
+    let old = digests(&root.join("old"))?;
+
+    for (digest, paths) in digests(&root.join("in"))? {
+        ensure!(
+            out.contains_key(&digest) || old.contains_key(&digest),
+            "source files {paths:?} were not copied"
+        );
+    }
+    check("every source file is in the out or old out directory");
+
+    ensure!(
+        out.values().all(|paths| paths.len() == 1),
+        "the out directory holds duplicates: {out:?}"
+    );
+    check("the out directory holds no duplicates");
+
+    ensure!(
+        out.keys().all(|digest| !old.contains_key(digest)),
+        "files already in the old out directory were copied again"
+    );
+    check("nothing in the old out directory was copied again");
+
+    ensure!(
+        fs::read_dir(root.join("tmp"))?.next().is_none(),
+        "the temp directory was left with files in it"
+    );
+    check("the temp directory was cleaned up");
+
+    sync_with_hooks_run(&sync_args, &RunStats::default())?;
+    ensure!(
+        digests(&root.join("out"))? == out,
+        "syncing again changed the out directory"
+    );
+    check("syncing again changes nothing");
+
+    println!("self-test passed");
+    Ok(())
+}
+
+fn check(description: &str) {
+    println!("ok: {description}");
+}
+
+/// Default sync arguments for the scratch tree, ignoring the environment, which is set up for
+/// real syncs.
+fn scratch_sync_args(root: &Path) -> Result<SyncArgs> {
+    let dir = |name: &str| root.join(name).into_os_string();
+    let matches = SyncArgs::augment_args(clap::Command::new("self-test"))
+        .mut_args(|arg| arg.env(None::<&str>))
+        .no_binary_name(true)
+        .try_get_matches_from([
+            "--in-dir".into(),
+            dir("in"),
+            "--out-dir".into(),
+            dir("out"),
+            "--old-out-dir".into(),
+            dir("old"),
+            "--temp-dir".into(),
+            dir("tmp"),
+            "--database-file".into(),
+            dir("db.sqlite"),
+            // the scratch files are far smaller than real photos.
+            "--include-small-files".into(),
+        ])?;
+    Ok(SyncArgs::from_arg_matches(&matches)?)
+}
+
+/// The files under `dir`, by their contents.
+fn digests(dir: &Path) -> Result<HashMap<ContentHash, Vec<PathBuf>>> {
+    let mut digests = HashMap::<_, Vec<_>>::new();
+    for entry in WalkDir::new(dir) {
+        let entry = entry?;
+        if entry.file_type().is_file() {
+            digests
+                .entry(digest(entry.path())?)
+                .or_default()
+                .push(entry.path().strip_prefix(dir)?.to_path_buf());
+        }
+    }
+    Ok(digests)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn self_test_passes() {
+        let dir = tempfile::tempdir().unwrap();
+        self_test(SelfTestArgs {
+            dir: Some(dir.path().to_path_buf()),
+        })
+        .unwrap();
+    }
+}
@@ -0,0 +1,384 @@
+//! Archiving to a NAS over SFTP, for `--out-url sftp://...`, through OpenSSH's `sftp` in batch
+//! mode, so the usual keys, agent and `~/.ssh/config` apply. As in the out directory, copies are
+//! uploaded under a temporary name and then put in place, so a copy which is there is whole; as
+//! that's done with a hard link, the server must support OpenSSH's `hardlink` extension.
+
+use std::{
+    io::{self, Write},
+    path::{Component, Path, PathBuf},
+    process::{Command, Output, Stdio},
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use chrono::{Datelike, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone};
+use eyre::{Result, bail, eyre};
+use tempfile::NamedTempFile;
+
+use crate::{
+    backend::TargetBackend,
+    compress::{self, Transform},
+    digest::{ContentHash, HashAlgorithm},
+};
+
+/// An `--out-url` given as `sftp://[<user>@]<host>[:<port>]/<path>`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SftpUrl {
+    pub user: Option<String>,
+    pub host: String,
+    pub port: Option<u16>,
+    /// The archive's root on the server. Paths given as `~/<path>` are relative, which `sftp`
+    /// takes as from the login directory.
+    pub path: String,
+}
+
+impl FromStr for SftpUrl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some(rest) = s.strip_prefix("sftp://") else {
+            return Err(format!("{s:?} isn't an sftp://<host>/<path> URL"));
+        };
+        let (authority, path) = rest.split_once('/').unwrap_or((rest, "~"));
+        let (user, host) = match authority.rsplit_once('@') {
+            Some((user, host)) => (Some(user.to_string()), host),
+            None => (None, authority),
+        };
+        let (host, port) = match host.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                Some(port.parse().map_err(|_| format!("{port:?} isn't a port"))?),
+            ),
+            None => (host, None),
+        };
+        if host.is_empty() {
+            return Err(format!("{s:?} names no host"));
+        }
+        let path = match path.strip_prefix('~') {
+            Some(relative) => relative.trim_matches('/').to_string(),
+            None => format!("/{}", path.trim_end_matches('/')),
+        };
+        Ok(Self {
+            user,
+            host: host.to_string(),
+            port,
+            path,
+        })
+    }
+}
+
+pub struct SftpTarget {
+    url: SftpUrl,
+}
+
+impl SftpTarget {
+    pub fn new(url: SftpUrl) -> Self {
+        Self { url }
+    }
+
+    /// The path on the server of the file stored as `path`.
+    fn remote(&self, path: &Path) -> String {
+        let mut remote = self.url.path.clone();
+        for component in path.components() {
+            if let Component::Normal(part) = component {
+                if !remote.is_empty() && !remote.ends_with('/') {
+                    remote.push('/');
+                }
+                remote.push_str(&part.to_string_lossy());
+            }
+        }
+        remote
+    }
+
+    /// Runs `commands` in one session. A command which fails ends it, unless it's prefixed with
+    /// `-`.
+    fn batch(&self, commands: &[String]) -> io::Result<Output> {
+        let destination = match &self.url.user {
+            Some(user) => format!("{user}@{}", self.url.host),
+            None => self.url.host.clone(),
+        };
+        let mut command = Command::new("sftp");
+        command.args(["-q", "-b", "-"]);
+        if let Some(port) = self.url.port {
+            command.arg("-P").arg(port.to_string());
+        }
+        let mut child = command
+            .arg(destination)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| io::Error::other(format!("could not run sftp: {e}")))?;
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        for line in commands {
+            writeln!(stdin, "{line}")?;
+        }
+        drop(stdin);
+        child.wait_with_output()
+    }
+
+    /// Downloads the file stored as `path` into `dir`, under its own name, which says whether
+    /// it's compressed.
+    fn download(&self, path: &Path, dir: &Path) -> Result<PathBuf> {
+        let download = dir.join(path.file_name().unwrap_or("download".as_ref()));
+        let output = self.batch(&[format!(
+            "get {} {}",
+            quote(&self.remote(path))?,
+            quote(&download.to_string_lossy())?
+        )])?;
+        if !output.status.success() {
+            bail!(
+                "could not download {:?}: {}",
+                self.location(path),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(download)
+    }
+}
+
+impl TargetBackend for SftpTarget {
+    fn location(&self, path: &Path) -> PathBuf {
+        let user = (self.url.user.as_ref()).map_or(String::new(), |user| format!("{user}@"));
+        let port = (self.url.port).map_or(String::new(), |port| format!(":{port}"));
+        let remote = self.remote(path);
+        let remote = match remote.strip_prefix('/') {
+            Some(absolute) => absolute.to_string(),
+            None => format!("~/{remote}"),
+        };
+        PathBuf::from(format!("sftp://{user}{}{port}/{remote}", self.url.host))
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool> {
+        let output = self.batch(&[format!("ls -d {}", quote(&self.remote(path))?)])?;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        match output.status.code() {
+            Some(0) => Ok(true),
+            // the exit status for a failed command, rather than a failed connection.
+            Some(1) if stderr.contains("not found") => Ok(false),
+            _ => Err(eyre!(
+                "could not list {:?}: {}",
+                self.location(path),
+                stderr.trim()
+            )),
+        }
+    }
+
+    /// `sftp` lists modification times to the minute, in the server's time zone, which is taken to
+    /// be this machine's, and only to the day for files older than six months. That's as close as
+    /// it can tell without downloading the file, and it's only asked when an interrupted or racing
+    /// transfer left one behind.
+    fn modified(&self, path: &Path) -> Result<Option<SystemTime>> {
+        let output = self.batch(&[format!("ls -l {}", quote(&self.remote(path))?)])?;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        match output.status.code() {
+            Some(0) => {}
+            Some(1) if stderr.contains("not found") => return Ok(None),
+            _ => bail!(
+                "could not list {:?}: {}",
+                self.location(path),
+                stderr.trim()
+            ),
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        // batch mode echoes the command before its output.
+        let listed = (stdout.lines())
+            .rfind(|line| !line.trim().is_empty() && !line.starts_with("sftp>"))
+            .ok_or_else(|| eyre!("sftp listed nothing for {:?}", self.location(path)))?;
+        let modified = listed_time(listed, Local::now().naive_local())
+            .ok_or_else(|| eyre!("could not read the time sftp listed for {listed:?}"))?;
+        Ok(Some(modified))
+    }
+
+    fn put(&self, staged: NamedTempFile, path: &Path) -> io::Result<SystemTime> {
+        let remote = self.remote(path);
+        let suffix = format!("photo-sync-{:016x}", fastrand::u64(..));
+        let temp = match remote.rsplit_once('/') {
+            Some((dir, name)) => format!("{dir}/.{name}.{suffix}"),
+            None => format!(".{remote}.{suffix}"),
+        };
+        let quoted = |path: &str| quote(path).map_err(|e| io::Error::other(format!("{e}")));
+        let commands = upload_commands(&staged.path().to_string_lossy(), &temp, &remote)
+            .map_err(|e| io::Error::other(format!("{e}")))?;
+        let output = self.batch(&commands)?;
+        if !output.status.success() {
+            self.batch(&[format!("-rm {}", quoted(&temp)?)])?;
+            if self.exists(path).unwrap_or(false) {
+                return Err(io::ErrorKind::AlreadyExists.into());
+            }
+            return Err(io::Error::other(format!(
+                "could not upload {:?}: {}",
+                self.location(path),
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        // SFTP keeps times to the second.
+        let modified = staged.as_file().metadata()?.modified()?;
+        let seconds = modified
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        Ok(UNIX_EPOCH + Duration::from_secs(seconds))
+    }
+
+    fn digest(
+        &self,
+        path: &Path,
+        transform: Transform,
+        algorithm: HashAlgorithm,
+    ) -> Result<ContentHash> {
+        let dir = tempfile::tempdir()?;
+        let download = self.download(path, dir.path())?;
+        compress::digest_archived(&download, transform, algorithm)
+    }
+
+    fn remove(&self, path: &Path) -> Result<()> {
+        let output = self.batch(&[format!("rm {}", quote(&self.remote(path))?)])?;
+        if !output.status.success() {
+            bail!(
+                "could not remove {:?}: {}",
+                self.location(path),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// The commands which upload the file at `local` to `remote` on the server, by way of `temp`.
+fn upload_commands(local: &str, temp: &str, remote: &str) -> Result<Vec<String>> {
+    // every directory it goes in, down from the archive's root, which are left be if they
+    // already exist.
+    let mut commands = Vec::new();
+    for (idx, _) in remote.match_indices('/').filter(|(idx, _)| *idx > 0) {
+        commands.push(format!("-mkdir {}", quote(&remote[..idx])?));
+    }
+    // uploaded with its modification time, and then given the permissions of any other archived
+    // copy.
+    commands.extend([
+        format!("put -p {} {}", quote(local)?, quote(temp)?),
+        format!("chmod 644 {}", quote(temp)?),
+        // a hard link fails, rather than replacing it, if something is already there, where a
+        // rename may not: servers with the posix-rename extension replace it.
+        format!("ln {} {}", quote(temp)?, quote(remote)?),
+        // left behind rather than failing an upload which is in place.
+        format!("-rm {}", quote(temp)?),
+    ]);
+    Ok(commands)
+}
+
+/// The modification time in a line `ls -l` listed, as of `now`, e.g.
+/// `-rw-r--r--  1 photos users 1234 Jan  5 10:42 IMG_0001.JPG`, with the year for files older
+/// than six months in place of the time of day.
+fn listed_time(line: &str, now: NaiveDateTime) -> Option<SystemTime> {
+    let fields: Vec<_> = line.split_whitespace().collect();
+    let [month, day, time_or_year] = fields.get(5..8)? else {
+        return None;
+    };
+    let at = |year: i32, time: &str| {
+        let date = NaiveDate::parse_from_str(&format!("{year} {month} {day}"), "%Y %b %d").ok()?;
+        Some(date.and_time(NaiveTime::parse_from_str(time, "%H:%M").ok()?))
+    };
+    let listed = match time_or_year.parse() {
+        Ok(year) => at(year, "00:00")?,
+        // within the last six months, so in this year unless that's in the future.
+        Err(_) => match at(now.year(), time_or_year)? {
+            listed if listed > now + chrono::Duration::days(1) => at(now.year() - 1, time_or_year)?,
+            listed => listed,
+        },
+    };
+    Some(Local.from_local_datetime(&listed).earliest()?.into())
+}
+
+/// Quotes `path` as an argument of an `sftp` command.
+fn quote(path: &str) -> Result<String> {
+    if path.contains('\n') {
+        bail!("{path:?} can't be given to sftp");
+    }
+    Ok(format!(
+        "\"{}\"",
+        path.replace('\\', "\\\\").replace('"', "\\\"")
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn urls_name_the_archive_root() {
+        let target = |url: &str| SftpTarget::new(url.parse().unwrap());
+        let nas = target("sftp://photos@nas.local:2222/volume1/photos/");
+        assert_eq!(
+            nas.url,
+            SftpUrl {
+                user: Some("photos".into()),
+                host: "nas.local".into(),
+                port: Some(2222),
+                path: "/volume1/photos".into(),
+            }
+        );
+        assert_eq!(
+            nas.remote(Path::new("2019/IMG_0001.JPG")),
+            "/volume1/photos/2019/IMG_0001.JPG"
+        );
+        assert_eq!(
+            nas.location(Path::new("2019/IMG_0001.JPG")),
+            Path::new("sftp://photos@nas.local:2222/volume1/photos/2019/IMG_0001.JPG")
+        );
+        let home = target("sftp://nas/~/photos");
+        assert_eq!(home.remote(Path::new("a.jpg")), "photos/a.jpg");
+        assert_eq!(target("sftp://nas").remote(Path::new("a.jpg")), "a.jpg");
+        assert_eq!(target("sftp://nas/").remote(Path::new("a.jpg")), "/a.jpg");
+        assert!("sftp://:22/photos".parse::<SftpUrl>().is_err());
+        assert!("sftp://nas:port/photos".parse::<SftpUrl>().is_err());
+
+        assert_eq!(quote(r#"IMG "1"\a.jpg"#).unwrap(), r#""IMG \"1\"\\a.jpg""#);
+        assert!(quote("a\nb").is_err());
+    }
+
+    #[test]
+    fn uploads_never_replace_what_is_there() {
+        let commands = upload_commands("/tmp/staged", "/p/2019/.a.jpg.x", "/p/2019/a.jpg").unwrap();
+        assert_eq!(
+            commands,
+            [
+                r#"-mkdir "/p""#,
+                r#"-mkdir "/p/2019""#,
+                r#"put -p "/tmp/staged" "/p/2019/.a.jpg.x""#,
+                r#"chmod 644 "/p/2019/.a.jpg.x""#,
+                r#"ln "/p/2019/.a.jpg.x" "/p/2019/a.jpg""#,
+                r#"-rm "/p/2019/.a.jpg.x""#,
+            ]
+        );
+    }
+
+    #[test]
+    fn listed_times_are_read_to_the_minute() {
+        let now = NaiveDate::from_ymd_opt(2024, 3, 1)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap();
+        let local = |y, m, d, h, min| {
+            let listed = NaiveDate::from_ymd_opt(y, m, d)
+                .unwrap()
+                .and_hms_opt(h, min, 0)
+                .unwrap();
+            SystemTime::from(Local.from_local_datetime(&listed).earliest().unwrap())
+        };
+        let line = |when: &str| format!("-rw-r--r--    1 photos users  1234 {when} IMG 0001.JPG");
+        assert_eq!(
+            listed_time(&line("Feb  5 10:42"), now),
+            Some(local(2024, 2, 5, 10, 42))
+        );
+        // later in the year than now, so last year.
+        assert_eq!(
+            listed_time(&line("Dec 24 18:00"), now),
+            Some(local(2023, 12, 24, 18, 0))
+        );
+        assert_eq!(
+            listed_time(&line("Jun 30  2019"), now),
+            Some(local(2019, 6, 30, 0, 0))
+        );
+        assert_eq!(listed_time("sftp> ls -l a.jpg", now), None);
+    }
+}
@@ -0,0 +1,147 @@
+//! Sidecars: the `.AAE` files iCloud exports with the edits made to a photo, and the `.XMP` files
+//! editors such as Lightroom and darktable keep a photo's metadata in. Each is transferred along
+//! with the file it belongs to, into the same directory, rather than as a file of its own.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::metadata::MediaType;
+
+const SIDECAR_EXTENSIONS: [&str; 2] = ["aae", "xmp"];
+
+pub fn is_sidecar(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| SIDECAR_EXTENSIONS.contains(&e.to_lowercase().as_str()))
+}
+
+/// The file in `in_dir` the sidecar `path` belongs to, if it is one: the file it's named after,
+/// e.g. `IMG_1234.HEIC` for `IMG_1234.HEIC.xmp`, or else one with the same stem, e.g. for
+/// `IMG_1234.AAE`, preferring photos to videos, as a Live Photo's edits are its still's.
+pub fn parent_of(in_dir: &Path, path: &Path) -> Option<PathBuf> {
+    if !is_sidecar(path) {
+        return None;
+    }
+    let stem = path.file_stem()?;
+    let named = path.with_file_name(stem);
+    if named.extension().is_some() && !is_sidecar(&named) && in_dir.join(&named).is_file() {
+        return Some(named);
+    }
+    let mut candidates: Vec<_> = fs::read_dir(in_dir.join(path.parent()?))
+        .ok()?
+        .filter_map(|entry| Some(path.with_file_name(entry.ok()?.file_name())))
+        .filter(|candidate| {
+            candidate.file_stem() == Some(stem)
+                && !is_sidecar(candidate)
+                && in_dir.join(candidate).is_file()
+        })
+        .collect();
+    candidates.sort_by_key(|candidate| {
+        let media_type = MediaType::of(candidate);
+        (
+            media_type != MediaType::Photo,
+            media_type != MediaType::Video,
+            candidate.clone(),
+        )
+    });
+    candidates.into_iter().next()
+}
+
+/// The sidecars in `in_dir` which belong to the file `path`.
+pub fn sidecars_of(in_dir: &Path, path: &Path) -> Vec<PathBuf> {
+    let Some(name) = path.file_name() else {
+        return Vec::new();
+    };
+    if is_sidecar(path) {
+        return Vec::new();
+    }
+    let mut sidecars = Vec::new();
+    for extension in SIDECAR_EXTENSIONS {
+        let named_after = |extension: &str| {
+            let mut named_after = name.to_owned();
+            named_after.push(format!(".{extension}"));
+            path.with_file_name(named_after)
+        };
+        // either case, but only once, as both are found on a case-insensitive filesystem.
+        let cases = [extension.to_uppercase(), extension.to_string()];
+        let forms = [
+            cases.clone().map(|e| path.with_extension(e)),
+            cases.map(|e| named_after(&e)),
+        ];
+        for form in forms {
+            if let Some(sidecar) = form.into_iter().find(|c| in_dir.join(c).is_file())
+                && parent_of(in_dir, &sidecar).as_deref() == Some(path)
+            {
+                sidecars.push(sidecar);
+            }
+        }
+    }
+    sidecars
+}
+
+/// Where the sidecar `sidecar` of `parent` goes, now that `parent` went to `destination`: beside
+/// it, and renamed as it was.
+pub fn placed_beside(sidecar: &Path, parent: &Path, destination: &Path) -> PathBuf {
+    let named_after = (sidecar.to_str(), parent.to_str(), destination.file_name());
+    if let (Some(sidecar), Some(parent), Some(name)) = named_after
+        && let Some(suffix) = sidecar.strip_prefix(parent)
+    {
+        let mut name = name.to_owned();
+        name.push(suffix);
+        return destination.with_file_name(name);
+    }
+    destination.with_extension(sidecar.extension().unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sidecars_go_with_their_photos() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in [
+            "IMG_1.HEIC",
+            "IMG_1.MOV",
+            "IMG_1.AAE",
+            "IMG_2.JPG",
+            "IMG_2.JPG.xmp",
+            "IMG_2.xmp",
+            "clip.MOV",
+            "clip.AAE",
+            "stray.AAE",
+        ] {
+            fs::write(dir.path().join(name), name).unwrap();
+        }
+        let parent = |path: &str| parent_of(dir.path(), Path::new(path));
+        let sidecars = |path: &str| sidecars_of(dir.path(), Path::new(path));
+
+        assert_eq!(parent("IMG_1.AAE"), Some(PathBuf::from("IMG_1.HEIC")));
+        assert_eq!(parent("IMG_2.JPG.xmp"), Some(PathBuf::from("IMG_2.JPG")));
+        assert_eq!(parent("clip.AAE"), Some(PathBuf::from("clip.MOV")));
+        assert_eq!(parent("stray.AAE"), None);
+        assert_eq!(parent("IMG_1.MOV"), None);
+        assert_eq!(sidecars("IMG_1.HEIC"), [PathBuf::from("IMG_1.AAE")]);
+        assert_eq!(sidecars("IMG_1.MOV"), Vec::<PathBuf>::new());
+        assert_eq!(
+            sidecars("IMG_2.JPG"),
+            [PathBuf::from("IMG_2.xmp"), PathBuf::from("IMG_2.JPG.xmp")]
+        );
+
+        let destination = Path::new("2024/07/IMG_2 (1).JPG");
+        assert_eq!(
+            placed_beside(
+                Path::new("IMG_2.JPG.xmp"),
+                Path::new("IMG_2.JPG"),
+                destination
+            ),
+            Path::new("2024/07/IMG_2 (1).JPG.xmp")
+        );
+        assert_eq!(
+            placed_beside(Path::new("IMG_2.xmp"), Path::new("IMG_2.JPG"), destination),
+            Path::new("2024/07/IMG_2 (1).xmp")
+        );
+    }
+}
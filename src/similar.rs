@@ -0,0 +1,99 @@
+//! Reporting archived images which look the same but whose bytes differ, e.g. a photo re-saved by
+//! an editor, re-encoded by iCloud, or the near-identical frames of a burst, which exact
+//! deduplication keeps every copy of.
+
+use std::collections::HashSet;
+
+use eyre::Result;
+
+use crate::{
+    FindSimilarArgs,
+    phash::ImageFingerprint,
+    store::PhotoSyncStore,
+    variants::{Image, archived_images, group_images},
+};
+
+pub fn find_similar(args: FindSimilarArgs) -> Result<()> {
+    let store = PhotoSyncStore::new(args.database_file)?;
+    let mut images = archived_images(&store, args.old_out_dir.as_deref(), args.out_dir.as_deref())?;
+    let groups = similar_images(&mut images, args.threshold);
+    for group in &groups {
+        let first = &group[0].fingerprint;
+        for (idx, image) in group.iter().enumerate() {
+            let ImageFingerprint { width, height, .. } = image.fingerprint;
+            let distance = match idx {
+                0 => String::new(),
+                _ => format!(
+                    ", {} bits from the first",
+                    first.distance(&image.fingerprint)
+                ),
+            };
+            println!(
+                "{}{:?} ({width}x{height}, {} bytes{distance})",
+                if idx == 0 { "" } else { "    " },
+                image.path,
+                image.size
+            );
+        }
+    }
+    println!(
+        "{} groups of similar images, {} images in all",
+        groups.len(),
+        groups.iter().map(Vec::len).sum::<usize>()
+    );
+    Ok(())
+}
+
+/// Groups images within `threshold` bits of each other, leaving out all but one copy of the same
+/// content, which exact deduplication already deals with.
+fn similar_images(images: &mut Vec<Image>, threshold: u32) -> Vec<Vec<&Image>> {
+    let mut seen = HashSet::new();
+    images.retain(|image| seen.insert(image.digest));
+    group_images(images, threshold, |_, _| true)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::digest::ContentHash;
+
+    #[test]
+    fn only_different_bytes_are_similar() {
+        let image = |path: &str, id, hash| Image {
+            path: PathBuf::from(path),
+            digest: ContentHash::new_for_tests(id),
+            size: 1000 + u64::from(id),
+            fingerprint: ImageFingerprint {
+                width: 4032,
+                height: 3024,
+                hash,
+            },
+        };
+        let mut images = vec![
+            image("IMG_0001.JPG", 1, 0xf0f0_f0f0_f0f0_f0f0),
+            image("IMG_0001 (edited).JPG", 2, 0xf0f0_f0f0_f0f0_f0f3),
+            image("copy of IMG_0001.JPG", 1, 0xf0f0_f0f0_f0f0_f0f0),
+            image("IMG_0002.JPG", 3, 0xf0f0_f0f0_f0f0_f0f0),
+            image("IMG_0003.JPG", 4, 0x0f0f_0f0f_0f0f_0f0f),
+            image("IMG_0004.JPG", 5, 0x0f0f_0f0f_0f0f_0fff),
+        ];
+        let mut paths = |threshold| -> Vec<Vec<String>> {
+            similar_images(&mut images, threshold)
+                .iter()
+                .map(|g| g.iter().map(|i| i.path.display().to_string()).collect())
+                .collect()
+        };
+        assert_eq!(
+            paths(2),
+            [vec![
+                "IMG_0002.JPG",
+                "IMG_0001 (edited).JPG",
+                "IMG_0001.JPG"
+            ]]
+        );
+        assert_eq!(paths(0), [vec!["IMG_0002.JPG", "IMG_0001.JPG"]]);
+        assert_eq!(paths(4).len(), 2);
+    }
+}
@@ -0,0 +1,86 @@
+//! Snapshotting the filesystem holding the archive after a successful run, so the archive can be
+//! recovered as it was after any sync.
+
+use std::{
+    fs,
+    path::Path,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use clap::ValueEnum;
+use eyre::{Result, bail, eyre};
+
+use crate::{hooks::run_hook, store::RunId};
+
+/// How to snapshot the archive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum SnapshotKind {
+    /// `zfs snapshot` the dataset given as the target.
+    Zfs,
+    /// Take a read-only `btrfs subvolume snapshot` of the subvolume given as the target, in its
+    /// `.snapshots` directory.
+    Btrfs,
+    /// Run the target through `sh -c`, with the snapshot's name in $PHOTO_SYNC_SNAPSHOT_NAME.
+    Command,
+}
+
+/// Snapshots `target` after `run`, returning the snapshot's name.
+pub fn take_snapshot(kind: SnapshotKind, target: &str, run: RunId) -> Result<String> {
+    let taken_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let name = format!("photo-sync-{run}-{taken_at}");
+    match kind {
+        SnapshotKind::Zfs => run_command("zfs", &["snapshot", &format!("{target}@{name}")])?,
+        SnapshotKind::Btrfs => {
+            let dir = Path::new(target).join(".snapshots");
+            fs::create_dir_all(&dir)?;
+            let snapshot = dir.join(&name);
+            run_command(
+                "btrfs",
+                &[
+                    "subvolume",
+                    "snapshot",
+                    "-r",
+                    target,
+                    &snapshot.to_string_lossy(),
+                ],
+            )?;
+        }
+        SnapshotKind::Command => run_hook(
+            "snapshot",
+            target,
+            &[
+                ("PHOTO_SYNC_SNAPSHOT_NAME", &name),
+                ("PHOTO_SYNC_RUN_ID", &run.to_string()),
+            ],
+        )?,
+    }
+    Ok(name)
+}
+
+fn run_command(program: &str, args: &[&str]) -> Result<()> {
+    let status = Command::new(program)
+        .args(args)
+        .status()
+        .map_err(|e| eyre!("`{program}` could not be started: {e}"))?;
+    if !status.success() {
+        bail!("`{program} {}` failed: {status}", args.join(" "));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshot_command_is_given_the_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let names = dir.path().join("names");
+        let command = format!(r#"echo "$PHOTO_SYNC_SNAPSHOT_NAME" >> {names:?}"#);
+        let name = take_snapshot(SnapshotKind::Command, &command, 7).unwrap();
+        assert!(name.starts_with("photo-sync-7-"));
+        assert_eq!(fs::read_to_string(&names).unwrap(), format!("{name}\n"));
+        assert!(take_snapshot(SnapshotKind::Command, "exit 1", 7).is_err());
+    }
+}
@@ -0,0 +1,189 @@
+//! Syncing several in directories in one run, e.g. iCloud export batches alongside a phone's DCIM
+//! folder, each catalogued under a namespace of its own while deduplication spans them all. An in
+//! directory may also be a macOS Photos library, whose originals are read in place, or an iCloud
+//! session directory, whose library's originals are downloaded into it.
+
+use std::{
+    collections::HashSet,
+    convert::Infallible,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use eyre::{Result, bail};
+
+use crate::{
+    icloudphotos::{self, ICloudLibrary},
+    photoslibrary::{self, PhotosLibrary},
+};
+
+/// An `--in-dir`, given as a path or as `<label>=<path>`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InDir {
+    label: Option<String>,
+    dir: PathBuf,
+}
+
+impl InDir {
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+}
+
+impl FromStr for InDir {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // paths may contain `=` themselves, so only a simple name before it is taken as a label.
+        let label = s.split_once('=').filter(|(label, _)| {
+            !label.is_empty()
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        });
+        Ok(match label {
+            Some((label, dir)) => Self {
+                label: Some(label.to_string()),
+                dir: PathBuf::from(dir),
+            },
+            None => Self {
+                label: None,
+                dir: PathBuf::from(s),
+            },
+        })
+    }
+}
+
+/// An in directory being synced, and the namespace its files are catalogued under.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Source {
+    /// The directory its files are found under, which for a Photos library or iCloud session
+    /// directory is its originals.
+    pub dir: PathBuf,
+    pub namespace: String,
+    pub kind: SourceKind,
+}
+
+/// What an in directory holds, which decides where its files are archived.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub enum SourceKind {
+    /// Files arranged as they're to be archived.
+    #[default]
+    Directory,
+    /// A Photos library, whose originals are renamed and dated from its database.
+    PhotosLibrary(PhotosLibrary),
+    /// An iCloud session directory, whose originals are downloaded from iCloud Photos.
+    ICloud(ICloudLibrary),
+}
+
+impl Source {
+    /// Where the file at `path`, relative to `dir`, is archived, before any `--rename-plugin` or
+    /// `--organize-by-date` has its say.
+    pub fn archived_as(&self, path: &Path) -> PathBuf {
+        match &self.kind {
+            SourceKind::Directory => path.to_path_buf(),
+            SourceKind::PhotosLibrary(library) => library
+                .archived_as(path)
+                .unwrap_or_else(|| path.to_path_buf()),
+            SourceKind::ICloud(library) => library
+                .archived_as(path)
+                .unwrap_or_else(|| path.to_path_buf()),
+        }
+    }
+
+    /// Whether its files are only staged there to be synced, so are deleted once archived,
+    /// whatever `--move` says. Originals downloaded from iCloud Photos are, as keeping them would
+    /// keep a second copy of the whole library.
+    pub fn is_staging(&self) -> bool {
+        matches!(self.kind, SourceKind::ICloud(_))
+    }
+
+    /// Whether [`Self::archived_as`] files its files by date, numbering those of the same name
+    /// among them, but not against files archived from elsewhere.
+    pub fn is_dated(&self) -> bool {
+        !matches!(self.kind, SourceKind::Directory)
+    }
+}
+
+/// The sources `in_dirs` give. The unlabelled one is catalogued under the machine's namespace, as
+/// when there is only one, and labelled ones under `<machine id>/<label>`.
+pub fn sources(in_dirs: &[InDir], machine_id: &str) -> Result<Vec<Source>> {
+    let mut namespaces = HashSet::new();
+    in_dirs
+        .iter()
+        .map(|in_dir| {
+            let namespace = match (&in_dir.label, machine_id) {
+                (None, _) => machine_id.to_string(),
+                (Some(label), "") => label.clone(),
+                (Some(label), machine_id) => format!("{machine_id}/{label}"),
+            };
+            if !namespaces.insert(namespace.clone()) {
+                match &in_dir.label {
+                    Some(label) => bail!("more than one in directory is labelled {label:?}"),
+                    None => bail!(
+                        "only one in directory may be unlabelled; give the others as \
+                         `<label>=<path>`"
+                    ),
+                }
+            }
+            if photoslibrary::is_photos_library(&in_dir.dir) {
+                return Ok(Source {
+                    dir: photoslibrary::originals(&in_dir.dir),
+                    namespace,
+                    kind: SourceKind::PhotosLibrary(PhotosLibrary::open(&in_dir.dir)?),
+                });
+            }
+            if icloudphotos::is_session_dir(&in_dir.dir) {
+                let library = ICloudLibrary::open(&in_dir.dir)?;
+                return Ok(Source {
+                    dir: library.originals(),
+                    namespace,
+                    kind: SourceKind::ICloud(library),
+                });
+            }
+            Ok(Source {
+                dir: in_dir.dir.clone(),
+                namespace,
+                kind: SourceKind::Directory,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labelled_dirs_get_namespaces_of_their_own() {
+        let in_dirs =
+            |dirs: &[&str]| -> Vec<InDir> { dirs.iter().map(|dir| dir.parse().unwrap()).collect() };
+        let source = |dir: &str, namespace: &str| Source {
+            dir: PathBuf::from(dir),
+            namespace: namespace.to_string(),
+            kind: SourceKind::Directory,
+        };
+
+        assert_eq!(
+            sources(
+                &in_dirs(&["/icloud", "dcim=/mnt/dcim", "/mnt/a=b"]),
+                "laptop"
+            )
+            .unwrap_err()
+            .to_string(),
+            "only one in directory may be unlabelled; give the others as `<label>=<path>`"
+        );
+        assert_eq!(
+            sources(&in_dirs(&["/icloud", "dcim=/mnt/dcim"]), "laptop").unwrap(),
+            [
+                source("/icloud", "laptop"),
+                source("/mnt/dcim", "laptop/dcim")
+            ]
+        );
+        assert_eq!(
+            sources(&in_dirs(&["dcim=/mnt/dcim"]), "").unwrap(),
+            [source("/mnt/dcim", "dcim")]
+        );
+        assert!(sources(&in_dirs(&["a=/x", "a=/y"]), "").is_err());
+    }
+}
@@ -1,50 +1,1064 @@
 use std::{
+    collections::{HashMap, HashSet},
+    ops::Deref,
     path::{Path, PathBuf},
-    sync::{Mutex, MutexGuard},
+    sync::{
+        Mutex, MutexGuard,
+        atomic::{AtomicBool, Ordering},
+    },
     time::{Duration, SystemTime},
 };
 
-use eyre::{ContextCompat, Result, eyre};
-use rusqlite::{Connection, OptionalExtension, params};
+use eyre::{OptionExt, Result, bail, ensure, eyre};
+use rusqlite::{
+    Connection, OpenFlags, OptionalExtension, params,
+    types::{FromSql, FromSqlError, FromSqlResult, Value, ValueRef},
+};
+use serde::{Deserialize, Serialize};
 
-use crate::digest::Sha256Hash;
+use crate::{
+    compress::Transform,
+    dbtrace,
+    digest::{ContentHash, HashAlgorithm},
+    failures::FailureKind,
+    fsinfo::network_filesystem,
+    lease,
+    manifest::ManifestEntry,
+    metadata::MediaMetadata,
+    partial::PartialDigest,
+    phash::ImageFingerprint,
+    tuning::StoreTuning,
+};
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum WasTransferredFromSourceResult {
     New,
     Transferred,
     NewMetadata {
         last_modified: SystemTime,
         size: u64,
-        digest: Sha256Hash,
+        digest: ContentHash,
+    },
+}
+
+/// Schema changes applied on top of the original tables, in order. The index of the last applied
+/// migration is tracked in sqlite's `user_version`, so entries must never be edited or reordered.
+const MIGRATIONS: &[&str] = &[
+    // source files are namespaced so that several machines can feed the same catalogue.
+    r#"
+    CREATE TABLE source_files_v1 (
+        namespace   TEXT    NOT NULL,
+        path        TEXT    NOT NULL,
+        mtime       INTEGER NOT NULL,
+        size        INTEGER NOT NULL,
+        digest      BLOB    NOT NULL,
+        PRIMARY KEY (namespace, path)
+    );
+    INSERT INTO source_files_v1 (namespace, path, mtime, size, digest)
+        SELECT '', path, mtime, size, digest FROM source_files;
+    DROP TABLE source_files;
+    ALTER TABLE source_files_v1 RENAME TO source_files;
+
+    CREATE TABLE leases (
+        name        TEXT    NOT NULL,
+        holder      TEXT    NOT NULL,
+        expires_at  INTEGER NOT NULL,
+        PRIMARY KEY (name)
+    );
+    "#,
+    // every row is attributed to the run which wrote it, so a failed run's writes can be found.
+    r#"
+    CREATE TABLE runs (
+        id          INTEGER PRIMARY KEY AUTOINCREMENT,
+        namespace   TEXT    NOT NULL,
+        started_at  INTEGER NOT NULL,
+        finished_at INTEGER,
+        status      TEXT    NOT NULL
+    );
+    ALTER TABLE old_target_files ADD COLUMN run_id INTEGER REFERENCES runs (id);
+    ALTER TABLE source_files ADD COLUMN run_id INTEGER REFERENCES runs (id);
+    "#,
+    // named sets of sync arguments, so one catalogue can serve several recurring jobs.
+    r#"
+    CREATE TABLE profiles (
+        name        TEXT    NOT NULL,
+        args        TEXT    NOT NULL,
+        updated_at  INTEGER NOT NULL,
+        PRIMARY KEY (name)
+    );
+    "#,
+    // when each archived file was last rehashed and found intact, so scrubs can go oldest first.
+    r#"
+    ALTER TABLE old_target_files ADD COLUMN last_verified INTEGER;
+    CREATE INDEX old_target_files_last_verified ON old_target_files (last_verified);
+    "#,
+    // an audit log of damaged archive files restored from other copies of their content.
+    r#"
+    CREATE TABLE repairs (
+        id          INTEGER PRIMARY KEY AUTOINCREMENT,
+        path        TEXT    NOT NULL,
+        digest      BLOB    NOT NULL,
+        source      TEXT    NOT NULL,
+        repaired_at INTEGER NOT NULL
+    );
+    "#,
+    // XOR parity over sets of archived files, each recovering any one member of its set.
+    r#"
+    CREATE TABLE parity_sets (
+        id          INTEGER PRIMARY KEY AUTOINCREMENT,
+        digest      BLOB    NOT NULL,
+        created_at  INTEGER NOT NULL
+    );
+    CREATE TABLE parity_members (
+        set_id      INTEGER NOT NULL REFERENCES parity_sets (id),
+        path        TEXT    NOT NULL,
+        size        INTEGER NOT NULL,
+        digest      BLOB    NOT NULL,
+        PRIMARY KEY (set_id, path)
+    );
+    CREATE INDEX parity_members_path ON parity_members (path);
+    "#,
+    // the files in the out directory, so files appearing in or vanishing from it can be noticed.
+    r#"
+    CREATE TABLE target_files (
+        path        TEXT    NOT NULL,
+        mtime       INTEGER NOT NULL,
+        size        INTEGER NOT NULL,
+        digest      BLOB    NOT NULL,
+        run_id      INTEGER REFERENCES runs (id),
+        PRIMARY KEY (path)
+    );
+    "#,
+    // the filesystem snapshot taken after each run, if any.
+    r#"
+    ALTER TABLE runs ADD COLUMN snapshot TEXT;
+    "#,
+    // bundles of out directory files packed for cold storage, and which files each holds.
+    r#"
+    CREATE TABLE bundles (
+        id          INTEGER PRIMARY KEY,
+        name        TEXT    NOT NULL UNIQUE,
+        digest      BLOB    NOT NULL,
+        size        INTEGER NOT NULL,
+        created_at  INTEGER NOT NULL
+    );
+    CREATE TABLE bundle_members (
+        bundle_id   INTEGER NOT NULL REFERENCES bundles (id),
+        path        TEXT    NOT NULL,
+        digest      BLOB    NOT NULL,
+        PRIMARY KEY (bundle_id, path)
+    );
+    CREATE INDEX bundle_members_digest ON bundle_members (digest);
+    CREATE INDEX bundle_members_path ON bundle_members (path);
+    "#,
+    // files moved or renamed in the source, whose rows were carried over to their new paths.
+    r#"
+    CREATE TABLE source_renames (
+        namespace   TEXT    NOT NULL,
+        from_path   TEXT    NOT NULL,
+        to_path     TEXT    NOT NULL,
+        run_id      INTEGER REFERENCES runs (id)
+    );
+    CREATE INDEX source_files_metadata ON source_files (namespace, size, mtime);
+    "#,
+    // for finding where files reorganised within the old out directory came from.
+    r#"
+    CREATE INDEX old_target_files_metadata ON old_target_files (size, mtime);
+    CREATE INDEX old_target_files_digest ON old_target_files (digest);
+    "#,
+    // the dimensions and perceptual hash of image content, so images are only decoded once.
+    r#"
+    CREATE TABLE image_fingerprints (
+        digest      BLOB    NOT NULL PRIMARY KEY,
+        width       INTEGER NOT NULL,
+        height      INTEGER NOT NULL,
+        phash       INTEGER NOT NULL
+    );
+    "#,
+    // files in the old out directory catalogued on their metadata alone, to be hashed later.
+    r#"
+    CREATE TABLE pending_digests (
+        path        TEXT    NOT NULL PRIMARY KEY,
+        mtime       INTEGER NOT NULL,
+        size        INTEGER NOT NULL,
+        run_id      INTEGER NOT NULL
+    );
+    "#,
+    // every content a changed source file has had, when `--keep-versions` archives each one.
+    r#"
+    CREATE TABLE source_versions (
+        namespace   TEXT    NOT NULL,
+        path        TEXT    NOT NULL,
+        version     INTEGER NOT NULL,
+        digest      BLOB    NOT NULL,
+        target_path TEXT,
+        archived_at INTEGER,
+        run_id      INTEGER NOT NULL,
+        PRIMARY KEY (namespace, path, version)
+    );
+    "#,
+    // where in the out directory each source file was written, when it isn't simply its path.
+    r#"
+    ALTER TABLE source_files ADD COLUMN target_path TEXT;
+    "#,
+    // transfers under way, so the next run can clean up after one which was interrupted.
+    r#"
+    CREATE TABLE pending_transfers (
+        namespace   TEXT    NOT NULL,
+        path        TEXT    NOT NULL,
+        work_dir    TEXT    NOT NULL,
+        target_path TEXT,
+        digest      BLOB,
+        mtime       INTEGER,
+        size        INTEGER,
+        run_id      INTEGER NOT NULL,
+        PRIMARY KEY (namespace, path)
+    );
+    "#,
+    // the source files of each Live Photo, so that verify can check both halves are archived.
+    r#"
+    CREATE TABLE live_photos (
+        namespace   TEXT    NOT NULL,
+        still_path  TEXT    NOT NULL,
+        video_path  TEXT    NOT NULL,
+        run_id      INTEGER NOT NULL,
+        PRIMARY KEY (namespace, still_path)
+    );
+    "#,
+    // paths as their raw bytes rather than text, so that ones which aren't UTF-8 can be catalogued.
+    r#"
+    UPDATE old_target_files SET path = CAST(path AS BLOB);
+    UPDATE target_files SET path = CAST(path AS BLOB);
+    UPDATE source_files SET path = CAST(path AS BLOB), target_path = CAST(target_path AS BLOB);
+    UPDATE source_versions
+        SET path = CAST(path AS BLOB), target_path = CAST(target_path AS BLOB);
+    UPDATE source_renames
+        SET from_path = CAST(from_path AS BLOB), to_path = CAST(to_path AS BLOB);
+    UPDATE pending_digests SET path = CAST(path AS BLOB);
+    UPDATE parity_members SET path = CAST(path AS BLOB);
+    UPDATE bundle_members SET path = CAST(path AS BLOB);
+    UPDATE repairs SET path = CAST(path AS BLOB);
+    UPDATE pending_transfers SET path = CAST(path AS BLOB), work_dir = CAST(work_dir AS BLOB),
+        target_path = CAST(target_path AS BLOB);
+    UPDATE live_photos
+        SET still_path = CAST(still_path AS BLOB), video_path = CAST(video_path AS BLOB);
+    "#,
+    // source files `--move` deleted once their content was archived.
+    r#"
+    CREATE TABLE deleted_sources (
+        namespace   TEXT    NOT NULL,
+        path        BLOB    NOT NULL,
+        digest      BLOB    NOT NULL,
+        size        INTEGER NOT NULL,
+        deleted_at  INTEGER NOT NULL,
+        run_id      INTEGER NOT NULL
+    );
+    "#,
+    // out directory files transcoded from HEIC, whose target_files digest is the original's.
+    r#"
+    CREATE TABLE transcoded_files (
+        path            BLOB    NOT NULL,
+        output_digest   BLOB    NOT NULL,
+        run_id          INTEGER,
+        PRIMARY KEY (path)
+    );
+    "#,
+    // what each archived photo or video is, from its EXIF data, by content.
+    r#"
+    CREATE TABLE media_metadata (
+        digest          BLOB    NOT NULL PRIMARY KEY,
+        media_type      TEXT    NOT NULL,
+        taken_at        TEXT,
+        camera_make     TEXT,
+        camera_model    TEXT,
+        has_gps         INTEGER NOT NULL,
+        width           INTEGER,
+        height          INTEGER
+    );
+    CREATE INDEX media_metadata_taken_at ON media_metadata (taken_at);
+    "#,
+    // the quick fingerprints `--quick-index` catalogues files by until they're hashed in full.
+    r#"
+    ALTER TABLE pending_digests ADD COLUMN partial_digest BLOB;
+    CREATE INDEX pending_digests_partial_digest ON pending_digests (size, partial_digest);
+    "#,
+    // the source files each AAE or XMP sidecar was transferred along with.
+    r#"
+    CREATE TABLE sidecars (
+        namespace       TEXT    NOT NULL,
+        sidecar_path    BLOB    NOT NULL,
+        parent_path     BLOB    NOT NULL,
+        run_id          INTEGER NOT NULL,
+        PRIMARY KEY (namespace, sidecar_path)
+    );
+    CREATE INDEX sidecars_parent_path ON sidecars (namespace, parent_path);
+    "#,
+    // source files which couldn't be transferred, retried first by the next run.
+    r#"
+    CREATE TABLE failed_transfers (
+        namespace   TEXT    NOT NULL,
+        path        BLOB    NOT NULL,
+        reason      TEXT    NOT NULL,
+        attempts    INTEGER NOT NULL,
+        failed_at   INTEGER NOT NULL,
+        run_id      INTEGER NOT NULL,
+        PRIMARY KEY (namespace, path)
+    );
+    "#,
+    // the command line of each run, and what it did.
+    r#"
+    ALTER TABLE runs ADD COLUMN args TEXT;
+    ALTER TABLE runs ADD COLUMN files_detected INTEGER;
+    ALTER TABLE runs ADD COLUMN files_transferred INTEGER;
+    ALTER TABLE runs ADD COLUMN bytes_transferred INTEGER;
+    ALTER TABLE runs ADD COLUMN files_deduplicated INTEGER;
+    ALTER TABLE runs ADD COLUMN files_failed INTEGER;
+    "#,
+    // new files whose place in the out directory was taken by a file not catalogued, and what was
+    // done about it.
+    r#"
+    CREATE TABLE collisions (
+        namespace   TEXT    NOT NULL,
+        source_path BLOB    NOT NULL,
+        target_path BLOB    NOT NULL,
+        resolution  TEXT    NOT NULL,
+        run_id      INTEGER NOT NULL
+    );
+    "#,
+    // for `--incremental-index`, what each directory of the old out directory held when its
+    // files were last all indexed.
+    r#"
+    CREATE TABLE dir_signatures (
+        path    BLOB    PRIMARY KEY,
+        mtime   INTEGER NOT NULL,
+        entries INTEGER NOT NULL
+    );
+    "#,
+    // what `--interactive` review decided to do with source files which failed to transfer or
+    // collided.
+    r#"
+    CREATE TABLE review_decisions (
+        namespace   TEXT    NOT NULL,
+        path        BLOB    NOT NULL,
+        decision    TEXT    NOT NULL,
+        decided_at  INTEGER NOT NULL,
+        PRIMARY KEY (namespace, path)
+    );
+    "#,
+    // source files `ignore add` said are never to be transferred, as paths relative to the in
+    // directory or, when `glob`, globs.
+    r#"
+    CREATE TABLE ignored_paths (
+        path        BLOB    PRIMARY KEY,
+        glob        INTEGER NOT NULL,
+        added_at    INTEGER NOT NULL
+    );
+    "#,
+    // source files `--propagate-deletions` found deleted from the in directory, and where their
+    // copy in the out directory was moved aside to.
+    r#"
+    CREATE TABLE propagated_deletions (
+        namespace   TEXT    NOT NULL,
+        path        BLOB    NOT NULL,
+        digest      BLOB    NOT NULL,
+        target_path BLOB    NOT NULL,
+        trashed_to  BLOB,
+        deleted_at  INTEGER NOT NULL
+    );
+    "#,
+    // the iCloud Photos assets downloaded into an iCloud session directory, by their ID, so each
+    // is only downloaded once.
+    r#"
+    CREATE TABLE icloud_assets (
+        namespace     TEXT    NOT NULL,
+        asset_id      TEXT    NOT NULL,
+        path          BLOB    NOT NULL,
+        digest        BLOB    NOT NULL,
+        downloaded_at INTEGER NOT NULL,
+        PRIMARY KEY (namespace, asset_id)
+    );
+    "#,
+    // what kind of photo each is, and the source files `--skip-screenshots` and
+    // `--collapse-bursts` left out for being one, so they're only read once.
+    r#"
+    ALTER TABLE media_metadata ADD COLUMN screenshot INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE media_metadata ADD COLUMN burst_id TEXT;
+    CREATE INDEX media_metadata_burst_id ON media_metadata (burst_id);
+    CREATE TABLE classified_out (
+        namespace   TEXT    NOT NULL,
+        path        BLOB    NOT NULL,
+        mtime       INTEGER NOT NULL,
+        size        INTEGER NOT NULL,
+        class       TEXT    NOT NULL,
+        PRIMARY KEY (namespace, path)
+    );
+    "#,
+    // every digest in the archive, with how many rows of old_target_files, source_files and
+    // target_files have it, kept up to date by the triggers `ensure_schema` creates, so looking one
+    // up doesn't scan all three.
+    r#"
+    CREATE TABLE digests (
+        digest  BLOB    NOT NULL,
+        refs    INTEGER NOT NULL,
+        PRIMARY KEY (digest)
+    ) WITHOUT ROWID;
+    INSERT INTO digests (digest, refs)
+        SELECT digest, COUNT(*) FROM (
+                  SELECT digest FROM old_target_files
+        UNION ALL SELECT digest FROM source_files
+        UNION ALL SELECT digest FROM target_files
+        ) GROUP BY digest;
+    CREATE INDEX source_files_digest ON source_files (digest);
+    CREATE INDEX target_files_digest ON target_files (digest);
+    "#,
+    // content deliberately removed from the out directory, which is never transferred again.
+    r#"
+    CREATE TABLE tombstones (
+        digest      BLOB    NOT NULL,
+        path        BLOB    NOT NULL,
+        added_at    INTEGER NOT NULL,
+        PRIMARY KEY (digest)
+    );
+    "#,
+    // how many files each run failed to transfer, for each reason.
+    r#"
+    CREATE TABLE run_failures (
+        run_id      INTEGER NOT NULL REFERENCES runs (id),
+        kind        TEXT    NOT NULL,
+        files       INTEGER NOT NULL,
+        PRIMARY KEY (run_id, kind)
+    );
+    "#,
+    // out directory files encrypted with age, whose target_files digest is the plaintext's.
+    r#"
+    CREATE TABLE encrypted_files (
+        path            BLOB    NOT NULL,
+        stored_digest   BLOB    NOT NULL,
+        run_id          INTEGER,
+        PRIMARY KEY (path)
+    );
+    "#,
+    // how out directory files other than those stored as they are were stored, which was once
+    // told by their names, so those written until now are taken to be as their names say.
+    r#"
+    CREATE TABLE transformed_files (
+        path        BLOB    NOT NULL,
+        transform   TEXT    NOT NULL,
+        run_id      INTEGER,
+        PRIMARY KEY (path)
+    );
+    INSERT INTO transformed_files (path, transform, run_id)
+        SELECT path, 'compressed', run_id FROM target_files
+        WHERE CAST(path AS TEXT) GLOB '*.zst' OR CAST(path AS TEXT) GLOB '*.zst.age';
+    INSERT INTO transformed_files (path, transform, run_id)
+        SELECT path, 'chunked', run_id FROM target_files WHERE CAST(path AS TEXT) GLOB '*.chunks';
+    ALTER TABLE pending_transfers ADD COLUMN transform TEXT;
+    "#,
+    // the total size of what each signed directory held, which a file rewritten in place with a
+    // different size changes. Signatures taken without it are taken again.
+    r#"
+    DELETE FROM dir_signatures;
+    ALTER TABLE dir_signatures ADD COLUMN size INTEGER NOT NULL DEFAULT 0;
+    "#,
+];
+
+/// The tables whose digests are counted in `digests`.
+const DIGEST_TABLES: [&str; 3] = ["old_target_files", "source_files", "target_files"];
+
+/// Tables derived from the others, which exports leave out and imports rebuild.
+const DERIVED_TABLES: [&str; 1] = ["digests"];
+
+/// The tables whose rows are attributed to the run which wrote them, which rolling a run back
+/// undoes its writes to.
+const RUN_TABLES: [&str; 6] = [
+    "old_target_files",
+    "pending_digests",
+    "source_versions",
+    "source_files",
+    "target_files",
+    "transformed_files",
+];
+
+/// What the tables keeping the rows of each of [`RUN_TABLES`] a run replaced, until it finishes,
+/// are named with, before the table's own name. Exports leave them out.
+const REPLACED_PREFIX: &str = "replaced_";
+
+/// How long concurrent writers (e.g. another machine sharing the catalogue) are waited on before
+/// a statement fails with `SQLITE_BUSY`.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How many connections for reads are kept open between them, roughly one per worker thread.
+const MAX_IDLE_READERS: usize = 16;
+
+pub type RunId = i64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RunStatus {
+    Running,
+    Succeeded,
+    /// The run failed, and the rows it wrote were left in place.
+    Aborted,
+    /// The run failed, and the rows it wrote were deleted.
+    RolledBack,
+}
+
+impl RunStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            RunStatus::Running => "running",
+            RunStatus::Succeeded => "succeeded",
+            RunStatus::Aborted => "aborted",
+            RunStatus::RolledBack => "rolled back",
+        }
+    }
+}
+
+/// What's to be done with a source file, as decided in `--interactive` review.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReviewDecision {
+    /// Transfer it next run, under a free name if its place in the out directory is taken.
+    Retransfer,
+    /// Never transfer it.
+    Ignore,
+    /// Never transfer it, as a copy was set aside to look into.
+    Quarantine,
+}
+
+impl ReviewDecision {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ReviewDecision::Retransfer => "retransfer",
+            ReviewDecision::Ignore => "ignore",
+            ReviewDecision::Quarantine => "quarantine",
+        }
+    }
+
+    fn parse(decision: &str) -> Result<Self> {
+        Ok(match decision {
+            "retransfer" => ReviewDecision::Retransfer,
+            "ignore" => ReviewDecision::Ignore,
+            "quarantine" => ReviewDecision::Quarantine,
+            other => bail!("unknown review decision {other:?}"),
+        })
+    }
+}
+
+/// A source file which failed to transfer, and is retried first until it's transferred.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FailedTransfer {
+    pub path: PathBuf,
+    pub reason: String,
+    pub attempts: u32,
+    pub failed_at: SystemTime,
+}
+
+/// What `doctor` reports about a database.
+pub struct DatabaseHealth {
+    pub journal_mode: String,
+    pub schema_version: usize,
+    pub quick_check: String,
+}
+
+/// The schema version databases are migrated to.
+pub const LATEST_SCHEMA_VERSION: usize = MIGRATIONS.len();
+
+/// Inspects the database at `path` without creating or migrating it.
+pub fn inspect_database(path: &Path) -> Result<DatabaseHealth> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    Ok(DatabaseHealth {
+        journal_mode: conn.query_row("PRAGMA journal_mode", [], |r| r.get(0))?,
+        schema_version: conn.query_row("PRAGMA user_version", [], |r| r.get(0))?,
+        quick_check: conn.query_row("PRAGMA quick_check", [], |r| r.get(0))?,
+    })
+}
+
+/// Writes a consistent copy of the database at `path` to `to`, which mustn't exist.
+pub fn backup_database(path: &Path, to: &Path) -> Result<()> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    conn.execute(
+        "VACUUM INTO ?1",
+        [to.to_str().ok_or_eyre("backup path is not UTF-8")?],
+    )?;
+    Ok(())
+}
+
+/// A file in the old out or out directory, and the digest it had when catalogued.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CataloguedFile {
+    pub path: PathBuf,
+    pub mtime: SystemTime,
+    pub size: u64,
+    pub digest: ContentHash,
+}
+
+/// Somewhere else the catalogue says a file's content can be found.
+#[derive(Debug, PartialEq, Eq)]
+pub enum FileCopy {
+    /// A path in the old out directory.
+    OldTarget(PathBuf),
+    /// A path in the out directory.
+    Target(PathBuf),
+    /// A path in the in directory of the namespace asked about.
+    Source(PathBuf),
+}
+
+/// A repaired archive file, from the audit log.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Repair {
+    pub path: PathBuf,
+    pub digest: ContentHash,
+    /// Where the content was restored from, e.g. `old:2020/a.jpg` or `source:laptop:a.jpg`.
+    pub source: String,
+    pub repaired_at: SystemTime,
+}
+
+/// A file in the old out directory covered (or to be covered) by a parity set.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParityMember {
+    pub path: PathBuf,
+    pub size: u64,
+    pub digest: ContentHash,
+}
+
+/// Parity over a set of files, stored in a file named after its digest.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParitySet {
+    pub digest: ContentHash,
+    pub members: Vec<ParityMember>,
+}
+
+/// A file in the out directory, as packed into a bundle.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BundleMember {
+    pub path: PathBuf,
+    pub size: u64,
+    pub digest: ContentHash,
+}
+
+/// A tar of out directory files for cold storage, e.g. one BD-R disc's worth.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Bundle {
+    pub name: String,
+    pub digest: ContentHash,
+    pub size: u64,
+    pub members: Vec<BundleMember>,
+}
+
+/// Which directory of the archive a file is in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Archive {
+    OldOut,
+    Out,
+}
+
+/// Files in the archive catalogued with the same content.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Duplicates {
+    pub digest: ContentHash,
+    pub size: u64,
+    pub files: Vec<(Archive, PathBuf)>,
+}
+
+/// What `db check` found wrong with the catalogue, each a table and how many of its rows are.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct HealthCheck {
+    /// What sqlite's own integrity check reported, if anything.
+    pub integrity: Vec<String>,
+    /// Rows cataloguing a path another row of the table catalogues too, stored differently.
+    pub duplicate_paths: Vec<(&'static str, u64)>,
+    /// Rows whose digest isn't one any algorithm gives.
+    pub malformed_digests: Vec<(&'static str, u64)>,
+    /// Rows modified before 1970, or over a year from now.
+    pub implausible_mtimes: Vec<(&'static str, u64)>,
+    /// Source files whose content isn't catalogued in either out directory.
+    pub unresolved_sources: u64,
+    /// Digests whose count in `digests` differs from the rows with them, which `db repair` fixes.
+    pub stale_digests: u64,
+}
+
+impl HealthCheck {
+    pub fn is_healthy(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// What `db stats` reports of the catalogue.
+#[derive(Debug, PartialEq, Eq)]
+pub struct CatalogueStats {
+    /// How many files each table catalogues, and their total size.
+    pub tables: Vec<(&'static str, u64, u64)>,
+    /// How many different contents are catalogued.
+    pub unique_digests: u64,
+    /// Files in the in and old out directories whose content another file had.
+    pub duplicates: u64,
+    /// The largest files in the archive, largest first.
+    pub largest: Vec<(Archive, PathBuf, u64)>,
+    /// The latest run of each namespace.
+    pub last_runs: Vec<LastRun>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct LastRun {
+    pub namespace: String,
+    pub id: RunId,
+    pub status: String,
+    pub started_at: SystemTime,
+    pub finished_at: Option<SystemTime>,
+}
+
+/// What a run did, kept with it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RunCounts {
+    pub files_detected: u64,
+    pub files_transferred: u64,
+    pub bytes_transferred: u64,
+    pub files_deduplicated: u64,
+    pub files_failed: u64,
+}
+
+impl RunCounts {
+    /// What was done after `earlier`, counts being kept over a whole `--watch`.
+    pub fn since(self, earlier: Self) -> Self {
+        Self {
+            files_detected: self.files_detected - earlier.files_detected,
+            files_transferred: self.files_transferred - earlier.files_transferred,
+            bytes_transferred: self.bytes_transferred - earlier.bytes_transferred,
+            files_deduplicated: self.files_deduplicated - earlier.files_deduplicated,
+            files_failed: self.files_failed - earlier.files_failed,
+        }
+    }
+}
+
+/// A run, as `history` lists it.
+#[derive(Debug, PartialEq, Eq)]
+pub struct RunRecord {
+    pub id: RunId,
+    pub namespace: String,
+    pub status: String,
+    pub started_at: SystemTime,
+    pub finished_at: Option<SystemTime>,
+    /// The command line it was started with, unless it was started by another command.
+    pub args: Option<String>,
+    /// Unless it didn't finish, or wasn't a sync.
+    pub counts: Option<RunCounts>,
+}
+
+/// Where a file added by a run was catalogued.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AddedFile {
+    /// Transferred from the in directory of this namespace.
+    Source(String, PathBuf),
+    /// Indexed in the old out directory.
+    OldOut(PathBuf),
+    /// Written to the out directory.
+    Out(PathBuf),
+}
+
+/// A file transferred from an in directory, as recorded with others in a batch.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransferredSource {
+    pub path: PathBuf,
+    pub digest: ContentHash,
+    pub last_modified: SystemTime,
+    pub size: u64,
+    /// Where its content was written in the out directory, if it wasn't already archived.
+    pub target_path: Option<PathBuf>,
+}
+
+/// A file transferred from an in directory, for reporting on deduplication.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SourceFile {
+    pub namespace: String,
+    pub path: PathBuf,
+    pub size: u64,
+    pub digest: ContentHash,
+    /// Whether its content was already in the old out directory.
+    pub in_old_target: bool,
+}
+
+/// How a source file differs between two states of an in directory, as `diff` lists it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Change {
+    Added,
+    Removed,
+    /// Its content changed.
+    Changed,
+    /// Only its modification time or size changed.
+    Touched,
+}
+
+/// A source file which differs between two states of an in directory.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct SourceChange {
+    pub change: Change,
+    pub namespace: String,
+    pub path: PathBuf,
+    pub size: u64,
+    pub digest: ContentHash,
+    /// What its content was before, if it changed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub previous_digest: Option<ContentHash>,
+}
+
+/// One content of a source file which changed after it was first transferred. Version 1 is the
+/// content first transferred, archived wherever that put it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceVersion {
+    pub path: PathBuf,
+    pub version: u32,
+    pub digest: ContentHash,
+    /// Where in the out directory this version was archived, unless its content already was.
+    pub target_path: Option<PathBuf>,
+    pub archived_at: Option<SystemTime>,
+}
+
+/// What a directory held, going by its modification time, which changes as files are added to it,
+/// removed or renamed, and the number and total size of the entries walked in it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DirSignature {
+    pub mtime: SystemTime,
+    pub entries: u64,
+    pub size: u64,
+}
+
+/// A source file, or with `glob`, a glob of them, never to be transferred.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IgnoredPath {
+    pub path: PathBuf,
+    pub glob: bool,
+    pub added_at: SystemTime,
+}
+
+/// Content removed from the out directory on purpose, which is never transferred again.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tombstone {
+    pub digest: ContentHash,
+    /// Where the content was when it was tombstoned.
+    pub path: PathBuf,
+    pub added_at: SystemTime,
+}
+
+/// A source file left out for its kind, e.g. as a screenshot, while it has this metadata.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClassifiedOut {
+    pub path: PathBuf,
+    pub last_modified: SystemTime,
+    pub size: u64,
+    /// Why, e.g. `screenshot` or `burst`.
+    pub class: String,
+}
+
+impl ClassifiedOut {
+    /// Whether the file still has the metadata it was left out with, to the second it's stored to.
+    pub fn is_current(&self, last_modified: SystemTime, size: u64) -> bool {
+        self.size == size
+            && system_time_as_i64(self.last_modified).ok() == system_time_as_i64(last_modified).ok()
+    }
+}
+
+/// A transfer under way, journalled so that if the process is killed partway through, the next
+/// run can remove its temporary files and catalogue what it finished writing.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PendingTransfer {
+    pub path: PathBuf,
+    /// The directory in the temp directory holding the transfer's temporary files.
+    pub work_dir: PathBuf,
+    /// What's being written to the out directory, once it's been copied and hashed.
+    pub writing: Option<PendingWrite>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PendingWrite {
+    pub target_path: PathBuf,
+    pub digest: ContentHash,
+    /// The source file's metadata when it was copied.
+    pub last_modified: SystemTime,
+    pub size: u64,
+    /// How the copy is stored.
+    pub transform: Transform,
+}
+
+/// Connections to a database in WAL mode, opened as reads need them and kept for the next.
+struct Readers {
+    path: PathBuf,
+    idle: Mutex<Vec<Connection>>,
+    /// Applied to connections as they're opened, as it is to the writer.
+    tuning: Mutex<StoreTuning>,
+    traced: AtomicBool,
+}
+
+impl Readers {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            idle: Mutex::new(Vec::new()),
+            tuning: Mutex::new(StoreTuning::default()),
+            traced: AtomicBool::new(false),
+        }
+    }
+
+    fn take(&self) -> Result<Connection> {
+        if let Some(conn) = self.idle.lock().unwrap().pop() {
+            return Ok(conn);
+        }
+        let conn = Connection::open(&self.path)?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        conn.pragma_update(None, "query_only", true)?;
+        self.tuning.lock().unwrap().apply(&conn)?;
+        if self.traced.load(Ordering::SeqCst) {
+            dbtrace::trace(&conn, true);
+        }
+        Ok(conn)
+    }
+
+    fn give_back(&self, conn: Connection) {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < MAX_IDLE_READERS {
+            idle.push(conn);
+        }
+    }
+}
+
+/// A connection reads are made through, returned to the pool once done with.
+enum ReadConnection<'a> {
+    Pooled {
+        readers: &'a Readers,
+        conn: Option<Connection>,
     },
+    /// The writer's connection, where there's no pool.
+    Shared(MutexGuard<'a, Connection>),
+}
+
+impl Deref for ReadConnection<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        match self {
+            Self::Pooled { conn, .. } => conn.as_ref().expect("only taken when dropped"),
+            Self::Shared(conn) => conn,
+        }
+    }
+}
+
+impl Drop for ReadConnection<'_> {
+    fn drop(&mut self) {
+        if let Self::Pooled { readers, conn } = self
+            && let Some(conn) = conn.take()
+        {
+            readers.give_back(conn);
+        }
+    }
 }
 
-pub struct PhotoSyncStore(Mutex<Connection>);
+pub struct PhotoSyncStore {
+    /// The connection writes are made through, one at a time.
+    conn: Mutex<Connection>,
+    /// Connections for reads alone, which with a write-ahead log needn't wait behind writes. `None`
+    /// where there can only be one connection, e.g. in memory or when locked exclusively.
+    readers: Option<Readers>,
+}
 
 impl PhotoSyncStore {
     #[cfg(test)]
     pub fn new_for_tests() -> Result<Self> {
-        let mut store = Self(Mutex::new(Connection::open_in_memory()?));
+        Self::new_in_memory()
+    }
+
+    /// A store which lasts only as long as the process.
+    pub fn new_in_memory() -> Result<Self> {
+        let mut store = Self {
+            conn: Mutex::new(Connection::open_in_memory()?),
+            readers: None,
+        };
         store.ensure_schema()?;
         Ok(store)
     }
 
+    /// Opens the database at `path` with a write-ahead log, so that the workers of a sync read it
+    /// concurrently, unless it's on a network filesystem, where sqlite can't share the log's index.
     pub fn new(path: PathBuf) -> Result<Self> {
-        let mut store = Self(Mutex::new(Connection::open(path)?));
+        let conn = Connection::open(&path)?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty());
+        let readers = match network_filesystem(dir.unwrap_or(Path::new(".")))? {
+            Some(_) => None,
+            None => {
+                // reports the mode it settled on, as a row.
+                conn.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+                Some(Readers::new(path))
+            }
+        };
+        let mut store = Self {
+            conn: Mutex::new(conn),
+            readers,
+        };
         store.ensure_schema()?;
         Ok(store)
     }
 
-    fn acquire_connection(&self) -> MutexGuard<Connection> {
-        self.0.lock().expect("no panicking here")
+    /// Opens the database holding its lock until the store is dropped, and with a rollback journal
+    /// rather than a write-ahead log. This relies on far less of the filesystem's locking, so is
+    /// safer where that's unreliable, at the cost of shutting out other readers.
+    pub fn new_exclusive(path: PathBuf) -> Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        conn.pragma_update(None, "locking_mode", "EXCLUSIVE")?;
+        conn.pragma_update(None, "journal_mode", "DELETE")?;
+        let mut store = Self {
+            conn: Mutex::new(conn),
+            readers: None,
+        };
+        store.ensure_schema()?;
+        Ok(store)
+    }
+
+    pub fn tune(&self, tuning: &StoreTuning) -> Result<()> {
+        if let Some(readers) = &self.readers {
+            *readers.tuning.lock().unwrap() = *tuning;
+        }
+        Ok(tuning.apply(&self.acquire_connection())?)
+    }
+
+    /// Prints every statement run from now on with how long it took, and times them for
+    /// [`dbtrace::print_summary`].
+    pub fn trace(&self) {
+        if let Some(readers) = &self.readers {
+            readers.traced.store(true, Ordering::SeqCst);
+        }
+        dbtrace::trace(&self.acquire_connection(), true);
+    }
+
+    fn acquire_connection(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().expect("no panicking here")
+    }
+
+    /// A connection to read through, which sees every write committed so far.
+    fn read_connection(&self) -> Result<ReadConnection<'_>> {
+        match &self.readers {
+            Some(readers) => Ok(ReadConnection::Pooled {
+                conn: Some(readers.take()?),
+                readers,
+            }),
+            None => Ok(ReadConnection::Shared(self.acquire_connection())),
+        }
     }
 
     // technically doesn't need &mut but helps to promote safety
     pub fn ensure_schema(&mut self) -> Result<()> {
-        let conn = self.acquire_connection();
+        let mut conn = self.acquire_connection();
+        let version: usize = conn.query_row("PRAGMA user_version", [], |r| r.get(0))?;
+        ensure!(
+            version <= LATEST_SCHEMA_VERSION,
+            "the database has schema version {version}, newer than this version of the tool \
+             supports ({LATEST_SCHEMA_VERSION}); upgrade before using it"
+        );
         conn.execute_batch(
             r#"
+        DROP VIEW IF EXISTS all_target_digests;
+
         CREATE TABLE IF NOT EXISTS old_target_files (
             path    TEXT    NOT NULL,
             mtime   INTEGER NOT NULL,
@@ -60,31 +1074,428 @@ impl PhotoSyncStore {
             digest  BLOB    NOT NULL,
             PRIMARY KEY (path)
         );
-
-        DROP VIEW IF EXISTS all_target_digests;
-        CREATE VIEW all_target_digests AS
-              SELECT digest FROM old_target_files
-        UNION ALL
-              SELECT digest FROM source_files;
     "#,
         )?;
+
+        // a record of when each migration was applied; `user_version` alone decides which are
+        // still to be.
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS schema_version (
+                version     INTEGER NOT NULL,
+                applied_at  INTEGER NOT NULL,
+                PRIMARY KEY (version)
+            );",
+        )?;
+        for (idx, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            let tx = conn.transaction()?;
+            tx.execute_batch(migration)?;
+            tx.pragma_update(None, "user_version", idx + 1)?;
+            tx.execute(
+                "INSERT OR REPLACE INTO schema_version (version, applied_at) VALUES (?1, ?2)",
+                params![idx + 1, system_time_as_i64(SystemTime::now())?],
+            )?;
+            tx.commit()?;
+        }
+
+        // so the rows `INSERT OR REPLACE` replaces are taken out of `digests` too.
+        conn.pragma_update(None, "recursive_triggers", true)?;
+        for table in DIGEST_TABLES {
+            conn.execute_batch(&format!(
+                r#"
+        CREATE TRIGGER IF NOT EXISTS {table}_digests_insert AFTER INSERT ON {table} BEGIN
+            INSERT INTO digests (digest, refs) VALUES (NEW.digest, 1)
+                ON CONFLICT (digest) DO UPDATE SET refs=refs+1;
+        END;
+        CREATE TRIGGER IF NOT EXISTS {table}_digests_delete AFTER DELETE ON {table} BEGIN
+            UPDATE digests SET refs=refs-1 WHERE digest=OLD.digest;
+            DELETE FROM digests WHERE digest=OLD.digest AND refs<=0;
+        END;
+        CREATE TRIGGER IF NOT EXISTS {table}_digests_update AFTER UPDATE OF digest ON {table}
+        WHEN OLD.digest IS NOT NEW.digest BEGIN
+            UPDATE digests SET refs=refs-1 WHERE digest=OLD.digest;
+            DELETE FROM digests WHERE digest=OLD.digest AND refs<=0;
+            INSERT INTO digests (digest, refs) VALUES (NEW.digest, 1)
+                ON CONFLICT (digest) DO UPDATE SET refs=refs+1;
+        END;
+    "#
+            ))?;
+        }
+
+        // the rows a run replaces are kept until it finishes, so rolling it back can restore them.
+        for table in RUN_TABLES {
+            let (columns, key) = table_columns(&conn, table)?;
+            let replaced = format!("{REPLACED_PREFIX}{table}");
+            conn.execute_batch(&format!(
+                "CREATE TABLE IF NOT EXISTS {replaced} AS
+                    SELECT *, run_id AS replaced_by FROM {table} WHERE 0;
+                CREATE INDEX IF NOT EXISTS {replaced}_replaced_by ON {replaced} (replaced_by);"
+            ))?;
+            // columns added to the table since are added here too.
+            let (kept, _) = table_columns(&conn, &replaced)?;
+            for column in columns.iter().filter(|column| !kept.contains(column)) {
+                conn.execute(&format!("ALTER TABLE {replaced} ADD COLUMN {column}"), [])?;
+            }
+            let list = columns.join(", ");
+            let old = columns.iter().map(|column| format!("OLD.{column}"));
+            let old = old.collect::<Vec<_>>().join(", ");
+            let same_key = key.iter().map(|column| format!("{column}=NEW.{column}"));
+            let same_key = same_key.collect::<Vec<_>>().join(" AND ");
+            conn.execute_batch(&format!(
+                r#"
+        DROP TRIGGER IF EXISTS {table}_replaced_insert;
+        CREATE TRIGGER {table}_replaced_insert BEFORE INSERT ON {table}
+        WHEN NEW.run_id IS NOT NULL BEGIN
+            INSERT INTO {replaced} ({list}, replaced_by)
+                SELECT {list}, NEW.run_id FROM {table}
+                WHERE {same_key} AND run_id IS NOT NEW.run_id;
+        END;
+        DROP TRIGGER IF EXISTS {table}_replaced_update;
+        CREATE TRIGGER {table}_replaced_update BEFORE UPDATE OF run_id ON {table}
+        WHEN NEW.run_id IS NOT NULL AND OLD.run_id IS NOT NEW.run_id BEGIN
+            INSERT INTO {replaced} ({list}, replaced_by) VALUES ({old}, NEW.run_id);
+        END;
+    "#
+            ))?;
+        }
+        Ok(())
+    }
+
+    /// Rebuilds `digests` from the rows it counts, e.g. after the database was written without
+    /// its triggers, and refreshes the statistics sqlite plans queries with. Returns how many
+    /// distinct digests there are.
+    pub fn reindex_digests(&self) -> Result<u64> {
+        let mut conn = self.acquire_connection();
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM digests", [])?;
+        let digests = tx.execute(
+            "INSERT INTO digests (digest, refs)
+                SELECT digest, COUNT(*) FROM (
+                          SELECT digest FROM old_target_files
+                UNION ALL SELECT digest FROM source_files
+                UNION ALL SELECT digest FROM target_files
+                ) GROUP BY digest",
+            [],
+        )?;
+        tx.commit()?;
+        conn.execute_batch("ANALYZE")?;
+        Ok(digests as u64)
+    }
+
+    /// Checks the database's integrity, and that its rows hold together, as of `now`.
+    pub fn health_check(&self, now: SystemTime) -> Result<HealthCheck> {
+        let conn = self.read_connection()?;
+        let mut check = HealthCheck::default();
+        let mut stmt = conn.prepare("PRAGMA integrity_check")?;
+        check.integrity = stmt
+            .query_map([], |r| r.get::<_, String>(0))?
+            .filter(|message| !matches!(message.as_deref(), Ok("ok")))
+            .collect::<rusqlite::Result<_>>()?;
+
+        let digest_ok = HashAlgorithm::ALL
+            .iter()
+            .map(|algorithm| {
+                let prefix = algorithm.prefix();
+                format!(
+                    "(length(digest) = {} AND substr(digest, 1, {}) = CAST('{prefix}' AS BLOB))",
+                    algorithm.stored_length(),
+                    prefix.len()
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(" OR ");
+        let (earliest, latest) = (
+            0,
+            system_time_as_i64(now + Duration::from_secs(365 * 24 * 60 * 60))?,
+        );
+        let count = |sql: &str| -> Result<u64> { Ok(conn.query_row(sql, [], |r| r.get(0))?) };
+        for table in DIGEST_TABLES {
+            let key = match table {
+                "source_files" => "namespace, CAST(path AS BLOB)",
+                _ => "CAST(path AS BLOB)",
+            };
+            for (problems, sql) in [
+                (
+                    &mut check.duplicate_paths,
+                    format!(
+                        "SELECT COALESCE(SUM(n - 1), 0) FROM
+                            (SELECT COUNT(*) AS n FROM {table} GROUP BY {key} HAVING n > 1)"
+                    ),
+                ),
+                (
+                    &mut check.malformed_digests,
+                    format!(
+                        "SELECT COUNT(*) FROM {table}
+                            WHERE typeof(digest) != 'blob' OR NOT ({digest_ok})"
+                    ),
+                ),
+                (
+                    &mut check.implausible_mtimes,
+                    format!(
+                        "SELECT COUNT(*) FROM {table}
+                            WHERE mtime < {earliest} OR mtime > {latest}"
+                    ),
+                ),
+            ] {
+                let rows = count(&sql)?;
+                if rows > 0 {
+                    problems.push((table, rows));
+                }
+            }
+        }
+        check.unresolved_sources = count(
+            "SELECT COUNT(*) FROM source_files AS s
+                WHERE NOT EXISTS (SELECT 1 FROM target_files AS t WHERE t.digest = s.digest)
+                AND NOT EXISTS (SELECT 1 FROM old_target_files AS o WHERE o.digest = s.digest)",
+        )?;
+        check.stale_digests = count(
+            "WITH counted AS (
+                SELECT digest, COUNT(*) AS refs FROM (
+                          SELECT digest FROM old_target_files
+                UNION ALL SELECT digest FROM source_files
+                UNION ALL SELECT digest FROM target_files
+                ) GROUP BY digest
+            )
+            SELECT COUNT(DISTINCT digest) FROM (
+                SELECT * FROM (SELECT digest, refs FROM counted EXCEPT SELECT digest, refs FROM digests)
+                UNION
+                SELECT * FROM (SELECT digest, refs FROM digests EXCEPT SELECT digest, refs FROM counted)
+            )",
+        )?;
+        Ok(check)
+    }
+
+    /// Rebuilds the database file without the space deleted rows left free.
+    pub fn vacuum(&self) -> Result<()> {
+        self.acquire_connection().execute_batch("VACUUM")?;
+        Ok(())
+    }
+
+    /// The schema versions migrated to since the database began recording them, and when.
+    pub fn schema_history(&self) -> Result<Vec<(usize, SystemTime)>> {
+        let conn = self.read_connection()?;
+        let mut stmt =
+            conn.prepare("SELECT version, applied_at FROM schema_version ORDER BY version")?;
+        let history = stmt
+            .query_map([], |r| Ok((r.get(0)?, i64_as_system_time(r.get(1)?))))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(history)
+    }
+
+    /// Calls `each` with every row of every table, in the order the tables were created, with the
+    /// table's name and column names.
+    pub fn dump_rows(
+        &self,
+        mut each: impl FnMut(&str, &[String], Vec<Value>) -> Result<()>,
+    ) -> Result<()> {
+        let conn = self.read_connection()?;
+        for table in table_names(&conn)? {
+            let mut stmt = conn.prepare(&format!("SELECT * FROM {}", quote_identifier(&table)))?;
+            let columns: Vec<String> = stmt.column_names().into_iter().map(String::from).collect();
+            let mut rows = stmt.query([])?;
+            while let Some(row) = rows.next()? {
+                let values = (0..columns.len())
+                    .map(|idx| row.get(idx))
+                    .collect::<rusqlite::Result<_>>()?;
+                each(&table, &columns, values)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes `rows`, each a table's name and a row's columns and their values, in one
+    /// transaction, replacing any row with the same key.
+    pub fn load_rows(
+        &self,
+        rows: impl IntoIterator<Item = Result<(String, Vec<(String, Value)>)>>,
+    ) -> Result<()> {
+        let mut conn = self.acquire_connection();
+        let tables = table_names(&conn)?;
+        let tx = conn.transaction()?;
+        for row in rows {
+            let (table, values) = row?;
+            ensure!(tables.contains(&table), "there's no table {table:?}");
+            let columns = values
+                .iter()
+                .map(|(column, _)| quote_identifier(column))
+                .collect::<Vec<_>>();
+            let placeholders = (1..=values.len())
+                .map(|idx| format!("?{idx}"))
+                .collect::<Vec<_>>();
+            tx.prepare_cached(&format!(
+                "INSERT OR REPLACE INTO {} ({}) VALUES ({})",
+                quote_identifier(&table),
+                columns.join(", "),
+                placeholders.join(", ")
+            ))?
+            .execute(rusqlite::params_from_iter(
+                values.iter().map(|(_, value)| value),
+            ))?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Totals over the whole catalogue, with the `largest` files in the archive.
+    pub fn catalogue_stats(&self, largest: usize) -> Result<CatalogueStats> {
+        let conn = self.read_connection()?;
+        let mut tables = Vec::new();
+        for table in [
+            "source_files",
+            "old_target_files",
+            "target_files",
+            "pending_digests",
+        ] {
+            let (files, bytes): (i64, i64) = conn.query_row(
+                &format!("SELECT COUNT(*), COALESCE(SUM(size), 0) FROM {table}"),
+                [],
+                |r| Ok((r.get(0)?, r.get(1)?)),
+            )?;
+            tables.push((table, files as u64, bytes as u64));
+        }
+        let unique_digests: i64 =
+            conn.query_row("SELECT COUNT(*) FROM digests", [], |r| r.get(0))?;
+        let duplicates: i64 = conn.query_row(
+            "SELECT COUNT(*) - COUNT(DISTINCT digest) FROM (
+                 SELECT digest FROM source_files UNION ALL SELECT digest FROM old_target_files
+             )",
+            [],
+            |r| r.get(0),
+        )?;
+        let mut stmt = conn.prepare(
+            "SELECT 0, path, size FROM old_target_files
+             UNION ALL SELECT 1, path, size FROM target_files
+             ORDER BY size DESC, path LIMIT ?1",
+        )?;
+        let largest = stmt
+            .query_map(params![largest as i64], |r| {
+                let archive = match r.get::<_, i64>(0)? {
+                    0 => Archive::OldOut,
+                    _ => Archive::Out,
+                };
+                Ok((
+                    archive,
+                    r.get::<_, StoredPath>(1)?.0,
+                    r.get::<_, i64>(2)? as u64,
+                ))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        let mut stmt = conn.prepare(
+            "SELECT namespace, id, status, started_at, finished_at FROM runs
+             WHERE id IN (SELECT MAX(id) FROM runs GROUP BY namespace)
+             ORDER BY namespace",
+        )?;
+        let last_runs = stmt
+            .query_map([], |r| {
+                Ok(LastRun {
+                    namespace: r.get(0)?,
+                    id: r.get(1)?,
+                    status: r.get(2)?,
+                    started_at: i64_as_system_time(r.get(3)?),
+                    finished_at: r.get::<_, Option<i64>>(4)?.map(i64_as_system_time),
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(CatalogueStats {
+            tables,
+            unique_digests: unique_digests as u64,
+            duplicates: duplicates as u64,
+            largest,
+            last_runs,
+        })
+    }
+
+    /// Takes (or renews) the named lease for `holder`, failing if another holder has a lease
+    /// which has not yet expired.
+    pub fn acquire_lease(&self, name: &str, holder: &str, ttl: Duration) -> Result<()> {
+        let conn = self.acquire_connection();
+        let now = system_time_as_i64(SystemTime::now())?;
+        let changed = conn.execute(
+            "INSERT INTO leases (name, holder, expires_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT (name) DO UPDATE SET holder=excluded.holder, expires_at=excluded.expires_at
+             WHERE leases.holder=excluded.holder OR leases.expires_at<?4",
+            params![name, holder, now + ttl.as_secs() as i64, now],
+        )?;
+        if changed == 0 {
+            let (current_holder, expires_at): (String, i64) = conn.query_row(
+                "SELECT holder, expires_at FROM leases WHERE name=?1",
+                params![name],
+                |r| Ok((r.get(0)?, r.get(1)?)),
+            )?;
+            bail!(
+                "catalogue lease {name:?} is held by {current_holder:?} for another {}s",
+                expires_at - now
+            );
+        }
+        Ok(())
+    }
+
+    pub fn release_lease(&self, name: &str, holder: &str) -> Result<()> {
+        self.acquire_connection().execute(
+            "DELETE FROM leases WHERE name=?1 AND holder=?2",
+            params![name, holder],
+        )?;
         Ok(())
     }
 
+    /// Releases the lease `name`, for `--force-unlock` on `machine`, if it has expired or is held
+    /// for `machine`, and records the runs its holder never finished as aborted. With no lease,
+    /// those are `machine`'s own. Returns how many runs were.
+    pub fn break_lease(&self, name: &str, machine: &str) -> Result<usize> {
+        let mut conn = self.acquire_connection();
+        let tx = conn.transaction()?;
+        let now = system_time_as_i64(SystemTime::now())?;
+        let lease: Option<(String, i64)> = tx
+            .query_row(
+                "SELECT holder, expires_at FROM leases WHERE name=?1",
+                params![name],
+                |r| Ok((r.get(0)?, r.get(1)?)),
+            )
+            .optional()?;
+        let holder_machine = match &lease {
+            Some((holder, expires_at)) => {
+                let holder_machine = lease::holder_machine(holder);
+                ensure!(
+                    *expires_at < now || holder_machine == machine,
+                    "catalogue lease {name:?} is held by {holder:?} for another {}s, so can't be \
+                     broken",
+                    expires_at - now
+                );
+                tx.execute("DELETE FROM leases WHERE name=?1", params![name])?;
+                holder_machine
+            }
+            None => machine,
+        };
+        let aborted = tx.execute(
+            "UPDATE runs SET finished_at=?1, status=?2 WHERE status=?3 AND namespace=?4",
+            params![
+                now,
+                RunStatus::Aborted.as_str(),
+                RunStatus::Running.as_str(),
+                holder_machine
+            ],
+        )?;
+        tx.commit()?;
+        Ok(aborted)
+    }
+
     pub fn exists_in_old_target(
         &self,
         path: &Path,
         last_modified: SystemTime,
         size: u64,
     ) -> Result<WasTransferredFromSourceResult> {
-        let conn = self.acquire_connection();
+        let conn = self.read_connection()?;
         let mut stmt = conn.prepare_cached(
             "SELECT mtime, size, digest FROM old_target_files \
              WHERE path=?1 LIMIT 1",
         )?;
         let row = stmt
-            .query_row(params![path_to_text(path)?,], |r| {
-                Ok((r.get(0)?, r.get(1)?, r.get(2)?))
+            .query_row(params![path_to_blob(path)?,], |r| {
+                Ok((
+                    r.get::<_, i64>(0)?,
+                    r.get::<_, i64>(1)?,
+                    r.get::<_, ContentHash>(2)?,
+                ))
             })
             .optional()?;
         let Some((mtime, current_size, digest)) = row else {
@@ -104,28 +1515,1057 @@ impl PhotoSyncStore {
 
     pub fn mark_exists_in_old_target(
         &self,
+        run: RunId,
         path: &Path,
         last_modified: SystemTime,
         size: u64,
-        digest: &Sha256Hash,
+        digest: &ContentHash,
     ) -> Result<()> {
-        self.acquire_connection().execute(
-            "INSERT OR REPLACE INTO old_target_files (path, mtime, size, digest)
-             VALUES (?1, ?2, ?3, ?4)",
+        let conn = self.acquire_connection();
+        conn.execute(
+            "INSERT OR REPLACE INTO old_target_files (path, mtime, size, digest, run_id)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
             params![
-                path_to_text(path)?,
+                path_to_blob(path)?,
                 system_time_as_i64(last_modified)?,
                 size as i64,
-                digest
+                digest,
+                run,
             ],
         )?;
+        conn.execute(
+            "DELETE FROM pending_digests WHERE path=?1",
+            params![path_to_blob(path)?],
+        )?;
         Ok(())
     }
 
-    pub fn exists_in_target(&self, digest: &Sha256Hash) -> Result<bool> {
-        let conn = self.acquire_connection();
+    /// Records several files in the old out directory, as [`Self::mark_exists_in_old_target`]
+    /// does one, in a single transaction.
+    pub fn mark_exists_in_old_target_batch(
+        &self,
+        run: RunId,
+        files: &[CataloguedFile],
+    ) -> Result<()> {
+        let mut conn = self.acquire_connection();
+        let tx = conn.transaction()?;
+        {
+            let mut insert = tx.prepare_cached(
+                "INSERT OR REPLACE INTO old_target_files (path, mtime, size, digest, run_id)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            let mut forget = tx.prepare_cached("DELETE FROM pending_digests WHERE path=?1")?;
+            for file in files {
+                let path = path_to_blob(&file.path)?;
+                insert.execute(params![
+                    path,
+                    system_time_as_i64(file.mtime)?,
+                    file.size as i64,
+                    file.digest,
+                    run,
+                ])?;
+                forget.execute(params![path])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Records a file in the old out directory without its digest, which `hash-pending` fills in,
+    /// or a sync once a new file shares its `partial` digest.
+    pub fn mark_pending_digest(
+        &self,
+        run: RunId,
+        path: &Path,
+        last_modified: SystemTime,
+        size: u64,
+        partial: Option<&PartialDigest>,
+    ) -> Result<()> {
+        self.acquire_connection().execute(
+            "INSERT OR REPLACE INTO pending_digests (path, mtime, size, run_id, partial_digest)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                path_to_blob(path)?,
+                system_time_as_i64(last_modified)?,
+                size as i64,
+                run,
+                partial,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Whether any file in the old out directory waits to be hashed with a partial digest.
+    pub fn has_partial_digests(&self) -> Result<bool> {
+        let conn = self.read_connection()?;
+        Ok(conn
+            .prepare("SELECT 1 FROM pending_digests WHERE partial_digest IS NOT NULL")?
+            .exists([])?)
+    }
+
+    /// The files in the old out directory waiting to be hashed which have this size and partial
+    /// digest, and so may have the same content.
+    pub fn pending_paths_with_partial_digest(
+        &self,
+        size: u64,
+        partial: &PartialDigest,
+    ) -> Result<Vec<PathBuf>> {
+        let conn = self.read_connection()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT path FROM pending_digests WHERE size=?1 AND partial_digest=?2",
+        )?;
+        let paths = stmt
+            .query_map(params![size as i64, partial], |r| {
+                Ok(r.get::<_, StoredPath>(0)?.0)
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(paths)
+    }
+
+    /// Whether a file in the old out directory with this metadata is waiting to be hashed.
+    pub fn is_pending_digest(
+        &self,
+        path: &Path,
+        last_modified: SystemTime,
+        size: u64,
+    ) -> Result<bool> {
+        let conn = self.read_connection()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT 1 FROM pending_digests WHERE path=?1 AND mtime=?2 AND size=?3",
+        )?;
+        Ok(stmt.exists(params![
+            path_to_blob(path)?,
+            system_time_as_i64(last_modified)?,
+            size as i64
+        ])?)
+    }
+
+    /// Records a transfer as under way, replacing what was recorded of it so far.
+    pub fn journal_transfer(
+        &self,
+        run: RunId,
+        namespace: &str,
+        transfer: &PendingTransfer,
+    ) -> Result<()> {
+        let writing = transfer.writing.as_ref();
+        self.acquire_connection().execute(
+            "INSERT OR REPLACE INTO pending_transfers
+                 (namespace, path, work_dir, target_path, digest, mtime, size, run_id, transform)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                namespace,
+                path_to_blob(&transfer.path)?,
+                path_to_blob(&transfer.work_dir)?,
+                writing.map(|w| path_to_blob(&w.target_path)).transpose()?,
+                writing.map(|w| w.digest),
+                writing
+                    .map(|w| system_time_as_i64(w.last_modified))
+                    .transpose()?,
+                writing.map(|w| w.size as i64),
+                run,
+                writing.map(|w| w.transform),
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn forget_pending_transfer(&self, namespace: &str, path: &Path) -> Result<()> {
+        self.acquire_connection().execute(
+            "DELETE FROM pending_transfers WHERE namespace=?1 AND path=?2",
+            params![namespace, path_to_blob(path)?],
+        )?;
+        Ok(())
+    }
+
+    /// The transfers still recorded as under way, which outside a run were interrupted.
+    pub fn pending_transfers(&self, namespace: &str) -> Result<Vec<PendingTransfer>> {
+        let conn = self.read_connection()?;
+        let mut stmt = conn.prepare(
+            "SELECT path, work_dir, target_path, digest, mtime, size, transform
+             FROM pending_transfers WHERE namespace=?1 ORDER BY path",
+        )?;
+        let transfers = stmt
+            .query_map(params![namespace], |r| {
+                let writing = match r.get::<_, Option<StoredPath>>(2)? {
+                    Some(target_path) => Some(PendingWrite {
+                        // journalled before how copies are stored was, when names said.
+                        transform: r
+                            .get::<_, Option<Transform>>(6)?
+                            .unwrap_or_else(|| Transform::by_name(&target_path.0)),
+                        target_path: target_path.0,
+                        digest: r.get(3)?,
+                        last_modified: i64_as_system_time(r.get(4)?),
+                        size: r.get::<_, i64>(5)? as u64,
+                    }),
+                    None => None,
+                };
+                Ok(PendingTransfer {
+                    path: r.get::<_, StoredPath>(0)?.0,
+                    work_dir: r.get::<_, StoredPath>(1)?.0,
+                    writing,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(transfers)
+    }
+
+    /// Records that the source files `still` and `video` are the halves of a Live Photo.
+    pub fn record_live_photo(
+        &self,
+        run: RunId,
+        namespace: &str,
+        still: &Path,
+        video: &Path,
+    ) -> Result<()> {
+        self.acquire_connection().execute(
+            "INSERT OR REPLACE INTO live_photos (namespace, still_path, video_path, run_id)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![namespace, path_to_blob(still)?, path_to_blob(video)?, run],
+        )?;
+        Ok(())
+    }
+
+    pub fn record_sidecar(
+        &self,
+        run: RunId,
+        namespace: &str,
+        parent: &Path,
+        sidecar: &Path,
+    ) -> Result<()> {
+        self.acquire_connection().execute(
+            "INSERT OR REPLACE INTO sidecars (namespace, sidecar_path, parent_path, run_id)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![
+                namespace,
+                path_to_blob(sidecar)?,
+                path_to_blob(parent)?,
+                run
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Adds `files` to those `run` failed to transfer for the reason `kind`.
+    pub fn record_run_failures(&self, run: RunId, kind: FailureKind, files: u64) -> Result<()> {
+        self.acquire_connection().execute(
+            "INSERT INTO run_failures (run_id, kind, files) VALUES (?1, ?2, ?3)
+             ON CONFLICT (run_id, kind) DO UPDATE SET files=files + excluded.files",
+            params![run, kind.name(), files as i64],
+        )?;
+        Ok(())
+    }
+
+    /// How many files `run` failed to transfer for each reason, most first.
+    pub fn run_failures(&self, run: RunId) -> Result<Vec<(String, u64)>> {
+        let conn = self.read_connection()?;
+        let mut stmt = conn.prepare(
+            "SELECT kind, files FROM run_failures WHERE run_id=?1 ORDER BY files DESC, kind",
+        )?;
+        let failures = stmt
+            .query_map(params![run], |r| {
+                Ok((r.get(0)?, r.get::<_, i64>(1)? as u64))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(failures)
+    }
+
+    /// Records that the source file `path` couldn't be transferred, for the next run to retry.
+    pub fn record_failed_transfer(
+        &self,
+        run: RunId,
+        namespace: &str,
+        path: &Path,
+        reason: &str,
+    ) -> Result<()> {
+        self.acquire_connection().execute(
+            "INSERT INTO failed_transfers (namespace, path, reason, attempts, failed_at, run_id)
+             VALUES (?1, ?2, ?3, 1, ?4, ?5)
+             ON CONFLICT (namespace, path) DO UPDATE SET reason=excluded.reason,
+                 attempts=attempts + 1, failed_at=excluded.failed_at, run_id=excluded.run_id",
+            params![
+                namespace,
+                path_to_blob(path)?,
+                reason,
+                system_time_as_i64(SystemTime::now())?,
+                run,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Records that the place of the source file `path` in the out directory was taken, and that
+    /// it was resolved as `resolution`, e.g. by archiving it as `target_path`.
+    pub fn record_collision(
+        &self,
+        run: RunId,
+        namespace: &str,
+        path: &Path,
+        target_path: &Path,
+        resolution: &str,
+    ) -> Result<()> {
+        self.acquire_connection().execute(
+            "INSERT INTO collisions (namespace, source_path, target_path, resolution, run_id)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                namespace,
+                path_to_blob(path)?,
+                path_to_blob(target_path)?,
+                resolution,
+                run
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// The source files in `namespace` which couldn't be transferred, those failing longest ago
+    /// first.
+    pub fn failed_transfers(&self, namespace: &str) -> Result<Vec<PathBuf>> {
+        let conn = self.read_connection()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT path FROM failed_transfers WHERE namespace=?1 ORDER BY failed_at, path",
+        )?;
+        let paths = stmt
+            .query_map(params![namespace], |r| Ok(r.get::<_, StoredPath>(0)?.0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(paths)
+    }
+
+    /// The signatures of the directories in the old out directory, by their path in it, as of the
+    /// last run which indexed it all.
+    pub fn dir_signatures(&self) -> Result<Vec<(PathBuf, DirSignature)>> {
+        let conn = self.read_connection()?;
+        let mut stmt =
+            conn.prepare_cached("SELECT path, mtime, entries, size FROM dir_signatures")?;
+        let signatures = stmt
+            .query_map([], |r| {
+                let signature = DirSignature {
+                    mtime: i64_as_system_time(r.get(1)?),
+                    entries: r.get::<_, i64>(2)? as u64,
+                    size: r.get::<_, i64>(3)? as u64,
+                };
+                Ok((r.get::<_, StoredPath>(0)?.0, signature))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(signatures)
+    }
+
+    /// Replaces the signatures of the directories in the old out directory with `signatures`.
+    pub fn replace_dir_signatures(&self, signatures: &[(PathBuf, DirSignature)]) -> Result<()> {
+        let mut conn = self.acquire_connection();
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM dir_signatures", [])?;
+        {
+            let mut insert = tx.prepare_cached(
+                "INSERT INTO dir_signatures (path, mtime, entries, size) VALUES (?1, ?2, ?3, ?4)",
+            )?;
+            for (path, signature) in signatures {
+                insert.execute(params![
+                    path_to_blob(path)?,
+                    system_time_as_i64(signature.mtime)?,
+                    signature.entries as i64,
+                    signature.size as i64,
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// The source files in `namespace` which failed to transfer, with why, oldest first.
+    pub fn failed_transfer_records(&self, namespace: &str) -> Result<Vec<FailedTransfer>> {
+        let conn = self.read_connection()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT path, reason, attempts, failed_at FROM failed_transfers WHERE namespace=?1 \
+             ORDER BY failed_at, path",
+        )?;
+        let failed = stmt
+            .query_map(params![namespace], |r| {
+                Ok(FailedTransfer {
+                    path: r.get::<_, StoredPath>(0)?.0,
+                    reason: r.get(1)?,
+                    attempts: r.get(2)?,
+                    failed_at: i64_as_system_time(r.get(3)?),
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(failed)
+    }
+
+    /// The source files `run` left untransferred as their place in the out directory was taken,
+    /// by namespace, with where they would have gone.
+    pub fn skipped_collisions(&self, run: RunId) -> Result<Vec<(String, PathBuf, PathBuf)>> {
+        let conn = self.read_connection()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT namespace, source_path, target_path FROM collisions \
+             WHERE run_id=?1 AND resolution IN ('skipped', 'failed') ORDER BY source_path",
+        )?;
+        let skipped = stmt
+            .query_map(params![run], |r| {
+                Ok((
+                    r.get(0)?,
+                    r.get::<_, StoredPath>(1)?.0,
+                    r.get::<_, StoredPath>(2)?.0,
+                ))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(skipped)
+    }
+
+    /// The latest run of `namespace`, if it has had one.
+    pub fn latest_run(&self, namespace: &str) -> Result<Option<RunId>> {
+        Ok(self.read_connection()?.query_row(
+            "SELECT MAX(id) FROM runs WHERE namespace=?1",
+            params![namespace],
+            |r| r.get(0),
+        )?)
+    }
+
+    pub fn record_review_decision(
+        &self,
+        namespace: &str,
+        path: &Path,
+        decision: ReviewDecision,
+    ) -> Result<()> {
+        self.acquire_connection().execute(
+            "INSERT OR REPLACE INTO review_decisions (namespace, path, decision, decided_at)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![
+                namespace,
+                path_to_blob(path)?,
+                decision.as_str(),
+                system_time_as_i64(SystemTime::now())?,
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn review_decision(&self, namespace: &str, path: &Path) -> Result<Option<ReviewDecision>> {
+        let decision: Option<String> = self
+            .read_connection()?
+            .query_row(
+                "SELECT decision FROM review_decisions WHERE namespace=?1 AND path=?2",
+                params![namespace, path_to_blob(path)?],
+                |r| r.get(0),
+            )
+            .optional()?;
+        decision.as_deref().map(ReviewDecision::parse).transpose()
+    }
+
+    pub fn review_decisions(&self, namespace: &str) -> Result<Vec<(PathBuf, ReviewDecision)>> {
+        let conn = self.read_connection()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT path, decision FROM review_decisions WHERE namespace=?1 ORDER BY path",
+        )?;
+        let decisions: Vec<(PathBuf, String)> = stmt
+            .query_map(params![namespace], |r| {
+                Ok((r.get::<_, StoredPath>(0)?.0, r.get(1)?))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        decisions
+            .into_iter()
+            .map(|(path, decision)| Ok((path, ReviewDecision::parse(&decision)?)))
+            .collect()
+    }
+
+    pub fn forget_review_decision(&self, namespace: &str, path: &Path) -> Result<()> {
+        self.acquire_connection().execute(
+            "DELETE FROM review_decisions WHERE namespace=?1 AND path=?2",
+            params![namespace, path_to_blob(path)?],
+        )?;
+        Ok(())
+    }
+
+    /// Adds `path` to the ignore list, returning whether it wasn't there already.
+    pub fn add_ignored_path(&self, path: &Path, glob: bool) -> Result<bool> {
+        let added = self.acquire_connection().execute(
+            "INSERT OR IGNORE INTO ignored_paths (path, glob, added_at) VALUES (?1, ?2, ?3)",
+            params![
+                path_to_blob(path)?,
+                glob,
+                system_time_as_i64(SystemTime::now())?
+            ],
+        )?;
+        Ok(added > 0)
+    }
+
+    pub fn ignored_paths(&self) -> Result<Vec<IgnoredPath>> {
+        let conn = self.read_connection()?;
+        let mut stmt =
+            conn.prepare_cached("SELECT path, glob, added_at FROM ignored_paths ORDER BY path")?;
+        let ignored = stmt
+            .query_map([], |r| {
+                Ok(IgnoredPath {
+                    path: r.get::<_, StoredPath>(0)?.0,
+                    glob: r.get(1)?,
+                    added_at: i64_as_system_time(r.get(2)?),
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(ignored)
+    }
+
+    /// Removes `path` from the ignore list, returning whether it was there.
+    pub fn remove_ignored_path(&self, path: &Path) -> Result<bool> {
+        let removed = self.acquire_connection().execute(
+            "DELETE FROM ignored_paths WHERE path=?1",
+            params![path_to_blob(path)?],
+        )?;
+        Ok(removed > 0)
+    }
+
+    /// Tombstones the content `digest`, last at `path`, returning whether it wasn't already.
+    pub fn add_tombstone(&self, digest: &ContentHash, path: &Path) -> Result<bool> {
+        let added = self.acquire_connection().execute(
+            "INSERT OR IGNORE INTO tombstones (digest, path, added_at) VALUES (?1, ?2, ?3)",
+            params![
+                digest,
+                path_to_blob(path)?,
+                system_time_as_i64(SystemTime::now())?
+            ],
+        )?;
+        Ok(added > 0)
+    }
+
+    pub fn tombstones(&self) -> Result<Vec<Tombstone>> {
+        let conn = self.read_connection()?;
+        let mut stmt =
+            conn.prepare_cached("SELECT digest, path, added_at FROM tombstones ORDER BY added_at")?;
+        let tombstones = stmt
+            .query_map([], |r| {
+                Ok(Tombstone {
+                    digest: r.get(0)?,
+                    path: r.get::<_, StoredPath>(1)?.0,
+                    added_at: i64_as_system_time(r.get(2)?),
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(tombstones)
+    }
+
+    pub fn is_tombstoned(&self, digest: &ContentHash) -> Result<bool> {
+        let conn = self.read_connection()?;
+        let mut stmt = conn.prepare_cached("SELECT 1 FROM tombstones WHERE digest=?1")?;
+        Ok(stmt
+            .query_row(params![digest], |_| Ok(()))
+            .optional()?
+            .is_some())
+    }
+
+    /// Removes the tombstone of `digest`, returning whether there was one. The source files left
+    /// out for any tombstone are looked at again, so those with this content are transferred.
+    pub fn remove_tombstone(&self, digest: &ContentHash) -> Result<bool> {
+        let mut conn = self.acquire_connection();
+        let tx = conn.transaction()?;
+        let removed = tx.execute("DELETE FROM tombstones WHERE digest=?1", params![digest])?;
+        tx.execute("DELETE FROM classified_out WHERE class='tombstoned'", [])?;
+        tx.commit()?;
+        Ok(removed > 0)
+    }
+
+    /// Forgets the file at `path` in the out directory, once it's been deleted, and the source
+    /// files transferred to it, so they no longer count as archived. When it was the last copy of
+    /// its content, `digest`, so are the source files skipped as duplicates of it.
+    pub fn forget_target_file(&self, path: &Path, digest: &ContentHash, last: bool) -> Result<()> {
+        let mut conn = self.acquire_connection();
+        let tx = conn.transaction()?;
+        let path = path_to_blob(path)?;
+        tx.execute("DELETE FROM target_files WHERE path=?1", params![path])?;
+        tx.execute("DELETE FROM transformed_files WHERE path=?1", params![path])?;
+        tx.execute(
+            "DELETE FROM source_files WHERE target_path=?1",
+            params![path],
+        )?;
+        if last {
+            tx.execute("DELETE FROM source_files WHERE digest=?1", params![digest])?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Records that the source file `path` was left out for being of `class`.
+    pub fn record_classified_out(&self, namespace: &str, file: &ClassifiedOut) -> Result<()> {
+        self.acquire_connection().execute(
+            "INSERT OR REPLACE INTO classified_out (namespace, path, mtime, size, class)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                namespace,
+                path_to_blob(&file.path)?,
+                system_time_as_i64(file.last_modified)?,
+                file.size as i64,
+                file.class,
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn classified_out(&self, namespace: &str) -> Result<Vec<ClassifiedOut>> {
+        let conn = self.read_connection()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT path, mtime, size, class FROM classified_out WHERE namespace=?1",
+        )?;
+        let files = stmt
+            .query_map(params![namespace], |r| {
+                Ok(ClassifiedOut {
+                    path: r.get::<_, StoredPath>(0)?.0,
+                    last_modified: i64_as_system_time(r.get(1)?),
+                    size: r.get::<_, i64>(2)? as u64,
+                    class: r.get(3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(files)
+    }
+
+    pub fn forget_failed_transfer(&self, namespace: &str, path: &Path) -> Result<()> {
+        self.acquire_connection().execute(
+            "DELETE FROM failed_transfers WHERE namespace=?1 AND path=?2",
+            params![namespace, path_to_blob(path)?],
+        )?;
+        Ok(())
+    }
+
+    /// Records that `--move` deleted the source file `path`, whose content is archived.
+    pub fn record_deleted_source(
+        &self,
+        run: RunId,
+        namespace: &str,
+        path: &Path,
+        digest: &ContentHash,
+        size: u64,
+    ) -> Result<()> {
+        self.acquire_connection().execute(
+            "INSERT INTO deleted_sources (namespace, path, digest, size, deleted_at, run_id)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                namespace,
+                path_to_blob(path)?,
+                digest,
+                size as i64,
+                system_time_as_i64(SystemTime::now())?,
+                run,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// The source files of `namespace` whose content was written to the out directory for them,
+    /// with their digest and where it was written.
+    pub fn source_targets(&self, namespace: &str) -> Result<Vec<(PathBuf, ContentHash, PathBuf)>> {
+        let conn = self.read_connection()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT path, digest, target_path FROM source_files \
+             WHERE namespace=?1 AND target_path IS NOT NULL ORDER BY path",
+        )?;
+        let targets = stmt
+            .query_map(params![namespace], |r| {
+                Ok((
+                    r.get::<_, StoredPath>(0)?.0,
+                    r.get(1)?,
+                    r.get::<_, StoredPath>(2)?.0,
+                ))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(targets)
+    }
+
+    /// Whether a source file other than `path` in `namespace` has the content `digest`.
+    pub fn shares_content(
+        &self,
+        namespace: &str,
+        path: &Path,
+        digest: &ContentHash,
+    ) -> Result<bool> {
+        Ok(self.read_connection()?.query_row(
+            "SELECT EXISTS (SELECT 1 FROM source_files \
+             WHERE digest=?3 AND NOT (namespace=?1 AND path=?2))",
+            params![namespace, path_to_blob(path)?, digest],
+            |r| r.get(0),
+        )?)
+    }
+
+    /// Records that the source file `path` was deleted from the in directory, and its copy
+    /// `target_path` moved to `trashed_to`, if it was still there, forgetting both.
+    pub fn record_propagated_deletion(
+        &self,
+        namespace: &str,
+        path: &Path,
+        digest: &ContentHash,
+        target_path: &Path,
+        trashed_to: Option<&Path>,
+    ) -> Result<()> {
+        let mut conn = self.acquire_connection();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT INTO propagated_deletions
+                 (namespace, path, digest, target_path, trashed_to, deleted_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                namespace,
+                path_to_blob(path)?,
+                digest,
+                path_to_blob(target_path)?,
+                trashed_to.map(path_to_blob).transpose()?,
+                system_time_as_i64(SystemTime::now())?,
+            ],
+        )?;
+        tx.execute(
+            "DELETE FROM source_files WHERE namespace=?1 AND path=?2",
+            params![namespace, path_to_blob(path)?],
+        )?;
+        tx.execute(
+            "DELETE FROM target_files WHERE path=?1",
+            params![path_to_blob(target_path)?],
+        )?;
+        tx.execute(
+            "DELETE FROM transformed_files WHERE path=?1",
+            params![path_to_blob(target_path)?],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// The IDs of the iCloud Photos assets already downloaded into `namespace`.
+    pub fn icloud_assets(&self, namespace: &str) -> Result<HashSet<String>> {
+        let conn = self.read_connection()?;
         let mut stmt =
-            conn.prepare_cached("SELECT 1 FROM all_target_digests WHERE digest=?1 LIMIT 1")?;
+            conn.prepare_cached("SELECT asset_id FROM icloud_assets WHERE namespace=?1")?;
+        let ids = stmt
+            .query_map(params![namespace], |r| r.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(ids)
+    }
+
+    /// Records that the iCloud Photos asset `asset_id` was downloaded as the source file `path`,
+    /// with the content `digest`.
+    pub fn record_icloud_asset(
+        &self,
+        namespace: &str,
+        asset_id: &str,
+        path: &Path,
+        digest: &ContentHash,
+    ) -> Result<()> {
+        self.acquire_connection().execute(
+            "INSERT OR REPLACE INTO icloud_assets (namespace, asset_id, path, digest, downloaded_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                namespace,
+                asset_id,
+                path_to_blob(path)?,
+                digest,
+                system_time_as_i64(SystemTime::now())?,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// The source files `--move` has deleted.
+    pub fn deleted_sources(&self, namespace: &str) -> Result<Vec<PathBuf>> {
+        let conn = self.read_connection()?;
+        let mut stmt = conn.prepare("SELECT path FROM deleted_sources WHERE namespace=?1")?;
+        let paths = stmt
+            .query_map(params![namespace], |r| Ok(r.get::<_, StoredPath>(0)?.0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(paths)
+    }
+
+    /// Records that the file at `path` in the out directory was transcoded, so its content is
+    /// `output_digest` rather than the digest it's catalogued with.
+    pub fn record_transcoded(
+        &self,
+        run: RunId,
+        path: &Path,
+        output_digest: &ContentHash,
+    ) -> Result<()> {
+        self.acquire_connection().execute(
+            "INSERT OR REPLACE INTO transcoded_files (path, output_digest, run_id)
+             VALUES (?1, ?2, ?3)",
+            params![path_to_blob(path)?, output_digest, run],
+        )?;
+        Ok(())
+    }
+
+    /// The digest of the file at `path` in the out directory, if it was transcoded.
+    pub fn transcoded_digest(&self, path: &Path) -> Result<Option<ContentHash>> {
+        let conn = self.read_connection()?;
+        let mut stmt =
+            conn.prepare_cached("SELECT output_digest FROM transcoded_files WHERE path=?1")?;
+        Ok(stmt
+            .query_row(params![path_to_blob(path)?], |r| r.get(0))
+            .optional()?)
+    }
+
+    /// The digests of the transcoded files in the out directory, by their path.
+    pub fn transcoded_files(&self) -> Result<HashMap<PathBuf, ContentHash>> {
+        let conn = self.read_connection()?;
+        let mut stmt = conn.prepare("SELECT path, output_digest FROM transcoded_files")?;
+        let files = stmt
+            .query_map([], |r| Ok((r.get::<_, StoredPath>(0)?.0, r.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(files)
+    }
+
+    /// Records that the file at `path` in the out directory was encrypted, so the bytes stored
+    /// there have `stored_digest`, which can be checked without decrypting them.
+    pub fn record_encrypted(
+        &self,
+        run: RunId,
+        path: &Path,
+        stored_digest: &ContentHash,
+    ) -> Result<()> {
+        self.acquire_connection().execute(
+            "INSERT OR REPLACE INTO encrypted_files (path, stored_digest, run_id)
+             VALUES (?1, ?2, ?3)",
+            params![path_to_blob(path)?, stored_digest, run],
+        )?;
+        Ok(())
+    }
+
+    /// The digests of the bytes stored for the encrypted files in the out directory, by their
+    /// path.
+    pub fn encrypted_files(&self) -> Result<HashMap<PathBuf, ContentHash>> {
+        let conn = self.read_connection()?;
+        let mut stmt = conn.prepare("SELECT path, stored_digest FROM encrypted_files")?;
+        let files = stmt
+            .query_map([], |r| Ok((r.get::<_, StoredPath>(0)?.0, r.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(files)
+    }
+
+    /// Records how the file at `path` in the out directory was stored.
+    pub fn record_transform(&self, run: RunId, path: &Path, transform: Transform) -> Result<()> {
+        let path = path_to_blob(path)?;
+        // only those which weren't stored as they are are kept.
+        if transform == Transform::None {
+            self.acquire_connection()
+                .execute("DELETE FROM transformed_files WHERE path=?1", params![path])?;
+        } else {
+            self.acquire_connection().execute(
+                "INSERT OR REPLACE INTO transformed_files (path, transform, run_id)
+                 VALUES (?1, ?2, ?3)",
+                params![path, transform, run],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// How the file at `path` in the out directory was stored.
+    pub fn transform_of(&self, path: &Path) -> Result<Transform> {
+        let conn = self.read_connection()?;
+        let mut stmt =
+            conn.prepare_cached("SELECT transform FROM transformed_files WHERE path=?1")?;
+        Ok(stmt
+            .query_row(params![path_to_blob(path)?], |r| r.get(0))
+            .optional()?
+            .unwrap_or_default())
+    }
+
+    /// How the files in the out directory which weren't stored as they are were stored, by their
+    /// path.
+    pub fn transformed_files(&self) -> Result<HashMap<PathBuf, Transform>> {
+        let conn = self.read_connection()?;
+        let mut stmt = conn.prepare("SELECT path, transform FROM transformed_files")?;
+        let files = stmt
+            .query_map([], |r| Ok((r.get::<_, StoredPath>(0)?.0, r.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(files)
+    }
+
+    /// The still and video source files of every Live Photo transferred.
+    pub fn live_photos(&self, namespace: &str) -> Result<Vec<(PathBuf, PathBuf)>> {
+        let conn = self.read_connection()?;
+        let mut stmt = conn.prepare(
+            "SELECT still_path, video_path FROM live_photos WHERE namespace=?1 ORDER BY still_path",
+        )?;
+        let pairs = stmt
+            .query_map(params![namespace], |r| {
+                Ok((r.get::<_, StoredPath>(0)?.0, r.get::<_, StoredPath>(1)?.0))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(pairs)
+    }
+
+    /// The files in the old out directory waiting to be hashed.
+    pub fn pending_digests(&self) -> Result<Vec<PathBuf>> {
+        let conn = self.read_connection()?;
+        let mut stmt = conn.prepare("SELECT path FROM pending_digests ORDER BY path")?;
+        let paths = stmt
+            .query_map([], |r| Ok(r.get::<_, StoredPath>(0)?.0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(paths)
+    }
+
+    /// Deletes the rows of `old_target` files, gone from the old out directory, and of `sources`
+    /// in `namespace`, gone from the in directory, in a single transaction.
+    pub fn prune(
+        &self,
+        old_target: &[PathBuf],
+        namespace: &str,
+        sources: &[PathBuf],
+    ) -> Result<()> {
+        let mut conn = self.acquire_connection();
+        let tx = conn.transaction()?;
+        {
+            let mut old = tx.prepare("DELETE FROM old_target_files WHERE path=?1")?;
+            for path in old_target {
+                old.execute(params![path_to_blob(path)?])?;
+            }
+            let mut source =
+                tx.prepare("DELETE FROM source_files WHERE namespace=?1 AND path=?2")?;
+            for path in sources {
+                source.execute(params![namespace, path_to_blob(path)?])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn forget_pending_digest(&self, path: &Path) -> Result<()> {
+        self.acquire_connection().execute(
+            "DELETE FROM pending_digests WHERE path=?1",
+            params![path_to_blob(path)?],
+        )?;
+        Ok(())
+    }
+
+    /// Files in the old out directory catalogued with this size and modification time.
+    pub fn old_target_paths_with_metadata(
+        &self,
+        last_modified: SystemTime,
+        size: u64,
+    ) -> Result<Vec<PathBuf>> {
+        let conn = self.read_connection()?;
+        let mut stmt =
+            conn.prepare_cached("SELECT path FROM old_target_files WHERE size=?1 AND mtime=?2")?;
+        let paths = stmt
+            .query_map(
+                params![size as i64, system_time_as_i64(last_modified)?],
+                |r| Ok(r.get::<_, StoredPath>(0)?.0),
+            )?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(paths)
+    }
+
+    pub fn old_target_paths_with_digest(&self, digest: &ContentHash) -> Result<Vec<PathBuf>> {
+        let conn = self.read_connection()?;
+        let mut stmt = conn.prepare_cached("SELECT path FROM old_target_files WHERE digest=?1")?;
+        let paths = stmt
+            .query_map(params![digest], |r| Ok(r.get::<_, StoredPath>(0)?.0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(paths)
+    }
+
+    /// Moves the row of a file in the old out directory moved from `from` to `to`, replacing any
+    /// row for what was at `to` before. Returns false if there's no longer a row at `from`.
+    pub fn move_old_target(
+        &self,
+        from: &Path,
+        to: &Path,
+        last_modified: SystemTime,
+        size: u64,
+    ) -> Result<bool> {
+        let mut conn = self.acquire_connection();
+        let tx = conn.transaction()?;
+        let (from, to) = (path_to_blob(from)?, path_to_blob(to)?);
+        let exists = tx
+            .query_row(
+                "SELECT 1 FROM old_target_files WHERE path=?1",
+                params![from],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some();
+        if !exists {
+            return Ok(false);
+        }
+        tx.execute("DELETE FROM old_target_files WHERE path=?1", params![to])?;
+        tx.execute(
+            "UPDATE old_target_files SET path=?2, mtime=?3, size=?4 WHERE path=?1",
+            params![from, to, system_time_as_i64(last_modified)?, size as i64],
+        )?;
+        // parity covers content, wherever it now is.
+        tx.execute(
+            "UPDATE parity_members SET path=?2 WHERE path=?1",
+            params![from, to],
+        )?;
+        tx.commit()?;
+        Ok(true)
+    }
+
+    /// Records a file in the out directory, at `path` relative to it.
+    pub fn mark_exists_in_target(
+        &self,
+        run: RunId,
+        path: &Path,
+        last_modified: SystemTime,
+        size: u64,
+        digest: &ContentHash,
+    ) -> Result<()> {
+        self.acquire_connection().execute(
+            "INSERT OR REPLACE INTO target_files (path, mtime, size, digest, run_id)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                path_to_blob(path)?,
+                system_time_as_i64(last_modified)?,
+                size as i64,
+                digest,
+                run,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Whether the file at `path` in the out directory is catalogued with this metadata.
+    pub fn target_file_is_current(
+        &self,
+        path: &Path,
+        last_modified: SystemTime,
+        size: u64,
+    ) -> Result<bool> {
+        let conn = self.read_connection()?;
+        let mut stmt = conn
+            .prepare_cached("SELECT 1 FROM target_files WHERE path=?1 AND mtime=?2 AND size=?3")?;
+        let current = stmt
+            .query_row(
+                params![
+                    path_to_blob(path)?,
+                    system_time_as_i64(last_modified)?,
+                    size as i64
+                ],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some();
+        Ok(current)
+    }
+
+    pub fn is_known_target(&self, path: &Path) -> Result<bool> {
+        let conn = self.read_connection()?;
+        let mut stmt = conn.prepare_cached("SELECT 1 FROM target_files WHERE path=?1")?;
+        let known = stmt
+            .query_row(params![path_to_blob(path)?], |_| Ok(()))
+            .optional()?
+            .is_some();
+        Ok(known)
+    }
+
+    pub fn has_target_files(&self) -> Result<bool> {
+        let conn = self.read_connection()?;
+        let mut stmt = conn.prepare_cached("SELECT 1 FROM target_files LIMIT 1")?;
+        Ok(stmt.exists([])?)
+    }
+
+    pub fn target_paths_with_digest(&self, digest: &ContentHash) -> Result<Vec<PathBuf>> {
+        let conn = self.read_connection()?;
+        let mut stmt = conn.prepare_cached("SELECT path FROM target_files WHERE digest=?1")?;
+        let paths = stmt
+            .query_map(params![digest], |r| Ok(r.get::<_, StoredPath>(0)?.0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(paths)
+    }
+
+    pub fn exists_in_target(&self, digest: &ContentHash) -> Result<bool> {
+        let conn = self.read_connection()?;
+        let mut stmt = conn.prepare_cached("SELECT 1 FROM digests WHERE digest=?1")?;
         let exists = stmt
             .query_row(params![digest], |_| Ok(()))
             .optional()?
@@ -133,25 +2573,49 @@ impl PhotoSyncStore {
         Ok(exists)
     }
 
+    /// Every digest in the archive, by any algorithm.
+    pub fn target_digests(&self) -> Result<Vec<ContentHash>> {
+        let conn = self.read_connection()?;
+        let mut stmt = conn.prepare("SELECT digest FROM digests")?;
+        let digests = stmt
+            .query_map([], |r| r.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(digests)
+    }
+
+    /// Whether any archived file is catalogued with a digest by `algorithm`.
+    pub fn has_target_digests_by(&self, algorithm: HashAlgorithm) -> Result<bool> {
+        let prefix = algorithm.prefix().as_bytes();
+        let conn = self.read_connection()?;
+        let exists = conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM digests \
+             WHERE length(digest)=?1 AND substr(digest, 1, ?2)=?3)",
+            params![algorithm.stored_length(), prefix.len(), prefix],
+            |r| r.get(0),
+        )?;
+        Ok(exists)
+    }
+
     pub fn was_transferred_from_source(
         &self,
+        namespace: &str,
         path: &Path,
         last_modified: SystemTime,
         size: u64,
     ) -> Result<WasTransferredFromSourceResult> {
-        let conn = self.acquire_connection();
+        let conn = self.read_connection()?;
         let mut stmt = conn.prepare_cached(
             "SELECT mtime, size, digest FROM source_files \
-             WHERE path=?1 LIMIT 1",
+             WHERE namespace=?1 AND path=?2 LIMIT 1",
         )?;
         let last_modified = system_time_as_i64(last_modified)?;
         let size = size as i64;
         let data = stmt
-            .query_row(params![path_to_text(path)?], |r| {
+            .query_row(params![namespace, path_to_blob(path)?], |r| {
                 Ok((
                     r.get::<_, i64>("mtime")?,
                     r.get::<_, i64>("size")?,
-                    r.get::<_, Sha256Hash>("digest")?,
+                    r.get::<_, ContentHash>("digest")?,
                 ))
             })
             .optional()?;
@@ -172,25 +2636,1171 @@ impl PhotoSyncStore {
         )
     }
 
-    pub fn mark_transferred_from_source(
+    pub fn mark_transferred_from_source(
+        &self,
+        run: RunId,
+        namespace: &str,
+        path: &Path,
+        digest: &ContentHash,
+        last_modified: SystemTime,
+        size: u64,
+    ) -> Result<()> {
+        let conn = self.acquire_connection();
+        conn.execute(
+            "INSERT INTO source_files (namespace, path, mtime, size, digest, run_id)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                namespace,
+                path_to_blob(path)?,
+                system_time_as_i64(last_modified)?,
+                size as i64,
+                digest,
+                run,
+            ],
+        )?;
+        conn.execute(
+            "DELETE FROM failed_transfers WHERE namespace=?1 AND path=?2",
+            params![namespace, path_to_blob(path)?],
+        )?;
+        conn.execute(
+            "DELETE FROM review_decisions WHERE namespace=?1 AND path=?2 AND decision='retransfer'",
+            params![namespace, path_to_blob(path)?],
+        )?;
+        Ok(())
+    }
+
+    /// Records several transferred source files, as [`Self::mark_transferred_from_source`] and
+    /// [`Self::set_source_target`] do one, in a single transaction.
+    pub fn mark_transferred_from_source_batch(
+        &self,
+        run: RunId,
+        namespace: &str,
+        sources: &[TransferredSource],
+    ) -> Result<()> {
+        let mut conn = self.acquire_connection();
+        let tx = conn.transaction()?;
+        {
+            let mut insert = tx.prepare_cached(
+                "INSERT INTO source_files (namespace, path, mtime, size, digest, run_id, target_path)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            )?;
+            let mut forget =
+                tx.prepare_cached("DELETE FROM failed_transfers WHERE namespace=?1 AND path=?2")?;
+            let mut retransferred = tx.prepare_cached(
+                "DELETE FROM review_decisions WHERE namespace=?1 AND path=?2 \
+                 AND decision='retransfer'",
+            )?;
+            for source in sources {
+                forget.execute(params![namespace, path_to_blob(&source.path)?])?;
+                retransferred.execute(params![namespace, path_to_blob(&source.path)?])?;
+                insert.execute(params![
+                    namespace,
+                    path_to_blob(&source.path)?,
+                    system_time_as_i64(source.last_modified)?,
+                    source.size as i64,
+                    source.digest,
+                    run,
+                    source
+                        .target_path
+                        .as_deref()
+                        .map(path_to_blob)
+                        .transpose()?,
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Records that the content of the source file `path` was written to `target`, relative to
+    /// the out directory.
+    pub fn set_source_target(&self, namespace: &str, path: &Path, target: &Path) -> Result<()> {
+        self.acquire_connection().execute(
+            "UPDATE source_files SET target_path = ?3 WHERE namespace = ?1 AND path = ?2",
+            params![namespace, path_to_blob(path)?, path_to_blob(target)?],
+        )?;
+        Ok(())
+    }
+
+    /// Replaces the catalogued content and metadata of a source file, e.g. once it has changed.
+    pub fn update_source(
+        &self,
+        run: RunId,
+        namespace: &str,
+        path: &Path,
+        digest: &ContentHash,
+        last_modified: SystemTime,
+        size: u64,
+    ) -> Result<()> {
+        self.acquire_connection().execute(
+            "INSERT OR REPLACE INTO source_files (namespace, path, mtime, size, digest, run_id)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                namespace,
+                path_to_blob(path)?,
+                system_time_as_i64(last_modified)?,
+                size as i64,
+                digest,
+                run,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// The number the next version of a changed source file gets.
+    pub fn next_version(&self, namespace: &str, path: &Path) -> Result<u32> {
+        let conn = self.read_connection()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT COALESCE(MAX(version), 1) + 1 FROM source_versions \
+             WHERE namespace=?1 AND path=?2",
+        )?;
+        Ok(stmt.query_row(params![namespace, path_to_blob(path)?], |r| r.get(0))?)
+    }
+
+    /// Records a new version of a changed source file, along with the content it was first
+    /// transferred with, `original`, if this is its first change.
+    pub fn record_version(
+        &self,
+        run: RunId,
+        namespace: &str,
+        original: &ContentHash,
+        version: &SourceVersion,
+    ) -> Result<()> {
+        let mut conn = self.acquire_connection();
+        let tx = conn.transaction()?;
+        let path = path_to_blob(&version.path)?;
+        tx.execute(
+            "INSERT OR IGNORE INTO source_versions (namespace, path, version, digest, run_id)
+             VALUES (?1, ?2, 1, ?3, ?4)",
+            params![namespace, path, original, run],
+        )?;
+        tx.execute(
+            "INSERT INTO source_versions
+                 (namespace, path, version, digest, target_path, archived_at, run_id)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                namespace,
+                path,
+                version.version,
+                version.digest,
+                version
+                    .target_path
+                    .as_deref()
+                    .map(path_to_blob)
+                    .transpose()?,
+                version.archived_at.map(system_time_as_i64).transpose()?,
+                run,
+            ],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Every version of a changed source file, oldest first.
+    pub fn versions(&self, namespace: &str, path: &Path) -> Result<Vec<SourceVersion>> {
+        let conn = self.read_connection()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT version, digest, target_path, archived_at FROM source_versions \
+             WHERE namespace=?1 AND path=?2 ORDER BY version",
+        )?;
+        let versions = stmt
+            .query_map(params![namespace, path_to_blob(path)?], |r| {
+                Ok(SourceVersion {
+                    path: path.to_path_buf(),
+                    version: r.get(0)?,
+                    digest: r.get(1)?,
+                    target_path: r.get::<_, Option<StoredPath>>(2)?.map(|p| p.0),
+                    archived_at: r.get::<_, Option<i64>>(3)?.map(i64_as_system_time),
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(versions)
+    }
+
+    /// Every version of every changed source file in a namespace, by path and then oldest first.
+    pub fn all_versions(&self, namespace: &str) -> Result<Vec<SourceVersion>> {
+        let conn = self.read_connection()?;
+        let mut stmt = conn.prepare(
+            "SELECT path, version, digest, target_path, archived_at FROM source_versions \
+             WHERE namespace=?1 ORDER BY path, version",
+        )?;
+        let versions = stmt
+            .query_map(params![namespace], |r| {
+                Ok(SourceVersion {
+                    path: r.get::<_, StoredPath>(0)?.0,
+                    version: r.get(1)?,
+                    digest: r.get(2)?,
+                    target_path: r.get::<_, Option<StoredPath>>(3)?.map(|p| p.0),
+                    archived_at: r.get::<_, Option<i64>>(4)?.map(i64_as_system_time),
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(versions)
+    }
+
+    /// Whether a version's content is also the content of a source file or another version, so
+    /// its archived copy is still needed once the version is forgotten.
+    pub fn is_version_content_shared(
+        &self,
+        namespace: &str,
+        version: &SourceVersion,
+    ) -> Result<bool> {
+        let conn = self.read_connection()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT 1 FROM source_files WHERE digest=?1 \
+             UNION ALL \
+             SELECT 1 FROM source_versions WHERE digest=?1 \
+                 AND NOT (namespace=?2 AND path=?3 AND version=?4)",
+        )?;
+        Ok(stmt.exists(params![
+            version.digest,
+            namespace,
+            path_to_blob(&version.path)?,
+            version.version
+        ])?)
+    }
+
+    /// Forgets a pruned version, along with the out directory row of its archived copy if
+    /// `copy_removed`.
+    pub fn forget_version(
+        &self,
+        namespace: &str,
+        version: &SourceVersion,
+        copy_removed: bool,
+    ) -> Result<()> {
+        let mut conn = self.acquire_connection();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "DELETE FROM source_versions WHERE namespace=?1 AND path=?2 AND version=?3",
+            params![namespace, path_to_blob(&version.path)?, version.version],
+        )?;
+        if copy_removed && let Some(target_path) = &version.target_path {
+            tx.execute(
+                "DELETE FROM target_files WHERE path=?1",
+                params![path_to_blob(target_path)?],
+            )?;
+            tx.execute(
+                "DELETE FROM transformed_files WHERE path=?1",
+                params![path_to_blob(target_path)?],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Source files catalogued with this size and modification time, e.g. candidates for having
+    /// been renamed to a new path with the same metadata.
+    pub fn source_paths_with_metadata(
+        &self,
+        namespace: &str,
+        last_modified: SystemTime,
+        size: u64,
+    ) -> Result<Vec<PathBuf>> {
+        let conn = self.read_connection()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT path FROM source_files WHERE namespace=?1 AND size=?2 AND mtime=?3",
+        )?;
+        let paths = stmt
+            .query_map(
+                params![namespace, size as i64, system_time_as_i64(last_modified)?],
+                |r| Ok(r.get::<_, StoredPath>(0)?.0),
+            )?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(paths)
+    }
+
+    pub fn source_paths_with_digest(
+        &self,
+        namespace: &str,
+        digest: &ContentHash,
+    ) -> Result<Vec<PathBuf>> {
+        let conn = self.read_connection()?;
+        let mut stmt =
+            conn.prepare_cached("SELECT path FROM source_files WHERE namespace=?1 AND digest=?2")?;
+        let paths = stmt
+            .query_map(params![namespace, digest], |r| {
+                Ok(r.get::<_, StoredPath>(0)?.0)
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(paths)
+    }
+
+    /// Moves the row of a source file renamed from `from` to `to`, keeping the run which first
+    /// transferred it. Returns false if there's no longer a row at `from`, e.g. because another
+    /// file claimed it first.
+    pub fn rename_source(
+        &self,
+        run: RunId,
+        namespace: &str,
+        from: &Path,
+        to: &Path,
+        last_modified: SystemTime,
+        size: u64,
+    ) -> Result<bool> {
+        let mut conn = self.acquire_connection();
+        let tx = conn.transaction()?;
+        let (from, to) = (path_to_blob(from)?, path_to_blob(to)?);
+        let renamed = tx.execute(
+            "UPDATE source_files SET path=?3, mtime=?4, size=?5 WHERE namespace=?1 AND path=?2",
+            params![
+                namespace,
+                from,
+                to,
+                system_time_as_i64(last_modified)?,
+                size as i64
+            ],
+        )?;
+        if renamed == 0 {
+            return Ok(false);
+        }
+        tx.execute(
+            "INSERT INTO source_renames (namespace, from_path, to_path, run_id) \
+             VALUES (?1, ?2, ?3, ?4)",
+            params![namespace, from, to, run],
+        )?;
+        tx.commit()?;
+        Ok(true)
+    }
+
+    pub fn begin_run(&self, namespace: &str) -> Result<RunId> {
+        let conn = self.acquire_connection();
+        conn.execute(
+            "INSERT INTO runs (namespace, started_at, status) VALUES (?1, ?2, ?3)",
+            params![
+                namespace,
+                system_time_as_i64(SystemTime::now())?,
+                RunStatus::Running.as_str()
+            ],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    /// The most recent run for `namespace`, if there has been one.
+    pub fn last_run(&self, namespace: &str) -> Result<Option<RunId>> {
+        let conn = self.read_connection()?;
+        let mut stmt = conn.prepare_cached("SELECT MAX(id) FROM runs WHERE namespace=?1")?;
+        Ok(stmt.query_row(params![namespace], |r| r.get(0))?)
+    }
+
+    /// Records how `run` finished, after which the rows it replaced can't be restored.
+    pub fn finish_run(&self, run: RunId, status: RunStatus) -> Result<()> {
+        let mut conn = self.acquire_connection();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "UPDATE runs SET finished_at=?2, status=?3 WHERE id=?1",
+            params![run, system_time_as_i64(SystemTime::now())?, status.as_str()],
+        )?;
+        for table in RUN_TABLES {
+            tx.execute(
+                &format!("DELETE FROM {REPLACED_PREFIX}{table} WHERE replaced_by=?1"),
+                params![run],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn record_run_args(&self, run: RunId, args: &str) -> Result<()> {
+        self.acquire_connection()
+            .execute("UPDATE runs SET args=?2 WHERE id=?1", params![run, args])?;
+        Ok(())
+    }
+
+    pub fn record_run_counts(&self, run: RunId, counts: &RunCounts) -> Result<()> {
+        self.acquire_connection().execute(
+            "UPDATE runs SET files_detected=?2, files_transferred=?3, bytes_transferred=?4,
+                 files_deduplicated=?5, files_failed=?6
+             WHERE id=?1",
+            params![
+                run,
+                counts.files_detected as i64,
+                counts.files_transferred as i64,
+                counts.bytes_transferred as i64,
+                counts.files_deduplicated as i64,
+                counts.files_failed as i64,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// The latest `limit` runs, or just `run`, newest first.
+    pub fn runs(&self, limit: usize, run: Option<RunId>) -> Result<Vec<RunRecord>> {
+        let conn = self.read_connection()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, namespace, status, started_at, finished_at, args, files_detected,
+                 files_transferred, bytes_transferred, files_deduplicated, files_failed
+             FROM runs WHERE ?2 IS NULL OR id=?2 ORDER BY id DESC LIMIT ?1",
+        )?;
+        let runs = stmt
+            .query_map(params![limit as i64, run], |r| {
+                let count = |idx: usize| r.get::<_, Option<i64>>(idx).map(|n| n.map(|n| n as u64));
+                let counts = match (count(6)?, count(7)?, count(8)?, count(9)?, count(10)?) {
+                    (
+                        Some(detected),
+                        Some(transferred),
+                        Some(bytes),
+                        Some(deduplicated),
+                        Some(failed),
+                    ) => Some(RunCounts {
+                        files_detected: detected,
+                        files_transferred: transferred,
+                        bytes_transferred: bytes,
+                        files_deduplicated: deduplicated,
+                        files_failed: failed,
+                    }),
+                    _ => None,
+                };
+                Ok(RunRecord {
+                    id: r.get(0)?,
+                    namespace: r.get(1)?,
+                    status: r.get(2)?,
+                    started_at: i64_as_system_time(r.get(3)?),
+                    finished_at: r.get::<_, Option<i64>>(4)?.map(i64_as_system_time),
+                    args: r.get(5)?,
+                    counts,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(runs)
+    }
+
+    /// The files `run` catalogued which are still catalogued as it left them.
+    pub fn files_added_by_run(&self, run: RunId) -> Result<Vec<AddedFile>> {
+        let conn = self.read_connection()?;
+        let mut added = Vec::new();
+        let mut stmt = conn.prepare(
+            "SELECT namespace, path FROM source_files WHERE run_id=?1 ORDER BY namespace, path",
+        )?;
+        for row in stmt.query_map(params![run], |r| {
+            Ok(AddedFile::Source(r.get(0)?, r.get::<_, StoredPath>(1)?.0))
+        })? {
+            added.push(row?);
+        }
+        for (table, added_file) in [
+            (
+                "old_target_files",
+                AddedFile::OldOut as fn(PathBuf) -> AddedFile,
+            ),
+            ("target_files", AddedFile::Out),
+        ] {
+            let mut stmt = conn.prepare(&format!(
+                "SELECT path FROM {table} WHERE run_id=?1 ORDER BY path"
+            ))?;
+            for row in stmt.query_map(params![run], |r| {
+                Ok(added_file(r.get::<_, StoredPath>(0)?.0))
+            })? {
+                added.push(row?);
+            }
+        }
+        Ok(added)
+    }
+
+    /// The source files catalogued, archived as new versions, renamed, or deleted by `--move`, by
+    /// the runs after `after` up to and including `upto`, by namespace and path. A file renamed
+    /// is removed from where it was and added where it is now.
+    pub fn source_changes_between(&self, after: RunId, upto: RunId) -> Result<Vec<SourceChange>> {
+        let conn = self.read_connection()?;
+        let mut changes = Vec::new();
+        // the first version archived by these runs, and the one before it.
+        let mut stmt = conn.prepare(
+            "SELECT s.namespace, s.path, s.size, s.digest, (
+                 SELECT p.digest FROM source_versions AS p
+                 WHERE p.namespace=s.namespace AND p.path=s.path AND p.version=(
+                     SELECT MIN(v.version) - 1 FROM source_versions AS v
+                     WHERE v.namespace=s.namespace AND v.path=s.path AND v.version > 1
+                         AND v.run_id > ?1 AND v.run_id <= ?2
+                 )
+             )
+             FROM source_files AS s WHERE s.run_id > ?1 AND s.run_id <= ?2",
+        )?;
+        for row in stmt.query_map(params![after, upto], |r| {
+            let previous_digest: Option<ContentHash> = r.get(4)?;
+            Ok(SourceChange {
+                change: match previous_digest {
+                    Some(_) => Change::Changed,
+                    None => Change::Added,
+                },
+                namespace: r.get(0)?,
+                path: r.get::<_, StoredPath>(1)?.0,
+                size: r.get::<_, i64>(2)? as u64,
+                digest: r.get(3)?,
+                previous_digest,
+            })
+        })? {
+            changes.push(row?);
+        }
+        let mut stmt = conn.prepare(
+            "SELECT namespace, path, size, digest FROM deleted_sources
+             WHERE run_id > ?1 AND run_id <= ?2",
+        )?;
+        for row in stmt.query_map(params![after, upto], |r| {
+            Ok(SourceChange {
+                change: Change::Removed,
+                namespace: r.get(0)?,
+                path: r.get::<_, StoredPath>(1)?.0,
+                size: r.get::<_, i64>(2)? as u64,
+                digest: r.get(3)?,
+                previous_digest: None,
+            })
+        })? {
+            changes.push(row?);
+        }
+        // a file renamed more than once is followed from where it first was to where it ended up.
+        let mut renamed_from = HashMap::<(String, PathBuf), PathBuf>::new();
+        let mut stmt = conn.prepare(
+            "SELECT namespace, from_path, to_path FROM source_renames
+             WHERE run_id > ?1 AND run_id <= ?2 ORDER BY rowid",
+        )?;
+        for row in stmt.query_map(params![after, upto], |r| {
+            Ok((
+                r.get::<_, String>(0)?,
+                r.get::<_, StoredPath>(1)?.0,
+                r.get::<_, StoredPath>(2)?.0,
+            ))
+        })? {
+            let (namespace, from, to) = row?;
+            let first = (renamed_from.remove(&(namespace.clone(), from.clone()))).unwrap_or(from);
+            renamed_from.insert((namespace, to), first);
+        }
+        let mut stmt = conn.prepare(
+            "SELECT size, digest, run_id FROM source_files WHERE namespace=?1 AND path=?2",
+        )?;
+        for ((namespace, to), from) in renamed_from {
+            let now = stmt
+                .query_row(params![namespace, path_to_blob(&to)?], |r| {
+                    Ok((
+                        r.get::<_, i64>(0)? as u64,
+                        r.get::<_, ContentHash>(1)?,
+                        r.get::<_, Option<RunId>>(2)?,
+                    ))
+                })
+                .optional()?;
+            // e.g. renamed back, deleted since, or catalogued by these runs, so already added.
+            let Some((size, digest, run)) = now else {
+                continue;
+            };
+            if from == to || run.is_some_and(|run| after < run && run <= upto) {
+                continue;
+            }
+            for (change, path) in [(Change::Removed, from), (Change::Added, to)] {
+                changes.push(SourceChange {
+                    change,
+                    namespace: namespace.clone(),
+                    path,
+                    size,
+                    digest,
+                    previous_digest: None,
+                });
+            }
+        }
+        changes.sort_by(|a, b| (&a.namespace, &a.path).cmp(&(&b.namespace, &b.path)));
+        Ok(changes)
+    }
+
+    pub fn record_snapshot(&self, run: RunId, snapshot: &str) -> Result<()> {
+        self.acquire_connection().execute(
+            "UPDATE runs SET snapshot=?2 WHERE id=?1",
+            params![run, snapshot],
+        )?;
+        Ok(())
+    }
+
+    pub fn save_profile(&self, name: &str, args: &[String]) -> Result<()> {
+        self.acquire_connection().execute(
+            "INSERT OR REPLACE INTO profiles (name, args, updated_at) VALUES (?1, ?2, ?3)",
+            params![
+                name,
+                serde_json::to_string(args)?,
+                system_time_as_i64(SystemTime::now())?
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn profile(&self, name: &str) -> Result<Option<Vec<String>>> {
+        let args: Option<String> = self
+            .read_connection()?
+            .query_row(
+                "SELECT args FROM profiles WHERE name=?1",
+                params![name],
+                |r| r.get(0),
+            )
+            .optional()?;
+        Ok(args.map(|a| serde_json::from_str(&a)).transpose()?)
+    }
+
+    pub fn profiles(&self) -> Result<Vec<(String, Vec<String>)>> {
+        let conn = self.read_connection()?;
+        let mut stmt = conn.prepare("SELECT name, args FROM profiles ORDER BY name")?;
+        let rows = stmt.query_map([], |r| Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?)))?;
+        rows.map(|row| {
+            let (name, args) = row?;
+            Ok((name, serde_json::from_str(&args)?))
+        })
+        .collect()
+    }
+
+    /// Returns whether there was a profile to delete.
+    pub fn delete_profile(&self, name: &str) -> Result<bool> {
+        let deleted = self
+            .acquire_connection()
+            .execute("DELETE FROM profiles WHERE name=?1", params![name])?;
+        Ok(deleted > 0)
+    }
+
+    /// Up to `limit` files from the old out directory which haven't been verified since `before`,
+    /// those verified longest ago (or never) first.
+    pub fn files_to_scrub(&self, before: SystemTime, limit: usize) -> Result<Vec<CataloguedFile>> {
+        let conn = self.read_connection()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT path, mtime, size, digest FROM old_target_files \
+             WHERE last_verified IS NULL OR last_verified<?1 \
+             ORDER BY last_verified, path LIMIT ?2",
+        )?;
+        let candidates = stmt
+            .query_map(
+                params![
+                    system_time_as_i64(before)?,
+                    i64::try_from(limit).unwrap_or(i64::MAX)
+                ],
+                |r| {
+                    Ok(CataloguedFile {
+                        path: r.get::<_, StoredPath>(0)?.0,
+                        mtime: i64_as_system_time(r.get(1)?),
+                        size: r.get::<_, i64>(2)? as u64,
+                        digest: r.get(3)?,
+                    })
+                },
+            )?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(candidates)
+    }
+
+    /// Every file catalogued in the old out directory.
+    pub fn old_target_files(&self) -> Result<Vec<CataloguedFile>> {
+        self.catalogued_files("old_target_files", "TRUE", [])
+    }
+
+    /// Every file catalogued in the out directory.
+    pub fn target_files(&self) -> Result<Vec<CataloguedFile>> {
+        self.catalogued_files("target_files", "TRUE", [])
+    }
+
+    /// The files in the out directory last written by `run`.
+    pub fn target_files_written_by(&self, run: RunId) -> Result<Vec<CataloguedFile>> {
+        self.catalogued_files("target_files", "run_id=?1", [run])
+    }
+
+    fn catalogued_files(
+        &self,
+        table: &str,
+        condition: &str,
+        params: impl rusqlite::Params,
+    ) -> Result<Vec<CataloguedFile>> {
+        let conn = self.read_connection()?;
+        let mut stmt = conn.prepare_cached(&format!(
+            "SELECT path, mtime, size, digest FROM {table} WHERE {condition} ORDER BY path"
+        ))?;
+        let files = stmt
+            .query_map(params, |r| {
+                Ok(CataloguedFile {
+                    path: r.get::<_, StoredPath>(0)?.0,
+                    mtime: i64_as_system_time(r.get(1)?),
+                    size: r.get::<_, i64>(2)? as u64,
+                    digest: r.get(3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(files)
+    }
+
+    /// Every content catalogued at more than one path in the archive, old out directory first.
+    pub fn duplicates(&self) -> Result<Vec<Duplicates>> {
+        let conn = self.read_connection()?;
+        let mut stmt = conn.prepare_cached(
+            "WITH files AS ( \
+                 SELECT digest, size, 0 AS archive, path FROM old_target_files \
+                 UNION ALL \
+                 SELECT digest, size, 1 AS archive, path FROM target_files \
+             ) \
+             SELECT digest, size, archive, path FROM files \
+             WHERE digest IN (SELECT digest FROM files GROUP BY digest HAVING COUNT(*)>1) \
+             ORDER BY digest, archive, path",
+        )?;
+        let mut rows = stmt.query([])?;
+        let mut duplicates = Vec::<Duplicates>::new();
+        while let Some(row) = rows.next()? {
+            let digest: ContentHash = row.get(0)?;
+            let archive = if row.get::<_, bool>(2)? {
+                Archive::Out
+            } else {
+                Archive::OldOut
+            };
+            let file = (archive, row.get::<_, StoredPath>(3)?.0);
+            match duplicates.last_mut() {
+                Some(group) if group.digest == digest => group.files.push(file),
+                _ => duplicates.push(Duplicates {
+                    digest,
+                    size: row.get::<_, i64>(1)? as u64,
+                    files: vec![file],
+                }),
+            }
+        }
+        Ok(duplicates)
+    }
+
+    /// Every file transferred from an in directory, in the order they were catalogued.
+    pub fn source_files(&self) -> Result<Vec<SourceFile>> {
+        let conn = self.read_connection()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT namespace, path, size, digest, \
+                 digest IN (SELECT digest FROM old_target_files) \
+             FROM source_files ORDER BY run_id, rowid",
+        )?;
+        let files = stmt
+            .query_map([], |r| {
+                Ok(SourceFile {
+                    namespace: r.get(0)?,
+                    path: r.get::<_, StoredPath>(1)?.0,
+                    size: r.get::<_, i64>(2)? as u64,
+                    digest: r.get(3)?,
+                    in_old_target: r.get(4)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(files)
+    }
+
+    pub fn mark_verified(&self, path: &Path, at: SystemTime) -> Result<()> {
+        self.acquire_connection().execute(
+            "UPDATE old_target_files SET last_verified=?1 WHERE path=?2",
+            params![system_time_as_i64(at)?, path_to_blob(path)?],
+        )?;
+        Ok(())
+    }
+
+    /// Every path in the old out and out directories, and every path in `namespace`'s in directory,
+    /// which was catalogued with `digest`.
+    pub fn copies_of(&self, digest: &ContentHash, namespace: &str) -> Result<Vec<FileCopy>> {
+        let conn = self.read_connection()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT 0, path FROM old_target_files WHERE digest=?1 \
+             UNION ALL \
+             SELECT 1, path FROM target_files WHERE digest=?1 \
+             UNION ALL \
+             SELECT 2, path FROM source_files WHERE digest=?1 AND namespace=?2",
+        )?;
+        let copies = stmt
+            .query_map(params![digest, namespace], |r| {
+                let path = r.get::<_, StoredPath>(1)?.0;
+                Ok(match r.get::<_, u8>(0)? {
+                    0 => FileCopy::OldTarget(path),
+                    1 => FileCopy::Target(path),
+                    _ => FileCopy::Source(path),
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(copies)
+    }
+
+    pub fn record_repair(&self, repair: &Repair) -> Result<()> {
+        self.acquire_connection().execute(
+            "INSERT INTO repairs (path, digest, source, repaired_at) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                path_to_blob(&repair.path)?,
+                repair.digest,
+                repair.source,
+                system_time_as_i64(repair.repaired_at)?,
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn repairs(&self) -> Result<Vec<Repair>> {
+        let conn = self.read_connection()?;
+        let mut stmt = conn
+            .prepare_cached("SELECT path, digest, source, repaired_at FROM repairs ORDER BY id")?;
+        let repairs = stmt
+            .query_map([], |r| {
+                Ok(Repair {
+                    path: r.get::<_, StoredPath>(0)?.0,
+                    digest: r.get(1)?,
+                    source: r.get(2)?,
+                    repaired_at: i64_as_system_time(r.get(3)?),
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(repairs)
+    }
+
+    /// Files in the old out directory not covered by parity of their current content.
+    pub fn files_without_parity(&self) -> Result<Vec<ParityMember>> {
+        let conn = self.read_connection()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT o.path, o.size, o.digest FROM old_target_files o \
+             LEFT JOIN parity_members p ON p.path=o.path AND p.digest=o.digest \
+             WHERE p.path IS NULL ORDER BY o.path",
+        )?;
+        let files = stmt
+            .query_map([], |r| {
+                Ok(ParityMember {
+                    path: r.get::<_, StoredPath>(0)?.0,
+                    size: r.get::<_, i64>(1)? as u64,
+                    digest: r.get(2)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(files)
+    }
+
+    pub fn add_parity_set(&self, set: &ParitySet, at: SystemTime) -> Result<()> {
+        let mut conn = self.acquire_connection();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT INTO parity_sets (digest, created_at) VALUES (?1, ?2)",
+            params![set.digest, system_time_as_i64(at)?],
+        )?;
+        let id = tx.last_insert_rowid();
+        for member in &set.members {
+            tx.execute(
+                "INSERT INTO parity_members (set_id, path, size, digest) VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    id,
+                    path_to_blob(&member.path)?,
+                    member.size as i64,
+                    member.digest
+                ],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// The newest parity set covering `path` with content `digest`.
+    pub fn parity_set_of(&self, path: &Path, digest: &ContentHash) -> Result<Option<ParitySet>> {
+        let conn = self.read_connection()?;
+        let set = conn
+            .prepare_cached(
+                "SELECT s.id, s.digest FROM parity_sets s \
+                 JOIN parity_members p ON p.set_id=s.id \
+                 WHERE p.path=?1 AND p.digest=?2 ORDER BY s.id DESC LIMIT 1",
+            )?
+            .query_row(params![path_to_blob(path)?, digest], |r| {
+                Ok((r.get::<_, i64>(0)?, r.get::<_, ContentHash>(1)?))
+            })
+            .optional()?;
+        let Some((id, digest)) = set else {
+            return Ok(None);
+        };
+        let members = conn
+            .prepare_cached(
+                "SELECT path, size, digest FROM parity_members WHERE set_id=?1 ORDER BY path",
+            )?
+            .query_map(params![id], |r| {
+                Ok(ParityMember {
+                    path: r.get::<_, StoredPath>(0)?.0,
+                    size: r.get::<_, i64>(1)? as u64,
+                    digest: r.get(2)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(Some(ParitySet { digest, members }))
+    }
+
+    /// Files in the out directory whose content isn't in any bundle yet, oldest first.
+    pub fn files_without_bundle(&self) -> Result<Vec<BundleMember>> {
+        let conn = self.read_connection()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT t.path, t.size, t.digest FROM target_files t \
+             WHERE NOT EXISTS (SELECT 1 FROM bundle_members b WHERE b.digest=t.digest) \
+             ORDER BY t.run_id, t.path",
+        )?;
+        let files = stmt
+            .query_map([], |r| {
+                Ok(BundleMember {
+                    path: r.get::<_, StoredPath>(0)?.0,
+                    size: r.get::<_, i64>(1)? as u64,
+                    digest: r.get(2)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(files)
+    }
+
+    pub fn bundle_count(&self) -> Result<u64> {
+        let conn = self.read_connection()?;
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM bundles", [], |r| r.get(0))?;
+        Ok(count as u64)
+    }
+
+    pub fn add_bundle(&self, bundle: &Bundle, at: SystemTime) -> Result<()> {
+        let mut conn = self.acquire_connection();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT INTO bundles (name, digest, size, created_at) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                bundle.name,
+                bundle.digest,
+                bundle.size as i64,
+                system_time_as_i64(at)?
+            ],
+        )?;
+        let id = tx.last_insert_rowid();
+        for member in &bundle.members {
+            tx.execute(
+                "INSERT INTO bundle_members (bundle_id, path, digest) VALUES (?1, ?2, ?3)",
+                params![id, path_to_blob(&member.path)?, member.digest],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// The bundles holding `path`, with the digest each bundle should have.
+    pub fn bundles_of(&self, path: &Path) -> Result<Vec<(String, ContentHash)>> {
+        let conn = self.read_connection()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT b.name, b.digest FROM bundles b \
+             JOIN bundle_members m ON m.bundle_id=b.id WHERE m.path=?1 ORDER BY b.id",
+        )?;
+        let bundles = stmt
+            .query_map(params![path_to_blob(path)?], |r| Ok((r.get(0)?, r.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(bundles)
+    }
+
+    pub fn image_fingerprint(&self, digest: &ContentHash) -> Result<Option<ImageFingerprint>> {
+        let conn = self.read_connection()?;
+        let fingerprint = conn
+            .prepare_cached("SELECT width, height, phash FROM image_fingerprints WHERE digest=?1")?
+            .query_row(params![digest], |r| {
+                Ok(ImageFingerprint {
+                    width: r.get(0)?,
+                    height: r.get(1)?,
+                    // stored as its bits, as sqlite integers are signed.
+                    hash: r.get::<_, i64>(2)? as u64,
+                })
+            })
+            .optional()?;
+        Ok(fingerprint)
+    }
+
+    pub fn record_image_fingerprint(
+        &self,
+        digest: &ContentHash,
+        fingerprint: &ImageFingerprint,
+    ) -> Result<()> {
+        self.acquire_connection().execute(
+            "INSERT OR REPLACE INTO image_fingerprints (digest, width, height, phash) \
+             VALUES (?1, ?2, ?3, ?4)",
+            params![
+                digest,
+                fingerprint.width,
+                fingerprint.height,
+                fingerprint.hash as i64
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn record_media_metadata(
         &self,
-        path: &Path,
-        digest: &Sha256Hash,
-        last_modified: SystemTime,
-        size: u64,
+        digest: &ContentHash,
+        metadata: &MediaMetadata,
     ) -> Result<()> {
         self.acquire_connection().execute(
-            "INSERT INTO source_files (path, mtime, size, digest)
-             VALUES (?1, ?2, ?3, ?4)",
+            "INSERT OR REPLACE INTO media_metadata \
+             (digest, media_type, taken_at, camera_make, camera_model, has_gps, width, height, \
+              screenshot, burst_id) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
             params![
-                path_to_text(path)?,
-                system_time_as_i64(last_modified)?,
-                size as i64,
                 digest,
+                metadata.media_type.as_str(),
+                // sortable, and understood by sqlite's date functions.
+                metadata
+                    .taken_at
+                    .map(|taken_at| taken_at.format("%Y-%m-%d %H:%M:%S").to_string()),
+                metadata.camera_make,
+                metadata.camera_model,
+                metadata.has_gps,
+                metadata.width,
+                metadata.height,
+                metadata.screenshot,
+                metadata.burst_id,
             ],
         )?;
         Ok(())
     }
+
+    /// The content whose metadata has been indexed.
+    pub fn media_metadata_digests(&self) -> Result<HashSet<ContentHash>> {
+        let conn = self.read_connection()?;
+        let mut stmt = conn.prepare("SELECT digest FROM media_metadata")?;
+        let digests = stmt
+            .query_map([], |r| r.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(digests)
+    }
+
+    /// Every file row in the catalogue, or only those written by runs after `since`.
+    pub fn manifest_entries(&self, since: Option<RunId>) -> Result<Vec<ManifestEntry>> {
+        let conn = self.read_connection()?;
+        let mut entries = conn
+            .prepare(
+                "SELECT path, mtime, size, digest FROM old_target_files \
+                 WHERE ?1 IS NULL OR run_id>?1 ORDER BY path",
+            )?
+            .query_map(params![since], |r| {
+                Ok(ManifestEntry::OldTarget {
+                    path: manifest_path(r.get(0)?),
+                    mtime: r.get(1)?,
+                    size: r.get::<_, i64>(2)? as u64,
+                    digest: r.get(3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        entries.extend(
+            conn.prepare(
+                "SELECT path, mtime, size, digest FROM target_files \
+                 WHERE ?1 IS NULL OR run_id>?1 ORDER BY path",
+            )?
+            .query_map(params![since], |r| {
+                Ok(ManifestEntry::Target {
+                    path: manifest_path(r.get(0)?),
+                    mtime: r.get(1)?,
+                    size: r.get::<_, i64>(2)? as u64,
+                    digest: r.get(3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?,
+        );
+        entries.extend(
+            conn.prepare(
+                "SELECT namespace, path, mtime, size, digest FROM source_files \
+                 WHERE ?1 IS NULL OR run_id>?1 ORDER BY namespace, path",
+            )?
+            .query_map(params![since], |r| {
+                Ok(ManifestEntry::Source {
+                    namespace: r.get(0)?,
+                    path: manifest_path(r.get(1)?),
+                    mtime: r.get(2)?,
+                    size: r.get::<_, i64>(3)? as u64,
+                    digest: r.get(4)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?,
+        );
+        Ok(entries)
+    }
+
+    /// Adds `entries` to the catalogue, replacing any rows for the same files. They aren't
+    /// attributed to any run.
+    pub fn add_manifest_entries(&self, entries: &[ManifestEntry]) -> Result<()> {
+        let mut conn = self.acquire_connection();
+        let tx = conn.transaction()?;
+        for entry in entries {
+            match entry {
+                ManifestEntry::OldTarget {
+                    path,
+                    mtime,
+                    size,
+                    digest,
+                } => tx.execute(
+                    "INSERT OR REPLACE INTO old_target_files (path, mtime, size, digest)
+                     VALUES (?1, ?2, ?3, ?4)",
+                    params![path.as_bytes(), mtime, *size as i64, digest],
+                )?,
+                ManifestEntry::Target {
+                    path,
+                    mtime,
+                    size,
+                    digest,
+                } => tx.execute(
+                    "INSERT OR REPLACE INTO target_files (path, mtime, size, digest)
+                     VALUES (?1, ?2, ?3, ?4)",
+                    params![path.as_bytes(), mtime, *size as i64, digest],
+                )?,
+                ManifestEntry::Source {
+                    namespace,
+                    path,
+                    mtime,
+                    size,
+                    digest,
+                } => tx.execute(
+                    "INSERT OR REPLACE INTO source_files (namespace, path, mtime, size, digest)
+                     VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![namespace, path.as_bytes(), mtime, *size as i64, digest],
+                )?,
+            };
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Deletes every row written by `run`, putting back those it replaced as they were. Files it
+    /// already wrote to the out directory stay, and are recognised by their content when a later
+    /// run gets to them, or can be adopted.
+    pub fn roll_back_run(&self, run: RunId) -> Result<()> {
+        let mut conn = self.acquire_connection();
+        let tx = conn.transaction()?;
+        for table in RUN_TABLES {
+            tx.execute(
+                &format!("DELETE FROM {table} WHERE run_id=?1"),
+                params![run],
+            )?;
+        }
+        for table in RUN_TABLES {
+            let replaced = format!("{REPLACED_PREFIX}{table}");
+            let list = table_columns(&tx, table)?.0.join(", ");
+            tx.execute(
+                &format!(
+                    "INSERT OR REPLACE INTO {table} ({list})
+                     SELECT {list} FROM {replaced} WHERE replaced_by=?1"
+                ),
+                params![run],
+            )?;
+            tx.execute(
+                &format!("DELETE FROM {replaced} WHERE replaced_by=?1"),
+                params![run],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+/// The catalogue's tables, leaving out sqlite's own and those derived from the others, in the
+/// order they were created.
+fn table_names(conn: &Connection) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare(
+        "SELECT name FROM sqlite_master WHERE type='table' AND name NOT LIKE 'sqlite_%' \
+         ORDER BY rowid",
+    )?;
+    let names = stmt
+        .query_map([], |r| r.get::<_, String>(0))?
+        .filter(|name| {
+            name.as_ref().map_or(true, |name| {
+                !DERIVED_TABLES.contains(&name.as_str()) && !name.starts_with(REPLACED_PREFIX)
+            })
+        })
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(names)
+}
+
+/// The columns of `table`, and those of its primary key, in order.
+fn table_columns(conn: &Connection, table: &str) -> Result<(Vec<String>, Vec<String>)> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", quote_identifier(table)))?;
+    let mut columns: Vec<(String, i64)> = stmt
+        .query_map([], |r| Ok((r.get(1)?, r.get(5)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+    let names = columns.iter().map(|(name, _)| name.clone()).collect();
+    columns.retain(|(_, key)| *key > 0);
+    columns.sort_by_key(|(_, key)| *key);
+    Ok((names, columns.into_iter().map(|(name, _)| name).collect()))
+}
+
+fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
 }
 
 fn system_time_as_i64(t: SystemTime) -> Result<i64> {
@@ -208,19 +3818,57 @@ fn i64_as_system_time(t: i64) -> SystemTime {
     }
 }
 
-fn path_to_text(p: &Path) -> Result<String> {
+/// `p` as it's stored: its raw bytes, so that file names which aren't UTF-8 can be catalogued.
+#[cfg(unix)]
+fn path_to_blob(p: &Path) -> Result<Vec<u8>> {
+    use std::os::unix::ffi::OsStrExt;
+    Ok(p.as_os_str().as_bytes().to_vec())
+}
+
+/// Elsewhere, e.g. on Windows, paths are stored with `/` between their components, as on every
+/// other platform, so a catalogue can be shared with or moved to one.
+#[cfg(not(unix))]
+fn path_to_blob(p: &Path) -> Result<Vec<u8>> {
+    use eyre::ContextCompat;
     p.to_str()
-        .map(|s| s.to_string())
+        .map(|s| s.replace('\\', "/").into_bytes())
         .wrap_err("could not convert to bytes")
 }
 
+/// The path of a manifest entry. Manifests are JSON, so a name which isn't UTF-8 is written with
+/// its invalid bytes replaced.
+fn manifest_path(path: StoredPath) -> String {
+    path.0.to_string_lossy().into_owned()
+}
+
+/// A path read back from the catalogue. Paths are stored as their bytes, but databases written
+/// before that may still have some as text.
+struct StoredPath(PathBuf);
+
+impl FromSql for StoredPath {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        let bytes = match value {
+            ValueRef::Text(bytes) | ValueRef::Blob(bytes) => bytes,
+            _ => return Err(FromSqlError::InvalidType),
+        };
+        #[cfg(unix)]
+        let path = {
+            use std::os::unix::ffi::OsStrExt;
+            PathBuf::from(std::ffi::OsStr::from_bytes(bytes))
+        };
+        #[cfg(not(unix))]
+        let path = PathBuf::from(String::from_utf8_lossy(bytes).into_owned());
+        Ok(Self(path))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::time::{Duration, SystemTime};
 
-    fn dummy_digest(n: u8) -> Sha256Hash {
-        Sha256Hash::new_for_tests(n)
+    fn dummy_digest(n: u8) -> ContentHash {
+        ContentHash::new_for_tests(n)
     }
 
     #[test]
@@ -238,14 +3886,17 @@ mod tests {
             WasTransferredFromSourceResult::New
         ));
         assert_eq!(
-            store.was_transferred_from_source(path, now, size).unwrap(),
+            store
+                .was_transferred_from_source("", path, now, size)
+                .unwrap(),
             WasTransferredFromSourceResult::New
         );
         assert!(!store.exists_in_target(&digest_a).unwrap());
 
         // Mark as already present in old target.
+        let run = store.begin_run("").unwrap();
         store
-            .mark_exists_in_old_target(path, now, size, &digest_a)
+            .mark_exists_in_old_target(run, path, now, size, &digest_a)
             .unwrap();
         assert!(matches!(
             store.exists_in_old_target(path, now, size).unwrap(),
@@ -261,14 +3912,522 @@ mod tests {
         let later = now + Duration::from_secs(10);
         let size2 = 5678u64;
         store
-            .mark_transferred_from_source(path, &digest_b, later, size2)
+            .mark_transferred_from_source(run, "", path, &digest_b, later, size2)
             .unwrap();
         assert_eq!(
             store
-                .was_transferred_from_source(path, later, size2)
+                .was_transferred_from_source("", path, later, size2)
                 .unwrap(),
             WasTransferredFromSourceResult::Transferred
         );
         assert!(store.exists_in_target(&digest_b).unwrap());
     }
+
+    #[test]
+    fn reads_do_not_wait_for_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = PhotoSyncStore::new(dir.path().join("db.sqlite")).unwrap();
+        let run = store.begin_run("").unwrap();
+        let now = SystemTime::now();
+        store
+            .mark_exists_in_old_target(run, Path::new("a.jpg"), now, 1, &dummy_digest(1))
+            .unwrap();
+
+        // a write in progress, which reads don't see until it's committed.
+        let writer = store.acquire_connection();
+        writer
+            .execute_batch("BEGIN; DELETE FROM old_target_files;")
+            .unwrap();
+        assert!(store.exists_in_target(&dummy_digest(1)).unwrap());
+        writer.execute_batch("COMMIT").unwrap();
+        assert!(!store.exists_in_target(&dummy_digest(1)).unwrap());
+        drop(writer);
+
+        let mode: String = store
+            .read_connection()
+            .unwrap()
+            .query_row("PRAGMA journal_mode", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(mode, "wal");
+    }
+
+    #[test]
+    fn concurrent_reads_proceed_during_a_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = PhotoSyncStore::new(dir.path().join("db.sqlite")).unwrap();
+        let run = store.begin_run("").unwrap();
+        let now = SystemTime::now();
+        let files: Vec<_> = (1..=8)
+            .map(|n| CataloguedFile {
+                path: PathBuf::from(format!("{n}.jpg")),
+                mtime: now,
+                size: n.into(),
+                digest: dummy_digest(n),
+            })
+            .collect();
+        store.mark_exists_in_old_target_batch(run, &files).unwrap();
+
+        // as indexing the old out directory does, readers on many threads query the catalogue
+        // while another write is in progress, and each sees what was committed before it.
+        let writer = store.acquire_connection();
+        writer
+            .execute_batch("BEGIN; DELETE FROM old_target_files;")
+            .unwrap();
+        std::thread::scope(|s| {
+            for file in &files {
+                let store = &store;
+                s.spawn(move || {
+                    assert_eq!(
+                        store
+                            .exists_in_old_target(&file.path, file.mtime, file.size)
+                            .unwrap(),
+                        WasTransferredFromSourceResult::Transferred
+                    );
+                    assert!(store.exists_in_target(&file.digest).unwrap());
+                });
+            }
+        });
+        writer.execute_batch("COMMIT").unwrap();
+        drop(writer);
+        assert!(store.old_target_files().unwrap().is_empty());
+    }
+
+    #[test]
+    fn batches_are_written_together() {
+        let store = PhotoSyncStore::new_for_tests().unwrap();
+        let run = store.begin_run("laptop").unwrap();
+        let now = SystemTime::now();
+        let files: Vec<_> = (1..=3)
+            .map(|n| CataloguedFile {
+                path: PathBuf::from(format!("old/{n}.jpg")),
+                mtime: now,
+                size: n.into(),
+                digest: dummy_digest(n),
+            })
+            .collect();
+        store.mark_exists_in_old_target_batch(run, &files).unwrap();
+        assert_eq!(store.old_target_files().unwrap().len(), 3);
+
+        let sources: Vec<_> = (4..=5)
+            .map(|n| TransferredSource {
+                path: PathBuf::from(format!("{n}.jpg")),
+                digest: dummy_digest(n),
+                last_modified: now,
+                size: n.into(),
+                target_path: (n == 4).then(|| PathBuf::from("2024/4.jpg")),
+            })
+            .collect();
+        store
+            .mark_transferred_from_source_batch(run, "laptop", &sources)
+            .unwrap();
+        for source in &sources {
+            assert_eq!(
+                store
+                    .was_transferred_from_source("laptop", &source.path, now, source.size)
+                    .unwrap(),
+                WasTransferredFromSourceResult::Transferred
+            );
+        }
+        assert_eq!(store.source_files().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn source_files_are_namespaced() {
+        let store = PhotoSyncStore::new_for_tests().unwrap();
+        let path = Path::new("IMG_0001.HEIC");
+        let now = SystemTime::now();
+        let run = store.begin_run("laptop").unwrap();
+
+        store
+            .mark_transferred_from_source(run, "laptop", path, &dummy_digest(1), now, 10)
+            .unwrap();
+        assert_eq!(
+            store
+                .was_transferred_from_source("laptop", path, now, 10)
+                .unwrap(),
+            WasTransferredFromSourceResult::Transferred
+        );
+        assert_eq!(
+            store
+                .was_transferred_from_source("desktop", path, now, 10)
+                .unwrap(),
+            WasTransferredFromSourceResult::New
+        );
+
+        // the same path may be recorded for another machine without conflicting.
+        store
+            .mark_transferred_from_source(run, "desktop", path, &dummy_digest(2), now, 20)
+            .unwrap();
+    }
+
+    #[test]
+    fn failed_transfers_are_kept_until_transferred() {
+        let store = PhotoSyncStore::new_for_tests().unwrap();
+        let now = SystemTime::now();
+        let run = store.begin_run("laptop").unwrap();
+        let (a, b) = (Path::new("a.jpg"), Path::new("b.jpg"));
+
+        store
+            .record_failed_transfer(run, "laptop", a, "busy")
+            .unwrap();
+        store
+            .record_failed_transfer(run, "laptop", b, "busy")
+            .unwrap();
+        store
+            .record_failed_transfer(run, "laptop", a, "gone")
+            .unwrap();
+        assert_eq!(store.failed_transfers("laptop").unwrap().len(), 2);
+        assert!(store.failed_transfers("desktop").unwrap().is_empty());
+        let attempts: i64 = (store.read_connection().unwrap())
+            .query_row(
+                "SELECT attempts FROM failed_transfers WHERE reason='gone'",
+                [],
+                |r| r.get(0),
+            )
+            .unwrap();
+        assert_eq!(attempts, 2);
+
+        store
+            .mark_transferred_from_source(run, "laptop", a, &dummy_digest(1), now, 1)
+            .unwrap();
+        store.forget_failed_transfer("laptop", b).unwrap();
+        assert!(store.failed_transfers("laptop").unwrap().is_empty());
+    }
+
+    #[test]
+    fn renamed_sources_keep_their_row() {
+        let store = PhotoSyncStore::new_for_tests().unwrap();
+        let (from, to) = (Path::new("IMG_0001.HEIC"), Path::new("2020/IMG_0001.HEIC"));
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let first = store.begin_run("laptop").unwrap();
+        store
+            .mark_transferred_from_source(first, "laptop", from, &dummy_digest(1), now, 10)
+            .unwrap();
+        assert_eq!(
+            store.source_paths_with_metadata("laptop", now, 10).unwrap(),
+            [from]
+        );
+        assert_eq!(
+            store
+                .source_paths_with_digest("desktop", &dummy_digest(1))
+                .unwrap(),
+            Vec::<PathBuf>::new()
+        );
+
+        let second = store.begin_run("laptop").unwrap();
+        assert!(
+            store
+                .rename_source(second, "laptop", from, to, now, 10)
+                .unwrap()
+        );
+        assert!(
+            !store
+                .rename_source(second, "laptop", from, to, now, 10)
+                .unwrap()
+        );
+        assert_eq!(
+            store
+                .was_transferred_from_source("laptop", to, now, 10)
+                .unwrap(),
+            WasTransferredFromSourceResult::Transferred
+        );
+        assert_eq!(
+            store
+                .was_transferred_from_source("laptop", from, now, 10)
+                .unwrap(),
+            WasTransferredFromSourceResult::New
+        );
+        // the row still belongs to the run which transferred it.
+        store.roll_back_run(second).unwrap();
+        assert_eq!(store.source_files().unwrap()[0].path, to);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn paths_need_not_be_utf8() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let store = PhotoSyncStore::new_for_tests().unwrap();
+        let run = store.begin_run("laptop").unwrap();
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        // as written by a camera using Latin-1.
+        let path = Path::new(OsStr::from_bytes(b"caf\xe9.jpg"));
+        store
+            .mark_transferred_from_source(run, "laptop", path, &dummy_digest(1), now, 10)
+            .unwrap();
+        store
+            .mark_exists_in_old_target(run, path, now, 10, &dummy_digest(1))
+            .unwrap();
+        assert_eq!(
+            store
+                .was_transferred_from_source("laptop", path, now, 10)
+                .unwrap(),
+            WasTransferredFromSourceResult::Transferred
+        );
+        assert_eq!(store.old_target_files().unwrap()[0].path, path);
+    }
+
+    #[test]
+    fn moved_old_targets_replace_what_was_there() {
+        let store = PhotoSyncStore::new_for_tests().unwrap();
+        let run = store.begin_run("").unwrap();
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let (a, b) = (Path::new("a.jpg"), Path::new("2020/b.jpg"));
+        store
+            .mark_exists_in_old_target(run, a, now, 1, &dummy_digest(1))
+            .unwrap();
+        store
+            .mark_exists_in_old_target(run, b, now, 2, &dummy_digest(2))
+            .unwrap();
+        assert_eq!(store.old_target_paths_with_metadata(now, 1).unwrap(), [a]);
+        assert_eq!(
+            store
+                .old_target_paths_with_digest(&dummy_digest(2))
+                .unwrap(),
+            [b]
+        );
+
+        assert!(store.move_old_target(a, b, now, 1).unwrap());
+        assert!(!store.move_old_target(a, b, now, 1).unwrap());
+        let files = store.old_target_files().unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!((&*files[0].path, files[0].digest), (b, dummy_digest(1)));
+    }
+
+    #[test]
+    fn leases_are_exclusive_until_expiry() {
+        let store = PhotoSyncStore::new_for_tests().unwrap();
+        let ttl = Duration::from_secs(60);
+
+        store.acquire_lease("sync", "a", ttl).unwrap();
+        // renewing our own lease is fine, taking someone else's is not.
+        store.acquire_lease("sync", "a", ttl).unwrap();
+        assert!(store.acquire_lease("sync", "b", ttl).is_err());
+
+        store.release_lease("sync", "a").unwrap();
+        store.acquire_lease("sync", "b", ttl).unwrap();
+
+        // an expired lease can be taken over.
+        store.acquire_lease("other", "a", Duration::ZERO).unwrap();
+        std::thread::sleep(Duration::from_millis(1100));
+        store.acquire_lease("other", "b", ttl).unwrap();
+    }
+
+    #[test]
+    fn only_expired_or_own_leases_are_broken() {
+        let store = PhotoSyncStore::new_for_tests().unwrap();
+        let ttl = Duration::from_secs(60);
+        let laptop = store.begin_run("laptop").unwrap();
+        let desktop = store.begin_run("desktop").unwrap();
+        let status = |run| store.runs(1, Some(run)).unwrap().remove(0).status;
+
+        // another machine's live lease is left alone.
+        store.acquire_lease("sync", "desktop#1", ttl).unwrap();
+        assert!(store.break_lease("sync", "laptop").is_err());
+        assert!(store.acquire_lease("sync", "laptop#2", ttl).is_err());
+
+        // its own can be broken, ending only its runs.
+        assert_eq!(store.break_lease("sync", "desktop").unwrap(), 1);
+        assert_eq!(status(desktop), "aborted");
+        assert_eq!(status(laptop), "running");
+
+        // as can one which has expired, whoever held it.
+        store
+            .acquire_lease("sync", "laptop#3", Duration::ZERO)
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(1100));
+        assert_eq!(store.break_lease("sync", "desktop").unwrap(), 1);
+        assert_eq!(status(laptop), "aborted");
+        store.acquire_lease("sync", "desktop#4", ttl).unwrap();
+    }
+
+    #[test]
+    fn rolling_back_a_run_removes_only_its_rows() {
+        let store = PhotoSyncStore::new_for_tests().unwrap();
+        let now = SystemTime::now();
+
+        let first = store.begin_run("").unwrap();
+        store
+            .mark_transferred_from_source(first, "", Path::new("a"), &dummy_digest(1), now, 1)
+            .unwrap();
+        store
+            .mark_exists_in_target(first, Path::new("a"), now, 1, &dummy_digest(1))
+            .unwrap();
+        store.finish_run(first, RunStatus::Succeeded).unwrap();
+
+        let second = store.begin_run("").unwrap();
+        store
+            .mark_exists_in_old_target(second, Path::new("b"), now, 2, &dummy_digest(2))
+            .unwrap();
+        store
+            .mark_transferred_from_source(second, "", Path::new("c"), &dummy_digest(3), now, 3)
+            .unwrap();
+        // rows it replaced are put back as they were, however many times it replaced them.
+        for n in 4..=5 {
+            store
+                .update_source(second, "", Path::new("a"), &dummy_digest(n), now, n.into())
+                .unwrap();
+            store
+                .mark_exists_in_target(second, Path::new("a"), now, n.into(), &dummy_digest(n))
+                .unwrap();
+        }
+        store.roll_back_run(second).unwrap();
+        store.finish_run(second, RunStatus::RolledBack).unwrap();
+
+        assert!(store.exists_in_target(&dummy_digest(1)).unwrap());
+        for n in 2..=5 {
+            assert!(!store.exists_in_target(&dummy_digest(n)).unwrap(), "{n}");
+        }
+        let sources = store.source_files().unwrap();
+        assert_eq!(sources.len(), 1);
+        assert_eq!((sources[0].digest, sources[0].size), (dummy_digest(1), 1));
+
+        // and once a run has finished, what it replaced is gone for good.
+        let third = store.begin_run("").unwrap();
+        store
+            .update_source(third, "", Path::new("a"), &dummy_digest(6), now, 6)
+            .unwrap();
+        store.finish_run(third, RunStatus::Aborted).unwrap();
+        store.roll_back_run(third).unwrap();
+        assert!(store.source_files().unwrap().is_empty());
+    }
+
+    #[test]
+    fn digests_follow_the_rows_they_count() {
+        let store = PhotoSyncStore::new_for_tests().unwrap();
+        let now = SystemTime::now();
+        let run = store.begin_run("").unwrap();
+        let digests = || -> Vec<(ContentHash, i64)> {
+            let conn = store.acquire_connection();
+            let mut stmt = conn
+                .prepare("SELECT digest, refs FROM digests ORDER BY digest")
+                .unwrap();
+            stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?)))
+                .unwrap()
+                .collect::<rusqlite::Result<_>>()
+                .unwrap()
+        };
+
+        store
+            .mark_exists_in_old_target(run, Path::new("a"), now, 1, &dummy_digest(1))
+            .unwrap();
+        store
+            .mark_exists_in_old_target(run, Path::new("b"), now, 1, &dummy_digest(1))
+            .unwrap();
+        // replacing a row takes its old digest out.
+        store
+            .mark_exists_in_old_target(run, Path::new("b"), now, 2, &dummy_digest(2))
+            .unwrap();
+        store
+            .mark_transferred_from_source(run, "", Path::new("c"), &dummy_digest(3), now, 3)
+            .unwrap();
+        store.roll_back_run(run).unwrap();
+        assert!(digests().is_empty());
+        assert!(!store.exists_in_target(&dummy_digest(1)).unwrap());
+
+        store
+            .mark_exists_in_old_target(run, Path::new("a"), now, 1, &dummy_digest(1))
+            .unwrap();
+        store
+            .mark_exists_in_old_target(run, Path::new("b"), now, 1, &dummy_digest(1))
+            .unwrap();
+        store
+            .mark_exists_in_old_target(run, Path::new("b"), now, 2, &dummy_digest(2))
+            .unwrap();
+        let maintained = digests();
+        assert_eq!(maintained, vec![(dummy_digest(1), 1), (dummy_digest(2), 1)]);
+        assert_eq!(store.reindex_digests().unwrap(), 2);
+        assert_eq!(digests(), maintained);
+        assert!(store.exists_in_target(&dummy_digest(2)).unwrap());
+    }
+
+    #[test]
+    fn scrub_visits_least_recently_verified_first() {
+        let store = PhotoSyncStore::new_for_tests().unwrap();
+        let now = SystemTime::now();
+        let run = store.begin_run("").unwrap();
+        for (idx, path) in ["a.jpg", "b.jpg", "c.jpg"].into_iter().enumerate() {
+            store
+                .mark_exists_in_old_target(run, Path::new(path), now, 1, &dummy_digest(idx as u8))
+                .unwrap();
+        }
+        let paths = |before, limit| {
+            store
+                .files_to_scrub(before, limit)
+                .unwrap()
+                .into_iter()
+                .map(|c| c.path)
+                .collect::<Vec<_>>()
+        };
+        let day = Duration::from_secs(24 * 60 * 60);
+        store.mark_verified(Path::new("a.jpg"), now - day).unwrap();
+        store.mark_verified(Path::new("b.jpg"), now).unwrap();
+
+        assert_eq!(
+            paths(now + day, 2),
+            [Path::new("c.jpg"), Path::new("a.jpg")]
+        );
+        assert_eq!(paths(now, 10), [Path::new("c.jpg"), Path::new("a.jpg")]);
+        store.mark_verified(Path::new("c.jpg"), now).unwrap();
+        assert_eq!(paths(now, 10), [Path::new("a.jpg")]);
+    }
+
+    #[test]
+    fn manifest_entries_move_between_stores() {
+        let store = PhotoSyncStore::new_for_tests().unwrap();
+        let now = SystemTime::now();
+        let run = store.begin_run("laptop").unwrap();
+        store
+            .mark_exists_in_old_target(run, Path::new("old.jpg"), now, 1, &dummy_digest(1))
+            .unwrap();
+        store
+            .mark_transferred_from_source(
+                run,
+                "laptop",
+                Path::new("new.jpg"),
+                &dummy_digest(2),
+                now,
+                2,
+            )
+            .unwrap();
+        let entries = store.manifest_entries(None).unwrap();
+        assert_eq!(entries.len(), 2);
+        let later = store.begin_run("laptop").unwrap();
+        store
+            .mark_exists_in_target(later, Path::new("later.jpg"), now, 3, &dummy_digest(3))
+            .unwrap();
+        assert_eq!(store.manifest_entries(Some(later)).unwrap(), []);
+        assert_eq!(
+            store.manifest_entries(Some(run)).unwrap(),
+            [ManifestEntry::Target {
+                path: "later.jpg".into(),
+                mtime: system_time_as_i64(now).unwrap(),
+                size: 3,
+                digest: dummy_digest(3),
+            }]
+        );
+
+        let copy = PhotoSyncStore::new_for_tests().unwrap();
+        copy.add_manifest_entries(&entries).unwrap();
+        assert_eq!(copy.manifest_entries(None).unwrap(), entries);
+        assert_eq!(
+            copy.was_transferred_from_source("laptop", Path::new("new.jpg"), now, 2)
+                .unwrap(),
+            WasTransferredFromSourceResult::Transferred
+        );
+    }
+
+    #[test]
+    fn profiles_roundtrip() {
+        let store = PhotoSyncStore::new_for_tests().unwrap();
+        assert_eq!(store.profile("phone").unwrap(), None);
+
+        let args = vec!["--in-dir".to_string(), "/photos".to_string()];
+        store.save_profile("phone", &args).unwrap();
+        assert_eq!(store.profile("phone").unwrap(), Some(args.clone()));
+        assert_eq!(store.profiles().unwrap(), vec![("phone".to_string(), args)]);
+
+        assert!(store.delete_profile("phone").unwrap());
+        assert!(!store.delete_profile("phone").unwrap());
+    }
 }
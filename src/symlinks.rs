@@ -0,0 +1,208 @@
+//! What to do with symbolic links met walking the in and old out directories, handled the same
+//! way in both so that what phase 1 indexes matches what phase 2 transfers.
+
+use std::{
+    fs, io,
+    path::{Component, Path, PathBuf},
+};
+
+use clap::ValueEnum;
+use eyre::Result;
+use tracing::warn;
+use walkdir::{DirEntry, WalkDir};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum SymlinkPolicy {
+    /// Sync what a link points to as if it were where the link is. Links which lead out of the
+    /// directory being walked, loop, or point nowhere are skipped.
+    Follow,
+    /// Leave links out.
+    Skip,
+    /// Recreate links in the out directory, pointing at the same file relative to the link. Links
+    /// which lead out of the in directory are skipped.
+    Preserve,
+}
+
+/// A walk of `root` which follows links if the policy does. Walks should be filtered with
+/// [`admits`] and [`skip_unwalkable`].
+pub fn walk(root: &Path, policy: SymlinkPolicy) -> WalkDir {
+    WalkDir::new(root).follow_links(policy == SymlinkPolicy::Follow)
+}
+
+/// Whether the policy lets a walk of `within` enter `entry`. Followed links must lead somewhere
+/// within it, so files outside aren't synced as if they were inside.
+pub fn admits(entry: &DirEntry, policy: SymlinkPolicy, within: &Path) -> bool {
+    if !entry.path_is_symlink() {
+        return true;
+    }
+    match policy {
+        SymlinkPolicy::Skip => false,
+        SymlinkPolicy::Preserve => true,
+        SymlinkPolicy::Follow => {
+            let inside = match (entry.path().canonicalize(), within.canonicalize()) {
+                (Ok(target), Ok(within)) => target.starts_with(within),
+                _ => false,
+            };
+            if !inside {
+                warn!("{:?} leads out of {within:?}, skipping it", entry.path());
+            }
+            inside
+        }
+    }
+}
+
+/// Skips, reporting them, the links a walk following links can't go through: loops, and links
+/// pointing nowhere. Other errors are passed on.
+pub fn skip_unwalkable(entry: walkdir::Result<DirEntry>) -> Option<walkdir::Result<DirEntry>> {
+    let Err(e) = &entry else {
+        return Some(entry);
+    };
+    let path = e.path().unwrap_or(Path::new("")).to_path_buf();
+    if let Some(ancestor) = e.loop_ancestor() {
+        warn!("{path:?} links back to {ancestor:?}, skipping it");
+        return None;
+    }
+    if e.io_error()
+        .is_some_and(|e| e.kind() == io::ErrorKind::NotFound)
+        && path.is_symlink()
+    {
+        warn!("{path:?} links to something which doesn't exist, skipping it");
+        return None;
+    }
+    Some(entry)
+}
+
+/// Recreates the link at `link`, relative to `in_dir`, in `out_dir`, at the destinations
+/// `destination` gives it and what it points to. Returns whether a link was created, rather than
+/// already there or skipped.
+pub fn preserve(
+    in_dir: &Path,
+    link: &Path,
+    out_dir: &Path,
+    destination: impl Fn(&Path) -> Result<PathBuf>,
+) -> Result<bool> {
+    let target = fs::read_link(in_dir.join(link))?;
+    let resolved = normalise(&in_dir.join(link).parent().unwrap_or(in_dir).join(target));
+    let Ok(target) = resolved.strip_prefix(normalise(in_dir)) else {
+        warn!("{link:?} leads out of the in directory, skipping it");
+        return Ok(false);
+    };
+
+    let link_out = out_dir.join(destination(link)?);
+    let target_out = out_dir.join(destination(target)?);
+    let relative = relative_path(&target_out, link_out.parent().unwrap_or(out_dir));
+    if fs::read_link(&link_out).is_ok_and(|existing| existing == relative) {
+        return Ok(false);
+    }
+    if let Some(parent) = link_out.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    symlink(&relative, &link_out)?;
+    Ok(true)
+}
+
+#[cfg(unix)]
+pub fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+pub fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(target, link)
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn symlink(_target: &Path, _link: &Path) -> io::Result<()> {
+    Err(io::Error::other("links aren't supported on this platform"))
+}
+
+/// Resolves `.` and `..` in a path without touching the filesystem, as links may be dangling.
+fn normalise(path: &Path) -> PathBuf {
+    let mut normalised = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalised.pop();
+            }
+            other => normalised.push(other),
+        }
+    }
+    normalised
+}
+
+/// The path from the directory `from` to `to`, both normalised.
+fn relative_path(to: &Path, from: &Path) -> PathBuf {
+    let (to, from) = (normalise(to), normalise(from));
+    let common = to
+        .components()
+        .zip(from.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let ups = from.components().count() - common;
+    (0..ups)
+        .map(|_| Component::ParentDir.as_os_str())
+        .chain(to.components().skip(common).map(|c| c.as_os_str()))
+        .collect()
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+
+    #[test]
+    fn links_are_followed_skipped_or_preserved() {
+        let dir = tempfile::tempdir().unwrap();
+        let (in_dir, out_dir, outside) = (
+            dir.path().join("in"),
+            dir.path().join("out"),
+            dir.path().join("outside"),
+        );
+        for d in [in_dir.join("2020"), out_dir.clone(), outside.clone()] {
+            fs::create_dir_all(d).unwrap();
+        }
+        fs::write(in_dir.join("2020/a.jpg"), "photo").unwrap();
+        fs::write(outside.join("b.jpg"), "private").unwrap();
+        symlink("2020/a.jpg", in_dir.join("latest.jpg")).unwrap();
+        symlink("../outside", in_dir.join("escape")).unwrap();
+        symlink("..", in_dir.join("2020/loop")).unwrap();
+        symlink("nowhere.jpg", in_dir.join("dangling.jpg")).unwrap();
+
+        let files = |policy| {
+            let mut files: Vec<_> = walk(&in_dir, policy)
+                .into_iter()
+                .filter_entry(|e| admits(e, policy, &in_dir))
+                .filter_map(skip_unwalkable)
+                .map(|e| e.unwrap())
+                .filter(|e| !e.file_type().is_dir())
+                .map(|e| e.path().strip_prefix(&in_dir).unwrap().to_path_buf())
+                .collect();
+            files.sort();
+            files
+        };
+        assert_eq!(
+            files(SymlinkPolicy::Follow),
+            [Path::new("2020/a.jpg"), Path::new("latest.jpg")]
+        );
+        assert_eq!(files(SymlinkPolicy::Skip), [Path::new("2020/a.jpg")]);
+        assert_eq!(files(SymlinkPolicy::Preserve).len(), 5);
+
+        let same = |path: &Path| Ok(path.to_path_buf());
+        assert!(preserve(&in_dir, Path::new("latest.jpg"), &out_dir, same).unwrap());
+        assert!(!preserve(&in_dir, Path::new("latest.jpg"), &out_dir, same).unwrap());
+        assert_eq!(
+            fs::read_link(out_dir.join("latest.jpg")).unwrap(),
+            Path::new("2020/a.jpg")
+        );
+        assert!(!preserve(&in_dir, Path::new("escape"), &out_dir, same).unwrap());
+        // destinations are rewritten, so links keep pointing at the same file.
+        let dated = |path: &Path| Ok(Path::new("by-date").join(path));
+        assert!(preserve(&in_dir, Path::new("2020/loop"), &out_dir, dated).unwrap());
+        assert_eq!(
+            fs::read_link(out_dir.join("by-date/2020/loop")).unwrap(),
+            Path::new("..")
+        );
+    }
+}
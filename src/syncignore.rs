@@ -0,0 +1,113 @@
+//! `.syncignore` files in the in directory, in gitignore syntax, excluding what they match in
+//! their directory and below, e.g. a `*` dropped into `Screenshots/`.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use ignore::{
+    Match,
+    gitignore::{Gitignore, GitignoreBuilder},
+};
+
+/// The name of the files read.
+pub const SYNCIGNORE: &str = ".syncignore";
+
+/// The `.syncignore` files of a tree, each read the first time something in its directory is
+/// checked.
+pub struct SyncIgnores {
+    root: PathBuf,
+    matchers: RefCell<HashMap<PathBuf, Option<Gitignore>>>,
+}
+
+impl SyncIgnores {
+    pub fn new(root: &Path) -> Self {
+        Self {
+            root: root.to_path_buf(),
+            matchers: RefCell::default(),
+        }
+    }
+
+    /// Whether `path`, under the root, is excluded. The nearest `.syncignore` with a matching
+    /// rule decides, so a deeper file can re-include with `!` what a shallower one excluded.
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        if path.file_name().is_some_and(|name| name == SYNCIGNORE) {
+            return true;
+        }
+        let mut matchers = self.matchers.borrow_mut();
+        for dir in path.ancestors().skip(1) {
+            if !dir.starts_with(&self.root) {
+                break;
+            }
+            let matcher = matchers
+                .entry(dir.to_path_buf())
+                .or_insert_with(|| read(dir));
+            match matcher.as_ref().map(|m| m.matched(path, is_dir)) {
+                Some(Match::Ignore(_)) => return true,
+                Some(Match::Whitelist(_)) => return false,
+                _ => {}
+            }
+        }
+        false
+    }
+}
+
+fn read(dir: &Path) -> Option<Gitignore> {
+    let path = dir.join(SYNCIGNORE);
+    if !path.is_file() {
+        return None;
+    }
+    let mut builder = GitignoreBuilder::new(dir);
+    // malformed lines are skipped rather than failing the run, as git does.
+    if let Some(e) = builder.add(&path) {
+        println!("problem reading {path:?}, some of it is ignored: {e}");
+    }
+    match builder.build() {
+        Ok(matcher) => Some(matcher),
+        Err(e) => {
+            println!("could not read {path:?}, so it's ignored: {e}");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use walkdir::WalkDir;
+
+    use super::*;
+
+    #[test]
+    fn syncignore_files_exclude_their_subtrees() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        for path in [
+            "a.jpg",
+            "notes.txt",
+            "keep/notes.txt",
+            "Screenshots/s.png",
+            "WhatsApp/Media/w.jpg",
+        ] {
+            fs::create_dir_all(root.join(path).parent().unwrap()).unwrap();
+            fs::write(root.join(path), "").unwrap();
+        }
+        fs::write(root.join(SYNCIGNORE), "*.txt\nWhatsApp/\n").unwrap();
+        fs::write(root.join("keep").join(SYNCIGNORE), "!notes.txt\n").unwrap();
+        fs::write(root.join("Screenshots").join(SYNCIGNORE), "*\n").unwrap();
+
+        let ignores = SyncIgnores::new(root);
+        let mut found: Vec<_> = WalkDir::new(root)
+            .into_iter()
+            .filter_entry(|e| !ignores.is_ignored(e.path(), e.file_type().is_dir()))
+            .map(|e| e.unwrap())
+            .filter(|e| e.file_type().is_file())
+            .map(|e| e.path().strip_prefix(root).unwrap().to_path_buf())
+            .collect();
+        found.sort();
+        assert_eq!(found, [Path::new("a.jpg"), Path::new("keep/notes.txt")]);
+    }
+}
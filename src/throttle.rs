@@ -0,0 +1,265 @@
+//! Holding reads or writes to a maximum rate, shared between the threads doing them.
+
+use std::{
+    io::{self, Read, Seek, SeekFrom, Write},
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Sleeps as needed to hold reads or writes to a maximum rate: a token bucket holding up to a
+/// second's worth of bytes, so a burst after a pause is short, rather than making up for all the
+/// time spent idle.
+pub struct Throttle {
+    bytes_per_second: Option<u64>,
+    clock: Box<dyn Clock>,
+    bucket: Mutex<Bucket>,
+}
+
+/// Where a [`Throttle`] tells the time and waits.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+    fn sleep(&self, duration: Duration);
+}
+
+struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration)
+    }
+}
+
+/// The bytes a second `mbps` megabits a second is, or as many as can be counted.
+pub fn megabits_to_bytes(mbps: u64) -> u64 {
+    mbps.saturating_mul(1_000_000) / 8
+}
+
+struct Bucket {
+    /// The bytes which can go without waiting, negative once they're owed.
+    tokens: f64,
+    filled: Instant,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_second: Option<u64>) -> Self {
+        Self::with_clock(bytes_per_second, Box::new(SystemClock))
+    }
+
+    pub fn with_clock(bytes_per_second: Option<u64>, clock: Box<dyn Clock>) -> Self {
+        Self {
+            bytes_per_second,
+            bucket: Mutex::new(Bucket {
+                tokens: 0.0,
+                filled: clock.now(),
+            }),
+            clock,
+        }
+    }
+
+    pub fn consumed(&self, bytes: u64) {
+        let Some(rate) = self.bytes_per_second else {
+            return;
+        };
+        let rate = rate as f64;
+        let owed = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = self.clock.now();
+            let refilled = now.duration_since(bucket.filled).as_secs_f64() * rate;
+            bucket.tokens = (bucket.tokens + refilled).min(rate) - bytes as f64;
+            bucket.filled = now;
+            -bucket.tokens
+        };
+        // the bytes are taken up front, so threads queue behind each other's debts.
+        if owed > 0.0 {
+            self.clock.sleep(Duration::from_secs_f64(owed / rate));
+        }
+    }
+}
+
+/// A writer whose writes are held to the rate of a [`Throttle`].
+pub struct ThrottledWriter<'a, W: Write> {
+    inner: W,
+    throttle: &'a Throttle,
+}
+
+impl<'a, W: Write> ThrottledWriter<'a, W> {
+    pub fn new(inner: W, throttle: &'a Throttle) -> Self {
+        Self { inner, throttle }
+    }
+}
+
+impl<W: Write> Write for ThrottledWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.throttle.consumed(written as u64);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A reader whose reads are held to the rate of a [`Throttle`].
+pub struct ThrottledReader<'a, R: Read> {
+    inner: R,
+    throttle: &'a Throttle,
+}
+
+impl<'a, R: Read> ThrottledReader<'a, R> {
+    pub fn new(inner: R, throttle: &'a Throttle) -> Self {
+        Self { inner, throttle }
+    }
+}
+
+impl<R: Read> Read for ThrottledReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.throttle.consumed(read as u64);
+        Ok(read)
+    }
+}
+
+impl<R: Read + Seek> Seek for ThrottledReader<'_, R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rayon::prelude::*;
+    use std::sync::Arc;
+
+    /// A clock which only moves when told to, recording how long it was asked to sleep.
+    struct FakeClock {
+        now: Mutex<Instant>,
+        /// Whether sleeping moves the clock on, as for one thread at a time.
+        sleeping_passes_time: bool,
+        slept: Mutex<Vec<Duration>>,
+    }
+
+    impl FakeClock {
+        fn new(sleeping_passes_time: bool) -> Arc<Self> {
+            Arc::new(Self {
+                now: Mutex::new(Instant::now()),
+                sleeping_passes_time,
+                slept: Mutex::default(),
+            })
+        }
+
+        fn advance(&self, by: Duration) {
+            *self.now.lock().unwrap() += by;
+        }
+    }
+
+    impl Clock for Arc<FakeClock> {
+        fn now(&self) -> Instant {
+            *self.now.lock().unwrap()
+        }
+
+        fn sleep(&self, duration: Duration) {
+            self.slept.lock().unwrap().push(duration);
+            if self.sleeping_passes_time {
+                self.advance(duration);
+            }
+        }
+    }
+
+    fn assert_secs(duration: Duration, secs: f64) {
+        assert!((duration.as_secs_f64() - secs).abs() < 1e-6, "{duration:?}");
+    }
+
+    #[test]
+    fn bytes_are_held_to_the_rate() {
+        let clock = FakeClock::new(true);
+        let throttle = Throttle::with_clock(Some(1_000), Box::new(clock.clone()));
+        let started = clock.now();
+        for _ in 0..50 {
+            throttle.consumed(100);
+        }
+        assert_secs(clock.now() - started, 5.0);
+    }
+
+    #[test]
+    fn idling_only_saves_up_a_seconds_worth() {
+        let clock = FakeClock::new(true);
+        let throttle = Throttle::with_clock(Some(1_000), Box::new(clock.clone()));
+        clock.advance(Duration::from_secs(3_600));
+        throttle.consumed(1_000);
+        assert!(clock.slept.lock().unwrap().is_empty());
+
+        clock.advance(Duration::from_secs(3_600));
+        throttle.consumed(3_000);
+        assert_secs(clock.slept.lock().unwrap()[0], 2.0);
+    }
+
+    #[test]
+    fn workers_share_the_rate() {
+        // time stands still, so each worker waits behind what the others took before it.
+        let clock = FakeClock::new(false);
+        let throttle = Throttle::with_clock(Some(1_000), Box::new(clock.clone()));
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(4)
+            .build()
+            .unwrap();
+        pool.install(|| (0..8).into_par_iter().for_each(|_| throttle.consumed(500)));
+        let mut slept = clock.slept.lock().unwrap().clone();
+        slept.sort();
+        for (slept, waited) in slept.into_iter().zip(1..) {
+            assert_secs(slept, waited as f64 * 0.5);
+        }
+    }
+
+    #[test]
+    fn megabits_saturate_rather_than_overflow() {
+        assert_eq!(megabits_to_bytes(8), 1_000_000);
+        assert_eq!(megabits_to_bytes(u64::MAX), u64::MAX / 8);
+    }
+
+    #[test]
+    fn writes_are_held_to_the_rate() {
+        let throttle = Throttle::new(Some(200_000));
+        let started = Instant::now();
+        let mut written = Vec::new();
+        let mut writer = ThrottledWriter::new(&mut written, &throttle);
+        for _ in 0..10 {
+            writer.write_all(&[0; 4_000]).unwrap();
+        }
+        assert_eq!(written.len(), 40_000);
+        assert!(started.elapsed() >= Duration::from_millis(200));
+
+        // time spent idle only builds up a second's worth, which all the readers share.
+        thread::sleep(Duration::from_millis(1100));
+        let started = Instant::now();
+        let mut read = Vec::new();
+        let readers = [&[0; 150_000][..], &[0; 150_000][..]];
+        let throttle = &throttle;
+        thread::scope(|s| {
+            let readers = readers.map(|content| {
+                s.spawn(move || {
+                    let mut read = Vec::new();
+                    let mut reader = ThrottledReader::new(content, throttle);
+                    reader.read_to_end(&mut read).unwrap();
+                    read
+                })
+            });
+            for reader in readers {
+                read.extend(reader.join().unwrap());
+            }
+        });
+        assert_eq!(read.len(), 300_000);
+        assert!(started.elapsed() >= Duration::from_millis(450));
+
+        let unlimited = Throttle::new(None);
+        let started = Instant::now();
+        unlimited.consumed(u64::MAX);
+        assert!(started.elapsed() < Duration::from_millis(200));
+    }
+}
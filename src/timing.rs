@@ -0,0 +1,137 @@
+//! Timing every file a run processes, so that the few which make a run slow (e.g. enormous videos,
+//! or a file on a dying sector) can be found.
+
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+use crate::sau64::SimpleAtomicU64;
+use tracing::{debug, info};
+
+/// How many of the slowest files are reported.
+const SLOWEST_KEPT: usize = 10;
+/// The upper bounds of the histogram's buckets, after which there's one more for anything slower.
+const BUCKETS: [Duration; 5] = [
+    Duration::from_millis(10),
+    Duration::from_millis(100),
+    Duration::from_secs(1),
+    Duration::from_secs(10),
+    Duration::from_secs(60),
+];
+
+/// What a file was timed doing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Work {
+    /// Hashing a file in the old out directory.
+    Hashing,
+    /// Transferring a file from the in directory, hashing it on the way.
+    Transferring,
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimedFile {
+    pub duration: Duration,
+    pub path: PathBuf,
+    pub bytes: u64,
+}
+
+#[derive(Default)]
+pub struct FileTimings {
+    /// The slowest files so far, with the quickest of them on top to be pushed out.
+    slowest: Mutex<BinaryHeap<Reverse<TimedFile>>>,
+    histogram: [SimpleAtomicU64; BUCKETS.len() + 1],
+    /// Nanoseconds spent on each kind of work, summed across threads.
+    spent: [SimpleAtomicU64; 2],
+}
+
+impl FileTimings {
+    pub fn record(&self, work: Work, path: &Path, bytes: u64, duration: Duration) {
+        self.spent[work as usize].fetch_add(duration.as_nanos() as u64);
+        let bucket = BUCKETS.iter().take_while(|b| duration >= **b).count();
+        self.histogram[bucket].fetch_add(1);
+
+        let mut slowest = self.slowest.lock().unwrap();
+        if slowest.len() == SLOWEST_KEPT
+            && slowest
+                .peek()
+                .is_some_and(|Reverse(f)| f.duration >= duration)
+        {
+            return;
+        }
+        slowest.push(Reverse(TimedFile {
+            duration,
+            path: path.to_path_buf(),
+            bytes,
+        }));
+        if slowest.len() > SLOWEST_KEPT {
+            slowest.pop();
+        }
+    }
+
+    pub fn spent(&self, work: Work) -> Duration {
+        Duration::from_nanos(self.spent[work as usize].as_u64())
+    }
+
+    /// The slowest files, slowest first.
+    pub fn slowest(&self) -> Vec<TimedFile> {
+        let slowest = std::mem::take(&mut *self.slowest.lock().unwrap());
+        slowest.into_sorted_vec().into_iter().map(|f| f.0).collect()
+    }
+
+    /// Logs the slowest files, and if `histogram`, how long files took overall. The slowest are
+    /// only logged at debug level unless the histogram is asked for.
+    pub fn print(&self, histogram: bool) {
+        let slowest = self.slowest();
+        for file in slowest {
+            let rate = file.bytes as f64 / file.duration.as_secs_f64().max(0.001) / 1_000_000.0;
+            let (took, megabytes) = (file.duration.as_secs_f64(), file.bytes / 1_000_000);
+            if histogram {
+                info!(
+                    "one of the slowest files, {:?}: {took:.1}s for {megabytes}MB ({rate:.1}MB/s)",
+                    file.path
+                );
+            } else {
+                debug!(
+                    "one of the slowest files, {:?}: {took:.1}s for {megabytes}MB ({rate:.1}MB/s)",
+                    file.path
+                );
+            }
+        }
+        if histogram {
+            info!("files by how long they took:");
+            for (i, count) in self.histogram.iter().enumerate() {
+                let label = match BUCKETS.get(i) {
+                    Some(bound) => format!("under {bound:?}"),
+                    None => format!("{:?} or more", BUCKETS[BUCKETS.len() - 1]),
+                };
+                info!("    {label:>15}: {count}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slowest_files_are_kept() {
+        let timings = FileTimings::default();
+        for n in 0..100 {
+            let path = PathBuf::from(format!("{n}.jpg"));
+            timings.record(Work::Hashing, &path, n, Duration::from_millis(n * 10));
+        }
+        let slowest = timings.slowest();
+        assert_eq!(slowest.len(), SLOWEST_KEPT);
+        assert_eq!(slowest[0].path, Path::new("99.jpg"));
+        assert_eq!(slowest[9].duration, Duration::from_millis(900));
+        let counts: Vec<_> = timings.histogram.iter().map(|c| c.as_u64()).collect();
+        assert_eq!(counts, [1, 9, 90, 0, 0, 0]);
+        assert_eq!(timings.spent(Work::Hashing), Duration::from_millis(49_500));
+        assert_eq!(timings.spent(Work::Transferring), Duration::ZERO);
+    }
+}
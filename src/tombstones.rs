@@ -0,0 +1,172 @@
+//! Tombstones: content deliberately removed from the out directory, e.g. a bad photo culled while
+//! curating it, which is never transferred again though the in directory still has it. Content is
+//! tombstoned by `tombstone add`, or by `--propagate-target-deletions`, which takes catalogued
+//! files gone from the out directory to have been deleted on purpose.
+
+use std::{collections::HashSet, io, path::Path};
+
+use chrono::{DateTime, Local};
+use eyre::{Result, WrapErr, ensure};
+use tracing::{info, warn};
+
+use crate::{
+    TombstoneArgs, TombstoneCommand,
+    store::{CataloguedFile, PhotoSyncStore},
+};
+
+pub fn tombstone(args: TombstoneArgs) -> Result<()> {
+    let store = PhotoSyncStore::new(args.database_file)?;
+    match args.command {
+        TombstoneCommand::Add { files, hash_algo } => {
+            for file in files {
+                let digest = hash_algo
+                    .digest(&file)
+                    .wrap_err_with(|| format!("could not hash {file:?}"))?;
+                if store.add_tombstone(&digest, &file)? {
+                    println!("{file:?} ({digest}) will never be transferred again");
+                } else {
+                    println!("{file:?} ({digest}) was already tombstoned");
+                }
+            }
+        }
+        TombstoneCommand::List => {
+            for tombstone in store.tombstones()? {
+                let added = DateTime::<Local>::from(tombstone.added_at).format("%Y-%m-%d");
+                println!(
+                    "{added}  {}  {}",
+                    tombstone.digest,
+                    tombstone.path.display()
+                );
+            }
+        }
+        TombstoneCommand::Remove { digest } => {
+            ensure!(
+                store.remove_tombstone(&digest)?,
+                "{digest} isn't tombstoned"
+            );
+            println!("{digest} will be transferred again");
+        }
+    }
+    Ok(())
+}
+
+/// Tombstones the content of the catalogued files gone from `out_dir`, and forgets them, returning
+/// how many were. Content with a copy still there isn't tombstoned. Nothing is, if every file is
+/// gone, as the out directory is more likely unmounted than emptied.
+pub fn propagate_target_deletions(
+    store: &PhotoSyncStore,
+    out_dir: &Path,
+    dry_run: bool,
+) -> Result<usize> {
+    let files = store.target_files()?;
+    let (mut present, mut gone): (Vec<&CataloguedFile>, Vec<&CataloguedFile>) = (vec![], vec![]);
+    for file in &files {
+        let path = out_dir.join(&file.path);
+        // a file which can't be looked at, e.g. on a share which has dropped, isn't known to be
+        // gone, so nothing is tombstoned rather than its content never being transferred again.
+        match path.symlink_metadata() {
+            Ok(_) => present.push(file),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => gone.push(file),
+            Err(e) => {
+                return Err(e).wrap_err_with(|| {
+                    format!("could not tell whether {path:?} was deleted, so not tombstoning")
+                });
+            }
+        }
+    }
+    if !files.is_empty() && present.is_empty() {
+        warn!(
+            "every file catalogued in {out_dir:?} is gone from it, so not tombstoning them; is it \
+             mounted?"
+        );
+        return Ok(0);
+    }
+    let kept: HashSet<_> = present.iter().map(|file| file.digest).collect();
+    let mut tombstoned = 0;
+    for file in gone {
+        let kept = kept.contains(&file.digest);
+        if dry_run {
+            if !kept {
+                info!(
+                    "would tombstone {:?}, deleted from the out directory",
+                    file.path
+                );
+            }
+            continue;
+        }
+        if !kept && store.add_tombstone(&file.digest, &file.path)? {
+            info!("tombstoned {:?}, deleted from the out directory", file.path);
+            tombstoned += 1;
+        }
+        store.forget_target_file(&file.path, &file.digest, !kept)?;
+    }
+    Ok(tombstoned)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, time::SystemTime};
+
+    use super::*;
+    use crate::digest::ContentHash;
+
+    #[test]
+    fn deleted_copies_are_tombstoned_unless_another_is_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = PhotoSyncStore::new_for_tests().unwrap();
+        let run = store.begin_run("").unwrap();
+        let now = SystemTime::now();
+        let (culled, kept) = (ContentHash::new_for_tests(1), ContentHash::new_for_tests(2));
+        for (path, digest) in [("bad.jpg", culled), ("a.jpg", kept), ("b.jpg", kept)] {
+            store
+                .mark_exists_in_target(run, Path::new(path), now, 1, &digest)
+                .unwrap();
+        }
+        // nothing's tombstoned while the whole out directory is missing.
+        assert_eq!(
+            propagate_target_deletions(&store, dir.path(), false).unwrap(),
+            0
+        );
+        fs::write(dir.path().join("a.jpg"), "a").unwrap();
+
+        assert_eq!(
+            propagate_target_deletions(&store, dir.path(), true).unwrap(),
+            0
+        );
+        assert!(!store.is_tombstoned(&culled).unwrap());
+        assert_eq!(
+            propagate_target_deletions(&store, dir.path(), false).unwrap(),
+            1
+        );
+        assert!(store.is_tombstoned(&culled).unwrap());
+        assert!(!store.is_tombstoned(&kept).unwrap());
+        let paths: Vec<_> = (store.target_files().unwrap().into_iter())
+            .map(|file| file.path)
+            .collect();
+        assert_eq!(paths, [Path::new("a.jpg")]);
+
+        assert!(store.remove_tombstone(&culled).unwrap());
+        assert!(!store.is_tombstoned(&culled).unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn files_which_cant_be_looked_at_arent_taken_as_deleted() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = PhotoSyncStore::new_for_tests().unwrap();
+        let run = store.begin_run("").unwrap();
+        let now = SystemTime::now();
+        for (path, n) in [("a.jpg", 1), ("album/b.jpg", 2)] {
+            store
+                .mark_exists_in_target(run, Path::new(path), now, 1, &ContentHash::new_for_tests(n))
+                .unwrap();
+        }
+        fs::write(dir.path().join("a.jpg"), "a").unwrap();
+        // a file where its directory should be, so looking it up fails other than as not found.
+        fs::write(dir.path().join("album"), "").unwrap();
+
+        assert!(propagate_target_deletions(&store, dir.path(), false).is_err());
+        assert!(!store.is_tombstoned(&ContentHash::new_for_tests(2)).unwrap());
+        assert_eq!(store.target_files().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,186 @@
+//! `--transcode-heic`: archiving HEIC and HEIF photos as JPEGs, for galleries and TVs which can't
+//! show HEIC. Files are still catalogued by the digest of the original, so sources dedupe as
+//! before, while the catalogue keeps the JPEG's digest to verify the archived copy against.
+
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+    str::FromStr,
+};
+
+use eyre::{Result, WrapErr, bail, eyre};
+
+use crate::hooks::run_hook;
+
+/// The quality JPEGs are written at when `--transcode-heic` doesn't give one.
+const DEFAULT_QUALITY: u8 = 90;
+
+/// What HEIC files are transcoded to, given as `jpeg[:quality]`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TranscodeTarget {
+    /// The JPEG quality, from 1 to 100.
+    pub quality: u8,
+}
+
+impl FromStr for TranscodeTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (format, quality) = match s.split_once(':') {
+            Some((format, quality)) => (format, Some(quality)),
+            None => (s, None),
+        };
+        if !format.eq_ignore_ascii_case("jpeg") && !format.eq_ignore_ascii_case("jpg") {
+            return Err(format!("can only transcode to jpeg, not {format:?}"));
+        }
+        let quality = match quality {
+            Some(quality) => quality
+                .parse()
+                .ok()
+                .filter(|quality| (1..=100).contains(quality))
+                .ok_or_else(|| format!("{quality:?} is not a quality from 1 to 100"))?,
+            None => DEFAULT_QUALITY,
+        };
+        Ok(Self { quality })
+    }
+}
+
+/// Converts a HEIC image into a JPEG.
+pub trait ImageConverter: Send + Sync {
+    /// Writes `from` to `to`, which ends in `.jpg`, as a JPEG of the given quality.
+    fn convert(&self, from: &Path, to: &Path, quality: u8) -> Result<()>;
+}
+
+/// `sips` on macOS, where it comes with the system, and libheif's `heif-convert` elsewhere.
+pub struct SystemConverter;
+
+impl ImageConverter for SystemConverter {
+    fn convert(&self, from: &Path, to: &Path, quality: u8) -> Result<()> {
+        let mut command = if cfg!(target_os = "macos") {
+            let mut command = Command::new("sips");
+            command
+                .args(["-s", "format", "jpeg", "-s", "formatOptions"])
+                .arg(quality.to_string())
+                .arg(from)
+                .arg("--out")
+                .arg(to);
+            command
+        } else {
+            let mut command = Command::new("heif-convert");
+            command.arg("-q").arg(quality.to_string()).arg(from).arg(to);
+            command
+        };
+        let output = command
+            .output()
+            .map_err(|e| eyre!("could not run {:?}: {e}", command.get_program()))?;
+        if !output.status.success() {
+            bail!(
+                "{:?} failed to convert {from:?}: {}",
+                command.get_program(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// A `--heic-converter` shell command, run with PHOTO_SYNC_INPUT, PHOTO_SYNC_OUTPUT and
+/// PHOTO_SYNC_QUALITY set.
+pub struct CommandConverter(pub String);
+
+impl ImageConverter for CommandConverter {
+    fn convert(&self, from: &Path, to: &Path, quality: u8) -> Result<()> {
+        let utf8 = |path: &Path| {
+            path.to_str()
+                .map(str::to_owned)
+                .ok_or_else(|| eyre!("the converter needs UTF-8 paths, got {path:?}"))
+        };
+        run_hook(
+            "heic-converter",
+            &self.0,
+            &[
+                ("PHOTO_SYNC_INPUT", &utf8(from)?),
+                ("PHOTO_SYNC_OUTPUT", &utf8(to)?),
+                ("PHOTO_SYNC_QUALITY", &quality.to_string()),
+            ],
+        )
+    }
+}
+
+/// Transcodes the HEIC files a sync transfers.
+pub struct Transcoder {
+    pub target: TranscodeTarget,
+    pub converter: Box<dyn ImageConverter>,
+}
+
+impl Transcoder {
+    /// Whether the file at `path` is transcoded.
+    pub fn applies_to(&self, path: &Path) -> bool {
+        is_heic(path)
+    }
+
+    /// Writes `from`, a copy of a HEIC file, to `to` as a JPEG.
+    pub fn transcode(&self, from: &Path, to: &Path) -> Result<()> {
+        self.converter
+            .convert(from, to, self.target.quality)
+            .wrap_err_with(|| format!("could not transcode {from:?}"))?;
+        if !to.metadata().is_ok_and(|metadata| metadata.len() > 0) {
+            bail!("transcoding {from:?} wrote nothing");
+        }
+        Ok(())
+    }
+}
+
+pub fn is_heic(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("heic") || ext.eq_ignore_ascii_case("heif"))
+}
+
+/// Where the transcoded copy of a file destined for `path` goes.
+pub fn transcoded_path(path: &Path) -> PathBuf {
+    path.with_extension("jpg")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn heic_files_are_converted_by_the_command() {
+        assert_eq!("jpeg".parse(), Ok(TranscodeTarget { quality: 90 }));
+        assert_eq!("JPEG:75".parse(), Ok(TranscodeTarget { quality: 75 }));
+        assert!("jpeg:101".parse::<TranscodeTarget>().is_err());
+        assert!("png".parse::<TranscodeTarget>().is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let (from, to) = (dir.path().join("staged"), dir.path().join("out.jpg"));
+        fs::write(&from, "heic").unwrap();
+        let transcoder = Transcoder {
+            target: TranscodeTarget { quality: 75 },
+            converter: Box::new(CommandConverter(
+                r#"(cat "$PHOTO_SYNC_INPUT"; echo " at $PHOTO_SYNC_QUALITY") > "$PHOTO_SYNC_OUTPUT""#
+                    .into(),
+            )),
+        };
+        assert!(transcoder.applies_to(Path::new("2019/IMG_0001.HEIC")));
+        assert!(!transcoder.applies_to(Path::new("2019/IMG_0001.JPG")));
+        assert_eq!(
+            transcoded_path(Path::new("2019/IMG_0001.HEIC")),
+            Path::new("2019/IMG_0001.jpg")
+        );
+        transcoder.transcode(&from, &to).unwrap();
+        assert_eq!(fs::read_to_string(&to).unwrap(), "heic at 75\n");
+
+        let failing = Transcoder {
+            converter: Box::new(CommandConverter("true".into())),
+            ..transcoder
+        };
+        assert!(
+            failing
+                .transcode(&from, &dir.path().join("empty.jpg"))
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,1588 @@
+//! Phase 3 of a sync: transferring the new files detection finds. Each is copied to a temporary
+//! file, hashed as it's read, and checked against what's archived. Content new to the archive is
+//! written as it's to be stored, put in its place, read back if asked to be, and catalogued.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    sync::mpsc::Receiver,
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use eyre::{OptionExt, Result, WrapErr, bail};
+use rayon::iter::{ParallelBridge, ParallelIterator};
+use tempfile::{NamedTempFile, TempDir};
+use tracing::{debug, debug_span, info, warn};
+
+use crate::{
+    SyncContext,
+    appledouble::{self, AppleDoublePolicy},
+    backend::TargetBackend,
+    bydate, cas,
+    cas::Layout,
+    chunks,
+    classify::BurstPolicy,
+    collision::{CollisionPolicy, free_path},
+    compress::{self, Transform},
+    copy, devices,
+    digest::{ContentHash, DigestWriter, HashAlgorithm},
+    encrypt::{self, is_encrypted},
+    failures::FailureKind,
+    fastcopy::{self, CopyStrategy},
+    fdbudget::FILES_PER_TRANSFER,
+    hooks::run_hook,
+    icloud::{self, PlaceholderPolicy},
+    ignorelist::IgnoreList,
+    immutable,
+    links::DedupeMode,
+    livephoto, media,
+    metadata::{self, MediaMetadata},
+    paranoid, phash,
+    platform::{FileInfo, copy_times, copy_xattrs, create_archive_dirs, drop_cached_pages},
+    progress::PhaseProgress,
+    promote_partial_digests,
+    sau64::SimpleAtomicU64,
+    sidecar, source_is_transferred,
+    store::{
+        ClassifiedOut, PendingTransfer, PendingWrite, ReviewDecision, SourceVersion,
+        TransferredSource, WasTransferredFromSourceResult,
+    },
+    throttle::{ThrottledReader, ThrottledWriter},
+    timing::Work,
+    transcode::{self, Transcoder},
+    transferlog::TransferRecord,
+    versions,
+};
+
+enum FileOutcome {
+    Success,
+    FailedToOpen(PathBuf, FailureKind),
+    FailedToCopy(PathBuf, FailureKind),
+    FileHookFailed(PathBuf),
+    AppleDoubleFailed(PathBuf),
+    /// The plugin failed for the file, so it wasn't transferred.
+    PluginFailed(PathBuf),
+    /// The file looks corrupt, for this reason, so wasn't transferred.
+    Corrupt(PathBuf, String),
+    /// The file's content is only in iCloud, and wasn't downloaded.
+    NotDownloaded(PathBuf),
+    /// The file's place in the out directory, the second path, is taken, so it wasn't transferred.
+    Collided(PathBuf, PathBuf),
+    /// The file's place in the out directory, the second path, holds other content, so it was
+    /// left for review rather than transferred.
+    Skipped(PathBuf, PathBuf),
+    /// The file is of a kind, e.g. a screenshot, left out of the archive, or its content was
+    /// tombstoned, so it wasn't transferred.
+    LeftOut,
+}
+
+impl FileOutcome {
+    /// How the outcome is described in the transfer log.
+    fn describe(&self, stored: bool) -> &'static str {
+        match self {
+            FileOutcome::Success if stored => "copied",
+            FileOutcome::Success => "duplicate",
+            FileOutcome::FailedToOpen(..) => "failed to open",
+            FileOutcome::FailedToCopy(..) => "failed to copy",
+            FileOutcome::FileHookFailed(_) => "file hook failed",
+            FileOutcome::AppleDoubleFailed(_) => "AppleDouble failed",
+            FileOutcome::PluginFailed(_) => "plugin failed",
+            FileOutcome::Corrupt(..) => "corrupt",
+            FileOutcome::NotDownloaded(_) => "not downloaded",
+            FileOutcome::Collided(..) => "collided",
+            FileOutcome::Skipped(..) => "skipped",
+            FileOutcome::LeftOut => "left out",
+        }
+    }
+
+    /// Why the file failed, unless it didn't. Files left in iCloud, left out or skipped didn't.
+    fn failure(&self) -> Option<FailureKind> {
+        match self {
+            FileOutcome::FailedToOpen(_, kind) | FileOutcome::FailedToCopy(_, kind) => Some(*kind),
+            FileOutcome::FileHookFailed(_)
+            | FileOutcome::AppleDoubleFailed(_)
+            | FileOutcome::PluginFailed(_) => Some(FailureKind::Hook),
+            FileOutcome::Corrupt(..) => Some(FailureKind::Corrupt),
+            FileOutcome::Collided(..) => Some(FailureKind::Collided),
+            FileOutcome::Success
+            | FileOutcome::NotDownloaded(_)
+            | FileOutcome::Skipped(..)
+            | FileOutcome::LeftOut => None,
+        }
+    }
+}
+
+/// Whether the file archived as `destination`, stored as `transform`, has the content `digest`.
+/// One which can't be read as it would have been stored doesn't.
+fn holds_content(
+    backend: &dyn TargetBackend,
+    destination: &Path,
+    transform: Transform,
+    digest: &ContentHash,
+) -> bool {
+    match backend.digest(destination, transform, digest.algorithm()) {
+        Ok(held) => held == *digest,
+        Err(e) => {
+            debug!("could not read {destination:?} as it would have been stored: {e:#}");
+            false
+        }
+    }
+}
+
+/// Checks the copy `backend` stores as `destination`, as `transform`, has the content `expected`,
+/// which for a transcoded copy is what it was transcoded to. Encrypted copies can't be read back,
+/// so are checked against the digest of their ciphertext, `stored`, instead, which is only
+/// chunked after it's encrypted.
+fn read_back(
+    backend: &dyn TargetBackend,
+    destination: &Path,
+    transform: Transform,
+    expected: &ContentHash,
+    stored: Option<ContentHash>,
+) -> Result<()> {
+    let (expected, transform) = match stored {
+        Some(stored) if transform == Transform::Chunked => (stored, Transform::Chunked),
+        Some(stored) => (stored, Transform::None),
+        None => (*expected, transform),
+    };
+    let actual = backend.digest(destination, transform, expected.algorithm())?;
+    if actual != expected {
+        bail!(
+            "{:?} was written as {expected} but reads back as {actual}",
+            backend.location(destination)
+        );
+    }
+    Ok(())
+}
+
+/// The digest the archived copies of the content `staged`, whose digest is `digest`, are
+/// catalogued under, if it's archived. Content archived before `--hash-algo` was changed is
+/// catalogued under its digest by the algorithm used then, so is rehashed by each of those.
+fn archived_digest(
+    ctx: &SyncContext,
+    digest: &ContentHash,
+    staged: &Path,
+) -> Result<Option<ContentHash>> {
+    if ctx.digests.exists_in_target(ctx.store, digest)? {
+        return Ok(Some(*digest));
+    }
+    for algorithm in other_algorithms(ctx)? {
+        let digest = algorithm.digest(staged)?;
+        if ctx.digests.exists_in_target(ctx.store, &digest)? {
+            return Ok(Some(digest));
+        }
+    }
+    Ok(None)
+}
+
+/// The algorithms besides `--hash-algo` which archived files are catalogued by, looked up once
+/// rather than for every file.
+fn other_algorithms<'a>(ctx: &SyncContext<'a>) -> Result<&'a [HashAlgorithm]> {
+    if let Some(algorithms) = ctx.other_algorithms.get() {
+        return Ok(algorithms);
+    }
+    let mut algorithms = Vec::new();
+    for algorithm in HashAlgorithm::ALL {
+        if algorithm != ctx.args.hash_algo && ctx.store.has_target_digests_by(algorithm)? {
+            algorithms.push(algorithm);
+        }
+    }
+    Ok(ctx.other_algorithms.get_or_init(|| algorithms))
+}
+
+/// The digest of `staged`, whose digest is `digest`, by `algorithm`.
+fn rehashed(digest: ContentHash, algorithm: HashAlgorithm, staged: &Path) -> Result<ContentHash> {
+    if digest.algorithm() == algorithm {
+        Ok(digest)
+    } else {
+        algorithm.digest(staged)
+    }
+}
+
+/// Whether some archived copy of `digest`, in the out or old out directory, has exactly the bytes
+/// of `staged`. A transcoded copy can't, so is intact if it still has what it was transcoded to.
+fn has_intact_copy(ctx: &SyncContext, digest: &ContentHash, staged: &Path) -> Result<bool> {
+    for ArchivedCopy {
+        path,
+        transform,
+        transcoded,
+    } in archived_copies(ctx, digest)?
+    {
+        let intact = match transcoded {
+            Some(output) => {
+                compress::digest_archived(&path, transform, output.algorithm()).ok() == Some(output)
+            }
+            None => paranoid::matches_archived(&path, transform, staged)?,
+        };
+        if intact {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// An archived copy of some content.
+struct ArchivedCopy {
+    path: PathBuf,
+    /// How it was stored.
+    transform: Transform,
+    /// The digest of what it was transcoded to, if it was.
+    transcoded: Option<ContentHash>,
+}
+
+/// Where the content `digest` is archived, in the out directory first.
+fn archived_copies(ctx: &SyncContext, digest: &ContentHash) -> Result<Vec<ArchivedCopy>> {
+    let SyncContext { store, args, .. } = ctx;
+    let mut copies = Vec::new();
+    // files in a bucket can't be compared in place.
+    if let Some(out_dir) = args.out_dir.as_deref().filter(|_| args.out_url.is_none()) {
+        for path in store.target_paths_with_digest(digest)? {
+            copies.push(ArchivedCopy {
+                transform: store.transform_of(&path)?,
+                transcoded: store.transcoded_digest(&path)?,
+                path: out_dir.join(path),
+            });
+        }
+    }
+    // the old out directory is catalogued by the bytes of its files, whatever they are.
+    copies.extend(
+        (store.old_target_paths_with_digest(digest)?)
+            .into_iter()
+            .map(|path| ArchivedCopy {
+                path: args.old_out_dir.join(path),
+                transform: Transform::None,
+                transcoded: None,
+            }),
+    );
+    Ok(copies)
+}
+
+/// Puts a link at `out_path` to an archived copy of the content `digest`, as `--dedupe-mode`
+/// says, returning whether one could be linked to. Copies which are compressed, encrypted,
+/// chunked or transcoded aren't, as they aren't the content the link is named for.
+fn link_duplicate(ctx: &SyncContext, digest: &ContentHash, out_path: &Path) -> Result<bool> {
+    // e.g. the archived copy itself, transferred from a file of the same name.
+    if out_path.exists() {
+        return Ok(false);
+    }
+    if let (Some(out_dir), Some(parent)) = (ctx.args.out_dir.as_deref(), out_path.parent()) {
+        create_archive_dirs(out_dir, parent)?;
+    }
+    let plain = |copy: &ArchivedCopy| {
+        copy.transform == Transform::None
+            && copy.transcoded.is_none()
+            && copy.path.is_file()
+            && !is_encrypted(&copy.path)
+    };
+    for copy in archived_copies(ctx, digest)?.into_iter().filter(plain) {
+        // e.g. a hardlink to a copy on another filesystem, so another copy is tried.
+        if ctx.args.dedupe_mode.link(&copy.path, out_path).is_ok() {
+            return Ok(true);
+        }
+    }
+    warn!("no archived copy of the content of {out_path:?} could be linked to");
+    Ok(false)
+}
+
+/// Copies a single new file from the in directory to the out directory, unless its contents are
+/// already there, filling in `record` as it goes.
+/// It goes where the plugin, or `--organize-by-date`, says, unless it's `placed_as` somewhere.
+fn transfer_file(
+    ctx: &SyncContext,
+    path: &Path,
+    placed_as: Option<&Path>,
+    record: &mut TransferRecord,
+) -> Result<FileOutcome> {
+    let _span = debug_span!("transfer", ?path).entered();
+    let SyncContext {
+        store,
+        run,
+        args,
+        source,
+        ..
+    } = ctx;
+    // each transfer's temporary files go in a directory of their own, journalled until it's done
+    // so the next run can clean up after it if the process is killed partway through.
+    let work_dir = TempDir::new_in(&args.temp_dir)?;
+    let pending = PendingTransfer {
+        path: path.to_path_buf(),
+        work_dir: work_dir.path().to_path_buf(),
+        writing: None,
+    };
+    store.journal_transfer(*run, &source.namespace, &pending)?;
+    let outcome = transfer_journalled_file(ctx, &pending, placed_as, record)?;
+    store.forget_pending_transfer(&source.namespace, path)?;
+    Ok(outcome)
+}
+
+/// Transfers the file `pending` journals, its work directory already made, as [`transfer_file`]
+/// says.
+fn transfer_journalled_file(
+    ctx: &SyncContext,
+    pending: &PendingTransfer,
+    placed_as: Option<&Path>,
+    record: &mut TransferRecord,
+) -> Result<FileOutcome> {
+    let SyncContext {
+        store,
+        run,
+        args,
+        stats,
+        claims,
+        fds,
+        source,
+        ..
+    } = ctx;
+    let _files = fds.acquire(FILES_PER_TRANSFER);
+    let (path, temp_dir) = (pending.path.as_path(), pending.work_dir.as_path());
+    let (in_dir, backend) = (&source.dir, ctx.backend);
+    let in_path = in_dir.join(path);
+    let moving = args.move_sources || source.is_staging();
+    let mut in_data = match open_source(ctx, &in_path) {
+        Ok(in_data) => in_data,
+        Err(outcome) => return Ok(outcome),
+    };
+
+    let file_info = FileInfo::of(&in_path)?;
+    let size = file_info.size;
+    record.bytes = Some(size);
+
+    // a file transferred before which has since changed, whose new content is a new version.
+    let changed = match store.was_transferred_from_source(
+        &source.namespace,
+        path,
+        file_info.modified,
+        size,
+    )? {
+        WasTransferredFromSourceResult::NewMetadata {
+            digest: original, ..
+        } if args.keep_versions => Some((original, store.next_version(&source.namespace, path)?)),
+        _ => None,
+    };
+
+    let transcoding = ctx
+        .transcoder
+        .filter(|transcoder| transcoder.applies_to(path));
+    let storage = Storage::new(ctx, transcoding.is_some());
+    let mut unstored = match pick_destination(ctx, &storage, path, placed_as, file_info.modified) {
+        Ok(unstored) => unstored,
+        Err(outcome) => return Ok(outcome),
+    };
+    record.destination = Some(unstored.clone());
+    if let Some((_, version)) = changed {
+        unstored = versions::versioned_path(&unstored, version);
+    }
+
+    let started = Instant::now();
+    let Staged {
+        copy: staged,
+        digest,
+        fast_copied,
+    } = match stage(ctx, &mut in_data, &in_path, temp_dir, size)? {
+        Ok(staged) => staged,
+        Err(outcome) => return Ok(outcome),
+    };
+    if args.report_throughput {
+        let elapsed = started.elapsed().as_secs_f64();
+        info!(
+            "copied {in_path:?}: {:.1}MB in {elapsed:.2}s ({:.1}MB/s)",
+            size as f64 / 1e6,
+            size as f64 / 1e6 / elapsed.max(f64::EPSILON)
+        );
+    }
+
+    record.digest = Some(digest);
+    // the content decides where it goes, once it's known.
+    if args.layout == Layout::Cas {
+        unstored = cas::addressed_path(&digest, path);
+        record.destination = Some(unstored.clone());
+    }
+    let mut destination = storage.stored_as(&unstored);
+    let mut out_path = backend.location(&destination);
+    if args.drop_page_cache
+        && let Err(e) = drop_cached_pages(&in_data)
+    {
+        debug!("failed to drop {in_path:?} from the page cache: {e}");
+    }
+
+    // a file cloned before it was hashed mustn't have changed in between, as the two would differ.
+    if (args.paranoid || fast_copied)
+        && !paranoid::unchanged_since(&in_path, &file_info).unwrap_or(false)
+    {
+        warn!("{in_path:?} changed while being copied. Skipping and moving on.");
+        return Ok(FileOutcome::FailedToCopy(
+            in_path,
+            FailureKind::ChangedWhileCopying,
+        ));
+    }
+
+    // only its metadata changed, e.g. it was touched, so there's no new version.
+    if let Some((original, _)) = changed
+        && original == rehashed(digest, original.algorithm(), staged.path())?
+    {
+        store.update_source(
+            *run,
+            &source.namespace,
+            path,
+            &digest,
+            file_info.modified,
+            size,
+        )?;
+        if moving && has_intact_copy(ctx, &original, staged.path())? {
+            remove_source(ctx, path, &file_info, &digest)?;
+        }
+        return Ok(FileOutcome::Success);
+    }
+
+    // content deleted from the out directory on purpose stays out of it.
+    if store.is_tombstoned(&digest)? {
+        debug!("leaving out {in_path:?}, whose content is tombstoned");
+        store.record_classified_out(
+            &source.namespace,
+            &ClassifiedOut {
+                path: path.to_path_buf(),
+                last_modified: file_info.modified,
+                size,
+                class: "tombstoned".to_string(),
+            },
+        )?;
+        return Ok(FileOutcome::LeftOut);
+    }
+
+    // checked once copied, so that what's checked is what would be archived.
+    if args.validate_media
+        && let Some(problem) = media::find_corruption(staged.path(), path)?
+    {
+        return Ok(FileOutcome::Corrupt(in_path, problem));
+    }
+
+    // read before anything's archived when it decides whether anything is.
+    let mut metadata = None;
+    if args.skip_screenshots || args.collapse_bursts != BurstPolicy::All {
+        let read = metadata::read_metadata(staged.path(), Transform::None, path)
+            .inspect_err(|e| debug!("could not read the metadata of {in_path:?}: {e}"))
+            .unwrap_or_default();
+        if let Some(class) = left_out_as(ctx, &in_path, &read) {
+            debug!("leaving out {in_path:?}, which is a {class}");
+            store.record_classified_out(
+                &source.namespace,
+                &ClassifiedOut {
+                    path: path.to_path_buf(),
+                    last_modified: file_info.modified,
+                    size,
+                    class: class.to_string(),
+                },
+            )?;
+            return Ok(FileOutcome::LeftOut);
+        }
+        metadata = Some(read);
+    }
+
+    // held until the content is catalogued, so another new file with the same content waits to
+    // find it there rather than writing it too.
+    let claim = claims.claim(digest);
+    promote_partial_digests(ctx, staged.path(), size)?;
+    let mut archived = archived_digest(ctx, &digest, staged.path())?;
+    if let Some(archived_as) = archived
+        && args.paranoid
+        && !has_intact_copy(ctx, &archived_as, staged.path())?
+    {
+        warn!("no archived copy of {in_path:?} matches it byte for byte, so archiving it again");
+        archived = None;
+    }
+    let already_exists = archived.is_some();
+    // content new to the archive is indexed while its copy is at hand.
+    if !already_exists {
+        index_content(ctx, staged.path(), path, &in_path, &digest, metadata)?;
+    }
+    // a copy written is read back before the source is removed, and one already archived must
+    // match it byte for byte, which `--paranoid` has already checked.
+    let removable = moving
+        && match archived {
+            Some(archived_as) => {
+                args.paranoid || has_intact_copy(ctx, &archived_as, staged.path())?
+            }
+            None => true,
+        };
+    if already_exists {
+        debug!("the content of {in_path:?} is already archived");
+        stats.files_deduplicated.fetch_add(1);
+        stats.bytes_deduplicated.fetch_add(size);
+    }
+
+    let mut companion_failed = false;
+    // whether a duplicate was put in the out directory as a link to the archived copy.
+    let mut linked = false;
+    if !ctx.mode.is_live() {
+        if already_exists && args.dedupe_mode != DedupeMode::Skip {
+            info!("would link {out_path:?} to the archived copy of {in_path:?}");
+        } else if already_exists {
+            info!("would skip {in_path:?}, as its content is already archived");
+        } else {
+            info!("would copy {in_path:?} to {out_path:?} ({size} bytes)");
+            record.stored = true;
+            stats.files_transferred.fetch_add(1);
+            stats.bytes_transferred.fetch_add(size);
+            // catalogued in the dry run's copy, so later files with the same content are skipped.
+            store.mark_exists_in_target(*run, &destination, file_info.modified, size, &digest)?;
+        }
+    } else if !already_exists {
+        let (staged, written) =
+            match write_transformed(ctx, staged, transcoding, &in_data, &in_path, temp_dir)? {
+                Ok(written) => written,
+                Err(outcome) => return Ok(outcome),
+            };
+        // what the stored copy is checked against, which for a transcoded one is what it was
+        // transcoded to.
+        let content = written.transcoded.unwrap_or(digest);
+        let writing = PendingWrite {
+            target_path: destination.clone(),
+            digest,
+            last_modified: file_info.modified,
+            size,
+            transform: storage.transform,
+        };
+        let placed = match place(ctx, &storage, pending, &unstored, staged, &content, writing)? {
+            Ok(placed) => placed,
+            Err(outcome) => return Ok(outcome),
+        };
+        destination = placed.destination;
+        out_path = backend.location(&destination);
+        if placed.written {
+            // a copy which doesn't read back is removed before it's catalogued, so the file is
+            // transferred again by the next run.
+            if (args.paranoid || args.immutable || moving)
+                && let Err(e) = read_back(
+                    backend,
+                    &destination,
+                    storage.transform,
+                    &content,
+                    written.stored,
+                )
+            {
+                warn!("{e}, so removed it. Skipping and moving on.");
+                backend.remove(&destination)?;
+                return Ok(FileOutcome::FailedToCopy(
+                    in_path,
+                    FailureKind::DigestMismatch,
+                ));
+            }
+            if args.immutable {
+                immutable::set_immutable(&out_path, true)
+                    .wrap_err_with(|| format!("could not mark {out_path:?} immutable"))?;
+            }
+            debug!("copied {in_path:?} to {out_path:?} ({size} bytes)");
+            record.stored = true;
+            stats.files_transferred.fetch_add(1);
+            stats.bytes_transferred.fetch_add(size);
+        }
+        catalogue_copy(
+            ctx,
+            &destination,
+            placed.modified,
+            size,
+            &digest,
+            storage.transform,
+            &written,
+        )?;
+        // an identical file already in its place is its archived copy as much as one written.
+        companion_failed = carry_over_companion(ctx, &in_path, &destination, temp_dir)?;
+    } else if let Some(archived_as) = archived {
+        if args.dedupe_mode != DedupeMode::Skip {
+            linked = link_duplicate(ctx, &archived_as, &out_path)?;
+        }
+        if linked {
+            debug!("linked {out_path:?} to the archived copy of {in_path:?}");
+            let modified = FileInfo::of(&out_path)?.modified;
+            store.mark_exists_in_target(*run, &destination, modified, size, &digest)?;
+            store.record_transform(*run, &destination, Transform::None)?;
+        }
+        // the AppleDouble file goes with the link, or else with the copy already archived.
+        let archived_at = if linked {
+            Some(destination.clone())
+        } else if carries_companions(args.apple_double) {
+            (store.target_paths_with_digest(&archived_as)?)
+                .into_iter()
+                .find(|path| backend.location(path).is_file())
+        } else {
+            None
+        };
+        if let Some(archived_at) = archived_at {
+            companion_failed = carry_over_companion(ctx, &in_path, &archived_at, temp_dir)?;
+        }
+    }
+    drop(claim);
+
+    let stored_as = (!already_exists || linked).then_some(destination);
+    catalogue_source(ctx, path, &file_info, &digest, changed, archived, stored_as)?;
+
+    if !already_exists
+        && ctx.mode.is_live()
+        && let Some(file_hook) = &args.file_hook
+    {
+        let hook_result = run_hook(
+            "per-file",
+            file_hook,
+            &[
+                ("PHOTO_SYNC_PATH", &out_path.to_string_lossy()),
+                ("PHOTO_SYNC_DIGEST", &digest.to_string()),
+            ],
+        );
+        if let Err(e) = hook_result {
+            warn!("{e}");
+            return Ok(FileOutcome::FileHookFailed(out_path));
+        }
+    }
+
+    if companion_failed {
+        return Ok(FileOutcome::AppleDoubleFailed(in_path));
+    }
+    if removable {
+        remove_source(ctx, path, &file_info, &digest)?;
+    }
+    Ok(FileOutcome::Success)
+}
+
+/// Whether `policy` carries AppleDouble files over to the data file's archived copy.
+fn carries_companions(policy: AppleDoublePolicy) -> bool {
+    matches!(policy, AppleDoublePolicy::Pair | AppleDoublePolicy::Xattrs)
+}
+
+/// Carries the AppleDouble file of `in_path` over to the archived copy at `destination`,
+/// cataloguing it alongside if it's paired, and returns whether that failed.
+fn carry_over_companion(
+    ctx: &SyncContext,
+    in_path: &Path,
+    destination: &Path,
+    temp_dir: &Path,
+) -> Result<bool> {
+    let SyncContext {
+        store,
+        run,
+        args,
+        backend,
+        ..
+    } = ctx;
+    if !carries_companions(args.apple_double) {
+        return Ok(false);
+    }
+    let out_path = backend.location(destination);
+    let paired = match appledouble::carry_over(args.apple_double, in_path, &out_path, temp_dir) {
+        Ok(paired) => paired,
+        Err(e) => {
+            warn!("failed to carry over the AppleDouble file of {in_path:?}: {e}");
+            return Ok(true);
+        }
+    };
+    if let (Some(paired), Some(companion)) = (paired, appledouble::companion_of(destination)) {
+        let FileInfo { size, modified } = FileInfo::of(&paired)?;
+        let digest = args.hash_algo.digest(&paired)?;
+        store.mark_exists_in_target(*run, &companion, modified, size, &digest)?;
+        store.record_transform(*run, &companion, Transform::None)?;
+    }
+    Ok(false)
+}
+
+/// Opens the new file at `in_path`, downloading it first if it's only in iCloud, or returns the
+/// outcome of a file which couldn't be.
+fn open_source(ctx: &SyncContext, in_path: &Path) -> Result<File, FileOutcome> {
+    let args = ctx.args;
+    // opening an evicted file would download it as it's read, which may stall or fail partway.
+    if icloud::is_placeholder(in_path) {
+        let timeout = Duration::from_secs(args.download_timeout);
+        let downloaded = match args.placeholders {
+            PlaceholderPolicy::Skip => false,
+            PlaceholderPolicy::Download => icloud::download(in_path, timeout)
+                .inspect_err(|e| warn!("could not download {in_path:?}: {e}"))
+                .unwrap_or(false),
+        };
+        if !downloaded {
+            return Err(FileOutcome::NotDownloaded(in_path.to_path_buf()));
+        }
+    }
+    // errors on first open are tolerated - the file is just skipped.
+    File::open(in_path).map_err(|e| {
+        warn!("error when opening {in_path:?}. Skipping and moving on. {e}");
+        FileOutcome::FailedToOpen(in_path.to_path_buf(), FailureKind::of_io(&e))
+    })
+}
+
+/// How a new file's copy is stored in the archive, which its name says.
+struct Storage<'a> {
+    backend: &'a dyn TargetBackend,
+    /// How it's stored, which is catalogued, as an original's name can end like a copy's.
+    transform: Transform,
+    transcoded: bool,
+    compressed: bool,
+    encrypted: bool,
+    chunked: bool,
+}
+
+impl<'a> Storage<'a> {
+    /// How `ctx` stores a file's copy, which is `transcoded` if it's to be.
+    fn new(ctx: &SyncContext<'a>, transcoded: bool) -> Self {
+        let args = ctx.args;
+        let transform = if args.chunked {
+            Transform::Chunked
+        } else if args.compress {
+            Transform::Compressed
+        } else {
+            Transform::None
+        };
+        Self {
+            backend: ctx.backend,
+            transform,
+            transcoded,
+            compressed: args.compress,
+            encrypted: !ctx.recipients.is_empty(),
+            chunked: args.chunked,
+        }
+    }
+
+    /// What the copy of a file archived as `destination` is stored as.
+    fn stored_as(&self, destination: &Path) -> PathBuf {
+        let mut destination = destination.to_path_buf();
+        if self.transcoded {
+            destination = transcode::transcoded_path(&destination);
+        }
+        if self.compressed {
+            destination = compress::compressed_path(&destination);
+        }
+        if self.encrypted {
+            destination = encrypt::encrypted_path(&destination);
+        }
+        if self.chunked {
+            destination = chunks::chunked_path(&destination);
+        }
+        destination
+    }
+
+    /// Whether the copy of a file archived as `destination` would be stored where something
+    /// already is.
+    fn is_taken(&self, destination: &Path) -> bool {
+        (self.backend)
+            .exists(&self.stored_as(destination))
+            .unwrap_or(false)
+    }
+}
+
+/// Where the new file `path`, last modified at `modified`, is archived, before its content is
+/// known: where the plugin, or `--organize-by-date`, says, unless it's `placed_as` somewhere. A
+/// file the plugin fails for isn't transferred.
+fn pick_destination(
+    ctx: &SyncContext,
+    storage: &Storage,
+    path: &Path,
+    placed_as: Option<&Path>,
+    modified: SystemTime,
+) -> Result<PathBuf, FileOutcome> {
+    let SyncContext {
+        plugin,
+        args,
+        source,
+        ..
+    } = ctx;
+    let destination = match placed_as {
+        Some(placed_as) => return Ok(placed_as.to_path_buf()),
+        None => match plugin.destination(&source.archived_as(path)) {
+            Ok(destination) => destination,
+            Err(e) => {
+                warn!("the plugin failed for {path:?}: {e:#}");
+                return Err(FileOutcome::PluginFailed(source.dir.join(path)));
+            }
+        },
+    };
+    if args.layout != Layout::Mirror {
+        return Ok(destination);
+    }
+    Ok(if args.organize_by_date {
+        let taken = bydate::taken_in(&source.dir.join(path), modified);
+        bydate::dated_path(&destination, taken, |dated| storage.is_taken(dated))
+    } else if source.is_dated() {
+        // dated already, but the name may be taken by a file archived from elsewhere.
+        free_path(&destination, |path| storage.is_taken(path))
+    } else {
+        destination
+    })
+}
+
+/// A new file's content, copied to a temporary file.
+struct Staged {
+    copy: NamedTempFile,
+    /// Its digest by `--hash-algo`, taken as it was read.
+    digest: ContentHash,
+    /// Whether it was cloned or copied within the kernel, and so read separately to hash it.
+    fast_copied: bool,
+}
+
+/// Copies the new file at `in_path`, of `size` bytes and open as `in_data`, to a temporary file
+/// in `temp_dir`, hashing it as it's read, or returns the outcome of a file which couldn't be.
+fn stage(
+    ctx: &SyncContext,
+    in_data: &mut File,
+    in_path: &Path,
+    temp_dir: &Path,
+    size: u64,
+) -> Result<Result<Staged, FileOutcome>> {
+    let SyncContext {
+        args,
+        upload_throttle,
+        read_throttle,
+        ..
+    } = ctx;
+    let mut copy = NamedTempFile::new_in(temp_dir)?;
+    let fast_copied = match args.copy_strategy {
+        CopyStrategy::Auto => fastcopy::fast_copy(in_data, in_path, temp_dir),
+        CopyStrategy::Read => Ok(None),
+    };
+    let fast_copied = match fast_copied {
+        Ok(fast_copied) => fast_copied,
+        Err(e) => {
+            warn!("failed to copy {in_path:?}: {e}");
+            let kind = FailureKind::of_io(&e);
+            return Ok(Err(FileOutcome::FailedToCopy(in_path.to_path_buf(), kind)));
+        }
+    };
+    let cloned = fast_copied.is_some();
+    let mut reader = ThrottledReader::new(in_data, read_throttle);
+    let buffer_size = args.copy_buffer_kib * 1024;
+    let copied = match fast_copied {
+        Some((fast_copy, how)) => {
+            debug!("{how:?} {in_path:?} rather than reading and writing it");
+            copy = fast_copy;
+            upload_throttle.consumed(size);
+            // the copy wasn't written from here, so the in file is read to hash it.
+            let mut hasher = DigestWriter::with_algorithm(io::sink(), args.hash_algo);
+            copy::copy(&mut reader, &mut hasher, buffer_size).map(|_| hasher.finalise())
+        }
+        None => {
+            let mut writer = DigestWriter::with_algorithm(
+                ThrottledWriter::new(copy.as_file_mut(), upload_throttle),
+                args.hash_algo,
+            );
+            copy::copy(&mut reader, &mut writer, buffer_size).map(|_| writer.finalise())
+        }
+    };
+    match copied {
+        Ok(digest) => Ok(Ok(Staged {
+            copy,
+            digest: digest?,
+            fast_copied: cloned,
+        })),
+        Err(e) => {
+            warn!("failed to copy bytes of file {in_path:?}: {e}");
+            let kind = FailureKind::of_io(&e);
+            Ok(Err(FileOutcome::FailedToCopy(in_path.to_path_buf(), kind)))
+        }
+    }
+}
+
+/// Catalogues the metadata, and for `--perceptual-hash` the fingerprint, of the content `digest`,
+/// new to the archive, staged as `staged` from the new file `path` at `in_path`. Its metadata may
+/// have been read already.
+fn index_content(
+    ctx: &SyncContext,
+    staged: &Path,
+    path: &Path,
+    in_path: &Path,
+    digest: &ContentHash,
+    metadata: Option<MediaMetadata>,
+) -> Result<()> {
+    let SyncContext {
+        store, args, stats, ..
+    } = ctx;
+    let metadata = match metadata {
+        Some(metadata) => Ok(metadata),
+        None => metadata::read_metadata(staged, Transform::None, path),
+    };
+    match metadata {
+        Ok(metadata) => store.record_media_metadata(digest, &metadata)?,
+        Err(e) => debug!("could not read the metadata of {in_path:?}: {e}"),
+    }
+    if args.perceptual_hash && phash::is_heic(path) {
+        debug!("not fingerprinting {in_path:?}, as HEIC images can't be decoded");
+        stats.images_not_fingerprinted.fetch_add(1);
+    } else if args.perceptual_hash && phash::is_image(path) {
+        match phash::fingerprint(staged, Transform::None) {
+            Ok(fingerprint) => store.record_image_fingerprint(digest, &fingerprint)?,
+            Err(e) => {
+                debug!("could not fingerprint {in_path:?}: {e}");
+                stats.images_not_fingerprinted.fetch_add(1);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The digests of a new file's copy as it was written to be stored.
+struct Written {
+    /// The digest of what it was transcoded to, if it was, which the stored copy is checked
+    /// against.
+    transcoded: Option<ContentHash>,
+    /// The digest of the bytes as stored, when they can't be read back as the content.
+    stored: Option<ContentHash>,
+}
+
+/// Writes `staged`, the copy of the new file at `in_path`, open as `in_data`, as it's to be stored:
+/// transcoded by `transcoding`, if given, then compressed, encrypted and chunked as `ctx` says,
+/// with the original's times and extended attributes. Returns the outcome of a file which
+/// couldn't be transcoded instead.
+fn write_transformed(
+    ctx: &SyncContext,
+    mut staged: NamedTempFile,
+    transcoding: Option<&Transcoder>,
+    in_data: &File,
+    in_path: &Path,
+    temp_dir: &Path,
+) -> Result<Result<(NamedTempFile, Written), FileOutcome>> {
+    let SyncContext {
+        args,
+        recipients,
+        chunks,
+        ..
+    } = ctx;
+    let mut transcoded = None;
+    if let Some(transcoder) = transcoding {
+        let output = tempfile::Builder::new()
+            .suffix(".jpg")
+            .tempfile_in(temp_dir)?;
+        if let Err(e) = transcoder.transcode(staged.path(), output.path()) {
+            warn!("{e:#}. Skipping and moving on.");
+            let kind = FailureKind::of(&e);
+            return Ok(Err(FileOutcome::FailedToCopy(in_path.to_path_buf(), kind)));
+        }
+        transcoded = Some(args.hash_algo.digest(output.path())?);
+        staged = output;
+    }
+    if args.compress {
+        let mut compressed = NamedTempFile::new_in(temp_dir)?;
+        compress::compress(
+            staged.path(),
+            compressed.as_file_mut(),
+            args.compression_level,
+        )?;
+        staged = compressed;
+    }
+    let mut stored = None;
+    if !recipients.is_empty() {
+        let mut encrypted = NamedTempFile::new_in(temp_dir)?;
+        let mut writer = DigestWriter::with_algorithm(encrypted.as_file_mut(), args.hash_algo);
+        encrypt::encrypt(staged.path(), &mut writer, recipients)?;
+        stored = Some(writer.finalise()?);
+        staged = encrypted;
+    }
+    if let Some(chunks) = chunks {
+        staged = chunks.store(staged.path(), temp_dir)?;
+    }
+    // set before the copy is renamed into place, so it's never seen without them.
+    if let Err(e) = copy_times(&in_data.metadata()?, staged.as_file()) {
+        warn!("failed to carry over the modification time of {in_path:?}: {e}");
+    }
+    if args.preserve_xattrs
+        && let Err(e) = copy_xattrs(in_path, staged.path())
+    {
+        warn!("failed to carry over the extended attributes of {in_path:?}: {e}");
+    }
+    if args.paranoid || args.fsync {
+        staged.as_file().sync_all()?;
+    }
+    Ok(Ok((staged, Written { transcoded, stored })))
+}
+
+/// Where a new file's copy went in the archive.
+struct Placed {
+    /// What it's stored as.
+    destination: PathBuf,
+    /// When it was last modified there.
+    modified: SystemTime,
+    /// Whether it was written there, rather than found there already.
+    written: bool,
+}
+
+/// Puts `staged`, the copy of the file `pending` transfers, to be archived as `unstored`, in its
+/// place, with `writing` journalled while it's written. A place already taken is resolved as
+/// `--on-collision` says, comparing what's there against `content`. Returns the outcome of a file
+/// with no place to go instead.
+fn place(
+    ctx: &SyncContext,
+    storage: &Storage,
+    pending: &PendingTransfer,
+    unstored: &Path,
+    staged: NamedTempFile,
+    content: &ContentHash,
+    mut writing: PendingWrite,
+) -> Result<Result<Placed, FileOutcome>> {
+    let SyncContext {
+        store,
+        run,
+        args,
+        source,
+        ..
+    } = ctx;
+    let backend = storage.backend;
+    let path = pending.path.as_path();
+    let mut destination = writing.target_path.clone();
+    // files review decided to retransfer after colliding are archived wherever they can be.
+    let policy = match store.review_decision(&source.namespace, path)? {
+        Some(ReviewDecision::Retransfer) => CollisionPolicy::Rename,
+        _ => args.on_collision,
+    };
+    if backend.exists(&destination)? {
+        let resolved =
+            resolve_collision(ctx, storage, path, unstored, &destination, content, policy)?;
+        match resolved {
+            Collision::Identical(modified) => {
+                return Ok(Ok(Placed {
+                    destination,
+                    modified,
+                    written: false,
+                }));
+            }
+            Collision::Renamed(renamed) => destination = renamed,
+            Collision::Refused(outcome) => return Ok(Err(outcome)),
+        }
+    }
+    writing.target_path = destination.clone();
+    store.journal_transfer(
+        *run,
+        &source.namespace,
+        &PendingTransfer {
+            writing: Some(writing),
+            ..pending.clone()
+        },
+    )?;
+    match backend.put(staged, &destination) {
+        Ok(modified) => Ok(Ok(Placed {
+            destination,
+            modified,
+            written: true,
+        })),
+        // e.g. another machine sharing the catalogue has just written there. The copy has gone
+        // with the failed write, so it can't be archived under another name now.
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            let policy = match policy {
+                CollisionPolicy::Rename => CollisionPolicy::OverwriteIfIdentical,
+                policy => policy,
+            };
+            let resolved =
+                resolve_collision(ctx, storage, path, unstored, &destination, content, policy)?;
+            Ok(match resolved {
+                Collision::Identical(modified) => Ok(Placed {
+                    destination,
+                    modified,
+                    written: false,
+                }),
+                Collision::Renamed(_) => unreachable!("copies which have gone aren't renamed"),
+                Collision::Refused(outcome) => Err(outcome),
+            })
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// What's done about a new file's place in the archive being taken.
+enum Collision {
+    /// The file there has the same content, so is kept as its copy. It was last modified then.
+    Identical(SystemTime),
+    /// It's archived under this name instead.
+    Renamed(PathBuf),
+    /// It isn't transferred, with this outcome.
+    Refused(FileOutcome),
+}
+
+/// How `policy` resolves the place `destination` of the new file `path`, to be archived as
+/// `unstored`, being taken, comparing what's there against `content`. The resolution is
+/// catalogued.
+fn resolve_collision(
+    ctx: &SyncContext,
+    storage: &Storage,
+    path: &Path,
+    unstored: &Path,
+    destination: &Path,
+    content: &ContentHash,
+    policy: CollisionPolicy,
+) -> Result<Collision> {
+    let SyncContext {
+        store, run, source, ..
+    } = ctx;
+    let backend = storage.backend;
+    let (in_path, out_path) = (source.dir.join(path), backend.location(destination));
+    let record = |target_path: &Path, resolution| {
+        store.record_collision(*run, &source.namespace, path, target_path, resolution)
+    };
+    if policy != CollisionPolicy::Error
+        && holds_content(backend, destination, storage.transform, content)
+    {
+        debug!("{out_path:?} already holds the content of {in_path:?}, so is kept");
+        record(destination, "identical")?;
+        let modified = (backend.modified(destination)?).ok_or_eyre("the archived copy has gone")?;
+        return Ok(Collision::Identical(modified));
+    }
+    Ok(match policy {
+        CollisionPolicy::Rename => {
+            let renamed = storage.stored_as(&free_path(unstored, |p| storage.is_taken(p)));
+            info!("{out_path:?} is taken, so archiving {in_path:?} as {renamed:?}");
+            record(&renamed, "renamed")?;
+            Collision::Renamed(renamed)
+        }
+        CollisionPolicy::Skip => {
+            record(destination, "skipped")?;
+            Collision::Refused(FileOutcome::Skipped(in_path, out_path))
+        }
+        CollisionPolicy::Error | CollisionPolicy::OverwriteIfIdentical => {
+            warn!(
+                "{out_path:?} is taken, so {in_path:?} can't be archived there; see --on-collision"
+            );
+            record(destination, "failed")?;
+            Collision::Refused(FileOutcome::Collided(in_path, out_path))
+        }
+    })
+}
+
+/// Catalogues the copy stored as `destination`, last modified at `modified`, of `size` bytes of
+/// the content `digest`, as `written` was written and stored as `transform`.
+fn catalogue_copy(
+    ctx: &SyncContext,
+    destination: &Path,
+    modified: SystemTime,
+    size: u64,
+    digest: &ContentHash,
+    transform: Transform,
+    written: &Written,
+) -> Result<()> {
+    let SyncContext { store, run, .. } = ctx;
+    store.mark_exists_in_target(*run, destination, modified, size, digest)?;
+    store.record_transform(*run, destination, transform)?;
+    if let Some(transcoded) = &written.transcoded {
+        store.record_transcoded(*run, destination, transcoded)?;
+    }
+    if let Some(stored) = &written.stored {
+        store.record_encrypted(*run, destination, stored)?;
+    }
+    Ok(())
+}
+
+/// Catalogues the source file `path`, as `file_info` says it was copied with the content
+/// `digest`, which is stored as `stored_as` if it was put in the archive for it: as a new version
+/// of the file, if it `changed`, or one renamed from a path which has gone, if its content was
+/// `archived` already, and otherwise as a file transferred for the first time.
+fn catalogue_source(
+    ctx: &SyncContext,
+    path: &Path,
+    file_info: &FileInfo,
+    digest: &ContentHash,
+    changed: Option<(ContentHash, u32)>,
+    archived: Option<ContentHash>,
+    stored_as: Option<PathBuf>,
+) -> Result<()> {
+    let SyncContext {
+        store, run, source, ..
+    } = ctx;
+    let namespace = &source.namespace;
+    // content already transferred from a path which has since gone was renamed to this one.
+    let renamed_from = if let Some(archived_as) = archived
+        && changed.is_none()
+    {
+        store
+            .source_paths_with_digest(namespace, &archived_as)?
+            .into_iter()
+            .find(|from| !source.dir.join(from).exists())
+    } else {
+        None
+    };
+    match (changed, renamed_from) {
+        (Some((original, version)), _) => {
+            let version = SourceVersion {
+                path: path.to_path_buf(),
+                version,
+                digest: *digest,
+                target_path: stored_as,
+                archived_at: Some(SystemTime::now()),
+            };
+            store.record_version(*run, namespace, &original, &version)?;
+            store.update_source(
+                *run,
+                namespace,
+                path,
+                digest,
+                file_info.modified,
+                file_info.size,
+            )?;
+            info!("{path:?} changed, kept as version {}", version.version);
+        }
+        (None, Some(from))
+            if store.rename_source(
+                *run,
+                namespace,
+                &from,
+                path,
+                file_info.modified,
+                file_info.size,
+            )? =>
+        {
+            info!("{from:?} was renamed to {path:?}");
+        }
+        _ => {
+            let transferred = TransferredSource {
+                path: path.to_path_buf(),
+                digest: *digest,
+                last_modified: file_info.modified,
+                size: file_info.size,
+                target_path: stored_as,
+            };
+            if let Some(batch) = ctx.transferred_sources.push(transferred) {
+                store.mark_transferred_from_source_batch(*run, namespace, &batch)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// What the file at `in_path`, with `metadata`, is left out of the archive as, if it is.
+fn left_out_as(
+    ctx: &SyncContext,
+    in_path: &Path,
+    metadata: &MediaMetadata,
+) -> Option<&'static str> {
+    if ctx.args.skip_screenshots && metadata.screenshot {
+        return Some("screenshot");
+    }
+    if ctx.args.collapse_bursts == BurstPolicy::BestOnly
+        && let Some(burst_id) = &metadata.burst_id
+        && !ctx.bursts.is_best(in_path, burst_id)
+    {
+        return Some("burst");
+    }
+    None
+}
+
+/// Deletes the in directory's file `path`, whose content `digest` is archived, for `--move` or
+/// from a staging in directory. It's kept if it changed after it was copied, as its new content
+/// isn't archived.
+fn remove_source(
+    ctx: &SyncContext,
+    path: &Path,
+    copied: &FileInfo,
+    digest: &ContentHash,
+) -> Result<()> {
+    let in_path = ctx.source.dir.join(path);
+    if !ctx.mode.is_live() {
+        info!("would remove {in_path:?}, as it's archived");
+        return Ok(());
+    }
+    if !paranoid::unchanged_since(&in_path, copied)? {
+        warn!("{in_path:?} changed after it was copied, so it was kept");
+        return Ok(());
+    }
+    fs::remove_file(&in_path).wrap_err_with(|| format!("could not remove {in_path:?}"))?;
+    // each of a staging directory's files has a directory of its own, which is left empty.
+    if ctx.source.is_staging()
+        && let Some(dir) = in_path.parent()
+    {
+        let _ = fs::remove_dir(dir);
+    }
+    ctx.store
+        .record_deleted_source(ctx.run, &ctx.source.namespace, path, digest, copied.size)
+}
+
+/// Once the file at `path` has been transferred to `destination`, transfers the video of the Live
+/// Photo it's the still of, if it is one, with `transfer`, named to match it.
+fn transfer_live_photo_partner(
+    ctx: &SyncContext,
+    path: &Path,
+    destination: Option<&Path>,
+    transfer: impl Fn(&Path, Option<&Path>) -> Result<(FileOutcome, Option<PathBuf>)>,
+) -> Result<FileOutcome> {
+    let SyncContext {
+        store,
+        run,
+        stats,
+        source,
+        ..
+    } = ctx;
+    let in_dir = &source.dir;
+    // the video of a still transferred before it, which stays where it was put, or of one which
+    // isn't to be transferred, and so isn't a pair in the archive.
+    if let Some(still) = livephoto::still_of(in_dir, path) {
+        if source_is_transferred(ctx, &still)? {
+            store.record_live_photo(*run, &source.namespace, &still, path)?;
+        }
+        return Ok(FileOutcome::Success);
+    }
+    let (Some(video), Some(destination)) = (livephoto::video_of(in_dir, path), destination) else {
+        return Ok(FileOutcome::Success);
+    };
+    match partner_wanted(ctx, &video)? {
+        Ok(true) => {
+            stats.files_detected.fetch_add(1);
+            let placed_as = destination.with_extension(video.extension().unwrap_or_default());
+            let (outcome, _) = transfer(&video, Some(&placed_as))?;
+            if !matches!(outcome, FileOutcome::Success) {
+                return Ok(outcome);
+            }
+        }
+        Ok(false) => {}
+        Err(outcome) => return Ok(outcome),
+    }
+    store.record_live_photo(*run, &source.namespace, path, &video)?;
+    Ok(FileOutcome::Success)
+}
+
+/// Transfers the AAE and XMP sidecars of `path`, just transferred to `destination`, beside it.
+fn transfer_sidecars(
+    ctx: &SyncContext,
+    path: &Path,
+    destination: Option<&Path>,
+    transfer: impl Fn(&Path, Option<&Path>) -> Result<(FileOutcome, Option<PathBuf>)>,
+) -> Result<FileOutcome> {
+    let SyncContext {
+        store,
+        run,
+        stats,
+        source,
+        ..
+    } = ctx;
+    let in_dir = &source.dir;
+    // the sidecar of a file transferred before it, which stays where it was put, or of one which
+    // isn't to be transferred, so went alone.
+    if let Some(parent) = sidecar::parent_of(in_dir, path) {
+        if source_is_transferred(ctx, &parent)? {
+            store.record_sidecar(*run, &source.namespace, &parent, path)?;
+        }
+        return Ok(FileOutcome::Success);
+    }
+    let Some(destination) = destination else {
+        return Ok(FileOutcome::Success);
+    };
+    for sidecar in sidecar::sidecars_of(in_dir, path) {
+        match partner_wanted(ctx, &sidecar)? {
+            Ok(true) => {
+                stats.files_detected.fetch_add(1);
+                let placed_as = sidecar::placed_beside(&sidecar, path, destination);
+                let (outcome, _) = transfer(&sidecar, Some(&placed_as))?;
+                if !matches!(outcome, FileOutcome::Success) {
+                    return Ok(outcome);
+                }
+            }
+            Ok(false) => {}
+            Err(outcome) => return Ok(outcome),
+        }
+        store.record_sidecar(*run, &source.namespace, path, &sidecar)?;
+    }
+    Ok(FileOutcome::Success)
+}
+
+/// Whether `partner`, a file detection left to go along with another, is to be transferred now
+/// that the other has been, or the outcome of the partner if the plugin fails for it.
+fn partner_wanted(ctx: &SyncContext, partner: &Path) -> Result<Result<bool, FileOutcome>> {
+    let SyncContext {
+        store,
+        plugin,
+        args,
+        source,
+        file_filters,
+        ..
+    } = ctx;
+    let info = FileInfo::of(&source.dir.join(partner))?;
+    if let Some(left_out) = file_filters.leaves_out(partner, info.size, info.modified) {
+        debug!("not transferring {partner:?} along with the file it goes with: {left_out:?}");
+        return Ok(Ok(false));
+    }
+    Ok(Ok(
+        match store.was_transferred_from_source(
+            &source.namespace,
+            partner,
+            info.modified,
+            info.size,
+        )? {
+            WasTransferredFromSourceResult::New => match plugin.accept(partner) {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("the plugin failed for {partner:?}: {e:#}");
+                    return Ok(Err(FileOutcome::PluginFailed(source.dir.join(partner))));
+                }
+            },
+            WasTransferredFromSourceResult::NewMetadata { .. } => args.keep_versions,
+            WasTransferredFromSourceResult::Transferred => false,
+        },
+    ))
+}
+
+pub fn transfer_new_files(ctx: &SyncContext, files: Receiver<PathBuf>) -> Result<()> {
+    info!("starting phase 3: transferring new files");
+    if ctx.args.cache_digests {
+        ctx.digests.load(ctx.store)?;
+    }
+    let stats = ctx.stats;
+    let not_fingerprinted_before = stats.images_not_fingerprinted.as_u64();
+    let files_considered = SimpleAtomicU64::default();
+    let bytes_considered = SimpleAtomicU64::default();
+    // detection may still be finding files, so the total grows as it goes.
+    let progress = PhaseProgress::new(
+        "transferring",
+        &files_considered,
+        Some(&bytes_considered),
+        Some(&stats.files_detected),
+    );
+
+    let transfer_one = |path: &Path, placed_as: Option<&Path>| {
+        let _working = ctx.current.working_on(&ctx.source.dir.join(path));
+        let started = Instant::now();
+        let mut record = TransferRecord::default();
+        let mut outcome = transfer_file(ctx, path, placed_as, &mut record);
+        // e.g. iCloud Drive refusing to open a file while it's downloading it.
+        for retry in 0..ctx.args.retries {
+            if !matches!(
+                outcome,
+                Ok(FileOutcome::FailedToOpen(..) | FileOutcome::FailedToCopy(..))
+            ) {
+                break;
+            }
+            let delay = Duration::from_secs(ctx.args.retry_delay << retry.min(16));
+            debug!("retrying {path:?} in {delay:?}");
+            thread::sleep(delay);
+            record = TransferRecord::default();
+            outcome = transfer_file(ctx, path, placed_as, &mut record);
+        }
+        let elapsed = started.elapsed();
+        ctx.timings.record(
+            Work::Transferring,
+            &ctx.source.dir.join(path),
+            record.bytes.unwrap_or_default(),
+            elapsed,
+        );
+        if let Some(transfer_log) = ctx.transfer_log {
+            let described = match &outcome {
+                Ok(outcome) => outcome.describe(record.stored),
+                Err(_) => "error",
+            };
+            transfer_log.record(path, described, &record, elapsed)?;
+        }
+        if let Some(size) = record.bytes {
+            bytes_considered.fetch_add(size);
+        }
+
+        files_considered.fetch_add(1);
+        progress.tick();
+        outcome.map(|outcome| (outcome, record.destination))
+    };
+    // listed since the file was detected, or detected by an earlier watch batch.
+    let ignore_list = IgnoreList::new(&ctx.store.ignored_paths()?)?;
+    let transfer = |path: PathBuf| {
+        ctx.pause.wait_if_paused()?;
+        if ignore_list.is_ignored(&path) {
+            debug!("not transferring {path:?}, which is on the ignore list");
+            return Ok(FileOutcome::Success);
+        }
+        match transfer_one(&path, None)? {
+            (FileOutcome::Success, destination) => {
+                let destination = destination.as_deref();
+                match transfer_sidecars(ctx, &path, destination, transfer_one)? {
+                    FileOutcome::Success => {
+                        transfer_live_photo_partner(ctx, &path, destination, transfer_one)
+                    }
+                    outcome => Ok(outcome),
+                }
+            }
+            (outcome, _) => Ok(outcome),
+        }
+    };
+    // only failures are reported, so there's no need to hold on to every success.
+    let results: Result<Vec<_>> = if ctx.args.sequential_per_device {
+        let in_dir = &ctx.source.dir;
+        devices::try_map_per_device(
+            files,
+            |path| devices::device_of(&in_dir.join(path)),
+            |path| Ok(Some(transfer(path)?).filter(|o| !matches!(o, FileOutcome::Success))),
+        )
+    } else {
+        files
+            .into_iter()
+            .par_bridge()
+            .map(transfer)
+            .filter(|outcome| !matches!(outcome, Ok(FileOutcome::Success)))
+            .collect()
+    };
+    // the files transferred before any failure are still catalogued.
+    ctx.store.mark_transferred_from_source_batch(
+        ctx.run,
+        &ctx.source.namespace,
+        &ctx.transferred_sources.take(),
+    )?;
+    let results = results?;
+    let mut failures = HashMap::<FailureKind, u64>::new();
+    for outcome in &results {
+        match outcome {
+            FileOutcome::FailedToOpen(..) => stats.files_failed_to_open.fetch_add(1),
+            FileOutcome::FailedToCopy(..) => stats.files_failed_to_copy.fetch_add(1),
+            FileOutcome::NotDownloaded(_) => stats.files_not_downloaded.fetch_add(1),
+            _ => 0,
+        };
+        if let Some(kind) = outcome.failure() {
+            stats.files_failed.fetch_add(1);
+            stats.record_failures(kind, 1);
+            *failures.entry(kind).or_default() += 1;
+        }
+        if let FileOutcome::FailedToOpen(path, _)
+        | FileOutcome::FailedToCopy(path, _)
+        | FileOutcome::NotDownloaded(path)
+        | FileOutcome::PluginFailed(path) = outcome
+            && let Ok(path) = path.strip_prefix(&ctx.source.dir)
+        {
+            ctx.store.record_failed_transfer(
+                ctx.run,
+                &ctx.source.namespace,
+                path,
+                outcome.describe(false),
+            )?;
+        }
+    }
+    for (kind, files) in failures {
+        ctx.store.record_run_failures(ctx.run, kind, files)?;
+    }
+
+    // a summary at the end, as the warnings for each file are mixed in with the run's progress.
+    for outcome in &results {
+        match outcome {
+            FileOutcome::Success => {}
+            FileOutcome::FailedToOpen(path, kind) | FileOutcome::FailedToCopy(path, kind) => {
+                warn!("could not transfer {path:?} ({kind})");
+            }
+            FileOutcome::FileHookFailed(path) => warn!("the per-file hook failed for {path:?}"),
+            FileOutcome::PluginFailed(path) => warn!("the plugin failed for {path:?}"),
+            FileOutcome::AppleDoubleFailed(path) => {
+                warn!("the AppleDouble file of {path:?} could not be carried over");
+            }
+            FileOutcome::Corrupt(path, problem) => {
+                warn!("not transferring {path:?}, which looks corrupt: {problem}");
+            }
+            FileOutcome::NotDownloaded(path) => {
+                info!("{path:?} is only in iCloud, so is left for a later run");
+            }
+            FileOutcome::Collided(path, out_path) => {
+                warn!("could not transfer {path:?}, as {out_path:?} is taken");
+            }
+            FileOutcome::Skipped(path, out_path) => {
+                warn!("not transferring {path:?}, as {out_path:?} already holds other content");
+            }
+            FileOutcome::LeftOut => {}
+        }
+    }
+    let left_out = (results.iter())
+        .filter(|outcome| matches!(outcome, FileOutcome::LeftOut))
+        .count();
+    if left_out > 0 {
+        info!("{left_out} screenshots, burst frames and tombstoned files were left out");
+    }
+    let not_fingerprinted = stats.images_not_fingerprinted.as_u64() - not_fingerprinted_before;
+    if not_fingerprinted > 0 {
+        info!("{not_fingerprinted} new images couldn't be fingerprinted, most likely as HEIC");
+    }
+
+    info!("finished phase 3: transferring new files");
+
+    Ok(())
+}
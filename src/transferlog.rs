@@ -0,0 +1,96 @@
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+use eyre::{Result, WrapErr};
+
+use crate::digest::ContentHash;
+
+/// What was learnt about a file while transferring it, for the transfer log.
+#[derive(Default)]
+pub struct TransferRecord {
+    pub bytes: Option<u64>,
+    pub digest: Option<ContentHash>,
+    /// Whether the file was written to the out directory, rather than found to be a duplicate.
+    pub stored: bool,
+    /// Where in the out directory the file goes, before any suffix for how it's stored.
+    pub destination: Option<PathBuf>,
+}
+
+/// A CSV file with a row for every file the transfer phase processes.
+pub struct TransferLog(Mutex<csv::Writer<File>>);
+
+impl TransferLog {
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path).wrap_err_with(|| format!("could not create {path:?}"))?;
+        let mut writer = csv::Writer::from_writer(file);
+        writer.write_record(["path", "outcome", "bytes", "duration_ms", "digest"])?;
+        Ok(Self(Mutex::new(writer)))
+    }
+
+    pub fn record(
+        &self,
+        path: &Path,
+        outcome: &str,
+        record: &TransferRecord,
+        duration: Duration,
+    ) -> Result<()> {
+        let mut writer = self.0.lock().unwrap();
+        writer.write_record([
+            &*path.to_string_lossy(),
+            outcome,
+            &record.bytes.map(|b| b.to_string()).unwrap_or_default(),
+            &duration.as_millis().to_string(),
+            &record.digest.map(|d| d.to_string()).unwrap_or_default(),
+        ])?;
+        // flushed row by row, so the log is complete up to the point a run dies.
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_has_a_row_per_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("run.csv");
+        let log = TransferLog::create(&path).unwrap();
+        let copied = TransferRecord {
+            bytes: Some(5),
+            digest: Some(ContentHash::new_for_tests(1)),
+            stored: true,
+            ..TransferRecord::default()
+        };
+        log.record(
+            Path::new("a, b.jpg"),
+            "copied",
+            &copied,
+            Duration::from_millis(12),
+        )
+        .unwrap();
+        log.record(
+            Path::new("c.jpg"),
+            "failed to open",
+            &TransferRecord::default(),
+            Duration::ZERO,
+        )
+        .unwrap();
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "path,outcome,bytes,duration_ms,digest",
+                &format!("\"a, b.jpg\",copied,5,12,{}", "01".repeat(32)),
+                "c.jpg,failed to open,,0,",
+            ]
+        );
+    }
+}
@@ -0,0 +1,149 @@
+//! Moving archived files aside into a dated `.trash` directory, rather than deleting or
+//! overwriting them outright, so that a mistaken prune or repair can be undone until the trash is
+//! emptied.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use chrono::{Days, Local, NaiveDate};
+use eyre::{Result, WrapErr};
+use walkdir::DirEntry;
+
+use crate::{immutable, review::QUARANTINE_DIR};
+
+/// The directory, at the top of an archive directory, files are moved aside into.
+pub const TRASH_DIR: &str = ".trash";
+/// The format of the name of each day's directory within the trash.
+const DAY_FORMAT: &str = "%Y-%m-%d";
+
+/// The trash of one archive directory, e.g. the out directory.
+pub struct Trash {
+    root: PathBuf,
+    retention_days: u64,
+}
+
+impl Trash {
+    pub fn new(root: &Path, retention_days: u64) -> Self {
+        Self {
+            root: root.to_path_buf(),
+            retention_days,
+        }
+    }
+
+    /// Moves `path`, which is under the archive directory, into today's trash, keeping its path
+    /// relative to the archive directory. Returns where it went.
+    pub fn discard(&self, path: &Path) -> Result<PathBuf> {
+        let to = self.destination(path)?;
+        if fs::rename(path, &to).is_err() {
+            // immutable files can't be moved, and not every platform can unmark them.
+            let _ = immutable::set_immutable(path, false);
+            fs::rename(path, &to).wrap_err_with(|| format!("could not move {path:?} to {to:?}"))?;
+        }
+        Ok(to)
+    }
+
+    /// Keeps a copy of `path` in today's trash, e.g. before it's overwritten in place. The copy is
+    /// a hardlink where possible, so takes no space until `path` is replaced.
+    pub fn keep_copy(&self, path: &Path) -> Result<PathBuf> {
+        let to = self.destination(path)?;
+        if fs::hard_link(path, &to).is_err() {
+            fs::copy(path, &to).wrap_err_with(|| format!("could not copy {path:?} to {to:?}"))?;
+        }
+        Ok(to)
+    }
+
+    /// Deletes the days of trash older than the retention period, returning how many there were.
+    pub fn empty_expired(&self) -> Result<usize> {
+        let dir = self.root.join(TRASH_DIR);
+        let Some(expired_before) = Local::now()
+            .date_naive()
+            .checked_sub_days(Days::new(self.retention_days))
+        else {
+            return Ok(0);
+        };
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        let mut emptied = 0;
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name();
+            // anything not named as a day wasn't put there by the trash, so is left alone.
+            let Ok(day) = NaiveDate::parse_from_str(&name.to_string_lossy(), DAY_FORMAT) else {
+                continue;
+            };
+            if day < expired_before {
+                fs::remove_dir_all(entry.path())?;
+                emptied += 1;
+            }
+        }
+        Ok(emptied)
+    }
+
+    /// Where `path` goes in today's trash, numbered if something with its name is already there.
+    fn destination(&self, path: &Path) -> Result<PathBuf> {
+        let relative = path.strip_prefix(&self.root)?;
+        let day = Local::now().format(DAY_FORMAT).to_string();
+        let mut to = self.root.join(TRASH_DIR).join(day).join(relative);
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let name = to.file_name().unwrap_or_default().to_os_string();
+        for n in 1.. {
+            if !to.exists() {
+                break;
+            }
+            let mut numbered = name.clone();
+            numbered.push(format!(".{n}"));
+            to.set_file_name(numbered);
+        }
+        Ok(to)
+    }
+}
+
+/// Whether a walk of an archive directory has reached its trash, or the files review quarantined,
+/// neither of which are part of the archive.
+pub fn is_trash(entry: &DirEntry) -> bool {
+    entry.depth() == 1 && (entry.file_name() == TRASH_DIR || entry.file_name() == QUARANTINE_DIR)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files_are_moved_aside_until_they_expire() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("2020")).unwrap();
+        fs::write(root.join("2020/a.jpg"), "photo").unwrap();
+        let trash = Trash::new(root, 30);
+
+        let copy = trash.keep_copy(&root.join("2020/a.jpg")).unwrap();
+        let discarded = trash.discard(&root.join("2020/a.jpg")).unwrap();
+        assert!(!root.join("2020/a.jpg").exists());
+        let today = root
+            .join(TRASH_DIR)
+            .join(Local::now().format(DAY_FORMAT).to_string());
+        assert_eq!(copy, today.join("2020/a.jpg"));
+        assert_eq!(discarded, today.join("2020/a.jpg.1"));
+        assert_eq!(fs::read_to_string(&discarded).unwrap(), "photo");
+
+        let old = root.join(TRASH_DIR).join("2000-01-01");
+        fs::create_dir_all(&old).unwrap();
+        fs::write(old.join("b.jpg"), "old").unwrap();
+        assert_eq!(trash.empty_expired().unwrap(), 1);
+        assert!(!old.exists());
+        assert!(discarded.exists());
+        let walked: Vec<_> = walkdir::WalkDir::new(root)
+            .into_iter()
+            .filter_entry(|e| !is_trash(e))
+            .map(|e| e.unwrap().into_path())
+            .collect();
+        assert_eq!(walked, [root.to_path_buf(), root.join("2020")]);
+    }
+}
@@ -0,0 +1,79 @@
+//! sqlite settings for the catalogue, for when its defaults suit a large catalogue badly, e.g.
+//! one of hundreds of megabytes on a NAS. Anything not given is left as sqlite has it.
+
+use clap::ValueEnum;
+use rusqlite::Connection;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StoreTuning {
+    pub cache_size_mb: Option<u64>,
+    pub mmap_size_mb: Option<u64>,
+    pub temp_store: Option<TempStore>,
+    pub synchronous: Option<Synchronous>,
+}
+
+/// Where sqlite keeps temporary tables and indexes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum TempStore {
+    Default,
+    File,
+    Memory,
+}
+
+/// How often sqlite waits for writes to reach the disk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Synchronous {
+    Off,
+    Normal,
+    Full,
+    Extra,
+}
+
+impl StoreTuning {
+    pub fn apply(&self, conn: &Connection) -> rusqlite::Result<()> {
+        if let Some(mb) = self.cache_size_mb {
+            // negative sizes are in KiB rather than pages.
+            conn.pragma_update(None, "cache_size", -((mb * 1024) as i64))?;
+        }
+        if let Some(mb) = self.mmap_size_mb {
+            // reports the size it settled on, as a row.
+            conn.pragma_update_and_check(None, "mmap_size", mb * 1024 * 1024, |_| Ok(()))?;
+        }
+        if let Some(temp_store) = self.temp_store {
+            conn.pragma_update(None, "temp_store", temp_store as i64)?;
+        }
+        if let Some(synchronous) = self.synchronous {
+            conn.pragma_update(None, "synchronous", synchronous as i64)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pragmas_are_set() {
+        let conn = Connection::open_in_memory().unwrap();
+        let pragma = |name: &str| -> i64 {
+            conn.query_row(&format!("PRAGMA {name}"), [], |r| r.get(0))
+                .unwrap()
+        };
+        let default_cache_size = pragma("cache_size");
+        StoreTuning::default().apply(&conn).unwrap();
+        assert_eq!(pragma("cache_size"), default_cache_size);
+
+        StoreTuning {
+            cache_size_mb: Some(64),
+            mmap_size_mb: None,
+            temp_store: Some(TempStore::Memory),
+            synchronous: Some(Synchronous::Normal),
+        }
+        .apply(&conn)
+        .unwrap();
+        assert_eq!(pragma("cache_size"), -64 * 1024);
+        assert_eq!(pragma("temp_store"), 2);
+        assert_eq!(pragma("synchronous"), 1);
+    }
+}
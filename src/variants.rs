@@ -0,0 +1,204 @@
+//! Reporting the same image archived at more than one resolution, e.g. an original alongside the
+//! "optimised" copy iCloud downloads, recommending the largest to keep.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use eyre::Result;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+use crate::{
+    VariantsArgs,
+    compress::{Transform, original_path},
+    digest::ContentHash,
+    phash::{self, ImageFingerprint, is_image},
+    store::PhotoSyncStore,
+};
+
+/// Aspect ratios further apart than this are different crops rather than resizes.
+const ASPECT_TOLERANCE: f64 = 0.02;
+
+#[derive(Debug)]
+pub struct Image {
+    pub path: PathBuf,
+    pub digest: ContentHash,
+    pub size: u64,
+    pub fingerprint: ImageFingerprint,
+}
+
+pub fn variants(args: VariantsArgs) -> Result<()> {
+    let store = PhotoSyncStore::new(args.database_file)?;
+    let images = archived_images(&store, args.old_out_dir.as_deref(), args.out_dir.as_deref())?;
+
+    let mut reclaimable = 0;
+    let groups = group_variants(&images, args.max_distance);
+    for group in &groups {
+        let (keep, others) = group.split_first().expect("groups aren't empty");
+        let describe = |image: &Image| {
+            let ImageFingerprint { width, height, .. } = image.fingerprint;
+            format!("{:?} ({width}x{height}, {} bytes)", image.path, image.size)
+        };
+        println!("KEEP {}", describe(keep));
+        for other in others {
+            println!("    lower resolution: {}", describe(other));
+            reclaimable += other.size;
+        }
+    }
+    println!(
+        "{} images are archived at several resolutions, {}MB could be reclaimed",
+        groups.len(),
+        reclaimable / 1_000_000
+    );
+    Ok(())
+}
+
+/// The images archived in the old out and out directories, fingerprinting those whose content
+/// hasn't been, e.g. by a sync with `--perceptual-hash`. HEIC images can't be, so are left out,
+/// and counted.
+pub fn archived_images(
+    store: &PhotoSyncStore,
+    old_out_dir: Option<&Path>,
+    out_dir: Option<&Path>,
+) -> Result<Vec<Image>> {
+    let mut archived = Vec::new();
+    if let Some(dir) = old_out_dir {
+        let files = store.old_target_files()?.into_iter();
+        archived.extend(files.map(|f| (dir, Transform::None, f)));
+    }
+    if let Some(dir) = out_dir {
+        let transforms = store.transformed_files()?;
+        archived.extend(store.target_files()?.into_iter().map(|f| {
+            let transform = transforms.get(&f.path).copied().unwrap_or_default();
+            (dir, transform, f)
+        }));
+    }
+
+    let heic = (archived.iter())
+        .filter(|(_, transform, file)| phash::is_heic(&original_path(&file.path, *transform)))
+        .count();
+    if heic > 0 {
+        println!("{heic} HEIC images were left out, as they can't be decoded");
+    }
+    archived
+        .into_par_iter()
+        .filter(|(_, transform, file)| is_image(&original_path(&file.path, *transform)))
+        .map(|(dir, transform, file)| {
+            let path = dir.join(&file.path);
+            let fingerprint = match store.image_fingerprint(&file.digest)? {
+                Some(fingerprint) => fingerprint,
+                None => match phash::fingerprint(&path, transform) {
+                    Ok(fingerprint) => {
+                        store.record_image_fingerprint(&file.digest, &fingerprint)?;
+                        fingerprint
+                    }
+                    Err(e) => {
+                        println!("could not decode {path:?}: {e}");
+                        return Ok(None);
+                    }
+                },
+            };
+            Ok(Some(Image {
+                path,
+                digest: file.digest,
+                size: file.size,
+                fingerprint,
+            }))
+        })
+        .filter_map(Result::transpose)
+        .collect()
+}
+
+/// Groups images which look the same but have different resolutions, largest first.
+fn group_variants(images: &[Image], max_distance: u32) -> Vec<Vec<&Image>> {
+    group_images(images, max_distance, are_variants)
+}
+
+/// Groups images whose hashes are within `max_distance` bits and which are `related`, largest
+/// first. Hashes within `max_distance` bits (at most 7) share one of their eight bytes, so only
+/// images sharing a byte are compared.
+pub fn group_images(
+    images: &[Image],
+    max_distance: u32,
+    related: impl Fn(&ImageFingerprint, &ImageFingerprint) -> bool,
+) -> Vec<Vec<&Image>> {
+    let mut buckets = HashMap::<(usize, u8), Vec<usize>>::new();
+    for (idx, image) in images.iter().enumerate() {
+        for (block, byte) in image.fingerprint.hash.to_be_bytes().into_iter().enumerate() {
+            buckets.entry((block, byte)).or_default().push(idx);
+        }
+    }
+
+    let mut parents: Vec<usize> = (0..images.len()).collect();
+    fn root(parents: &mut [usize], mut idx: usize) -> usize {
+        while parents[idx] != idx {
+            parents[idx] = parents[parents[idx]];
+            idx = parents[idx];
+        }
+        idx
+    }
+    for members in buckets.values() {
+        for (i, &a) in members.iter().enumerate() {
+            for &b in &members[i + 1..] {
+                let (fa, fb) = (&images[a].fingerprint, &images[b].fingerprint);
+                if fa.distance(fb) <= max_distance && related(fa, fb) {
+                    let (root_a, root_b) = (root(&mut parents, a), root(&mut parents, b));
+                    parents[root_a] = root_b;
+                }
+            }
+        }
+    }
+
+    let mut groups = HashMap::<usize, Vec<&Image>>::new();
+    for (idx, image) in images.iter().enumerate() {
+        let group = root(&mut parents, idx);
+        groups.entry(group).or_default().push(image);
+    }
+    let mut groups: Vec<_> = groups.into_values().filter(|g| g.len() > 1).collect();
+    for group in &mut groups {
+        group.sort_by_key(|image| std::cmp::Reverse((image.fingerprint.pixels(), image.size)));
+    }
+    groups.sort_by(|a, b| a[0].path.cmp(&b[0].path));
+    groups
+}
+
+fn are_variants(a: &ImageFingerprint, b: &ImageFingerprint) -> bool {
+    let aspect = |f: &ImageFingerprint| f64::from(f.width) / f64::from(f.height.max(1));
+    a.pixels() != b.pixels() && (aspect(a) - aspect(b)).abs() <= ASPECT_TOLERANCE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn variants_are_grouped_largest_first() {
+        let image = |path: &str, width, height, hash| Image {
+            path: PathBuf::from(path),
+            digest: ContentHash::new_for_tests(hash as u8),
+            size: u64::from(width),
+            fingerprint: ImageFingerprint {
+                width,
+                height,
+                hash,
+            },
+        };
+        let images = [
+            image("optimised.jpg", 1024, 768, 0xf0f0_f0f0_f0f0_f0f1),
+            image("original.jpg", 4032, 3024, 0xf0f0_f0f0_f0f0_f0f0),
+            // the same size, so a duplicate rather than a variant.
+            image("copy.jpg", 4032, 3024, 0xf0f0_f0f0_f0f0_f0f0),
+            image("cropped.jpg", 3024, 3024, 0xf0f0_f0f0_f0f0_f0f0),
+            image("different.jpg", 1024, 768, 0x0f0f_0f0f_0f0f_0f0f),
+        ];
+        let groups = group_variants(&images, 4);
+        let paths: Vec<Vec<_>> = groups
+            .iter()
+            .map(|g| g.iter().map(|i| i.path.to_str().unwrap()).collect())
+            .collect();
+        assert_eq!(paths.len(), 1);
+        assert_eq!(paths[0][2], "optimised.jpg");
+        assert_eq!(paths[0].len(), 3);
+    }
+}
@@ -0,0 +1,521 @@
+//! Checking the archive still holds what the catalogue says it does.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::{Path, PathBuf},
+};
+
+use eyre::{ContextCompat, Result, bail};
+use walkdir::WalkDir;
+
+use crate::{
+    VerifyArgs, appledouble, chunks,
+    compress::{Transform, digest_archived},
+    destination,
+    digest::ContentHash,
+    encrypt::is_encrypted,
+    store::{CataloguedFile, FileCopy, PhotoSyncStore, RunId, SourceFile},
+    trash,
+};
+
+pub fn verify(args: VerifyArgs) -> Result<()> {
+    if !args.against_source
+        && !args.last_run
+        && args.run.is_none()
+        && !args.full
+        && !args.live_photos
+    {
+        bail!(
+            "nothing to verify, pass --full, --against-source, --live-photos, --last-run or --run"
+        );
+    }
+    let store = PhotoSyncStore::new(args.database_file)?;
+    let archive = Archive {
+        store: &store,
+        out_dir: &args.out_dir,
+        old_out_dir: args.old_out_dir.as_deref(),
+        namespace: &args.machine_id,
+        transcoded: store.transcoded_files()?,
+        encrypted: store.encrypted_files()?,
+        transformed: store.transformed_files()?,
+    };
+
+    let run = match args.run {
+        Some(run) => Some(run),
+        None if args.last_run => Some(
+            store
+                .last_run(&args.machine_id)?
+                .wrap_err_with(|| format!("{:?} has never synced", args.machine_id))?,
+        ),
+        None => None,
+    };
+    if let Some(run) = run {
+        let (checked, damaged) = archive.damaged_from_run(run)?;
+        if !damaged.is_empty() {
+            bail!(
+                "{} of {checked} files written by run {run} are missing or corrupt",
+                damaged.len()
+            );
+        }
+        println!("all {checked} files written by run {run} are intact");
+    }
+
+    if args.full {
+        let mut damaged = 0;
+        let mut audits = vec![(
+            args.out_dir.as_path(),
+            audit(
+                &args.out_dir,
+                archive.as_archived(store.target_files()?),
+                |path, file| archive.rehash(path, file),
+            )?,
+        )];
+        if let Some(old_out_dir) = &args.old_out_dir {
+            let audit = audit(old_out_dir, store.old_target_files()?, |path, file| {
+                file.digest.algorithm().digest(path).map(Some)
+            })?;
+            audits.push((old_out_dir, audit));
+        }
+        for (dir, audit) in audits {
+            for path in &audit.missing {
+                println!("MISSING {:?}", dir.join(path));
+            }
+            for (path, problem) in &audit.mismatched {
+                println!("MISMATCH {:?}: {problem}", dir.join(path));
+            }
+            for path in &audit.extra {
+                println!("EXTRA {:?}", dir.join(path));
+            }
+            println!(
+                "checked {} catalogued files in {dir:?}: {} missing, {} mismatched, and {} files not catalogued",
+                audit.checked,
+                audit.missing.len(),
+                audit.mismatched.len(),
+                audit.extra.len()
+            );
+            damaged += audit.missing.len() + audit.mismatched.len();
+        }
+        if damaged > 0 {
+            bail!("{damaged} catalogued files are missing or corrupt");
+        }
+    }
+
+    if args.live_photos {
+        let (checked, broken) = archive.broken_live_photos()?;
+        if broken > 0 {
+            bail!("{broken} of {checked} Live Photos have lost a half");
+        }
+        println!("both halves of all {checked} Live Photos are archived");
+    }
+
+    if let Some(in_dir) = args.in_dir.as_deref().filter(|_| args.against_source) {
+        let (checked, unarchived) = archive.unarchived_sources(in_dir)?;
+        for file in &unarchived {
+            println!("NOT ARCHIVED {:?} ({})", file.path, file.digest);
+        }
+        if !unarchived.is_empty() {
+            bail!(
+                "{} of {checked} transferred files still in {in_dir:?} aren't in the archive",
+                unarchived.len(),
+            );
+        }
+        println!("all {checked} transferred files still in {in_dir:?} are archived");
+    }
+    Ok(())
+}
+
+/// What rehashing a whole archive directory found.
+#[derive(Debug, Default)]
+struct Audit {
+    checked: usize,
+    missing: Vec<PathBuf>,
+    /// With the digest they're catalogued with and what was found instead.
+    mismatched: Vec<(PathBuf, String)>,
+    /// Files which aren't catalogued, e.g. copied in by hand, which `orphans --adopt` catalogues.
+    extra: Vec<PathBuf>,
+}
+
+/// Rehashes every file in `dir` with `rehash`, comparing them with the `catalogued` files. Those
+/// `rehash` gives no digest for are taken on trust.
+fn audit(
+    dir: &Path,
+    catalogued: Vec<CataloguedFile>,
+    rehash: impl Fn(&Path, &CataloguedFile) -> Result<Option<ContentHash>>,
+) -> Result<Audit> {
+    let mut found = BTreeSet::new();
+    for entry in WalkDir::new(dir).into_iter().filter_entry(|entry| {
+        !trash::is_trash(entry) && !destination::is_marker(entry) && !chunks::is_repository(entry)
+    }) {
+        let entry = entry?;
+        if entry.file_type().is_file() {
+            found.insert(entry.path().strip_prefix(dir)?.to_path_buf());
+        }
+    }
+
+    let known: BTreeSet<_> = catalogued.iter().map(|file| file.path.clone()).collect();
+    let mut audit = Audit {
+        checked: catalogued.len(),
+        ..Audit::default()
+    };
+    for file in catalogued {
+        if !found.remove(&file.path) {
+            audit.missing.push(file.path);
+            continue;
+        }
+        match rehash(&dir.join(&file.path), &file) {
+            Ok(None) => {}
+            Ok(Some(actual)) if actual == file.digest => {}
+            Ok(Some(actual)) => audit.mismatched.push((
+                file.path,
+                format!("catalogued as {}, now {actual}", file.digest),
+            )),
+            Err(e) => audit
+                .mismatched
+                .push((file.path, format!("could not be read: {e}"))),
+        }
+    }
+    // AppleDouble files carried over with their data file aren't catalogued themselves.
+    audit.extra = found
+        .into_iter()
+        .filter(|path| !appledouble::data_file_of(path).is_some_and(|data| known.contains(&data)))
+        .collect();
+    Ok(audit)
+}
+
+struct Archive<'a> {
+    store: &'a PhotoSyncStore,
+    out_dir: &'a Path,
+    old_out_dir: Option<&'a Path>,
+    namespace: &'a str,
+    /// The digests of transcoded files, which aren't those they're catalogued with.
+    transcoded: HashMap<PathBuf, ContentHash>,
+    /// The digests of the bytes stored for encrypted files, which can't be read without an
+    /// identity.
+    encrypted: HashMap<PathBuf, ContentHash>,
+    /// How the files stored otherwise than as they are were stored.
+    transformed: HashMap<PathBuf, Transform>,
+}
+
+impl Archive<'_> {
+    /// `files` in the out directory, with the digests they should have there: for those which
+    /// were encrypted, of the bytes stored.
+    fn as_archived(&self, files: Vec<CataloguedFile>) -> Vec<CataloguedFile> {
+        files
+            .into_iter()
+            .map(|file| CataloguedFile {
+                digest: (self.encrypted.get(&file.path))
+                    .or_else(|| self.transcoded.get(&file.path))
+                    .copied()
+                    .unwrap_or(file.digest),
+                ..file
+            })
+            .collect()
+    }
+
+    /// What `file`, archived at `path` and with the digest it should have there, now hashes to.
+    /// Encrypted files can't be read without an identity, so are hashed as stored, or if that
+    /// wasn't catalogued, taken on trust.
+    fn rehash(&self, path: &Path, file: &CataloguedFile) -> Result<Option<ContentHash>> {
+        let algorithm = file.digest.algorithm();
+        if self.encrypted.contains_key(&file.path) {
+            return algorithm.digest(path).map(Some);
+        }
+        if is_encrypted(&file.path) {
+            return Ok(None);
+        }
+        let transform = self.transform_of(&file.path);
+        digest_archived(path, transform, algorithm).map(Some)
+    }
+
+    /// How the file at `path` in the out directory was stored.
+    fn transform_of(&self, path: &Path) -> Transform {
+        self.transformed.get(path).copied().unwrap_or_default()
+    }
+
+    /// Rehashes the files in the out directory last written by `run`, reporting and returning
+    /// those which are missing or no longer match their catalogued digest, and how many were
+    /// checked.
+    fn damaged_from_run(&self, run: RunId) -> Result<(usize, Vec<CataloguedFile>)> {
+        let files = self.as_archived(self.store.target_files_written_by(run)?);
+        let checked = files.len();
+        let mut damaged = Vec::new();
+        for file in files {
+            let path = self.out_dir.join(&file.path);
+            if !path.is_file() {
+                println!("MISSING {:?}", file.path);
+                damaged.push(file);
+                continue;
+            }
+            let Some(actual) = self.rehash(&path, &file)? else {
+                continue;
+            };
+            if actual != file.digest {
+                println!(
+                    "MISMATCH {:?}: catalogued as {}, now {actual}",
+                    file.path, file.digest
+                );
+                damaged.push(file);
+            }
+        }
+        Ok((checked, damaged))
+    }
+
+    /// Finds the files transferred from `in_dir` which are still there but whose content can't be
+    /// found intact in the archive, returning them and how many were checked.
+    fn unarchived_sources(&self, in_dir: &Path) -> Result<(usize, Vec<SourceFile>)> {
+        let mut archived = HashMap::new();
+        let mut checked = 0;
+        let mut unarchived = Vec::new();
+        for file in self.store.source_files()? {
+            if file.namespace != self.namespace || !in_dir.join(&file.path).is_file() {
+                continue;
+            }
+            checked += 1;
+            // duplicates share their content's copies, which only need checking once.
+            let intact = match archived.get(&file.digest) {
+                Some(intact) => *intact,
+                None => {
+                    let intact = self.holds(&file.digest)?;
+                    archived.insert(file.digest, intact);
+                    intact
+                }
+            };
+            if !intact {
+                unarchived.push(file);
+            }
+        }
+        Ok((checked, unarchived))
+    }
+
+    /// Checks the still and video of every Live Photo are archived intact, reporting those which
+    /// aren't and returning how many were checked and how many of those were broken.
+    fn broken_live_photos(&self) -> Result<(usize, usize)> {
+        let digests: HashMap<_, _> = self
+            .store
+            .source_files()?
+            .into_iter()
+            .filter(|file| file.namespace == self.namespace)
+            .map(|file| (file.path, file.digest))
+            .collect();
+        let pairs = self.store.live_photos(self.namespace)?;
+        let mut broken = 0;
+        for (still, video) in &pairs {
+            let mut lost = Vec::new();
+            for half in [still, video] {
+                let intact = match digests.get(half) {
+                    Some(digest) => self.holds(digest)?,
+                    None => false,
+                };
+                if !intact {
+                    lost.push(half);
+                }
+            }
+            if !lost.is_empty() {
+                println!("BROKEN LIVE PHOTO {still:?}: {lost:?} not archived intact");
+                broken += 1;
+            }
+        }
+        Ok((pairs.len(), broken))
+    }
+
+    /// Whether any catalogued archive copy of `expected` is still intact.
+    fn holds(&self, expected: &ContentHash) -> Result<bool> {
+        for copy in self.store.copies_of(expected, self.namespace)? {
+            let (path, actual, wanted) = match copy {
+                FileCopy::Target(path) => {
+                    let full_path = self.out_dir.join(&path);
+                    match self.encrypted.get(&path) {
+                        // encrypted copies can't be read without an identity, so are checked
+                        // against what was stored.
+                        Some(stored) => {
+                            let actual = stored.algorithm().digest(&full_path);
+                            (full_path, actual, *stored)
+                        }
+                        // those encrypted before what was stored was catalogued are taken on
+                        // trust.
+                        None if is_encrypted(&path) => {
+                            if full_path.is_file() {
+                                return Ok(true);
+                            }
+                            continue;
+                        }
+                        None => {
+                            // a transcoded copy holds the content as it was transcoded.
+                            let wanted = self.transcoded.get(&path).copied().unwrap_or(*expected);
+                            let transform = self.transform_of(&path);
+                            let actual =
+                                digest_archived(&full_path, transform, expected.algorithm());
+                            (full_path, actual, wanted)
+                        }
+                    }
+                }
+                FileCopy::OldTarget(path) => match self.old_out_dir {
+                    Some(old_out_dir) => {
+                        let path = old_out_dir.join(path);
+                        let actual = expected.algorithm().digest(&path);
+                        (path, actual, *expected)
+                    }
+                    None => continue,
+                },
+                FileCopy::Source(_) => continue,
+            };
+            if path.is_file() && actual.ok() == Some(wanted) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf, time::SystemTime};
+
+    use super::*;
+    use crate::digest::digest;
+
+    #[test]
+    fn sources_without_intact_copies_are_found() {
+        let dir = tempfile::tempdir().unwrap();
+        let (in_dir, out_dir) = (dir.path().join("in"), dir.path().join("out"));
+        fs::create_dir_all(&in_dir).unwrap();
+        fs::create_dir_all(&out_dir).unwrap();
+        let store = PhotoSyncStore::new_for_tests().unwrap();
+        let run = store.begin_run("laptop").unwrap();
+        let now = SystemTime::now();
+        for (path, content, archived) in [
+            ("kept.jpg", "a", Some("a")),
+            ("dup of kept.jpg", "a", None),
+            ("damaged.jpg", "b", Some("rot")),
+            ("removed.jpg", "c", None),
+            ("deleted from source.jpg", "d", None),
+        ] {
+            fs::write(in_dir.join(path), content).unwrap();
+            let digest = digest(&in_dir.join(path)).unwrap();
+            store
+                .mark_transferred_from_source(run, "laptop", Path::new(path), &digest, now, 1)
+                .unwrap();
+            if let Some(archived) = archived {
+                fs::write(out_dir.join(path), archived).unwrap();
+                store
+                    .mark_exists_in_target(run, Path::new(path), now, 1, &digest)
+                    .unwrap();
+            }
+        }
+        fs::remove_file(in_dir.join("deleted from source.jpg")).unwrap();
+
+        let archive = Archive {
+            store: &store,
+            out_dir: &out_dir,
+            old_out_dir: None,
+            namespace: "laptop",
+            transcoded: HashMap::new(),
+            encrypted: HashMap::new(),
+            transformed: HashMap::new(),
+        };
+        let (checked, unarchived) = archive.unarchived_sources(&in_dir).unwrap();
+        assert_eq!(checked, 4);
+        let paths: Vec<_> = unarchived.into_iter().map(|f| f.path).collect();
+        assert_eq!(
+            paths,
+            [PathBuf::from("damaged.jpg"), PathBuf::from("removed.jpg")]
+        );
+    }
+
+    #[test]
+    fn only_the_runs_files_are_rehashed() {
+        let dir = tempfile::tempdir().unwrap();
+        let out_dir = dir.path();
+        let store = PhotoSyncStore::new_for_tests().unwrap();
+        let now = SystemTime::now();
+        for (path, content, archived) in [
+            ("earlier.jpg", "a", "rot"),
+            ("intact.jpg", "b", "b"),
+            ("damaged.jpg", "c", "rot"),
+        ] {
+            let run = store.begin_run("laptop").unwrap();
+            fs::write(out_dir.join(path), content).unwrap();
+            let digest = digest(&out_dir.join(path)).unwrap();
+            fs::write(out_dir.join(path), archived).unwrap();
+            store
+                .mark_exists_in_target(run, Path::new(path), now, 1, &digest)
+                .unwrap();
+        }
+        let last_run = store.last_run("laptop").unwrap().unwrap();
+        let second_run = store.begin_run("phone").unwrap();
+        store
+            .mark_exists_in_target(
+                second_run,
+                Path::new("intact.jpg"),
+                now,
+                1,
+                &digest(&out_dir.join("intact.jpg")).unwrap(),
+            )
+            .unwrap();
+
+        let archive = Archive {
+            store: &store,
+            out_dir,
+            old_out_dir: None,
+            namespace: "laptop",
+            transcoded: HashMap::new(),
+            encrypted: HashMap::new(),
+            transformed: HashMap::new(),
+        };
+        let (checked, damaged) = archive.damaged_from_run(last_run).unwrap();
+        assert_eq!(checked, 1);
+        assert_eq!(damaged[0].path, Path::new("damaged.jpg"));
+        assert_eq!(archive.damaged_from_run(second_run).unwrap(), (1, vec![]));
+
+        // encrypted copies are checked as they were stored.
+        let third_run = store.begin_run("laptop").unwrap();
+        let sealed = out_dir.join("sealed.jpg.age");
+        fs::write(&sealed, "ciphertext").unwrap();
+        let plaintext = ContentHash::new_for_tests(1);
+        store
+            .mark_exists_in_target(third_run, Path::new("sealed.jpg.age"), now, 1, &plaintext)
+            .unwrap();
+        let archive = Archive {
+            encrypted: HashMap::from([(PathBuf::from("sealed.jpg.age"), digest(&sealed).unwrap())]),
+            ..archive
+        };
+        assert_eq!(archive.damaged_from_run(third_run).unwrap(), (1, vec![]));
+        fs::write(&sealed, "tampered").unwrap();
+        assert_eq!(archive.damaged_from_run(third_run).unwrap().1.len(), 1);
+    }
+
+    #[test]
+    fn full_audits_find_missing_changed_and_extra_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let out_dir = dir.path();
+        fs::create_dir(out_dir.join(trash::TRASH_DIR)).unwrap();
+        fs::write(out_dir.join(trash::TRASH_DIR).join("gone.jpg"), "x").unwrap();
+        let now = SystemTime::now();
+        let catalogued = [
+            ("intact.jpg", "a"),
+            ("rotted.jpg", "b"),
+            ("deleted.jpg", "c"),
+        ]
+        .into_iter()
+        .map(|(path, content)| {
+            fs::write(out_dir.join(path), content).unwrap();
+            CataloguedFile {
+                path: PathBuf::from(path),
+                mtime: now,
+                size: 1,
+                digest: digest(&out_dir.join(path)).unwrap(),
+            }
+        })
+        .collect();
+        fs::write(out_dir.join("rotted.jpg"), "rot").unwrap();
+        fs::remove_file(out_dir.join("deleted.jpg")).unwrap();
+        fs::write(out_dir.join("copied in.jpg"), "d").unwrap();
+        fs::write(out_dir.join("._intact.jpg"), "resource fork").unwrap();
+
+        let audit = audit(out_dir, catalogued, |path, _| digest(path).map(Some)).unwrap();
+        assert_eq!(audit.checked, 3);
+        assert_eq!(audit.missing, [PathBuf::from("deleted.jpg")]);
+        assert_eq!(audit.mismatched[0].0, Path::new("rotted.jpg"));
+        assert_eq!(audit.extra, [PathBuf::from("copied in.jpg")]);
+    }
+}
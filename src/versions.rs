@@ -0,0 +1,231 @@
+//! Archiving the new content of source files which change after they were transferred, e.g. photos
+//! edited and exported again, as versions kept alongside the content first transferred, and
+//! pruning old versions so that repeated edits don't grow the archive without bound.
+
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use eyre::{Result, ensure};
+
+use crate::{
+    PruneVersionsArgs, VersionsArgs,
+    store::{PhotoSyncStore, SourceVersion},
+    trash::Trash,
+};
+
+/// The out directory subtree new versions are archived under.
+pub const VERSIONS_DIR: &str = "versions";
+
+pub fn versions(args: VersionsArgs) -> Result<()> {
+    let store = PhotoSyncStore::new(args.database_file)?;
+    let versions = store.versions(&args.machine_id, &args.path)?;
+    if versions.is_empty() {
+        println!("{:?} has not changed since it was transferred", args.path);
+    }
+    for version in versions {
+        let archived = match (&version.target_path, version.archived_at) {
+            (Some(target_path), Some(at)) => format!(
+                "archived at {target_path:?} at {}",
+                at.duration_since(SystemTime::UNIX_EPOCH)?.as_secs()
+            ),
+            (None, Some(_)) => "already archived".to_string(),
+            _ => "as first transferred".to_string(),
+        };
+        println!("v{} {} {archived}", version.version, version.digest);
+    }
+    Ok(())
+}
+
+/// Which versions of a changed file to keep. A version is kept if either rule keeps it. The
+/// content first transferred and the latest version are always kept.
+#[derive(Clone, Copy, Debug)]
+pub struct Retention {
+    /// Keep this many of the latest versions.
+    pub keep_last: Option<usize>,
+    /// Keep versions archived less than this long ago.
+    pub keep_newer_than: Option<Duration>,
+}
+
+impl Retention {
+    /// The versions of one file, oldest first, which the rules don't keep.
+    fn prunable<'a>(
+        &self,
+        versions: &'a [SourceVersion],
+        now: SystemTime,
+    ) -> Vec<&'a SourceVersion> {
+        let latest = versions.len().saturating_sub(1);
+        versions
+            .iter()
+            .enumerate()
+            .filter(|&(i, version)| {
+                let Some(archived_at) = version.archived_at else {
+                    // the content first transferred.
+                    return false;
+                };
+                let recent = self
+                    .keep_newer_than
+                    .is_some_and(|age| archived_at + age > now);
+                let among_last = self.keep_last.is_some_and(|n| latest - i < n);
+                i != latest && !recent && !among_last
+            })
+            .map(|(_, version)| version)
+            .collect()
+    }
+}
+
+pub fn prune_versions(args: PruneVersionsArgs) -> Result<()> {
+    ensure!(
+        args.keep_last.is_some() || args.keep_newer_than_days.is_some(),
+        "give --keep-last and/or --keep-newer-than-days"
+    );
+    let retention = Retention {
+        keep_last: args.keep_last,
+        keep_newer_than: args
+            .keep_newer_than_days
+            .map(|days| Duration::from_secs(days * 24 * 60 * 60)),
+    };
+    let store = PhotoSyncStore::new(args.database_file)?;
+    let trash = Trash::new(&args.out_dir, args.trash_retention_days);
+    let now = SystemTime::now();
+    let all = store.all_versions(&args.machine_id)?;
+
+    let (mut pruned, mut trashed) = (0, 0);
+    for versions in all.chunk_by(|a, b| a.path == b.path) {
+        for version in retention.prunable(versions, now) {
+            // the archived copy may be the only copy of some other file's content too.
+            let copy = version
+                .target_path
+                .as_ref()
+                .filter(|_| {
+                    !store
+                        .is_version_content_shared(&args.machine_id, version)
+                        .unwrap_or(true)
+                })
+                .map(|path| args.out_dir.join(path));
+            println!("pruning {:?} v{}", version.path, version.version);
+            if args.dry_run {
+                pruned += 1;
+                continue;
+            }
+            if let Some(copy) = copy.as_ref().filter(|copy| copy.exists()) {
+                let size = copy.metadata()?.len();
+                if let Err(e) = trash.discard(copy) {
+                    println!("could not move {copy:?} to the trash, keeping the version: {e}");
+                    continue;
+                }
+                trashed += size;
+            }
+            store.forget_version(&args.machine_id, version, copy.is_some())?;
+            pruned += 1;
+        }
+    }
+
+    let verb = if args.dry_run {
+        "would prune"
+    } else {
+        "pruned"
+    };
+    println!(
+        "{verb} {pruned} versions, moving {}MB to the trash",
+        trashed / 1_000_000
+    );
+    if !args.dry_run {
+        trash.empty_expired()?;
+    }
+    Ok(())
+}
+
+/// Where version `version` of the file archived at `destination` goes, e.g. `2020/a.jpg` version 2
+/// goes to `versions/2020/a.v2.jpg`.
+pub fn versioned_path(destination: &Path, version: u32) -> PathBuf {
+    let stem = destination
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy();
+    let name = match destination.extension() {
+        Some(extension) => format!("{stem}.v{version}.{}", extension.to_string_lossy()),
+        None => format!("{stem}.v{version}"),
+    };
+    Path::new(VERSIONS_DIR).join(destination.with_file_name(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        digest::ContentHash,
+        store::{PhotoSyncStore, SourceVersion},
+    };
+
+    #[test]
+    fn versions_are_numbered_after_the_original() {
+        assert_eq!(
+            versioned_path(Path::new("2020/a.jpg"), 2),
+            Path::new("versions/2020/a.v2.jpg")
+        );
+        assert_eq!(
+            versioned_path(Path::new("b"), 3),
+            Path::new("versions/b.v3")
+        );
+
+        let store = PhotoSyncStore::new_for_tests().unwrap();
+        let run = store.begin_run("laptop").unwrap();
+        let path = Path::new("a.jpg");
+        let (original, edited) = (ContentHash::new_for_tests(1), ContentHash::new_for_tests(2));
+        assert_eq!(store.next_version("laptop", path).unwrap(), 2);
+        let version = SourceVersion {
+            path: path.to_path_buf(),
+            version: 2,
+            digest: edited,
+            target_path: Some(versioned_path(path, 2)),
+            archived_at: Some(SystemTime::UNIX_EPOCH),
+        };
+        store
+            .record_version(run, "laptop", &original, &version)
+            .unwrap();
+        assert_eq!(store.next_version("laptop", path).unwrap(), 3);
+        let versions = store.versions("laptop", path).unwrap();
+        assert_eq!(
+            versions
+                .iter()
+                .map(|v| (v.version, v.digest))
+                .collect::<Vec<_>>(),
+            [(1, original), (2, edited)]
+        );
+        assert_eq!(versions[1], version);
+    }
+
+    #[test]
+    fn retention_always_keeps_the_original_and_latest() {
+        let day = Duration::from_secs(24 * 60 * 60);
+        let now = SystemTime::UNIX_EPOCH + 100 * day;
+        let versions: Vec<_> = [None, Some(10), Some(50), Some(98), Some(99)]
+            .into_iter()
+            .enumerate()
+            .map(|(i, archived_days)| SourceVersion {
+                path: PathBuf::from("a.jpg"),
+                version: i as u32 + 1,
+                digest: ContentHash::new_for_tests(i as u8),
+                target_path: Some(versioned_path(Path::new("a.jpg"), i as u32 + 1)),
+                archived_at: archived_days.map(|days| SystemTime::UNIX_EPOCH + days * day),
+            })
+            .collect();
+        let pruned = |keep_last, keep_newer_than_days: Option<u32>| {
+            let retention = Retention {
+                keep_last,
+                keep_newer_than: keep_newer_than_days.map(|days| days * day),
+            };
+            retention
+                .prunable(&versions, now)
+                .iter()
+                .map(|v| v.version)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(pruned(Some(2), None), [2, 3]);
+        assert_eq!(pruned(Some(0), None), [2, 3, 4]);
+        assert_eq!(pruned(None, Some(30)), [2, 3]);
+        assert_eq!(pruned(Some(3), Some(60)), [2]);
+    }
+}
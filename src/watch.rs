@@ -0,0 +1,124 @@
+//! `--watch`: once the first sync is done, waiting for files in the in directory to change and
+//! syncing just those, rather than scanning the whole of it again from cron.
+
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    sync::mpsc::{self, RecvTimeoutError},
+    time::Duration,
+};
+
+use eyre::{Result, WrapErr};
+use notify::{EventKind, RecursiveMode, Watcher};
+
+use crate::pause::PauseControl;
+use tracing::{info, warn};
+
+/// How long the in directory must be quiet after a change before it's synced, so that e.g. a
+/// batch of downloads is synced together, and a file isn't copied while it's still being written.
+const SETTLE_TIME: Duration = Duration::from_secs(5);
+
+/// How often waiting for changes checks whether the run has been aborted.
+const ABORT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Calls `sync` with the paths created or changed in `in_dir` whenever it settles after changing,
+/// until `pause` is aborted. A failed sync is reported, and the changes are picked up again by
+/// the next one, as they're still new.
+pub fn watch_in_dir(
+    in_dir: &Path,
+    pause: &PauseControl,
+    mut sync: impl FnMut(&[PathBuf]) -> Result<()>,
+) -> Result<()> {
+    let (events, events_rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(events)?;
+    // events name paths within the directory as watched, which are given to `sync` within it as
+    // the run knows it.
+    let watched = in_dir.canonicalize()?;
+    watcher
+        .watch(&watched, RecursiveMode::Recursive)
+        .wrap_err_with(|| format!("could not watch {in_dir:?}"))?;
+    info!("watching {in_dir:?} for changes");
+
+    let mut changed = BTreeSet::new();
+    loop {
+        let timeout = if changed.is_empty() {
+            ABORT_CHECK_INTERVAL
+        } else {
+            SETTLE_TIME
+        };
+        match events_rx.recv_timeout(timeout) {
+            Ok(Ok(event)) => {
+                if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                    changed.extend(
+                        event.paths.iter().filter_map(|path| {
+                            Some(in_dir.join(path.strip_prefix(&watched).ok()?))
+                        }),
+                    );
+                }
+            }
+            // e.g. the kernel's event queue overflowed, so changes may have been missed.
+            Ok(Err(e)) => warn!("problem watching {in_dir:?}, changes may be missed: {e}"),
+            Err(RecvTimeoutError::Timeout) => {
+                pause.wait_if_paused()?;
+                if changed.is_empty() {
+                    continue;
+                }
+                let roots = outermost(std::mem::take(&mut changed));
+                info!("syncing {} changed paths in {in_dir:?}", roots.len());
+                if let Err(e) = sync(&roots) {
+                    // an abort fails the sync, and stops watching.
+                    pause.wait_if_paused()?;
+                    warn!("syncing changes failed, so they're retried when next changed: {e:#}");
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => unreachable!("the watcher is still held"),
+        }
+    }
+}
+
+/// The paths among `changed` which still exist and aren't within another of them, which would
+/// walk them anyway.
+pub(crate) fn outermost(changed: BTreeSet<PathBuf>) -> Vec<PathBuf> {
+    let mut roots: Vec<PathBuf> = Vec::new();
+    // sorted, so a directory comes before everything within it.
+    for path in changed {
+        if path.exists() && !roots.last().is_some_and(|root| path.starts_with(root)) {
+            roots.push(path);
+        }
+    }
+    roots
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn only_the_outermost_changes_are_walked() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name);
+        fs::create_dir_all(path("new album/sub")).unwrap();
+        for name in [
+            "a.jpg",
+            "new album/b.jpg",
+            "new album/sub/c.jpg",
+            "new album2.jpg",
+        ] {
+            fs::write(path(name), name).unwrap();
+        }
+        let changed = [
+            "a.jpg",
+            "new album",
+            "new album/b.jpg",
+            "new album/sub/c.jpg",
+            "new album2.jpg",
+            "deleted.jpg",
+        ];
+        assert_eq!(
+            outermost(changed.into_iter().map(path).collect()),
+            [path("a.jpg"), path("new album"), path("new album2.jpg")]
+        );
+    }
+}
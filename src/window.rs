@@ -0,0 +1,60 @@
+//! Hours of the day a run may do its work in, e.g. overnight when nobody else is using the disks.
+
+use std::str::FromStr;
+
+use chrono::NaiveTime;
+use eyre::{Report, Result, eyre};
+
+/// A daily window of local time such as `01:00-07:00`. Windows may span midnight, e.g.
+/// `22:00-06:00`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimeWindow {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl TimeWindow {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        }
+    }
+}
+
+impl FromStr for TimeWindow {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| eyre!("expected a window like 01:00-07:00, not {s:?}"))?;
+        let parse = |time: &str| NaiveTime::parse_from_str(time.trim(), "%H:%M");
+        Ok(Self {
+            start: parse(start)?,
+            end: parse(end)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn windows_may_span_midnight() {
+        let time = |s| NaiveTime::parse_from_str(s, "%H:%M").unwrap();
+        let night: TimeWindow = "22:00-06:30".parse().unwrap();
+        assert!(night.contains(time("23:59")));
+        assert!(night.contains(time("00:00")));
+        assert!(!night.contains(time("06:30")));
+        assert!(!night.contains(time("12:00")));
+
+        let morning: TimeWindow = "01:00-07:00".parse().unwrap();
+        assert!(morning.contains(time("01:00")));
+        assert!(!morning.contains(time("23:00")));
+        assert!("7am".parse::<TimeWindow>().is_err());
+        assert!("25:00-26:00".parse::<TimeWindow>().is_err());
+    }
+}
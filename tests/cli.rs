@@ -0,0 +1,87 @@
+//! Runs the built binary, for what depends on its environment, which tests in the same process
+//! can't safely set.
+
+use std::{
+    fs,
+    path::Path,
+    process::{Command, Output},
+};
+
+/// Runs the binary in `dir` with `args`, and `env` as the only PHOTO_SYNC_* variables set.
+fn photo_sync(dir: &Path, args: &[&str], env: &[(&str, &str)]) -> Output {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_icloud-photo-synchroniser"));
+    for (name, _) in std::env::vars_os() {
+        if name.to_string_lossy().starts_with("PHOTO_SYNC_") {
+            command.env_remove(name);
+        }
+    }
+    command
+        .current_dir(dir)
+        .args(args)
+        .envs(env.iter().copied())
+        .output()
+        .unwrap()
+}
+
+/// A directory to sync a photo from, and to.
+fn test_dir() -> tempfile::TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    for name in ["in", "out", "old", "tmp"] {
+        fs::create_dir(dir.path().join(name)).unwrap();
+    }
+    fs::write(dir.path().join("in/a.jpg"), "photo").unwrap();
+    dir
+}
+
+const SYNC_ENV: &[(&str, &str)] = &[
+    ("PHOTO_SYNC_IN_DIR", "in"),
+    ("PHOTO_SYNC_OUT_DIR", "out"),
+    ("PHOTO_SYNC_OLD_OUT_DIR", "old"),
+    ("PHOTO_SYNC_TEMP_DIR", "tmp"),
+    ("PHOTO_SYNC_DATABASE", "db.sqlite"),
+    ("PHOTO_SYNC_INCLUDE_SMALL_FILES", "true"),
+];
+
+#[test]
+fn environment_variables_stand_in_for_sync_args() {
+    let dir = test_dir();
+    let env = [SYNC_ENV, &[("PHOTO_SYNC_LOG_LEVEL", "debug")]].concat();
+
+    let from_env = photo_sync(dir.path(), &[], &env);
+    assert!(from_env.status.success(), "{from_env:?}");
+    let stdout = String::from_utf8_lossy(&from_env.stdout);
+    assert!(stdout.contains("log_level: Debug"), "{stdout}");
+    assert_eq!(fs::read(dir.path().join("out/a.jpg")).unwrap(), b"photo");
+
+    // the command line takes precedence.
+    let overridden = photo_sync(dir.path(), &["--log-level=warn"], &env);
+    assert!(overridden.status.success(), "{overridden:?}");
+    assert!(!String::from_utf8_lossy(&overridden.stdout).contains("DEBUG"));
+
+    // and subcommands aren't taken as given sync arguments too.
+    let history = photo_sync(dir.path(), &["history", "--database-file=db.sqlite"], &env);
+    assert!(history.status.success(), "{history:?}");
+}
+
+#[test]
+fn only_a_database_file_from_the_environment_gives_way_to_a_catalogue() {
+    let dir = test_dir();
+    let stderr = |output: Output| String::from_utf8_lossy(&output.stderr).into_owned();
+    // nothing is listening, so the sync fails, but only once it has got as far as connecting.
+    let addr = "--catalogue-addr=127.0.0.1:1";
+
+    let from_env = stderr(photo_sync(dir.path(), &[addr], SYNC_ENV));
+    assert!(
+        from_env.contains("could not connect to catalogue"),
+        "{from_env}"
+    );
+    let without_database: Vec<_> = (SYNC_ENV.iter().copied())
+        .filter(|(name, _)| *name != "PHOTO_SYNC_DATABASE")
+        .collect();
+    let given = stderr(photo_sync(
+        dir.path(),
+        &[addr, "--database-file=db.sqlite"],
+        &without_database,
+    ));
+    assert!(given.contains("cannot be used with"), "{given}");
+}